@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use aquifer::buffer_pool::{BackgroundWriterConfig, PrefetchConfig, ScanRingConfig};
+use aquifer::checkpointer::{Checkpointer, CheckpointerConfig};
+use aquifer::checksum::ChecksumAlgorithm;
+use aquifer::discard::DiscardConfig;
+use aquifer::eviction::EvictionKind;
+use aquifer::page::PAGE_HEADER_SIZE;
+use aquifer::segment::SegmentAllocation;
+use aquifer::traits::{PageId, PageStore, StorageConfig, StorageError, StorageManager, WalStore};
+use aquifer::wal::WalLayout;
+use aquifer::wal_compress::WalCompression;
+use aquifer::wal_record::WalRecordType;
+use cpu_time::ThreadTime;
+
+// Checkpoints a database with every page of its working set dirty, and reports the
+// wall and CPU time the checkpoint took and the memory the process grew by meanwhile:
+//
+//   cargo bench --bench checkpoint_bench [-- <pages>]
+//
+// The directories live under the system temp dir and are removed afterwards.
+
+const DB: u32 = 1;
+const SPACE: u32 = 1;
+const DEFAULT_PAGES: u32 = 4096;
+const EXTENT_PAGES: u32 = 64;
+
+fn main() -> Result<(), StorageError> {
+    let pages = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_PAGES)
+        .next_multiple_of(EXTENT_PAGES);
+    let dir = std::env::temp_dir().join(format!("checkpoint_bench-{}", std::process::id()));
+    let res = bench(&dir, pages);
+    let _ = fs::remove_dir_all(&dir);
+    res
+}
+
+fn bench(dir: &Path, pages: u32) -> Result<(), StorageError> {
+    let data_dir = dir.join("data");
+    let wal_dir = dir.join("wal");
+    fs::create_dir_all(&data_dir).map_err(StorageError::Io)?;
+    fs::create_dir_all(&wal_dir).map_err(StorageError::Io)?;
+    let manager = StorageManager::mount(config(data_dir, wal_dir, pages as usize))?;
+
+    tokio_uring::start(async {
+        let storage = Rc::new(manager.local_worker(0));
+        let partition = manager.local_partition(0, Rc::clone(&storage));
+        let pool = partition.pool();
+        let mut first = None;
+        for _ in 0..pages / EXTENT_PAGES {
+            let start = storage.allocate_extent(DB, SPACE, EXTENT_PAGES).await?;
+            first.get_or_insert(start);
+        }
+        let first = first.unwrap_or(0);
+        for page_no in first..first + pages {
+            let mut page = pool.get_page_mut(PageId { db_id: DB, space_id: SPACE, page_no }).await?;
+            page[PAGE_HEADER_SIZE..].fill(page_no as u8);
+            page.set_lsn(storage.append_wal(DB, WalRecordType::OPAQUE, &page_no.to_le_bytes()).await?);
+        }
+
+        let checkpointer = Checkpointer::new(Rc::clone(pool), CheckpointerConfig::default());
+        let resident = physical_mem();
+        let cpu = ThreadTime::now();
+        let started = Instant::now();
+        let summary = checkpointer.checkpoint(DB).await?;
+        report(pages, started.elapsed(), cpu.elapsed(), physical_mem().saturating_sub(resident));
+        println!("{:?}", summary);
+        Ok(())
+    })
+}
+
+fn report(pages: u32, wall: Duration, cpu: Duration, grown: usize) {
+    println!("checkpoint of {} dirty pages", pages);
+    println!("  wall      {:>10.2} ms", wall.as_secs_f64() * 1e3);
+    println!("  cpu       {:>10.2} ms", cpu.as_secs_f64() * 1e3);
+    println!("  per page  {:>10.2} us", wall.as_secs_f64() * 1e6 / pages as f64);
+    println!("  rss grew  {:>10} KiB", grown / 1024);
+}
+
+fn physical_mem() -> usize {
+    memory_stats::memory_stats().map_or(0, |stats| stats.physical_mem)
+}
+
+fn config(data_dir: PathBuf, wal_dir: PathBuf, frames: usize) -> StorageConfig {
+    StorageConfig {
+        data_dir,
+        wal_dir,
+        io_uring_entries: 256,
+        checksum: ChecksumAlgorithm::Crc32c,
+        checksum_offload_threshold: None,
+        spaces: HashMap::new(),
+        discard: DiscardConfig::default(),
+        segment_allocation: SegmentAllocation::Sparse,
+        end_to_end_checksums: false,
+        full_page_writes: false,
+        corrupt_pages: Default::default(),
+        redo_past_corruption: false,
+        pinned_pages: Vec::new(),
+        commit_delay: None,
+        async_commit_window: Duration::from_millis(10),
+        wal_archiver: None,
+        wal_compression: WalCompression::default(),
+        wal_keys: HashMap::new(),
+        wal_future_segments: 0,
+        wal_layout: WalLayout::PerDatabase,
+        wal_direct_io: false,
+        cores: 1,
+        buffer_pool_frames: frames,
+        buffer_pool_max_frames: frames,
+        buffer_pool_warm_up: false,
+        numa_aware: false,
+        buffer_pool_eviction: EvictionKind::default(),
+        background_writer: BackgroundWriterConfig::default(),
+        prefetch: PrefetchConfig::default(),
+        // The working set is dirtied in page order; keep it out of the scan ring.
+        scan_ring: ScanRingConfig { trigger: u32::MAX, ..ScanRingConfig::default() },
+        buffer_pool_bulk_budget: 100,
+        checkpointer: CheckpointerConfig::default(),
+        slow_io_threshold: None,
+        completions: Default::default(),
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use aquifer::eviction::{EvictionKind, EvictionPolicy};
+use aquifer::traits::PageId;
+
+// Replays a Zipfian page trace against each eviction policy, driven the way
+// `BufferPool` drives it, at a few pool sizes, and reports the hit ratios:
+//
+//   cargo bench --bench eviction_bench
+//
+// A second run mixes in sequential scans over random stretches of the space, which a
+// policy resisting scans keeps from flushing the hot set.
+
+const PAGES: u32 = 100_000;
+const ACCESSES: usize = 2_000_000;
+const SKEW: f64 = 0.99;
+// Pool sizes, in percent of PAGES
+const POOL_PERCENTS: [u32; 3] = [1, 5, 10];
+// With scans, one access in SCAN_ONE_IN starts a scan of SCAN_PAGES pages
+const SCAN_ONE_IN: u64 = 2_000;
+const SCAN_PAGES: u32 = 500;
+const KINDS: [EvictionKind; 3] = [EvictionKind::ClockSweep, EvictionKind::Lru2, EvictionKind::TinyLfu];
+
+fn main() {
+    for scans in [false, true] {
+        let trace = trace(scans);
+        println!("zipf s={} over {} pages, {} accesses{}", SKEW, PAGES, trace.len(), if scans { ", with scans" } else { "" });
+        print!("{:>8}", "pool");
+        for kind in KINDS {
+            print!("{:>14}", format!("{:?}", kind));
+        }
+        println!();
+        for percent in POOL_PERCENTS {
+            let frames = (PAGES * percent / 100) as usize;
+            print!("{:>7}%", percent);
+            for kind in KINDS {
+                print!("{:>13.2}%", hit_ratio(kind.build(frames), frames, &trace) * 100.0);
+            }
+            println!();
+        }
+        println!();
+    }
+}
+
+// Runs `trace` through a pool of `frames` frames under `policy`
+fn hit_ratio(mut policy: Box<dyn EvictionPolicy>, frames: usize, trace: &[u32]) -> f64 {
+    let mut resident: HashMap<u32, usize> = HashMap::with_capacity(frames);
+    let mut pages: Vec<Option<u32>> = vec![None; frames];
+    let mut free: Vec<usize> = (0..frames).rev().collect();
+    let mut hits = 0;
+    for &page_no in trace {
+        if let Some(&frame) = resident.get(&page_no) {
+            policy.accessed(frame);
+            hits += 1;
+            continue;
+        }
+        let page_id = PageId { db_id: 1, space_id: 1, page_no };
+        let frame = match free.pop() {
+            Some(frame) => frame,
+            None => {
+                let victim = policy.victim(page_id, &|frame| pages[frame].is_some()).expect("a frame to evict");
+                policy.removed(victim);
+                resident.remove(&pages[victim].take().unwrap());
+                victim
+            }
+        };
+        policy.inserted(frame, page_id);
+        pages[frame] = Some(page_no);
+        resident.insert(page_no, frame);
+    }
+    hits as f64 / trace.len() as f64
+}
+
+// Page numbers drawn from a Zipfian distribution, the most popular scattered over the
+// space rather than all at the start of it
+fn trace(scans: bool) -> Vec<u32> {
+    let mut cdf = Vec::with_capacity(PAGES as usize);
+    let mut sum = 0.0;
+    for rank in 1..=PAGES {
+        sum += 1.0 / (rank as f64).powf(SKEW);
+        cdf.push(sum);
+    }
+    let mut rng = Rng(0x5EED);
+    let mut trace = Vec::with_capacity(ACCESSES);
+    while trace.len() < ACCESSES {
+        if scans && rng.next().is_multiple_of(SCAN_ONE_IN) {
+            let start = (rng.next() % (PAGES - SCAN_PAGES) as u64) as u32;
+            trace.extend(start..start + SCAN_PAGES);
+            continue;
+        }
+        let target = rng.unit() * sum;
+        let rank = cdf.partition_point(|&at| at < target) as u32;
+        trace.push(scatter(rank));
+    }
+    trace.truncate(ACCESSES);
+    trace
+}
+
+// A fixed permutation of 0..PAGES
+fn scatter(rank: u32) -> u32 {
+    ((rank as u64 * 7_919) % PAGES as u64) as u32
+}
+
+// xorshift64*
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // Uniform in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,111 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use aquifer::page_table::PageTable;
+use aquifer::traits::PageId;
+
+// Times `PageTable` against the `RefCell<HashMap>` it replaced, under the load a
+// buffer pool puts on it: lookups of cached and uncached pages, and evictions, each a
+// removal and an insert. Reports nanoseconds per operation at a few lookup shares:
+//
+//   cargo bench --bench page_table_bench
+
+const FRAMES: usize = 16_384;
+// Pages looked up, twice the frames so about half the lookups miss
+const PAGES: u32 = 32_768;
+const OPS: usize = 10_000_000;
+const LOOKUP_PERCENTS: [u64; 3] = [99, 90, 50];
+
+trait Table {
+    fn get(&self, page_id: PageId) -> Option<usize>;
+    fn insert(&self, page_id: PageId, frame: usize);
+    fn remove(&self, page_id: PageId) -> Option<usize>;
+}
+
+impl Table for PageTable {
+    fn get(&self, page_id: PageId) -> Option<usize> {
+        PageTable::get(self, page_id)
+    }
+
+    fn insert(&self, page_id: PageId, frame: usize) {
+        PageTable::insert(self, page_id, frame)
+    }
+
+    fn remove(&self, page_id: PageId) -> Option<usize> {
+        PageTable::remove(self, page_id)
+    }
+}
+
+impl Table for RefCell<HashMap<PageId, usize>> {
+    fn get(&self, page_id: PageId) -> Option<usize> {
+        self.borrow().get(&page_id).copied()
+    }
+
+    fn insert(&self, page_id: PageId, frame: usize) {
+        self.borrow_mut().insert(page_id, frame);
+    }
+
+    fn remove(&self, page_id: PageId) -> Option<usize> {
+        self.borrow_mut().remove(&page_id)
+    }
+}
+
+fn main() {
+    println!("{} frames, {} pages, {} ops", FRAMES, PAGES, OPS);
+    println!("{:>8} {:>14} {:>14}", "lookups", "PageTable", "HashMap");
+    for percent in LOOKUP_PERCENTS {
+        let table = run(&PageTable::new(FRAMES), percent);
+        let map = run(&RefCell::new(HashMap::with_capacity(FRAMES)), percent);
+        println!("{:>7}% {:>11.1} ns {:>11.1} ns", percent, per_op(table), per_op(map));
+    }
+}
+
+fn per_op(elapsed: Duration) -> f64 {
+    elapsed.as_nanos() as f64 / OPS as f64
+}
+
+// Fills the table, then runs the mixed load on it
+fn run(table: &impl Table, lookup_percent: u64) -> Duration {
+    // The page each frame holds, evicted round-robin
+    let mut frames: Vec<PageId> = (0..FRAMES as u32).map(page_id).collect();
+    for (frame, &page) in frames.iter().enumerate() {
+        table.insert(page, frame);
+    }
+    let mut rng = Rng(0x5EED);
+    let mut next_victim = 0;
+    let started = Instant::now();
+    for _ in 0..OPS {
+        let page = page_id((rng.next() % PAGES as u64) as u32);
+        if rng.next() % 100 < lookup_percent {
+            black_box(table.get(page));
+            continue;
+        }
+        if table.get(page).is_some() {
+            continue;
+        }
+        let frame = next_victim;
+        next_victim = (next_victim + 1) % FRAMES;
+        table.remove(frames[frame]);
+        table.insert(page, frame);
+        frames[frame] = page;
+    }
+    started.elapsed()
+}
+
+fn page_id(page_no: u32) -> PageId {
+    PageId { db_id: 1, space_id: 1 + page_no % 4, page_no }
+}
+
+// xorshift64*
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
@@ -0,0 +1,763 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::commit_ts::CommitTimestamp;
+use crate::compression;
+use crate::page::{self, PAGE_SIZE};
+use crate::segment::{self, SegmentHeader};
+use crate::trace;
+use crate::traits::{Lsn, PageId, StorageError};
+use crate::wal;
+use crate::wal_checkpoint;
+use crate::wal_crypt::{WalCipher, WalKey};
+use crate::wal_recovery;
+use crate::wal_redo;
+use crate::wal_retention;
+
+// -----------------------------------------------------------------------------
+// Base Backups
+//
+// `basebackup` copies one database while the engine keeps running, into
+//
+//   dest/data/db_<id>/...              the database's data files, dictionaries included
+//   dest/wal/[core_<n>/]db_<id>/...    each WAL stream, from where its recovery starts
+//   dest/backup_manifest               what was copied, see below
+//
+// The data files are copied while pages are being written, so the copy is no snapshot
+// by itself; the WAL copied with it makes it one.
+//
+//   1. A replication slot (see `wal_retention.rs`) holds each stream's WAL, and its
+//      last checkpoint is read (see `wal_redo.rs`): recovery of the copy starts at its
+//      redo LSN, or earlier where a transaction active then may need rolling back.
+//   2. Every data file is copied. Pages are checked as they are read, and one caught
+//      mid-write (its checksum fails) is read again, so each copied page is a whole
+//      image, and at least as new as the checkpoint.
+//   3. Each stream's WAL is copied, from the start found in 1 to its end on disk. With
+//      write-ahead logging, every change in a copied page was durable in the WAL before
+//      the page was written, so before it was copied: the copied WAL holds it.
+//   4. Each copied stream is pointed at the checkpoint of 1, not a later one, which
+//      may postdate pages copied before it completed.
+//
+// Restoring (see `restore.rs`) puts `data/` and `wal/` in place in the data and WAL
+// directories, without a clean shutdown marker, so mount recovers: redo brings every
+// page up to the end of the copied WAL, and transactions in flight there are rolled
+// back.
+//
+// The manifest, written last, so a backup without one is incomplete:
+//
+//   magic u64 | db_id u32 | started_at u64 | parent's started_at u64 (0: a full
+//   backup) | stream count u32, then per stream:
+//     root (as a file path) | origin u64 | start u64 | checkpoint u64 (0: none) | end u64
+//     | newest page LSN u64
+//   file count u32, then per file:
+//     path length u16 | path (UTF-8, relative to dest) | size u64 | CRC32C u32
+//   CRC32C of all of the above (u32)
+//
+// Everything here is blocking std::fs I/O: run it on a thread of its own, not a core.
+// -----------------------------------------------------------------------------
+
+const MANIFEST_FILE: &str = "backup_manifest";
+const MANIFEST_MAGIC: u64 = u64::from_le_bytes(*b"CASCBAK\0");
+
+// Rereads of a page caught mid-write before it counts as corrupt, and the pause before each
+const TORN_RETRIES: usize = 100;
+const TORN_RETRY: Duration = Duration::from_millis(1);
+
+/// One WAL stream in a backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupStream {
+    /// The stream's root relative to the WAL directory: empty, or `core_<n>`.
+    pub root: PathBuf,
+    pub origin: Lsn,
+    /// Where recovery of the copy starts reading, and the checkpoint it redoes from.
+    pub start: Lsn,
+    pub checkpoint: Option<Lsn>,
+    /// End of the last whole record copied.
+    pub end: Lsn,
+    /// Newest LSN stamped on a copied page from this stream. Recovery must get at least
+    /// this far for the copy to be consistent.
+    pub newest_page_lsn: Lsn,
+}
+
+/// One file in a backup, relative to its root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub crc: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupManifest {
+    pub db_id: u32,
+    pub started_at: CommitTimestamp,
+    /// For an incremental backup, `started_at` of the backup it builds on.
+    pub parent: Option<CommitTimestamp>,
+    pub streams: Vec<BackupStream>,
+    pub files: Vec<BackupFile>,
+}
+
+impl BackupManifest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&MANIFEST_MAGIC.to_le_bytes());
+        out.extend_from_slice(&self.db_id.to_le_bytes());
+        out.extend_from_slice(&self.started_at.0.to_le_bytes());
+        out.extend_from_slice(&self.parent.map_or(0, |parent| parent.0).to_le_bytes());
+        out.extend_from_slice(&(self.streams.len() as u32).to_le_bytes());
+        for stream in &self.streams {
+            put_path(&mut out, &stream.root);
+            let checkpoint = stream.checkpoint.unwrap_or(Lsn(0));
+            for lsn in [stream.origin, stream.start, checkpoint, stream.end, stream.newest_page_lsn] {
+                out.extend_from_slice(&lsn.0.to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&(self.files.len() as u32).to_le_bytes());
+        for file in &self.files {
+            put_path(&mut out, &file.path);
+            out.extend_from_slice(&file.size.to_le_bytes());
+            out.extend_from_slice(&file.crc.to_le_bytes());
+        }
+        let crc = checksum::crc32c(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (body, crc) = bytes.split_last_chunk::<4>()?;
+        if checksum::crc32c(body) != u32::from_le_bytes(*crc) {
+            return None;
+        }
+        let mut rest = body;
+        if take_u64(&mut rest)? != MANIFEST_MAGIC {
+            return None;
+        }
+        let db_id = take_u32(&mut rest)?;
+        let started_at = CommitTimestamp(take_u64(&mut rest)?);
+        let parent = Some(take_u64(&mut rest)?).filter(|&parent| parent != 0).map(CommitTimestamp);
+
+        let mut streams = Vec::new();
+        for _ in 0..take_u32(&mut rest)? {
+            let root = take_path(&mut rest)?;
+            let origin = Lsn(take_u64(&mut rest)?);
+            let start = Lsn(take_u64(&mut rest)?);
+            let checkpoint = Some(Lsn(take_u64(&mut rest)?)).filter(|&lsn| lsn != Lsn(0));
+            let end = Lsn(take_u64(&mut rest)?);
+            let newest_page_lsn = Lsn(take_u64(&mut rest)?);
+            streams.push(BackupStream { root, origin, start, checkpoint, end, newest_page_lsn });
+        }
+        let mut files = Vec::new();
+        for _ in 0..take_u32(&mut rest)? {
+            let path = take_path(&mut rest)?;
+            let size = take_u64(&mut rest)?;
+            let crc = take_u32(&mut rest)?;
+            files.push(BackupFile { path, size, crc });
+        }
+        rest.is_empty().then_some(Self { db_id, started_at, parent, streams, files })
+    }
+}
+
+fn put_path(out: &mut Vec<u8>, path: &Path) {
+    let path = path.to_string_lossy();
+    out.extend_from_slice(&(path.len() as u16).to_le_bytes());
+    out.extend_from_slice(path.as_bytes());
+}
+
+fn take_path(rest: &mut &[u8]) -> Option<PathBuf> {
+    let (len, tail) = rest.split_first_chunk::<2>()?;
+    let len = u16::from_le_bytes(*len) as usize;
+    let path = std::str::from_utf8(tail.get(..len)?).ok()?;
+    *rest = &tail[len..];
+    Some(PathBuf::from(path))
+}
+
+fn take_u32(rest: &mut &[u8]) -> Option<u32> {
+    let (head, tail) = rest.split_first_chunk::<4>()?;
+    *rest = tail;
+    Some(u32::from_le_bytes(*head))
+}
+
+fn take_u64(rest: &mut &[u8]) -> Option<u64> {
+    let (head, tail) = rest.split_first_chunk::<8>()?;
+    *rest = tail;
+    Some(u64::from_le_bytes(*head))
+}
+
+/// Copies database `db_id` of the engine over `data_dir` and `wal_dir` into the empty
+/// or missing directory `dest`, while the engine runs. `keys` decrypt encrypted WALs.
+pub fn basebackup(
+    data_dir: &Path,
+    wal_dir: &Path,
+    db_id: u32,
+    checksum: ChecksumAlgorithm,
+    keys: &HashMap<u32, WalKey>,
+    dest: &Path,
+) -> Result<BackupManifest, StorageError> {
+    backup(data_dir, wal_dir, db_id, checksum, keys, None, dest)
+}
+
+// A full backup, or with `parent` an incremental one
+fn backup(
+    data_dir: &Path,
+    wal_dir: &Path,
+    db_id: u32,
+    checksum: ChecksumAlgorithm,
+    keys: &HashMap<u32, WalKey>,
+    parent: Option<&BackupManifest>,
+    dest: &Path,
+) -> Result<BackupManifest, StorageError> {
+    let started_at = CommitTimestamp::now();
+    let mut slots = HeldWal { db_id, name: format!("basebackup_{}", started_at.0), roots: Vec::new() };
+
+    // 1. Hold each stream's WAL, then find where recovery of the copy starts.
+    let mut plans = Vec::new();
+    for (root, origin) in wal::wal_roots(wal_dir)? {
+        if !wal::wal_databases(&root)?.contains(&db_id) {
+            continue;
+        }
+        let retained = wal_recovery::recovery_start(&root, db_id, origin)?;
+        wal_retention::create_slot(&root, db_id, &slots.name, retained)?;
+        slots.roots.push(root.clone());
+        let cipher = keys.get(&db_id).map(|key| WalCipher::new(key, db_id, wal::lsn_core(origin)));
+        let plan = wal_redo::plan(&root, db_id, origin, cipher.as_ref())?;
+        wal_retention::advance_slot(&root, db_id, &slots.name, plan.scan_from)?;
+        plans.push((plan, cipher));
+    }
+
+    // 2. The data files, including those created meanwhile
+    let src_db_dir = data_dir.join(format!("db_{}", db_id));
+    let dest_db_dir = dest.join("data").join(format!("db_{}", db_id));
+    fs::create_dir_all(&dest_db_dir).map_err(StorageError::Io)?;
+    let mut page_map = parent.map(|parent| PageMapWriter::create(dest, parent)).transpose()?;
+    let mut newest: HashMap<u16, Lsn> = HashMap::new();
+    let mut copied = Vec::new();
+    loop {
+        let mut names: Vec<String> = list_files(&src_db_dir)?.into_iter().filter(|name| !copied.contains(name)).collect();
+        if names.is_empty() {
+            break;
+        }
+        names.sort();
+        for name in names {
+            let (src, dst) = (src_db_dir.join(&name), dest_db_dir.join(&name));
+            let res = match (segment::parse_segment_file_name(&name), &mut page_map) {
+                (Some((space_id, seg_no)), Some(page_map)) => {
+                    page_map.add_segment(&src, (db_id, space_id, seg_no), checksum, &mut newest)
+                }
+                (Some((space_id, seg_no)), None) => copy_segment(&src, &dst, (db_id, space_id, seg_no), checksum, &mut newest),
+                (None, _) => copy_file(&src, &dst),
+            };
+            match res {
+                Ok(()) => {}
+                // Dropped while we were at it
+                Err(StorageError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            copied.push(name);
+        }
+    }
+    if let Some(page_map) = page_map {
+        page_map.finish()?;
+    }
+
+    // 3. and 4. The WAL, from each stream's start to its end
+    let mut streams = Vec::new();
+    for (plan, cipher) in plans {
+        let rel = plan.wal_dir.strip_prefix(wal_dir).unwrap_or(Path::new("")).to_path_buf();
+        let dest_root = dest.join("wal").join(&rel);
+        let (first_seg, _) = wal::locate(plan.scan_from);
+        fs::create_dir_all(dest_root.join(format!("db_{}", db_id))).map_err(StorageError::Io)?;
+        for (seg_no, path) in wal::wal_segments(&plan.wal_dir, db_id)? {
+            if seg_no < first_seg {
+                continue;
+            }
+            let dst = dest_root.join(format!("db_{}", db_id)).join(path.file_name().unwrap());
+            copy_file(&path, &dst)?;
+        }
+        wal_recovery::set_recovery_start(&dest_root, db_id, plan.scan_from)?;
+        if let Some(checkpoint) = plan.checkpoint {
+            wal_checkpoint::set_last_checkpoint(&dest_root, db_id, checkpoint)?;
+        }
+
+        let end = wal_recovery::recover_tail(&dest_root, db_id, plan.origin, cipher.as_ref())?.tail;
+        let newest_page_lsn = newest.get(&wal::lsn_core(plan.origin)).copied();
+        if let Some(newest_page_lsn) = newest_page_lsn.filter(|&lsn| lsn >= end) {
+            return Err(StorageError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "backup of db {}: WAL of {} ends at {:?}, before copied page LSN {:?}",
+                    db_id,
+                    plan.wal_dir.display(),
+                    end,
+                    newest_page_lsn
+                ),
+            )));
+        }
+        streams.push(BackupStream {
+            root: rel,
+            origin: plan.origin,
+            start: plan.scan_from,
+            checkpoint: plan.checkpoint,
+            end,
+            newest_page_lsn: newest_page_lsn.unwrap_or(Lsn(0)),
+        });
+    }
+
+    let manifest = BackupManifest {
+        db_id,
+        started_at,
+        parent: parent.map(|parent| parent.started_at),
+        streams,
+        files: file_list(dest)?,
+    };
+    wal_recovery::write_durably(&dest.join(MANIFEST_FILE), &manifest.encode())?;
+    Ok(manifest)
+}
+
+// Every file of the backup in `dest`, its manifest aside
+fn file_list(dest: &Path) -> Result<Vec<BackupFile>, StorageError> {
+    let mut files = Vec::new();
+    for path in walk(dest)? {
+        if path == dest.join(MANIFEST_FILE) {
+            continue;
+        }
+        let bytes = fs::read(&path).map_err(StorageError::Io)?;
+        files.push(BackupFile {
+            path: path.strip_prefix(dest).unwrap().to_path_buf(),
+            size: bytes.len() as u64,
+            crc: checksum::crc32c(&bytes),
+        });
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Reads the manifest of the backup in `dest` and checks every file it lists against
+/// it. Fails on a missing manifest, which means the backup never completed.
+pub fn verify(dest: &Path) -> Result<BackupManifest, StorageError> {
+    let path = dest.join(MANIFEST_FILE);
+    let bytes = fs::read(&path).map_err(StorageError::Io)?;
+    let manifest = BackupManifest::decode(&bytes).ok_or_else(|| invalid(&path, "corrupt backup manifest"))?;
+    for file in &manifest.files {
+        let path = dest.join(&file.path);
+        let bytes = fs::read(&path).map_err(StorageError::Io)?;
+        if bytes.len() as u64 != file.size || checksum::crc32c(&bytes) != file.crc {
+            return Err(invalid(&path, "backup file differs from its manifest entry"));
+        }
+    }
+    Ok(manifest)
+}
+
+fn invalid(path: &Path, reason: &str) -> StorageError {
+    StorageError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", reason, path.display())))
+}
+
+// The slots holding WAL for a backup, dropped when it ends either way
+struct HeldWal {
+    db_id: u32,
+    name: String,
+    roots: Vec<PathBuf>,
+}
+
+impl Drop for HeldWal {
+    fn drop(&mut self) {
+        for root in &self.roots {
+            if let Err(e) = wal_retention::drop_slot(root, self.db_id, &self.name) {
+                trace::warn_event!("backup: slot {} of {} not dropped: {:?}", self.name, root.display(), e);
+            }
+        }
+    }
+}
+
+// Names of the regular files in `dir`, temporary ones aside
+fn list_files(dir: &Path) -> Result<Vec<String>, StorageError> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir).map_err(StorageError::Io)? {
+        let entry = entry.map_err(StorageError::Io)?;
+        let is_file = entry.file_type().map_err(StorageError::Io)?.is_file();
+        if let Some(name) = entry.file_name().to_str().filter(|name| is_file && !name.ends_with(".tmp")) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Every regular file under `dir`, recursively.
+pub fn walk(dir: &Path) -> Result<Vec<PathBuf>, StorageError> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).map_err(StorageError::Io)? {
+            let entry = entry.map_err(StorageError::Io)?;
+            if entry.file_type().map_err(StorageError::Io)?.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Copies `src` over `dst` and syncs the copy.
+pub fn copy_file(src: &Path, dst: &Path) -> Result<(), StorageError> {
+    fs::copy(src, dst).map_err(StorageError::Io)?;
+    fs::File::open(dst).and_then(|f| f.sync_all()).map_err(StorageError::Io)
+}
+
+// Copies a segment page by page, rereading pages caught mid-write, and notes the
+// newest page LSN of each stream in `newest`.
+fn copy_segment(
+    src: &Path,
+    dst: &Path,
+    segment: SegmentIds,
+    algorithm: ChecksumAlgorithm,
+    newest: &mut HashMap<u16, Lsn>,
+) -> Result<(), StorageError> {
+    let file = fs::File::open(src).map_err(StorageError::Io)?;
+    let mut out = fs::File::create(dst).map_err(StorageError::Io)?;
+    let mut page = vec![0u8; PAGE_SIZE];
+    let mut offset = 0u64;
+    loop {
+        let len = read_whole_page(&file, src, segment, offset, algorithm, &mut page)?;
+        if len == 0 {
+            break;
+        }
+        if offset > 0 && len == PAGE_SIZE {
+            note_page_lsn(newest, &page);
+        }
+        out.write_all(&page[..len]).map_err(StorageError::Io)?;
+        offset += len as u64;
+        if len < PAGE_SIZE {
+            break;
+        }
+    }
+    out.sync_all().map_err(StorageError::Io)
+}
+
+// A segment file: (db_id, space_id, seg_no)
+type SegmentIds = (u32, u32, u32);
+
+// Reads the page at `offset` of segment file `file` (at `path`), again while its
+// checksum fails, as it does while a write of it is in flight. Returns the bytes read:
+// a whole page, or less at the end of the file.
+fn read_whole_page(
+    file: &fs::File,
+    path: &Path,
+    (db_id, space_id, seg_no): SegmentIds,
+    offset: u64,
+    algorithm: ChecksumAlgorithm,
+    page: &mut [u8],
+) -> Result<usize, StorageError> {
+    for _ in 0..=TORN_RETRIES {
+        let len = read_page_at(file, offset, page)?;
+        if len < PAGE_SIZE {
+            return Ok(len);
+        }
+        let whole = match offset {
+            0 => SegmentHeader::decode(page, path).is_ok(),
+            _ => intact(algorithm, page),
+        };
+        if whole {
+            return Ok(len);
+        }
+        thread::sleep(TORN_RETRY);
+    }
+    let page_no = seg_no * segment::PAGES_PER_SEGMENT + (offset / PAGE_SIZE as u64) as u32 - 1;
+    Err(StorageError::Corruption(PageId { db_id, space_id, page_no }))
+}
+
+// The LSN of a written page, if it can be read: compressed frames hide it.
+fn readable_page_lsn(page: &[u8]) -> Option<Lsn> {
+    (!page::is_fresh(page) && !compression::is_compressed(page)).then(|| page::page_lsn(page))
+}
+
+fn note_page_lsn(newest: &mut HashMap<u16, Lsn>, page: &[u8]) {
+    if let Some(lsn) = readable_page_lsn(page) {
+        let stream = newest.entry(wal::lsn_core(lsn)).or_insert(Lsn(0));
+        *stream = (*stream).max(lsn);
+    }
+}
+
+// Whether a page read is a whole image: never written, or passing its checksum
+fn intact(algorithm: ChecksumAlgorithm, page: &[u8]) -> bool {
+    if page::is_fresh(page) {
+        return true;
+    }
+    match compression::is_compressed(page) {
+        true => compression::verify_frame(algorithm, page),
+        false => checksum::verify_page(algorithm, page),
+    }
+}
+
+// Reads up to a page at `offset`; short only at the end of the file.
+fn read_page_at(file: &fs::File, offset: u64, page: &mut [u8]) -> Result<usize, StorageError> {
+    let mut len = 0;
+    while len < page.len() {
+        match file.read_at(&mut page[len..], offset + len as u64) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(StorageError::Io(e)),
+        }
+    }
+    Ok(len)
+}
+
+// -----------------------------------------------------------------------------
+// Incremental Backups
+//
+// An incremental backup is laid out like a full one, but for the segments: only the
+// pages changed since the backup it builds on (its parent) are copied, into
+//
+//   dest/incremental   the pages (8KB each) in map order, then their map:
+//     segment count u32, then per segment of the database at backup time:
+//       space_id u32 | seg_no u32 | file size u64 | page count u32 | page index u32 x count
+//     map length u32 | CRC32C of the map u32
+//
+// where index 0 is the segment's header page, which is always copied. Other files of
+// the data directory are copied whole, and the WAL as for a full backup.
+//
+// A page is copied if its LSN is at or past where recovery of the parent starts, in
+// the page's stream. The parent's copy of an older page is whole and current: every
+// change to it was on disk before the parent's copy began, and none came since. A
+// newer page may have been copied mid-way through its changes, which the parent's
+// WAL makes up for but this backup's does not, so it is copied again. Pages whose LSN
+// can't be read (compressed, or never logged) are always copied.
+//
+// `combine` merges a chain of incrementals onto a full backup, in place: each one's
+// pages are written over the segments, segments it doesn't list are removed, and its
+// other data files and WAL replace the previous ones. The result is a full backup
+// as of the last incremental, with a manifest saying so.
+// -----------------------------------------------------------------------------
+
+const INCREMENTAL_FILE: &str = "incremental";
+
+/// Copies the pages of database `db_id` changed since backup `parent` (full or
+/// incremental) into `dest`, with the WAL to make them consistent; see `basebackup`.
+pub fn incremental_backup(
+    data_dir: &Path,
+    wal_dir: &Path,
+    db_id: u32,
+    checksum: ChecksumAlgorithm,
+    keys: &HashMap<u32, WalKey>,
+    parent: &BackupManifest,
+    dest: &Path,
+) -> Result<BackupManifest, StorageError> {
+    if parent.db_id != db_id {
+        return Err(invalid(dest, &format!("parent backup is of db {}, not db {}", parent.db_id, db_id)));
+    }
+    backup(data_dir, wal_dir, db_id, checksum, keys, Some(parent), dest)
+}
+
+// A segment's entry in the page map
+#[derive(Debug)]
+struct MappedSegment {
+    space_id: u32,
+    seg_no: u32,
+    size: u64,
+    pages: Vec<u32>,
+}
+
+// Writes the incremental file: pages as they are found, the map at the end.
+struct PageMapWriter {
+    out: fs::File,
+    // Where each stream's changes start to count, by core
+    since: HashMap<u16, Lsn>,
+    segments: Vec<MappedSegment>,
+}
+
+impl PageMapWriter {
+    fn create(dest: &Path, parent: &BackupManifest) -> Result<Self, StorageError> {
+        Ok(Self {
+            out: fs::File::create(dest.join(INCREMENTAL_FILE)).map_err(StorageError::Io)?,
+            since: parent.streams.iter().map(|stream| (wal::lsn_core(stream.origin), stream.start)).collect(),
+            segments: Vec::new(),
+        })
+    }
+
+    // Adds the changed pages of a segment, noting page LSNs in `newest`.
+    fn add_segment(
+        &mut self,
+        src: &Path,
+        segment: SegmentIds,
+        algorithm: ChecksumAlgorithm,
+        newest: &mut HashMap<u16, Lsn>,
+    ) -> Result<(), StorageError> {
+        let file = fs::File::open(src).map_err(StorageError::Io)?;
+        let mut mapped = MappedSegment { space_id: segment.1, seg_no: segment.2, size: 0, pages: Vec::new() };
+        let mut page = vec![0u8; PAGE_SIZE];
+        loop {
+            let offset = mapped.size;
+            let len = read_whole_page(&file, src, segment, offset, algorithm, &mut page)?;
+            if len < PAGE_SIZE {
+                // A partial page is a segment still being extended; the rest is unallocated.
+                break;
+            }
+            mapped.size += PAGE_SIZE as u64;
+            if offset > 0 {
+                note_page_lsn(newest, &page);
+                if !self.changed(&page) {
+                    continue;
+                }
+            }
+            self.out.write_all(&page).map_err(StorageError::Io)?;
+            mapped.pages.push((offset / PAGE_SIZE as u64) as u32);
+        }
+        self.segments.push(mapped);
+        Ok(())
+    }
+
+    fn changed(&self, page: &[u8]) -> bool {
+        if page::is_fresh(page) {
+            return false;
+        }
+        match readable_page_lsn(page).filter(|&lsn| lsn != Lsn(0)) {
+            Some(lsn) => self.since.get(&wal::lsn_core(lsn)).is_none_or(|&since| lsn >= since),
+            None => true,
+        }
+    }
+
+    fn finish(mut self) -> Result<(), StorageError> {
+        let mut map = Vec::new();
+        map.extend_from_slice(&(self.segments.len() as u32).to_le_bytes());
+        for segment in &self.segments {
+            map.extend_from_slice(&segment.space_id.to_le_bytes());
+            map.extend_from_slice(&segment.seg_no.to_le_bytes());
+            map.extend_from_slice(&segment.size.to_le_bytes());
+            map.extend_from_slice(&(segment.pages.len() as u32).to_le_bytes());
+            for index in &segment.pages {
+                map.extend_from_slice(&index.to_le_bytes());
+            }
+        }
+        let crc = checksum::crc32c(&map);
+        map.extend_from_slice(&((map.len()) as u32).to_le_bytes());
+        map.extend_from_slice(&crc.to_le_bytes());
+        self.out.write_all(&map).map_err(StorageError::Io)?;
+        self.out.sync_all().map_err(StorageError::Io)
+    }
+}
+
+// Reads the page map at the end of an incremental file.
+fn read_page_map(path: &Path) -> Result<Vec<MappedSegment>, StorageError> {
+    let bytes = fs::read(path).map_err(StorageError::Io)?;
+    let corrupt = || invalid(path, "corrupt incremental page map");
+    let (body, trailer) = bytes.split_last_chunk::<8>().ok_or_else(corrupt)?;
+    let map_len = u32::from_le_bytes(trailer[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    let map = body.len().checked_sub(map_len).map(|start| &body[start..]).ok_or_else(corrupt)?;
+    if checksum::crc32c(map) != crc {
+        return Err(corrupt());
+    }
+    let mut rest = map;
+    let mut segments = Vec::new();
+    for _ in 0..take_u32(&mut rest).ok_or_else(corrupt)? {
+        let mut segment = MappedSegment {
+            space_id: take_u32(&mut rest).ok_or_else(corrupt)?,
+            seg_no: take_u32(&mut rest).ok_or_else(corrupt)?,
+            size: take_u64(&mut rest).ok_or_else(corrupt)?,
+            pages: Vec::new(),
+        };
+        for _ in 0..take_u32(&mut rest).ok_or_else(corrupt)? {
+            segment.pages.push(take_u32(&mut rest).ok_or_else(corrupt)?);
+        }
+        segments.push(segment);
+    }
+    let pages: usize = segments.iter().map(|segment| segment.pages.len()).sum();
+    if !rest.is_empty() || pages * PAGE_SIZE + map_len != body.len() {
+        return Err(corrupt());
+    }
+    Ok(segments)
+}
+
+/// Merges the incremental backups `chain`, oldest first, each building on the one
+/// before and the first on `full`, onto the full backup `full`, in place. Every backup
+/// is verified first. `full` has no manifest while a merge is under way, so one cut
+/// short reads as incomplete.
+pub fn combine(full: &Path, chain: &[PathBuf]) -> Result<BackupManifest, StorageError> {
+    let mut manifest = verify(full)?;
+    if manifest.parent.is_some() {
+        return Err(invalid(full, "not a full backup"));
+    }
+    let incrementals = chain.iter().map(|dir| verify(dir)).collect::<Result<Vec<_>, _>>()?;
+    let mut parent = (manifest.db_id, manifest.started_at);
+    for (dir, incremental) in chain.iter().zip(&incrementals) {
+        if (incremental.db_id, incremental.parent) != (parent.0, Some(parent.1)) {
+            return Err(invalid(dir, "incremental backup does not build on the one before it"));
+        }
+        parent = (incremental.db_id, incremental.started_at);
+    }
+
+    let db_id = manifest.db_id;
+    let full_db_dir = full.join("data").join(format!("db_{}", db_id));
+    for (dir, incremental) in chain.iter().zip(incrementals) {
+        remove_if_exists(&full.join(MANIFEST_FILE))?;
+
+        // The pages, and segments gone since
+        let pages_path = dir.join(INCREMENTAL_FILE);
+        let segments = read_page_map(&pages_path)?;
+        let pages = fs::File::open(&pages_path).map_err(StorageError::Io)?;
+        let mut at = 0u64;
+        let mut page = vec![0u8; PAGE_SIZE];
+        for mapped in &segments {
+            let path = segment::segment_path(&full.join("data"), db_id, mapped.space_id, mapped.seg_no);
+            let out = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path).map_err(StorageError::Io)?;
+            out.set_len(mapped.size).map_err(StorageError::Io)?;
+            for &index in &mapped.pages {
+                pages.read_exact_at(&mut page, at).map_err(StorageError::Io)?;
+                out.write_all_at(&page, index as u64 * PAGE_SIZE as u64).map_err(StorageError::Io)?;
+                at += PAGE_SIZE as u64;
+            }
+            out.sync_all().map_err(StorageError::Io)?;
+        }
+        let listed: Vec<(u32, u32)> = segments.iter().map(|mapped| (mapped.space_id, mapped.seg_no)).collect();
+        for name in list_files(&full_db_dir)? {
+            let keep = segment::parse_segment_file_name(&name).is_some_and(|ids| listed.contains(&ids));
+            if !keep {
+                fs::remove_file(full_db_dir.join(&name)).map_err(StorageError::Io)?;
+            }
+        }
+
+        // Everything else is copied whole.
+        let src_db_dir = dir.join("data").join(format!("db_{}", db_id));
+        for name in list_files(&src_db_dir)? {
+            copy_file(&src_db_dir.join(&name), &full_db_dir.join(&name))?;
+        }
+        remove_if_exists(&full.join("wal"))?;
+        let wal_files = match dir.join("wal").is_dir() {
+            true => walk(&dir.join("wal"))?,
+            false => Vec::new(),
+        };
+        for path in wal_files {
+            let dst = full.join(path.strip_prefix(dir).unwrap());
+            fs::create_dir_all(dst.parent().unwrap()).map_err(StorageError::Io)?;
+            copy_file(&path, &dst)?;
+        }
+
+        manifest = BackupManifest {
+            db_id,
+            started_at: incremental.started_at,
+            parent: None,
+            streams: incremental.streams,
+            files: file_list(full)?,
+        };
+        wal_recovery::write_durably(&full.join(MANIFEST_FILE), &manifest.encode())?;
+    }
+    Ok(manifest)
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), StorageError> {
+    let res = match path.is_dir() {
+        true => fs::remove_dir_all(path),
+        false => fs::remove_file(path),
+    };
+    match res {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(StorageError::Io(e)),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future;
+
+use crate::buffer_pool::{BackgroundWriterConfig, PrefetchConfig, ScanRingConfig};
+use crate::checkpointer::CheckpointerConfig;
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::discard::DiscardConfig;
+use crate::eviction::EvictionKind;
+use crate::page::{PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::quarantine::CorruptPagePolicy;
+use crate::ring::{CompletionConfig, CompletionMode};
+use crate::segment::{self, EXTENT_PAGES, PAGES_PER_SEGMENT};
+use crate::traits::{AlignedBuf, PageId, PageStore, StorageConfig, StorageError, StorageManager};
+use crate::wal::WalLayout;
+use crate::wal_compress::WalCompression;
+
+// -----------------------------------------------------------------------------
+// I/O Benchmark
+//
+// `cascade-bench`, for performance work on the storage layer: each thread mounts a
+// core's `CoreStorage` and drives its page I/O directly, with no buffer pool, WAL or
+// higher layer in the way. A binary's `main` hands `run` its arguments after the
+// program name:
+//
+//   cascade-bench --data-dir <dir> --wal-dir <dir> [--workload read|write|scan|mixed]
+//                 [--threads <n>] [--queue-depth <n>] [--pages <n>] [--seconds <n>]
+//                 [--read-percent <n>] [--scan-pages <n>] [--checksum crc32|crc32c]
+//                 [--completions eager|batched|polled|all]
+//
+// Every thread (a core, pinned like one) first writes a working set of `--pages`
+// pages to a space of its own (BENCH_SPACE + core, in database BENCH_DB), then keeps
+// `--queue-depth` requests in flight on its ring for `--seconds`:
+//
+//   read    a random page of the working set, `read_page`
+//   write   a random page, stamped anew each time, `write_page`
+//   scan    the next `--scan-pages` pages, `read_pages`, each request from its own
+//           stretch of the working set and wrapping around
+//   mixed   reads `--read-percent` of the time, writes otherwise
+//
+// Latencies are kept per request, and the report gives requests and pages a second,
+// throughput and latency percentiles over every thread. `--completions` sets up the
+// rings to post completions eagerly, in batches or with submission polling (see
+// `ring.rs`); `all` runs the workload under each in turn and compares them, IOPS
+// against latency. Point it at a scratch data directory: the working sets are freed
+// afterwards, but the segments stay.
+// -----------------------------------------------------------------------------
+
+/// Database the working sets go in.
+pub const BENCH_DB: u32 = 0xBE0C;
+/// Space of core 0's working set; core n uses BENCH_SPACE + n.
+pub const BENCH_SPACE: u32 = 1;
+
+// Pages written at once while preparing a working set
+const PREPARE_BATCH: usize = 64;
+
+// How long a polled ring's kernel thread spins without work before it sleeps
+const POLL_IDLE: Duration = Duration::from_millis(10);
+
+const USAGE: &str = "usage: cascade-bench --data-dir <dir> --wal-dir <dir> [--workload read|write|scan|mixed] \
+[--threads <n>] [--queue-depth <n>] [--pages <n>] [--seconds <n>] [--read-percent <n>] [--scan-pages <n>] \
+[--checksum crc32|crc32c] [--completions eager|batched|polled|all]";
+
+/// What each request does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    RandomRead,
+    RandomWrite,
+    SequentialScan,
+    /// Reads this percent of the time, writes otherwise.
+    Mixed { read_percent: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub workload: Workload,
+    pub threads: usize,
+    /// Requests each thread keeps in flight.
+    pub queue_depth: usize,
+    /// Pages in each thread's working set, at most a segment's worth.
+    pub pages: u32,
+    pub duration: Duration,
+    /// Pages a scan request reads.
+    pub scan_pages: u32,
+    pub checksum: ChecksumAlgorithm,
+    /// How the rings post completions.
+    pub completions: CompletionConfig,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            workload: Workload::RandomRead,
+            threads: 1,
+            queue_depth: 32,
+            pages: 65_536,
+            duration: Duration::from_secs(10),
+            scan_pages: 32,
+            checksum: ChecksumAlgorithm::Crc32c,
+            completions: CompletionConfig::default(),
+        }
+    }
+}
+
+/// Requests made and how long each took, over one thread or all of them.
+#[derive(Debug, Clone, Default)]
+pub struct BenchResult {
+    pub requests: u64,
+    pub pages: u64,
+    /// Longest time a thread spent making requests.
+    pub elapsed: Duration,
+    // Sorted once the result is complete
+    latencies: Vec<Duration>,
+}
+
+impl BenchResult {
+    fn record(&mut self, pages: u32, latency: Duration) {
+        self.requests += 1;
+        self.pages += pages as u64;
+        self.latencies.push(latency);
+    }
+
+    fn merge(&mut self, other: BenchResult) {
+        self.requests += other.requests;
+        self.pages += other.pages;
+        self.elapsed = self.elapsed.max(other.elapsed);
+        self.latencies.extend(other.latencies);
+    }
+
+    fn complete(mut self) -> Self {
+        self.latencies.sort_unstable();
+        self
+    }
+
+    /// Requests a second.
+    pub fn iops(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Bytes a second.
+    pub fn throughput(&self) -> f64 {
+        (self.pages * PAGE_SIZE as u64) as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The latency `p` (0.0 to 1.0) of requests took at most.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let at = ((self.latencies.len() - 1) as f64 * p.clamp(0.0, 1.0)).round() as usize;
+        self.latencies[at]
+    }
+}
+
+/// Runs `cascade-bench` with its arguments, program name excluded.
+pub fn run(args: impl IntoIterator<Item = String>) -> Result<(), String> {
+    let mut args = Args::parse(args.into_iter())?;
+    let data_dir = args.path("--data-dir")?;
+    let wal_dir = args.path("--wal-dir")?;
+    let defaults = BenchOptions::default();
+    let read_percent = args.number("--read-percent", 70)?;
+    let workload = match args.take("--workload").as_deref() {
+        None | Some("read") => Workload::RandomRead,
+        Some("write") => Workload::RandomWrite,
+        Some("scan") => Workload::SequentialScan,
+        Some("mixed") if read_percent <= 100 => Workload::Mixed { read_percent },
+        Some("mixed") => return Err(format!("bad --read-percent {}", read_percent)),
+        Some(other) => return Err(format!("unknown workload {}\n{}", other, USAGE)),
+    };
+    let checksum = match args.take("--checksum").as_deref() {
+        None | Some("crc32c") => ChecksumAlgorithm::Crc32c,
+        Some("crc32") => ChecksumAlgorithm::Crc32,
+        Some(other) => return Err(format!("unknown checksum {}", other)),
+    };
+    let polled = CompletionMode::Polled { idle: POLL_IDLE };
+    let modes = match args.take("--completions").as_deref() {
+        None | Some("eager") => vec![CompletionMode::Eager],
+        Some("batched") => vec![CompletionMode::Batched],
+        Some("polled") => vec![polled],
+        Some("all") => vec![CompletionMode::Eager, CompletionMode::Batched, polled],
+        Some(other) => return Err(format!("unknown completion mode {}\n{}", other, USAGE)),
+    };
+    let mut options = BenchOptions {
+        workload,
+        threads: args.number("--threads", defaults.threads)?.max(1),
+        queue_depth: args.number("--queue-depth", defaults.queue_depth)?.max(1),
+        pages: args.number("--pages", defaults.pages)?.clamp(EXTENT_PAGES, PAGES_PER_SEGMENT),
+        duration: Duration::from_secs(args.number("--seconds", defaults.duration.as_secs())?),
+        scan_pages: args.number("--scan-pages", defaults.scan_pages)?.max(1),
+        checksum,
+        completions: CompletionConfig::default(),
+    };
+    args.finish()?;
+
+    let mut results = Vec::new();
+    for mode in modes {
+        options.completions.mode = mode;
+        // Mounted anew for each mode, since the rings are set up from the config
+        let manager = StorageManager::mount(config(data_dir.clone(), wal_dir.clone(), &options)).map_err(|e| format!("mount failed: {:?}", e))?;
+        let result = bench(&manager, &options).map_err(|e| format!("bench failed: {:?}", e))?;
+        manager.mark_clean_shutdown().map_err(|e| format!("shutdown failed: {:?}", e))?;
+
+        println!(
+            "{:?}, {} completions: {} thread(s), queue depth {}, {} page(s) each, {:.1}s",
+            options.workload,
+            mode.name(),
+            options.threads,
+            options.queue_depth,
+            options.pages,
+            result.elapsed.as_secs_f64(),
+        );
+        println!(
+            "{} request(s), {:.0} IOPS, {:.0} pages/s, {:.1} MiB/s",
+            result.requests,
+            result.iops(),
+            result.pages as f64 / result.elapsed.as_secs_f64().max(f64::EPSILON),
+            result.throughput() / (1 << 20) as f64,
+        );
+        let percentiles: Vec<String> = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p99.9", 0.999), ("max", 1.0)]
+            .iter()
+            .map(|(name, p)| format!("{} {:.1}us", name, result.percentile(*p).as_secs_f64() * 1e6))
+            .collect();
+        println!("latency {}", percentiles.join("  "));
+        results.push((mode, result));
+    }
+
+    if results.len() > 1 {
+        println!("\n{:<8} {:>10} {:>10} {:>10} {:>10}", "mode", "IOPS", "p50 us", "p99 us", "p99.9 us");
+        for (mode, result) in &results {
+            let us = |p: f64| result.percentile(p).as_secs_f64() * 1e6;
+            println!("{:<8} {:>10.0} {:>10.1} {:>10.1} {:>10.1}", mode.name(), result.iops(), us(0.5), us(0.99), us(0.999));
+        }
+    }
+    Ok(())
+}
+
+/// Runs the benchmark on a thread per core of `manager`, which must have at least
+/// `options.threads` cores.
+pub fn bench(manager: &StorageManager, options: &BenchOptions) -> Result<BenchResult, StorageError> {
+    thread::scope(|scope| {
+        let threads: Vec<_> = (0..options.threads)
+            .map(|core_id| {
+                thread::Builder::new()
+                    .name(format!("storage-bench-{}", core_id))
+                    .spawn_scoped(scope, move || {
+                        manager.runtime().start(async move {
+                            let storage = manager.local_worker(core_id);
+                            bench_core(&storage, options, BENCH_SPACE + core_id as u32, core_id as u64).await
+                        })
+                    })
+                    .expect("failed to spawn bench thread")
+            })
+            .collect();
+        let mut result = BenchResult::default();
+        for thread in threads {
+            result.merge(thread.join().unwrap()?);
+        }
+        Ok(result.complete())
+    })
+}
+
+/// One core's part: writes its working set to `space_id`, runs the workload on it, and
+/// frees it.
+pub async fn bench_core<S: PageStore>(store: &S, options: &BenchOptions, space_id: u32, seed: u64) -> Result<BenchResult, StorageError> {
+    let first = prepare(store, options, space_id).await?;
+    let workers = (0..options.queue_depth).map(|slot| worker(store, options, first, slot, seed));
+    let mut result = BenchResult::default();
+    for worker in future::join_all(workers).await {
+        result.merge(worker?);
+    }
+    store.free_extent(BENCH_DB, space_id, first.page_no, options.pages.next_multiple_of(EXTENT_PAGES)).await?;
+    Ok(result.complete())
+}
+
+// Allocates the working set and writes every page of it; returns its first page
+async fn prepare<S: PageStore>(store: &S, options: &BenchOptions, space_id: u32) -> Result<PageId, StorageError> {
+    let pages = options.pages.next_multiple_of(EXTENT_PAGES);
+    let first_page = store.allocate_extent(BENCH_DB, space_id, pages).await?;
+    let mut rng = Rng::new(space_id as u64);
+    let mut page_no = first_page;
+    while page_no < first_page + options.pages {
+        let count = (PREPARE_BATCH as u32).min(first_page + options.pages - page_no);
+        let bufs = (0..count).map(|_| filled_page(options.checksum, &mut rng)).collect();
+        store.write_pages(PageId { db_id: BENCH_DB, space_id, page_no }, bufs).await.1?;
+        page_no += count;
+    }
+    Ok(PageId { db_id: BENCH_DB, space_id, page_no: first_page })
+}
+
+// One request slot: requests one after the other until the time is up
+async fn worker<S: PageStore>(store: &S, options: &BenchOptions, first: PageId, slot: usize, seed: u64) -> Result<BenchResult, StorageError> {
+    let mut rng = Rng::new(seed << 32 | slot as u64);
+    let mut result = BenchResult::default();
+    let mut page = filled_page(options.checksum, &mut rng);
+    let scan_pages = options.scan_pages.min(options.pages);
+    let mut scans: Vec<AlignedBuf> = (0..scan_pages).map(|_| AlignedBuf::page()).collect();
+    // Each slot scans from its own stretch of the working set
+    let mut scan_at = (slot as u64 * options.pages as u64 / options.queue_depth as u64) as u32;
+
+    let started = Instant::now();
+    while started.elapsed() < options.duration {
+        let read = match options.workload {
+            Workload::RandomRead => true,
+            Workload::RandomWrite => false,
+            Workload::Mixed { read_percent } => rng.below(100) < read_percent,
+            Workload::SequentialScan => {
+                let count = scan_pages.min(options.pages - scan_at) as usize;
+                let bufs: Vec<AlignedBuf> = scans.drain(..count).collect();
+                let page_id = PageId { page_no: first.page_no + scan_at, ..first };
+                let request_started = Instant::now();
+                let (bufs, res) = store.read_pages(page_id, bufs).await;
+                result.record(count as u32, request_started.elapsed());
+                scans.extend(bufs);
+                res?;
+                scan_at = (scan_at + count as u32) % options.pages;
+                continue;
+            }
+        };
+        let page_id = PageId { page_no: first.page_no + rng.below(options.pages), ..first };
+        let request_started = Instant::now();
+        page = match read {
+            true => {
+                let (buf, res) = store.read_page(page_id, page).await;
+                res?;
+                buf
+            }
+            false => {
+                // A change to the page, as a writer would make
+                page[PAGE_SIZE - 8..].copy_from_slice(&rng.next().to_le_bytes());
+                checksum::stamp_page(options.checksum, &mut page);
+                let (buf, res) = store.write_page(page_id, page).await;
+                res?;
+                buf
+            }
+        };
+        result.record(1, request_started.elapsed());
+    }
+    result.elapsed = started.elapsed();
+    Ok(result)
+}
+
+// A page of random bytes after a zeroed header, stamped
+fn filled_page(checksum: ChecksumAlgorithm, rng: &mut Rng) -> AlignedBuf {
+    let mut page = AlignedBuf::page();
+    page.fill(0);
+    for chunk in page[PAGE_HEADER_SIZE..].chunks_mut(8) {
+        chunk.copy_from_slice(&rng.next().to_le_bytes()[..chunk.len()]);
+    }
+    checksum::stamp_page(checksum, &mut page);
+    page
+}
+
+// The engine configured for `options`: a core a thread, and nothing running beside
+// the page I/O that would skew it
+fn config(data_dir: PathBuf, wal_dir: PathBuf, options: &BenchOptions) -> StorageConfig {
+    StorageConfig {
+        data_dir,
+        wal_dir,
+        io_uring_entries: (options.queue_depth * 2).next_power_of_two().max(256) as u32,
+        completions: options.completions,
+        checksum: options.checksum,
+        checksum_offload_threshold: None,
+        spaces: HashMap::new(),
+        discard: DiscardConfig::default(),
+        segment_allocation: segment::SegmentAllocation::Preallocate,
+        end_to_end_checksums: false,
+        full_page_writes: false,
+        corrupt_pages: CorruptPagePolicy::default(),
+        redo_past_corruption: false,
+        pinned_pages: Vec::new(),
+        commit_delay: None,
+        async_commit_window: Duration::from_millis(10),
+        wal_archiver: None,
+        wal_compression: WalCompression::default(),
+        wal_keys: HashMap::new(),
+        wal_future_segments: 0,
+        wal_layout: WalLayout::PerDatabase,
+        wal_direct_io: false,
+        cores: options.threads,
+        buffer_pool_frames: 1024,
+        buffer_pool_max_frames: 1024,
+        buffer_pool_warm_up: false,
+        numa_aware: true,
+        buffer_pool_eviction: EvictionKind::default(),
+        background_writer: BackgroundWriterConfig::default(),
+        prefetch: PrefetchConfig::default(),
+        scan_ring: ScanRingConfig::default(),
+        buffer_pool_bulk_budget: 100,
+        checkpointer: CheckpointerConfig::default(),
+        slow_io_threshold: None,
+    }
+}
+
+// xorshift64*: cheap, and good enough to pick pages
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Never zero, which xorshift can't leave
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        (((self.next() >> 32) * n as u64) >> 32) as u32
+    }
+}
+
+// `--name value` pairs, each given once
+struct Args {
+    values: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut values = HashMap::new();
+        while let Some(name) = args.next() {
+            if !name.starts_with("--") {
+                return Err(format!("unexpected argument {}\n{}", name, USAGE));
+            }
+            let value = args.next().ok_or_else(|| format!("{} needs a value", name))?;
+            if values.insert(name.clone(), value).is_some() {
+                return Err(format!("{} given more than once", name));
+            }
+        }
+        Ok(Self { values })
+    }
+
+    fn take(&mut self, name: &str) -> Option<String> {
+        self.values.remove(name)
+    }
+
+    fn path(&mut self, name: &str) -> Result<PathBuf, String> {
+        self.take(name).map(PathBuf::from).ok_or_else(|| format!("{} is required\n{}", name, USAGE))
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, name: &str, default: T) -> Result<T, String> {
+        match self.take(name) {
+            Some(value) => value.parse().map_err(|_| format!("bad {} {}", name, value)),
+            None => Ok(default),
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self.values.keys().next() {
+            Some(name) => Err(format!("unknown option {}\n{}", name, USAGE)),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,872 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use futures::lock::Mutex;
+use futures::stream::{self, Stream};
+
+use crate::buffer_pool::BufferPool;
+use crate::checksum;
+use crate::page::{self, PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::segment::EXTENT_PAGES;
+use crate::sequence::{self, Sequence};
+use crate::trace;
+use crate::traits::{AlignedBuf, PageId, PageStore, StorageError, WalStore};
+use crate::wal_record::WalRecordType;
+use crate::wal_registry::{PageRecord, WalRecord, WalRegistry};
+
+// -----------------------------------------------------------------------------
+// Blob Store
+//
+// Large objects, megabytes to gigabytes, kept out of the heap and its overflow
+// chains: an overflow value is a linked list of pooled pages, read one page at a
+// time. A blob store has a space of its own, and a blob there is a few runs of pages
+// allocated as extents of up to BLOB_EXTENT_PAGES pages, written with `write_pages`
+// bypassing the buffer pool, and never changed. The first page of the first run is
+// the blob's header, the rest data pages holding PAYLOAD_SIZE bytes each after the
+// page header:
+//
+//   header    [24..32)  serial     from the store's sequence, told apart from
+//                                  earlier blobs with the same header page
+//             [32..40)  len        bytes of the blob
+//             [40..44)  refs       references left; garbage at 0
+//             [44..48)  crc        CRC32C of the bytes
+//             [48..50)  count      runs, at most MAX_EXTENTS
+//             [56..)    runs       first_page u32 | pages u32, the header included
+//
+// A blob is named by its header page and serial (`BlobId`). Page 0 of the space is
+// the store's root:
+//
+//   [24..28)  sequence_page  page of the serial sequence
+//   [28..30)  count          headers on the garbage list, at most MAX_GARBAGE
+//   [32..)    garbage        header page_no u32 each
+//
+// Headers and the root are pooled and changed with page records, logged and flushed
+// before anything relies on them (page = db_id u32 | space_id u32 | page_no u32):
+//
+//   BLOB_ROOT_INIT       page | sequence_page u32          root formatted
+//   BLOB_GARBAGE_ADD     page | header u32                 blob listed for collection
+//   BLOB_GARBAGE_REMOVE  page | header u32                 listing dropped
+//   BLOB_INIT            page | serial u64 | len u64 | refs u32 | crc u32 |
+//                        count u16 | runs                  header formatted
+//   BLOB_SET_REFS        page | refs u32                   reference taken or dropped
+//
+// `put` lists the new blob as garbage first, formats its header with no references,
+// writes the data pages, then sets one reference and drops the listing, so a put cut
+// short is collected like a deleted blob. Data pages carry the LSN of their header's
+// BLOB_INIT: later than any record left over for the pages from a blob that had them
+// before, which redo then skips. `delete` drops a reference, and lists the blob once
+// none are left. `collect_garbage` frees the listed blobs nothing reads anymore; the
+// listing is dropped and flushed before their extents are freed, so a crash between
+// the two leaks the blob rather than freeing its pages twice. So does a put cut short
+// before its header is logged.
+//
+// Like a B-tree, a store is used through the pool it is opened on only (see
+// `btree.rs`), the pool of the core owning its root page.
+// -----------------------------------------------------------------------------
+
+/// Page types of blob pages.
+pub const PAGE_TYPE_BLOB_ROOT: u16 = 13;
+pub const PAGE_TYPE_BLOB_HEADER: u16 = 14;
+pub const PAGE_TYPE_BLOB_DATA: u16 = 15;
+
+/// Page of a store's space holding its root.
+pub const ROOT_PAGE: u32 = 0;
+
+/// Most pages of one extent of a blob.
+pub const BLOB_EXTENT_PAGES: u32 = 1024;
+
+/// Bytes of a blob on each data page.
+pub const PAYLOAD_SIZE: usize = PAGE_SIZE - PAGE_HEADER_SIZE;
+
+const SEQUENCE_PAGE_OFFSET: usize = 24;
+const GARBAGE_COUNT_OFFSET: usize = 28;
+const GARBAGE_OFFSET: usize = 32;
+
+/// Blobs the garbage list can hold.
+pub const MAX_GARBAGE: usize = (PAGE_SIZE - GARBAGE_OFFSET) / 4;
+
+const SERIAL_OFFSET: usize = 24;
+const LEN_OFFSET: usize = 32;
+const REFS_OFFSET: usize = 40;
+const CRC_OFFSET: usize = 44;
+const EXTENT_COUNT_OFFSET: usize = 48;
+const EXTENTS_OFFSET: usize = 56;
+
+/// Runs of pages a blob can have.
+pub const MAX_EXTENTS: usize = (PAGE_SIZE - EXTENTS_OFFSET) / 8;
+
+/// Bytes a blob can have.
+pub const MAX_BLOB_SIZE: u64 = (MAX_EXTENTS as u64 * BLOB_EXTENT_PAGES as u64 - 1) * PAYLOAD_SIZE as u64;
+
+// Data pages written, and read, with one I/O
+const IO_PAGES: usize = 128;
+
+/// A blob: its header page, and the serial it was created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlobId {
+    pub page_no: u32,
+    pub serial: u64,
+}
+
+impl BlobId {
+    /// Bytes of an encoded id.
+    pub const SIZE: usize = 12;
+
+    /// The id as stored by applications referring to the blob.
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut out = [0; Self::SIZE];
+        out[..4].copy_from_slice(&self.page_no.to_le_bytes());
+        out[4..].copy_from_slice(&self.serial.to_le_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            page_no: get_u32(bytes, 0),
+            serial: u64::from_le_bytes(bytes[4..].try_into().unwrap()),
+        })
+    }
+}
+
+/// The blob store in space `space_id` of database `db_id`, over `pool`.
+pub struct BlobStore<'a, S: PageStore + WalStore> {
+    pool: &'a BufferPool<S>,
+    db_id: u32,
+    space_id: u32,
+    serials: Sequence<'a, S>,
+    // Open readers of each blob, by header page
+    readers: RefCell<HashMap<u32, usize>>,
+    // Headers of the blobs being put
+    writing: RefCell<HashSet<u32>>,
+    collecting: Mutex<()>,
+}
+
+impl<'a, S: PageStore + WalStore> BlobStore<'a, S> {
+    /// Opens the store in space `space_id` of database `db_id`, creating it empty if
+    /// the space is new.
+    pub async fn open(pool: &'a BufferPool<S>, db_id: u32, space_id: u32) -> Result<Self, StorageError> {
+        let page_id = PageId { db_id, space_id, page_no: ROOT_PAGE };
+        let mut root = pool.get_page_mut(page_id).await?;
+        if page::page_type(&root) != PAGE_TYPE_BLOB_ROOT {
+            if !page::is_fresh(&root) {
+                return Err(StorageError::NotABlobStore(page_id));
+            }
+            // A create cut short may have allocated these already, and they are then
+            // leaked.
+            pool.store().allocate_extent(db_id, space_id, 1).await?;
+            let sequence_page = Sequence::create(pool, db_id, space_id, 1).await?.page_no;
+            root.apply(&BlobRootInit { page_id, sequence_page }).await?;
+        }
+        let sequence_page = get_u32(&root, SEQUENCE_PAGE_OFFSET);
+        drop(root);
+        let serials = Sequence::open(pool, PageId { db_id, space_id, page_no: sequence_page }, sequence::DEFAULT_CHUNK).await?;
+        Ok(Self {
+            pool,
+            db_id,
+            space_id,
+            serials,
+            readers: RefCell::new(HashMap::new()),
+            writing: RefCell::new(HashSet::new()),
+            collecting: Mutex::new(()),
+        })
+    }
+
+    pub fn db_id(&self) -> u32 {
+        self.db_id
+    }
+
+    pub fn space_id(&self) -> u32 {
+        self.space_id
+    }
+
+    /// Stores `bytes` as a new blob with one reference, durable once this returns.
+    pub async fn put(&self, bytes: &[u8]) -> Result<BlobId, StorageError> {
+        if bytes.len() as u64 > MAX_BLOB_SIZE {
+            return Err(StorageError::BlobTooLarge(bytes.len() as u64));
+        }
+        let serial = self.serials.next().await?;
+        let extents = self.allocate(1 + bytes.len().div_ceil(PAYLOAD_SIZE) as u32).await?;
+        let header_id = self.page(extents[0].0);
+        self.writing.borrow_mut().insert(header_id.page_no);
+        let _writing = Writing { store: self, page_no: header_id.page_no };
+
+        if let Err(e) = self.list_garbage(header_id.page_no).await {
+            self.release(&extents).await;
+            return Err(e);
+        }
+        let mut header = self.pool.get_page_overwrite(header_id).await?;
+        let init = BlobInit {
+            page_id: header_id,
+            serial,
+            len: bytes.len() as u64,
+            refs: 0,
+            crc: checksum::crc32c(bytes),
+            extents: extents.clone(),
+        };
+        let lsn = header.apply(&init).await?;
+        drop(header);
+        // The data pages carry the init's LSN, and are written past the pool, so the
+        // WAL has to be durable beyond it first.
+        self.pool.store().flush_wal(self.db_id).await?;
+
+        let mut chunks = bytes.chunks(PAYLOAD_SIZE);
+        for (first_page, pages) in data_runs(&extents) {
+            let mut written = 0;
+            while written < pages {
+                let batch = (pages - written).min(IO_PAGES as u32);
+                let mut bufs = Vec::with_capacity(batch as usize);
+                for chunk in chunks.by_ref().take(batch as usize) {
+                    let mut page = AlignedBuf::page();
+                    page.fill(0);
+                    page::set_page_type(&mut page, PAGE_TYPE_BLOB_DATA);
+                    page::set_page_lsn(&mut page, lsn);
+                    page[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+                    checksum::stamp_page(self.pool.checksum(), &mut page);
+                    bufs.push(page);
+                }
+                self.pool.store().write_pages(self.page(first_page + written), bufs).await.1?;
+                written += batch;
+            }
+        }
+
+        let mut header = self.pool.get_page_mut(header_id).await?;
+        header.apply(&BlobSetRefs { page_id: header_id, refs: 1 }).await?;
+        drop(header);
+        let mut root = self.pool.get_page_mut(self.page(ROOT_PAGE)).await?;
+        root.apply(&BlobGarbageRemove { page_id: self.page(ROOT_PAGE), header: header_id.page_no }).await?;
+        drop(root);
+        self.pool.store().flush_wal(self.db_id).await?;
+        Ok(BlobId { page_no: header_id.page_no, serial })
+    }
+
+    /// The whole of blob `id`.
+    pub async fn get(&self, id: BlobId) -> Result<Vec<u8>, StorageError> {
+        let mut reader = self.reader(id).await?;
+        let mut bytes = Vec::with_capacity(reader.len() as usize);
+        while let Some(chunk) = reader.next_chunk().await? {
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+
+    /// A reader of blob `id`, a few hundred KB at a time. The blob isn't collected
+    /// while it is open, even once deleted.
+    pub async fn reader(&self, id: BlobId) -> Result<BlobReader<'_, 'a, S>, StorageError> {
+        let header_id = self.page(id.page_no);
+        let header = self.pool.get_page(header_id).await?;
+        check_live(&header, id, header_id)?;
+        let len = u64::from_le_bytes(header[LEN_OFFSET..LEN_OFFSET + 8].try_into().unwrap());
+        let crc = get_u32(&header, CRC_OFFSET);
+        let runs = data_runs(&header_extents(&header));
+        drop(header);
+        *self.readers.borrow_mut().entry(id.page_no).or_default() += 1;
+        Ok(BlobReader {
+            store: self,
+            id,
+            len,
+            left: len,
+            expected_crc: crc,
+            crc: 0,
+            runs,
+            run: 0,
+            offset: 0,
+            done: false,
+        })
+    }
+
+    /// Bytes of blob `id`.
+    pub async fn len(&self, id: BlobId) -> Result<u64, StorageError> {
+        let header_id = self.page(id.page_no);
+        let header = self.pool.get_page(header_id).await?;
+        check_live(&header, id, header_id)?;
+        Ok(u64::from_le_bytes(header[LEN_OFFSET..LEN_OFFSET + 8].try_into().unwrap()))
+    }
+
+    /// References blob `id` has left.
+    pub async fn refs(&self, id: BlobId) -> Result<u32, StorageError> {
+        let header_id = self.page(id.page_no);
+        let header = self.pool.get_page(header_id).await?;
+        check_live(&header, id, header_id)?;
+        Ok(get_u32(&header, REFS_OFFSET))
+    }
+
+    /// Takes another reference to blob `id`, durable once this returns.
+    pub async fn add_ref(&self, id: BlobId) -> Result<u32, StorageError> {
+        let header_id = self.page(id.page_no);
+        let mut header = self.pool.get_page_mut(header_id).await?;
+        check_live(&header, id, header_id)?;
+        let refs = get_u32(&header, REFS_OFFSET) + 1;
+        header.apply(&BlobSetRefs { page_id: header_id, refs }).await?;
+        drop(header);
+        self.pool.store().flush_wal(self.db_id).await?;
+        Ok(refs)
+    }
+
+    /// Drops a reference to blob `id`, durable once this returns. Once none are left,
+    /// the blob is gone, and its pages are freed by the next `collect_garbage` that
+    /// finds no reader on it. Returns the references left.
+    pub async fn delete(&self, id: BlobId) -> Result<u32, StorageError> {
+        let root_id = self.page(ROOT_PAGE);
+        let header_id = self.page(id.page_no);
+        let mut root = self.pool.get_page_mut(root_id).await?;
+        let mut header = self.pool.get_page_mut(header_id).await?;
+        check_live(&header, id, header_id)?;
+        let refs = get_u32(&header, REFS_OFFSET) - 1;
+        if refs == 0 && garbage(&root).len() >= MAX_GARBAGE {
+            return Err(StorageError::BlobGarbageFull(root_id));
+        }
+        header.apply(&BlobSetRefs { page_id: header_id, refs }).await?;
+        if refs == 0 {
+            root.apply(&BlobGarbageAdd { page_id: root_id, header: id.page_no }).await?;
+        }
+        drop(header);
+        drop(root);
+        self.pool.store().flush_wal(self.db_id).await?;
+        Ok(refs)
+    }
+
+    /// Blobs listed for collection, deleted or not yet fully put.
+    pub async fn garbage(&self) -> Result<usize, StorageError> {
+        Ok(garbage(&self.pool.get_page(self.page(ROOT_PAGE)).await?).len())
+    }
+
+    /// Frees the listed blobs with no reference, reader or put in progress, and drops
+    /// listings of blobs whose put finished. Returns the blobs freed.
+    pub async fn collect_garbage(&self) -> Result<usize, StorageError> {
+        let _collecting = self.collecting.lock().await;
+        let root_id = self.page(ROOT_PAGE);
+        let mut root = self.pool.get_page_mut(root_id).await?;
+        let mut freed = Vec::new();
+        for page_no in garbage(&root) {
+            if self.writing.borrow().contains(&page_no) || self.readers.borrow().contains_key(&page_no) {
+                continue;
+            }
+            let header_id = self.page(page_no);
+            let header = self.pool.get_page(header_id).await?;
+            // A put cut short before its header was logged leaves nothing to free.
+            if page::page_type(&header) == PAGE_TYPE_BLOB_HEADER && get_u32(&header, REFS_OFFSET) == 0 {
+                freed.push((header_id, header_extents(&header)));
+            }
+            drop(header);
+            root.apply(&BlobGarbageRemove { page_id: root_id, header: page_no }).await?;
+        }
+        drop(root);
+        self.pool.store().flush_wal(self.db_id).await?;
+        for (header_id, extents) in &freed {
+            // The header stays cached, but clean: it is never written over the pages'
+            // next owner.
+            self.pool.flush_page(*header_id).await?;
+            for &(first_page, pages) in extents {
+                self.pool.store().free_extent(self.db_id, self.space_id, first_page, pages.next_multiple_of(EXTENT_PAGES)).await?;
+            }
+        }
+        Ok(freed.len())
+    }
+
+    /// Collects garbage every `interval`, until the future is dropped.
+    pub async fn run_gc(&self, interval: Duration) {
+        loop {
+            if let Err(e) = self.collect_garbage().await {
+                trace::warn_event!("blob: db {} space {}: garbage collection failed: {:?}", self.db_id, self.space_id, e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    // Extents for `pages` pages, freeing those already allocated if one can't be
+    async fn allocate(&self, pages: u32) -> Result<Vec<(u32, u32)>, StorageError> {
+        let mut extents = Vec::new();
+        let mut left = pages;
+        while left > 0 {
+            let len = left.min(BLOB_EXTENT_PAGES);
+            match self.pool.store().allocate_extent(self.db_id, self.space_id, len.next_multiple_of(EXTENT_PAGES)).await {
+                Ok(first_page) => extents.push((first_page, len)),
+                Err(e) => {
+                    self.release(&extents).await;
+                    return Err(e);
+                }
+            }
+            left -= len;
+        }
+        Ok(extents)
+    }
+
+    // Frees extents no blob was logged with, as far as the store lets it
+    async fn release(&self, extents: &[(u32, u32)]) {
+        for &(first_page, pages) in extents {
+            let _ = self.pool.store().free_extent(self.db_id, self.space_id, first_page, pages.next_multiple_of(EXTENT_PAGES)).await;
+        }
+    }
+
+    async fn list_garbage(&self, header: u32) -> Result<(), StorageError> {
+        let root_id = self.page(ROOT_PAGE);
+        let mut root = self.pool.get_page_mut(root_id).await?;
+        if garbage(&root).len() >= MAX_GARBAGE {
+            return Err(StorageError::BlobGarbageFull(root_id));
+        }
+        root.apply(&BlobGarbageAdd { page_id: root_id, header }).await?;
+        Ok(())
+    }
+
+    fn page(&self, page_no: u32) -> PageId {
+        PageId {
+            db_id: self.db_id,
+            space_id: self.space_id,
+            page_no,
+        }
+    }
+}
+
+// Takes a put's blob out of `writing` however the put ends
+struct Writing<'s, 'a, S: PageStore + WalStore> {
+    store: &'s BlobStore<'a, S>,
+    page_no: u32,
+}
+
+impl<S: PageStore + WalStore> Drop for Writing<'_, '_, S> {
+    fn drop(&mut self) {
+        self.store.writing.borrow_mut().remove(&self.page_no);
+    }
+}
+
+/// Reads a blob in order, checking its CRC once at the end.
+pub struct BlobReader<'s, 'a, S: PageStore + WalStore> {
+    store: &'s BlobStore<'a, S>,
+    id: BlobId,
+    len: u64,
+    // Bytes not read yet
+    left: u64,
+    expected_crc: u32,
+    crc: u32,
+    // Runs of data pages, and the position in them
+    runs: Vec<(u32, u32)>,
+    run: usize,
+    offset: u32,
+    done: bool,
+}
+
+impl<'s, 'a, S: PageStore + WalStore> BlobReader<'s, 'a, S> {
+    pub fn id(&self) -> BlobId {
+        self.id
+    }
+
+    /// Bytes of the blob.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The next bytes of the blob; `None` past its end. A page that fails its checksum,
+    /// or bytes that don't match the blob's CRC, end the read with `Corruption`.
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, StorageError> {
+        if self.done {
+            return Ok(None);
+        }
+        let chunk = self.read().await;
+        if !matches!(chunk, Ok(Some(_))) {
+            self.done = true;
+        }
+        chunk
+    }
+
+    /// Adapts the reader into a `Stream` of chunks.
+    pub fn into_stream(self) -> impl Stream<Item = Result<Vec<u8>, StorageError>> + 's {
+        stream::unfold(self, |mut reader| async move {
+            let item = reader.next_chunk().await.transpose()?;
+            Some((item, reader))
+        })
+    }
+
+    async fn read(&mut self) -> Result<Option<Vec<u8>>, StorageError> {
+        let Some(&(first_page, pages)) = self.runs.get(self.run) else {
+            if self.crc != self.expected_crc {
+                return Err(StorageError::Corruption(self.store.page(self.id.page_no)));
+            }
+            return Ok(None);
+        };
+        let batch = (pages - self.offset).min(IO_PAGES as u32);
+        let start = self.store.page(first_page + self.offset);
+        let bufs = (0..batch).map(|_| AlignedBuf::page()).collect();
+        let (bufs, res) = self.store.pool.store().read_pages(start, bufs).await;
+        res?;
+        let mut chunk = Vec::with_capacity(batch as usize * PAYLOAD_SIZE);
+        for (i, page) in bufs.iter().enumerate() {
+            if page::page_type(page) != PAGE_TYPE_BLOB_DATA || !checksum::verify_page(self.store.pool.checksum(), page) {
+                return Err(StorageError::Corruption(PageId { page_no: start.page_no + i as u32, ..start }));
+            }
+            let take = self.left.min(PAYLOAD_SIZE as u64) as usize;
+            chunk.extend_from_slice(&page[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + take]);
+            self.left -= take as u64;
+        }
+        self.crc = checksum::crc32c_append(self.crc, &chunk);
+        self.offset += batch;
+        if self.offset == pages {
+            self.run += 1;
+            self.offset = 0;
+        }
+        Ok(Some(chunk))
+    }
+}
+
+impl<S: PageStore + WalStore> Drop for BlobReader<'_, '_, S> {
+    fn drop(&mut self) {
+        let mut readers = self.store.readers.borrow_mut();
+        let count = readers.get_mut(&self.id.page_no).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            readers.remove(&self.id.page_no);
+        }
+    }
+}
+
+// Whether the header page holds blob `id` with references left
+fn check_live(header: &[u8], id: BlobId, header_id: PageId) -> Result<(), StorageError> {
+    let live = page::page_type(header) == PAGE_TYPE_BLOB_HEADER
+        && u64::from_le_bytes(header[SERIAL_OFFSET..SERIAL_OFFSET + 8].try_into().unwrap()) == id.serial
+        && get_u32(header, REFS_OFFSET) > 0;
+    if live {
+        Ok(())
+    } else {
+        Err(StorageError::BlobNotFound(header_id))
+    }
+}
+
+fn header_extents(header: &[u8]) -> Vec<(u32, u32)> {
+    let count = get_u16(header, EXTENT_COUNT_OFFSET) as usize;
+    (0..count)
+        .map(|i| {
+            let at = EXTENTS_OFFSET + i * 8;
+            (get_u32(header, at), get_u32(header, at + 4))
+        })
+        .collect()
+}
+
+// The runs of data pages of a blob's extents: all but the header
+fn data_runs(extents: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    extents
+        .iter()
+        .enumerate()
+        .map(|(i, &(first_page, pages))| if i == 0 { (first_page + 1, pages - 1) } else { (first_page, pages) })
+        .filter(|&(_, pages)| pages > 0)
+        .collect()
+}
+
+fn garbage(root: &[u8]) -> Vec<u32> {
+    let count = get_u16(root, GARBAGE_COUNT_OFFSET) as usize;
+    (0..count).map(|i| get_u32(root, GARBAGE_OFFSET + i * 4)).collect()
+}
+
+fn set_garbage(root: &mut [u8], headers: &[u32]) {
+    root[GARBAGE_COUNT_OFFSET..GARBAGE_COUNT_OFFSET + 2].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+    for (i, header) in headers.iter().enumerate() {
+        let at = GARBAGE_OFFSET + i * 4;
+        root[at..at + 4].copy_from_slice(&header.to_le_bytes());
+    }
+}
+
+fn get_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn get_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// Registers the blob store's page records for redo.
+pub fn register(registry: &mut WalRegistry) {
+    registry.register_page::<BlobRootInit>("blob");
+    registry.register_page::<BlobGarbageAdd>("blob");
+    registry.register_page::<BlobGarbageRemove>("blob");
+    registry.register_page::<BlobInit>("blob");
+    registry.register_page::<BlobSetRefs>("blob");
+}
+
+// -----------------------------------------------------------------------------
+// WAL records
+// -----------------------------------------------------------------------------
+
+const PAGE_ID_SIZE: usize = 12;
+
+fn encode_page_id(out: &mut Vec<u8>, page_id: PageId) {
+    for part in [page_id.db_id, page_id.space_id, page_id.page_no] {
+        out.extend_from_slice(&part.to_le_bytes());
+    }
+}
+
+fn decode_page_id(payload: &[u8]) -> Option<PageId> {
+    let field = |at: usize| Some(get_u32(payload.get(at..at + 4)?, 0));
+    Some(PageId {
+        db_id: field(0)?,
+        space_id: field(4)?,
+        page_no: field(8)?,
+    })
+}
+
+/// A store's root page formatted, with an empty garbage list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobRootInit {
+    pub page_id: PageId,
+    pub sequence_page: u32,
+}
+
+impl WalRecord for BlobRootInit {
+    const TYPE: WalRecordType = WalRecordType::BLOB_ROOT_INIT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.sequence_page.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 4 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            sequence_page: get_u32(payload, PAGE_ID_SIZE),
+        })
+    }
+}
+
+impl PageRecord for BlobRootInit {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[PAGE_HEADER_SIZE..].fill(0);
+        page::set_page_type(page, PAGE_TYPE_BLOB_ROOT);
+        page[SEQUENCE_PAGE_OFFSET..SEQUENCE_PAGE_OFFSET + 4].copy_from_slice(&self.sequence_page.to_le_bytes());
+    }
+}
+
+/// A blob's header added to a root's garbage list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobGarbageAdd {
+    pub page_id: PageId,
+    pub header: u32,
+}
+
+impl WalRecord for BlobGarbageAdd {
+    const TYPE: WalRecordType = WalRecordType::BLOB_GARBAGE_ADD;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.header.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 4 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            header: get_u32(payload, PAGE_ID_SIZE),
+        })
+    }
+}
+
+impl PageRecord for BlobGarbageAdd {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let mut headers = garbage(page);
+        headers.push(self.header);
+        set_garbage(page, &headers);
+    }
+}
+
+/// A blob's header taken off a root's garbage list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobGarbageRemove {
+    pub page_id: PageId,
+    pub header: u32,
+}
+
+impl WalRecord for BlobGarbageRemove {
+    const TYPE: WalRecordType = WalRecordType::BLOB_GARBAGE_REMOVE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.header.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 4 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            header: get_u32(payload, PAGE_ID_SIZE),
+        })
+    }
+}
+
+impl PageRecord for BlobGarbageRemove {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let mut headers = garbage(page);
+        if let Some(at) = headers.iter().position(|&header| header == self.header) {
+            headers.remove(at);
+            page[GARBAGE_OFFSET + headers.len() * 4..GARBAGE_OFFSET + headers.len() * 4 + 4].fill(0);
+            set_garbage(page, &headers);
+        }
+    }
+}
+
+/// A blob's header page formatted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobInit {
+    pub page_id: PageId,
+    pub serial: u64,
+    pub len: u64,
+    pub refs: u32,
+    pub crc: u32,
+    /// Runs of the blob's pages, first_page and pages, the header's first.
+    pub extents: Vec<(u32, u32)>,
+}
+
+impl WalRecord for BlobInit {
+    const TYPE: WalRecordType = WalRecordType::BLOB_INIT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.serial.to_le_bytes());
+        out.extend_from_slice(&self.len.to_le_bytes());
+        out.extend_from_slice(&self.refs.to_le_bytes());
+        out.extend_from_slice(&self.crc.to_le_bytes());
+        out.extend_from_slice(&(self.extents.len() as u16).to_le_bytes());
+        for &(first_page, pages) in &self.extents {
+            out.extend_from_slice(&first_page.to_le_bytes());
+            out.extend_from_slice(&pages.to_le_bytes());
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let fixed = PAGE_ID_SIZE + 26;
+        if payload.len() < fixed {
+            return None;
+        }
+        let count = get_u16(payload, fixed - 2) as usize;
+        if count > MAX_EXTENTS || payload.len() != fixed + count * 8 {
+            return None;
+        }
+        let extents = (0..count)
+            .map(|i| {
+                let at = fixed + i * 8;
+                (get_u32(payload, at), get_u32(payload, at + 4))
+            })
+            .collect();
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            serial: u64::from_le_bytes(payload[PAGE_ID_SIZE..PAGE_ID_SIZE + 8].try_into().unwrap()),
+            len: u64::from_le_bytes(payload[PAGE_ID_SIZE + 8..PAGE_ID_SIZE + 16].try_into().unwrap()),
+            refs: get_u32(payload, PAGE_ID_SIZE + 16),
+            crc: get_u32(payload, PAGE_ID_SIZE + 20),
+            extents,
+        })
+    }
+}
+
+impl PageRecord for BlobInit {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[PAGE_HEADER_SIZE..].fill(0);
+        page::set_page_type(page, PAGE_TYPE_BLOB_HEADER);
+        page[SERIAL_OFFSET..SERIAL_OFFSET + 8].copy_from_slice(&self.serial.to_le_bytes());
+        page[LEN_OFFSET..LEN_OFFSET + 8].copy_from_slice(&self.len.to_le_bytes());
+        page[REFS_OFFSET..REFS_OFFSET + 4].copy_from_slice(&self.refs.to_le_bytes());
+        page[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&self.crc.to_le_bytes());
+        page[EXTENT_COUNT_OFFSET..EXTENT_COUNT_OFFSET + 2].copy_from_slice(&(self.extents.len() as u16).to_le_bytes());
+        for (i, &(first_page, pages)) in self.extents.iter().enumerate() {
+            let at = EXTENTS_OFFSET + i * 8;
+            page[at..at + 4].copy_from_slice(&first_page.to_le_bytes());
+            page[at + 4..at + 8].copy_from_slice(&pages.to_le_bytes());
+        }
+    }
+}
+
+/// A blob's references set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobSetRefs {
+    pub page_id: PageId,
+    pub refs: u32,
+}
+
+impl WalRecord for BlobSetRefs {
+    const TYPE: WalRecordType = WalRecordType::BLOB_SET_REFS;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.refs.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 4 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            refs: get_u32(payload, PAGE_ID_SIZE),
+        })
+    }
+}
+
+impl PageRecord for BlobSetRefs {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[REFS_OFFSET..REFS_OFFSET + 4].copy_from_slice(&self.refs.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::checksum::ChecksumAlgorithm;
+    use crate::eviction::EvictionKind;
+    use crate::mem_store::MemStore;
+
+    use super::*;
+
+    fn pool() -> BufferPool<MemStore> {
+        let store = Rc::new(MemStore::new(ChecksumAlgorithm::Crc32c).with_end_to_end_checksums(true));
+        BufferPool::new(store, 64, 64, ChecksumAlgorithm::Crc32c, EvictionKind::default())
+    }
+
+    #[tokio::test]
+    async fn put_writes_the_data_pages_and_get_reads_them_back() {
+        let pool = pool();
+        let blobs = BlobStore::open(&pool, 1, 1).await.unwrap();
+        // More pages than one write takes, the last one partly filled
+        let bytes: Vec<u8> = (0..(IO_PAGES * 2 + 3) * PAYLOAD_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let id = blobs.put(&bytes).await.unwrap();
+
+        for page_no in id.page_no + 1..id.page_no + 1 + bytes.len().div_ceil(PAYLOAD_SIZE) as u32 {
+            let stored = pool.store().page(blobs.page(page_no)).unwrap();
+            assert_eq!(page::page_type(&stored), PAGE_TYPE_BLOB_DATA);
+            assert!(checksum::verify_page(ChecksumAlgorithm::Crc32c, &stored), "page {}", page_no);
+        }
+        assert_eq!(blobs.len(id).await.unwrap(), bytes.len() as u64);
+        assert_eq!(blobs.refs(id).await.unwrap(), 1);
+        assert_eq!(blobs.get(id).await.unwrap(), bytes);
+    }
+}
@@ -0,0 +1,265 @@
+use std::cell::Cell;
+
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::page::{self, PAGE_SIZE};
+use crate::segment::EXTENT_PAGES;
+use crate::traits::{AlignedBuf, PageId, PageState, PageStore, StorageError};
+
+// -----------------------------------------------------------------------------
+// Bloom Filters
+//
+// A filter over the keys of a run of data, an LSM tree's SST or a range of heap
+// pages, so a point lookup skips the runs that can't hold its key: `may_contain`
+// false means the key is not there, true that it may be. With `bits_per_key` bits a
+// key and the best number of probes (bits_per_key x ln 2), about 0.6185^bits_per_key
+// of the lookups for keys not there still read the run: 1% at 10 bits.
+//
+// A key is hashed once to 64 bits, and probe i sets or tests bit (h1 + i x h2) mod
+// bits, h1 and h2 the hash's halves. A filter is kept in pages of its own next to the
+// data it covers, written once with `write_pages` bypassing the buffer pool:
+//
+//   [24..28)  len       bytes of the bit array, the same on every page
+//   [28..29)  probes
+//   [32..)    the array, BYTES_PER_PAGE bytes a page
+//
+// and read back whole (`read_filter`) when its run is opened. `BloomStats` counts the
+// lookups a filter let through for nothing.
+// -----------------------------------------------------------------------------
+
+/// Page type of filter pages.
+pub const PAGE_TYPE_BLOOM: u16 = 18;
+
+/// Bits a key, unless configured otherwise.
+pub const DEFAULT_BITS_PER_KEY: u32 = 10;
+
+const LEN_OFFSET: usize = 24;
+const PROBES_OFFSET: usize = 28;
+const BITS_OFFSET: usize = 32;
+
+/// Bytes of the bit array a page holds.
+pub const BYTES_PER_PAGE: usize = PAGE_SIZE - BITS_OFFSET;
+
+const MAX_PROBES: u32 = 30;
+
+/// A Bloom filter over a run's keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    probes: u32,
+}
+
+impl BloomFilter {
+    /// A filter over the keys with hashes `hashes` (`key_hash`), `bits_per_key` bits
+    /// each.
+    pub fn build(hashes: &[u64], bits_per_key: u32) -> Self {
+        let bits_per_key = bits_per_key.max(1);
+        let len = (hashes.len() * bits_per_key as usize).div_ceil(8).max(8);
+        let probes = ((bits_per_key as f64 * std::f64::consts::LN_2).round() as u32).clamp(1, MAX_PROBES);
+        let mut filter = Self { bits: vec![0; len], probes };
+        for &hash in hashes {
+            filter.insert_hash(hash);
+        }
+        filter
+    }
+
+    /// Adds a key by its hash.
+    pub fn insert_hash(&mut self, hash: u64) {
+        let bits = self.bits.len() as u64 * 8;
+        for bit in probes(hash, self.probes, bits) {
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    /// False if `key` is certainly not in the run.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.may_contain_hash(key_hash(key))
+    }
+
+    pub fn may_contain_hash(&self, hash: u64) -> bool {
+        let bits = self.bits.len() as u64 * 8;
+        probes(hash, self.probes, bits).all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Bytes of the bit array.
+    pub fn len(&self) -> usize {
+        self.bits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.is_empty()
+    }
+
+    /// Pages the filter takes.
+    pub fn pages(&self) -> u32 {
+        self.bits.len().div_ceil(BYTES_PER_PAGE) as u32
+    }
+
+    /// The filter laid out in pages, not stamped yet.
+    pub fn to_pages(&self) -> Vec<AlignedBuf> {
+        self.bits
+            .chunks(BYTES_PER_PAGE)
+            .map(|chunk| {
+                let mut page = AlignedBuf::page();
+                page.fill(0);
+                page::set_page_type(&mut page, PAGE_TYPE_BLOOM);
+                page[LEN_OFFSET..LEN_OFFSET + 4].copy_from_slice(&(self.bits.len() as u32).to_le_bytes());
+                page[PROBES_OFFSET] = self.probes as u8;
+                page[BITS_OFFSET..BITS_OFFSET + chunk.len()].copy_from_slice(chunk);
+                page
+            })
+            .collect()
+    }
+
+    /// The filter laid out in `pages` by `to_pages`; `None` if they hold something else.
+    pub fn from_pages(pages: &[AlignedBuf]) -> Option<Self> {
+        let first = pages.first()?;
+        let len = u32::from_le_bytes(first[LEN_OFFSET..LEN_OFFSET + 4].try_into().unwrap()) as usize;
+        let probes = first[PROBES_OFFSET] as u32;
+        if len == 0 || len.div_ceil(BYTES_PER_PAGE) != pages.len() || !(1..=MAX_PROBES).contains(&probes) {
+            return None;
+        }
+        let mut bits = Vec::with_capacity(len);
+        for page in pages {
+            if page::page_type(page) != PAGE_TYPE_BLOOM || page[LEN_OFFSET..LEN_OFFSET + 4] != first[LEN_OFFSET..LEN_OFFSET + 4] {
+                return None;
+            }
+            let take = (len - bits.len()).min(BYTES_PER_PAGE);
+            bits.extend_from_slice(&page[BITS_OFFSET..BITS_OFFSET + take]);
+        }
+        Some(Self { bits, probes })
+    }
+}
+
+/// The 64-bit hash filters take keys by.
+pub fn key_hash(key: &[u8]) -> u64 {
+    // FNV-1a, then splitmix64's finalizer to spread it over both halves
+    let mut h = 0xCBF2_9CE4_8422_2325u64;
+    for &byte in key {
+        h = (h ^ byte as u64).wrapping_mul(0x0100_0000_01B3);
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^ (h >> 31)
+}
+
+fn probes(hash: u64, probes: u32, bits: u64) -> impl Iterator<Item = u64> {
+    let (h1, h2) = (hash & 0xFFFF_FFFF, (hash >> 32) | 1);
+    (0..probes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bits)
+}
+
+/// Writes `filter` to a new extent of space `space_id`, stamped with `checksum`, and
+/// returns its first page.
+pub async fn write_filter<S: PageStore>(store: &S, checksum: ChecksumAlgorithm, db_id: u32, space_id: u32, filter: &BloomFilter) -> Result<u32, StorageError> {
+    let mut pages = filter.to_pages();
+    let first_page = store.allocate_extent(db_id, space_id, filter.pages().next_multiple_of(EXTENT_PAGES)).await?;
+    for page in &mut pages {
+        checksum::stamp_page(checksum, page);
+    }
+    store.write_pages(PageId { db_id, space_id, page_no: first_page }, pages).await.1?;
+    Ok(first_page)
+}
+
+/// Reads back the filter written from `page_id` on.
+pub async fn read_filter<S: PageStore>(store: &S, page_id: PageId) -> Result<BloomFilter, StorageError> {
+    let (first, res) = store.read_page(page_id, AlignedBuf::page()).await;
+    if res? != PageState::Written || page::page_type(&first) != PAGE_TYPE_BLOOM {
+        return Err(StorageError::Corruption(page_id));
+    }
+    let len = u32::from_le_bytes(first[LEN_OFFSET..LEN_OFFSET + 4].try_into().unwrap()) as usize;
+    let mut pages = vec![first];
+    let rest = len.div_ceil(BYTES_PER_PAGE).saturating_sub(1);
+    if rest > 0 {
+        let bufs = (0..rest).map(|_| AlignedBuf::page()).collect();
+        let (bufs, res) = store.read_pages(PageId { page_no: page_id.page_no + 1, ..page_id }, bufs).await;
+        res?;
+        pages.extend(bufs);
+    }
+    BloomFilter::from_pages(&pages).ok_or(StorageError::Corruption(page_id))
+}
+
+/// What filters saved, and cost, the lookups consulting them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BloomMetrics {
+    /// Lookups that consulted a filter.
+    pub checks: u64,
+    /// Of those, the ones it turned away: a run not read.
+    pub skips: u64,
+    /// Of those it let through, the ones that found nothing: a run read for nothing.
+    pub false_positives: u64,
+}
+
+impl BloomMetrics {
+    /// Share of the lookups for keys not there that a filter let through.
+    pub fn false_positive_rate(&self) -> f64 {
+        let negatives = self.skips + self.false_positives;
+        if negatives == 0 {
+            0.0
+        } else {
+            self.false_positives as f64 / negatives as f64
+        }
+    }
+}
+
+/// Counters behind `BloomMetrics`, kept by whatever consults filters.
+#[derive(Debug, Default)]
+pub struct BloomStats {
+    checks: Cell<u64>,
+    skips: Cell<u64>,
+    false_positives: Cell<u64>,
+}
+
+impl BloomStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consults `filter` for `hash`, counting the answer. False: skip the run.
+    pub fn check(&self, filter: &BloomFilter, hash: u64) -> bool {
+        self.checks.set(self.checks.get() + 1);
+        let maybe = filter.may_contain_hash(hash);
+        if !maybe {
+            self.skips.set(self.skips.get() + 1);
+        }
+        maybe
+    }
+
+    /// Counts a run read after `check` let it through, without the key.
+    pub fn false_positive(&self) {
+        self.false_positives.set(self.false_positives.get() + 1);
+    }
+
+    pub fn metrics(&self) -> BloomMetrics {
+        BloomMetrics {
+            checks: self.checks.get(),
+            skips: self.skips.get(),
+            false_positives: self.false_positives.get(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mem_store::MemStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn a_filter_of_several_pages_reads_back_whole() {
+        let store = MemStore::new(ChecksumAlgorithm::Crc32c).with_end_to_end_checksums(true);
+        let hashes: Vec<u64> = (0..50_000u32).map(|i| key_hash(&i.to_le_bytes())).collect();
+        let filter = BloomFilter::build(&hashes, DEFAULT_BITS_PER_KEY);
+        assert!(filter.pages() > 1);
+
+        let first_page = write_filter(&store, ChecksumAlgorithm::Crc32c, 1, 1, &filter).await.unwrap();
+        let page_id = PageId { db_id: 1, space_id: 1, page_no: first_page };
+        for page_no in first_page..first_page + filter.pages() {
+            let stored = store.page(PageId { page_no, ..page_id }).unwrap();
+            assert!(checksum::verify_page(ChecksumAlgorithm::Crc32c, &stored), "page {}", page_no);
+        }
+        let read = read_filter(&store, page_id).await.unwrap();
+        assert_eq!(read, filter);
+        assert!((0..50_000u32).all(|i| read.may_contain(&i.to_le_bytes())));
+        let false_positives = (50_000..60_000u32).filter(|i| read.may_contain(&i.to_le_bytes())).count();
+        assert!(false_positives < 300, "{}", false_positives);
+    }
+}
@@ -0,0 +1,904 @@
+use std::ops::{Bound, Range, RangeBounds};
+
+use crate::buffer_pool::{BufferPool, LatchPath, PageReadGuard, PageWriteGuard};
+use crate::page::{self, PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::traits::{PageId, PageStore, StorageError, WalStore};
+use crate::wal_record::WalRecordType;
+use crate::wal_registry::{PageRecord, WalRecord, WalRegistry};
+
+// -----------------------------------------------------------------------------
+// B+tree
+//
+// An index of u64 keys to u64 values (a heap tuple id, another page), in the pages of
+// one space, read and changed through a buffer pool. Every node is one page with
+// fixed-size entries, sorted by key, after the page header:
+//
+//   [24..26)  count         entries in the node
+//   [26..28)  level         0 for leaves, counting up to the root
+//   [28..32)  right         next node on the same level (NO_PAGE: none)
+//   [32..40)  high_key      lowest key of the right sibling's range, if FLAG_HIGH_KEY
+//   [40..44)  leftmost      internal: child for keys below the first entry's
+//   [44..46)  flags
+//   [48..)    entries       leaf: key u64 | value u64
+//                           internal: key u64 | child page_no u32, the child holding
+//                           keys from this one up to the next entry's
+//
+// The root stays at the page the tree was created at: when it splits, its halves move
+// to two new pages and it becomes their parent. Nodes are linked to their right
+// siblings and bounded by a high key (a B-link tree), so a key above a node's high key
+// is found by moving right. Normally a split and its parent's new entry happen under
+// the same latches and nobody sees one without the other; the link is for a split
+// whose parent entry was lost to a crash, which stays reachable and is never repaired.
+//
+// Lookups and scans couple read latches down from the root, and scans follow the leaf
+// chain rightwards (see `buffer_pool.rs` for the latch order). Deletes do the same and
+// write-latch only the leaf. An insert into a leaf with room does too; otherwise it
+// descends again holding write latches (`LatchPath`), releasing everything above the
+// last node with room for one more entry, which is as far up as its splits can go.
+// Deletes never merge nodes: an emptied leaf stays in the chain for later inserts into
+// its range, so the tree never shrinks.
+//
+// Every change to a node is a page record, made and logged by its write guard
+// (`PageWriteGuard::apply`, full-page writes included), so redo replays the tree
+// like any page:
+//
+//   BTREE_NODE     page | node [24..48 + count x entry)    a whole node, written anew
+//   BTREE_INSERT   page | index u16 | key u64 | value u64  entry added at index
+//   BTREE_DELETE   page | index u16                        entry removed at index
+//   BTREE_UPDATE   page | index u16 | value u64            leaf entry's new value
+//   BTREE_SPLIT    page | count u16 | right u32 | high u64 left half of a split
+//
+// (page = db_id u32 | space_id u32 | page_no u32.) A split logs the new right node,
+// then the left one's truncation, then the parent's entry, so a crash between any two
+// leaves a tree that is still searchable; a root split logs both halves before the
+// root. New pages come from `PageStore::allocate_extent`, which is not logged, so a
+// crash mid-split may leak a page.
+//
+// The changes are not transactional: a rollback does not undo them, and a caller
+// needing that logs its own undo. A tree is read and written only through the pool it
+// is opened on, whichever core owns its pages (`partition::owning_core`); open it on
+// one core. Redo still replays its pages on their owners, from that core's log.
+// -----------------------------------------------------------------------------
+
+/// Page types of B-tree nodes.
+pub const PAGE_TYPE_BTREE_LEAF: u16 = 2;
+pub const PAGE_TYPE_BTREE_INTERNAL: u16 = 3;
+
+/// No right sibling.
+pub const NO_PAGE: u32 = u32::MAX;
+
+const COUNT_OFFSET: usize = 24;
+const LEVEL_OFFSET: usize = 26;
+const RIGHT_OFFSET: usize = 28;
+const HIGH_KEY_OFFSET: usize = 32;
+const LEFTMOST_OFFSET: usize = 40;
+const NODE_FLAGS_OFFSET: usize = 44;
+const ENTRIES_OFFSET: usize = 48;
+
+const FLAG_HIGH_KEY: u16 = 1;
+
+const LEAF_ENTRY_SIZE: usize = 16;
+const INTERNAL_ENTRY_SIZE: usize = 12;
+
+/// Entries a leaf holds.
+pub const LEAF_CAPACITY: usize = (PAGE_SIZE - ENTRIES_OFFSET) / LEAF_ENTRY_SIZE;
+/// Entries an internal node holds, besides its leftmost child.
+pub const INTERNAL_CAPACITY: usize = (PAGE_SIZE - ENTRIES_OFFSET) / INTERNAL_ENTRY_SIZE;
+
+/// Registers the B-tree's record types, so recovery can replay them.
+pub fn register(registry: &mut WalRegistry) {
+    registry.register_page::<BTreeNode>("btree");
+    registry.register_page::<BTreeInsert>("btree");
+    registry.register_page::<BTreeDelete>("btree");
+    registry.register_page::<BTreeUpdate>("btree");
+    registry.register_page::<BTreeSplit>("btree");
+}
+
+/// A B+tree rooted at `root`, over `pool`.
+pub struct BTree<'a, S: PageStore + WalStore> {
+    pool: &'a BufferPool<S>,
+    root: PageId,
+}
+
+impl<'a, S: PageStore + WalStore> BTree<'a, S> {
+    /// Creates an empty tree in a new page of the space.
+    pub async fn create(pool: &'a BufferPool<S>, db_id: u32, space_id: u32) -> Result<Self, StorageError> {
+        let page_no = pool.store().allocate_extent(db_id, space_id, 1).await?;
+        let root = PageId { db_id, space_id, page_no };
+        let mut page = pool.get_page_overwrite(root).await?;
+        page.apply(&BTreeNode::new(root, 0, NO_PAGE, None, NO_PAGE, &[])).await?;
+        Ok(Self { pool, root })
+    }
+
+    /// The tree created at `root`.
+    pub fn open(pool: &'a BufferPool<S>, root: PageId) -> Self {
+        Self { pool, root }
+    }
+
+    /// The root page, which never moves: what `open` takes.
+    pub fn root(&self) -> PageId {
+        self.root
+    }
+
+    /// The value of `key`.
+    pub async fn search(&self, key: u64) -> Result<Option<u64>, StorageError> {
+        let leaf = self.read_leaf(key).await?;
+        Ok(search_leaf(&leaf, key).ok().map(|index| value_at(&leaf, index)))
+    }
+
+    /// Entries with keys in `keys`, in key order, read as the scan reaches them.
+    pub async fn range(&self, keys: impl RangeBounds<u64>) -> Result<RangeScan<'a, S>, StorageError> {
+        let start = match keys.start_bound() {
+            Bound::Included(&start) | Bound::Excluded(&start) => start,
+            Bound::Unbounded => 0,
+        };
+        let leaf = self.read_leaf(start).await?;
+        let index = match (keys.start_bound(), search_leaf(&leaf, start)) {
+            (Bound::Excluded(_), Ok(at)) => at + 1,
+            (_, Ok(at) | Err(at)) => at,
+        };
+        Ok(RangeScan {
+            leaf: Some(leaf),
+            index,
+            end: keys.end_bound().cloned(),
+        })
+    }
+
+    /// Sets `key` to `value`. Returns the value it replaced.
+    pub async fn insert(&self, key: u64, value: u64) -> Result<Option<u64>, StorageError> {
+        // Most inserts fit in their leaf, which is then all they latch.
+        let mut leaf = self.write_leaf(key).await?;
+        match search_leaf(&leaf, key) {
+            Ok(index) => return update(&mut leaf, index, value).await.map(Some),
+            Err(index) if count(&leaf) < LEAF_CAPACITY => {
+                let page_id = leaf.page_id();
+                leaf.apply(&BTreeInsert::new(page_id, index, key, value)).await?;
+                return Ok(None);
+            }
+            Err(_) => drop(leaf),
+        }
+
+        let mut path = LatchPath::new(self.pool);
+        path.descend(self.root).await?;
+        loop {
+            let node = path.leaf().unwrap();
+            if let Some(right) = move_right(node, key) {
+                path.step_right(self.page(right)).await?;
+                continue;
+            }
+            if count(node) < capacity(node) {
+                path.release_ancestors();
+            }
+            let node = path.leaf().unwrap();
+            if is_leaf(node) {
+                break;
+            }
+            let child = self.page(child_for(node, key));
+            path.descend(child).await?;
+        }
+        // Someone may have got here first.
+        let leaf = path.leaf().unwrap();
+        if let Ok(index) = search_leaf(leaf, key) {
+            return update(leaf, index, value).await.map(Some);
+        }
+
+        let guards = path.guards();
+        let mut pending = (key, value);
+        for node in guards.iter_mut().rev() {
+            if count(node) < capacity(node) {
+                let index = insert_position(node, pending.0);
+                node.apply(&BTreeInsert::new(node.page_id(), index, pending.0, pending.1)).await?;
+                return Ok(None);
+            }
+            if node.page_id() == self.root {
+                self.split_root(node, pending).await?;
+                return Ok(None);
+            }
+            pending = self.split(node, pending).await?;
+        }
+        unreachable!("the highest node latched has room or is the root")
+    }
+
+    /// Removes `key`. Returns its value.
+    pub async fn delete(&self, key: u64) -> Result<Option<u64>, StorageError> {
+        let mut leaf = self.write_leaf(key).await?;
+        let Ok(index) = search_leaf(&leaf, key) else {
+            return Ok(None);
+        };
+        let (page_id, value) = (leaf.page_id(), value_at(&leaf, index));
+        leaf.apply(&BTreeDelete { page_id, index: index as u16 }).await?;
+        Ok(Some(value))
+    }
+
+    fn page(&self, page_no: u32) -> PageId {
+        PageId { page_no, ..self.root }
+    }
+
+    // The leaf whose range holds `key`, read latches coupled down from the root
+    async fn read_leaf(&self, key: u64) -> Result<PageReadGuard<'a, S>, StorageError> {
+        let mut node = self.pool.get_page(self.root).await?;
+        loop {
+            if let Some(right) = move_right(&node, key) {
+                node = node.couple(self.page(right)).await?;
+            } else if is_leaf(&node) {
+                return Ok(node);
+            } else {
+                let child = self.page(child_for(&node, key));
+                node = node.couple(child).await?;
+            }
+        }
+    }
+
+    // The leaf whose range holds `key`, write-latched: read latches are coupled down
+    // to its parent, which is released once the leaf is latched.
+    async fn write_leaf(&self, key: u64) -> Result<PageWriteGuard<'a, S>, StorageError> {
+        let mut node = self.pool.get_page(self.root).await?;
+        let mut leaf = loop {
+            if let Some(right) = move_right(&node, key) {
+                node = node.couple(self.page(right)).await?;
+                continue;
+            }
+            if is_leaf(&node) {
+                // A leaf root; it may split before it is latched again.
+                drop(node);
+                let root = self.pool.get_page_mut(self.root).await?;
+                if is_leaf(&root) {
+                    break root;
+                }
+                node = self.pool.get_page(self.root).await?;
+                continue;
+            }
+            let child = self.page(child_for(&node, key));
+            if level(&node) == 1 {
+                let leaf = self.pool.get_page_mut(child).await?;
+                drop(node);
+                break leaf;
+            }
+            node = node.couple(child).await?;
+        };
+        while let Some(right) = move_right(&leaf, key) {
+            leaf = self.pool.get_page_mut(self.page(right)).await?;
+        }
+        Ok(leaf)
+    }
+
+    async fn allocate(&self, pages: u32) -> Result<u32, StorageError> {
+        self.pool.store().allocate_extent(self.root.db_id, self.root.space_id, pages).await
+    }
+
+    // Splits full node `node` in two, a new right sibling taking its upper half, and
+    // adds `pending` to the half it belongs in. Returns the parent's entry for the new
+    // node.
+    async fn split(&self, node: &mut PageWriteGuard<'a, S>, pending: (u64, u64)) -> Result<(u64, u64), StorageError> {
+        let right_id = self.page(self.allocate(1).await?);
+        let mut right = self.pool.get_page_overwrite(right_id).await?;
+        let (separator, right_node) = upper_half(node, right_id, right_sibling(node).unwrap_or(NO_PAGE), high_key(node));
+        right.apply(&right_node).await?;
+        let mid = count(node) / 2;
+        let split = BTreeSplit {
+            page_id: node.page_id(),
+            count: mid as u16,
+            right: right_id.page_no,
+            high_key: separator,
+        };
+        node.apply(&split).await?;
+
+        let target = if pending.0 >= separator { &mut right } else { node };
+        let index = insert_position(target, pending.0);
+        target.apply(&BTreeInsert::new(target.page_id(), index, pending.0, pending.1)).await?;
+        Ok((separator, right_id.page_no as u64))
+    }
+
+    // Splits the full root: its halves move to two new pages, and it becomes their
+    // parent, a level up. Adds `pending` to the half it belongs in.
+    async fn split_root(&self, root: &mut PageWriteGuard<'a, S>, pending: (u64, u64)) -> Result<(), StorageError> {
+        let first = self.allocate(2).await?;
+        let (left_id, right_id) = (self.page(first), self.page(first + 1));
+        let mut left = self.pool.get_page_overwrite(left_id).await?;
+        let mut right = self.pool.get_page_overwrite(right_id).await?;
+
+        let node_level = level(root);
+        let mid = count(root) / 2;
+        let (separator, right_node) = upper_half(root, right_id, NO_PAGE, None);
+        let left_node = BTreeNode::new(
+            left_id,
+            node_level,
+            right_id.page_no,
+            Some(separator),
+            leftmost(root),
+            &root[entries(root, 0..mid)],
+        );
+        let mut entry = [0u8; INTERNAL_ENTRY_SIZE];
+        entry[..8].copy_from_slice(&separator.to_le_bytes());
+        entry[8..].copy_from_slice(&right_id.page_no.to_le_bytes());
+        let root_node = BTreeNode::new(self.root, node_level + 1, NO_PAGE, None, left_id.page_no, &entry);
+        left.apply(&left_node).await?;
+        right.apply(&right_node).await?;
+        root.apply(&root_node).await?;
+
+        let target = if pending.0 >= separator { &mut right } else { &mut left };
+        let index = insert_position(target, pending.0);
+        target.apply(&BTreeInsert::new(target.page_id(), index, pending.0, pending.1)).await?;
+        Ok(())
+    }
+}
+
+/// A scan over a key range, holding a read latch on the leaf it is in.
+pub struct RangeScan<'a, S: PageStore + WalStore> {
+    leaf: Option<PageReadGuard<'a, S>>,
+    index: usize,
+    end: Bound<u64>,
+}
+
+impl<S: PageStore + WalStore> RangeScan<'_, S> {
+    /// The next entry in range, or `None` past the end, after which the scan holds no
+    /// latch.
+    pub async fn next(&mut self) -> Result<Option<(u64, u64)>, StorageError> {
+        loop {
+            let Some(leaf) = &self.leaf else {
+                return Ok(None);
+            };
+            if self.index < count(leaf) {
+                let key = key_at(leaf, self.index);
+                let in_range = match self.end {
+                    Bound::Included(end) => key <= end,
+                    Bound::Excluded(end) => key < end,
+                    Bound::Unbounded => true,
+                };
+                if !in_range {
+                    self.leaf = None;
+                    return Ok(None);
+                }
+                self.index += 1;
+                return Ok(Some((key, value_at(leaf, self.index - 1))));
+            }
+            let leaf = self.leaf.take().unwrap();
+            let Some(right) = right_sibling(&leaf) else {
+                return Ok(None);
+            };
+            let right = PageId { page_no: right, ..leaf.page_id() };
+            self.leaf = Some(leaf.couple(right).await?);
+            self.index = 0;
+        }
+    }
+}
+
+async fn update<S: PageStore + WalStore>(leaf: &mut PageWriteGuard<'_, S>, index: usize, value: u64) -> Result<u64, StorageError> {
+    let (page_id, old) = (leaf.page_id(), value_at(leaf, index));
+    leaf.apply(&BTreeUpdate { page_id, index: index as u16, value }).await?;
+    Ok(old)
+}
+
+// The upper half of full node `node`, as node `page_id` with the given right sibling and
+// high key, and the lowest key of its range. Of an internal node, the middle entry's
+// child becomes the new node's leftmost, and its key only the separator.
+fn upper_half(node: &[u8], page_id: PageId, right: u32, high_key: Option<u64>) -> (u64, BTreeNode) {
+    let (n, mid) = (count(node), count(node) / 2);
+    let separator = key_at(node, mid);
+    let upper = match is_leaf(node) {
+        true => BTreeNode::new(page_id, 0, right, high_key, NO_PAGE, &node[entries(node, mid..n)]),
+        false => BTreeNode::new(page_id, level(node), right, high_key, child_at(node, mid), &node[entries(node, mid + 1..n)]),
+    };
+    (separator, upper)
+}
+
+// -----------------------------------------------------------------------------
+// Node layout
+// -----------------------------------------------------------------------------
+
+fn get_u16(page: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(page[at..at + 2].try_into().unwrap())
+}
+
+fn get_u32(page: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(page[at..at + 4].try_into().unwrap())
+}
+
+fn get_u64(page: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(page[at..at + 8].try_into().unwrap())
+}
+
+fn count(node: &[u8]) -> usize {
+    get_u16(node, COUNT_OFFSET) as usize
+}
+
+fn set_count(node: &mut [u8], count: usize) {
+    node[COUNT_OFFSET..COUNT_OFFSET + 2].copy_from_slice(&(count as u16).to_le_bytes());
+}
+
+fn level(node: &[u8]) -> u16 {
+    get_u16(node, LEVEL_OFFSET)
+}
+
+fn is_leaf(node: &[u8]) -> bool {
+    level(node) == 0
+}
+
+fn right_sibling(node: &[u8]) -> Option<u32> {
+    Some(get_u32(node, RIGHT_OFFSET)).filter(|&right| right != NO_PAGE)
+}
+
+fn high_key(node: &[u8]) -> Option<u64> {
+    (get_u16(node, NODE_FLAGS_OFFSET) & FLAG_HIGH_KEY != 0).then(|| get_u64(node, HIGH_KEY_OFFSET))
+}
+
+fn set_links(node: &mut [u8], right: u32, high_key: Option<u64>) {
+    node[RIGHT_OFFSET..RIGHT_OFFSET + 4].copy_from_slice(&right.to_le_bytes());
+    node[HIGH_KEY_OFFSET..HIGH_KEY_OFFSET + 8].copy_from_slice(&high_key.unwrap_or(0).to_le_bytes());
+    let flags = match high_key {
+        Some(_) => FLAG_HIGH_KEY,
+        None => 0,
+    };
+    node[NODE_FLAGS_OFFSET..NODE_FLAGS_OFFSET + 2].copy_from_slice(&flags.to_le_bytes());
+}
+
+fn leftmost(node: &[u8]) -> u32 {
+    get_u32(node, LEFTMOST_OFFSET)
+}
+
+fn entry_size(node: &[u8]) -> usize {
+    match is_leaf(node) {
+        true => LEAF_ENTRY_SIZE,
+        false => INTERNAL_ENTRY_SIZE,
+    }
+}
+
+fn capacity(node: &[u8]) -> usize {
+    match is_leaf(node) {
+        true => LEAF_CAPACITY,
+        false => INTERNAL_CAPACITY,
+    }
+}
+
+// Bytes of entries `range`
+fn entries(node: &[u8], range: Range<usize>) -> Range<usize> {
+    let size = entry_size(node);
+    ENTRIES_OFFSET + range.start * size..ENTRIES_OFFSET + range.end * size
+}
+
+fn key_at(node: &[u8], index: usize) -> u64 {
+    get_u64(node, entries(node, index..index).start)
+}
+
+fn value_at(leaf: &[u8], index: usize) -> u64 {
+    get_u64(leaf, entries(leaf, index..index).start + 8)
+}
+
+fn child_at(node: &[u8], index: usize) -> u32 {
+    get_u32(node, entries(node, index..index).start + 8)
+}
+
+// Where `key` is in the leaf, or where it would go
+fn search_leaf(leaf: &[u8], key: u64) -> Result<usize, usize> {
+    let at = insert_position(leaf, key);
+    match at < count(leaf) && key_at(leaf, at) == key {
+        true => Ok(at),
+        false => Err(at),
+    }
+}
+
+// Entries with keys below `key`
+fn insert_position(node: &[u8], key: u64) -> usize {
+    partition(node, |entry| entry < key)
+}
+
+// The child of internal `node` whose range holds `key`
+fn child_for(node: &[u8], key: u64) -> u32 {
+    match partition(node, |entry| entry <= key) {
+        0 => leftmost(node),
+        at => child_at(node, at - 1),
+    }
+}
+
+// Entries whose keys satisfy `before`, which holds for a prefix of them
+fn partition(node: &[u8], before: impl Fn(u64) -> bool) -> usize {
+    let (mut low, mut high) = (0, count(node));
+    while low < high {
+        let mid = (low + high) / 2;
+        match before(key_at(node, mid)) {
+            true => low = mid + 1,
+            false => high = mid,
+        }
+    }
+    low
+}
+
+// The right sibling to move to if `key` is past the node's range
+fn move_right(node: &[u8], key: u64) -> Option<u32> {
+    high_key(node).filter(|&high| key >= high).and_then(|_| right_sibling(node))
+}
+
+// -----------------------------------------------------------------------------
+// WAL records
+// -----------------------------------------------------------------------------
+
+const PAGE_ID_SIZE: usize = 12;
+
+fn encode_page_id(out: &mut Vec<u8>, page_id: PageId) {
+    for part in [page_id.db_id, page_id.space_id, page_id.page_no] {
+        out.extend_from_slice(&part.to_le_bytes());
+    }
+}
+
+// The page id, and the fields after it if they are `len` bytes long
+fn decode_page_id(payload: &[u8], len: usize) -> Option<(PageId, &[u8])> {
+    if payload.len() != PAGE_ID_SIZE + len {
+        return None;
+    }
+    let page_id = PageId {
+        db_id: get_u32(payload, 0),
+        space_id: get_u32(payload, 4),
+        page_no: get_u32(payload, 8),
+    };
+    Some((page_id, &payload[PAGE_ID_SIZE..]))
+}
+
+/// A node written whole: a new one, or one rebuilt in a root split.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BTreeNode {
+    pub page_id: PageId,
+    /// The node's bytes from the end of the page header to the end of its entries.
+    pub body: Vec<u8>,
+}
+
+impl BTreeNode {
+    /// Node `page_id` holding the `entries` bytes, laid out for its level.
+    pub fn new(page_id: PageId, level: u16, right: u32, high_key: Option<u64>, leftmost: u32, entries: &[u8]) -> Self {
+        let size = match level {
+            0 => LEAF_ENTRY_SIZE,
+            _ => INTERNAL_ENTRY_SIZE,
+        };
+        let mut node = vec![0u8; ENTRIES_OFFSET + entries.len()];
+        set_count(&mut node, entries.len() / size);
+        node[LEVEL_OFFSET..LEVEL_OFFSET + 2].copy_from_slice(&level.to_le_bytes());
+        set_links(&mut node, right, high_key);
+        node[LEFTMOST_OFFSET..LEFTMOST_OFFSET + 4].copy_from_slice(&leftmost.to_le_bytes());
+        node[ENTRIES_OFFSET..].copy_from_slice(entries);
+        Self {
+            page_id,
+            body: node[PAGE_HEADER_SIZE..].to_vec(),
+        }
+    }
+}
+
+impl WalRecord for BTreeNode {
+    const TYPE: WalRecordType = WalRecordType::BTREE_NODE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.reserve(PAGE_ID_SIZE + self.body.len());
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.body);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let body_len = payload.len().checked_sub(PAGE_ID_SIZE)?;
+        if !(ENTRIES_OFFSET - PAGE_HEADER_SIZE..=PAGE_SIZE - PAGE_HEADER_SIZE).contains(&body_len) {
+            return None;
+        }
+        let (page_id, body) = decode_page_id(payload, body_len)?;
+        Some(Self { page_id, body: body.to_vec() })
+    }
+}
+
+impl PageRecord for BTreeNode {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[PAGE_HEADER_SIZE..].fill(0);
+        page[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + self.body.len()].copy_from_slice(&self.body);
+        let page_type = match is_leaf(page) {
+            true => PAGE_TYPE_BTREE_LEAF,
+            false => PAGE_TYPE_BTREE_INTERNAL,
+        };
+        page::set_page_type(page, page_type);
+    }
+}
+
+/// An entry added at `index`: a leaf's key and value, or an internal node's key and
+/// child page number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BTreeInsert {
+    pub page_id: PageId,
+    pub index: u16,
+    pub key: u64,
+    pub value: u64,
+}
+
+impl BTreeInsert {
+    pub fn new(page_id: PageId, index: usize, key: u64, value: u64) -> Self {
+        Self { page_id, index: index as u16, key, value }
+    }
+}
+
+impl WalRecord for BTreeInsert {
+    const TYPE: WalRecordType = WalRecordType::BTREE_INSERT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.index.to_le_bytes());
+        out.extend_from_slice(&self.key.to_le_bytes());
+        out.extend_from_slice(&self.value.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let (page_id, fields) = decode_page_id(payload, 18)?;
+        Some(Self {
+            page_id,
+            index: get_u16(fields, 0),
+            key: get_u64(fields, 2),
+            value: get_u64(fields, 10),
+        })
+    }
+}
+
+impl PageRecord for BTreeInsert {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let (index, n) = (self.index as usize, count(page));
+        let moved = entries(page, index..n);
+        let at = moved.start;
+        page.copy_within(moved, at + entry_size(page));
+        page[at..at + 8].copy_from_slice(&self.key.to_le_bytes());
+        match is_leaf(page) {
+            true => page[at + 8..at + 16].copy_from_slice(&self.value.to_le_bytes()),
+            false => page[at + 8..at + 12].copy_from_slice(&(self.value as u32).to_le_bytes()),
+        }
+        set_count(page, n + 1);
+    }
+}
+
+/// The entry at `index` removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BTreeDelete {
+    pub page_id: PageId,
+    pub index: u16,
+}
+
+impl WalRecord for BTreeDelete {
+    const TYPE: WalRecordType = WalRecordType::BTREE_DELETE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.index.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let (page_id, fields) = decode_page_id(payload, 2)?;
+        Some(Self { page_id, index: get_u16(fields, 0) })
+    }
+}
+
+impl PageRecord for BTreeDelete {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let (index, n) = (self.index as usize, count(page));
+        let moved = entries(page, index + 1..n);
+        let to = moved.start - entry_size(page);
+        page.copy_within(moved, to);
+        let last = entries(page, n - 1..n);
+        page[last].fill(0);
+        set_count(page, n - 1);
+    }
+}
+
+/// A new value for the leaf entry at `index`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BTreeUpdate {
+    pub page_id: PageId,
+    pub index: u16,
+    pub value: u64,
+}
+
+impl WalRecord for BTreeUpdate {
+    const TYPE: WalRecordType = WalRecordType::BTREE_UPDATE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.index.to_le_bytes());
+        out.extend_from_slice(&self.value.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let (page_id, fields) = decode_page_id(payload, 10)?;
+        Some(Self {
+            page_id,
+            index: get_u16(fields, 0),
+            value: get_u64(fields, 2),
+        })
+    }
+}
+
+impl PageRecord for BTreeUpdate {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let at = entries(page, self.index as usize..self.index as usize).start + 8;
+        page[at..at + 8].copy_from_slice(&self.value.to_le_bytes());
+    }
+}
+
+/// The left half of a split: the node keeps its first `count` entries, and links to
+/// the new node `right`, whose range starts at `high_key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BTreeSplit {
+    pub page_id: PageId,
+    pub count: u16,
+    pub right: u32,
+    pub high_key: u64,
+}
+
+impl WalRecord for BTreeSplit {
+    const TYPE: WalRecordType = WalRecordType::BTREE_SPLIT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.count.to_le_bytes());
+        out.extend_from_slice(&self.right.to_le_bytes());
+        out.extend_from_slice(&self.high_key.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let (page_id, fields) = decode_page_id(payload, 14)?;
+        Some(Self {
+            page_id,
+            count: get_u16(fields, 0),
+            right: get_u32(fields, 2),
+            high_key: get_u64(fields, 6),
+        })
+    }
+}
+
+impl PageRecord for BTreeSplit {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let moved = entries(page, self.count as usize..count(page));
+        page[moved].fill(0);
+        set_count(page, self.count as usize);
+        set_links(page, self.right, Some(self.high_key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use futures::future::join_all;
+
+    use super::*;
+    use crate::checksum::ChecksumAlgorithm;
+    use crate::core_storage::CoreStorage;
+    use crate::eviction::EvictionKind;
+    use crate::partition::{PagePartition, PageRouter};
+    use crate::mem_store::MemStore;
+    use crate::traits::{Lsn, StorageConfig};
+    use crate::wal_redo;
+
+    const KEYS: u64 = 20_000;
+    const FRAMES: usize = 64;
+
+    fn pool() -> BufferPool<MemStore> {
+        let store = Rc::new(MemStore::new(ChecksumAlgorithm::Crc32c));
+        BufferPool::new(store, FRAMES, FRAMES, ChecksumAlgorithm::Crc32c, EvictionKind::ClockSweep)
+    }
+
+    // Every key below KEYS once, scattered: 7919 is prime and doesn't divide KEYS
+    fn scattered(i: u64) -> u64 {
+        i * 7919 % KEYS
+    }
+
+    async fn collect<S: PageStore + WalStore>(mut scan: RangeScan<'_, S>) -> Vec<(u64, u64)> {
+        let mut entries = Vec::new();
+        while let Some(entry) = scan.next().await.unwrap() {
+            entries.push(entry);
+        }
+        entries
+    }
+
+    #[tokio::test]
+    async fn inserts_split_nodes_and_stay_searchable() {
+        let pool = pool();
+        let tree = BTree::create(&pool, 1, 1).await.unwrap();
+        for i in 0..KEYS {
+            let key = scattered(i);
+            assert_eq!(tree.insert(key, key * 2).await.unwrap(), None);
+        }
+        // Deep enough that internal nodes split too
+        assert!(KEYS as usize > LEAF_CAPACITY * 2);
+        for key in 0..KEYS {
+            assert_eq!(tree.search(key).await.unwrap(), Some(key * 2), "key {}", key);
+        }
+        assert_eq!(tree.search(KEYS).await.unwrap(), None);
+
+        let scan = collect(tree.range(100..=1_100).await.unwrap()).await;
+        assert_eq!(scan, (100..=1_100).map(|key| (key, key * 2)).collect::<Vec<_>>());
+        assert_eq!(collect(tree.range(..).await.unwrap()).await.len(), KEYS as usize);
+
+        assert_eq!(tree.insert(42, 1).await.unwrap(), Some(84));
+        assert_eq!(tree.search(42).await.unwrap(), Some(1));
+        for key in (0..KEYS).step_by(2) {
+            assert!(tree.delete(key).await.unwrap().is_some());
+        }
+        assert_eq!(tree.delete(0).await.unwrap(), None);
+        let scan = collect(tree.range(..).await.unwrap()).await;
+        assert_eq!(scan.len(), KEYS as usize / 2);
+        assert!(scan.iter().all(|&(key, _)| key % 2 == 1));
+
+        // Reopened from its root, which never moved
+        let reopened = BTree::open(&pool, tree.root());
+        assert_eq!(reopened.search(KEYS - 1).await.unwrap(), Some((KEYS - 1) * 2));
+    }
+
+    #[tokio::test]
+    async fn concurrent_inserts_keep_every_key() {
+        const TASKS: u64 = 8;
+        let pool = pool();
+        let tree = BTree::create(&pool, 1, 1).await.unwrap();
+        let tasks = (0..TASKS).map(|task| {
+            let tree = &tree;
+            async move {
+                for i in (task..KEYS).step_by(TASKS as usize) {
+                    let key = scattered(i);
+                    tree.insert(key, key + 1).await?;
+                    tokio::task::yield_now().await;
+                }
+                Ok::<_, StorageError>(())
+            }
+        });
+        for res in join_all(tasks).await {
+            res.unwrap();
+        }
+        let scan = collect(tree.range(..).await.unwrap()).await;
+        assert_eq!(scan, (0..KEYS).map(|key| (key, key + 1)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn redo_rebuilds_the_tree_from_the_log() {
+        let dir = std::env::temp_dir().join(format!("aquifer-btree-{}-redo", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::create_dir_all(dir.join("wal")).unwrap();
+        let config = StorageConfig::scratch(dir.join("data"), dir.join("wal"));
+        let partition = || {
+            let storage = Rc::new(CoreStorage::new(&config, 0));
+            let pool = BufferPool::new(storage, FRAMES, FRAMES, ChecksumAlgorithm::Crc32c, EvictionKind::ClockSweep);
+            let (router, mut inboxes) = PageRouter::new(1);
+            PagePartition::new(0, Rc::new(pool), router, inboxes.remove(0))
+        };
+        tokio_uring::start(async {
+            // Some pages are written back as eviction victims, the rest only logged.
+            let before = partition();
+            let tree = BTree::create(before.pool(), 1, 1).await.unwrap();
+            let root = tree.root();
+            for i in 0..KEYS {
+                let key = scattered(i);
+                tree.insert(key, key * 3).await.unwrap();
+            }
+            before.pool().store().flush_wal(1).await.unwrap();
+            drop(before);
+
+            let mut registry = WalRegistry::new();
+            register(&mut registry);
+            let plans = vec![wal_redo::plan(&config.wal_dir, 1, Lsn(0), None).unwrap()];
+            let after = partition();
+            let summary = wal_redo::redo(&after, &plans, &HashMap::new(), &registry, false).await.unwrap();
+            assert!(summary.redone > 0);
+            let tree = BTree::open(after.pool(), root);
+            let scan = collect(tree.range(..).await.unwrap()).await;
+            assert_eq!(scan, (0..KEYS).map(|key| (key, key * 3)).collect::<Vec<_>>());
+        });
+    }
+}
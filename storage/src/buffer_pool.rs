@@ -0,0 +1,1803 @@
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::{HashMap, VecDeque};
+use std::ops::{Deref, DerefMut, Range};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::future::join_all;
+use futures::lock::{Mutex, MutexGuard};
+use futures::StreamExt;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::eviction::{EvictionKind, EvictionPolicy};
+use crate::full_page::{self, FullPageImage};
+use crate::metrics::CoreMetrics;
+use crate::numa;
+use crate::page;
+use crate::page_table::PageTable;
+use crate::runtime::{Runtime, TokioRuntime};
+use crate::segment;
+use crate::stats::{BufferPoolStats, RecentRate};
+use crate::trace;
+use crate::traits::{AlignedBuf, Lsn, PageId, PageStore, StorageError, WalStore};
+use crate::wal_checkpoint::DirtyPage;
+use crate::wal_registry::{PageRecord, WalRecord};
+
+// -----------------------------------------------------------------------------
+// Buffer Pool
+//
+// One pool per core, over that core's `CoreStorage`: a fixed arena of 8KB frames
+// allocated up front, and a page table mapping each cached `PageId` to its frame.
+// Callers get a `PageReadGuard` or a `PageWriteGuard`, which pin the frame for as
+// long as they live (a pinned frame is never reused for another page) and hold its
+// latch: shared by readers, exclusive to one writer. Guards may be held across awaits;
+// a task waiting for a latch yields to the core's other tasks instead of spinning.
+//
+// Tasks holding several latches (B-tree traversals) take them in one order: parent
+// before child, left sibling before right. Waiting only in that order can't deadlock.
+// Going against it (a right-to-left scan, back up to a parent) uses `try_get_page` or
+// `try_get_page_mut`, which give up rather than wait: release everything and restart.
+// `PageReadGuard::couple` and `LatchPath` do the top-down coupling: a child is latched
+// before its parent is released, so no writer can change the path in between.
+//
+// A frame's `io` lock is held while it is being filled from disk, so a second caller
+// asking for the same page waits for that read instead of issuing its own. Write-back
+// holds the latch shared, like a reader, so a page is never written half-modified.
+// Before a dirty page is written, the WAL is flushed past the page's LSN, unless
+// `WalStore::flushed_lsn` says it already is (write-ahead logging). A dirty frame
+// keeps the LSN of its oldest change not yet on disk, its rec_lsn, which is what a
+// checkpoint's dirty page table records. With full-page writes on, a write guard keeps
+// the page's image from before its first change, to log ahead of the change (see
+// `full_page.rs`).
+//
+// With no free frame left, the pool's `EvictionPolicy` picks an unpinned victim (see
+// `eviction.rs`), which is written back first if dirty. To keep that write off the
+// foreground path, a background writer periodically cleans the next victims in
+// batches: one WAL flush per database, then all page writes in flight at once. A
+// batch is sorted by page, and neighbours within a segment go out as one vectored
+// `write_pages`, so a checkpoint over a mostly sequential dirty set issues a few large
+// writes instead of one 8KB write per page.
+//
+// Sequential scans are detected per space: once `PrefetchConfig::trigger` consecutive
+// page numbers have been requested, the next `window` pages are queued for the
+// prefetcher task, which maps them into frames and fills them with one `read_pages`.
+// The scan keeps topping the window up as it advances, so it finds its pages cached or
+// already in flight instead of waiting on one 8KB read at a time. Callers that know
+// what they'll read next (an index scan's leaf pages) queue it with `prefetch`.
+// Read-ahead pages enter the policy as its next victims until they're first used.
+//
+// A long sequential scan would still push the whole working set out, one page per
+// miss. With a scan ring (`with_scan_ring`), once a space's run reaches the ring's
+// trigger, the pages it misses on (and reads ahead) go into a small FIFO of frames:
+// when the ring is full, the next one reuses the ring's oldest frame rather than
+// evicting somebody else's page. The scan recycles its own frames, and other pages
+// are only evicted to grow the ring. A ring page anyone else asks for leaves the ring
+// and stays cached like any other; one still pinned when its turn comes is let go the
+// same way.
+//
+// The pool can be resized while running, in chunks of FRAME_CHUNK frames, up to the
+// `max_frames` it was created with (frame headers for all of them exist from the start;
+// only page memory comes and goes). Growing allocates the new chunks' pages and frees
+// their frames. Shrinking first stops handing out the frames given up and makes them
+// unevictable, then writes back and evicts their pages itself, waiting for pinned ones
+// to be released, and frees each frame's page as soon as it is idle.
+//
+// Bulk operations (a bulk load, an index build) get at pages through `get_page_bulk`
+// and `get_page_mut_bulk`, and are held to a budget of the pool's frames
+// (`with_bulk_budget`): frames they load count against it until evicted, or until
+// someone else uses the page and it joins the working set. Past the budget, pages not
+// already cached are read from the store into a private buffer, and written straight
+// back by `BulkPageMut::finish`, never entering the pool. One COPY can then take at
+// most its share of the pool, however much it touches. A spilled page bypasses the
+// latches, so a bulk operation spills only pages nobody else uses meanwhile: those of
+// the table or index it is building.
+//
+// On multi-socket machines, `bind_to_numa_node` places every frame's page on the
+// owning core's NUMA node, including frames added by later growth (see `numa.rs`).
+// -----------------------------------------------------------------------------
+
+// Page writes a batched flush keeps in flight at once
+const FLUSH_BATCH: usize = 64;
+
+// Most pages one coalesced write covers (512KB)
+const MAX_WRITE_RUN: usize = 64;
+
+/// Granularity of `BufferPool::resize`, in frames (4MB).
+pub const FRAME_CHUNK: usize = 512;
+
+// Pause before a shrink checks again on frames still pinned
+const RESIZE_RETRY: Duration = Duration::from_millis(10);
+
+/// Pacing of `BufferPool::run_background_writer`.
+#[derive(Debug, Clone)]
+pub struct BackgroundWriterConfig {
+    /// Pause between rounds.
+    pub interval: Duration,
+    /// Frames to keep reusable without a write: free frames plus clean ones among the
+    /// next victims.
+    pub clean_target: usize,
+    /// Most pages written per round. With `interval`, caps the writer's I/O rate.
+    pub max_pages_per_round: usize,
+}
+
+/// Recycling of frames among the pages of bulk scans; see `BufferPool::with_scan_ring`.
+#[derive(Debug, Clone)]
+pub struct ScanRingConfig {
+    /// Consecutive page numbers requested in a space before its misses go to the ring.
+    pub trigger: u32,
+    /// Frames in the ring. Should exceed the prefetch window, or read-ahead pages are
+    /// recycled before the scan gets to them.
+    pub frames: usize,
+}
+
+impl Default for ScanRingConfig {
+    fn default() -> Self {
+        Self { trigger: 64, frames: 64 }
+    }
+}
+
+/// Sequential scan detection and read-ahead of `BufferPool::run_prefetcher`.
+#[derive(Debug, Clone)]
+pub struct PrefetchConfig {
+    /// Consecutive page numbers requested in a space before read-ahead starts.
+    pub trigger: u32,
+    /// Pages read ahead of the scan. Refilled once half of it has been consumed.
+    pub window: u32,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self { trigger: 4, window: 32 }
+    }
+}
+
+// Prefetch requests queued beyond this are dropped: read-ahead is only a hint
+const PREFETCH_QUEUE: usize = 64;
+
+// Most pages one queued prefetch request covers
+const MAX_READ_AHEAD: u32 = 64;
+
+// Read-ahead runs a warm-up keeps in flight at once
+const WARM_UP_RUNS: usize = 8;
+
+// A space's recent accesses, for scan detection
+struct ScanState {
+    // Page number a sequential scan would request next
+    next: u32,
+    // Consecutive page numbers requested so far
+    run: u32,
+    // Read-ahead has been requested up to here
+    prefetched_to: u32,
+}
+
+impl Default for BackgroundWriterConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(200),
+            clean_target: 128,
+            max_pages_per_round: 100,
+        }
+    }
+}
+
+struct Frame {
+    page_id: Cell<Option<PageId>>,
+    // Taken only while the frame is filled from disk, when nobody else can reach it
+    buf: RefCell<Option<AlignedBuf>>,
+    pins: Cell<u32>,
+    // Since when `pins` has been above zero
+    pinned_at: Cell<Option<Instant>>,
+    dirty: Cell<bool>,
+    // While dirty: LSN of the oldest change not on disk, if it was logged
+    rec_lsn: Cell<Option<Lsn>>,
+    // Read ahead, and not requested since
+    prefetched: Cell<bool>,
+    io: Mutex<()>,
+    // Holds a bulk scan's page, in the pool's scan ring
+    in_ring: Cell<bool>,
+    // Loaded by a bulk operation, and counted against its budget
+    bulk: Cell<bool>,
+    // Page content latch. Taken before `io` by write-back, never the other way around.
+    latch: RwLock<()>,
+}
+
+/// A per-core page cache on top of a `PageStore`.
+pub struct BufferPool<S> {
+    store: Rc<S>,
+    checksum: ChecksumAlgorithm,
+    frames: Vec<Frame>,
+    // Frames in use: `frames[..active]`; later ones have no page memory
+    active: Cell<usize>,
+    resizing: Mutex<()>,
+    // Where frames' pages are bound, once `bind_to_numa_node` is called
+    numa_node: Cell<Option<usize>>,
+    // Shared with the other cores' routers, which look pages up in it
+    page_table: Arc<PageTable>,
+    free: RefCell<Vec<usize>>,
+    policy: RefCell<Box<dyn EvictionPolicy>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+    evictions: Cell<u64>,
+    eviction_rate: RecentRate,
+    // Completed pin periods and their total length
+    pin_periods: Cell<u64>,
+    pinned_time: Cell<Duration>,
+    // Dirty victims a load had to write back itself, and pages the background writer wrote
+    victim_writes: Cell<u64>,
+    background_writes: Cell<u64>,
+    // Set once the prefetcher runs; scans are only tracked then
+    prefetch: RefCell<Option<PrefetchConfig>>,
+    scans: RefCell<HashMap<(u32, u32), ScanState>>,
+    // Read-ahead requests: first page, page count, and whether for a bulk scan
+    prefetch_tx: RefCell<mpsc::Sender<(PageId, u32, bool)>>,
+    prefetch_rx: RefCell<Option<mpsc::Receiver<(PageId, u32, bool)>>>,
+    prefetched: Cell<u64>,
+    prefetch_hits: Cell<u64>,
+    prefetch_wasted: Cell<u64>,
+    scan_ring: Option<ScanRingConfig>,
+    // Frames in the scan ring, oldest first
+    ring: RefCell<VecDeque<usize>>,
+    ring_recycles: Cell<u64>,
+    // Percent of the frames in use bulk operations may hold, the frames they hold, and
+    // their requests served around the pool
+    bulk_budget: u32,
+    bulk_frames: Cell<usize>,
+    bulk_spills: Cell<u64>,
+    // The core's metrics, counting hits and misses along with the pool
+    metrics: Option<Arc<CoreMetrics>>,
+    // Clock for pin times and the pool's own waits
+    runtime: Rc<dyn Runtime>,
+}
+
+impl<S: PageStore + WalStore> BufferPool<S> {
+    /// Allocates `frames` page frames up front; `resize` can grow the pool to `max_frames`.
+    /// `checksum` is what pages are stamped with on write-back, and must match the store's.
+    pub fn new(store: Rc<S>, frames: usize, max_frames: usize, checksum: ChecksumAlgorithm, eviction: EvictionKind) -> Self {
+        Self::with_policy(store, frames, max_frames, checksum, eviction.build(max_frames))
+    }
+
+    /// Like `new`, with a replacement policy of the caller's own, sized for `max_frames`.
+    pub fn with_policy(
+        store: Rc<S>,
+        frames: usize,
+        max_frames: usize,
+        checksum: ChecksumAlgorithm,
+        policy: Box<dyn EvictionPolicy>,
+    ) -> Self {
+        assert!(frames <= max_frames, "buffer pool of {} frames exceeds its maximum of {}", frames, max_frames);
+        let active = frames;
+        let frames: Vec<Frame> = (0..max_frames)
+            .map(|idx| Frame {
+                page_id: Cell::new(None),
+                buf: RefCell::new((idx < active).then(AlignedBuf::page)),
+                pins: Cell::new(0),
+                pinned_at: Cell::new(None),
+                dirty: Cell::new(false),
+                rec_lsn: Cell::new(None),
+                prefetched: Cell::new(false),
+                io: Mutex::new(()),
+                in_ring: Cell::new(false),
+                bulk: Cell::new(false),
+                latch: RwLock::new(()),
+            })
+            .collect();
+        // Popped from the back, so frames are handed out in order.
+        let free = (0..active).rev().collect();
+        let (prefetch_tx, prefetch_rx) = mpsc::channel(PREFETCH_QUEUE);
+        Self {
+            store,
+            checksum,
+            frames,
+            active: Cell::new(active),
+            resizing: Mutex::new(()),
+            numa_node: Cell::new(None),
+            page_table: Arc::new(PageTable::new(max_frames)),
+            free: RefCell::new(free),
+            policy: RefCell::new(policy),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+            evictions: Cell::new(0),
+            eviction_rate: RecentRate::default(),
+            pin_periods: Cell::new(0),
+            pinned_time: Cell::new(Duration::ZERO),
+            victim_writes: Cell::new(0),
+            background_writes: Cell::new(0),
+            prefetch: RefCell::new(None),
+            scans: RefCell::new(HashMap::new()),
+            prefetch_tx: RefCell::new(prefetch_tx),
+            prefetch_rx: RefCell::new(Some(prefetch_rx)),
+            prefetched: Cell::new(0),
+            prefetch_hits: Cell::new(0),
+            prefetch_wasted: Cell::new(0),
+            scan_ring: None,
+            ring: RefCell::new(VecDeque::new()),
+            ring_recycles: Cell::new(0),
+            bulk_budget: 100,
+            bulk_frames: Cell::new(0),
+            bulk_spills: Cell::new(0),
+            metrics: None,
+            runtime: Rc::new(TokioRuntime),
+        }
+    }
+
+    /// Keeps bulk scans' pages in a ring of their own, so they don't evict the working
+    /// set. Scans are detected per space, as for read-ahead.
+    pub fn with_scan_ring(mut self, config: ScanRingConfig) -> Self {
+        self.scan_ring = Some(config);
+        self
+    }
+
+    /// Lets bulk operations load pages into at most `percent` percent of the frames in
+    /// use; beyond that they read and write around the pool. Without it, they may take
+    /// every frame.
+    pub fn with_bulk_budget(mut self, percent: u32) -> Self {
+        self.bulk_budget = percent.min(100);
+        self
+    }
+
+    /// Counts the pool's hits and misses into `metrics` too, for the exporter.
+    pub fn with_metrics(mut self, metrics: Arc<CoreMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Takes the time, and waits, on `runtime` instead of the wall clock: for a
+    /// simulation to run the pool on its schedule (see `sim.rs`).
+    pub fn with_runtime(mut self, runtime: Rc<dyn Runtime>) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    pub fn store(&self) -> &Rc<S> {
+        &self.store
+    }
+
+    /// The checksum pages are stamped with, for pages written around the pool.
+    pub fn checksum(&self) -> ChecksumAlgorithm {
+        self.checksum
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.active.get()
+    }
+
+    /// The pool's page table, for other cores to look pages up in.
+    pub fn page_table(&self) -> &Arc<PageTable> {
+        &self.page_table
+    }
+
+    /// Frames `resize` can grow the pool to.
+    pub fn max_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Pages currently cached.
+    pub fn len(&self) -> usize {
+        self.page_table.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.page_table.is_empty()
+    }
+
+    /// Requests served from the pool since it was created.
+    pub fn hits(&self) -> u64 {
+        self.hits.get()
+    }
+
+    /// Requests that had to read the page from the store.
+    pub fn misses(&self) -> u64 {
+        self.misses.get()
+    }
+
+    /// Pages evicted to make room for others since the pool was created.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.get()
+    }
+
+    /// Evictions that had to write the victim back first, stalling the load behind it.
+    /// Should stay rare with the background writer running.
+    pub fn victim_writes(&self) -> u64 {
+        self.victim_writes.get()
+    }
+
+    /// Pages the background writer has written back.
+    pub fn background_writes(&self) -> u64 {
+        self.background_writes.get()
+    }
+
+    /// Pages read ahead of a sequential scan.
+    pub fn prefetched(&self) -> u64 {
+        self.prefetched.get()
+    }
+
+    /// Frames bulk scans reused from their ring instead of evicting other pages.
+    pub fn ring_recycles(&self) -> u64 {
+        self.ring_recycles.get()
+    }
+
+    /// A snapshot of the pool's counters, with its dirty and pinned frames counted now.
+    pub fn stats(&self) -> BufferPoolStats {
+        let (mut dirty, mut pinned) = (0, 0);
+        for frame in &self.frames {
+            dirty += frame.dirty.get() as usize;
+            pinned += (frame.pins.get() > 0) as usize;
+        }
+        let periods = self.pin_periods.get();
+        BufferPoolStats {
+            capacity: self.capacity(),
+            cached: self.len(),
+            dirty,
+            pinned,
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+            evictions: self.evictions.get(),
+            evictions_per_sec: self.eviction_rate.per_sec(),
+            victim_writes: self.victim_writes.get(),
+            background_writes: self.background_writes.get(),
+            avg_pin_duration: if periods == 0 { Duration::ZERO } else { self.pinned_time.get() / periods as u32 },
+            prefetched: self.prefetched.get(),
+            prefetch_hits: self.prefetch_hits.get(),
+            prefetch_wasted: self.prefetch_wasted.get(),
+            ring_recycles: self.ring_recycles.get(),
+            bulk_cached: self.bulk_frames.get(),
+            bulk_spills: self.bulk_spills.get(),
+        }
+    }
+
+    /// Pins `page_id` in the pool for reading, reading it from the store if it isn't
+    /// cached, and waits out any writer. A never-written page comes back zero-filled.
+    /// With every frame pinned, fails with `BufferPoolExhausted`.
+    pub async fn get_page(&self, page_id: PageId) -> Result<PageReadGuard<'_, S>, StorageError> {
+        self.read_latched(page_id, false).await
+    }
+
+    async fn read_latched(&self, page_id: PageId, bulk_op: bool) -> Result<PageReadGuard<'_, S>, StorageError> {
+        loop {
+            let pin = self.pin_page(page_id, bulk_op).await?;
+            let frame = pin.frame();
+            let latch = frame.latch.read().await;
+            // A writer that panicked may have dropped the page meanwhile.
+            if frame.page_id.get() != Some(page_id) {
+                continue;
+            }
+            let page = Ref::map(frame.buf.borrow(), |buf| &buf.as_ref().unwrap()[..page::PAGE_SIZE]);
+            return Ok(PageReadGuard { page, _latch: latch, pin });
+        }
+    }
+
+    /// Like `get_page`, but for modification: waits until no other guard holds the page.
+    pub async fn get_page_mut(&self, page_id: PageId) -> Result<PageWriteGuard<'_, S>, StorageError> {
+        self.write_latched(page_id, false).await
+    }
+
+    async fn write_latched(&self, page_id: PageId, bulk_op: bool) -> Result<PageWriteGuard<'_, S>, StorageError> {
+        loop {
+            let pin = self.pin_page(page_id, bulk_op).await?;
+            let frame = pin.frame();
+            let latch = frame.latch.write().await;
+            if frame.page_id.get() != Some(page_id) {
+                continue;
+            }
+            let page = RefMut::map(frame.buf.borrow_mut(), |buf| &mut buf.as_mut().unwrap()[..page::PAGE_SIZE]);
+            return Ok(PageWriteGuard { page, lsn: None, modified: false, image: None, unread: false, _latch: latch, pin });
+        }
+    }
+
+    /// Like `get_page_mut`, for a change that overwrites the whole page: one not cached
+    /// isn't read first, so a page torn on disk can still be restored from its image in
+    /// the WAL. Such a page reads as zeros until overwritten, and is dropped from the pool
+    /// again if the guard is released unmodified.
+    pub async fn get_page_overwrite(&self, page_id: PageId) -> Result<PageWriteGuard<'_, S>, StorageError> {
+        loop {
+            if self.page_table.contains(page_id) {
+                return self.get_page_mut(page_id).await;
+            }
+            let idx = self.claim_victim(page_id).await?;
+            if self.page_table.contains(page_id) {
+                self.release(idx);
+                continue;
+            }
+            self.count_miss();
+            let frame = &self.frames[idx];
+            let io = self.map_frame(idx, page_id);
+            frame.buf.borrow_mut().as_mut().unwrap()[..page::PAGE_SIZE].fill(0);
+            let pin = FramePin { pool: self, idx };
+            // Latched before anyone queued on `io` gets to see the zeros.
+            let latch = frame.latch.try_write().expect("free frame is latched");
+            drop(io);
+            let page = RefMut::map(frame.buf.borrow_mut(), |buf| &mut buf.as_mut().unwrap()[..page::PAGE_SIZE]);
+            return Ok(PageWriteGuard { page, lsn: None, modified: false, image: None, unread: true, _latch: latch, pin });
+        }
+    }
+
+    /// Like `get_page`, but returns `None` instead of waiting while a writer holds or
+    /// awaits the page's latch. Still waits for the page to be read in if it isn't
+    /// cached. For latching against the parent-to-child, left-to-right order.
+    pub async fn try_get_page(&self, page_id: PageId) -> Result<Option<PageReadGuard<'_, S>>, StorageError> {
+        loop {
+            let pin = self.pin_page(page_id, false).await?;
+            let frame = pin.frame();
+            let Ok(latch) = frame.latch.try_read() else {
+                return Ok(None);
+            };
+            if frame.page_id.get() != Some(page_id) {
+                continue;
+            }
+            let page = Ref::map(frame.buf.borrow(), |buf| &buf.as_ref().unwrap()[..page::PAGE_SIZE]);
+            return Ok(Some(PageReadGuard { page, _latch: latch, pin }));
+        }
+    }
+
+    /// Like `get_page_mut`, but returns `None` instead of waiting while any guard holds
+    /// the page.
+    pub async fn try_get_page_mut(&self, page_id: PageId) -> Result<Option<PageWriteGuard<'_, S>>, StorageError> {
+        loop {
+            let pin = self.pin_page(page_id, false).await?;
+            let frame = pin.frame();
+            let Ok(latch) = frame.latch.try_write() else {
+                return Ok(None);
+            };
+            if frame.page_id.get() != Some(page_id) {
+                continue;
+            }
+            let page = RefMut::map(frame.buf.borrow_mut(), |buf| &mut buf.as_mut().unwrap()[..page::PAGE_SIZE]);
+            return Ok(Some(PageWriteGuard { page, lsn: None, modified: false, image: None, unread: false, _latch: latch, pin }));
+        }
+    }
+
+    /// Like `get_page`, for a bulk operation: a page not cached is loaded into the pool
+    /// only while bulk operations hold less than their budget of frames, and otherwise
+    /// read into a buffer of its own.
+    pub async fn get_page_bulk(&self, page_id: PageId) -> Result<BulkPage<'_, S>, StorageError> {
+        if self.admits_bulk(page_id) {
+            return Ok(BulkPage::Cached(self.read_latched(page_id, true).await?));
+        }
+        Ok(BulkPage::Direct(self.read_direct(page_id).await?))
+    }
+
+    /// Like `get_page_mut`, for a bulk operation, with the budget of `get_page_bulk`. The
+    /// change is only kept once the page is `finish`ed.
+    pub async fn get_page_mut_bulk(&self, page_id: PageId) -> Result<BulkPageMut<'_, S>, StorageError> {
+        if self.admits_bulk(page_id) {
+            return Ok(BulkPageMut::Cached(self.write_latched(page_id, true).await?));
+        }
+        let buf = self.read_direct(page_id).await?;
+        Ok(BulkPageMut::Direct {
+            pool: self,
+            page_id,
+            buf,
+            lsn: None,
+        })
+    }
+
+    /// Frames holding pages that bulk operations loaded.
+    pub fn bulk_frames(&self) -> usize {
+        self.bulk_frames.get()
+    }
+
+    // Whether a bulk operation may go through the pool for `page_id`: always when it is
+    // cached, else while under budget.
+    fn admits_bulk(&self, page_id: PageId) -> bool {
+        self.bulk_budget == 100
+            || self.page_table.contains(page_id)
+            || self.bulk_frames.get() < self.capacity() * self.bulk_budget as usize / 100
+    }
+
+    async fn read_direct(&self, page_id: PageId) -> Result<AlignedBuf, StorageError> {
+        self.bulk_spills.set(self.bulk_spills.get() + 1);
+        let (buf, res) = self.store.read_page(page_id, AlignedBuf::page()).await;
+        res.map(|_| buf)
+    }
+
+    // Writes a bulk operation's spilled page: into the pool if the page has been cached
+    // since, else straight to the store, after the WAL covering it.
+    async fn write_direct(&self, page_id: PageId, mut buf: AlignedBuf, lsn: Option<Lsn>) -> Result<(), StorageError> {
+        if let Some(lsn) = lsn {
+            page::set_page_lsn(&mut buf[..page::PAGE_SIZE], lsn);
+        }
+        if self.page_table.contains(page_id) {
+            let mut page = self.get_page_mut(page_id).await?;
+            page.copy_from_slice(&buf[..page::PAGE_SIZE]);
+            if let Some(lsn) = lsn {
+                page.set_lsn(lsn);
+            }
+            return Ok(());
+        }
+        self.flush_wal_for(page_id.db_id, page::page_lsn(&buf)).await?;
+        checksum::stamp_page(self.checksum, &mut buf);
+        self.store.write_page(page_id, buf).await.1
+    }
+
+    // Pins `page_id`, loading it if needed; a `bulk_op`'s load counts against its budget,
+    // and anyone else's use of the page takes it out.
+    async fn pin_page(&self, page_id: PageId, bulk_op: bool) -> Result<FramePin<'_, S>, StorageError> {
+        let bulk = self.track_scan(page_id);
+        loop {
+            let cached = self.page_table.get(page_id);
+            if let Some(idx) = cached {
+                let frame = &self.frames[idx];
+                self.pin(idx);
+                let pin = FramePin { pool: self, idx };
+                // Wait out a read still filling the frame; if it failed, start over.
+                drop(frame.io.lock().await);
+                if frame.page_id.get() != Some(page_id) {
+                    continue;
+                }
+                if frame.prefetched.replace(false) {
+                    self.prefetch_hits.set(self.prefetch_hits.get() + 1);
+                }
+                // The scan's own ring pages stay next in line; anyone else's use
+                // takes the page out of the ring.
+                if !(bulk && frame.in_ring.get()) {
+                    self.leave_ring(idx);
+                    self.policy.borrow_mut().accessed(idx);
+                }
+                if !bulk_op {
+                    self.leave_bulk(idx);
+                }
+                self.count_hit();
+                return Ok(pin);
+            }
+            let idx = self.claim_frame(page_id, bulk).await?;
+            // Another caller may have loaded the page while a victim was written back.
+            if self.page_table.contains(page_id) {
+                self.release(idx);
+                continue;
+            }
+            self.count_miss();
+            if bulk_op {
+                self.frames[idx].bulk.set(true);
+                self.bulk_frames.set(self.bulk_frames.get() + 1);
+            }
+            return self.load(page_id, idx).await;
+        }
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(db_id = page_id.db_id, space_id = page_id.space_id, page_no = page_id.page_no)))]
+    async fn load(&self, page_id: PageId, idx: usize) -> Result<FramePin<'_, S>, StorageError> {
+        let frame = &self.frames[idx];
+        let io = self.map_frame(idx, page_id);
+        let buf = frame.buf.borrow_mut().take().unwrap();
+        let (buf, res) = self.store.read_page(page_id, buf).await;
+        *frame.buf.borrow_mut() = Some(buf);
+        let pin = FramePin { pool: self, idx };
+        if let Err(e) = res {
+            self.unmap(idx);
+            drop(io);
+            return Err(e);
+        }
+        drop(io);
+        Ok(pin)
+    }
+
+    /// Assigns the claimed frame `idx` to `page_id`, pinned once, and returns its `io`
+    /// lock for the read filling it. Taken before the first await, so callers arriving
+    /// mid-read queue on it.
+    fn map_frame(&self, idx: usize, page_id: PageId) -> MutexGuard<'_, ()> {
+        let frame = &self.frames[idx];
+        frame.page_id.set(Some(page_id));
+        self.pin(idx);
+        frame.dirty.set(false);
+        self.page_table.insert(page_id, idx);
+        self.policy.borrow_mut().inserted(idx, page_id);
+        if frame.in_ring.get() {
+            self.policy.borrow_mut().prefetched(idx);
+        }
+        frame.io.try_lock().expect("free frame has I/O in flight")
+    }
+
+    /// A frame to load `page_id` into: a free one, or else an evicted victim. For a
+    /// `bulk` scan's page, the scan ring's oldest frame once the ring is full.
+    async fn claim_frame(&self, page_id: PageId, bulk: bool) -> Result<usize, StorageError> {
+        let Some(ring) = self.scan_ring.as_ref().filter(|_| bulk) else {
+            return self.claim_victim(page_id).await;
+        };
+        let oldest = if self.ring.borrow().len() >= ring.frames { self.ring.borrow_mut().pop_front() } else { None };
+        let recycled = match oldest {
+            Some(idx) => {
+                self.frames[idx].in_ring.set(false);
+                self.evictable(idx) && self.evict(idx).await?
+            }
+            None => false,
+        };
+        let idx = match oldest {
+            Some(idx) if recycled => {
+                self.ring_recycles.set(self.ring_recycles.get() + 1);
+                idx
+            }
+            _ => self.claim_victim(page_id).await?,
+        };
+        self.frames[idx].in_ring.set(true);
+        self.ring.borrow_mut().push_back(idx);
+        Ok(idx)
+    }
+
+    async fn claim_victim(&self, page_id: PageId) -> Result<usize, StorageError> {
+        loop {
+            if let Some(idx) = self.free.borrow_mut().pop() {
+                return Ok(idx);
+            }
+            let victim = self.policy.borrow_mut().victim(page_id, &|idx| self.evictable(idx));
+            let idx = victim.ok_or(StorageError::BufferPoolExhausted)?;
+            if self.evict(idx).await? {
+                return Ok(idx);
+            }
+        }
+    }
+
+    /// Writes back and unmaps the page in frame `idx`. False if the frame was pinned,
+    /// dirtied again, taken by another caller or given up by a shrink meanwhile.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(idx, dirty = self.frames[idx].dirty.get(), db_id = tracing::field::Empty, space_id = tracing::field::Empty, page_no = tracing::field::Empty)))]
+    async fn evict(&self, idx: usize) -> Result<bool, StorageError> {
+        let frame = &self.frames[idx];
+        if frame.dirty.get() {
+            self.victim_writes.set(self.victim_writes.get() + 1);
+        }
+        // Even a clean victim may have a write-back in flight; that has to finish
+        // before the frame is refilled.
+        self.write_back(idx).await?;
+        if frame.pins.get() > 0 || frame.dirty.get() || idx >= self.active.get() {
+            return Ok(false);
+        }
+        let Some(victim) = frame.page_id.take() else {
+            return Ok(false);
+        };
+        trace::record!(db_id = victim.db_id, space_id = victim.space_id, page_no = victim.page_no);
+        self.page_table.remove(victim);
+        self.policy.borrow_mut().removed(idx);
+        self.leave_ring(idx);
+        self.leave_bulk(idx);
+        self.count_wasted_prefetch(idx);
+        self.evictions.set(self.evictions.get() + 1);
+        self.eviction_rate.record(1);
+        Ok(true)
+    }
+
+    fn count_wasted_prefetch(&self, idx: usize) {
+        if self.frames[idx].prefetched.replace(false) {
+            self.prefetch_wasted.set(self.prefetch_wasted.get() + 1);
+        }
+    }
+
+    fn leave_bulk(&self, idx: usize) {
+        if self.frames[idx].bulk.replace(false) {
+            self.bulk_frames.set(self.bulk_frames.get() - 1);
+        }
+    }
+
+    fn leave_ring(&self, idx: usize) {
+        if self.frames[idx].in_ring.replace(false) {
+            self.ring.borrow_mut().retain(|&ring_idx| ring_idx != idx);
+        }
+    }
+
+    /// Feeds `page_id` to its space's scan detector, queueing read-ahead once the
+    /// accesses look sequential. Returns whether the page belongs to a bulk scan.
+    fn track_scan(&self, page_id: PageId) -> bool {
+        let prefetch = self.prefetch.borrow().clone();
+        if prefetch.is_none() && self.scan_ring.is_none() {
+            return false;
+        }
+        let mut scans = self.scans.borrow_mut();
+        let scan = scans.entry((page_id.db_id, page_id.space_id)).or_insert(ScanState {
+            next: page_id.page_no,
+            run: 0,
+            prefetched_to: 0,
+        });
+        if page_id.page_no == scan.next {
+            scan.run += 1;
+        } else if page_id.page_no.wrapping_add(1) != scan.next {
+            // Re-reading the current page neither breaks nor extends a run.
+            scan.run = 1;
+            scan.prefetched_to = 0;
+        }
+        scan.next = page_id.page_no.saturating_add(1);
+        let bulk = self.scan_ring.as_ref().is_some_and(|ring| scan.run >= ring.trigger);
+
+        let Some(config) = prefetch else {
+            return bulk;
+        };
+        let ahead = scan.prefetched_to.saturating_sub(scan.next);
+        if scan.run < config.trigger || ahead > config.window / 2 {
+            return bulk;
+        }
+        let start = scan.next.max(scan.prefetched_to);
+        let end = scan.next.saturating_add(config.window);
+        let request = (PageId { page_no: start, ..page_id }, end - start, bulk);
+        if end > start && self.prefetch_tx.borrow_mut().try_send(request).is_ok() {
+            scan.prefetched_to = end;
+        }
+        bulk
+    }
+
+    /// Runs the prefetcher: reads ahead of the sequential scans `get_page` detects, as
+    /// configured. Never returns; spawn it on the pool's core. Without it running, no
+    /// scans are tracked.
+    pub async fn run_prefetcher(&self, config: PrefetchConfig) {
+        let Some(mut requests) = self.prefetch_rx.borrow_mut().take() else {
+            panic!("buffer pool prefetcher started twice");
+        };
+        *self.prefetch.borrow_mut() = Some(config);
+        while let Some((start, pages, bulk)) = requests.next().await {
+            if let Err(e) = self.read_ahead(start, pages, bulk).await {
+                trace::warn_event!("buffer pool: prefetch of {:?} (+{} pages) failed: {:?}", start, pages, e);
+            }
+        }
+    }
+
+    /// Queues `range` to be read into the pool by the prefetcher, and returns at once.
+    /// Frames are taken as for any miss, and the pages are left unpinned, as the
+    /// policy's next victims until first requested. A hint only: pages already cached
+    /// are skipped, and requests beyond what the prefetcher has queued are dropped.
+    /// Panics if the range spans spaces.
+    pub fn prefetch(&self, range: Range<PageId>) {
+        let (start, end) = (range.start, range.end);
+        assert!(
+            (start.db_id, start.space_id) == (end.db_id, end.space_id),
+            "prefetch range {:?}..{:?} spans spaces",
+            start,
+            end
+        );
+        let mut page_no = start.page_no;
+        while page_no < end.page_no {
+            let pages = (end.page_no - page_no).min(MAX_READ_AHEAD);
+            if self.prefetch_tx.borrow_mut().try_send((PageId { page_no, ..start }, pages, false)).is_err() {
+                return;
+            }
+            page_no += pages;
+        }
+    }
+
+    /// The pages cached now, for `warmup::save`.
+    pub fn resident_pages(&self) -> Vec<PageId> {
+        self.frames.iter().filter_map(|frame| frame.page_id.get()).collect()
+    }
+
+    /// Reads `pages` (sorted, as `warmup::load` returns them) into the pool, as many as
+    /// fit in its free frames, coalescing neighbours into one `read_pages` each. Pages
+    /// that can't be read are skipped. Returns the pages loaded.
+    pub async fn warm_up(&self, pages: &[PageId]) -> usize {
+        let before = self.len();
+        let mut runs: Vec<(PageId, u32)> = Vec::new();
+        for &page_id in pages.iter().take(self.free.borrow().len()) {
+            match runs.last_mut() {
+                Some((start, len))
+                    if (start.db_id, start.space_id) == (page_id.db_id, page_id.space_id)
+                        && start.page_no.checked_add(*len) == Some(page_id.page_no)
+                        && *len < MAX_READ_AHEAD =>
+                {
+                    *len += 1
+                }
+                _ => runs.push((page_id, 1)),
+            }
+        }
+        futures::stream::iter(runs)
+            .map(|(start, len)| async move {
+                if let Err(e) = self.read_ahead(start, len, false).await {
+                    trace::warn_event!("buffer pool: warm-up of {:?} (+{} pages) failed: {:?}", start, len, e);
+                }
+            })
+            .buffer_unordered(WARM_UP_RUNS)
+            .collect::<()>()
+            .await;
+        self.len() - before
+    }
+
+    /// Reads the uncached pages among `pages` pages from `start` into the pool, one
+    /// `read_pages` per contiguous run, all in flight at once; a `bulk` scan's go into
+    /// its ring. Stops early, without an error, when no frame can be freed.
+    async fn read_ahead(&self, start: PageId, pages: u32, bulk: bool) -> Result<(), StorageError> {
+        let mut runs: Vec<Vec<(usize, MutexGuard<'_, ()>)>> = vec![Vec::new()];
+        for page_no in start.page_no..start.page_no.saturating_add(pages) {
+            let page_id = PageId { page_no, ..start };
+            if self.page_table.contains(page_id) {
+                runs.push(Vec::new());
+                continue;
+            }
+            let idx = match self.claim_frame(page_id, bulk).await {
+                Ok(idx) => idx,
+                Err(StorageError::BufferPoolExhausted) => break,
+                Err(e) => {
+                    runs.into_iter().for_each(|run| self.unmap_run(run));
+                    return Err(e);
+                }
+            };
+            // Claiming may have awaited a victim's write-back; a reader may have got here.
+            if self.page_table.contains(page_id) {
+                self.release(idx);
+                runs.push(Vec::new());
+                continue;
+            }
+            let io = self.map_frame(idx, page_id);
+            self.policy.borrow_mut().prefetched(idx);
+            runs.last_mut().unwrap().push((idx, io));
+        }
+        runs.retain(|run| !run.is_empty());
+
+        let results = join_all(runs.into_iter().map(|run| self.fill_run(run))).await;
+        results.into_iter().collect()
+    }
+
+    // Reads a run of consecutive pages mapped by `read_ahead`, and unpins them.
+    async fn fill_run(&self, run: Vec<(usize, MutexGuard<'_, ()>)>) -> Result<(), StorageError> {
+        let start = self.frames[run[0].0].page_id.get().unwrap();
+        let bufs = run.iter().map(|(idx, _)| self.frames[*idx].buf.borrow_mut().take().unwrap()).collect();
+        let (bufs, res) = self.store.read_pages(start, bufs).await;
+        for ((idx, _), buf) in run.iter().zip(bufs) {
+            *self.frames[*idx].buf.borrow_mut() = Some(buf);
+        }
+        if res.is_err() {
+            self.unmap_run(run);
+            return res;
+        }
+        self.prefetched.set(self.prefetched.get() + run.len() as u64);
+        for (idx, io) in run {
+            // Before the I/O lock goes, so a request waiting on it finds the page counted.
+            self.frames[idx].prefetched.set(true);
+            drop(io);
+            self.unpin(idx);
+        }
+        Ok(())
+    }
+
+    // Undoes `map_frame` for frames whose read failed or never started.
+    fn unmap_run(&self, run: Vec<(usize, MutexGuard<'_, ()>)>) {
+        for (idx, io) in run {
+            self.unmap(idx);
+            drop(io);
+            self.unpin(idx);
+        }
+    }
+
+    /// Drops the frame's page from the page table. The frame is freed once unpinned.
+    fn unmap(&self, idx: usize) {
+        if let Some(page_id) = self.frames[idx].page_id.take() {
+            self.page_table.remove(page_id);
+            self.policy.borrow_mut().removed(idx);
+            self.leave_ring(idx);
+            self.leave_bulk(idx);
+            self.count_wasted_prefetch(idx);
+        }
+    }
+
+    // Frames being given up by a shrink are evicted by it, not by the policy.
+    fn evictable(&self, idx: usize) -> bool {
+        let frame = &self.frames[idx];
+        frame.pins.get() == 0 && frame.page_id.get().is_some() && idx < self.active.get()
+    }
+
+    fn count_hit(&self) {
+        self.hits.set(self.hits.get() + 1);
+        if let Some(metrics) = &self.metrics {
+            metrics.buffer_pool_hits.inc();
+        }
+    }
+
+    fn count_miss(&self) {
+        self.misses.set(self.misses.get() + 1);
+        if let Some(metrics) = &self.metrics {
+            metrics.buffer_pool_misses.inc();
+        }
+    }
+
+    fn pin(&self, idx: usize) {
+        let frame = &self.frames[idx];
+        if frame.pins.get() == 0 {
+            frame.pinned_at.set(Some(self.runtime.now()));
+        }
+        frame.pins.set(frame.pins.get() + 1);
+    }
+
+    fn unpin(&self, idx: usize) {
+        let frame = &self.frames[idx];
+        frame.pins.set(frame.pins.get() - 1);
+        if frame.pins.get() == 0 {
+            if let Some(since) = frame.pinned_at.take() {
+                self.pin_periods.set(self.pin_periods.get() + 1);
+                self.pinned_time.set(self.pinned_time.get() + self.runtime.now().saturating_duration_since(since));
+            }
+        }
+        // A frame whose page was dropped goes back once its last user lets go.
+        if frame.pins.get() == 0 && frame.page_id.get().is_none() {
+            self.release(idx);
+        }
+    }
+
+    // Returns an idle frame to the free list, unless a shrink is giving it up.
+    fn release(&self, idx: usize) {
+        self.leave_ring(idx);
+        self.leave_bulk(idx);
+        if idx < self.active.get() {
+            self.free.borrow_mut().push(idx);
+        }
+    }
+
+    /// Grows or shrinks the pool to about `target_bytes` of pages: whole `FRAME_CHUNK`s,
+    /// at least one, at most `max_frames`. Returns the frames now in use. Shrinking
+    /// writes back and evicts the pages in the frames given up, and waits until their
+    /// pins are released. After an error the pool keeps its new size, with some pages
+    /// left in frames given up; resizing again finishes (or undoes) the job.
+    pub async fn resize(&self, target_bytes: usize) -> Result<usize, StorageError> {
+        let _resizing = self.resizing.lock().await;
+        let target = target_bytes
+            .div_ceil(page::PAGE_SIZE)
+            .next_multiple_of(FRAME_CHUNK)
+            .max(FRAME_CHUNK)
+            .min(self.frames.len());
+        let active = self.active.get();
+        if target > active {
+            self.grow(active..target);
+        } else if target < active {
+            self.shrink(target..active).await?;
+        }
+        Ok(self.active.get())
+    }
+
+    fn grow(&self, added: Range<usize>) {
+        self.active.set(added.end);
+        // In reverse, so the new frames are handed out in order. Frames still holding a
+        // page from an unfinished shrink just become evictable again.
+        for idx in added.rev() {
+            let frame = &self.frames[idx];
+            if frame.page_id.get().is_some() || frame.pins.get() > 0 {
+                continue;
+            }
+            frame.buf.borrow_mut().get_or_insert_with(|| self.frame_buf());
+            self.release(idx);
+        }
+    }
+
+    /// Moves every frame's page to NUMA node `node`, and allocates there from now on.
+    /// Call before the pool is used: frames being read into are skipped.
+    pub fn bind_to_numa_node(&self, node: usize) -> Result<(), StorageError> {
+        for frame in &self.frames {
+            if let Some(buf) = frame.buf.borrow().as_ref() {
+                numa::bind(buf, node)?;
+            }
+        }
+        self.numa_node.set(Some(node));
+        Ok(())
+    }
+
+    // Page memory for a frame being added, on the pool's NUMA node if it has one
+    fn frame_buf(&self) -> AlignedBuf {
+        let buf = AlignedBuf::page();
+        if let Some(node) = self.numa_node.get() {
+            if let Err(e) = numa::bind(&buf, node) {
+                trace::warn_event!("buffer pool: binding frames to NUMA node {} failed, no longer trying: {:?}", node, e);
+                self.numa_node.set(None);
+            }
+        }
+        buf
+    }
+
+    async fn shrink(&self, removed: Range<usize>) -> Result<(), StorageError> {
+        self.active.set(removed.start);
+        self.free.borrow_mut().retain(|&idx| idx < removed.start);
+        let mut left: Vec<usize> = removed.collect();
+        loop {
+            let mut pinned = Vec::new();
+            for idx in left {
+                let frame = &self.frames[idx];
+                if frame.page_id.get().is_some() {
+                    // Also waits out I/O in flight on the frame.
+                    self.write_back(idx).await?;
+                    if frame.pins.get() > 0 || frame.dirty.get() {
+                        pinned.push(idx);
+                        continue;
+                    }
+                    if frame.page_id.get().is_some() {
+                        self.unmap(idx);
+                        self.evictions.set(self.evictions.get() + 1);
+                    }
+                } else if frame.pins.get() > 0 {
+                    pinned.push(idx);
+                    continue;
+                }
+                frame.buf.borrow_mut().take();
+            }
+            if pinned.is_empty() {
+                return Ok(());
+            }
+            left = pinned;
+            self.runtime.sleep(RESIZE_RETRY).await;
+        }
+    }
+
+    /// Writes `page_id` back if it is cached and dirty. The WAL is made durable first,
+    /// so the log always covers every change on disk.
+    pub async fn flush_page(&self, page_id: PageId) -> Result<(), StorageError> {
+        let cached = self.page_table.get(page_id);
+        match cached {
+            Some(idx) => self.write_back(idx).await.map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes back every page of `db_id` dirty when called, or being modified then, and
+    /// waits out write-backs of its pages already in flight. Once this returns, every
+    /// change made before the call is on disk. Returns the pages written.
+    pub async fn flush_database(&self, db_id: u32) -> Result<usize, StorageError> {
+        self.flush_frames(|page_id| page_id.db_id == db_id).await
+    }
+
+    /// The dirty page table of `db_id`: its cached pages with logged changes not yet on
+    /// disk, each with the LSN of the oldest one. Writers go on meanwhile; only pages
+    /// being modified are waited for, so every change logged before the call is covered.
+    pub async fn dirty_pages(&self, db_id: u32) -> Vec<DirtyPage> {
+        let mut dirty = Vec::new();
+        for frame in &self.frames {
+            if frame.page_id.get().is_none_or(|page_id| page_id.db_id != db_id) {
+                continue;
+            }
+            let _latch = frame.latch.read().await;
+            let Some(page_id) = frame.page_id.get().filter(|page_id| page_id.db_id == db_id && frame.dirty.get()) else {
+                continue;
+            };
+            if let Some(rec_lsn) = frame.rec_lsn.get() {
+                dirty.push(DirtyPage { page_id, rec_lsn });
+            }
+        }
+        dirty
+    }
+
+    /// LSN of the oldest logged change to `page_id` not yet on disk, if it has one.
+    pub fn rec_lsn(&self, page_id: PageId) -> Option<Lsn> {
+        let frame = &self.frames[self.page_table.get(page_id)?];
+        if frame.page_id.get() != Some(page_id) || !frame.dirty.get() {
+            return None;
+        }
+        frame.rec_lsn.get()
+    }
+
+    /// Writes back those of `pages` still holding changes older than `lsn`, without
+    /// waiting on any: pages latched by a writer, or with a read or write-back in
+    /// flight, are skipped. Returns the pages written, and those skipped.
+    pub async fn flush_older(&self, pages: &[PageId], lsn: Lsn) -> Result<(usize, Vec<PageId>), StorageError> {
+        let mut frames: Vec<(PageId, usize)> = pages
+            .iter()
+            .filter(|&&page_id| self.rec_lsn(page_id).is_some_and(|rec_lsn| rec_lsn < lsn))
+            .map(|&page_id| (page_id, self.page_table.get(page_id).unwrap()))
+            .collect();
+        frames.sort_by_key(|(page_id, _)| (page_id.db_id, page_id.space_id, page_id.page_no));
+        let (mut written, mut skipped) = (0, Vec::new());
+        for batch in frames.chunks(FLUSH_BATCH) {
+            let idxs: Vec<usize> = batch.iter().map(|(_, idx)| *idx).collect();
+            let (batch_written, busy) = self.write_batch(&idxs).await?;
+            written += batch_written;
+            skipped.extend(batch.iter().filter(|(_, idx)| busy.contains(idx)).map(|(page_id, _)| *page_id));
+        }
+        Ok((written, skipped))
+    }
+
+    /// Logs the current image of `page_id`, if it is still dirty with a change older
+    /// than `lsn`, so redo from before `lsn` can restore it torn (see `full_page.rs`).
+    /// For the pages a checkpoint leaves dirty. Returns whether it was logged.
+    pub async fn log_image(&self, page_id: PageId, lsn: Lsn) -> Result<bool, StorageError> {
+        if self.rec_lsn(page_id).is_none_or(|rec_lsn| rec_lsn >= lsn) {
+            return Ok(false);
+        }
+        // The read latch keeps writers out until the image is logged.
+        let page = self.get_page(page_id).await?;
+        let image = FullPageImage::of(page_id, &page);
+        self.store.append_wal(page_id.db_id, FullPageImage::TYPE, &image.to_payload()).await?;
+        Ok(true)
+    }
+
+    // The image to log ahead of the first change to `page`, if it needs one. Were the
+    // horizon unavailable, the change couldn't be logged either.
+    fn full_page_image(&self, page_id: PageId, page: &[u8]) -> Option<FullPageImage> {
+        let horizon = self.store.full_page_horizon(page_id.db_id).unwrap_or(None);
+        full_page::needs_image(page, horizon).then(|| FullPageImage::of(page_id, page))
+    }
+
+    /// Writes back every dirty page.
+    pub async fn flush_all(&self) -> Result<(), StorageError> {
+        self.flush_frames(|_| true).await.map(|_| ())
+    }
+
+    // Writes back the frames holding pages that match, in page order, so batches
+    // coalesce into long runs.
+    async fn flush_frames(&self, matches: impl Fn(PageId) -> bool) -> Result<usize, StorageError> {
+        let mut frames: Vec<(PageId, usize)> = (0..self.frames.len())
+            .filter_map(|idx| self.frames[idx].page_id.get().filter(|&page_id| matches(page_id)).map(|page_id| (page_id, idx)))
+            .collect();
+        frames.sort_by_key(|(page_id, _)| (page_id.db_id, page_id.space_id, page_id.page_no));
+        let frames: Vec<usize> = frames.into_iter().map(|(_, idx)| idx).collect();
+        let mut written = 0;
+        for batch in frames.chunks(FLUSH_BATCH) {
+            let (batch_written, busy) = self.write_batch(batch).await?;
+            written += batch_written;
+            // One at a time: waiting for one latch while holding others could deadlock
+            // with a writer latching pages in a different order.
+            for idx in busy {
+                written += self.write_back(idx).await? as usize;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Runs the background writer: every `interval`, one `clean_ahead` round. Never
+    /// returns; spawn it on the pool's core next to the foreground work.
+    pub async fn run_background_writer(&self, config: BackgroundWriterConfig) {
+        loop {
+            if let Err(e) = self.clean_ahead(&config).await {
+                trace::warn_event!("buffer pool: background write-back failed: {:?}", e);
+            }
+            self.runtime.sleep(config.interval).await;
+        }
+    }
+
+    /// One background writer round: writes back the dirty frames among the next
+    /// `clean_target` victims (less the free frames), at most `max_pages_per_round` of
+    /// them. Returns how many were written.
+    pub async fn clean_ahead(&self, config: &BackgroundWriterConfig) -> Result<usize, StorageError> {
+        let free = self.free.borrow().len();
+        if free >= config.clean_target {
+            return Ok(0);
+        }
+        let upcoming = self.policy.borrow().next_victims(config.clean_target - free, &|idx| self.evictable(idx));
+        let dirty: Vec<usize> = upcoming
+            .into_iter()
+            .filter(|&idx| self.frames[idx].dirty.get())
+            .take(config.max_pages_per_round)
+            .collect();
+        let (written, _) = self.write_batch(&dirty).await?;
+        self.background_writes.set(self.background_writes.get() + written as u64);
+        Ok(written)
+    }
+
+    /// Writes back dirty frames together: images are copied first, then each database's
+    /// WAL is flushed once if needed, then the pages are written, runs of neighbours with one
+    /// `write_pages` each, all submitted at once. Returns the pages written, and the
+    /// frames skipped for being latched by a writer or having a read or write-back in
+    /// flight.
+    async fn write_batch(&self, idxs: &[usize]) -> Result<(usize, Vec<usize>), StorageError> {
+        // Held until the writes complete
+        let mut claimed: Vec<(RwLockReadGuard<'_, ()>, MutexGuard<'_, ()>)> = Vec::new();
+        let mut writes: Vec<(usize, PageId, AlignedBuf)> = Vec::new();
+        let mut busy = Vec::new();
+        for &idx in idxs {
+            let frame = &self.frames[idx];
+            let (Ok(latch), Some(io)) = (frame.latch.try_read(), frame.io.try_lock()) else {
+                busy.push(idx);
+                continue;
+            };
+            let Some(page_id) = frame.page_id.get().filter(|_| frame.dirty.get()) else {
+                continue;
+            };
+            writes.push((idx, page_id, self.stamped_image(frame)));
+            claimed.push((latch, io));
+        }
+        if writes.is_empty() {
+            return Ok((0, busy));
+        }
+
+        // Newest page LSN of each database
+        let mut newest: HashMap<u32, Lsn> = HashMap::new();
+        for (_, page_id, image) in &writes {
+            let lsn = newest.entry(page_id.db_id).or_insert(Lsn(0));
+            *lsn = (*lsn).max(page::page_lsn(image));
+        }
+        for (db_id, lsn) in newest {
+            if let Err(e) = self.flush_wal_for(db_id, lsn).await {
+                for (idx, ..) in &writes {
+                    self.frames[*idx].dirty.set(true);
+                }
+                return Err(e);
+            }
+        }
+
+        writes.sort_by_key(|(_, page_id, _)| (page_id.db_id, page_id.space_id, page_id.page_no));
+        let mut runs: Vec<(PageId, Vec<usize>, Vec<AlignedBuf>)> = Vec::new();
+        for (idx, page_id, image) in writes {
+            match runs.last_mut() {
+                // Segments are separate files: a run never crosses into the next one.
+                Some((start, idxs, images))
+                    if (start.db_id, start.space_id) == (page_id.db_id, page_id.space_id)
+                        && start.page_no + idxs.len() as u32 == page_id.page_no
+                        && page_id.page_no % segment::PAGES_PER_SEGMENT != 0
+                        && idxs.len() < MAX_WRITE_RUN =>
+                {
+                    idxs.push(idx);
+                    images.push(image);
+                }
+                _ => runs.push((page_id, vec![idx], vec![image])),
+            }
+        }
+
+        let mut run_frames = Vec::with_capacity(runs.len());
+        let mut run_writes = Vec::with_capacity(runs.len());
+        for (start, idxs, images) in runs {
+            run_frames.push(idxs);
+            run_writes.push(self.write_run(start, images));
+        }
+        let results = join_all(run_writes).await;
+        let mut written = 0;
+        let mut first_err = None;
+        for (idxs, res) in run_frames.iter().zip(results) {
+            match res {
+                Ok(()) => written += idxs.len(),
+                Err(e) => {
+                    for &idx in idxs {
+                        self.frames[idx].dirty.set(true);
+                    }
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+        drop(claimed);
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok((written, busy)),
+        }
+    }
+
+    // One write per run: `write_pages` for neighbours, `write_page` for a lone page.
+    async fn write_run(&self, start: PageId, mut images: Vec<AlignedBuf>) -> Result<(), StorageError> {
+        if images.len() == 1 {
+            return self.store.write_page(start, images.pop().unwrap()).await.1;
+        }
+        self.store.write_pages(start, images).await.1
+    }
+
+    /// Writes the frame's page back if it is dirty. Returns whether it was.
+    async fn write_back(&self, idx: usize) -> Result<bool, StorageError> {
+        let frame = &self.frames[idx];
+        // Held through the write: a writer can't start on the page, or drop it, meanwhile.
+        let _latch = frame.latch.read().await;
+        let _io = frame.io.lock().await;
+        let Some(page_id) = frame.page_id.get() else {
+            return Ok(false);
+        };
+        if !frame.dirty.get() {
+            return Ok(false);
+        }
+
+        let image = self.stamped_image(frame);
+        let res = match self.flush_wal_for(page_id.db_id, page::page_lsn(&image)).await {
+            Ok(()) => self.store.write_page(page_id, image).await.1,
+            Err(e) => Err(e),
+        };
+        if res.is_err() {
+            frame.dirty.set(true);
+        }
+        res.map(|()| true)
+    }
+
+    // Write-ahead logging: makes the WAL durable past `page_lsn`, the LSN of a page
+    // about to be written, unless it already is.
+    async fn flush_wal_for(&self, db_id: u32, page_lsn: Lsn) -> Result<(), StorageError> {
+        if page_lsn < self.store.flushed_lsn(db_id)? {
+            return Ok(());
+        }
+        self.store.flush_wal(db_id).await
+    }
+
+    /// A stamped copy of the frame's page, to write while it stays readable. Marks the
+    /// frame clean: changes made after the write dirty it again, and go out with the
+    /// next write-back. The caller holds the frame's latch.
+    fn stamped_image(&self, frame: &Frame) -> AlignedBuf {
+        let mut image = AlignedBuf::page();
+        image.copy_from_slice(frame.buf.borrow().as_ref().unwrap());
+        frame.dirty.set(false);
+        checksum::stamp_page(self.checksum, &mut image);
+        image
+    }
+}
+
+// A pin on a frame, released on drop. Guards drop it after their latch.
+struct FramePin<'a, S: PageStore + WalStore> {
+    pool: &'a BufferPool<S>,
+    idx: usize,
+}
+
+impl<'a, S: PageStore + WalStore> FramePin<'a, S> {
+    fn frame(&self) -> &'a Frame {
+        &self.pool.frames[self.idx]
+    }
+}
+
+impl<S: PageStore + WalStore> Drop for FramePin<'_, S> {
+    fn drop(&mut self) {
+        self.pool.unpin(self.idx);
+    }
+}
+
+/// A page pinned and latched for reading. Other readers may hold the page at the same
+/// time; writers wait until every read guard is dropped.
+pub struct PageReadGuard<'a, S: PageStore + WalStore> {
+    // Dropped in this order: the borrow, the latch, then the pin
+    page: Ref<'a, [u8]>,
+    _latch: RwLockReadGuard<'a, ()>,
+    pin: FramePin<'a, S>,
+}
+
+impl<'a, S: PageStore + WalStore> PageReadGuard<'a, S> {
+    pub fn page_id(&self) -> PageId {
+        self.pin.frame().page_id.get().unwrap()
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.pin.frame().dirty.get()
+    }
+
+    /// Latch coupling: read-latches `child`, then releases this page. A reader
+    /// descending a tree this way never sees a child its parent no longer points to.
+    pub async fn couple(self, child: PageId) -> Result<PageReadGuard<'a, S>, StorageError> {
+        let child = self.pin.pool.get_page(child).await?;
+        drop(self);
+        Ok(child)
+    }
+}
+
+impl<S: PageStore + WalStore> Deref for PageReadGuard<'_, S> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.page
+    }
+}
+
+/// A page pinned and latched exclusively for modification. Dropping it after a change
+/// (any mutable access, or `set_lsn`) marks the page dirty and stamps the LSN given to
+/// `set_lsn`; a guard only taken to exclude others leaves the page as it was. Hold it until the WAL record of the
+/// change is appended: a checkpoint waits for it, and so counts the change as logged
+/// before it began. The checksum is stamped on write-back. Append the record with
+/// `log`, which also logs the page's full image when the change needs one.
+///
+/// If the holder panics, the latch and pin are still released. The interrupted change
+/// has no WAL record, so a page that was clean is dropped from the pool, and the next
+/// reader gets the on-disk image; a dirty one keeps the partial change.
+pub struct PageWriteGuard<'a, S: PageStore + WalStore> {
+    page: RefMut<'a, [u8]>,
+    lsn: Option<Lsn>,
+    modified: bool,
+    // The page before its first change, until `log` appends it
+    image: Option<FullPageImage>,
+    // Mapped by `get_page_overwrite` without reading the page
+    unread: bool,
+    _latch: RwLockWriteGuard<'a, ()>,
+    pin: FramePin<'a, S>,
+}
+
+impl<S: PageStore + WalStore> PageWriteGuard<'_, S> {
+    pub fn page_id(&self) -> PageId {
+        self.pin.frame().page_id.get().unwrap()
+    }
+
+    /// The LSN of the WAL record describing the change, stamped on the page on drop.
+    pub fn set_lsn(&mut self, lsn: Lsn) {
+        self.lsn = Some(lsn);
+        self.modified = true;
+    }
+
+    /// Appends `record`, the WAL record of the change, after the page's full image if
+    /// full-page writes call for one, and stamps the record's LSN on the page.
+    pub async fn log<R: WalRecord>(&mut self, record: &R) -> Result<Lsn, StorageError> {
+        let db_id = self.page_id().db_id;
+        let store = &self.pin.pool.store;
+        if let Some(image) = &self.image {
+            store.append_wal(db_id, FullPageImage::TYPE, &image.to_payload()).await?;
+            self.image = None;
+        }
+        let lsn = store.append_wal(db_id, R::TYPE, &record.to_payload()).await?;
+        self.set_lsn(lsn);
+        Ok(lsn)
+    }
+
+    /// Makes the change `record` describes, by its redo, and `log`s it: for access
+    /// methods whose records say all there is to a change.
+    pub async fn apply<R: PageRecord>(&mut self, record: &R) -> Result<Lsn, StorageError> {
+        record.redo(self);
+        self.log(record).await
+    }
+}
+
+impl<S: PageStore + WalStore> Deref for PageWriteGuard<'_, S> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.page
+    }
+}
+
+impl<S: PageStore + WalStore> DerefMut for PageWriteGuard<'_, S> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        if !self.modified && !self.unread {
+            self.image = self.pin.pool.full_page_image(self.page_id(), &self.page);
+        }
+        self.modified = true;
+        &mut self.page
+    }
+}
+
+impl<S: PageStore + WalStore> Drop for PageWriteGuard<'_, S> {
+    fn drop(&mut self) {
+        let frame = self.pin.frame();
+        if !self.modified {
+            if self.unread {
+                self.pin.pool.unmap(self.pin.idx);
+            }
+            return;
+        }
+        if std::thread::panicking() {
+            if !frame.dirty.get() {
+                self.pin.pool.unmap(self.pin.idx);
+            }
+            return;
+        }
+        if let Some(lsn) = self.lsn {
+            page::set_page_lsn(&mut self.page, lsn);
+        }
+        if !frame.dirty.replace(true) || frame.rec_lsn.get().is_none() {
+            frame.rec_lsn.set(self.lsn);
+        }
+    }
+}
+
+/// A page read by a bulk operation: latched in the pool, or a private copy read
+/// around it once the operation's budget is used up.
+pub enum BulkPage<'a, S: PageStore + WalStore> {
+    Cached(PageReadGuard<'a, S>),
+    Direct(AlignedBuf),
+}
+
+impl<S: PageStore + WalStore> Deref for BulkPage<'_, S> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            BulkPage::Cached(guard) => guard,
+            BulkPage::Direct(buf) => &buf[..page::PAGE_SIZE],
+        }
+    }
+}
+
+/// A page being modified by a bulk operation: latched in the pool, or a private copy
+/// written back to the store by `finish`. Dropping a private copy unfinished loses the
+/// change.
+#[must_use = "a bulk page's change is only kept once it is finished"]
+pub enum BulkPageMut<'a, S: PageStore + WalStore> {
+    Cached(PageWriteGuard<'a, S>),
+    Direct {
+        pool: &'a BufferPool<S>,
+        page_id: PageId,
+        buf: AlignedBuf,
+        lsn: Option<Lsn>,
+    },
+}
+
+impl<S: PageStore + WalStore> BulkPageMut<'_, S> {
+    /// The LSN of the WAL record describing the change, stamped on the page.
+    pub fn set_lsn(&mut self, lsn: Lsn) {
+        match self {
+            BulkPageMut::Cached(guard) => guard.set_lsn(lsn),
+            BulkPageMut::Direct { lsn: page_lsn, .. } => *page_lsn = Some(lsn),
+        }
+    }
+
+    /// Keeps the change: a latched page is released dirty, as by dropping its guard; a
+    /// private copy is written once the WAL is durable, or into the pool if the page has
+    /// been cached meanwhile.
+    pub async fn finish(self) -> Result<(), StorageError> {
+        match self {
+            BulkPageMut::Cached(_) => Ok(()),
+            BulkPageMut::Direct { pool, page_id, buf, lsn } => pool.write_direct(page_id, buf, lsn).await,
+        }
+    }
+}
+
+impl<S: PageStore + WalStore> Deref for BulkPageMut<'_, S> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            BulkPageMut::Cached(guard) => guard,
+            BulkPageMut::Direct { buf, .. } => &buf[..page::PAGE_SIZE],
+        }
+    }
+}
+
+impl<S: PageStore + WalStore> DerefMut for BulkPageMut<'_, S> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            BulkPageMut::Cached(guard) => guard,
+            BulkPageMut::Direct { buf, .. } => &mut buf[..page::PAGE_SIZE],
+        }
+    }
+}
+
+/// Write latches held down one path of a tree, root first, for a change that may
+/// spread upwards (a split or merge). Latch crabbing: each page is latched before its
+/// ancestors may be released, and they are released once it is known not to need them.
+pub struct LatchPath<'a, S: PageStore + WalStore> {
+    pool: &'a BufferPool<S>,
+    guards: Vec<PageWriteGuard<'a, S>>,
+}
+
+impl<'a, S: PageStore + WalStore> LatchPath<'a, S> {
+    pub fn new(pool: &'a BufferPool<S>) -> Self {
+        Self { pool, guards: Vec::new() }
+    }
+
+    /// Write-latches `page_id`, a child of the last page held, and returns it.
+    pub async fn descend(&mut self, page_id: PageId) -> Result<&mut PageWriteGuard<'a, S>, StorageError> {
+        let guard = self.pool.get_page_mut(page_id).await?;
+        self.guards.push(guard);
+        Ok(self.guards.last_mut().unwrap())
+    }
+
+    /// Write-latches `page_id`, the right sibling of the last page held, then releases
+    /// that page and returns the sibling in its place: for a B-link tree whose key range
+    /// moved right in a split.
+    pub async fn step_right(&mut self, page_id: PageId) -> Result<&mut PageWriteGuard<'a, S>, StorageError> {
+        let guard = self.pool.get_page_mut(page_id).await?;
+        self.guards.pop();
+        self.guards.push(guard);
+        Ok(self.guards.last_mut().unwrap())
+    }
+
+    /// Releases every page above the last one: for when it can absorb the change
+    /// without touching its parent.
+    pub fn release_ancestors(&mut self) {
+        if self.guards.len() > 1 {
+            self.guards.drain(..self.guards.len() - 1);
+        }
+    }
+
+    /// The last page latched.
+    pub fn leaf(&mut self) -> Option<&mut PageWriteGuard<'a, S>> {
+        self.guards.last_mut()
+    }
+
+    /// Releases the last page and returns its parent, for carrying a change upwards.
+    pub fn ascend(&mut self) -> Option<&mut PageWriteGuard<'a, S>> {
+        self.guards.pop();
+        self.guards.last_mut()
+    }
+
+    /// Pages held, from the highest one still latched down to the last.
+    pub fn guards(&mut self) -> &mut [PageWriteGuard<'a, S>] {
+        &mut self.guards
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::join_all;
+
+    use crate::mem_store::MemStore;
+    use crate::wal_record::WalRecordType;
+
+    use super::*;
+
+    const FRAMES: usize = 16;
+    const PAGES: u32 = 200;
+    const TASKS: u64 = 8;
+    const OPS: usize = 400;
+    const KINDS: [EvictionKind; 3] = [EvictionKind::ClockSweep, EvictionKind::Lru2, EvictionKind::TinyLfu];
+
+    fn page_id(page_no: u32) -> PageId {
+        PageId { db_id: 1, space_id: 1, page_no }
+    }
+
+    fn pool(eviction: EvictionKind) -> BufferPool<MemStore> {
+        let store = Rc::new(MemStore::new(ChecksumAlgorithm::Crc32c));
+        BufferPool::new(store, FRAMES, FRAMES, ChecksumAlgorithm::Crc32c, eviction)
+    }
+
+    // Overwrites the page's body with `value`, logged
+    async fn set(pool: &BufferPool<MemStore>, page_no: u32, value: u64) -> Result<(), StorageError> {
+        let mut page = pool.get_page_mut(page_id(page_no)).await?;
+        for chunk in page[page::PAGE_HEADER_SIZE..].chunks_exact_mut(8) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        let lsn = pool.store.append_wal(1, WalRecordType::OPAQUE, &value.to_le_bytes()).await?;
+        page.set_lsn(lsn);
+        Ok(())
+    }
+
+    // Whether the whole body holds `value`
+    fn holds(page: &[u8], value: u64) -> bool {
+        page[page::PAGE_HEADER_SIZE..].chunks_exact(8).all(|chunk| chunk == value.to_le_bytes())
+    }
+
+    // xorshift64*
+    fn next(state: &mut u64) -> u64 {
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    #[tokio::test]
+    async fn evicts_and_writes_back_more_pages_than_frames() {
+        for eviction in KINDS {
+            let pool = pool(eviction);
+            for page_no in 0..PAGES {
+                set(&pool, page_no, page_no as u64 + 1).await.unwrap();
+                assert!(pool.len() <= FRAMES);
+            }
+            assert!(pool.evictions() >= (PAGES as usize - FRAMES) as u64, "{:?}", eviction);
+            assert!(pool.store.page_count() >= PAGES as usize - FRAMES, "{:?}", eviction);
+
+            for page_no in (0..PAGES).rev() {
+                let page = pool.get_page(page_id(page_no)).await.unwrap();
+                assert!(holds(&page, page_no as u64 + 1), "{:?} page {}", eviction, page_no);
+            }
+            pool.flush_all().await.unwrap();
+            for page_no in 0..PAGES {
+                let stored = pool.store.page(page_id(page_no)).unwrap();
+                assert!(checksum::verify_page(ChecksumAlgorithm::Crc32c, &stored), "{:?} page {}", eviction, page_no);
+                assert!(holds(&stored, page_no as u64 + 1), "{:?} page {}", eviction, page_no);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_readers_and_writers_keep_every_change() {
+        for eviction in KINDS {
+            let pool = pool(eviction);
+            let expected = RefCell::new(vec![0u64; PAGES as usize]);
+            let tasks = (0..TASKS).map(|task| {
+                let (pool, expected) = (&pool, &expected);
+                async move {
+                    let mut rng = task + 1;
+                    for op in 0..OPS {
+                        // Each task writes only its own pages, so it knows what they hold.
+                        let page_no = (next(&mut rng) % (PAGES as u64 / TASKS) * TASKS + task) as u32;
+                        if next(&mut rng).is_multiple_of(3) {
+                            let stamp = (task << 32) | (op as u64 + 1);
+                            set(pool, page_no, stamp).await?;
+                            expected.borrow_mut()[page_no as usize] = stamp;
+                        } else {
+                            let page = pool.get_page(page_id(page_no)).await?;
+                            assert!(holds(&page, expected.borrow()[page_no as usize]), "{:?} page {}", eviction, page_no);
+                        }
+                        tokio::task::yield_now().await;
+                    }
+                    Ok::<_, StorageError>(())
+                }
+            });
+            for res in join_all(tasks).await {
+                res.unwrap();
+            }
+            assert!(pool.evictions() > 0, "{:?}", eviction);
+
+            pool.flush_all().await.unwrap();
+            for (page_no, &stamp) in expected.borrow().iter().enumerate() {
+                match pool.store.page(page_id(page_no as u32)) {
+                    Some(stored) => assert!(holds(&stored, stamp), "{:?} page {}", eviction, page_no),
+                    None => assert_eq!(stamp, 0, "{:?} page {} never written back", eviction, page_no),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pinned_pages_are_not_evicted() {
+        let pool = pool(EvictionKind::ClockSweep);
+        set(&pool, 0, 7).await.unwrap();
+        let pinned = pool.get_page(page_id(0)).await.unwrap();
+        for page_no in 1..PAGES {
+            set(&pool, page_no, page_no as u64).await.unwrap();
+        }
+        assert!(holds(&pinned, 7));
+        assert!(pinned.is_dirty());
+        drop(pinned);
+
+        // With every frame pinned, another page has nowhere to go.
+        let mut guards = Vec::new();
+        for page_no in 0..FRAMES as u32 {
+            guards.push(pool.get_page(page_id(page_no)).await.unwrap());
+        }
+        assert!(matches!(pool.get_page(page_id(PAGES)).await, Err(StorageError::BufferPoolExhausted)));
+        drop(guards);
+        assert!(holds(&pool.get_page(page_id(PAGES)).await.unwrap(), 0));
+    }
+}
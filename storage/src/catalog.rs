@@ -0,0 +1,475 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::buffer_pool::{BufferPool, PageWriteGuard};
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::page::{self, PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::segment;
+use crate::traits::{PageId, PageStore, SpaceOptions, StorageError, WalStore};
+use crate::wal_record::WalRecordType;
+use crate::wal_registry::{PageRecord, WalRecord};
+
+// -----------------------------------------------------------------------------
+// Space Catalog
+//
+// Every database keeps the list of its spaces, with their page size and flags, in its
+// space 0, which holds nothing else and is not listed itself. The catalog is a chain
+// of pages starting at page 0, the bootstrap page, each with up to
+// `ENTRIES_PER_PAGE` entries in no particular order:
+//
+//   [24..26)  count         entries in the page
+//   [28..32)  next          next page of the chain (NO_PAGE: none)
+//   [32..)    entries       space_id u32 | page_size u32 | flags u32 | reserved u32
+//
+// Changes are page records, registered with every `WalRegistry`, so redo replays the
+// catalog like anything else:
+//
+//   CATALOG_INIT     page                      page formatted, no entries
+//   CATALOG_PUT      page | index u16 | entry  entry written at index, appended at count
+//   CATALOG_REMOVE   page | index u16          entry at index replaced by the last one
+//   CATALOG_LINK     page | next u32           next page of the chain
+//
+// (page = db_id u32 | space_id u32 | page_no u32.) Writers hold the bootstrap page's
+// write latch throughout, so they take turns; readers latch one page at a time. A new
+// page is formatted before it is linked in, so a crash in between only leaks it. Like
+// a B-tree, the catalog is used through the pool it is opened on only (see
+// `btree.rs`), and space 0 must not be compressed or encrypted.
+//
+// `StorageManager::mount` reads every database's catalog straight from the data files
+// (`read`), and refuses to start if a space listed as encrypted has no key configured.
+// After a crash that is the catalog as the data files have it, before redo: `list`
+// gives the recovered one. Unlogged spaces are flagged for their owners, which are to
+// recreate them after a crash; the storage engine does not log or replay pages on
+// their behalf either way.
+// -----------------------------------------------------------------------------
+
+/// The space holding a database's catalog.
+pub const CATALOG_SPACE: u32 = 0;
+
+/// Page type of catalog pages.
+pub const PAGE_TYPE_CATALOG: u16 = 7;
+
+/// Page of the catalog space the chain starts at.
+pub const BOOTSTRAP_PAGE: u32 = 0;
+
+/// No next page.
+pub const NO_PAGE: u32 = u32::MAX;
+
+/// Space flags.
+pub const SPACE_COMPRESSED: u32 = 1 << 0; // Pages compressed (`SpaceOptions::compression`)
+pub const SPACE_ENCRYPTED: u32 = 1 << 1;  // Pages encrypted (`SpaceOptions::encryption`)
+pub const SPACE_UNLOGGED: u32 = 1 << 2;   // Changes not logged; contents lost on a crash
+
+const COUNT_OFFSET: usize = 24;
+const NEXT_OFFSET: usize = 28;
+const ENTRIES_OFFSET: usize = 32;
+const ENTRY_SIZE: usize = 16;
+
+/// Entries per catalog page.
+pub const ENTRIES_PER_PAGE: usize = (PAGE_SIZE - ENTRIES_OFFSET) / ENTRY_SIZE;
+
+/// One space of a database, as the catalog lists it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceEntry {
+    pub space_id: u32,
+    pub page_size: u32,
+    pub flags: u32,
+}
+
+impl SpaceEntry {
+    /// Space `space_id` as configured by `options`, logged unless `unlogged`.
+    pub fn new(space_id: u32, options: &SpaceOptions, unlogged: bool) -> Self {
+        let mut flags = 0;
+        if options.compression.is_some() {
+            flags |= SPACE_COMPRESSED;
+        }
+        if options.encryption.is_some() {
+            flags |= SPACE_ENCRYPTED;
+        }
+        if unlogged {
+            flags |= SPACE_UNLOGGED;
+        }
+        Self {
+            space_id,
+            page_size: PAGE_SIZE as u32,
+            flags,
+        }
+    }
+
+    fn encode(&self, out: &mut [u8]) {
+        out[0..4].copy_from_slice(&self.space_id.to_le_bytes());
+        out[4..8].copy_from_slice(&self.page_size.to_le_bytes());
+        out[8..12].copy_from_slice(&self.flags.to_le_bytes());
+        out[12..16].fill(0);
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        Self {
+            space_id: get_u32(bytes, 0),
+            page_size: get_u32(bytes, 4),
+            flags: get_u32(bytes, 8),
+        }
+    }
+}
+
+/// The catalog of database `db_id`, over `pool`.
+pub struct Catalog<'a, S: PageStore + WalStore> {
+    pool: &'a BufferPool<S>,
+    db_id: u32,
+}
+
+impl<'a, S: PageStore + WalStore> Catalog<'a, S> {
+    /// Opens the catalog of database `db_id`, creating it empty if it has none yet.
+    pub async fn open(pool: &'a BufferPool<S>, db_id: u32) -> Result<Self, StorageError> {
+        let catalog = Self { pool, db_id };
+        let mut root = pool.get_page_mut(catalog.page(BOOTSTRAP_PAGE)).await?;
+        if page::page_type(&root) == PAGE_TYPE_CATALOG {
+            return Ok(catalog);
+        }
+        if !page::is_fresh(&root) {
+            return Err(StorageError::NotACatalog(db_id));
+        }
+        // A bootstrap cut short may have allocated the page already, and this one is
+        // then leaked.
+        pool.store().allocate_extent(db_id, CATALOG_SPACE, 1).await?;
+        let page_id = root.page_id();
+        root.apply(&CatalogInit { page_id }).await?;
+        Ok(catalog)
+    }
+
+    /// Every space listed.
+    pub async fn list(&self) -> Result<Vec<SpaceEntry>, StorageError> {
+        let mut entries = Vec::new();
+        let mut page_no = BOOTSTRAP_PAGE;
+        while page_no != NO_PAGE {
+            let page = self.pool.get_page(self.page(page_no)).await?;
+            entries.extend((0..count(&page)).map(|index| entry(&page, index)));
+            page_no = next(&page);
+        }
+        Ok(entries)
+    }
+
+    /// The entry of space `space_id`, if listed.
+    pub async fn get(&self, space_id: u32) -> Result<Option<SpaceEntry>, StorageError> {
+        Ok(self.list().await?.into_iter().find(|entry| entry.space_id == space_id))
+    }
+
+    /// Lists `entry`, replacing the space's entry if it has one.
+    pub async fn put(&self, entry: SpaceEntry) -> Result<(), StorageError> {
+        let mut root = self.pool.get_page_mut(self.page(BOOTSTRAP_PAGE)).await?;
+        if let Some((page_no, index)) = self.find(&root, entry.space_id).await? {
+            return self.write(&mut root, page_no, &CatalogPut { page_id: self.page(page_no), index, entry }).await;
+        }
+
+        // First page with room, or a new one at the end of the chain
+        let mut page_no = BOOTSTRAP_PAGE;
+        loop {
+            let (entries, next_page) = self.read(&root, page_no).await?;
+            if (entries as usize) < ENTRIES_PER_PAGE {
+                return self.write(&mut root, page_no, &CatalogPut { page_id: self.page(page_no), index: entries, entry }).await;
+            }
+            if next_page == NO_PAGE {
+                break;
+            }
+            page_no = next_page;
+        }
+        let new_page = self.pool.store().allocate_extent(self.db_id, CATALOG_SPACE, 1).await?;
+        let page_id = self.page(new_page);
+        let mut page = self.pool.get_page_overwrite(page_id).await?;
+        page.apply(&CatalogInit { page_id }).await?;
+        page.apply(&CatalogPut { page_id, index: 0, entry }).await?;
+        drop(page);
+        self.write(&mut root, page_no, &CatalogLink { page_id: self.page(page_no), next: new_page }).await
+    }
+
+    /// Unlists space `space_id`. Returns whether it was listed.
+    pub async fn remove(&self, space_id: u32) -> Result<bool, StorageError> {
+        let mut root = self.pool.get_page_mut(self.page(BOOTSTRAP_PAGE)).await?;
+        let Some((page_no, index)) = self.find(&root, space_id).await? else {
+            return Ok(false);
+        };
+        self.write(&mut root, page_no, &CatalogRemove { page_id: self.page(page_no), index }).await?;
+        Ok(true)
+    }
+
+    // Page and index of space `space_id`'s entry. Writers are kept out by `root`.
+    async fn find(&self, root: &PageWriteGuard<'_, S>, space_id: u32) -> Result<Option<(u32, u16)>, StorageError> {
+        let mut page_no = BOOTSTRAP_PAGE;
+        while page_no != NO_PAGE {
+            let found = match page_no {
+                BOOTSTRAP_PAGE => (0..count(root)).find(|&index| entry(root, index).space_id == space_id),
+                _ => {
+                    let page = self.pool.get_page(self.page(page_no)).await?;
+                    (0..count(&page)).find(|&index| entry(&page, index).space_id == space_id)
+                }
+            };
+            if let Some(index) = found {
+                return Ok(Some((page_no, index)));
+            }
+            page_no = self.read(root, page_no).await?.1;
+        }
+        Ok(None)
+    }
+
+    // Entry count and next page of page `page_no`
+    async fn read(&self, root: &PageWriteGuard<'_, S>, page_no: u32) -> Result<(u16, u32), StorageError> {
+        if page_no == BOOTSTRAP_PAGE {
+            return Ok((count(root), next(root)));
+        }
+        let page = self.pool.get_page(self.page(page_no)).await?;
+        Ok((count(&page), next(&page)))
+    }
+
+    // Applies `record` to page `page_no`, `root` or another
+    async fn write<R: PageRecord>(&self, root: &mut PageWriteGuard<'_, S>, page_no: u32, record: &R) -> Result<(), StorageError> {
+        match page_no {
+            BOOTSTRAP_PAGE => root.apply(record).await?,
+            _ => self.pool.get_page_mut(self.page(page_no)).await?.apply(record).await?,
+        };
+        Ok(())
+    }
+
+    fn page(&self, page_no: u32) -> PageId {
+        PageId {
+            db_id: self.db_id,
+            space_id: CATALOG_SPACE,
+            page_no,
+        }
+    }
+}
+
+/// Database `db_id`'s catalog as the data files under `data_dir` have it, for mount.
+/// Empty if the database has none. Blocking.
+pub fn read(data_dir: &Path, db_id: u32, algorithm: ChecksumAlgorithm) -> Result<Vec<SpaceEntry>, StorageError> {
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    let mut page = vec![0u8; PAGE_SIZE];
+    let mut page_no = BOOTSTRAP_PAGE;
+    while page_no != NO_PAGE && visited.insert(page_no) {
+        let page_id = PageId { db_id, space_id: CATALOG_SPACE, page_no };
+        let (seg_no, offset) = segment::locate(page_no);
+        let file = match fs::File::open(segment::segment_path(data_dir, db_id, CATALOG_SPACE, seg_no)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && page_no == BOOTSTRAP_PAGE => return Ok(entries),
+            Err(e) => return Err(StorageError::Io(e)),
+        };
+        match file.read_exact_at(&mut page, offset) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && page_no == BOOTSTRAP_PAGE => return Ok(entries),
+            res => res.map_err(StorageError::Io)?,
+        }
+        if page_no == BOOTSTRAP_PAGE && page::is_fresh(&page) {
+            return Ok(entries);
+        }
+        if !checksum::verify_page(algorithm, &page) {
+            return Err(StorageError::Corruption(page_id));
+        }
+        if page::page_type(&page) != PAGE_TYPE_CATALOG {
+            return Err(StorageError::NotACatalog(db_id));
+        }
+        entries.extend((0..count(&page)).map(|index| entry(&page, index)));
+        page_no = next(&page);
+    }
+    Ok(entries)
+}
+
+fn get_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn count(page: &[u8]) -> u16 {
+    u16::from_le_bytes(page[COUNT_OFFSET..COUNT_OFFSET + 2].try_into().unwrap())
+}
+
+fn next(page: &[u8]) -> u32 {
+    get_u32(page, NEXT_OFFSET)
+}
+
+fn entry(page: &[u8], index: u16) -> SpaceEntry {
+    let at = ENTRIES_OFFSET + index as usize * ENTRY_SIZE;
+    SpaceEntry::decode(&page[at..at + ENTRY_SIZE])
+}
+
+// -----------------------------------------------------------------------------
+// WAL records
+// -----------------------------------------------------------------------------
+
+const PAGE_ID_SIZE: usize = 12;
+
+fn encode_page_id(out: &mut Vec<u8>, page_id: PageId) {
+    for part in [page_id.db_id, page_id.space_id, page_id.page_no] {
+        out.extend_from_slice(&part.to_le_bytes());
+    }
+}
+
+fn decode_page_id(payload: &[u8]) -> Option<PageId> {
+    let field = |at: usize| Some(get_u32(payload.get(at..at + 4)?, 0));
+    Some(PageId {
+        db_id: field(0)?,
+        space_id: field(4)?,
+        page_no: field(8)?,
+    })
+}
+
+// The index after the page id, checked against the page's capacity
+fn decode_index(payload: &[u8]) -> Option<u16> {
+    let index = u16::from_le_bytes(payload.get(PAGE_ID_SIZE..PAGE_ID_SIZE + 2)?.try_into().unwrap());
+    ((index as usize) < ENTRIES_PER_PAGE).then_some(index)
+}
+
+/// A catalog page formatted, with no entries and no next page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogInit {
+    pub page_id: PageId,
+}
+
+impl WalRecord for CatalogInit {
+    const TYPE: WalRecordType = WalRecordType::CATALOG_INIT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE {
+            return None;
+        }
+        Some(Self { page_id: decode_page_id(payload)? })
+    }
+}
+
+impl PageRecord for CatalogInit {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[PAGE_HEADER_SIZE..].fill(0);
+        page[NEXT_OFFSET..NEXT_OFFSET + 4].copy_from_slice(&NO_PAGE.to_le_bytes());
+        page::set_page_type(page, PAGE_TYPE_CATALOG);
+    }
+}
+
+/// An entry written at `index`, which is the page's count for a new one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogPut {
+    pub page_id: PageId,
+    pub index: u16,
+    pub entry: SpaceEntry,
+}
+
+impl WalRecord for CatalogPut {
+    const TYPE: WalRecordType = WalRecordType::CATALOG_PUT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.index.to_le_bytes());
+        let mut entry = [0u8; ENTRY_SIZE];
+        self.entry.encode(&mut entry);
+        out.extend_from_slice(&entry);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 2 + ENTRY_SIZE {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            index: decode_index(payload)?,
+            entry: SpaceEntry::decode(&payload[PAGE_ID_SIZE + 2..]),
+        })
+    }
+}
+
+impl PageRecord for CatalogPut {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let at = ENTRIES_OFFSET + self.index as usize * ENTRY_SIZE;
+        self.entry.encode(&mut page[at..at + ENTRY_SIZE]);
+        if self.index == count(page) {
+            page[COUNT_OFFSET..COUNT_OFFSET + 2].copy_from_slice(&(self.index + 1).to_le_bytes());
+        }
+    }
+}
+
+/// The entry at `index` removed, the page's last entry moved into its place.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogRemove {
+    pub page_id: PageId,
+    pub index: u16,
+}
+
+impl WalRecord for CatalogRemove {
+    const TYPE: WalRecordType = WalRecordType::CATALOG_REMOVE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.index.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 2 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            index: decode_index(payload)?,
+        })
+    }
+}
+
+impl PageRecord for CatalogRemove {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let last = count(page) - 1;
+        let from = ENTRIES_OFFSET + last as usize * ENTRY_SIZE;
+        let to = ENTRIES_OFFSET + self.index as usize * ENTRY_SIZE;
+        page.copy_within(from..from + ENTRY_SIZE, to);
+        page[from..from + ENTRY_SIZE].fill(0);
+        page[COUNT_OFFSET..COUNT_OFFSET + 2].copy_from_slice(&last.to_le_bytes());
+    }
+}
+
+/// The next page of the chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogLink {
+    pub page_id: PageId,
+    pub next: u32,
+}
+
+impl WalRecord for CatalogLink {
+    const TYPE: WalRecordType = WalRecordType::CATALOG_LINK;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.next.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 4 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            next: get_u32(payload, PAGE_ID_SIZE),
+        })
+    }
+}
+
+impl PageRecord for CatalogLink {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[NEXT_OFFSET..NEXT_OFFSET + 4].copy_from_slice(&self.next.to_le_bytes());
+    }
+}
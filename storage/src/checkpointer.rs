@@ -0,0 +1,325 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::buffer_pool::BufferPool;
+use crate::core_storage::CoreStorage;
+use crate::failpoint;
+use crate::trace;
+use crate::traits::{Lsn, PageId, StorageError, WalStore};
+use crate::wal_checkpoint::{ActiveTxn, CheckpointEnd, DirtyPage};
+
+// -----------------------------------------------------------------------------
+// Checkpointer
+//
+// Bounds recovery time and WAL size. Checkpoints are fuzzy: writes go on throughout.
+// Per database, a checkpoint
+//
+//   1. appends CHECKPOINT_BEGIN,
+//   2. captures the dirty page table (each dirty page with its rec_lsn, the oldest
+//      change not on disk) and the transactions in progress,
+//   3. writes the captured pages back in the background, at most `write_rate` a
+//      second, skipping pages a writer holds and coming back to them later,
+//   4. appends CHECKPOINT_END with the pages of the table still dirty with changes
+//      from before the begin record (with full-page writes, after logging their
+//      images), and points the checkpoint file at it, which moves the redo start up
+//      to the begin record or the oldest such change (or change held outside the
+//      pool, see `with_unflushed`),
+//   5. truncates the WAL up to what recovery can still need.
+//
+// Step 2 relies on the order every change follows: its `PageWriteGuard` is held until
+// its WAL record is appended, and `BufferPool::dirty_pages` waits for the guards it
+// finds. So a change logged before the begin record is in a page dirty by then, or
+// being modified then, and is in the table. Pages dirtied later only hold changes redo
+// replays from the begin record anyway. Until step 4, recovery still starts from the
+// previous checkpoint, so a crash mid-checkpoint loses nothing.
+// -----------------------------------------------------------------------------
+
+/// When `Checkpointer::run` checkpoints a database: whichever comes first.
+#[derive(Debug, Clone)]
+pub struct CheckpointerConfig {
+    /// Time since the last checkpoint.
+    pub interval: Duration,
+    /// WAL appended since the last checkpoint began, in bytes.
+    pub max_wal_bytes: u64,
+    /// Most pages a second a checkpoint writes back, spreading its I/O out behind the
+    /// foreground work. `None` writes as fast as the device takes them.
+    pub write_rate: Option<u32>,
+}
+
+impl Default for CheckpointerConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(300),
+            max_wal_bytes: 1 << 30,
+            write_rate: None,
+        }
+    }
+}
+
+// Pages a checkpoint writes back between pauses
+const WRITE_BATCH: usize = 64;
+
+// Passes over the pages a writer held, and the pause before each
+const BUSY_RETRIES: usize = 3;
+const BUSY_RETRY: Duration = Duration::from_millis(10);
+
+/// What one checkpoint did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointSummary {
+    pub begin_lsn: Lsn,
+    pub end_lsn: Lsn,
+    /// Pages in the captured dirty page table, those written back, and those left in
+    /// the end record's table.
+    pub dirty_pages: usize,
+    pub pages_written: usize,
+    pub pages_left: usize,
+    /// Bytes of WAL segments `truncate_wal` removed or recycled.
+    pub wal_bytes_reclaimed: u64,
+}
+
+type ActiveTxnsFn = Box<dyn Fn(u32) -> Vec<ActiveTxn>>;
+type UnflushedFn = Box<dyn Fn(u32) -> Vec<DirtyPage>>;
+
+/// Checkpoints the databases of one core, over that core's buffer pool.
+pub struct Checkpointer {
+    pool: Rc<BufferPool<CoreStorage>>,
+    config: CheckpointerConfig,
+    active_txns: Option<ActiveTxnsFn>,
+    unflushed: Option<UnflushedFn>,
+    // When each database last checkpointed, and the LSN its WAL volume counts from
+    last: RefCell<HashMap<u32, (Instant, Lsn)>>,
+}
+
+impl Checkpointer {
+    pub fn new(pool: Rc<BufferPool<CoreStorage>>, config: CheckpointerConfig) -> Self {
+        Self {
+            pool,
+            config,
+            active_txns: None,
+            unflushed: None,
+            last: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Where checkpoints get the database's transactions in progress from, so truncation
+    /// keeps the WAL they may have to roll back. Without one, none are assumed.
+    pub fn with_active_txns(mut self, active_txns: impl Fn(u32) -> Vec<ActiveTxn> + 'static) -> Self {
+        self.active_txns = Some(Box::new(active_txns));
+        self
+    }
+
+    /// Where checkpoints get the database's changes held in memory outside the pool,
+    /// such as LSM memtables (`Lsm::unflushed`), each as a page dirty since its oldest
+    /// record, so redo starts early enough to replay them.
+    pub fn with_unflushed(mut self, unflushed: impl Fn(u32) -> Vec<DirtyPage> + 'static) -> Self {
+        self.unflushed = Some(Box::new(unflushed));
+        self
+    }
+
+    /// Checkpoints every database whose WAL is open on this core whenever it is due.
+    /// Never returns; spawn it on the pool's core.
+    pub async fn run(&self) {
+        // Often enough to notice the WAL volume trigger well before it is far exceeded.
+        let poll = (self.config.interval / 10).min(Duration::from_secs(1));
+        loop {
+            for db_id in self.pool.store().wal_databases() {
+                match self.due(db_id).await {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        if let Err(e) = self.checkpoint(db_id).await {
+                            trace::warn_event!("checkpoint: db {} failed: {:?}", db_id, e);
+                        }
+                    }
+                    Err(e) => trace::warn_event!("checkpoint: db {} failed: {:?}", db_id, e),
+                }
+            }
+            tokio::time::sleep(poll).await;
+        }
+    }
+
+    async fn due(&self, db_id: u32) -> Result<bool, StorageError> {
+        let storage = self.pool.store();
+        let known = self.last.borrow().get(&db_id).copied();
+        let (at, from) = match known {
+            Some(last) => last,
+            None => {
+                // First look since mount: the volume counts from the last checkpoint.
+                let last = (Instant::now(), storage.redo_start(db_id).await?);
+                self.last.borrow_mut().insert(db_id, last);
+                last
+            }
+        };
+        let tail = storage.wal_stats(db_id)?.tail_lsn;
+        Ok(at.elapsed() >= self.config.interval || tail.0.saturating_sub(from.0) >= self.config.max_wal_bytes)
+    }
+
+    /// Checkpoints `db_id` now.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip_all, fields(db_id, begin_lsn = tracing::field::Empty, end_lsn = tracing::field::Empty)))]
+    pub async fn checkpoint(&self, db_id: u32) -> Result<CheckpointSummary, StorageError> {
+        let storage = self.pool.store();
+        let started = Instant::now();
+
+        let begin_lsn = storage.begin_checkpoint(db_id).await?;
+        trace::record!(begin_lsn = begin_lsn.0);
+        let captured = self.pool.dirty_pages(db_id).await;
+        let active_txns = self.active_txns.as_ref().map_or_else(Vec::new, |active_txns| active_txns(db_id));
+
+        let pages: Vec<PageId> = captured.iter().map(|page| page.page_id).collect();
+        let pages_written = self.write_back(pages, begin_lsn).await?;
+        failpoint::hit!("checkpoint-after-write-back");
+
+        // Written back or not, a page whose oldest change is newer than the begin record
+        // is covered by redo from there.
+        let mut dirty_pages: Vec<DirtyPage> = captured
+            .iter()
+            .filter_map(|page| {
+                let rec_lsn = self.pool.rec_lsn(page.page_id).filter(|&rec_lsn| rec_lsn < begin_lsn)?;
+                Some(DirtyPage { page_id: page.page_id, rec_lsn })
+            })
+            .collect();
+        // Redo starts before the images logged since the begin record for these, so
+        // with full-page writes they get one now.
+        if storage.full_page_horizon(db_id)?.is_some() {
+            for page in &dirty_pages {
+                self.pool.log_image(page.page_id, begin_lsn).await?;
+            }
+        }
+        if let Some(unflushed) = &self.unflushed {
+            dirty_pages.extend(unflushed(db_id).into_iter().filter(|page| page.rec_lsn < begin_lsn));
+        }
+        let pages_left = dirty_pages.len();
+        let end = CheckpointEnd { begin_lsn, dirty_pages, active_txns };
+        let end_lsn = storage.end_checkpoint(db_id, &end).await?;
+        trace::record!(end_lsn = end_lsn.0);
+        self.last.borrow_mut().insert(db_id, (started, begin_lsn));
+        failpoint::hit!("checkpoint-before-truncate");
+
+        let wal_bytes_reclaimed = storage.truncate_wal(db_id, end.oldest_needed_lsn()).await?;
+        Ok(CheckpointSummary {
+            begin_lsn,
+            end_lsn,
+            dirty_pages: captured.len(),
+            pages_written,
+            pages_left,
+            wal_bytes_reclaimed,
+        })
+    }
+
+    // Writes back the pages still holding changes older than `lsn`, paced by
+    // `write_rate`. Pages a writer holds are retried a few times, then left.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip_all, fields(pages = pages.len(), lsn = lsn.0)))]
+    async fn write_back(&self, mut pages: Vec<PageId>, lsn: Lsn) -> Result<usize, StorageError> {
+        let mut written = 0;
+        for retry in 0..=BUSY_RETRIES {
+            if pages.is_empty() {
+                break;
+            }
+            if retry > 0 {
+                tokio::time::sleep(BUSY_RETRY).await;
+            }
+            let mut busy = Vec::new();
+            for batch in pages.chunks(WRITE_BATCH) {
+                let (batch_written, skipped) = self.pool.flush_older(batch, lsn).await?;
+                written += batch_written;
+                busy.extend(skipped);
+                if let Some(rate) = self.config.write_rate.filter(|&rate| rate > 0) {
+                    tokio::time::sleep(Duration::from_secs_f64(batch_written as f64 / rate as f64)).await;
+                }
+            }
+            pages = busy;
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::checksum::ChecksumAlgorithm;
+    use crate::page;
+    use crate::traits::{AlignedBuf, PageId, PageStore, StorageConfig};
+    use crate::wal_record::WalRecordType;
+
+    const PAGES: u32 = 8;
+
+    fn scratch_config(name: &str) -> StorageConfig {
+        let dir = std::env::temp_dir().join(format!("aquifer-checkpointer-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (data_dir, wal_dir): (PathBuf, PathBuf) = (dir.join("data"), dir.join("wal"));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        std::fs::create_dir_all(&wal_dir).unwrap();
+        StorageConfig::scratch(data_dir, wal_dir)
+    }
+
+    fn pool(config: &StorageConfig) -> Rc<BufferPool<CoreStorage>> {
+        let storage = Rc::new(CoreStorage::new(config, 0));
+        Rc::new(BufferPool::new(storage, 4, 4, ChecksumAlgorithm::Crc32c, config.buffer_pool_eviction))
+    }
+
+    // Logs and applies a change filling page `page_no` of db 1 with `value`
+    async fn set(pool: &BufferPool<CoreStorage>, page_no: u32, value: u8) {
+        let mut page = pool.get_page_mut(PageId { db_id: 1, space_id: 1, page_no }).await.unwrap();
+        page[page::PAGE_HEADER_SIZE..].fill(value);
+        let lsn = pool.store().append_wal(1, WalRecordType::OPAQUE, &[value]).await.unwrap();
+        page.set_lsn(lsn);
+    }
+
+    #[test]
+    fn a_checkpoint_writes_dirty_pages_and_moves_the_redo_start() {
+        let config = scratch_config("writes");
+        tokio_uring::start(async {
+            let pool = pool(&config);
+            pool.store().allocate_extent(1, 1, PAGES).await.unwrap();
+            for page_no in 0..PAGES {
+                set(&pool, page_no, page_no as u8 + 1).await;
+            }
+            let checkpointer = Checkpointer::new(Rc::clone(&pool), CheckpointerConfig::default());
+
+            let summary = checkpointer.checkpoint(1).await.unwrap();
+            // Frames dirty at the checkpoint; the others went out as eviction victims.
+            assert_eq!(summary.pages_written, pool.len());
+            assert!(summary.end_lsn > summary.begin_lsn);
+            assert_eq!(pool.store().redo_start(1).await.unwrap(), summary.begin_lsn);
+            let again = checkpointer.checkpoint(1).await.unwrap();
+            assert_eq!(again.pages_written, 0);
+            assert!(again.begin_lsn > summary.end_lsn);
+
+            // Every change is on disk for a fresh storage, which also finds the checkpoint.
+            let reopened = CoreStorage::new(&config, 0);
+            assert_eq!(reopened.redo_start(1).await.unwrap(), again.begin_lsn);
+            for page_no in 0..PAGES {
+                let page_id = PageId { db_id: 1, space_id: 1, page_no };
+                let (buf, res) = reopened.read_page(page_id, AlignedBuf::page()).await;
+                res.unwrap();
+                assert!(buf[page::PAGE_HEADER_SIZE..].iter().all(|&b| b == page_no as u8 + 1), "page {}", page_no);
+            }
+        });
+    }
+
+    #[test]
+    fn wal_volume_makes_a_checkpoint_due() {
+        let config = scratch_config("due");
+        tokio_uring::start(async {
+            let pool = pool(&config);
+            pool.store().allocate_extent(1, 1, 1).await.unwrap();
+            let checkpointer = Checkpointer::new(
+                Rc::clone(&pool),
+                CheckpointerConfig { interval: Duration::from_secs(3600), max_wal_bytes: 4096, ..Default::default() },
+            );
+            set(&pool, 0, 1).await;
+            assert!(!checkpointer.due(1).await.unwrap());
+
+            let payload = vec![0u8; 1024];
+            for _ in 0..4 {
+                pool.store().append_wal(1, WalRecordType::OPAQUE, &payload).await.unwrap();
+            }
+            assert!(checkpointer.due(1).await.unwrap());
+            checkpointer.checkpoint(1).await.unwrap();
+            assert!(!checkpointer.due(1).await.unwrap());
+        });
+    }
+}
@@ -0,0 +1,274 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use crate::page::{self, PAGE_SIZE};
+use crate::traits::AlignedBuf;
+
+/// Which CRC is stored in the page header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumAlgorithm {
+    /// CRC32 (IEEE) via `crc32fast`. Kept for segments written before CRC32C existed.
+    Crc32,
+    /// CRC32C (Castagnoli). Uses the SSE4.2 `crc32` / ARMv8 `crc32c*` instructions when the CPU has them.
+    #[default]
+    Crc32c,
+}
+
+impl ChecksumAlgorithm {
+    pub fn compute(self, data: &[u8]) -> u32 {
+        match self {
+            ChecksumAlgorithm::Crc32 => crc32fast::hash(data),
+            ChecksumAlgorithm::Crc32c => crc32c(data),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// 1. CRC32C Kernels
+// -----------------------------------------------------------------------------
+
+const CRC32C_POLY: u32 = 0x82F6_3B78; // Reflected Castagnoli polynomial
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32C_POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_append(0, data)
+}
+
+/// Continues a CRC32C over `data`, dispatching to the fastest kernel the CPU supports.
+pub fn crc32c_append(crc: u32, data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("sse4.2") {
+        return unsafe { crc32c_sse42(crc, data) };
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("crc") {
+        return unsafe { crc32c_armv8(crc, data) };
+    }
+
+    crc32c_software(crc, data)
+}
+
+fn crc32c_software(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(crc: u32, data: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    let mut chunks = data.chunks_exact(8);
+    let mut crc64 = (!crc) as u64;
+    for chunk in &mut chunks {
+        crc64 = _mm_crc32_u64(crc64, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+
+    let mut crc = crc64 as u32;
+    for &byte in chunks.remainder() {
+        crc = _mm_crc32_u8(crc, byte);
+    }
+    !crc
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "crc")]
+unsafe fn crc32c_armv8(crc: u32, data: &[u8]) -> u32 {
+    use std::arch::aarch64::{__crc32cb, __crc32cd};
+
+    let mut chunks = data.chunks_exact(8);
+    let mut crc = !crc;
+    for chunk in &mut chunks {
+        crc = __crc32cd(crc, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    for &byte in chunks.remainder() {
+        crc = __crc32cb(crc, byte);
+    }
+    !crc
+}
+
+// -----------------------------------------------------------------------------
+// 2. Page Stamping / Verification
+// -----------------------------------------------------------------------------
+
+/// The checksum covers everything after the checksum field itself, including the page LSN.
+pub fn page_checksum(algorithm: ChecksumAlgorithm, page: &[u8]) -> u32 {
+    algorithm.compute(&page[page::CHECKSUM_COVERAGE_START..PAGE_SIZE])
+}
+
+pub fn stamp_page(algorithm: ChecksumAlgorithm, page: &mut [u8]) {
+    let crc = page_checksum(algorithm, page);
+    page::set_checksum(page, crc);
+}
+
+pub fn verify_page(algorithm: ChecksumAlgorithm, page: &[u8]) -> bool {
+    page::checksum(page) == page_checksum(algorithm, page)
+}
+
+/// Stamps every 8KB page held in `buf` (a buffer may carry more than one page).
+fn stamp_buf(algorithm: ChecksumAlgorithm, buf: &mut AlignedBuf) {
+    for page in buf.chunks_exact_mut(PAGE_SIZE) {
+        stamp_page(algorithm, page);
+    }
+}
+
+fn verify_buf(algorithm: ChecksumAlgorithm, buf: &AlignedBuf) -> bool {
+    buf.chunks_exact(PAGE_SIZE).all(|page| verify_page(algorithm, page))
+}
+
+// -----------------------------------------------------------------------------
+// 3. The Offloading Pipeline
+// -----------------------------------------------------------------------------
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Hashes page batches for one core.
+/// Small batches are hashed inline; batches above `offload_threshold` bytes are
+/// shipped to a dedicated helper thread so the io_uring submit loop keeps reaping
+/// completions while multi-MB checkpoints or bulk loads are being checksummed.
+pub struct ChecksumPipeline {
+    algorithm: ChecksumAlgorithm,
+    offload_threshold: Option<usize>,
+    worker: Option<mpsc::Sender<Job>>,
+    // Batches shipped to the helper thread so far
+    offloaded: Cell<u64>,
+}
+
+impl ChecksumPipeline {
+    pub fn new(algorithm: ChecksumAlgorithm, offload_threshold: Option<usize>, core_id: usize) -> Self {
+        let worker = offload_threshold.map(|_| {
+            let (tx, rx) = mpsc::channel::<Job>();
+            thread::Builder::new()
+                .name(format!("checksum-{}", core_id))
+                .spawn(move || {
+                    // Exits once the owning CoreStorage drops the sender.
+                    for job in rx {
+                        job();
+                    }
+                })
+                .expect("failed to spawn checksum helper thread");
+            tx
+        });
+
+        Self { algorithm, offload_threshold, worker, offloaded: Cell::new(0) }
+    }
+
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        self.algorithm
+    }
+
+    /// Batches hashed on the helper thread rather than inline so far.
+    pub fn offloaded_batches(&self) -> u64 {
+        self.offloaded.get()
+    }
+
+    /// Stamps the checksum into every page of every buffer.
+    pub async fn stamp_batch(&self, mut bufs: Vec<AlignedBuf>) -> Vec<AlignedBuf> {
+        let algorithm = self.algorithm;
+        if !self.should_offload(&bufs) {
+            bufs.iter_mut().for_each(|buf| stamp_buf(algorithm, buf));
+            return bufs;
+        }
+
+        self.offload(move || {
+            bufs.iter_mut().for_each(|buf| stamp_buf(algorithm, buf));
+            bufs
+        })
+        .await
+    }
+
+    /// Verifies every buffer, returning one flag per buffer (`false` = at least one bad page).
+    pub async fn verify_batch(&self, bufs: Vec<AlignedBuf>) -> (Vec<AlignedBuf>, Vec<bool>) {
+        let algorithm = self.algorithm;
+        if !self.should_offload(&bufs) {
+            let ok = bufs.iter().map(|buf| verify_buf(algorithm, buf)).collect();
+            return (bufs, ok);
+        }
+
+        self.offload(move || {
+            let ok = bufs.iter().map(|buf| verify_buf(algorithm, buf)).collect();
+            (bufs, ok)
+        })
+        .await
+    }
+
+    fn should_offload(&self, bufs: &[AlignedBuf]) -> bool {
+        match (self.offload_threshold, &self.worker) {
+            (Some(threshold), Some(_)) => bufs.iter().map(|b| b.len()).sum::<usize>() > threshold,
+            _ => false,
+        }
+    }
+
+    fn offload<T, F>(&self, f: F) -> Completion<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.offloaded.set(self.offloaded.get() + 1);
+        let slot = Arc::new(Mutex::new(Slot { value: None, waker: None }));
+        let job_slot = Arc::clone(&slot);
+        let job: Job = Box::new(move || {
+            let value = f();
+            let mut slot = job_slot.lock().unwrap();
+            slot.value = Some(value);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        });
+
+        // If the helper thread is gone, hash inline rather than fail the I/O.
+        if let Err(mpsc::SendError(job)) = self.worker.as_ref().unwrap().send(job) {
+            job();
+        }
+        Completion { slot }
+    }
+}
+
+struct Slot<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// Resolves once the helper thread has finished the job.
+struct Completion<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Future for Completion<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.value.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,626 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use crate::blob::{self, PAGE_TYPE_BLOB_DATA, PAGE_TYPE_BLOB_HEADER, PAGE_TYPE_BLOB_ROOT};
+use crate::bloom::PAGE_TYPE_BLOOM;
+use crate::btree::{self, PAGE_TYPE_BTREE_INTERNAL, PAGE_TYPE_BTREE_LEAF};
+use crate::buffer_pool::{BackgroundWriterConfig, PrefetchConfig, ScanRingConfig};
+use crate::catalog::PAGE_TYPE_CATALOG;
+use crate::checkpointer::{Checkpointer, CheckpointerConfig};
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::commit_ts::CommitRecord;
+use crate::compression;
+use crate::discard::DiscardConfig;
+use crate::eviction::EvictionKind;
+use crate::fsm::{self, PAGE_TYPE_FSM};
+use crate::hash_index::{self, PAGE_TYPE_HASH_BUCKET, PAGE_TYPE_HASH_META};
+use crate::heap::{self, HeapFile, PAGE_TYPE_HEAP, PAGE_TYPE_OVERFLOW};
+use crate::load::{self, Columns, LoadError};
+use crate::lsm::{self, PAGE_TYPE_LSM_DATA, PAGE_TYPE_LSM_INDEX, PAGE_TYPE_LSM_MANIFEST};
+use crate::page::{self, PAGE_SIZE};
+use crate::page_verify;
+use crate::quarantine::CorruptPagePolicy;
+use crate::restore;
+use crate::ring::CompletionConfig;
+use crate::segment::{self, SegmentAllocation, PAGE_TYPE_SEGMENT_HEADER};
+use crate::sequence::PAGE_TYPE_SEQUENCE;
+use crate::stats;
+use crate::traits::{Lsn, StorageConfig, StorageManager};
+use crate::undo::{AbortRecord, Compensation, PageUpdate};
+use crate::vm::{self, PAGE_TYPE_VM};
+use crate::wal::{self, WalLayout};
+use crate::wal_compress::WalCompression;
+use crate::wal_crypt::{WalCipher, WalKey};
+use crate::wal_reader::WalReader;
+use crate::wal_record::{self, WalRecordType};
+use crate::wal_recovery;
+use crate::wal_registry::{WalRecord as _, WalRegistry};
+
+// -----------------------------------------------------------------------------
+// cascade-cli
+//
+// The offline tools, run against the directories of a stopped engine. A binary's
+// `main` hands `run` its arguments after the program name, prints the error if any,
+// and exits non-zero:
+//
+//   cascade-cli restore --full <dir> [--incremental <dir>]... [--archive <dir>]
+//                       --data-dir <dir> --wal-dir <dir>
+//                       [--checksum crc32|crc32c] [--wal-key <db_id>:<64 hex digits>]...
+//   cascade-cli page dump --data-dir <dir> --db <id> --space <id> --page <no>
+//                       [--checksum crc32|crc32c]
+//   cascade-cli verify --data-dir <dir> [--db <id>] [--space <id>] [--threads <n>]
+//   cascade-cli waldump --wal-dir <dir> --db <id> [--start <lsn>] [--end <lsn>]
+//                       [--wal-key <db_id>:<64 hex digits>]
+//   cascade-cli stats --data-dir <dir> --wal-dir <dir>
+//   cascade-cli import --format csv --file <path> --data-dir <dir> --wal-dir <dir>
+//                       --db <id> --space <id> --columns <type>[,<type>]...
+//                       [--not-null <column>]... [--header true|false]
+//                       [--checksum crc32|crc32c] [--wal-key <db_id>:<64 hex digits>]...
+//
+// Options repeat in the order given; incrementals go oldest first. Record types of
+// higher layers are unknown here, so their pages only count towards segment headers
+// through what the data files show (see `restore.rs`).
+//
+// `page dump` reads a page straight from its segment file, as stored: compressed and
+// encrypted images are shown as they are on disk. It prints the page header, checks
+// the CRC (the frame's, for a compressed image) with the segment's checksum unless
+// told otherwise, and hex dumps the page, runs of identical lines folded into `*`.
+//
+// `verify` checks every page in use, of one space, one database or all of them (see
+// `page_verify.rs`), on as many threads as there are CPUs unless told otherwise. It
+// prints a summary and the bad pages, and fails if there are any.
+//
+// `waldump` prints the records of a database's WAL with a `WalReader`, from `--start`
+// (a record boundary; the recovery start by default) up to `--end` or the end of the
+// log: LSN, framed length, type and its owner, and the transaction and page the record
+// names, for the types that name one. The reader hands out only records passing their
+// CRC, so where it stopped, and why, closes the dump; stopping short of `--end` fails.
+// `--wal-dir` is the root of the stream: the per-core layout has one per core, each
+// needing its own `--start`.
+//
+// `stats` totals the space in use against what the data and WAL files take on disk
+// (see `stats::space_stats`) and prints the space amplification. Write amplification
+// is counted by a running engine (`CoreStorage::write_stats`), so it isn't here.
+//
+// `import` loads a CSV file into a heap space as rows of `--columns` (see `load.rs`
+// for the types and the format), `--not-null` columns counted from 0. It checks the
+// whole file first and loads nothing if a record is bad. Then it mounts the engine on
+// one core, recovers, inserts the rows, printing progress as it goes, checkpoints and
+// shuts down cleanly. The first line is a header unless `--header false`.
+// -----------------------------------------------------------------------------
+
+const USAGE: &str = "usage: cascade-cli restore --full <dir> [--incremental <dir>]... [--archive <dir>] \
+--data-dir <dir> --wal-dir <dir> [--checksum crc32|crc32c] [--wal-key <db_id>:<hex>]...
+       cascade-cli page dump --data-dir <dir> --db <id> --space <id> --page <no> [--checksum crc32|crc32c]
+       cascade-cli verify --data-dir <dir> [--db <id>] [--space <id>] [--threads <n>]
+       cascade-cli waldump --wal-dir <dir> --db <id> [--start <lsn>] [--end <lsn>] [--wal-key <db_id>:<hex>]
+       cascade-cli stats --data-dir <dir> --wal-dir <dir>
+       cascade-cli import --format csv --file <path> --data-dir <dir> --wal-dir <dir> --db <id> --space <id> \
+--columns <type>[,<type>]... [--not-null <column>]... [--header true|false] [--checksum crc32|crc32c] [--wal-key <db_id>:<hex>]...";
+
+// Bytes a hex dump line shows
+const DUMP_LINE: usize = 16;
+
+/// Runs the subcommand in `args`, the command line after the program name, printing
+/// its outcome. Errors are for the user to read.
+pub fn run(args: impl IntoIterator<Item = String>) -> Result<(), String> {
+    let mut args = args.into_iter();
+    match args.next().as_deref() {
+        Some("restore") => run_restore(Options::parse(args)?),
+        Some("page") => match args.next().as_deref() {
+            Some("dump") => run_page_dump(Options::parse(args)?),
+            Some(other) => Err(format!("unknown page command {}\n{}", other, USAGE)),
+            None => Err(USAGE.to_string()),
+        },
+        Some("verify") => run_verify(Options::parse(args)?),
+        Some("waldump") => run_waldump(Options::parse(args)?),
+        Some("stats") => run_stats(Options::parse(args)?),
+        Some("import") => run_import(Options::parse(args)?),
+        Some(other) => Err(format!("unknown command {}\n{}", other, USAGE)),
+        None => Err(USAGE.to_string()),
+    }
+}
+
+fn run_restore(mut options: Options) -> Result<(), String> {
+    let full = options.path("--full")?;
+    let data_dir = options.path("--data-dir")?;
+    let wal_dir = options.path("--wal-dir")?;
+    let incrementals: Vec<PathBuf> = options.take("--incremental").into_iter().map(PathBuf::from).collect();
+    let archive = options.take_one("--archive")?.map(PathBuf::from);
+    let checksum = options.take_one("--checksum")?.map_or(Ok(ChecksumAlgorithm::Crc32c), |name| parse_checksum(&name))?;
+    let keys = options.take("--wal-key").iter().map(|key| parse_key(key)).collect::<Result<HashMap<_, _>, _>>()?;
+    options.finish()?;
+
+    let summary = restore::restore(
+        &data_dir,
+        &wal_dir,
+        checksum,
+        &keys,
+        &full,
+        &incrementals,
+        archive.as_deref(),
+        &WalRegistry::new(),
+    )
+    .map_err(|e| format!("restore failed: {:?}", e))?;
+    println!(
+        "restored db {} from the backup started at {:?}: {} incremental(s), {} archived WAL segment(s), {} segment header(s) fixed",
+        summary.manifest.db_id,
+        summary.manifest.started_at,
+        incrementals.len(),
+        summary.archived_segments,
+        summary.headers_fixed,
+    );
+    Ok(())
+}
+
+fn run_page_dump(mut options: Options) -> Result<(), String> {
+    let data_dir = options.path("--data-dir")?;
+    let db_id = options.number("--db")?;
+    let space_id = options.number("--space")?;
+    let page_no = options.number("--page")?;
+    let checksum = options.take_one("--checksum")?.map(|name| parse_checksum(&name)).transpose()?;
+    options.finish()?;
+
+    let (seg_no, offset) = segment::locate(page_no);
+    let path = segment::segment_path(&data_dir, db_id, space_id, seg_no);
+    let header = segment::read_header(&path).map_err(|e| format!("{}: {:?}", path.display(), e))?;
+    let checksum = checksum.unwrap_or(header.checksum);
+    println!("db {} space {} page {}: {} at offset {}", db_id, space_id, page_no, path.display(), offset);
+    match read_raw_page(&path, offset)? {
+        None => println!("never written: past the end of the segment"),
+        Some(page) if page::is_fresh(&page) => println!("never written: all zeros"),
+        Some(page) => {
+            print_header(&page, checksum);
+            hex_dump(&page);
+        }
+    }
+    Ok(())
+}
+
+fn run_verify(mut options: Options) -> Result<(), String> {
+    let data_dir = options.path("--data-dir")?;
+    let db_id = options.optional_number("--db")?;
+    let space_id = options.optional_number("--space")?;
+    let threads = match options.optional_number("--threads")? {
+        Some(threads) => threads,
+        None => thread::available_parallelism().map_or(1, |threads| threads.get()),
+    };
+    options.finish()?;
+
+    let report = page_verify::verify_pages(&data_dir, db_id, space_id, threads).map_err(|e| format!("verify failed: {:?}", e))?;
+    println!(
+        "{} segment(s), {} page(s) in use checked, {} never written: {} bad page(s), {} unreadable segment(s)",
+        report.segments,
+        report.pages,
+        report.unwritten,
+        report.bad_pages.len(),
+        report.bad_segments.len(),
+    );
+    for (path, reason) in &report.bad_segments {
+        println!("bad segment {}: {}", path.display(), reason);
+    }
+    for page_id in &report.bad_pages {
+        println!("bad page db {} space {} page {}", page_id.db_id, page_id.space_id, page_id.page_no);
+    }
+    match report.is_clean() {
+        true => Ok(()),
+        false => Err(format!("{} bad page(s), {} unreadable segment(s)", report.bad_pages.len(), report.bad_segments.len())),
+    }
+}
+
+fn run_waldump(mut options: Options) -> Result<(), String> {
+    let wal_dir = options.path("--wal-dir")?;
+    let db_id = options.number("--db")?;
+    let start = options.optional_number("--start")?.map(Lsn);
+    let end = options.optional_number("--end")?.map(Lsn);
+    let keys = options.take("--wal-key").iter().map(|key| parse_key(key)).collect::<Result<HashMap<_, _>, _>>()?;
+    options.finish()?;
+
+    let start = match start {
+        Some(start) => start,
+        None => wal_recovery::recovery_start(&wal_dir, db_id, wal::stream_origin(0)).map_err(|e| format!("no recovery start: {:?}", e))?,
+    };
+    let cipher = keys.get(&db_id).map(|key| Rc::new(WalCipher::new(key, db_id, wal::lsn_core(start))));
+    let registry = registry();
+
+    tokio_uring::start(async move {
+        let mut reader = WalReader::open(&wal_dir, db_id, start).await.map_err(|e| format!("waldump failed: {:?}", e))?;
+        if let Some(cipher) = cipher {
+            reader = reader.decrypt_with(cipher);
+        }
+        let mut records = 0u64;
+        // Past the last record printed
+        let mut position = start;
+        while let Some(record) = reader.next().await {
+            let record = record.map_err(|e| format!("waldump failed: {:?}", e))?;
+            if end.is_some_and(|end| record.lsn >= end) {
+                break;
+            }
+            print_record(&record, &registry);
+            records += 1;
+            position = record.end_lsn();
+        }
+
+        println!("{} record(s) from lsn {} to {}", records, start.0, position.0);
+        match reader.stopped_at() {
+            Some((lsn, reason)) => println!("stopped at lsn {}: {}", lsn.0, reason),
+            None if reader.trailing_bytes() > 0 => println!("stopped at lsn {}: torn record, {} byte(s)", position.0, reader.trailing_bytes()),
+            None => {}
+        }
+        match end {
+            Some(end) if position < end => Err(format!("the log ends at lsn {}, before {}", position.0, end.0)),
+            _ => Ok(()),
+        }
+    })
+}
+
+fn run_stats(mut options: Options) -> Result<(), String> {
+    let data_dir = options.path("--data-dir")?;
+    let wal_dir = options.path("--wal-dir")?;
+    options.finish()?;
+
+    let stats = stats::space_stats(&data_dir, &wal_dir).map_err(|e| format!("stats failed: {:?}", e))?;
+    println!("in use      {:>16} bytes", stats.logical_bytes);
+    println!("data files  {:>16} bytes", stats.data_file_bytes);
+    println!("wal files   {:>16} bytes", stats.wal_file_bytes);
+    match stats.space_amplification() {
+        Some(ratio) => println!("space amplification {:.2}", ratio),
+        None => println!("space amplification -: no pages in use"),
+    }
+    Ok(())
+}
+
+fn run_import(mut options: Options) -> Result<(), String> {
+    let format = options.take_one("--format")?.ok_or_else(|| format!("--format is required\n{}", USAGE))?;
+    let file = options.path("--file")?;
+    let data_dir = options.path("--data-dir")?;
+    let wal_dir = options.path("--wal-dir")?;
+    let db_id = options.number("--db")?;
+    let space_id = options.number("--space")?;
+    let mut columns = Columns::parse(&options.take_one("--columns")?.ok_or_else(|| format!("--columns is required\n{}", USAGE))?)?;
+    for column in options.take("--not-null") {
+        columns.set_not_null(column.parse().map_err(|_| format!("bad --not-null {}", column))?)?;
+    }
+    let header = match options.take_one("--header")?.as_deref() {
+        None | Some("true") => true,
+        Some("false") => false,
+        Some(other) => return Err(format!("bad --header {}", other)),
+    };
+    let checksum = options.take_one("--checksum")?.map_or(Ok(ChecksumAlgorithm::Crc32c), |name| parse_checksum(&name))?;
+    let keys = options.take("--wal-key").iter().map(|key| parse_key(key)).collect::<Result<HashMap<_, _>, _>>()?;
+    options.finish()?;
+    if format != "csv" {
+        return Err(format!("unknown format {}, only csv can be imported", format));
+    }
+
+    let open = || File::open(&file).map(BufReader::new).map_err(|e| format!("{}: {}", file.display(), e));
+    let rows = load::check_csv(open()?, header, &columns).map_err(|e| load_failed(&file, e))?;
+    println!("{}: {} row(s) checked", file.display(), rows);
+
+    let manager = StorageManager::mount(engine_config(data_dir, wal_dir, checksum, keys)).map_err(|e| format!("mount failed: {:?}", e))?;
+    let registry = registry();
+    manager
+        .runtime()
+        .start(async {
+            let storage = Rc::new(manager.local_worker(0));
+            let partition = manager.local_partition(0, storage);
+            manager.recover(&partition, &registry).await.map_err(|e| format!("recovery failed: {:?}", e))?;
+            let pool = partition.pool();
+            let heap = HeapFile::new(pool, db_id, space_id);
+            let loaded = load::load_csv(&heap, open()?, header, &columns, |rows| println!("{} row(s) imported", rows))
+                .await
+                .map_err(|e| load_failed(&file, e))?;
+            Checkpointer::new(Rc::clone(pool), CheckpointerConfig::default())
+                .checkpoint(db_id)
+                .await
+                .map_err(|e| format!("checkpoint failed: {:?}", e))?;
+            pool.flush_all().await.map_err(|e| format!("flush failed: {:?}", e))?;
+            println!("imported {} row(s) into db {} space {}", loaded, db_id, space_id);
+            Ok::<(), String>(())
+        })?;
+    manager.mark_clean_shutdown().map_err(|e| format!("shutdown failed: {:?}", e))
+}
+
+fn load_failed(file: &Path, e: LoadError) -> String {
+    match e {
+        LoadError::BadRecord { line, reason } => format!("{}:{}: {}", file.display(), line, reason),
+        LoadError::Storage(e) => format!("import failed: {:?}", e),
+    }
+}
+
+// Every record type the engine's own structures log
+fn registry() -> WalRegistry {
+    let mut registry = WalRegistry::new();
+    heap::register(&mut registry);
+    btree::register(&mut registry);
+    fsm::register(&mut registry);
+    vm::register(&mut registry);
+    lsm::register(&mut registry);
+    blob::register(&mut registry);
+    hash_index::register(&mut registry);
+    registry
+}
+
+// The engine on one core, as the offline tools run it
+fn engine_config(data_dir: PathBuf, wal_dir: PathBuf, checksum: ChecksumAlgorithm, wal_keys: HashMap<u32, WalKey>) -> StorageConfig {
+    StorageConfig {
+        data_dir,
+        wal_dir,
+        io_uring_entries: 256,
+        completions: CompletionConfig::default(),
+        checksum,
+        checksum_offload_threshold: None,
+        spaces: HashMap::new(),
+        discard: DiscardConfig::default(),
+        segment_allocation: SegmentAllocation::Sparse,
+        end_to_end_checksums: false,
+        full_page_writes: true,
+        corrupt_pages: CorruptPagePolicy::default(),
+        redo_past_corruption: false,
+        pinned_pages: Vec::new(),
+        commit_delay: None,
+        async_commit_window: Duration::from_millis(10),
+        wal_archiver: None,
+        wal_compression: WalCompression::default(),
+        wal_keys,
+        wal_future_segments: 0,
+        wal_layout: WalLayout::PerDatabase,
+        wal_direct_io: false,
+        cores: 1,
+        buffer_pool_frames: 1024,
+        buffer_pool_max_frames: 1024,
+        buffer_pool_warm_up: false,
+        numa_aware: false,
+        buffer_pool_eviction: EvictionKind::default(),
+        background_writer: BackgroundWriterConfig::default(),
+        prefetch: PrefetchConfig::default(),
+        scan_ring: ScanRingConfig::default(),
+        buffer_pool_bulk_budget: 100,
+        checkpointer: CheckpointerConfig::default(),
+        slow_io_threshold: None,
+    }
+}
+
+fn print_record(record: &wal_record::WalRecord, registry: &WalRegistry) {
+    let xid = match record.record_type {
+        WalRecordType::PAGE_UPDATE => PageUpdate::decode(&record.payload).map(|update| update.xid),
+        WalRecordType::COMPENSATION => Compensation::decode(&record.payload).map(|clr| clr.xid),
+        WalRecordType::ABORT => AbortRecord::decode(&record.payload).map(|abort| abort.xid),
+        WalRecordType::COMMIT => CommitRecord::decode(&record.payload).map(|commit| commit.xid),
+        _ => None,
+    };
+    let page = match registry.page_change(record) {
+        Ok(Some(change)) => format!("{}/{}/{}", change.page_id.db_id, change.page_id.space_id, change.page_id.page_no),
+        Ok(None) => "-".to_string(),
+        Err(_) if registry.owner(record.record_type).is_none() => "-".to_string(),
+        Err(_) => "malformed".to_string(),
+    };
+    let gsn = record.gsn.map_or(String::new(), |gsn| format!("  gsn {}", gsn));
+    println!(
+        "lsn {:>12}  len {:>6}  type 0x{:04x} {:<20} {:<8} xid {:>8}  page {:<16} crc ok{}",
+        record.lsn.0,
+        record.framed_len(),
+        record.record_type.0,
+        record_type_name(record.record_type),
+        registry.owner(record.record_type).unwrap_or("?"),
+        xid.map_or("-".to_string(), |xid| xid.to_string()),
+        page,
+        gsn,
+    );
+}
+
+fn record_type_name(record_type: WalRecordType) -> &'static str {
+    match record_type {
+        WalRecordType::OPAQUE => "opaque",
+        WalRecordType::COMMIT => "commit",
+        WalRecordType::CHECKPOINT_BEGIN => "checkpoint begin",
+        WalRecordType::CHECKPOINT_END => "checkpoint end",
+        WalRecordType::PAGE_UPDATE => "page update",
+        WalRecordType::COMPENSATION => "compensation",
+        WalRecordType::ABORT => "abort",
+        WalRecordType::FULL_PAGE_IMAGE => "full page image",
+        WalRecordType::CATALOG_INIT => "catalog init",
+        WalRecordType::CATALOG_PUT => "catalog put",
+        WalRecordType::CATALOG_REMOVE => "catalog remove",
+        WalRecordType::CATALOG_LINK => "catalog link",
+        WalRecordType::SEQUENCE_SET => "sequence set",
+        WalRecordType::HEAP_INIT => "heap init",
+        WalRecordType::HEAP_INSERT => "heap insert",
+        WalRecordType::HEAP_DELETE => "heap delete",
+        WalRecordType::HEAP_COMPACT => "heap compact",
+        WalRecordType::HEAP_VISIBLE => "heap visible",
+        WalRecordType::HEAP_OVERFLOW => "heap overflow",
+        WalRecordType::HEAP_SET_XMAX => "heap set xmax",
+        WalRecordType::BTREE_NODE => "btree node",
+        WalRecordType::BTREE_INSERT => "btree insert",
+        WalRecordType::BTREE_DELETE => "btree delete",
+        WalRecordType::BTREE_UPDATE => "btree update",
+        WalRecordType::BTREE_SPLIT => "btree split",
+        WalRecordType::FSM_INIT => "fsm init",
+        WalRecordType::FSM_SET => "fsm set",
+        WalRecordType::FSM_PAGES => "fsm pages",
+        WalRecordType::VM_INIT => "vm init",
+        WalRecordType::VM_SET => "vm set",
+        WalRecordType::LSM_WRITE => "lsm write",
+        WalRecordType::LSM_MANIFEST => "lsm manifest",
+        WalRecordType::BLOB_ROOT_INIT => "blob root init",
+        WalRecordType::BLOB_GARBAGE_ADD => "blob garbage add",
+        WalRecordType::BLOB_GARBAGE_REMOVE => "blob garbage remove",
+        WalRecordType::BLOB_INIT => "blob init",
+        WalRecordType::BLOB_SET_REFS => "blob set refs",
+        WalRecordType::HASH_META => "hash meta",
+        WalRecordType::HASH_PAGE => "hash page",
+        WalRecordType::HASH_INSERT => "hash insert",
+        WalRecordType::HASH_DELETE => "hash delete",
+        WalRecordType::HASH_UPDATE => "hash update",
+        WalRecordType::HASH_LINK => "hash link",
+        _ => "unknown",
+    }
+}
+
+// The page at `offset` of the segment file, as stored; `None` if the file ends first
+fn read_raw_page(path: &Path, offset: u64) -> Result<Option<Vec<u8>>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut page = vec![0; PAGE_SIZE];
+    match file.read_exact_at(&mut page, offset) {
+        Ok(()) => Ok(Some(page)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+fn print_header(page: &[u8], algorithm: ChecksumAlgorithm) {
+    let stored = page::checksum(page);
+    let computed = match compression::is_compressed(page) {
+        true => compression::frame_len(page).map(|len| algorithm.compute(&page[page::CHECKSUM_COVERAGE_START..len])),
+        false => Some(checksum::page_checksum(algorithm, page)),
+    };
+    let status = match computed {
+        Some(computed) if computed == stored => format!("ok ({:?})", algorithm),
+        Some(computed) => format!("MISMATCH, computed 0x{:08x} ({:?})", computed, algorithm),
+        None => "MISMATCH, frame length out of range".to_string(),
+    };
+    let page_type = page::page_type(page);
+    let flags = page::flags(page);
+    let flag_names: Vec<&str> = [
+        (page::FLAG_COMPRESSED_LZ4, "compressed-lz4"),
+        (page::FLAG_COMPRESSED_ZSTD, "compressed-zstd"),
+        (page::FLAG_ENCRYPTED, "encrypted"),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags & flag != 0)
+    .map(|(_, name)| *name)
+    .collect();
+
+    println!("checksum  0x{:08x}  {}", stored, status);
+    println!("lsn       {}", page::page_lsn(page).0);
+    println!("type      {} {}", page_type, page_type_name(page_type));
+    println!("flags     0x{:04x} {}", flags, flag_names.join(" "));
+    println!("dict      {}", page::dict_version(page));
+    println!("origin    0x{:08x}", page::origin_checksum(page));
+}
+
+fn page_type_name(page_type: u16) -> &'static str {
+    match page_type {
+        PAGE_TYPE_SEGMENT_HEADER => "segment header",
+        PAGE_TYPE_BTREE_LEAF => "b-tree leaf",
+        PAGE_TYPE_BTREE_INTERNAL => "b-tree internal",
+        PAGE_TYPE_HEAP => "heap",
+        PAGE_TYPE_FSM => "free space map",
+        PAGE_TYPE_VM => "visibility map",
+        PAGE_TYPE_CATALOG => "catalog",
+        PAGE_TYPE_OVERFLOW => "heap overflow",
+        PAGE_TYPE_SEQUENCE => "sequence",
+        PAGE_TYPE_LSM_MANIFEST => "lsm manifest",
+        PAGE_TYPE_LSM_DATA => "lsm data",
+        PAGE_TYPE_LSM_INDEX => "lsm index",
+        PAGE_TYPE_BLOB_ROOT => "blob root",
+        PAGE_TYPE_BLOB_HEADER => "blob header",
+        PAGE_TYPE_BLOB_DATA => "blob data",
+        PAGE_TYPE_HASH_META => "hash meta",
+        PAGE_TYPE_HASH_BUCKET => "hash bucket",
+        PAGE_TYPE_BLOOM => "bloom filter",
+        _ => "unknown",
+    }
+}
+
+// `offset  hex bytes  |ascii|` lines, a run of lines like the one before shown as `*`
+fn hex_dump(bytes: &[u8]) {
+    let mut previous: Option<&[u8]> = None;
+    let mut folded = false;
+    for (at, line) in bytes.chunks(DUMP_LINE).enumerate() {
+        if previous == Some(line) {
+            if !folded {
+                println!("*");
+                folded = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        folded = false;
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = line.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+        println!("{:04x}  {}  |{}|", at * DUMP_LINE, hex.join(" "), ascii);
+    }
+    println!("{:04x}", bytes.len());
+}
+
+fn parse_checksum(name: &str) -> Result<ChecksumAlgorithm, String> {
+    match name {
+        "crc32c" => Ok(ChecksumAlgorithm::Crc32c),
+        "crc32" => Ok(ChecksumAlgorithm::Crc32),
+        other => Err(format!("unknown checksum {}", other)),
+    }
+}
+
+// `<db_id>:<64 hex digits>`
+fn parse_key(arg: &str) -> Result<(u32, WalKey), String> {
+    let bad = || format!("bad WAL key {}, expected <db_id>:<64 hex digits>", arg);
+    let (db_id, hex) = arg.split_once(':').ok_or_else(bad)?;
+    let db_id = db_id.parse().map_err(|_| bad())?;
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(bad());
+    }
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| bad())?;
+    }
+    Ok((db_id, WalKey::new(bytes)))
+}
+
+// `--name value` pairs, by name in the order given
+struct Options {
+    values: HashMap<String, Vec<String>>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut values: HashMap<String, Vec<String>> = HashMap::new();
+        while let Some(name) = args.next() {
+            if !name.starts_with("--") {
+                return Err(format!("unexpected argument {}\n{}", name, USAGE));
+            }
+            let value = args.next().ok_or_else(|| format!("{} needs a value", name))?;
+            values.entry(name).or_default().push(value);
+        }
+        Ok(Self { values })
+    }
+
+    fn take(&mut self, name: &str) -> Vec<String> {
+        self.values.remove(name).unwrap_or_default()
+    }
+
+    fn take_one(&mut self, name: &str) -> Result<Option<String>, String> {
+        let mut values = self.take(name);
+        match values.len() {
+            0 | 1 => Ok(values.pop()),
+            _ => Err(format!("{} given more than once", name)),
+        }
+    }
+
+    fn path(&mut self, name: &str) -> Result<PathBuf, String> {
+        self.take_one(name)?.map(PathBuf::from).ok_or_else(|| format!("{} is required\n{}", name, USAGE))
+    }
+
+    fn number<T: FromStr>(&mut self, name: &str) -> Result<T, String> {
+        self.optional_number(name)?.ok_or_else(|| format!("{} is required\n{}", name, USAGE))
+    }
+
+    fn optional_number<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, String> {
+        self.take_one(name)?.map(|value| value.parse().map_err(|_| format!("bad {} {}", name, value))).transpose()
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self.values.keys().next() {
+            Some(name) => Err(format!("unknown option {}\n{}", name, USAGE)),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::traits::Lsn;
+use crate::wal_record::WalRecordType;
+use crate::wal_registry::WalRecord;
+
+/// Wall-clock commit time in microseconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommitTimestamp(pub u64);
+
+impl CommitTimestamp {
+    pub fn now() -> Self {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self(since_epoch.as_micros() as u64)
+    }
+}
+
+/// How long `CoreStorage::commit` waits before returning.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Return once the commit record is durable.
+    #[default]
+    Sync,
+    /// Return once the commit record is staged; the background WAL flusher makes it
+    /// durable within `StorageConfig::async_commit_window`. A crash before then loses
+    /// the transaction, but never corrupts anything: like Postgres'
+    /// `synchronous_commit = off`.
+    Async,
+}
+
+/// A committed transaction's place in the WAL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Commit {
+    pub lsn: Lsn,
+    /// The transaction is durable once the flushed LSN reaches this.
+    pub end_lsn: Lsn,
+    pub commit_ts: CommitTimestamp,
+    /// Whether it already was durable when `commit` returned.
+    pub durable: bool,
+}
+
+/// Payload of a commit WAL record: `[0..8) xid`, `[8..16) commit_ts`, little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitRecord {
+    pub xid: u64,
+    pub commit_ts: CommitTimestamp,
+}
+
+impl CommitRecord {
+    pub const SIZE: usize = 16;
+}
+
+impl WalRecord for CommitRecord {
+    const TYPE: WalRecordType = WalRecordType::COMMIT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.xid.to_le_bytes());
+        out.extend_from_slice(&self.commit_ts.0.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            xid: u64::from_le_bytes(payload[0..8].try_into().unwrap()),
+            commit_ts: CommitTimestamp(u64::from_le_bytes(payload[8..16].try_into().unwrap())),
+        })
+    }
+}
+
+/// Ordered LSN <-> commit timestamp mapping for one database.
+///
+/// Commit timestamps are made strictly increasing in LSN order (a clock step backwards
+/// is absorbed by `next_timestamp`), so both columns are sorted and either can be
+/// binary searched. Every commit is kept; memory is bounded by pruning alongside WAL
+/// truncation, since nothing before the oldest retained LSN can be a PITR target.
+#[derive(Debug, Default)]
+pub struct CommitTimestampMap {
+    entries: Vec<(Lsn, CommitTimestamp)>,
+}
+
+impl CommitTimestampMap {
+    /// A commit timestamp for a new commit: the wall clock, but never at or before the
+    /// previous commit's.
+    pub fn next_timestamp(&self) -> CommitTimestamp {
+        let now = CommitTimestamp::now();
+        match self.entries.last() {
+            Some(&(_, last)) if now <= last => CommitTimestamp(last.0 + 1),
+            _ => now,
+        }
+    }
+
+    /// Records a commit. Commits must be recorded in LSN order; out-of-order entries
+    /// (e.g. replayed twice during recovery) are ignored.
+    pub fn record(&mut self, lsn: Lsn, commit_ts: CommitTimestamp) {
+        if let Some(&(last_lsn, last_ts)) = self.entries.last() {
+            if lsn <= last_lsn || commit_ts <= last_ts {
+                return;
+            }
+        }
+        self.entries.push((lsn, commit_ts));
+    }
+
+    /// Commit time of the last commit at or before `lsn`: "as of when is this LSN".
+    pub fn timestamp_at(&self, lsn: Lsn) -> Option<CommitTimestamp> {
+        let idx = self.entries.partition_point(|&(l, _)| l <= lsn);
+        idx.checked_sub(1).map(|i| self.entries[i].1)
+    }
+
+    /// LSN of the last commit at or before `commit_ts`. Recovering up to and including
+    /// this record is the PITR stop point for that target time.
+    pub fn lsn_at(&self, commit_ts: CommitTimestamp) -> Option<Lsn> {
+        let idx = self.entries.partition_point(|&(_, ts)| ts <= commit_ts);
+        idx.checked_sub(1).map(|i| self.entries[i].0)
+    }
+
+    /// Replication lag in microseconds: how far the newest commit is ahead of the last
+    /// commit a standby has applied at `applied_lsn`.
+    pub fn lag_micros(&self, applied_lsn: Lsn) -> Option<u64> {
+        let &(_, newest) = self.entries.last()?;
+        let applied = self.timestamp_at(applied_lsn).unwrap_or(CommitTimestamp(0));
+        Some(newest.0.saturating_sub(applied.0))
+    }
+
+    pub fn latest(&self) -> Option<(Lsn, CommitTimestamp)> {
+        self.entries.last().copied()
+    }
+
+    /// Drops entries older than `lsn`, keeping the last one before it so `timestamp_at`
+    /// stays answerable at the truncation point.
+    pub fn prune_before(&mut self, lsn: Lsn) {
+        let idx = self.entries.partition_point(|&(l, _)| l < lsn);
+        self.entries.drain(..idx.saturating_sub(1));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
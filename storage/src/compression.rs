@@ -0,0 +1,344 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use zstd::bulk::{Compressor, Decompressor};
+
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::page::{self, PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::segment;
+use crate::traits::StorageError;
+
+/// Dictionary version stored in the page header of pages compressed without a dictionary.
+pub const NO_DICTIONARY: u32 = 0;
+
+/// zstd's sweet spot for many small, similar inputs is a dictionary around 100x smaller than the training set.
+pub const DEFAULT_DICTIONARY_SIZE: usize = 16 * 1024;
+
+/// e.g., /data_dir/db_10/space_25.v0003.zdict
+pub fn dictionary_path(data_dir: &Path, db_id: u32, space_id: u32, version: u32) -> PathBuf {
+    data_dir
+        .join(format!("db_{}", db_id))
+        .join(format!("space_{}.v{:04}.zdict", space_id, version))
+}
+
+fn parse_dictionary_file_name(name: &str, space_id: u32) -> Option<u32> {
+    let rest = name.strip_prefix(&format!("space_{}.v", space_id))?;
+    rest.strip_suffix(".zdict")?.parse().ok()
+}
+
+/// One trained dictionary, with its zstd contexts loaded once rather than per page.
+struct Dictionary {
+    compressor: RefCell<Compressor<'static>>,
+    decompressor: RefCell<Decompressor<'static>>,
+}
+
+impl Dictionary {
+    fn load(bytes: &[u8], level: i32) -> Result<Self, StorageError> {
+        Ok(Self {
+            compressor: RefCell::new(Compressor::with_dictionary(level, bytes).map_err(StorageError::Io)?),
+            decompressor: RefCell::new(Decompressor::with_dictionary(bytes).map_err(StorageError::Io)?),
+        })
+    }
+}
+
+/// Every dictionary generation of one space.
+/// The newest generation compresses new writes; older generations stay loaded so
+/// pages written under them (identified by the dictionary version in their header)
+/// remain readable until they are rewritten and the generation is retired.
+/// Lives on a single core, hence the `RefCell`s instead of locks.
+pub struct SpaceDictionaries {
+    data_dir: PathBuf,
+    db_id: u32,
+    space_id: u32,
+    level: i32,
+    generations: BTreeMap<u32, Dictionary>,
+}
+
+impl SpaceDictionaries {
+    /// Loads every persisted dictionary generation of the space.
+    pub fn load(data_dir: &Path, db_id: u32, space_id: u32, level: i32) -> Result<Self, StorageError> {
+        let mut generations = BTreeMap::new();
+        let db_dir = data_dir.join(format!("db_{}", db_id));
+
+        if db_dir.is_dir() {
+            for entry in fs::read_dir(&db_dir).map_err(StorageError::Io)? {
+                let path = entry.map_err(StorageError::Io)?.path();
+                let version = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| parse_dictionary_file_name(n, space_id));
+                if let Some(version) = version {
+                    let bytes = fs::read(&path).map_err(StorageError::Io)?;
+                    generations.insert(version, Dictionary::load(&bytes, level)?);
+                }
+            }
+        }
+
+        Ok(Self {
+            data_dir: data_dir.to_path_buf(),
+            db_id,
+            space_id,
+            level,
+            generations,
+        })
+    }
+
+    /// The version new pages are compressed with, or `NO_DICTIONARY` if none has been trained.
+    pub fn current_version(&self) -> u32 {
+        self.generations.keys().next_back().copied().unwrap_or(NO_DICTIONARY)
+    }
+
+    pub fn versions(&self) -> Vec<u32> {
+        self.generations.keys().copied().collect()
+    }
+
+    /// Trains a new generation from sample page payloads and persists it before it is used,
+    /// so no page can ever reference a dictionary that is not on disk.
+    pub fn train(&mut self, samples: &[Vec<u8>], max_size: usize) -> Result<u32, StorageError> {
+        let bytes = zstd::dict::from_samples(samples, max_size).map_err(StorageError::Io)?;
+        let version = self.current_version() + 1;
+        let path = dictionary_path(&self.data_dir, self.db_id, self.space_id, version);
+
+        // Write-then-rename keeps a crash from leaving a half-written dictionary behind.
+        let tmp = path.with_extension("zdict.tmp");
+        let mut file = fs::File::create(&tmp).map_err(StorageError::Io)?;
+        file.write_all(&bytes).map_err(StorageError::Io)?;
+        file.sync_all().map_err(StorageError::Io)?;
+        fs::rename(&tmp, &path).map_err(StorageError::Io)?;
+        fs::File::open(path.parent().unwrap())
+            .and_then(|dir| dir.sync_all())
+            .map_err(StorageError::Io)?;
+
+        self.generations.insert(version, Dictionary::load(&bytes, self.level)?);
+        Ok(version)
+    }
+
+    /// Drops an old generation once no page references it any more.
+    /// The current generation can never be retired.
+    pub fn retire(&mut self, version: u32) -> Result<(), StorageError> {
+        if version == self.current_version() || self.generations.remove(&version).is_none() {
+            return Ok(());
+        }
+        let path = dictionary_path(&self.data_dir, self.db_id, self.space_id, version);
+        fs::remove_file(path).map_err(StorageError::Io)
+    }
+
+    /// Compresses with the newest dictionary (or plain zstd if none exists).
+    /// Returns the dictionary version to record in the page header.
+    pub fn compress(&self, payload: &[u8]) -> Result<(u32, Vec<u8>), StorageError> {
+        let version = self.current_version();
+        let compressed = match self.generations.get(&version) {
+            Some(dict) => dict.compressor.borrow_mut().compress(payload),
+            None => zstd::bulk::compress(payload, self.level),
+        };
+        Ok((version, compressed.map_err(StorageError::Io)?))
+    }
+
+    /// Decompresses into `dst` using the generation named by the page header.
+    pub fn decompress(&self, version: u32, src: &[u8], dst: &mut [u8]) -> Result<usize, StorageError> {
+        let result = if version == NO_DICTIONARY {
+            zstd::bulk::decompress_to_buffer(src, dst)
+        } else {
+            let dict = self.generations.get(&version).ok_or(StorageError::MissingDictionary {
+                db_id: self.db_id,
+                space_id: self.space_id,
+                version,
+            })?;
+            dict.decompressor.borrow_mut().decompress_to_buffer(src, dst)
+        };
+        result.map_err(StorageError::Io)
+    }
+}
+
+/// Samples up to `max_samples` non-empty page payloads (header excluded) spread evenly
+/// across the space's segments, for use as a training set.
+pub fn sample_pages(data_dir: &Path, db_id: u32, space_id: u32, max_samples: usize) -> Result<Vec<Vec<u8>>, StorageError> {
+    let mut segments = Vec::new();
+    for seg_no in 0.. {
+        let path = segment::segment_path(data_dir, db_id, space_id, seg_no);
+        match fs::metadata(&path) {
+            Ok(meta) => segments.push((path, meta.len() / PAGE_SIZE as u64)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+            Err(e) => return Err(StorageError::Io(e)),
+        }
+    }
+
+    // Data pages only (slot 0 of every segment is the header page).
+    let total_pages: u64 = segments.iter().map(|(_, pages)| pages.saturating_sub(1)).sum();
+    let stride = (total_pages / max_samples.max(1) as u64).max(1);
+
+    let mut samples = Vec::new();
+    let mut page = vec![0u8; PAGE_SIZE];
+    for (path, pages) in segments {
+        let mut file = fs::File::open(&path).map_err(StorageError::Io)?;
+        let mut slot = 1;
+        while slot < pages && samples.len() < max_samples {
+            file.seek(SeekFrom::Start(slot * PAGE_SIZE as u64)).map_err(StorageError::Io)?;
+            file.read_exact(&mut page).map_err(StorageError::Io)?;
+            if page.iter().any(|&b| b != 0) {
+                samples.push(page[PAGE_HEADER_SIZE..].to_vec());
+            }
+            slot += stride;
+        }
+    }
+    Ok(samples)
+}
+
+// -----------------------------------------------------------------------------
+// Compressed Page Frames
+//
+// A compressed page is stored as a frame padded to the filesystem block size;
+// the rest of the 8KB slot is hole-punched so it costs no device space:
+//
+//   [0..24)   page header (flags carry the codec, checksum covers the frame)
+//   [24..28)  compressed payload length
+//   [28..)    compressed payload (original bytes [24..8192))
+//   ...       zero padding up to the next FS_BLOCK_SIZE boundary
+//
+// Pages that would not save at least one block are written uncompressed.
+// -----------------------------------------------------------------------------
+
+/// Granularity at which hole punching actually frees space.
+pub const FS_BLOCK_SIZE: usize = 4096;
+
+const FRAME_LEN_OFFSET: usize = PAGE_HEADER_SIZE;
+pub const FRAME_PAYLOAD_OFFSET: usize = FRAME_LEN_OFFSET + 4;
+
+/// Largest frame worth writing: anything bigger saves no blocks.
+const MAX_FRAME_SIZE: usize = PAGE_SIZE - FS_BLOCK_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageCompression {
+    /// Cheap enough for warm spaces; roughly 2x on typical heap pages.
+    Lz4,
+    /// Better ratios for cold analytical spaces, using the space's trained dictionary when one exists.
+    Zstd { level: i32 },
+}
+
+impl PageCompression {
+    fn flag(self) -> u16 {
+        match self {
+            PageCompression::Lz4 => page::FLAG_COMPRESSED_LZ4,
+            PageCompression::Zstd { .. } => page::FLAG_COMPRESSED_ZSTD,
+        }
+    }
+}
+
+pub fn is_compressed(page: &[u8]) -> bool {
+    page::flags(page) & (page::FLAG_COMPRESSED_LZ4 | page::FLAG_COMPRESSED_ZSTD) != 0
+}
+
+/// Compresses `page` into `frame`, returning the padded frame length,
+/// or `None` if the page should be written uncompressed.
+pub fn compress_page(
+    codec: PageCompression,
+    dictionaries: Option<&SpaceDictionaries>,
+    algorithm: ChecksumAlgorithm,
+    page: &[u8],
+    frame: &mut [u8],
+) -> Result<Option<usize>, StorageError> {
+    let payload = &page[PAGE_HEADER_SIZE..PAGE_SIZE];
+    let room = MAX_FRAME_SIZE - FRAME_PAYLOAD_OFFSET;
+
+    let (dict_version, compressed) = match codec {
+        PageCompression::Lz4 => (NO_DICTIONARY, lz4_flex::block::compress(payload)),
+        PageCompression::Zstd { level } => match dictionaries {
+            Some(dicts) => dicts.compress(payload)?,
+            None => (NO_DICTIONARY, zstd::bulk::compress(payload, level).map_err(StorageError::Io)?),
+        },
+    };
+    if compressed.len() > room {
+        return Ok(None);
+    }
+    let compressed_len = compressed.len();
+    frame[FRAME_PAYLOAD_OFFSET..FRAME_PAYLOAD_OFFSET + compressed_len].copy_from_slice(&compressed);
+
+    let frame_len = (FRAME_PAYLOAD_OFFSET + compressed_len).next_multiple_of(FS_BLOCK_SIZE);
+    frame[..PAGE_HEADER_SIZE].copy_from_slice(&page[..PAGE_HEADER_SIZE]);
+    frame[FRAME_LEN_OFFSET..FRAME_PAYLOAD_OFFSET].copy_from_slice(&(compressed_len as u32).to_le_bytes());
+    frame[FRAME_PAYLOAD_OFFSET + compressed_len..frame_len].fill(0);
+    page::set_flags(frame, page::flags(page) | codec.flag());
+    page::set_dict_version(frame, dict_version);
+
+    stamp_frame(algorithm, frame, frame_len);
+    Ok(Some(frame_len))
+}
+
+/// Padded on-disk length of a compressed frame, or `None` if the length field is implausible.
+pub fn frame_len(frame: &[u8]) -> Option<usize> {
+    let compressed_len = u32::from_le_bytes(frame[FRAME_LEN_OFFSET..FRAME_PAYLOAD_OFFSET].try_into().unwrap()) as usize;
+    let len = (FRAME_PAYLOAD_OFFSET + compressed_len).next_multiple_of(FS_BLOCK_SIZE);
+    (len <= MAX_FRAME_SIZE).then_some(len)
+}
+
+/// The frame checksum covers `[4..frame_len)` of whatever bytes are on disk (ciphertext included).
+pub fn stamp_frame(algorithm: ChecksumAlgorithm, frame: &mut [u8], frame_len: usize) {
+    let crc = algorithm.compute(&frame[page::CHECKSUM_COVERAGE_START..frame_len]);
+    page::set_checksum(frame, crc);
+}
+
+pub fn verify_frame(algorithm: ChecksumAlgorithm, frame: &[u8]) -> bool {
+    match frame_len(frame) {
+        Some(len) => page::checksum(frame) == algorithm.compute(&frame[page::CHECKSUM_COVERAGE_START..len]),
+        None => false,
+    }
+}
+
+/// Verifies a compressed frame read from disk and expands it in place into a normal,
+/// freshly stamped page, so callers never see the compressed representation.
+/// Returns `false` if the frame is corrupt.
+pub fn decompress_page(
+    dictionaries: Option<&SpaceDictionaries>,
+    algorithm: ChecksumAlgorithm,
+    buf: &mut [u8],
+) -> Result<bool, StorageError> {
+    if !verify_frame(algorithm, buf) {
+        return Ok(false);
+    }
+    expand_frame(dictionaries, algorithm, buf)
+}
+
+/// Expands an already verified (and, if needed, decrypted) frame in place.
+pub fn expand_frame(
+    dictionaries: Option<&SpaceDictionaries>,
+    algorithm: ChecksumAlgorithm,
+    buf: &mut [u8],
+) -> Result<bool, StorageError> {
+    let Some(len) = frame_len(buf) else {
+        return Ok(false);
+    };
+    let compressed_len = u32::from_le_bytes(buf[FRAME_LEN_OFFSET..FRAME_PAYLOAD_OFFSET].try_into().unwrap()) as usize;
+
+    // Source and destination overlap inside `buf`, so stage the compressed bytes first.
+    let compressed = buf[FRAME_PAYLOAD_OFFSET..FRAME_PAYLOAD_OFFSET + compressed_len].to_vec();
+    debug_assert!(FRAME_PAYLOAD_OFFSET + compressed_len <= len);
+    let flags = page::flags(buf);
+    let version = page::dict_version(buf);
+    let payload = &mut buf[PAGE_HEADER_SIZE..PAGE_SIZE];
+
+    let expanded = if flags & page::FLAG_COMPRESSED_LZ4 != 0 {
+        lz4_flex::block::decompress_into(&compressed, payload).ok()
+    } else {
+        match dictionaries {
+            Some(dicts) => match dicts.decompress(version, &compressed, payload) {
+                Ok(n) => Some(n),
+                // A missing dictionary generation is an error of its own, not page corruption.
+                Err(e @ StorageError::MissingDictionary { .. }) => return Err(e),
+                Err(_) => None,
+            },
+            None if version == NO_DICTIONARY => zstd::bulk::decompress_to_buffer(&compressed, payload).ok(),
+            None => None,
+        }
+    };
+    if expanded != Some(PAGE_SIZE - PAGE_HEADER_SIZE) {
+        return Ok(false);
+    }
+
+    page::set_flags(buf, flags & !(page::FLAG_COMPRESSED_LZ4 | page::FLAG_COMPRESSED_ZSTD));
+    page::set_dict_version(buf, NO_DICTIONARY);
+    checksum::stamp_page(algorithm, buf);
+    Ok(true)
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::buffer_pool::{BackgroundWriterConfig, PrefetchConfig, ScanRingConfig};
+use crate::checkpointer::{Checkpointer, CheckpointerConfig};
+use crate::checksum::ChecksumAlgorithm;
+use crate::commit_ts::Durability;
+use crate::discard::DiscardConfig;
+use crate::eviction::EvictionKind;
+use crate::failpoint::POINTS;
+use crate::page::PAGE_HEADER_SIZE;
+use crate::page_verify;
+use crate::quarantine::CorruptPagePolicy;
+use crate::ring::CompletionConfig;
+use crate::segment::{SegmentAllocation, EXTENT_PAGES};
+use crate::traits::{PageId, PageStore, StorageConfig, StorageError, StorageManager};
+use crate::txn::TransactionManager;
+use crate::wal::WalLayout;
+use crate::wal_compress::WalCompression;
+use crate::wal_registry::WalRegistry;
+
+// -----------------------------------------------------------------------------
+// Crash Test Harness
+//
+// `cascade-crashtest` kills the engine at its failpoints (see `failpoint.rs`) and
+// checks what recovery makes of it, run after run. A binary's `main` hands `run` its
+// arguments after the program name, and the harness runs the same binary again for
+// its children, so `main` must do nothing else first. Build with `failpoints`:
+//
+//   cascade-crashtest --dir <dir> [--iterations <n>] [--txns <n>] [--seed <n>]
+//
+// Each run gets a fresh directory under `--dir` and a seed of its own, and picks a
+// point and a hit of it at random. Then:
+//
+//   workload   a child runs `--txns` transactions, one at a time, on one core, with
+//              CASCADE_FAILPOINTS set to SIGKILL it at that hit. Each overwrites
+//              WRITES_PER_TXN slots, u64s on the first PAGES pages of a space, with
+//              its number; one in ABORT_ONE_IN rolls back instead of committing, and
+//              every CHECKPOINT_EVERY transactions a checkpoint runs. The child prints
+//              each commit once it is durable.
+//   verify     a second child mounts the directory, recovers, prints every slot,
+//              then checkpoints and shuts down cleanly.
+//   check      the slots must hold exactly the transactions committed up to the last
+//              one printed, or up to the next commit after it, which may have made it
+//              to disk before the kill: nothing rolled back or cut off by the kill,
+//              nothing committed lost, nothing torn. Every page in use must pass its
+//              checksum (`page_verify`).
+//
+// The transactions follow from the run's seed alone, so the parent knows what each
+// one wrote without being told. A run that fails stops the harness and leaves its
+// directory behind; one that passes is removed. A point the run never reaches lets the
+// workload finish, and the run is checked all the same.
+// -----------------------------------------------------------------------------
+
+/// Database and space the workload writes.
+pub const CRASH_DB: u32 = 0xC4A5;
+pub const CRASH_SPACE: u32 = 1;
+
+// Pages holding the slots, u64s after the page header
+const PAGES: u32 = 4;
+const SLOTS_PER_PAGE: usize = 8;
+const SLOTS: usize = PAGES as usize * SLOTS_PER_PAGE;
+const WRITES_PER_TXN: usize = 3;
+const ABORT_ONE_IN: u32 = 8;
+const CHECKPOINT_EVERY: u64 = 16;
+
+const USAGE: &str = "usage: cascade-crashtest --dir <dir> [--iterations <n>] [--txns <n>] [--seed <n>]";
+
+/// What one run found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashRun {
+    pub point: &'static str,
+    pub hit: u64,
+    /// Whether the point fired, and the last commit the workload reported.
+    pub killed: bool,
+    pub committed: u64,
+}
+
+// One transaction of the workload: the slots it writes, and whether it rolls back
+struct Txn {
+    slots: Vec<usize>,
+    aborts: bool,
+}
+
+/// Runs `cascade-crashtest` with its arguments, program name excluded.
+pub fn run(args: impl IntoIterator<Item = String>) -> Result<(), String> {
+    let mut args = Args::parse(args.into_iter())?;
+    // Set by the harness on its children
+    let role = args.take("--role");
+    let dir = args.path("--dir")?;
+    let txns = args.number("--txns", 200)?;
+    let seed = args.number("--seed", 1)?;
+    match role.as_deref() {
+        None => {
+            let iterations = args.number("--iterations", 200)?;
+            args.finish()?;
+            crash_test(&dir, iterations, txns, seed)
+        }
+        Some("workload") => {
+            args.finish()?;
+            workload(&dir, txns, seed).map_err(|e| format!("workload failed: {:?}", e))
+        }
+        Some("verify") => {
+            args.finish()?;
+            verify(&dir).map_err(|e| format!("verify failed: {:?}", e))
+        }
+        Some(other) => Err(format!("unknown role {}\n{}", other, USAGE)),
+    }
+}
+
+/// Runs `iterations` runs in directories under `dir`, stopping at the first that
+/// fails.
+pub fn crash_test(dir: &Path, iterations: u64, txns: u64, seed: u64) -> Result<(), String> {
+    if !cfg!(feature = "failpoints") {
+        return Err("cascade-crashtest needs the failpoints feature".to_string());
+    }
+    let exe = std::env::current_exe().map_err(|e| format!("can't find the harness binary: {}", e))?;
+    let mut rng = Rng::new(seed);
+    for iteration in 0..iterations {
+        let run_dir = dir.join(format!("run-{}", iteration));
+        if run_dir.exists() {
+            return Err(format!("{} already exists", run_dir.display()));
+        }
+        let run_seed = rng.next();
+        let point = POINTS[rng.below(POINTS.len() as u32) as usize];
+        let hit = 1 + rng.below(reach(point, txns) as u32) as u64;
+        let run = crash_run(&exe, &run_dir, txns, run_seed, point, hit)
+            .map_err(|e| format!("run {} ({} hit {}, seed {}): {}; left in {}", iteration, point, hit, run_seed, e, run_dir.display()))?;
+        println!(
+            "run {}: {} hit {}: {}, committed up to txn {}, recovered",
+            iteration,
+            run.point,
+            run.hit,
+            if run.killed { "killed" } else { "not reached" },
+            run.committed,
+        );
+        fs::remove_dir_all(&run_dir).map_err(|e| format!("can't remove {}: {}", run_dir.display(), e))?;
+    }
+    Ok(())
+}
+
+// Kills a workload at `point`'s `hit`, recovers, and checks the result
+fn crash_run(exe: &Path, dir: &Path, txns: u64, seed: u64, point: &'static str, hit: u64) -> Result<CrashRun, String> {
+    let failpoint = format!("{}=kill@{}", point, hit);
+    let (lines, status) = child(exe, "workload", dir, txns, seed, Some(&failpoint))?;
+    let killed = status.signal() == Some(libc::SIGKILL);
+    if !killed && !status.success() {
+        return Err(format!("workload exited with {}", status));
+    }
+    let committed = lines.iter().rev().find_map(|line| line.strip_prefix("committed ")?.parse().ok()).unwrap_or(0);
+
+    let (lines, status) = child(exe, "verify", dir, txns, seed, None)?;
+    if !status.success() {
+        return Err(format!("recovery exited with {}", status));
+    }
+    let mut slots = vec![None; SLOTS];
+    for line in &lines {
+        let mut words = line.split(' ');
+        if let (Some("slot"), Some(slot), Some(value)) = (words.next(), words.next(), words.next()) {
+            let slot: usize = slot.parse().map_err(|_| format!("bad line {:?}", line))?;
+            *slots.get_mut(slot).ok_or_else(|| format!("bad line {:?}", line))? = value.parse::<u64>().ok();
+        }
+    }
+    let slots: Vec<u64> = slots.into_iter().collect::<Option<_>>().ok_or("recovery didn't report every slot")?;
+
+    // The commit after the last one reported may or may not have made it to disk.
+    let plans = plans(seed, txns);
+    let before = expected(&plans, committed);
+    let after = plans.iter().skip(committed as usize).position(|txn| !txn.aborts).map(|next| expected(&plans, committed + next as u64 + 1));
+    if slots != before && after.as_ref() != Some(&slots) {
+        return Err(format!("recovered {:?}, expected {:?} or {:?}", slots, before, after));
+    }
+
+    let report = page_verify::verify_pages(&dir.join("data"), Some(CRASH_DB), None, 1).map_err(|e| format!("page check failed: {:?}", e))?;
+    if !report.is_clean() {
+        return Err(format!("bad pages {:?}, bad segments {:?}", report.bad_pages, report.bad_segments));
+    }
+    Ok(CrashRun { point, hit, killed, committed })
+}
+
+// Runs the harness binary as a child in `role`, and returns what it printed
+fn child(exe: &Path, role: &str, dir: &Path, txns: u64, seed: u64, failpoint: Option<&str>) -> Result<(Vec<String>, ExitStatus), String> {
+    let mut command = Command::new(exe);
+    command
+        .arg("--role")
+        .arg(role)
+        .arg("--dir")
+        .arg(dir)
+        .arg("--txns")
+        .arg(txns.to_string())
+        .arg("--seed")
+        .arg(seed.to_string())
+        .stdout(Stdio::piped());
+    match failpoint {
+        Some(spec) => command.env("CASCADE_FAILPOINTS", spec),
+        None => command.env_remove("CASCADE_FAILPOINTS"),
+    };
+    let mut child = command.spawn().map_err(|e| format!("can't start the {}: {}", role, e))?;
+    let stdout = child.stdout.take().unwrap();
+    let lines = BufReader::new(stdout).lines().collect::<Result<Vec<_>, _>>().map_err(|e| format!("reading the {}: {}", role, e))?;
+    let status = child.wait().map_err(|e| format!("waiting for the {}: {}", role, e))?;
+    Ok((lines, status))
+}
+
+// Roughly how often a workload of `txns` transactions reaches `point`
+fn reach(point: &str, txns: u64) -> u64 {
+    let checkpoints = txns / CHECKPOINT_EVERY;
+    let hits = match point {
+        "extent-before-header" | "extent-after-header" => 1,
+        // The slot pages are neighbours, written back together by `write_pages`
+        "page-before-write" | "wal-truncate-before-remove" => checkpoints,
+        point if point.starts_with("checkpoint-") => checkpoints,
+        // A WAL flush a commit
+        _ => txns,
+    };
+    hits.max(1)
+}
+
+// The workload's transactions, from the run's seed
+fn plans(seed: u64, txns: u64) -> Vec<Txn> {
+    let mut rng = Rng::new(seed);
+    (0..txns)
+        .map(|_| {
+            let mut slots = Vec::with_capacity(WRITES_PER_TXN);
+            while slots.len() < WRITES_PER_TXN {
+                let slot = rng.below(SLOTS as u32) as usize;
+                if !slots.contains(&slot) {
+                    slots.push(slot);
+                }
+            }
+            Txn { slots, aborts: rng.below(ABORT_ONE_IN) == 0 }
+        })
+        .collect()
+}
+
+// The slots once the first `txns` transactions have run
+fn expected(plans: &[Txn], txns: u64) -> Vec<u64> {
+    let mut slots = vec![0; SLOTS];
+    for (index, txn) in plans.iter().take(txns as usize).enumerate() {
+        if !txn.aborts {
+            for &slot in &txn.slots {
+                slots[slot] = index as u64 + 1;
+            }
+        }
+    }
+    slots
+}
+
+fn slot_page(slot: usize) -> PageId {
+    PageId { db_id: CRASH_DB, space_id: CRASH_SPACE, page_no: (slot / SLOTS_PER_PAGE) as u32 }
+}
+
+fn slot_offset(slot: usize) -> usize {
+    PAGE_HEADER_SIZE + slot % SLOTS_PER_PAGE * 8
+}
+
+// The workload child
+fn workload(dir: &Path, txns: u64, seed: u64) -> Result<(), StorageError> {
+    let manager = mount(dir)?;
+    let txn_manager = TransactionManager::open(&dir.join("data"), CRASH_DB)?;
+    manager.runtime().start(async {
+        let storage = Rc::new(manager.local_worker(0));
+        let partition = manager.local_partition(0, Rc::clone(&storage));
+        manager.recover(&partition, &WalRegistry::new()).await?;
+        let first = storage.allocate_extent(CRASH_DB, CRASH_SPACE, EXTENT_PAGES).await?;
+        assert_eq!(first, 0, "crash test space not empty");
+        let checkpointer = Checkpointer::new(Rc::clone(partition.pool()), CheckpointerConfig::default());
+
+        for (index, plan) in plans(seed, txns).iter().enumerate() {
+            let number = index as u64 + 1;
+            let mut txn = txn_manager.begin()?;
+            for &slot in &plan.slots {
+                partition.update_page(txn.undo_log(), slot_page(slot), slot_offset(slot), number.to_le_bytes().to_vec()).await?;
+            }
+            if plan.aborts {
+                txn_manager.abort(&mut txn, &partition).await?;
+            } else {
+                txn_manager.commit(&txn, &partition, Durability::Sync).await?;
+                println!("committed {}", number);
+            }
+            if number.is_multiple_of(CHECKPOINT_EVERY) {
+                checkpointer.checkpoint(CRASH_DB).await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+// The verify child
+fn verify(dir: &Path) -> Result<(), StorageError> {
+    let manager = mount(dir)?;
+    manager.runtime().start(async {
+        let storage = Rc::new(manager.local_worker(0));
+        let partition = manager.local_partition(0, Rc::clone(&storage));
+        manager.recover(&partition, &WalRegistry::new()).await?;
+        for slot in 0..SLOTS {
+            let offset = slot_offset(slot);
+            let value = partition
+                .read_page(slot_page(slot), move |page| u64::from_le_bytes(page[offset..offset + 8].try_into().unwrap()))
+                .await?;
+            println!("slot {} {}", slot, value);
+        }
+        // Recovered pages go to disk, for the page check.
+        Checkpointer::new(Rc::clone(partition.pool()), CheckpointerConfig::default()).checkpoint(CRASH_DB).await?;
+        partition.pool().flush_all().await
+    })?;
+    manager.mark_clean_shutdown()
+}
+
+fn mount(dir: &Path) -> Result<StorageManager, StorageError> {
+    let data_dir = dir.join("data");
+    let wal_dir = dir.join("wal");
+    fs::create_dir_all(&data_dir).map_err(StorageError::Io)?;
+    fs::create_dir_all(&wal_dir).map_err(StorageError::Io)?;
+    StorageManager::mount(config(data_dir, wal_dir))
+}
+
+fn config(data_dir: PathBuf, wal_dir: PathBuf) -> StorageConfig {
+    StorageConfig {
+        data_dir,
+        wal_dir,
+        io_uring_entries: 256,
+        completions: CompletionConfig::default(),
+        checksum: ChecksumAlgorithm::Crc32c,
+        checksum_offload_threshold: None,
+        spaces: HashMap::new(),
+        discard: DiscardConfig::default(),
+        segment_allocation: SegmentAllocation::Sparse,
+        end_to_end_checksums: true,
+        full_page_writes: true,
+        corrupt_pages: CorruptPagePolicy::default(),
+        redo_past_corruption: false,
+        pinned_pages: Vec::new(),
+        commit_delay: None,
+        async_commit_window: Duration::from_millis(10),
+        wal_archiver: None,
+        wal_compression: WalCompression::default(),
+        wal_keys: HashMap::new(),
+        wal_future_segments: 0,
+        wal_layout: WalLayout::PerDatabase,
+        wal_direct_io: false,
+        cores: 1,
+        buffer_pool_frames: 64,
+        buffer_pool_max_frames: 64,
+        buffer_pool_warm_up: false,
+        numa_aware: false,
+        buffer_pool_eviction: EvictionKind::default(),
+        background_writer: BackgroundWriterConfig::default(),
+        prefetch: PrefetchConfig::default(),
+        scan_ring: ScanRingConfig::default(),
+        buffer_pool_bulk_budget: 100,
+        checkpointer: CheckpointerConfig::default(),
+        slow_io_threshold: None,
+    }
+}
+
+// xorshift64*: cheap, and good enough to pick slots
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Never zero, which xorshift can't leave
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        (((self.next() >> 32) * n as u64) >> 32) as u32
+    }
+}
+
+// `--name value` pairs, each given once
+struct Args {
+    values: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut values = HashMap::new();
+        while let Some(name) = args.next() {
+            if !name.starts_with("--") {
+                return Err(format!("unexpected argument {}\n{}", name, USAGE));
+            }
+            let value = args.next().ok_or_else(|| format!("{} needs a value", name))?;
+            if values.insert(name.clone(), value).is_some() {
+                return Err(format!("{} given more than once", name));
+            }
+        }
+        Ok(Self { values })
+    }
+
+    fn take(&mut self, name: &str) -> Option<String> {
+        self.values.remove(name)
+    }
+
+    fn path(&mut self, name: &str) -> Result<PathBuf, String> {
+        self.take(name).map(PathBuf::from).ok_or_else(|| format!("{} is required\n{}", name, USAGE))
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, name: &str, default: T) -> Result<T, String> {
+        match self.take(name) {
+            Some(value) => value.parse().map_err(|_| format!("bad {} {}", name, value)),
+            None => Ok(default),
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self.values.keys().next() {
+            Some(name) => Err(format!("unknown option {}\n{}", name, USAGE)),
+            None => Ok(()),
+        }
+    }
+}
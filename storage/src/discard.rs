@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::traits::StorageError;
+
+/// Freed bytes accumulated before an `fstrim`-style pass is kicked off (64MiB).
+pub const TRIM_BATCH_BYTES: u64 = 64 << 20;
+
+/// Ranges shorter than this aren't worth trimming; most SSDs ignore them anyway.
+const TRIM_MIN_EXTENT: u64 = 1 << 20;
+
+// _IOWR('X', 121, struct fstrim_range), from <linux/fs.h>.
+const FITRIM: libc::c_ulong = 0xC018_5879;
+
+#[repr(C)]
+struct FstrimRange {
+    start: u64,
+    len: u64,
+    minlen: u64,
+}
+
+/// What happens on disk when an extent is freed or a space is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiscardMode {
+    /// Freed pages keep their blocks; the SSD never learns they are dead.
+    #[default]
+    Off,
+    /// Punch a hole over the freed range. The filesystem releases the blocks and, if it
+    /// is mounted with `-o discard`, issues the TRIM itself.
+    PunchHole,
+    /// Punch holes, and batch a `FITRIM` over the filesystem every `TRIM_BATCH_BYTES`
+    /// of freed space. For filesystems mounted without online discard.
+    Trim,
+}
+
+/// Discard settings, resolved per device: an entry in `devices` applies to every file on
+/// the same filesystem as its path (typically a mount point), everything else uses `default`.
+#[derive(Debug, Clone, Default)]
+pub struct DiscardConfig {
+    pub default: DiscardMode,
+    pub devices: HashMap<PathBuf, DiscardMode>,
+}
+
+impl DiscardConfig {
+    /// The mode for the device holding `path`. Unreadable device paths are ignored.
+    pub fn mode_for(&self, path: &Path) -> DiscardMode {
+        if self.devices.is_empty() {
+            return self.default;
+        }
+        let dev = match std::fs::metadata(path) {
+            Ok(meta) => meta.dev(),
+            Err(_) => return self.default,
+        };
+        self.devices
+            .iter()
+            .find(|(device_path, _)| std::fs::metadata(device_path).map(|m| m.dev() == dev).unwrap_or(false))
+            .map(|(_, &mode)| mode)
+            .unwrap_or(self.default)
+    }
+}
+
+/// Issues `FITRIM` over the whole filesystem containing `path`, like `fstrim(8)`.
+/// Returns the number of bytes the filesystem reports as trimmed.
+pub fn fstrim(path: &Path) -> Result<u64, StorageError> {
+    let dir = File::open(path).map_err(StorageError::Io)?;
+    let mut range = FstrimRange {
+        start: 0,
+        len: u64::MAX,
+        minlen: TRIM_MIN_EXTENT,
+    };
+    let rc = unsafe { libc::ioctl(dir.as_raw_fd(), FITRIM, &mut range as *mut FstrimRange) };
+    if rc != 0 {
+        return Err(StorageError::Io(std::io::Error::last_os_error()));
+    }
+    Ok(range.len)
+}
+
+/// Runs `fstrim` on a short-lived thread. A trim pass can take seconds on a large
+/// filesystem and must never block an io_uring submit loop; failures are dropped since
+/// a missed trim only costs SSD write performance, never correctness.
+pub fn fstrim_in_background(path: PathBuf) {
+    let _ = thread::Builder::new()
+        .name("fstrim".to_string())
+        .spawn(move || {
+            let _ = fstrim(&path);
+        });
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes256;
+
+use crate::traits::PageId;
+
+const BLOCK: usize = 16;
+
+/// A 512-bit AES-256-XTS key: the first half encrypts data, the second half encrypts tweaks.
+#[derive(Clone)]
+pub struct XtsKey([u8; 64]);
+
+impl XtsKey {
+    /// XTS is only secure when the two halves differ, so identical halves are rejected.
+    pub fn new(bytes: [u8; 64]) -> Option<Self> {
+        (bytes[..32] != bytes[32..]).then_some(Self(bytes))
+    }
+}
+
+// Never print key material.
+impl fmt::Debug for XtsKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("XtsKey(..)")
+    }
+}
+
+impl Drop for XtsKey {
+    fn drop(&mut self) {
+        for byte in self.0.iter_mut() {
+            // Volatile so the wipe isn't optimized away as a dead store.
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+/// Encrypts page contents with AES-256-XTS, using the `PageId` as the tweak.
+/// Because the tweak is the page's address, identical plaintext on two pages
+/// yields different ciphertext, and a page copied to another slot won't decrypt.
+pub struct PageCipher {
+    data: Aes256,
+    tweak: Aes256,
+}
+
+impl PageCipher {
+    pub fn new(key: &XtsKey) -> Self {
+        Self {
+            data: Aes256::new(GenericArray::from_slice(&key.0[..32])),
+            tweak: Aes256::new(GenericArray::from_slice(&key.0[32..])),
+        }
+    }
+
+    /// Encrypts `data` in place. `data` must be at least one AES block long;
+    /// a trailing partial block is handled with ciphertext stealing.
+    pub fn encrypt(&self, page_id: PageId, data: &mut [u8]) {
+        assert!(data.len() >= BLOCK, "XTS needs at least one full block");
+        let mut tweak = self.initial_tweak(page_id);
+        let full = data.len() / BLOCK;
+        let rem = data.len() % BLOCK;
+        let plain_blocks = if rem == 0 { full } else { full - 1 };
+
+        for block in data[..plain_blocks * BLOCK].chunks_exact_mut(BLOCK) {
+            self.encrypt_block(block, &tweak);
+            mul_alpha(&mut tweak);
+        }
+
+        if rem != 0 {
+            // Ciphertext stealing (IEEE 1619): encrypt the last full block, emit its head as the
+            // final partial block, and re-encrypt the partial plaintext padded with its tail.
+            let last = plain_blocks * BLOCK;
+            let mut stolen = [0u8; BLOCK];
+            stolen.copy_from_slice(&data[last..last + BLOCK]);
+            self.encrypt_block(&mut stolen, &tweak);
+            mul_alpha(&mut tweak);
+
+            let mut padded = [0u8; BLOCK];
+            padded[..rem].copy_from_slice(&data[last + BLOCK..]);
+            padded[rem..].copy_from_slice(&stolen[rem..]);
+            data[last + BLOCK..].copy_from_slice(&stolen[..rem]);
+
+            self.encrypt_block(&mut padded, &tweak);
+            data[last..last + BLOCK].copy_from_slice(&padded);
+        }
+    }
+
+    /// Inverse of `encrypt`.
+    pub fn decrypt(&self, page_id: PageId, data: &mut [u8]) {
+        assert!(data.len() >= BLOCK, "XTS needs at least one full block");
+        let mut tweak = self.initial_tweak(page_id);
+        let full = data.len() / BLOCK;
+        let rem = data.len() % BLOCK;
+        let plain_blocks = if rem == 0 { full } else { full - 1 };
+
+        for block in data[..plain_blocks * BLOCK].chunks_exact_mut(BLOCK) {
+            self.decrypt_block(block, &tweak);
+            mul_alpha(&mut tweak);
+        }
+
+        if rem != 0 {
+            // The stolen block was encrypted with the *next* tweak, so undo that one first.
+            let last = plain_blocks * BLOCK;
+            let mut next_tweak = tweak;
+            mul_alpha(&mut next_tweak);
+
+            let mut padded = [0u8; BLOCK];
+            padded.copy_from_slice(&data[last..last + BLOCK]);
+            self.decrypt_block(&mut padded, &next_tweak);
+
+            let mut stolen = [0u8; BLOCK];
+            stolen[..rem].copy_from_slice(&data[last + BLOCK..]);
+            stolen[rem..].copy_from_slice(&padded[rem..]);
+            data[last + BLOCK..].copy_from_slice(&padded[..rem]);
+
+            self.decrypt_block(&mut stolen, &tweak);
+            data[last..last + BLOCK].copy_from_slice(&stolen);
+        }
+    }
+
+    fn initial_tweak(&self, page_id: PageId) -> [u8; BLOCK] {
+        let mut tweak = [0u8; BLOCK];
+        tweak[0..4].copy_from_slice(&page_id.page_no.to_le_bytes());
+        tweak[4..8].copy_from_slice(&page_id.space_id.to_le_bytes());
+        tweak[8..12].copy_from_slice(&page_id.db_id.to_le_bytes());
+        let block = GenericArray::from_mut_slice(&mut tweak);
+        self.tweak.encrypt_block(block);
+        tweak
+    }
+
+    fn encrypt_block(&self, block: &mut [u8], tweak: &[u8; BLOCK]) {
+        xor(block, tweak);
+        self.data.encrypt_block(GenericArray::from_mut_slice(block));
+        xor(block, tweak);
+    }
+
+    fn decrypt_block(&self, block: &mut [u8], tweak: &[u8; BLOCK]) {
+        xor(block, tweak);
+        self.data.decrypt_block(GenericArray::from_mut_slice(block));
+        xor(block, tweak);
+    }
+}
+
+fn xor(block: &mut [u8], tweak: &[u8; BLOCK]) {
+    for (b, t) in block.iter_mut().zip(tweak) {
+        *b ^= t;
+    }
+}
+
+/// Multiplies the tweak by the primitive element alpha in GF(2^128) (little-endian convention).
+fn mul_alpha(tweak: &mut [u8; BLOCK]) {
+    let mut carry = 0u8;
+    for byte in tweak.iter_mut() {
+        let next_carry = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next_carry;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
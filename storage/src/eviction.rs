@@ -0,0 +1,377 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use crate::traits::PageId;
+
+// -----------------------------------------------------------------------------
+// Buffer Pool Eviction
+//
+// The buffer pool owns frames, pins and the page table; which unpinned frame to reuse
+// is up to its `EvictionPolicy`, told about every load, hit and eviction:
+//
+//   ClockSweep   second chance with usage counts (0..=5). Cheap, close to LRU; the
+//                default.
+//   Lru2         LRU-K with K = 2: evicts the page whose second-to-last access is
+//                oldest, and pages seen only once before any other. A sequential scan
+//                can't flush pages that are actually reused.
+//   TinyLfu      W-TinyLFU: new pages enter a small LRU window; when a frame is needed,
+//                the window's oldest page must be accessed more often than the main
+//                area's oldest page (per an aging count-min sketch) to displace it.
+//                Suits skewed (Zipfian) workloads with a long cold tail.
+//
+// Policies see frame indexes and page ids only, never the frames themselves. Pages read
+// ahead of use are marked `prefetched`; each policy ranks them as its next victims.
+// `benches/eviction_bench.rs` compares their hit ratios on a Zipfian trace.
+// -----------------------------------------------------------------------------
+
+/// Replacement policy of a `BufferPool`.
+pub trait EvictionPolicy {
+    /// `frame` now holds `page_id`, being loaded.
+    fn inserted(&mut self, frame: usize, page_id: PageId);
+
+    /// The page just `inserted` in `frame` was read ahead, not requested: nothing says
+    /// it will be used, so it should be among the first to go until it is `accessed`.
+    fn prefetched(&mut self, frame: usize);
+
+    /// The page in `frame` was requested again.
+    fn accessed(&mut self, frame: usize);
+
+    /// `frame` no longer holds a page: it was evicted, or its load failed.
+    fn removed(&mut self, frame: usize);
+
+    /// Picks the frame to evict to make room for `incoming`, among those `evictable`
+    /// accepts (unpinned, holding a page). `None` if there is none.
+    fn victim(&mut self, incoming: PageId, evictable: &dyn Fn(usize) -> bool) -> Option<usize>;
+
+    /// Up to `count` frames `evictable` accepts, roughly in the order they would be
+    /// evicted, without changing any state. The background writer cleans these first.
+    fn next_victims(&self, count: usize, evictable: &dyn Fn(usize) -> bool) -> Vec<usize>;
+}
+
+/// The built-in policies, selectable in `StorageConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionKind {
+    #[default]
+    ClockSweep,
+    Lru2,
+    TinyLfu,
+}
+
+impl EvictionKind {
+    /// A policy for a pool of `frames` frames.
+    pub fn build(self, frames: usize) -> Box<dyn EvictionPolicy> {
+        match self {
+            EvictionKind::ClockSweep => Box::new(ClockSweep::new(frames)),
+            EvictionKind::Lru2 => Box::new(Lru2::new(frames)),
+            EvictionKind::TinyLfu => Box::new(TinyLfu::new(frames)),
+        }
+    }
+}
+
+const MAX_USAGE: u8 = 5;
+
+/// Clock (second chance) replacement. Every access bumps the frame's usage count, the
+/// hand decrements it as it passes, and the first frame it finds at zero is the victim.
+pub struct ClockSweep {
+    usage: Vec<u8>,
+    hand: usize,
+}
+
+impl ClockSweep {
+    pub fn new(frames: usize) -> Self {
+        Self { usage: vec![0; frames], hand: 0 }
+    }
+}
+
+impl EvictionPolicy for ClockSweep {
+    fn inserted(&mut self, frame: usize, _page_id: PageId) {
+        self.usage[frame] = 1;
+    }
+
+    fn prefetched(&mut self, frame: usize) {
+        self.usage[frame] = 0;
+    }
+
+    fn accessed(&mut self, frame: usize) {
+        self.usage[frame] = (self.usage[frame] + 1).min(MAX_USAGE);
+    }
+
+    fn removed(&mut self, frame: usize) {
+        self.usage[frame] = 0;
+    }
+
+    fn victim(&mut self, _incoming: PageId, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let n = self.usage.len();
+        // Enough passes to drain every usage count: finding nothing means all are pinned.
+        for _ in 0..n * (MAX_USAGE as usize + 1) {
+            let frame = self.hand;
+            self.hand = (frame + 1) % n;
+            if !evictable(frame) {
+                continue;
+            }
+            if self.usage[frame] > 0 {
+                self.usage[frame] -= 1;
+                continue;
+            }
+            return Some(frame);
+        }
+        None
+    }
+
+    fn next_victims(&self, count: usize, evictable: &dyn Fn(usize) -> bool) -> Vec<usize> {
+        // The frames already at zero usage, as the hand will reach them.
+        let n = self.usage.len();
+        (0..n)
+            .map(|i| (self.hand + i) % n)
+            .filter(|&frame| self.usage[frame] == 0 && evictable(frame))
+            .take(count)
+            .collect()
+    }
+}
+
+/// LRU-2. Frames are ordered by their page's second-to-last access (0 if it has only
+/// one), then its last. The last access of up to `frames` evicted pages is remembered,
+/// so a page read back soon after eviction keeps its history.
+pub struct Lru2 {
+    now: u64,
+    // Page, last access and the one before, per occupied frame
+    frames: Vec<Option<(PageId, u64, u64)>>,
+    order: BTreeSet<(u64, u64, usize)>,
+    evicted: HashMap<PageId, u64>,
+    evicted_order: VecDeque<PageId>,
+}
+
+impl Lru2 {
+    pub fn new(frames: usize) -> Self {
+        Self {
+            now: 0,
+            frames: vec![None; frames],
+            order: BTreeSet::new(),
+            evicted: HashMap::new(),
+            evicted_order: VecDeque::new(),
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.now += 1;
+        self.now
+    }
+
+    fn place(&mut self, frame: usize, page_id: PageId, last: u64, previous: u64) {
+        self.frames[frame] = Some((page_id, last, previous));
+        self.order.insert((previous, last, frame));
+    }
+}
+
+impl EvictionPolicy for Lru2 {
+    fn inserted(&mut self, frame: usize, page_id: PageId) {
+        let now = self.tick();
+        let previous = self.evicted.remove(&page_id).unwrap_or(0);
+        self.place(frame, page_id, now, previous);
+    }
+
+    fn prefetched(&mut self, frame: usize) {
+        // As if never accessed: ahead of every page with a real access.
+        let Some((page_id, last, previous)) = self.frames[frame] else {
+            return;
+        };
+        self.order.remove(&(previous, last, frame));
+        self.place(frame, page_id, 0, 0);
+    }
+
+    fn accessed(&mut self, frame: usize) {
+        let Some((page_id, last, previous)) = self.frames[frame] else {
+            return;
+        };
+        self.order.remove(&(previous, last, frame));
+        let now = self.tick();
+        self.place(frame, page_id, now, last);
+    }
+
+    fn removed(&mut self, frame: usize) {
+        let Some((page_id, last, previous)) = self.frames[frame].take() else {
+            return;
+        };
+        self.order.remove(&(previous, last, frame));
+        if self.evicted.insert(page_id, last).is_none() {
+            self.evicted_order.push_back(page_id);
+        }
+        while self.evicted_order.len() > self.frames.len() {
+            let oldest = self.evicted_order.pop_front().unwrap();
+            self.evicted.remove(&oldest);
+        }
+    }
+
+    fn victim(&mut self, _incoming: PageId, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.order.iter().map(|&(_, _, frame)| frame).find(|&frame| evictable(frame))
+    }
+
+    fn next_victims(&self, count: usize, evictable: &dyn Fn(usize) -> bool) -> Vec<usize> {
+        self.order.iter().map(|&(_, _, frame)| frame).filter(|&frame| evictable(frame)).take(count).collect()
+    }
+}
+
+/// W-TinyLFU. A window of about 1% of the frames (at least one) takes new pages in
+/// LRU order; pages leave it into the main area, also LRU. When a frame is needed, the
+/// window's oldest page is admitted to the main area only if the sketch counts more
+/// accesses to it than to the main area's oldest page, which is evicted in its stead.
+/// Prefetched pages go in at the window's old end.
+pub struct TinyLfu {
+    // Access stamps count up from the middle of the range; prefetched pages get stamps
+    // counting down from there, older than any access
+    now: u64,
+    cold: u64,
+    sketch: FrequencySketch,
+    // Page, whether it is in the main area, and its last access, per occupied frame
+    frames: Vec<Option<(PageId, bool, u64)>>,
+    window: BTreeMap<u64, usize>,
+    main: BTreeMap<u64, usize>,
+    window_size: usize,
+}
+
+impl TinyLfu {
+    pub fn new(frames: usize) -> Self {
+        Self {
+            now: 1 << 63,
+            cold: 1 << 63,
+            sketch: FrequencySketch::new(frames),
+            frames: vec![None; frames],
+            window: BTreeMap::new(),
+            main: BTreeMap::new(),
+            window_size: (frames / 100).max(1),
+        }
+    }
+
+    fn place(&mut self, frame: usize, page_id: PageId, in_main: bool) {
+        self.now += 1;
+        self.place_at(frame, page_id, in_main, self.now);
+    }
+
+    fn place_at(&mut self, frame: usize, page_id: PageId, in_main: bool, stamp: u64) {
+        self.frames[frame] = Some((page_id, in_main, stamp));
+        let area = if in_main { &mut self.main } else { &mut self.window };
+        area.insert(stamp, frame);
+    }
+
+    fn unplace(&mut self, frame: usize) -> Option<(PageId, bool)> {
+        let (page_id, in_main, last) = self.frames[frame].take()?;
+        let area = if in_main { &mut self.main } else { &mut self.window };
+        area.remove(&last);
+        Some((page_id, in_main))
+    }
+
+    fn page(&self, frame: usize) -> PageId {
+        self.frames[frame].unwrap().0
+    }
+}
+
+impl EvictionPolicy for TinyLfu {
+    fn inserted(&mut self, frame: usize, page_id: PageId) {
+        self.sketch.increment(page_id);
+        self.place(frame, page_id, false);
+        // Until the pool is full nothing is evicted, and the overflow moves on freely.
+        // Pages keep their stamp, so one never used stays first in line in the main area.
+        while self.window.len() > self.window_size {
+            let (stamp, oldest) = self.window.pop_first().unwrap();
+            let page_id = self.page(oldest);
+            self.place_at(oldest, page_id, true, stamp);
+        }
+    }
+
+    fn prefetched(&mut self, frame: usize) {
+        if let Some((page_id, in_main)) = self.unplace(frame) {
+            self.cold -= 1;
+            self.place_at(frame, page_id, in_main, self.cold);
+        }
+    }
+
+    fn accessed(&mut self, frame: usize) {
+        if let Some((page_id, in_main)) = self.unplace(frame) {
+            self.sketch.increment(page_id);
+            self.place(frame, page_id, in_main);
+        }
+    }
+
+    fn removed(&mut self, frame: usize) {
+        self.unplace(frame);
+    }
+
+    fn victim(&mut self, _incoming: PageId, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let candidate = self.window.values().copied().find(|&frame| evictable(frame));
+        let victim = self.main.values().copied().find(|&frame| evictable(frame));
+        match (candidate, victim) {
+            (Some(candidate), Some(victim)) => {
+                if self.sketch.frequency(self.page(candidate)) > self.sketch.frequency(self.page(victim)) {
+                    let (page_id, _) = self.unplace(candidate).unwrap();
+                    self.place(candidate, page_id, true);
+                    Some(victim)
+                } else {
+                    Some(candidate)
+                }
+            }
+            (candidate, victim) => candidate.or(victim),
+        }
+    }
+
+    fn next_victims(&self, count: usize, evictable: &dyn Fn(usize) -> bool) -> Vec<usize> {
+        // Most duels go to the main area's page; the window's oldest pages go first.
+        self.window.values().chain(self.main.values()).copied().filter(|&frame| evictable(frame)).take(count).collect()
+    }
+}
+
+const SKETCH_ROWS: usize = 4;
+const SKETCH_MAX: u8 = 15;
+// Odd multipliers for multiply-shift hashing, one per row
+const SKETCH_SEEDS: [u64; SKETCH_ROWS] = [0x9E37_79B9_7F4A_7C15, 0xC2B2_AE3D_27D4_EB4F, 0x1656_67B1_9E37_79F9, 0x85EB_CA77_C2B2_AE63];
+
+/// Count-min sketch of recent access counts, with 4-bit saturating counters. After 10
+/// increments per slot every count is halved, so old popularity fades.
+struct FrequencySketch {
+    // One counter per row at each slot
+    counters: Vec<[u8; SKETCH_ROWS]>,
+    shift: u32,
+    additions: usize,
+    reset_at: usize,
+}
+
+impl FrequencySketch {
+    fn new(frames: usize) -> Self {
+        // Several slots per frame: the sketch tracks pages well beyond those cached.
+        let width = (frames * 4).max(16).next_power_of_two();
+        Self {
+            counters: vec![[0; SKETCH_ROWS]; width],
+            shift: 64 - width.trailing_zeros(),
+            additions: 0,
+            reset_at: 10 * width,
+        }
+    }
+
+    fn slots(&self, page_id: PageId) -> [usize; SKETCH_ROWS] {
+        let mut hasher = DefaultHasher::new();
+        page_id.hash(&mut hasher);
+        let hash = hasher.finish();
+        SKETCH_SEEDS.map(|seed| (hash.wrapping_mul(seed) >> self.shift) as usize)
+    }
+
+    fn frequency(&self, page_id: PageId) -> u8 {
+        let slots = self.slots(page_id);
+        (0..SKETCH_ROWS).map(|row| self.counters[slots[row]][row]).min().unwrap()
+    }
+
+    fn increment(&mut self, page_id: PageId) {
+        let slots = self.slots(page_id);
+        for (row, slot) in slots.into_iter().enumerate() {
+            let count = &mut self.counters[slot][row];
+            *count = (*count + 1).min(SKETCH_MAX);
+        }
+        self.additions += 1;
+        if self.additions == self.reset_at {
+            for counts in &mut self.counters {
+                for count in counts {
+                    *count /= 2;
+                }
+            }
+            self.additions /= 2;
+        }
+    }
+}
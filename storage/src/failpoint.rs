@@ -0,0 +1,200 @@
+#[cfg(feature = "failpoints")]
+use std::collections::HashMap;
+#[cfg(feature = "failpoints")]
+use std::sync::{Mutex, OnceLock};
+#[cfg(feature = "failpoints")]
+use std::time::Duration;
+
+#[cfg(feature = "failpoints")]
+use crate::traits::StorageError;
+
+// -----------------------------------------------------------------------------
+// Failpoints
+//
+// Built with the `failpoints` feature, named points on the write paths can be made to
+// fail, stall or take the process down, for crash-consistency tests to stop the engine
+// exactly between two steps that must both happen, or be redone, for the data to
+// survive. Each is a `failpoint::hit!` at the step; without the feature they compile
+// out.
+//
+//   wal-before-fsync             WAL blocks written, not yet fdatasync'd; or, for a
+//                                flush written through O_DSYNC, its block not yet written
+//   wal-after-fsync              WAL durable, flushed LSN not yet advanced
+//   wal-truncate-before-remove   recovery start moved, old segments not yet removed
+//   page-before-write            page image (and its full-page record) logged, page
+//                                not yet written in place
+//   extent-before-header         extent fallocated, segment header not yet claiming it
+//   extent-after-header          header claims the extent, caller not yet told
+//   checkpoint-after-write-back  dirty pages written back, end record not yet logged
+//   checkpoint-before-mark       end record durable, not yet the last checkpoint
+//   checkpoint-before-truncate   checkpoint complete, WAL not yet truncated
+//
+// Points are armed by `configure`, or for a child process from CASCADE_FAILPOINTS,
+// read on first use: `name=action[@nth]` pairs separated by `;`, e.g.
+// `wal-before-fsync=abort@3`. An action fires on the nth hit of its point (the first
+// by default) and on every one after:
+//
+//   off        nothing; the point only counts its hits
+//   error      the step fails with EIO
+//   panic      the thread panics
+//   abort      the process aborts: nothing is unwound or flushed
+//   kill       the process SIGKILLs itself, as a crash test's child should
+//   sleep(ms)  the step waits, blocking its thread, then goes on
+// -----------------------------------------------------------------------------
+
+/// Evaluates failpoint `$name`, returning its error from the enclosing function, or
+/// `$bail(error)` for one not returning a `Result`. Nothing without the `failpoints`
+/// feature.
+#[cfg(feature = "failpoints")]
+macro_rules! hit {
+    ($name:literal) => {
+        crate::failpoint::eval($name)?
+    };
+    ($name:literal, $bail:expr) => {
+        if let Err(e) = crate::failpoint::eval($name) {
+            #[allow(clippy::redundant_closure_call)]
+            return ($bail)(e);
+        }
+    };
+}
+
+#[cfg(not(feature = "failpoints"))]
+macro_rules! hit {
+    ($name:literal) => {};
+    ($name:literal, $bail:expr) => {};
+}
+
+pub(crate) use hit;
+
+/// Every point, in the order of the table above.
+pub const POINTS: &[&str] = &[
+    "wal-before-fsync",
+    "wal-after-fsync",
+    "wal-truncate-before-remove",
+    "page-before-write",
+    "extent-before-header",
+    "extent-after-header",
+    "checkpoint-after-write-back",
+    "checkpoint-before-mark",
+    "checkpoint-before-truncate",
+];
+
+/// What an armed failpoint does.
+#[cfg(feature = "failpoints")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Off,
+    Error,
+    Panic,
+    Abort,
+    Kill,
+    Sleep(Duration),
+}
+
+#[cfg(feature = "failpoints")]
+#[derive(Default)]
+struct Registry {
+    // Action and the hit it fires from, by point
+    armed: HashMap<String, (Action, u64)>,
+    hits: HashMap<String, u64>,
+}
+
+#[cfg(feature = "failpoints")]
+fn registry() -> &'static Mutex<Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut registry = Registry::default();
+        if let Ok(spec) = std::env::var("CASCADE_FAILPOINTS") {
+            for (name, action, nth) in parse(&spec).unwrap_or_else(|e| panic!("CASCADE_FAILPOINTS: {}", e)) {
+                registry.armed.insert(name, (action, nth));
+            }
+        }
+        Mutex::new(registry)
+    })
+}
+
+/// Arms point `name` to take `action` from its `nth` hit on, counting from 1.
+#[cfg(feature = "failpoints")]
+pub fn configure(name: &str, action: Action, nth: u64) {
+    registry().lock().unwrap().armed.insert(name.to_string(), (action, nth.max(1)));
+}
+
+/// Disarms point `name`.
+#[cfg(feature = "failpoints")]
+pub fn remove(name: &str) {
+    registry().lock().unwrap().armed.remove(name);
+}
+
+/// Disarms every point and forgets their hits.
+#[cfg(feature = "failpoints")]
+pub fn clear() {
+    let mut registry = registry().lock().unwrap();
+    registry.armed.clear();
+    registry.hits.clear();
+}
+
+/// Times point `name` was reached, armed or not.
+#[cfg(feature = "failpoints")]
+pub fn hits(name: &str) -> u64 {
+    registry().lock().unwrap().hits.get(name).copied().unwrap_or(0)
+}
+
+/// Counts a hit of point `name` and takes its action, if armed and due.
+#[cfg(feature = "failpoints")]
+pub fn eval(name: &str) -> Result<(), StorageError> {
+    let action = {
+        let mut registry = registry().lock().unwrap();
+        let hits = registry.hits.entry(name.to_string()).or_insert(0);
+        *hits += 1;
+        let hits = *hits;
+        match registry.armed.get(name) {
+            Some(&(action, nth)) if hits >= nth => action,
+            _ => Action::Off,
+        }
+    };
+    match action {
+        Action::Off => Ok(()),
+        Action::Error => Err(StorageError::Io(std::io::Error::from_raw_os_error(libc::EIO))),
+        Action::Panic => panic!("failpoint {} hit", name),
+        Action::Abort => std::process::abort(),
+        Action::Kill => {
+            // SAFETY: signals our own process, which doesn't outlive the call.
+            unsafe { libc::kill(libc::getpid(), libc::SIGKILL) };
+            std::process::abort()
+        }
+        Action::Sleep(delay) => {
+            std::thread::sleep(delay);
+            Ok(())
+        }
+    }
+}
+
+// `name=action[@nth]` pairs separated by `;`
+#[cfg(feature = "failpoints")]
+fn parse(spec: &str) -> Result<Vec<(String, Action, u64)>, String> {
+    let mut points = Vec::new();
+    for pair in spec.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (name, action) = pair.split_once('=').ok_or_else(|| format!("{:?} is not name=action", pair))?;
+        let (action, nth) = match action.split_once('@') {
+            Some((action, nth)) => (action, nth.parse().map_err(|_| format!("bad hit count in {:?}", pair))?),
+            None => (action, 1),
+        };
+        let action = match action {
+            "off" => Action::Off,
+            "error" => Action::Error,
+            "panic" => Action::Panic,
+            "abort" => Action::Abort,
+            "kill" => Action::Kill,
+            _ => {
+                let ms = action
+                    .strip_prefix("sleep(")
+                    .and_then(|rest| rest.strip_suffix(')'))
+                    .and_then(|ms| ms.parse().ok())
+                    .ok_or_else(|| format!("unknown action in {:?}", pair))?;
+                Action::Sleep(Duration::from_millis(ms))
+            }
+        };
+        points.push((name.trim().to_string(), action, u64::max(nth, 1)));
+    }
+    Ok(points)
+}
@@ -0,0 +1,330 @@
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::time::Duration;
+
+use crate::traits::{AlignedBuf, Lsn, PageId, PageState, PageStore, StorageError, WalStore};
+use crate::wal_record::WalRecordType;
+
+// -----------------------------------------------------------------------------
+// Fault Injection
+//
+// `FaultyStore` wraps a `PageStore` and `WalStore` and passes every call through,
+// unless a fault armed with `inject` says otherwise, for tests to drive the error and
+// recovery paths deterministically. A fault names the operations it applies to and
+// fires on the nth of them from when it was armed (the next one by default), then on
+// every one after that until it has fired `times` times. One restricted to a page
+// only counts and fires on operations covering it; a vectored call is one operation.
+//
+//   Eio         the call fails with EIO and never reaches the wrapped store
+//   Corrupt     a byte of each page, or of its page alone, is flipped: as read,
+//               after the wrapped store returns it, or as written, for the write alone
+//   Delay       the call waits this long first, then goes through
+//   ShortRead   a read fills only its first `pages` pages, zeroes the rest and fails
+//               with `ShortRead`, as at the end of a cut segment file
+//
+// Corrupt and ShortRead apply to page reads and writes only; WAL calls can fail or be
+// delayed. Faults never touch what the wrapped store holds except through a corrupt
+// write, so the same store can be reopened without the wrapper to see what a crash
+// left behind.
+// -----------------------------------------------------------------------------
+
+/// Operations a fault can apply to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultOp {
+    /// `read_page` and `read_pages`.
+    Read,
+    /// `write_page` and `write_pages`.
+    Write,
+    Allocate,
+    Free,
+    WalAppend,
+    /// `flush_wal` and `flush_wal_until`.
+    WalFlush,
+    WalTruncate,
+}
+
+/// What a fault does when it fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    Eio,
+    /// XORs `mask` into byte `offset` of each page, or of the fault's page alone.
+    Corrupt { offset: usize, mask: u8 },
+    Delay(Duration),
+    /// Fills the first `pages` pages of the read only.
+    ShortRead { pages: usize },
+}
+
+/// A fault to arm with `FaultyStore::inject`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub op: FaultOp,
+    pub kind: FaultKind,
+    /// Fires on the nth operation matching it, counting from 1.
+    pub nth: u64,
+    /// Fires this many times, from the nth on; `None` for every one.
+    pub times: Option<u64>,
+    /// Only operations covering this page match.
+    pub page_id: Option<PageId>,
+}
+
+impl Fault {
+    /// A fault firing once, on the next `op`.
+    pub fn new(op: FaultOp, kind: FaultKind) -> Self {
+        Self { op, kind, nth: 1, times: Some(1), page_id: None }
+    }
+
+    pub fn with_nth(mut self, nth: u64) -> Self {
+        self.nth = nth.max(1);
+        self
+    }
+
+    pub fn with_times(mut self, times: u64) -> Self {
+        self.times = Some(times);
+        self
+    }
+
+    pub fn always(mut self) -> Self {
+        self.times = None;
+        self
+    }
+
+    pub fn with_page(mut self, page_id: PageId) -> Self {
+        self.page_id = Some(page_id);
+        self
+    }
+}
+
+// An injected fault and how far along it is
+struct Armed {
+    fault: Fault,
+    seen: u64,
+    fired: u64,
+}
+
+/// A store failing on demand, wrapping `S`.
+pub struct FaultyStore<S> {
+    inner: S,
+    armed: RefCell<Vec<Armed>>,
+    injected: Cell<u64>,
+}
+
+impl<S> FaultyStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, armed: RefCell::new(Vec::new()), injected: Cell::new(0) }
+    }
+
+    /// Arms `fault`, counting operations from now.
+    pub fn inject(&self, fault: Fault) {
+        self.armed.borrow_mut().push(Armed { fault, seen: 0, fired: 0 });
+    }
+
+    /// Disarms every fault.
+    pub fn clear(&self) {
+        self.armed.borrow_mut().clear();
+    }
+
+    /// Faults armed and not spent.
+    pub fn armed(&self) -> usize {
+        self.armed.borrow().len()
+    }
+
+    /// Times a fault fired so far.
+    pub fn injected(&self) -> u64 {
+        self.injected.get()
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    // Counts an operation on `pages` pages from `first`, if any, and returns what
+    // fires on it. Spent faults are disarmed.
+    fn fire(&self, op: FaultOp, first: Option<PageId>, pages: usize) -> Vec<Fault> {
+        let covers = |page_id: PageId| {
+            first.is_some_and(|first| {
+                first.db_id == page_id.db_id
+                    && first.space_id == page_id.space_id
+                    && (first.page_no..first.page_no.saturating_add(pages as u32)).contains(&page_id.page_no)
+            })
+        };
+        let mut fired = Vec::new();
+        let mut armed = self.armed.borrow_mut();
+        for armed in armed.iter_mut() {
+            if armed.fault.op != op || armed.fault.page_id.is_some_and(|page_id| !covers(page_id)) {
+                continue;
+            }
+            armed.seen += 1;
+            if armed.seen >= armed.fault.nth {
+                armed.fired += 1;
+                fired.push(armed.fault);
+            }
+        }
+        armed.retain(|armed| armed.fault.times.is_none_or(|times| armed.fired < times));
+        self.injected.set(self.injected.get() + fired.len() as u64);
+        fired
+    }
+
+    // Fires the faults of a call that can only fail or wait
+    async fn fail_or_wait(&self, op: FaultOp) -> Result<(), StorageError> {
+        let faults = self.fire(op, None, 0);
+        delay(&faults).await;
+        match fails(&faults) {
+            true => Err(eio()),
+            false => Ok(()),
+        }
+    }
+}
+
+fn eio() -> StorageError {
+    StorageError::Io(io::Error::from_raw_os_error(libc::EIO))
+}
+
+fn fails(faults: &[Fault]) -> bool {
+    faults.iter().any(|fault| fault.kind == FaultKind::Eio)
+}
+
+async fn delay(faults: &[Fault]) {
+    let delay: Duration = faults
+        .iter()
+        .map(|fault| match fault.kind {
+            FaultKind::Delay(delay) => delay,
+            _ => Duration::ZERO,
+        })
+        .sum();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Flips the bytes of the faults corrupting page `page_id`
+fn corrupt(faults: &[Fault], page_id: PageId, page: &mut [u8]) {
+    for fault in faults.iter().filter(|fault| fault.page_id.is_none_or(|target| target == page_id)) {
+        if let FaultKind::Corrupt { offset, mask } = fault.kind {
+            if let Some(byte) = page.get_mut(offset) {
+                *byte ^= mask;
+            }
+        }
+    }
+}
+
+fn short_read(faults: &[Fault]) -> Option<usize> {
+    faults.iter().find_map(|fault| match fault.kind {
+        FaultKind::ShortRead { pages } => Some(pages),
+        _ => None,
+    })
+}
+
+fn nth_page(first: PageId, index: usize) -> PageId {
+    PageId { page_no: first.page_no + index as u32, ..first }
+}
+
+impl<S: PageStore> PageStore for FaultyStore<S> {
+    async fn read_page(&self, page_id: PageId, buf: AlignedBuf) -> (AlignedBuf, Result<PageState, StorageError>) {
+        let faults = self.fire(FaultOp::Read, Some(page_id), 1);
+        delay(&faults).await;
+        if fails(&faults) {
+            return (buf, Err(eio()));
+        }
+        let (mut buf, res) = self.inner.read_page(page_id, buf).await;
+        if short_read(&faults).is_some() {
+            buf.fill(0);
+            return (buf, Err(StorageError::ShortRead));
+        }
+        corrupt(&faults, page_id, &mut buf);
+        (buf, res)
+    }
+
+    async fn read_pages(&self, start_page_id: PageId, bufs: Vec<AlignedBuf>) -> (Vec<AlignedBuf>, Result<(), StorageError>) {
+        let faults = self.fire(FaultOp::Read, Some(start_page_id), bufs.len());
+        delay(&faults).await;
+        if fails(&faults) {
+            return (bufs, Err(eio()));
+        }
+        let (mut bufs, res) = self.inner.read_pages(start_page_id, bufs).await;
+        for (index, buf) in bufs.iter_mut().enumerate() {
+            corrupt(&faults, nth_page(start_page_id, index), buf);
+        }
+        match short_read(&faults) {
+            Some(pages) if pages < bufs.len() => {
+                for buf in &mut bufs[pages..] {
+                    buf.fill(0);
+                }
+                (bufs, Err(StorageError::ShortRead))
+            }
+            _ => (bufs, res),
+        }
+    }
+
+    async fn write_page(&self, page_id: PageId, mut buf: AlignedBuf) -> (AlignedBuf, Result<(), StorageError>) {
+        let faults = self.fire(FaultOp::Write, Some(page_id), 1);
+        delay(&faults).await;
+        if fails(&faults) {
+            return (buf, Err(eio()));
+        }
+        // Flipped for the write, and back for the caller
+        corrupt(&faults, page_id, &mut buf);
+        let (mut buf, res) = self.inner.write_page(page_id, buf).await;
+        corrupt(&faults, page_id, &mut buf);
+        (buf, res)
+    }
+
+    async fn write_pages(&self, start_page_id: PageId, mut bufs: Vec<AlignedBuf>) -> (Vec<AlignedBuf>, Result<(), StorageError>) {
+        let faults = self.fire(FaultOp::Write, Some(start_page_id), bufs.len());
+        delay(&faults).await;
+        if fails(&faults) {
+            return (bufs, Err(eio()));
+        }
+        for (index, buf) in bufs.iter_mut().enumerate() {
+            corrupt(&faults, nth_page(start_page_id, index), buf);
+        }
+        let (mut bufs, res) = self.inner.write_pages(start_page_id, bufs).await;
+        for (index, buf) in bufs.iter_mut().enumerate() {
+            corrupt(&faults, nth_page(start_page_id, index), buf);
+        }
+        (bufs, res)
+    }
+
+    async fn allocate_extent(&self, db_id: u32, space_id: u32, num_pages: u32) -> Result<u32, StorageError> {
+        self.fail_or_wait(FaultOp::Allocate).await?;
+        self.inner.allocate_extent(db_id, space_id, num_pages).await
+    }
+
+    async fn free_extent(&self, db_id: u32, space_id: u32, start_page: u32, num_pages: u32) -> Result<(), StorageError> {
+        self.fail_or_wait(FaultOp::Free).await?;
+        self.inner.free_extent(db_id, space_id, start_page, num_pages).await
+    }
+}
+
+impl<S: WalStore> WalStore for FaultyStore<S> {
+    async fn append_wal(&self, db_id: u32, record_type: WalRecordType, payload: &[u8]) -> Result<Lsn, StorageError> {
+        self.fail_or_wait(FaultOp::WalAppend).await?;
+        self.inner.append_wal(db_id, record_type, payload).await
+    }
+
+    async fn flush_wal(&self, db_id: u32) -> Result<(), StorageError> {
+        self.fail_or_wait(FaultOp::WalFlush).await?;
+        self.inner.flush_wal(db_id).await
+    }
+
+    async fn flush_wal_until(&self, db_id: u32, lsn: Lsn) -> Result<(), StorageError> {
+        self.fail_or_wait(FaultOp::WalFlush).await?;
+        self.inner.flush_wal_until(db_id, lsn).await
+    }
+
+    fn flushed_lsn(&self, db_id: u32) -> Result<Lsn, StorageError> {
+        self.inner.flushed_lsn(db_id)
+    }
+
+    fn full_page_horizon(&self, db_id: u32) -> Result<Option<Lsn>, StorageError> {
+        self.inner.full_page_horizon(db_id)
+    }
+
+    async fn truncate_wal(&self, db_id: u32, up_to_lsn: Lsn) -> Result<u64, StorageError> {
+        self.fail_or_wait(FaultOp::WalTruncate).await?;
+        self.inner.truncate_wal(db_id, up_to_lsn).await
+    }
+}
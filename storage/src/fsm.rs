@@ -0,0 +1,315 @@
+use crate::buffer_pool::BufferPool;
+use crate::page::{self, PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::traits::{PageId, PageStore, StorageError, WalStore};
+use crate::wal_record::WalRecordType;
+use crate::wal_registry::{PageRecord, WalRecord, WalRegistry};
+
+// -----------------------------------------------------------------------------
+// Free Space Map
+//
+// Roughly how many bytes each data page of a space has free, so an insert can go
+// straight to a page with room (`find_page_with_space`). One byte per data page, in
+// units of `FREE_UNIT` rounded down, is kept in map pages laid out at fixed places
+// among the data pages. The space is cut into groups of `PAGES_PER_GROUP` pages: a
+// map page, the visibility map's page for the group (`vm.rs`), and the data pages
+// both cover:
+//
+//   [24..28)  map_pages     map pages in the space, on the first one only
+//   [32..)    free          one byte per data page covered
+//
+// Pages are handed out in order (`allocate_page`), skipping the groups' map pages on
+// the way and formatting the free space map's, so a space with a free space map holds
+// nothing else. A map page lost to a crash between its allocation and its formatting
+// is formatted by the next update it covers.
+//
+// The map is a hint, updated lazily by its users: an entry can be below the page's
+// free space, which only wastes the difference, or above it, which costs whoever
+// picks the page a look and a correction. Updates are page records, so redo replays
+// the map like anything else:
+//
+//   FSM_INIT    page                        map page formatted, every entry 0
+//   FSM_SET     page | index u16 | free u8  entry of one data page
+//   FSM_PAGES   page | map_pages u32        map pages in use, on the first one
+//
+// (page = db_id u32 | space_id u32 | page_no u32.) Like the heap it serves, a map is
+// used through the pool it is opened on only (see `heap.rs`).
+// -----------------------------------------------------------------------------
+
+/// Page type of free space map pages.
+pub const PAGE_TYPE_FSM: u16 = 5;
+
+/// Bytes per unit of an entry.
+pub const FREE_UNIT: usize = 32;
+
+const MAP_PAGES_OFFSET: usize = 24;
+const ENTRIES_OFFSET: usize = 32;
+
+/// Data pages covered by one map page.
+pub const PAGES_PER_MAP_PAGE: u32 = (PAGE_SIZE - ENTRIES_OFFSET) as u32;
+
+/// Pages of a group: the free space map's page, the visibility map's, and the data
+/// pages they cover.
+pub const PAGES_PER_GROUP: u32 = PAGES_PER_MAP_PAGE + 2;
+
+/// Whether `page_no` is where a map page goes, the free space map's or the visibility
+/// map's.
+pub fn is_map_page(page_no: u32) -> bool {
+    page_no % PAGES_PER_GROUP < 2
+}
+
+/// First page of the group holding `page_no`: its free space map page.
+pub fn group_start(page_no: u32) -> u32 {
+    page_no / PAGES_PER_GROUP * PAGES_PER_GROUP
+}
+
+/// Registers the map's record types, so recovery can replay them.
+pub fn register(registry: &mut WalRegistry) {
+    registry.register_page::<FsmInit>("fsm");
+    registry.register_page::<FsmSet>("fsm");
+    registry.register_page::<FsmPages>("fsm");
+}
+
+/// The free space maps of database `db_id`'s spaces, over `pool`.
+pub struct FreeSpaceMap<'a, S: PageStore + WalStore> {
+    pool: &'a BufferPool<S>,
+    db_id: u32,
+}
+
+impl<'a, S: PageStore + WalStore> FreeSpaceMap<'a, S> {
+    pub fn new(pool: &'a BufferPool<S>, db_id: u32) -> Self {
+        Self { pool, db_id }
+    }
+
+    /// A data page of `space_id` recorded with at least `needed_bytes` free, the
+    /// lowest-numbered one. `None` if there is none.
+    pub async fn find_page_with_space(&self, space_id: u32, needed_bytes: usize) -> Result<Option<u32>, StorageError> {
+        let needed = needed_bytes.div_ceil(FREE_UNIT).max(1);
+        if needed > u8::MAX as usize {
+            return Ok(None);
+        }
+        let map_pages = {
+            let first = self.pool.get_page(self.page(space_id, 0)).await?;
+            if page::page_type(&first) != PAGE_TYPE_FSM {
+                return Ok(None);
+            }
+            get_u32(&first, MAP_PAGES_OFFSET)
+        };
+        for group in 0..map_pages {
+            let map = self.pool.get_page(self.page(space_id, group * PAGES_PER_GROUP)).await?;
+            if page::page_type(&map) != PAGE_TYPE_FSM {
+                continue;
+            }
+            if let Some(index) = map[ENTRIES_OFFSET..].iter().position(|&free| free as usize >= needed) {
+                return Ok(Some(group * PAGES_PER_GROUP + 2 + index as u32));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Free bytes recorded for data page `page_no` of `space_id`, in whole units.
+    pub async fn recorded(&self, space_id: u32, page_no: u32) -> Result<usize, StorageError> {
+        let (map_no, index) = locate(page_no);
+        let map = self.pool.get_page(self.page(space_id, map_no)).await?;
+        if page::page_type(&map) != PAGE_TYPE_FSM {
+            return Ok(0);
+        }
+        Ok(map[ENTRIES_OFFSET + index] as usize * FREE_UNIT)
+    }
+
+    /// Records `free_bytes` free in data page `page_no` of `space_id`.
+    pub async fn record(&self, space_id: u32, page_no: u32, free_bytes: usize) -> Result<(), StorageError> {
+        let (map_no, index) = locate(page_no);
+        let free = (free_bytes / FREE_UNIT).min(u8::MAX as usize) as u8;
+        let mut map = self.pool.get_page_mut(self.page(space_id, map_no)).await?;
+        if page::page_type(&map) != PAGE_TYPE_FSM {
+            drop(map);
+            self.format(space_id, map_no).await?;
+            map = self.pool.get_page_mut(self.page(space_id, map_no)).await?;
+        }
+        if map[ENTRIES_OFFSET + index] != free {
+            let page_id = map.page_id();
+            map.apply(&FsmSet { page_id, index: index as u16, free }).await?;
+        }
+        Ok(())
+    }
+
+    /// Allocates the next data page of `space_id`, formatting the map pages passed.
+    pub async fn allocate_page(&self, space_id: u32) -> Result<u32, StorageError> {
+        loop {
+            let page_no = self.pool.store().allocate_extent(self.db_id, space_id, 1).await?;
+            if !is_map_page(page_no) {
+                return Ok(page_no);
+            }
+            self.format(space_id, group_start(page_no)).await?;
+        }
+    }
+
+    // Formats map page `map_no` and the first one, if not yet, and counts it in.
+    async fn format(&self, space_id: u32, map_no: u32) -> Result<(), StorageError> {
+        for page_no in [map_no, 0] {
+            let mut map = self.pool.get_page_mut(self.page(space_id, page_no)).await?;
+            if page::page_type(&map) != PAGE_TYPE_FSM {
+                let page_id = map.page_id();
+                map.apply(&FsmInit { page_id }).await?;
+            }
+        }
+        let map_pages = map_no / PAGES_PER_GROUP + 1;
+        let mut first = self.pool.get_page_mut(self.page(space_id, 0)).await?;
+        if get_u32(&first, MAP_PAGES_OFFSET) < map_pages {
+            let page_id = first.page_id();
+            first.apply(&FsmPages { page_id, map_pages }).await?;
+        }
+        Ok(())
+    }
+
+    fn page(&self, space_id: u32, page_no: u32) -> PageId {
+        PageId {
+            db_id: self.db_id,
+            space_id,
+            page_no,
+        }
+    }
+}
+
+// Map page covering data page `page_no`, and its entry there
+fn locate(page_no: u32) -> (u32, usize) {
+    let map_no = group_start(page_no);
+    (map_no, (page_no - map_no - 2) as usize)
+}
+
+fn get_u32(page: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(page[at..at + 4].try_into().unwrap())
+}
+
+// -----------------------------------------------------------------------------
+// WAL records
+// -----------------------------------------------------------------------------
+
+const PAGE_ID_SIZE: usize = 12;
+
+fn encode_page_id(out: &mut Vec<u8>, page_id: PageId) {
+    for part in [page_id.db_id, page_id.space_id, page_id.page_no] {
+        out.extend_from_slice(&part.to_le_bytes());
+    }
+}
+
+fn decode_page_id(payload: &[u8]) -> Option<PageId> {
+    let field = |at: usize| Some(u32::from_le_bytes(payload.get(at..at + 4)?.try_into().unwrap()));
+    Some(PageId {
+        db_id: field(0)?,
+        space_id: field(4)?,
+        page_no: field(8)?,
+    })
+}
+
+/// A map page formatted, every entry 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsmInit {
+    pub page_id: PageId,
+}
+
+impl WalRecord for FsmInit {
+    const TYPE: WalRecordType = WalRecordType::FSM_INIT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE {
+            return None;
+        }
+        Some(Self { page_id: decode_page_id(payload)? })
+    }
+}
+
+impl PageRecord for FsmInit {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[PAGE_HEADER_SIZE..].fill(0);
+        page::set_page_type(page, PAGE_TYPE_FSM);
+    }
+}
+
+/// The entry at `index` of a map page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsmSet {
+    pub page_id: PageId,
+    pub index: u16,
+    /// Free bytes in `FREE_UNIT`s.
+    pub free: u8,
+}
+
+impl WalRecord for FsmSet {
+    const TYPE: WalRecordType = WalRecordType::FSM_SET;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.index.to_le_bytes());
+        out.push(self.free);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 3 {
+            return None;
+        }
+        let index = u16::from_le_bytes(payload[PAGE_ID_SIZE..PAGE_ID_SIZE + 2].try_into().unwrap());
+        if index as u32 >= PAGES_PER_MAP_PAGE {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            index,
+            free: payload[PAGE_ID_SIZE + 2],
+        })
+    }
+}
+
+impl PageRecord for FsmSet {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[ENTRIES_OFFSET + self.index as usize] = self.free;
+    }
+}
+
+/// The number of map pages in use, kept on the first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsmPages {
+    pub page_id: PageId,
+    pub map_pages: u32,
+}
+
+impl WalRecord for FsmPages {
+    const TYPE: WalRecordType = WalRecordType::FSM_PAGES;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.map_pages.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 4 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            map_pages: get_u32(payload, PAGE_ID_SIZE),
+        })
+    }
+}
+
+impl PageRecord for FsmPages {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[MAP_PAGES_OFFSET..MAP_PAGES_OFFSET + 4].copy_from_slice(&self.map_pages.to_le_bytes());
+    }
+}
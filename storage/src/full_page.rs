@@ -0,0 +1,99 @@
+use crate::page;
+use crate::traits::{Lsn, PageId};
+use crate::wal_record::WalRecordType;
+use crate::wal_registry::{PageRecord, WalRecord};
+
+// -----------------------------------------------------------------------------
+// Full-Page Writes
+//
+// An 8KB page write is not atomic: a crash mid-write can leave a page that is part old
+// and part new (torn), which fails its checksum and can't be read back. Redo only
+// reapplies changes to a page it can read, so the log alone can't repair it.
+//
+// With `StorageConfig::full_page_writes`, the first change to a page since the WAL's
+// full-page horizon logs the whole page as it was before that change, as a
+// FULL_PAGE_IMAGE record ahead of the change's own record:
+//
+//   FULL_PAGE_IMAGE   db_id u32 | space_id u32 | page_no u32 | image [0 or 8192]
+//
+// A never-written page is logged with no image. The horizon is the begin record of
+// the last checkpoint started, or where the log resumed if none has been since: any
+// write-back that may be in flight at a crash is of a page changed after it, so redo
+// meets an image of that page before its changes. A checkpoint leaving pages dirty
+// with changes older than its begin record, where redo will start, logs their images
+// before its end record for the same reason. Redo skips the records of a page it
+// can't read until it reaches an image of the page, restores the page from it, and
+// replays the rest of the page's changes over it (see `wal_redo.rs`). It fails only
+// if no image comes.
+//
+// The write guard takes the image when the page is first modified (`DerefMut`), and
+// `PageWriteGuard::log` appends it with the change's record. Each checkpoint so costs
+// one extra page of WAL per page changed after it, the price of recovering from torn
+// writes without a doublewrite file.
+// -----------------------------------------------------------------------------
+
+const HEADER_SIZE: usize = 12;
+
+/// A page as it was before its first change since the full-page horizon.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FullPageImage {
+    pub page_id: PageId,
+    /// The whole page, or empty for a page never written.
+    pub image: Vec<u8>,
+}
+
+impl FullPageImage {
+    /// The image of `page` to log, fresh pages as no image.
+    pub fn of(page_id: PageId, page: &[u8]) -> Self {
+        let image = match page::is_fresh(page) {
+            true => Vec::new(),
+            false => page.to_vec(),
+        };
+        Self { page_id, image }
+    }
+}
+
+impl WalRecord for FullPageImage {
+    const TYPE: WalRecordType = WalRecordType::FULL_PAGE_IMAGE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.reserve(HEADER_SIZE + self.image.len());
+        for part in [self.page_id.db_id, self.page_id.space_id, self.page_id.page_no] {
+            out.extend_from_slice(&part.to_le_bytes());
+        }
+        out.extend_from_slice(&self.image);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let field = |at: usize| Some(u32::from_le_bytes(payload.get(at..at + 4)?.try_into().unwrap()));
+        let page_id = PageId {
+            db_id: field(0)?,
+            space_id: field(4)?,
+            page_no: field(8)?,
+        };
+        let image = &payload[HEADER_SIZE..];
+        if !image.is_empty() && image.len() != page::PAGE_SIZE {
+            return None;
+        }
+        Some(Self { page_id, image: image.to_vec() })
+    }
+}
+
+impl PageRecord for FullPageImage {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        match self.image.is_empty() {
+            true => page.fill(0),
+            false => page.copy_from_slice(&self.image),
+        }
+    }
+}
+
+/// Whether the first change to `page` must log its image first, given the WAL's
+/// full-page horizon (`None` with full-page writes off).
+pub fn needs_image(page: &[u8], horizon: Option<Lsn>) -> bool {
+    horizon.is_some_and(|horizon| page::is_fresh(page) || page::page_lsn(page) < horizon)
+}
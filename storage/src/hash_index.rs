@@ -0,0 +1,766 @@
+use crate::buffer_pool::{BufferPool, PageWriteGuard};
+use crate::page::{self, PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::segment::EXTENT_PAGES;
+use crate::traits::{PageId, PageStore, StorageError, WalStore};
+use crate::wal_record::WalRecordType;
+use crate::wal_registry::{PageRecord, WalRecord, WalRegistry};
+
+// -----------------------------------------------------------------------------
+// Hash Index
+//
+// An index of u64 keys to u64 values like the B-tree's, for point lookups only: a key
+// is found in its bucket, one page unless the bucket overflowed, with no descent.
+// Buckets grow by linear hashing. With `level` L and split pointer `next`, there are
+// 2^L + next buckets, and a key hashing to h is in bucket h mod 2^(L+1) if that
+// exists, else in h mod 2^L. Whenever an insert has to chain an overflow page to a
+// full bucket, bucket `next` is split: its entries go to it or to the new bucket
+// next + 2^L, by h mod 2^(L+1), and `next` moves on, to 0 and L + 1 once every bucket
+// of the level is split. Splits stop at MAX_LEVEL.
+//
+// An index has a space of its own. Page 0 is its meta page:
+//
+//   [24..28)  level
+//   [28..32)  next         bucket to split next
+//   [32..36)  free         first free overflow page (NO_PAGE: none), each free one
+//                          pointing to the next
+//   [36..40)  cursor       next overflow page never used, up to
+//   [40..44)  end          the end of the extent it is in
+//   [48..)    groups       first page u32 of each group of buckets
+//
+// Bucket pages are allocated a group at a time: group 0 is bucket 0, and group g the
+// 2^(g-1) buckets from 2^(g-1), allocated when the first of them is split off, so a
+// bucket's page is found from the meta page alone. Bucket and overflow pages are laid
+// out alike, their entries in no order:
+//
+//   [24..26)  count
+//   [28..32)  next         next overflow page of the bucket (NO_PAGE: none)
+//   [32..36)  bucket       the bucket the page belongs to (NO_BUCKET: free)
+//   [40..)    entries      key u64 | value u64
+//
+// Every change is a page record (page = db_id u32 | space_id u32 | page_no u32):
+//
+//   HASH_META    page | level u32 | next u32 | free u32 | cursor u32 | end u32 |
+//                groups                                    meta page written anew
+//   HASH_PAGE    page | bucket u32 | next u32 | count u16 | entries   page written anew
+//   HASH_INSERT  page | key u64 | value u64                entry added at the end
+//   HASH_DELETE  page | index u16                          entry replaced by the last
+//   HASH_UPDATE  page | index u16 | value u64              entry's new value
+//   HASH_LINK    page | next u32                           overflow page chained
+//
+// A split logs the meta page with the pages it allocates, then the new bucket's pages,
+// then the meta page with `next` moved on, then the old bucket's pages, and last the
+// pages it no longer needs on the free list. A crash before the second meta record
+// leaves the old bucket whole; one after it, entries in the old bucket that belong to
+// the new one, which lookups never reach there and the next split of the bucket drops.
+// Pages allocated or freed by a split or an insert cut short are leaked.
+//
+// Every operation holds the meta page's latch throughout, shared, and a bucket's
+// pages are changed only under its first page's write latch. An insert that needs an
+// overflow page starts over with the meta page write latched, which shuts everyone
+// else out for the overflow page and the split. Like the B-tree's, the changes are not
+// transactional, and an index is used through the pool it is opened on only (see
+// `btree.rs`).
+// -----------------------------------------------------------------------------
+
+/// Page types of hash index pages.
+pub const PAGE_TYPE_HASH_META: u16 = 16;
+pub const PAGE_TYPE_HASH_BUCKET: u16 = 17;
+
+/// Page of an index's space holding its meta page.
+pub const META_PAGE: u32 = 0;
+
+/// No next page.
+pub const NO_PAGE: u32 = u32::MAX;
+
+/// Bucket of a free overflow page.
+pub const NO_BUCKET: u32 = u32::MAX;
+
+/// Level past which buckets are no longer split: the biggest group is then one
+/// segment's worth of pages.
+pub const MAX_LEVEL: u32 = 18;
+
+const GROUPS: usize = MAX_LEVEL as usize + 1;
+
+// Overflow pages allocated at a time
+const OVERFLOW_EXTENT_PAGES: u32 = 64;
+
+const LEVEL_OFFSET: usize = 24;
+const NEXT_BUCKET_OFFSET: usize = 28;
+const FREE_OFFSET: usize = 32;
+const CURSOR_OFFSET: usize = 36;
+const END_OFFSET: usize = 40;
+const GROUPS_OFFSET: usize = 48;
+
+const COUNT_OFFSET: usize = 24;
+const NEXT_PAGE_OFFSET: usize = 28;
+const BUCKET_OFFSET: usize = 32;
+const ENTRIES_OFFSET: usize = 40;
+const ENTRY_SIZE: usize = 16;
+
+/// Entries a bucket or overflow page holds.
+pub const BUCKET_CAPACITY: usize = (PAGE_SIZE - ENTRIES_OFFSET) / ENTRY_SIZE;
+
+/// Registers the hash index's record types, so recovery can replay them.
+pub fn register(registry: &mut WalRegistry) {
+    registry.register_page::<HashMeta>("hash");
+    registry.register_page::<HashPage>("hash");
+    registry.register_page::<HashInsert>("hash");
+    registry.register_page::<HashDelete>("hash");
+    registry.register_page::<HashUpdate>("hash");
+    registry.register_page::<HashLink>("hash");
+}
+
+// What an insert found in its bucket
+enum Placed {
+    Done(Option<u64>),
+    // Every page full; the last one
+    Full(u32),
+}
+
+/// The hash index in space `space_id` of database `db_id`, over `pool`.
+pub struct HashIndex<'a, S: PageStore + WalStore> {
+    pool: &'a BufferPool<S>,
+    db_id: u32,
+    space_id: u32,
+}
+
+impl<'a, S: PageStore + WalStore> HashIndex<'a, S> {
+    /// Opens the index in space `space_id` of database `db_id`, creating it with one
+    /// empty bucket if the space is new.
+    pub async fn open(pool: &'a BufferPool<S>, db_id: u32, space_id: u32) -> Result<Self, StorageError> {
+        let index = Self { pool, db_id, space_id };
+        let page_id = index.page(META_PAGE);
+        let mut meta = pool.get_page_mut(page_id).await?;
+        if page::page_type(&meta) != PAGE_TYPE_HASH_META {
+            if !page::is_fresh(&meta) {
+                return Err(StorageError::NotAHashIndex(page_id));
+            }
+            // A create cut short may have allocated these already, and they are then
+            // leaked.
+            pool.store().allocate_extent(db_id, space_id, EXTENT_PAGES).await?;
+            let bucket = pool.store().allocate_extent(db_id, space_id, EXTENT_PAGES).await?;
+            index.write_page(bucket, 0, NO_PAGE, &[]).await?;
+            let mut groups = [NO_PAGE; GROUPS];
+            groups[0] = bucket;
+            meta.apply(&HashMeta { page_id, level: 0, next: 0, free: NO_PAGE, cursor: 0, end: 0, groups }).await?;
+        }
+        Ok(index)
+    }
+
+    pub fn db_id(&self) -> u32 {
+        self.db_id
+    }
+
+    pub fn space_id(&self) -> u32 {
+        self.space_id
+    }
+
+    /// Buckets the index has.
+    pub async fn buckets(&self) -> Result<u64, StorageError> {
+        let meta = self.pool.get_page(self.page(META_PAGE)).await?;
+        Ok(HashMeta::decode_page(self.page(META_PAGE), &meta).buckets())
+    }
+
+    /// The value of `key`.
+    pub async fn search(&self, key: u64) -> Result<Option<u64>, StorageError> {
+        let meta_page = self.pool.get_page(self.page(META_PAGE)).await?;
+        let meta = HashMeta::decode_page(self.page(META_PAGE), &meta_page);
+        let mut node = self.pool.get_page(self.page(meta.bucket_page(meta.bucket(key)))).await?;
+        loop {
+            if let Some(index) = find(&node, key) {
+                return Ok(Some(entry(&node, index).1));
+            }
+            match next_page(&node) {
+                NO_PAGE => return Ok(None),
+                next => node = node.couple(self.page(next)).await?,
+            }
+        }
+    }
+
+    /// Sets `key` to `value`. Returns the value it replaced.
+    pub async fn insert(&self, key: u64, value: u64) -> Result<Option<u64>, StorageError> {
+        {
+            let meta_page = self.pool.get_page(self.page(META_PAGE)).await?;
+            let meta = HashMeta::decode_page(self.page(META_PAGE), &meta_page);
+            if let Placed::Done(replaced) = self.place(&meta, key, value).await? {
+                return Ok(replaced);
+            }
+        }
+
+        let mut meta_page = self.pool.get_page_mut(self.page(META_PAGE)).await?;
+        let mut meta = HashMeta::decode_page(self.page(META_PAGE), &meta_page);
+        // Room may have been made meanwhile.
+        let last = match self.place(&meta, key, value).await? {
+            Placed::Done(replaced) => return Ok(replaced),
+            Placed::Full(last) => last,
+        };
+        let overflow = self.allocate_overflow(&mut meta).await?;
+        meta_page.apply(&meta).await?;
+        self.write_page(overflow, meta.bucket(key), NO_PAGE, &[(key, value)]).await?;
+        let mut last = self.pool.get_page_mut(self.page(last)).await?;
+        let page_id = last.page_id();
+        last.apply(&HashLink { page_id, next: overflow }).await?;
+        drop(last);
+        if meta.level < MAX_LEVEL {
+            self.split(&mut meta_page, &mut meta).await?;
+        }
+        Ok(None)
+    }
+
+    /// Removes `key`. Returns its value.
+    pub async fn delete(&self, key: u64) -> Result<Option<u64>, StorageError> {
+        let meta_page = self.pool.get_page(self.page(META_PAGE)).await?;
+        let meta = HashMeta::decode_page(self.page(META_PAGE), &meta_page);
+        let mut first = self.pool.get_page_mut(self.page(meta.bucket_page(meta.bucket(key)))).await?;
+        if let Some(index) = find(&first, key) {
+            return remove(&mut first, index).await.map(Some);
+        }
+        let mut next = next_page(&first);
+        while next != NO_PAGE {
+            let mut node = self.pool.get_page_mut(self.page(next)).await?;
+            if let Some(index) = find(&node, key) {
+                return remove(&mut node, index).await.map(Some);
+            }
+            next = next_page(&node);
+        }
+        Ok(None)
+    }
+
+    // Sets `key` in its bucket, if it is there or a page of the bucket has room. The
+    // caller holds the meta page latched.
+    async fn place(&self, meta: &HashMeta, key: u64, value: u64) -> Result<Placed, StorageError> {
+        let first_no = meta.bucket_page(meta.bucket(key));
+        let mut first = self.pool.get_page_mut(self.page(first_no)).await?;
+        if let Some(index) = find(&first, key) {
+            return update(&mut first, index, value).await.map(|old| Placed::Done(Some(old)));
+        }
+        let mut room = (count(&first) < BUCKET_CAPACITY).then_some(first_no);
+        let (mut last, mut next) = (first_no, next_page(&first));
+        while next != NO_PAGE {
+            let mut node = self.pool.get_page_mut(self.page(next)).await?;
+            if let Some(index) = find(&node, key) {
+                return update(&mut node, index, value).await.map(|old| Placed::Done(Some(old)));
+            }
+            if room.is_none() && count(&node) < BUCKET_CAPACITY {
+                room = Some(next);
+            }
+            last = next;
+            next = next_page(&node);
+        }
+        let Some(room) = room else {
+            return Ok(Placed::Full(last));
+        };
+        let insert = HashInsert { page_id: self.page(room), key, value };
+        if room == first_no {
+            first.apply(&insert).await?;
+        } else {
+            self.pool.get_page_mut(insert.page_id).await?.apply(&insert).await?;
+        }
+        Ok(Placed::Done(None))
+    }
+
+    // Splits bucket `meta.next`, with the meta page write latched
+    async fn split(&self, meta_page: &mut PageWriteGuard<'_, S>, meta: &mut HashMeta) -> Result<(), StorageError> {
+        let half = 1u32 << meta.level;
+        let old = meta.next;
+        let new = old + half;
+        if old == 0 {
+            let group = meta.level as usize + 1;
+            meta.groups[group] = self.pool.store().allocate_extent(self.db_id, self.space_id, half.next_multiple_of(EXTENT_PAGES)).await?;
+        }
+
+        // Entries left behind by a split cut short are dropped here.
+        let mut chain = vec![meta.bucket_page(old)];
+        let (mut stay, mut go) = (Vec::new(), Vec::new());
+        loop {
+            let node = self.pool.get_page(self.page(*chain.last().unwrap())).await?;
+            for index in 0..count(&node) {
+                let (key, value) = entry(&node, index);
+                if meta.bucket(key) != old {
+                    continue;
+                }
+                if hash(key) & (2 * half as u64 - 1) == new as u64 {
+                    go.push((key, value));
+                } else {
+                    stay.push((key, value));
+                }
+            }
+            match next_page(&node) {
+                NO_PAGE => break,
+                next => chain.push(next),
+            }
+        }
+
+        let mut new_chain = vec![meta.bucket_page(new)];
+        for _ in 1..go.len().div_ceil(BUCKET_CAPACITY) {
+            new_chain.push(self.allocate_overflow(meta).await?);
+        }
+        meta_page.apply(&*meta).await?;
+        self.write_chain(new, &new_chain, &go).await?;
+
+        meta.next += 1;
+        if meta.next == half {
+            meta.level += 1;
+            meta.next = 0;
+        }
+        meta_page.apply(&*meta).await?;
+
+        let kept = stay.len().div_ceil(BUCKET_CAPACITY).max(1);
+        self.write_chain(old, &chain[..kept], &stay).await?;
+        if kept < chain.len() {
+            for &page_no in &chain[kept..] {
+                self.write_page(page_no, NO_BUCKET, meta.free, &[]).await?;
+                meta.free = page_no;
+            }
+            meta_page.apply(&*meta).await?;
+        }
+        Ok(())
+    }
+
+    // Writes `entries` of `bucket` over the pages of `chain`, linked in order
+    async fn write_chain(&self, bucket: u32, chain: &[u32], entries: &[(u64, u64)]) -> Result<(), StorageError> {
+        let mut chunks = entries.chunks(BUCKET_CAPACITY);
+        for (i, &page_no) in chain.iter().enumerate() {
+            let next = chain.get(i + 1).copied().unwrap_or(NO_PAGE);
+            self.write_page(page_no, bucket, next, chunks.next().unwrap_or_default()).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_page(&self, page_no: u32, bucket: u32, next: u32, entries: &[(u64, u64)]) -> Result<(), StorageError> {
+        let page_id = self.page(page_no);
+        let mut page = self.pool.get_page_overwrite(page_id).await?;
+        page.apply(&HashPage { page_id, bucket, next, entries: entries.to_vec() }).await?;
+        Ok(())
+    }
+
+    // A page for an overflow page, off the free list or else never used; `meta` is to
+    // be logged before the page is
+    async fn allocate_overflow(&self, meta: &mut HashMeta) -> Result<u32, StorageError> {
+        if meta.free != NO_PAGE {
+            let page_no = meta.free;
+            meta.free = next_page(&self.pool.get_page(self.page(page_no)).await?);
+            return Ok(page_no);
+        }
+        if meta.cursor == meta.end {
+            meta.cursor = self.pool.store().allocate_extent(self.db_id, self.space_id, OVERFLOW_EXTENT_PAGES).await?;
+            meta.end = meta.cursor + OVERFLOW_EXTENT_PAGES;
+        }
+        meta.cursor += 1;
+        Ok(meta.cursor - 1)
+    }
+
+    fn page(&self, page_no: u32) -> PageId {
+        PageId {
+            db_id: self.db_id,
+            space_id: self.space_id,
+            page_no,
+        }
+    }
+}
+
+/// The bucket hash of `key`.
+pub fn hash(key: u64) -> u64 {
+    // splitmix64's finalizer
+    let mut h = key.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^ (h >> 31)
+}
+
+fn count(page: &[u8]) -> usize {
+    get_u16(page, COUNT_OFFSET) as usize
+}
+
+fn next_page(page: &[u8]) -> u32 {
+    get_u32(page, NEXT_PAGE_OFFSET)
+}
+
+fn entry(page: &[u8], index: usize) -> (u64, u64) {
+    let at = ENTRIES_OFFSET + index * ENTRY_SIZE;
+    (get_u64(page, at), get_u64(page, at + 8))
+}
+
+fn find(page: &[u8], key: u64) -> Option<usize> {
+    (0..count(page)).find(|&index| entry(page, index).0 == key)
+}
+
+async fn update<S: PageStore + WalStore>(page: &mut PageWriteGuard<'_, S>, index: usize, value: u64) -> Result<u64, StorageError> {
+    let old = entry(page, index).1;
+    let page_id = page.page_id();
+    page.apply(&HashUpdate { page_id, index: index as u16, value }).await?;
+    Ok(old)
+}
+
+async fn remove<S: PageStore + WalStore>(page: &mut PageWriteGuard<'_, S>, index: usize) -> Result<u64, StorageError> {
+    let value = entry(page, index).1;
+    let page_id = page.page_id();
+    page.apply(&HashDelete { page_id, index: index as u16 }).await?;
+    Ok(value)
+}
+
+fn set_entry(page: &mut [u8], index: usize, key: u64, value: u64) {
+    let at = ENTRIES_OFFSET + index * ENTRY_SIZE;
+    page[at..at + 8].copy_from_slice(&key.to_le_bytes());
+    page[at + 8..at + 16].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_count(page: &mut [u8], count: usize) {
+    page[COUNT_OFFSET..COUNT_OFFSET + 2].copy_from_slice(&(count as u16).to_le_bytes());
+}
+
+fn get_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn get_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn get_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+// -----------------------------------------------------------------------------
+// WAL records
+// -----------------------------------------------------------------------------
+
+const PAGE_ID_SIZE: usize = 12;
+
+fn encode_page_id(out: &mut Vec<u8>, page_id: PageId) {
+    for part in [page_id.db_id, page_id.space_id, page_id.page_no] {
+        out.extend_from_slice(&part.to_le_bytes());
+    }
+}
+
+fn decode_page_id(payload: &[u8]) -> Option<PageId> {
+    let field = |at: usize| Some(get_u32(payload.get(at..at + 4)?, 0));
+    Some(PageId {
+        db_id: field(0)?,
+        space_id: field(4)?,
+        page_no: field(8)?,
+    })
+}
+
+/// An index's meta page, written anew.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashMeta {
+    pub page_id: PageId,
+    pub level: u32,
+    /// Bucket to split next.
+    pub next: u32,
+    /// First free overflow page.
+    pub free: u32,
+    /// Next overflow page never used, and the end of its extent.
+    pub cursor: u32,
+    pub end: u32,
+    /// First page of each group of buckets.
+    pub groups: [u32; GROUPS],
+}
+
+impl HashMeta {
+    fn decode_page(page_id: PageId, page: &[u8]) -> Self {
+        Self {
+            page_id,
+            level: get_u32(page, LEVEL_OFFSET),
+            next: get_u32(page, NEXT_BUCKET_OFFSET),
+            free: get_u32(page, FREE_OFFSET),
+            cursor: get_u32(page, CURSOR_OFFSET),
+            end: get_u32(page, END_OFFSET),
+            groups: std::array::from_fn(|group| get_u32(page, GROUPS_OFFSET + group * 4)),
+        }
+    }
+
+    /// Buckets the index has.
+    pub fn buckets(&self) -> u64 {
+        (1u64 << self.level) + self.next as u64
+    }
+
+    /// The bucket holding `key`.
+    pub fn bucket(&self, key: u64) -> u32 {
+        let h = hash(key);
+        let bucket = h & ((2u64 << self.level) - 1);
+        if bucket < self.buckets() {
+            bucket as u32
+        } else {
+            (h & ((1u64 << self.level) - 1)) as u32
+        }
+    }
+
+    /// The first page of `bucket`.
+    pub fn bucket_page(&self, bucket: u32) -> u32 {
+        let group = (u32::BITS - bucket.leading_zeros()) as usize;
+        let first = if group == 0 { 0 } else { 1 << (group - 1) };
+        self.groups[group] + (bucket - first)
+    }
+}
+
+impl WalRecord for HashMeta {
+    const TYPE: WalRecordType = WalRecordType::HASH_META;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        for field in [self.level, self.next, self.free, self.cursor, self.end] {
+            out.extend_from_slice(&field.to_le_bytes());
+        }
+        for group in self.groups {
+            out.extend_from_slice(&group.to_le_bytes());
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 20 + GROUPS * 4 {
+            return None;
+        }
+        let field = |i: usize| get_u32(payload, PAGE_ID_SIZE + i * 4);
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            level: field(0),
+            next: field(1),
+            free: field(2),
+            cursor: field(3),
+            end: field(4),
+            groups: std::array::from_fn(|group| field(5 + group)),
+        })
+    }
+}
+
+impl PageRecord for HashMeta {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[PAGE_HEADER_SIZE..].fill(0);
+        page::set_page_type(page, PAGE_TYPE_HASH_META);
+        let fields = [
+            (LEVEL_OFFSET, self.level),
+            (NEXT_BUCKET_OFFSET, self.next),
+            (FREE_OFFSET, self.free),
+            (CURSOR_OFFSET, self.cursor),
+            (END_OFFSET, self.end),
+        ];
+        for (at, field) in fields {
+            page[at..at + 4].copy_from_slice(&field.to_le_bytes());
+        }
+        for (group, first) in self.groups.iter().enumerate() {
+            let at = GROUPS_OFFSET + group * 4;
+            page[at..at + 4].copy_from_slice(&first.to_le_bytes());
+        }
+    }
+}
+
+/// A bucket or overflow page, written anew.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashPage {
+    pub page_id: PageId,
+    pub bucket: u32,
+    pub next: u32,
+    pub entries: Vec<(u64, u64)>,
+}
+
+impl WalRecord for HashPage {
+    const TYPE: WalRecordType = WalRecordType::HASH_PAGE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.bucket.to_le_bytes());
+        out.extend_from_slice(&self.next.to_le_bytes());
+        out.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        for &(key, value) in &self.entries {
+            out.extend_from_slice(&key.to_le_bytes());
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let fixed = PAGE_ID_SIZE + 10;
+        if payload.len() < fixed {
+            return None;
+        }
+        let count = get_u16(payload, fixed - 2) as usize;
+        if count > BUCKET_CAPACITY || payload.len() != fixed + count * ENTRY_SIZE {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            bucket: get_u32(payload, PAGE_ID_SIZE),
+            next: get_u32(payload, PAGE_ID_SIZE + 4),
+            entries: (0..count).map(|i| (get_u64(payload, fixed + i * ENTRY_SIZE), get_u64(payload, fixed + i * ENTRY_SIZE + 8))).collect(),
+        })
+    }
+}
+
+impl PageRecord for HashPage {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[PAGE_HEADER_SIZE..].fill(0);
+        page::set_page_type(page, PAGE_TYPE_HASH_BUCKET);
+        page[NEXT_PAGE_OFFSET..NEXT_PAGE_OFFSET + 4].copy_from_slice(&self.next.to_le_bytes());
+        page[BUCKET_OFFSET..BUCKET_OFFSET + 4].copy_from_slice(&self.bucket.to_le_bytes());
+        set_count(page, self.entries.len());
+        for (index, &(key, value)) in self.entries.iter().enumerate() {
+            set_entry(page, index, key, value);
+        }
+    }
+}
+
+/// An entry added at the end of a bucket or overflow page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashInsert {
+    pub page_id: PageId,
+    pub key: u64,
+    pub value: u64,
+}
+
+impl WalRecord for HashInsert {
+    const TYPE: WalRecordType = WalRecordType::HASH_INSERT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.key.to_le_bytes());
+        out.extend_from_slice(&self.value.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 16 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            key: get_u64(payload, PAGE_ID_SIZE),
+            value: get_u64(payload, PAGE_ID_SIZE + 8),
+        })
+    }
+}
+
+impl PageRecord for HashInsert {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let count = count(page);
+        set_entry(page, count, self.key, self.value);
+        set_count(page, count + 1);
+    }
+}
+
+/// An entry removed from a bucket or overflow page, the last one taking its place.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashDelete {
+    pub page_id: PageId,
+    pub index: u16,
+}
+
+impl WalRecord for HashDelete {
+    const TYPE: WalRecordType = WalRecordType::HASH_DELETE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.index.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 2 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            index: get_u16(payload, PAGE_ID_SIZE),
+        })
+    }
+}
+
+impl PageRecord for HashDelete {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let last = count(page) - 1;
+        let (key, value) = entry(page, last);
+        set_entry(page, self.index as usize, key, value);
+        set_entry(page, last, 0, 0);
+        set_count(page, last);
+    }
+}
+
+/// An entry's new value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashUpdate {
+    pub page_id: PageId,
+    pub index: u16,
+    pub value: u64,
+}
+
+impl WalRecord for HashUpdate {
+    const TYPE: WalRecordType = WalRecordType::HASH_UPDATE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.index.to_le_bytes());
+        out.extend_from_slice(&self.value.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 10 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            index: get_u16(payload, PAGE_ID_SIZE),
+            value: get_u64(payload, PAGE_ID_SIZE + 2),
+        })
+    }
+}
+
+impl PageRecord for HashUpdate {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let key = entry(page, self.index as usize).0;
+        set_entry(page, self.index as usize, key, self.value);
+    }
+}
+
+/// An overflow page chained after a bucket's last page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashLink {
+    pub page_id: PageId,
+    pub next: u32,
+}
+
+impl WalRecord for HashLink {
+    const TYPE: WalRecordType = WalRecordType::HASH_LINK;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.next.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 4 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            next: get_u32(payload, PAGE_ID_SIZE),
+        })
+    }
+}
+
+impl PageRecord for HashLink {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[NEXT_PAGE_OFFSET..NEXT_PAGE_OFFSET + 4].copy_from_slice(&self.next.to_le_bytes());
+    }
+}
@@ -0,0 +1,1146 @@
+use std::cell::Cell;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::bloom::{self, BloomFilter, BloomStats};
+use crate::buffer_pool::{BufferPool, PageWriteGuard};
+use crate::fsm::FreeSpaceMap;
+use crate::index::{HeapEvent, IndexRegistry};
+use crate::mvcc::{self, Snapshot, TransactionStatus, TupleHeader, XidStatus};
+use crate::page::{self, PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::record::{self, ColumnType, OverflowPointer, Value};
+use crate::traits::{PageId, PageStore, StorageError, WalStore};
+use crate::vm::{self, VisibilityMap};
+use crate::wal_record::WalRecordType;
+use crate::wal_registry::{PageRecord, WalRecord, WalRegistry};
+
+// -----------------------------------------------------------------------------
+// Heap Files
+//
+// Variable-length tuples in the pages of one space, addressed by `TupleId` (page and
+// slot). A tuple is just bytes to the heap; rows are laid out in them as `record.rs`
+// says. Each page is a classic slotted page: a line pointer array growing up after
+// the header, and the tuples growing down from the end of the page, with the free
+// space in between:
+//
+//   [24..26)  slot_count   line pointers in the array
+//   [26..28)  free_end     start of the tuple area
+//   [28..30)  flags        FLAG_ALL_VISIBLE
+//   [32..)    line pointers, offset u16 | len u16 each; offset 0 is a free slot
+//   ...       free space
+//   [free_end..8192)       tuples
+//
+// A tuple keeps its slot for life, so a `TupleId` stays valid while the tuple moves
+// about the page. Deleting one frees its slot for the next insert into the page, and
+// leaves its bytes as a hole. Once the free space in between is too small for a tuple
+// but the holes would make room, the insert first compacts the page: live tuples are
+// packed against the end of the page in slot order, and trailing free slots dropped.
+//
+// Every change is a page record, made and logged by its write guard
+// (`PageWriteGuard::apply`), so redo replays heap pages like any other. Placement is
+// decided by the page alone, so a record needs no offsets:
+//
+//   HEAP_INIT      page                         page formatted empty
+//   HEAP_INSERT    page | slot u16 | tuple      tuple placed just below free_end
+//   HEAP_DELETE    page | slot u16              slot freed
+//   HEAP_COMPACT   page                         tuples packed, trailing free slots dropped
+//   HEAP_VISIBLE   page                         FLAG_ALL_VISIBLE set
+//   HEAP_OVERFLOW  page | next u32 | bytes      page formatted as an overflow page
+//   HEAP_SET_XMAX  page | slot u16 | xmax u64 | cmax u32   version's deleter set
+//
+// A page whose tuples are all visible can be marked so in the space's visibility map
+// (`set_all_visible`, see `vm.rs`), and carries FLAG_ALL_VISIBLE while it is, so
+// inserts and deletes only look the map up for the pages marked. They clear the map's
+// bits before changing such a page, and the flag along with the change.
+//
+// (page = db_id u32 | space_id u32 | page_no u32.)
+//
+// A varlena value of a row over `OVERFLOW_THRESHOLD` bytes is kept out of the row,
+// in a chain of overflow pages of the space, and the row points to its first page
+// (`insert_row`, see `record.rs`). Each page holds the next piece of the value:
+//
+//   [24..28)  next          next page of the chain (NO_PAGE: none)
+//   [28..30)  len           bytes of the value in this page
+//   [32..)    bytes
+//
+// A chain is written last page first, each formatted whole by one record, so it is
+// complete by the time anything points to it. Deleting the row (`delete_row`) turns
+// the chain's pages back into empty heap pages, recorded as such in the free space
+// map, where the next chain or insert finds them. A crash partway through writing or
+// freeing a chain leaks the pages no longer reachable.
+//
+// Tuples can be row versions under MVCC, starting with a header naming the
+// transactions that inserted and deleted them (see `mvcc.rs`). A version is inserted
+// like any tuple, deleted by setting its xmax in place, and removed only once dead,
+// by vacuum (`prune_page`). A heap given an `IndexRegistry` tells the indexes of its
+// space of each version inserted, updated or deleted (see `index.rs`).
+//
+// A range of pages no longer inserted into, a full segment say, can have a Bloom
+// filter over keys taken from its tuples (`build_bloom`), for lookups by key to skip
+// the range when it can't hold one (`find_in`, see `bloom.rs`). Filters are the
+// caller's to keep, written with `bloom::write_filter` to a space of their own: the
+// heap's maps' pages sit among its pages, leaving no room for runs of filter pages.
+//
+// An insert goes to the page the last one went to, or else to one the space's free
+// space map has room in (`fsm.rs`), or else to a new page. The map is kept lazily:
+// a new page is recorded as it starts, a page as it turns out too full for an insert,
+// and a page whose deletes freed `FSM_DELETE_SLACK` bytes more than it is recorded
+// with. The maps' pages sit among the heap's, so the space holds the heap only. Like
+// the B-tree's, the changes are not transactional, and a heap file is used through
+// the pool it is opened on only (see `btree.rs`).
+// -----------------------------------------------------------------------------
+
+/// Page type of heap pages.
+pub const PAGE_TYPE_HEAP: u16 = 4;
+
+const SLOT_COUNT_OFFSET: usize = 24;
+const FREE_END_OFFSET: usize = 26;
+const PAGE_FLAGS_OFFSET: usize = 28;
+const SLOTS_OFFSET: usize = 32;
+const SLOT_SIZE: usize = 4;
+
+// The page's bits in the visibility map are set.
+const FLAG_ALL_VISIBLE: u16 = 1;
+
+/// Largest tuple a page holds.
+pub const MAX_TUPLE_SIZE: usize = PAGE_SIZE - SLOTS_OFFSET - SLOT_SIZE;
+
+/// Page type of overflow pages.
+pub const PAGE_TYPE_OVERFLOW: u16 = 8;
+
+const OVERFLOW_NEXT_OFFSET: usize = 24;
+const OVERFLOW_LEN_OFFSET: usize = 28;
+const OVERFLOW_DATA_OFFSET: usize = 32;
+
+/// Bytes of a value an overflow page holds.
+pub const OVERFLOW_PAGE_DATA: usize = PAGE_SIZE - OVERFLOW_DATA_OFFSET;
+
+/// Varlena values longer than this are kept in overflow pages by `insert_row`.
+pub const OVERFLOW_THRESHOLD: usize = MAX_TUPLE_SIZE / 4;
+
+/// No next overflow page.
+pub const NO_PAGE: u32 = u32::MAX;
+
+// Free space of an empty heap page
+const EMPTY_PAGE_FREE: usize = PAGE_SIZE - SLOTS_OFFSET;
+
+/// Bytes a page's free space must grow by through deletes before the free space map
+/// hears of it.
+pub const FSM_DELETE_SLACK: usize = PAGE_SIZE / 8;
+
+/// Where a tuple lives: its page in the heap file's space, and its slot there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TupleId {
+    pub page_no: u32,
+    pub slot: u16,
+}
+
+impl TupleId {
+    /// The id packed into a u64, e.g. for a B-tree value.
+    pub fn to_u64(self) -> u64 {
+        (self.page_no as u64) << 16 | self.slot as u64
+    }
+
+    pub fn from_u64(packed: u64) -> Self {
+        Self {
+            page_no: (packed >> 16) as u32,
+            slot: packed as u16,
+        }
+    }
+}
+
+/// What `HeapFile::delete_version` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteOutcome {
+    /// Marked deleted, now or earlier by the same transaction.
+    Deleted,
+    /// No version there.
+    Missing,
+    /// Already deleted by this other transaction, committed or still running: a write
+    /// conflict for the caller to resolve.
+    Conflict(u64),
+}
+
+/// What `HeapFile::update_version` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    /// Old version marked deleted, and the new one stored here.
+    Updated(TupleId),
+    /// No version there.
+    Missing,
+    /// Already deleted by this other transaction, as with `DeleteOutcome::Conflict`.
+    Conflict(u64),
+}
+
+/// Registers the heap's record types, so recovery can replay them.
+pub fn register(registry: &mut WalRegistry) {
+    registry.register_page::<HeapInit>("heap");
+    registry.register_page::<HeapInsert>("heap");
+    registry.register_page::<HeapDelete>("heap");
+    registry.register_page::<HeapCompact>("heap");
+    registry.register_page::<HeapVisible>("heap");
+    registry.register_page::<HeapOverflow>("heap");
+    registry.register_page::<HeapSetXmax>("heap");
+}
+
+/// The heap file in space `space_id` of database `db_id`, over `pool`.
+pub struct HeapFile<'a, S: PageStore + WalStore> {
+    pool: &'a BufferPool<S>,
+    db_id: u32,
+    space_id: u32,
+    fsm: FreeSpaceMap<'a, S>,
+    vm: VisibilityMap<'a, S>,
+    // Page the next insert tries first
+    target: Cell<Option<u32>>,
+    indexes: Option<Rc<IndexRegistry<'a>>>,
+}
+
+impl<'a, S: PageStore + WalStore> HeapFile<'a, S> {
+    pub fn new(pool: &'a BufferPool<S>, db_id: u32, space_id: u32) -> Self {
+        Self {
+            pool,
+            db_id,
+            space_id,
+            fsm: FreeSpaceMap::new(pool, db_id),
+            vm: VisibilityMap::new(pool, db_id),
+            target: Cell::new(None),
+            indexes: None,
+        }
+    }
+
+    /// Tells the indexes `indexes` has for this space of the versions changed.
+    pub fn with_indexes(mut self, indexes: Rc<IndexRegistry<'a>>) -> Self {
+        self.indexes = Some(indexes);
+        self
+    }
+
+    /// Stores `tuple`, and returns where.
+    pub async fn insert_tuple(&self, tuple: &[u8]) -> Result<TupleId, StorageError> {
+        if tuple.len() > MAX_TUPLE_SIZE {
+            return Err(StorageError::TupleTooLarge(tuple.len()));
+        }
+        if let Some(page_no) = self.target.get() {
+            if let Some(tid) = self.insert_at(page_no, tuple).await? {
+                return Ok(tid);
+            }
+        }
+        // Each page found too full is recorded as such, so this ends.
+        while let Some(page_no) = self.fsm.find_page_with_space(self.space_id, tuple.len() + SLOT_SIZE).await? {
+            if let Some(tid) = self.insert_at(page_no, tuple).await? {
+                self.target.set(Some(page_no));
+                return Ok(tid);
+            }
+        }
+
+        let page_no = self.fsm.allocate_page(self.space_id).await?;
+        let page_id = self.page(page_no);
+        let mut page = self.pool.get_page_overwrite(page_id).await?;
+        page.apply(&HeapInit { page_id }).await?;
+        let slot = insert_into(&mut page, tuple).await?.expect("a tuple of at most MAX_TUPLE_SIZE fits an empty page");
+        self.fsm.record(self.space_id, page_no, free_space(&page)).await?;
+        self.target.set(Some(page_no));
+        Ok(TupleId { page_no, slot })
+    }
+
+    /// The tuple at `tid`, if there is one.
+    pub async fn get_tuple(&self, tid: TupleId) -> Result<Option<Vec<u8>>, StorageError> {
+        let page = self.pool.get_page(self.page(tid.page_no)).await?;
+        Ok(live_tuple(&page, tid.slot).map(|range| page[range].to_vec()))
+    }
+
+    /// Deletes the tuple at `tid`. Returns whether there was one.
+    pub async fn delete(&self, tid: TupleId) -> Result<bool, StorageError> {
+        Ok(self.take_tuple(tid).await?.is_some())
+    }
+
+    /// Stores the row `values` of `columns`, with its varlena values over
+    /// `OVERFLOW_THRESHOLD` bytes in overflow pages, and returns where.
+    pub async fn insert_row(&self, columns: &[ColumnType], values: &[Value<'_>]) -> Result<TupleId, StorageError> {
+        let mut row = values.to_vec();
+        let mut chains = Vec::new();
+        for value in &mut row {
+            let bytes = match *value {
+                Value::Bytes(bytes) => bytes,
+                Value::Text(text) => text.as_bytes(),
+                _ => continue,
+            };
+            if bytes.len() > OVERFLOW_THRESHOLD {
+                let pointer = self.write_overflow(bytes).await?;
+                chains.push(pointer);
+                *value = Value::Overflow(pointer);
+            }
+        }
+        let result = self.insert_tuple(&record::encode_row(columns, &row)).await;
+        if result.is_err() {
+            for pointer in chains {
+                self.free_overflow(pointer).await?;
+            }
+        }
+        result
+    }
+
+    /// Deletes the row at `tid`, a row of `columns`, and frees its overflow pages.
+    /// Returns whether there was one. A tuple that isn't a row of `columns` is deleted
+    /// all the same, and nothing else freed.
+    pub async fn delete_row(&self, tid: TupleId, columns: &[ColumnType]) -> Result<bool, StorageError> {
+        let Some(tuple) = self.take_tuple(tid).await? else {
+            return Ok(false);
+        };
+        for value in record::decode_row(columns, &tuple).unwrap_or_default() {
+            if let Value::Overflow(pointer) = value {
+                self.free_overflow(pointer).await?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Stores `value` in a new chain of overflow pages, and returns where.
+    pub async fn write_overflow(&self, value: &[u8]) -> Result<OverflowPointer, StorageError> {
+        let len = u32::try_from(value.len()).map_err(|_| StorageError::TupleTooLarge(value.len()))?;
+        let mut next = NO_PAGE;
+        for piece in value.chunks(OVERFLOW_PAGE_DATA).rev() {
+            let mut page = self.take_empty_page().await?;
+            let page_id = page.page_id();
+            page.apply(&HeapOverflow { page_id, next, bytes: piece.to_vec() }).await?;
+            next = page_id.page_no;
+        }
+        Ok(OverflowPointer {
+            space_id: self.space_id,
+            first_page: next,
+            len,
+        })
+    }
+
+    /// The value kept in the overflow pages at `pointer`.
+    pub async fn read_overflow(&self, pointer: OverflowPointer) -> Result<Vec<u8>, StorageError> {
+        let mut value = Vec::with_capacity(pointer.len as usize);
+        let mut page_no = pointer.first_page;
+        while value.len() < pointer.len as usize {
+            let page_id = PageId { space_id: pointer.space_id, ..self.page(page_no) };
+            let page = self.pool.get_page(page_id).await?;
+            let Some(bytes) = overflow_bytes(&page) else {
+                return Err(StorageError::BrokenOverflow(page_id));
+            };
+            value.extend_from_slice(bytes);
+            page_no = get_u32(&page, OVERFLOW_NEXT_OFFSET);
+            if page_no == NO_PAGE {
+                break;
+            }
+        }
+        if value.len() != pointer.len as usize {
+            return Err(StorageError::BrokenOverflow(PageId { space_id: pointer.space_id, ..self.page(pointer.first_page) }));
+        }
+        Ok(value)
+    }
+
+    /// Frees the overflow pages at `pointer`, for the free space map to hand out again.
+    /// The value must no longer be pointed to.
+    pub async fn free_overflow(&self, pointer: OverflowPointer) -> Result<(), StorageError> {
+        let mut page_no = pointer.first_page;
+        while page_no != NO_PAGE {
+            let page_id = PageId { space_id: pointer.space_id, ..self.page(page_no) };
+            let mut page = self.pool.get_page_mut(page_id).await?;
+            if page::page_type(&page) != PAGE_TYPE_OVERFLOW {
+                return Err(StorageError::BrokenOverflow(page_id));
+            }
+            let next = get_u32(&page, OVERFLOW_NEXT_OFFSET);
+            page.apply(&HeapInit { page_id }).await?;
+            self.fsm.record(pointer.space_id, page_no, EMPTY_PAGE_FREE).await?;
+            page_no = next;
+        }
+        Ok(())
+    }
+
+    // Removes the tuple at `tid`, and returns it if there was one.
+    async fn take_tuple(&self, tid: TupleId) -> Result<Option<Vec<u8>>, StorageError> {
+        let mut page = self.pool.get_page_mut(self.page(tid.page_no)).await?;
+        if live_tuple(&page, tid.slot).is_none() {
+            return Ok(None);
+        }
+        Ok(self.remove_tuples(&mut page, &[tid.slot]).await?.pop())
+    }
+
+    // Removes the tuples in `slots`, all live, and returns them.
+    async fn remove_tuples(&self, page: &mut PageWriteGuard<'_, S>, slots: &[u16]) -> Result<Vec<Vec<u8>>, StorageError> {
+        let page_id = page.page_id();
+        if all_visible(page) {
+            self.vm.clear(self.space_id, page_id.page_no).await?;
+        }
+        let mut tuples = Vec::with_capacity(slots.len());
+        for &slot in slots {
+            tuples.push(page[live_tuple(page, slot).expect("removing a live tuple")].to_vec());
+            page.apply(&HeapDelete { page_id, slot }).await?;
+        }
+        let free = free_space(page);
+        if free >= self.fsm.recorded(self.space_id, page_id.page_no).await? + FSM_DELETE_SLACK {
+            self.fsm.record(self.space_id, page_id.page_no, free).await?;
+        }
+        Ok(tuples)
+    }
+
+    // An empty heap page the free space map knows of, or else a new page, write
+    // latched and taken out of the map.
+    async fn take_empty_page(&self) -> Result<PageWriteGuard<'a, S>, StorageError> {
+        // Each page found not empty is recorded as such, so this ends.
+        while let Some(page_no) = self.fsm.find_page_with_space(self.space_id, EMPTY_PAGE_FREE).await? {
+            let page = self.pool.get_page_mut(self.page(page_no)).await?;
+            let free = free_space(&page);
+            self.fsm.record(self.space_id, page_no, if free == EMPTY_PAGE_FREE { 0 } else { free }).await?;
+            if free == EMPTY_PAGE_FREE {
+                if all_visible(&page) {
+                    self.vm.clear(self.space_id, page_no).await?;
+                }
+                return Ok(page);
+            }
+        }
+        let page_no = self.fsm.allocate_page(self.space_id).await?;
+        self.pool.get_page_overwrite(self.page(page_no)).await
+    }
+
+    /// Stores `row` as a new version, inserted by transaction `xid` in its command `cid`,
+    /// and returns where.
+    pub async fn insert_version(&self, xid: u64, cid: u32, row: &[u8]) -> Result<TupleId, StorageError> {
+        let tid = self.insert_tuple(&version(xid, cid, row)).await?;
+        self.notify(&HeapEvent::Insert { xid, tid, row }).await?;
+        Ok(tid)
+    }
+
+    /// The row of the version at `tid`, if there is one and `snapshot` sees it.
+    pub async fn get_version(&self, tid: TupleId, snapshot: &Snapshot, status: &impl TransactionStatus) -> Result<Option<Vec<u8>>, StorageError> {
+        let page = self.pool.get_page(self.page(tid.page_no)).await?;
+        let Some(range) = live_tuple(&page, tid.slot) else {
+            return Ok(None);
+        };
+        let tuple = &page[range];
+        match TupleHeader::decode(tuple) {
+            Some(header) if snapshot.is_visible(&header, status) => Ok(Some(tuple[mvcc::HEADER_SIZE..].to_vec())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Marks the version at `tid` deleted by transaction `xid` in its command `cid`.
+    /// The version stays for the snapshots that still see it, until vacuum.
+    pub async fn delete_version(&self, tid: TupleId, xid: u64, cid: u32, status: &impl TransactionStatus) -> Result<DeleteOutcome, StorageError> {
+        let (outcome, row) = self.set_xmax(tid, xid, cid, status).await?;
+        if let Some(row) = row {
+            self.notify(&HeapEvent::Delete { xid, tid, row: &row }).await?;
+        }
+        Ok(outcome)
+    }
+
+    /// Replaces the version at `tid` with one of `row`, by transaction `xid` in its
+    /// command `cid`: the old version is marked deleted, and the new one stored apart.
+    pub async fn update_version(&self, tid: TupleId, xid: u64, cid: u32, row: &[u8], status: &impl TransactionStatus) -> Result<UpdateOutcome, StorageError> {
+        let old_row = match self.set_xmax(tid, xid, cid, status).await? {
+            (DeleteOutcome::Deleted, Some(old_row)) => old_row,
+            // Deleted earlier by the same transaction: nothing left to update
+            (DeleteOutcome::Deleted, None) | (DeleteOutcome::Missing, _) => return Ok(UpdateOutcome::Missing),
+            (DeleteOutcome::Conflict(xmax), _) => return Ok(UpdateOutcome::Conflict(xmax)),
+        };
+        let new_tid = self.insert_tuple(&version(xid, cid, row)).await?;
+        self.notify(&HeapEvent::Update { xid, old_tid: tid, old_row: &old_row, new_tid, new_row: row }).await?;
+        Ok(UpdateOutcome::Updated(new_tid))
+    }
+
+    // Sets the xmax of the version at `tid`, and returns its row if this deleted it
+    async fn set_xmax(&self, tid: TupleId, xid: u64, cid: u32, status: &impl TransactionStatus) -> Result<(DeleteOutcome, Option<Vec<u8>>), StorageError> {
+        let mut page = self.pool.get_page_mut(self.page(tid.page_no)).await?;
+        let Some(range) = live_tuple(&page, tid.slot) else {
+            return Ok((DeleteOutcome::Missing, None));
+        };
+        let Some(header) = TupleHeader::decode(&page[range.clone()]) else {
+            return Ok((DeleteOutcome::Missing, None));
+        };
+        match header.xmax {
+            mvcc::INVALID_XID => {}
+            xmax if xmax == xid => return Ok((DeleteOutcome::Deleted, None)),
+            xmax if status.status(xmax) != XidStatus::Aborted => return Ok((DeleteOutcome::Conflict(xmax), None)),
+            _ => {}
+        }
+        let row = page[range.start + mvcc::HEADER_SIZE..range.end].to_vec();
+        if all_visible(&page) {
+            self.vm.clear(self.space_id, tid.page_no).await?;
+        }
+        let page_id = page.page_id();
+        page.apply(&HeapSetXmax { page_id, slot: tid.slot, xmax: xid, cmax: cid }).await?;
+        Ok((DeleteOutcome::Deleted, Some(row)))
+    }
+
+    async fn notify(&self, event: &HeapEvent<'_>) -> Result<(), StorageError> {
+        match &self.indexes {
+            Some(indexes) => indexes.notify(self.db_id, self.space_id, event).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the versions of page `page_no` no snapshot with a `xmin` of at least
+    /// `horizon` can see, and returns their rows, for the caller to free what they
+    /// point to (`free_overflow`). For vacuum.
+    pub async fn prune_page(&self, page_no: u32, horizon: u64, status: &impl TransactionStatus) -> Result<Vec<Vec<u8>>, StorageError> {
+        let mut page = self.pool.get_page_mut(self.page(page_no)).await?;
+        let dead: Vec<u16> = (0..slot_count(&page) as u16)
+            .filter(|&slot| {
+                let header = live_tuple(&page, slot).and_then(|range| TupleHeader::decode(&page[range]));
+                header.is_some_and(|header| header.is_dead(horizon, status))
+            })
+            .collect();
+        if dead.is_empty() {
+            return Ok(Vec::new());
+        }
+        let tuples = self.remove_tuples(&mut page, &dead).await?;
+        Ok(tuples.into_iter().map(|tuple| tuple[mvcc::HEADER_SIZE..].to_vec()).collect())
+    }
+
+    /// Marks page `page_no` all-visible, and all-frozen too if `frozen`, in the
+    /// visibility map. The caller vouches for every tuple on it. Returns false if it
+    /// is not a heap page.
+    pub async fn set_all_visible(&self, page_no: u32, frozen: bool) -> Result<bool, StorageError> {
+        // Held until the map has the bits, so no change slips in between.
+        let mut page = self.pool.get_page_mut(self.page(page_no)).await?;
+        if page::page_type(&page) != PAGE_TYPE_HEAP {
+            return Ok(false);
+        }
+        if !all_visible(&page) {
+            let page_id = page.page_id();
+            page.apply(&HeapVisible { page_id }).await?;
+        }
+        let bits = if frozen { vm::ALL_VISIBLE | vm::ALL_FROZEN } else { vm::ALL_VISIBLE };
+        self.vm.set(self.space_id, page_no, bits).await?;
+        Ok(true)
+    }
+
+    /// A Bloom filter over the keys `key` takes from the tuples of pages `pages`, a
+    /// segment of the heap say, `bits_per_key` bits each. Tuples inserted there later
+    /// are not in it: build it for ranges no longer inserted into.
+    pub async fn build_bloom(&self, pages: Range<u32>, bits_per_key: u32, key: impl Fn(&[u8]) -> Option<Vec<u8>>) -> Result<BloomFilter, StorageError> {
+        let mut hashes = Vec::new();
+        for page_no in pages {
+            let page = self.pool.get_page(self.page(page_no)).await?;
+            for slot in 0..slot_count(&page) as u16 {
+                if let Some(key) = live_tuple(&page, slot).and_then(|range| key(&page[range])) {
+                    hashes.push(bloom::key_hash(&key));
+                }
+            }
+        }
+        Ok(BloomFilter::build(&hashes, bits_per_key))
+    }
+
+    /// The tuples of pages `pages` `key_of` takes `key` from, reading them only if
+    /// `filter`, built over them by `build_bloom`, may hold it. Counted in `stats`.
+    pub async fn find_in(
+        &self,
+        pages: Range<u32>,
+        filter: &BloomFilter,
+        stats: &BloomStats,
+        key: &[u8],
+        key_of: impl Fn(&[u8]) -> Option<Vec<u8>>,
+    ) -> Result<Vec<TupleId>, StorageError> {
+        if !stats.check(filter, bloom::key_hash(key)) {
+            return Ok(Vec::new());
+        }
+        let mut found = Vec::new();
+        for page_no in pages {
+            let page = self.pool.get_page(self.page(page_no)).await?;
+            for slot in 0..slot_count(&page) as u16 {
+                let tuple_key = live_tuple(&page, slot).and_then(|range| key_of(&page[range]));
+                if tuple_key.as_deref() == Some(key) {
+                    found.push(TupleId { page_no, slot });
+                }
+            }
+        }
+        if found.is_empty() {
+            stats.false_positive();
+        }
+        Ok(found)
+    }
+
+    // Adds `tuple` to page `page_no`, or records the page's free space in the map if
+    // it has too little.
+    async fn insert_at(&self, page_no: u32, tuple: &[u8]) -> Result<Option<TupleId>, StorageError> {
+        let mut page = self.pool.get_page_mut(self.page(page_no)).await?;
+        if all_visible(&page) && free_space(&page) >= tuple.len() + SLOT_SIZE {
+            self.vm.clear(self.space_id, page_no).await?;
+        }
+        match insert_into(&mut page, tuple).await? {
+            Some(slot) => Ok(Some(TupleId { page_no, slot })),
+            None => {
+                self.fsm.record(self.space_id, page_no, free_space(&page)).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn page(&self, page_no: u32) -> PageId {
+        PageId {
+            db_id: self.db_id,
+            space_id: self.space_id,
+            page_no,
+        }
+    }
+}
+
+// Adds `tuple` to the page, compacting it first if that makes room. Returns its slot,
+// or `None` if the page is too full.
+async fn insert_into<S: PageStore + WalStore>(page: &mut PageWriteGuard<'_, S>, tuple: &[u8]) -> Result<Option<u16>, StorageError> {
+    let page_id = page.page_id();
+    // Room for a new line pointer is asked for even if a free one is reused.
+    let needed = tuple.len() + SLOT_SIZE;
+    if free_space(page) < needed {
+        return Ok(None);
+    }
+    if free_end(page) - free_start(page) < needed {
+        page.apply(&HeapCompact { page_id }).await?;
+    }
+    let slot = (0..slot_count(page)).find(|&slot| pointer(page, slot).0 == 0).unwrap_or(slot_count(page)) as u16;
+    page.apply(&HeapInsert { page_id, slot, tuple: tuple.to_vec() }).await?;
+    Ok(Some(slot))
+}
+
+// -----------------------------------------------------------------------------
+// Page layout
+// -----------------------------------------------------------------------------
+
+fn get_u16(page: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(page[at..at + 2].try_into().unwrap())
+}
+
+fn put_u16(page: &mut [u8], at: usize, value: usize) {
+    page[at..at + 2].copy_from_slice(&(value as u16).to_le_bytes());
+}
+
+fn slot_count(page: &[u8]) -> usize {
+    get_u16(page, SLOT_COUNT_OFFSET) as usize
+}
+
+fn free_start(page: &[u8]) -> usize {
+    SLOTS_OFFSET + slot_count(page) * SLOT_SIZE
+}
+
+fn free_end(page: &[u8]) -> usize {
+    get_u16(page, FREE_END_OFFSET) as usize
+}
+
+// Offset and length of the tuple in `slot`
+fn pointer(page: &[u8], slot: usize) -> (usize, usize) {
+    let at = SLOTS_OFFSET + slot * SLOT_SIZE;
+    (get_u16(page, at) as usize, get_u16(page, at + 2) as usize)
+}
+
+fn set_pointer(page: &mut [u8], slot: usize, offset: usize, len: usize) {
+    let at = SLOTS_OFFSET + slot * SLOT_SIZE;
+    put_u16(page, at, offset);
+    put_u16(page, at + 2, len);
+}
+
+fn all_visible(page: &[u8]) -> bool {
+    page::page_type(page) == PAGE_TYPE_HEAP && get_u16(page, PAGE_FLAGS_OFFSET) & FLAG_ALL_VISIBLE != 0
+}
+
+fn clear_all_visible(page: &mut [u8]) {
+    let flags = get_u16(page, PAGE_FLAGS_OFFSET) & !FLAG_ALL_VISIBLE;
+    put_u16(page, PAGE_FLAGS_OFFSET, flags as usize);
+}
+
+// Free bytes once compacted; none if not a heap page
+fn free_space(page: &[u8]) -> usize {
+    if page::page_type(page) != PAGE_TYPE_HEAP {
+        return 0;
+    }
+    let live: usize = (0..slot_count(page)).filter_map(|slot| live_tuple(page, slot as u16)).map(|range| range.len()).sum();
+    PAGE_SIZE - free_start(page) - live
+}
+
+fn get_u32(page: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(page[at..at + 4].try_into().unwrap())
+}
+
+// The piece of a value an overflow page holds; `None` if it is not one
+fn overflow_bytes(page: &[u8]) -> Option<&[u8]> {
+    if page::page_type(page) != PAGE_TYPE_OVERFLOW {
+        return None;
+    }
+    let len = (get_u16(page, OVERFLOW_LEN_OFFSET) as usize).min(OVERFLOW_PAGE_DATA);
+    Some(&page[OVERFLOW_DATA_OFFSET..OVERFLOW_DATA_OFFSET + len])
+}
+
+// A new version of `row`, inserted by `xid` in command `cid`
+fn version(xid: u64, cid: u32, row: &[u8]) -> Vec<u8> {
+    let mut tuple = Vec::with_capacity(mvcc::HEADER_SIZE + row.len());
+    TupleHeader::inserted(xid, cid).encode(&mut tuple);
+    tuple.extend_from_slice(row);
+    tuple
+}
+
+// Bytes of the tuple in `slot`, if it holds one
+fn live_tuple(page: &[u8], slot: u16) -> Option<std::ops::Range<usize>> {
+    let slot = slot as usize;
+    if page::page_type(page) != PAGE_TYPE_HEAP || slot >= slot_count(page) {
+        return None;
+    }
+    match pointer(page, slot) {
+        (0, _) => None,
+        (offset, len) => Some(offset..offset + len),
+    }
+}
+
+// -----------------------------------------------------------------------------
+// WAL records
+// -----------------------------------------------------------------------------
+
+const PAGE_ID_SIZE: usize = 12;
+
+fn encode_page_id(out: &mut Vec<u8>, page_id: PageId) {
+    for part in [page_id.db_id, page_id.space_id, page_id.page_no] {
+        out.extend_from_slice(&part.to_le_bytes());
+    }
+}
+
+fn decode_page_id(payload: &[u8]) -> Option<PageId> {
+    let field = |at: usize| Some(u32::from_le_bytes(payload.get(at..at + 4)?.try_into().unwrap()));
+    Some(PageId {
+        db_id: field(0)?,
+        space_id: field(4)?,
+        page_no: field(8)?,
+    })
+}
+
+// The slot after the page id, and what follows it
+fn decode_slot(payload: &[u8]) -> Option<(PageId, u16, &[u8])> {
+    let page_id = decode_page_id(payload)?;
+    let slot = u16::from_le_bytes(payload.get(PAGE_ID_SIZE..PAGE_ID_SIZE + 2)?.try_into().unwrap());
+    Some((page_id, slot, &payload[PAGE_ID_SIZE + 2..]))
+}
+
+/// A page formatted as an empty heap page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapInit {
+    pub page_id: PageId,
+}
+
+impl WalRecord for HeapInit {
+    const TYPE: WalRecordType = WalRecordType::HEAP_INIT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE {
+            return None;
+        }
+        Some(Self { page_id: decode_page_id(payload)? })
+    }
+}
+
+impl PageRecord for HeapInit {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[PAGE_HEADER_SIZE..].fill(0);
+        put_u16(page, FREE_END_OFFSET, PAGE_SIZE);
+        page::set_page_type(page, PAGE_TYPE_HEAP);
+    }
+}
+
+/// A tuple stored in `slot`, a free one or the next new one, just below the tuple area.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapInsert {
+    pub page_id: PageId,
+    pub slot: u16,
+    pub tuple: Vec<u8>,
+}
+
+impl WalRecord for HeapInsert {
+    const TYPE: WalRecordType = WalRecordType::HEAP_INSERT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.reserve(PAGE_ID_SIZE + 2 + self.tuple.len());
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.slot.to_le_bytes());
+        out.extend_from_slice(&self.tuple);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let (page_id, slot, tuple) = decode_slot(payload)?;
+        if tuple.len() > MAX_TUPLE_SIZE {
+            return None;
+        }
+        Some(Self { page_id, slot, tuple: tuple.to_vec() })
+    }
+}
+
+impl PageRecord for HeapInsert {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let offset = free_end(page) - self.tuple.len();
+        page[offset..offset + self.tuple.len()].copy_from_slice(&self.tuple);
+        put_u16(page, FREE_END_OFFSET, offset);
+        let slot = self.slot as usize;
+        if slot == slot_count(page) {
+            put_u16(page, SLOT_COUNT_OFFSET, slot + 1);
+        }
+        set_pointer(page, slot, offset, self.tuple.len());
+        clear_all_visible(page);
+    }
+}
+
+/// The tuple in `slot` deleted, and the slot freed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapDelete {
+    pub page_id: PageId,
+    pub slot: u16,
+}
+
+impl WalRecord for HeapDelete {
+    const TYPE: WalRecordType = WalRecordType::HEAP_DELETE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.slot.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let (page_id, slot, rest) = decode_slot(payload)?;
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(Self { page_id, slot })
+    }
+}
+
+impl PageRecord for HeapDelete {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        set_pointer(page, self.slot as usize, 0, 0);
+        clear_all_visible(page);
+    }
+}
+
+/// The page's live tuples packed against its end, in slot order, and trailing free
+/// slots dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapCompact {
+    pub page_id: PageId,
+}
+
+impl WalRecord for HeapCompact {
+    const TYPE: WalRecordType = WalRecordType::HEAP_COMPACT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE {
+            return None;
+        }
+        Some(Self { page_id: decode_page_id(payload)? })
+    }
+}
+
+impl PageRecord for HeapCompact {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let live: Vec<(usize, Vec<u8>)> = (0..slot_count(page))
+            .filter_map(|slot| live_tuple(page, slot as u16).map(|range| (slot, page[range].to_vec())))
+            .collect();
+        let slots = live.last().map_or(0, |(slot, _)| slot + 1);
+        page[SLOTS_OFFSET + slots * SLOT_SIZE..].fill(0);
+        put_u16(page, SLOT_COUNT_OFFSET, slots);
+        let mut end = PAGE_SIZE;
+        for (slot, bytes) in live {
+            end -= bytes.len();
+            page[end..end + bytes.len()].copy_from_slice(&bytes);
+            set_pointer(page, slot, end, bytes.len());
+        }
+        put_u16(page, FREE_END_OFFSET, end);
+    }
+}
+
+/// The page marked all-visible: its bits in the visibility map are set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapVisible {
+    pub page_id: PageId,
+}
+
+impl WalRecord for HeapVisible {
+    const TYPE: WalRecordType = WalRecordType::HEAP_VISIBLE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE {
+            return None;
+        }
+        Some(Self { page_id: decode_page_id(payload)? })
+    }
+}
+
+impl PageRecord for HeapVisible {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let flags = get_u16(page, PAGE_FLAGS_OFFSET) | FLAG_ALL_VISIBLE;
+        put_u16(page, PAGE_FLAGS_OFFSET, flags as usize);
+    }
+}
+
+/// A page formatted as an overflow page holding `bytes`, followed by page `next`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapOverflow {
+    pub page_id: PageId,
+    pub next: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl WalRecord for HeapOverflow {
+    const TYPE: WalRecordType = WalRecordType::HEAP_OVERFLOW;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.next.to_le_bytes());
+        out.extend_from_slice(&self.bytes);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let bytes = payload.get(PAGE_ID_SIZE + 4..)?;
+        if bytes.len() > OVERFLOW_PAGE_DATA {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            next: get_u32(payload, PAGE_ID_SIZE),
+            bytes: bytes.to_vec(),
+        })
+    }
+}
+
+impl PageRecord for HeapOverflow {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[PAGE_HEADER_SIZE..].fill(0);
+        page[OVERFLOW_NEXT_OFFSET..OVERFLOW_NEXT_OFFSET + 4].copy_from_slice(&self.next.to_le_bytes());
+        put_u16(page, OVERFLOW_LEN_OFFSET, self.bytes.len());
+        page[OVERFLOW_DATA_OFFSET..OVERFLOW_DATA_OFFSET + self.bytes.len()].copy_from_slice(&self.bytes);
+        page::set_page_type(page, PAGE_TYPE_OVERFLOW);
+    }
+}
+
+/// The transaction and command that deleted the version in `slot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapSetXmax {
+    pub page_id: PageId,
+    pub slot: u16,
+    pub xmax: u64,
+    pub cmax: u32,
+}
+
+impl WalRecord for HeapSetXmax {
+    const TYPE: WalRecordType = WalRecordType::HEAP_SET_XMAX;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.slot.to_le_bytes());
+        out.extend_from_slice(&self.xmax.to_le_bytes());
+        out.extend_from_slice(&self.cmax.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let (page_id, slot, rest) = decode_slot(payload)?;
+        if rest.len() != 12 {
+            return None;
+        }
+        Some(Self {
+            page_id,
+            slot,
+            xmax: u64::from_le_bytes(rest[0..8].try_into().unwrap()),
+            cmax: get_u32(rest, 8),
+        })
+    }
+}
+
+impl PageRecord for HeapSetXmax {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let (offset, _) = pointer(page, self.slot as usize);
+        page[offset + 8..offset + 16].copy_from_slice(&self.xmax.to_le_bytes());
+        page[offset + 20..offset + 24].copy_from_slice(&self.cmax.to_le_bytes());
+        clear_all_visible(page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::checksum::ChecksumAlgorithm;
+    use crate::core_storage::CoreStorage;
+    use crate::eviction::EvictionKind;
+    use crate::partition::{PagePartition, PageRouter};
+    use crate::mem_store::MemStore;
+    use crate::traits::{Lsn, StorageConfig};
+    use crate::wal_redo;
+
+    const FRAMES: usize = 16;
+
+    fn pool() -> BufferPool<MemStore> {
+        let store = Rc::new(MemStore::new(ChecksumAlgorithm::Crc32c));
+        BufferPool::new(store, FRAMES, FRAMES, ChecksumAlgorithm::Crc32c, EvictionKind::ClockSweep)
+    }
+
+    fn tuple(i: usize, len: usize) -> Vec<u8> {
+        (0..len).map(|b| (i + b) as u8).collect()
+    }
+
+    #[test]
+    fn tuple_ids_pack_into_u64() {
+        let tid = TupleId { page_no: 0xDEAD_BEEF, slot: 0x1234 };
+        assert_eq!(TupleId::from_u64(tid.to_u64()), tid);
+    }
+
+    #[tokio::test]
+    async fn deleted_slots_are_reused_and_compaction_keeps_tuple_ids() {
+        let pool = pool();
+        let heap = HeapFile::new(&pool, 1, 1);
+        // 500-byte tuples: 16 fill a page, the 17th goes to a new one.
+        let mut tids = Vec::new();
+        for i in 0..17 {
+            tids.push(heap.insert_tuple(&tuple(i, 500)).await.unwrap());
+        }
+        assert!(tids[..16].iter().all(|tid| tid.page_no == tids[0].page_no));
+        assert_ne!(tids[16].page_no, tids[0].page_no);
+        for (i, &tid) in tids.iter().enumerate() {
+            assert_eq!(heap.get_tuple(tid).await.unwrap(), Some(tuple(i, 500)));
+        }
+
+        // Holes in the first page, none of them big enough on its own
+        for i in (1..16).step_by(2) {
+            assert!(heap.delete(tids[i]).await.unwrap());
+        }
+        assert!(!heap.delete(tids[1]).await.unwrap());
+        assert_eq!(heap.get_tuple(tids[1]).await.unwrap(), None);
+
+        // Inserts go to the newest page, so place one in the first page directly: the
+        // holes only make room once the page is compacted.
+        let page_no = tids[0].page_no;
+        let mut page = pool.get_page_mut(heap.page(page_no)).await.unwrap();
+        let big = tuple(99, 2_000);
+        let slot = insert_into(&mut page, &big).await.unwrap().expect("holes make room once compacted");
+        drop(page);
+        assert_eq!(slot, 1, "the first free slot is reused");
+        assert_eq!(heap.get_tuple(TupleId { page_no, slot }).await.unwrap(), Some(big));
+        for i in (0..16).step_by(2) {
+            assert_eq!(heap.get_tuple(tids[i]).await.unwrap(), Some(tuple(i, 500)), "tuple {}", i);
+        }
+
+        assert!(matches!(
+            heap.insert_tuple(&vec![0; MAX_TUPLE_SIZE + 1]).await,
+            Err(StorageError::TupleTooLarge(_))
+        ));
+        let whole = heap.insert_tuple(&vec![7; MAX_TUPLE_SIZE]).await.unwrap();
+        assert_eq!(heap.get_tuple(whole).await.unwrap().unwrap().len(), MAX_TUPLE_SIZE);
+    }
+
+    #[tokio::test]
+    async fn versions_are_seen_by_snapshots_and_pruned_once_dead() {
+        use crate::mvcc::tests::Statuses;
+        use XidStatus::*;
+        let pool = pool();
+        let heap = HeapFile::new(&pool, 1, 1);
+        let mut status = Statuses::default();
+        let a = heap.insert_version(3, 0, b"a").await.unwrap();
+        let b = heap.insert_version(3, 0, b"b").await.unwrap();
+        status.0.insert(3, Committed);
+
+        // 4 deletes `a`, while a snapshot taken before it commits keeps seeing it.
+        let before = Snapshot::new(mvcc::INVALID_XID, 0, 4, 5, vec![4]);
+        assert_eq!(heap.delete_version(a, 4, 0, &status).await.unwrap(), DeleteOutcome::Deleted);
+        assert_eq!(heap.delete_version(a, 4, 1, &status).await.unwrap(), DeleteOutcome::Deleted);
+        assert_eq!(heap.delete_version(a, 5, 0, &status).await.unwrap(), DeleteOutcome::Conflict(4));
+        status.0.insert(4, Committed);
+        let after = Snapshot::new(mvcc::INVALID_XID, 0, 5, 5, Vec::new());
+        assert_eq!(heap.get_version(a, &before, &status).await.unwrap(), Some(b"a".to_vec()));
+        assert_eq!(heap.get_version(a, &after, &status).await.unwrap(), None);
+        assert_eq!(heap.get_version(b, &after, &status).await.unwrap(), Some(b"b".to_vec()));
+
+        // An aborted delete leaves the version to be deleted again.
+        assert_eq!(heap.delete_version(b, 6, 0, &status).await.unwrap(), DeleteOutcome::Deleted);
+        status.0.insert(6, Aborted);
+        assert_eq!(heap.get_version(b, &after, &status).await.unwrap(), Some(b"b".to_vec()));
+        assert_eq!(heap.delete_version(b, 7, 0, &status).await.unwrap(), DeleteOutcome::Deleted);
+
+        // `a` is dead only below a horizon past its deleter; `b`'s delete is running.
+        assert!(heap.prune_page(a.page_no, 4, &status).await.unwrap().is_empty());
+        assert_eq!(heap.prune_page(a.page_no, 5, &status).await.unwrap(), vec![b"a".to_vec()]);
+        assert_eq!(heap.get_tuple(a).await.unwrap(), None);
+        assert_eq!(heap.delete_version(a, 8, 0, &status).await.unwrap(), DeleteOutcome::Missing);
+        assert!(heap.get_tuple(b).await.unwrap().is_some());
+    }
+
+    #[test]
+    fn redo_rebuilds_heap_pages_from_the_log() {
+        let dir = std::env::temp_dir().join(format!("aquifer-heap-{}-redo", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::create_dir_all(dir.join("wal")).unwrap();
+        let config = StorageConfig::scratch(dir.join("data"), dir.join("wal"));
+        let partition = || {
+            let storage = Rc::new(CoreStorage::new(&config, 0));
+            let pool = BufferPool::new(storage, FRAMES, FRAMES, ChecksumAlgorithm::Crc32c, EvictionKind::ClockSweep);
+            let (router, mut inboxes) = PageRouter::new(1);
+            PagePartition::new(0, Rc::new(pool), router, inboxes.remove(0))
+        };
+        tokio_uring::start(async {
+            let before = partition();
+            let heap = HeapFile::new(before.pool(), 1, 1);
+            let mut live = Vec::new();
+            for i in 0..2_000 {
+                let tid = heap.insert_tuple(&tuple(i, 10 + i % 300)).await.unwrap();
+                if i % 3 == 0 {
+                    heap.delete(tid).await.unwrap();
+                } else {
+                    live.push((tid, tuple(i, 10 + i % 300)));
+                }
+            }
+            before.pool().store().flush_wal(1).await.unwrap();
+            drop(heap);
+            drop(before);
+
+            let mut registry = WalRegistry::new();
+            register(&mut registry);
+            crate::fsm::register(&mut registry);
+            let plans = vec![wal_redo::plan(&config.wal_dir, 1, Lsn(0), None).unwrap()];
+            let after = partition();
+            let summary = wal_redo::redo(&after, &plans, &HashMap::new(), &registry, false).await.unwrap();
+            assert!(summary.redone > 0);
+            let heap = HeapFile::new(after.pool(), 1, 1);
+            for (tid, bytes) in live {
+                assert_eq!(heap.get_tuple(tid).await.unwrap(), Some(bytes), "{:?}", tid);
+            }
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
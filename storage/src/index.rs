@@ -0,0 +1,174 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use futures::future::LocalBoxFuture;
+
+use crate::btree::BTree;
+use crate::heap::TupleId;
+use crate::traits::{PageStore, StorageError, WalStore};
+
+// -----------------------------------------------------------------------------
+// Index Maintenance
+//
+// Secondary indexes follow the rows of a heap without their callers' help: an index
+// subscribes to a heap space in an `IndexRegistry`, and a `HeapFile` given the
+// registry (`HeapFile::with_indexes`) hands each of its version changes to the
+// space's indexes in subscription order, as a `HeapEvent` with the transaction making
+// it:
+//
+//   Insert   `insert_version`   the new version's tuple id and row
+//   Update   `update_version`   the old version's and the new one's
+//   Delete   `delete_version`   the deleted version's, once per deleting transaction
+//
+// The heap change is made first, and the indexes are told in the same call, before it
+// returns. An index failing fails the call with the heap change made and the indexes
+// after it not told; the caller is to abort the transaction. Plain tuples and rows
+// (`insert_tuple`, `insert_row`, ...) have no transaction and tell nobody.
+//
+// `BTreeIndex` keeps a unique index of u64 keys taken from the rows, each to the tuple
+// id of its row's newest version. Like the B-tree's own, its changes are not undone
+// by a rollback.
+// -----------------------------------------------------------------------------
+
+/// A change to a heap's versions by transaction `xid`. Rows are without their version
+/// header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapEvent<'e> {
+    Insert {
+        xid: u64,
+        tid: TupleId,
+        row: &'e [u8],
+    },
+    Update {
+        xid: u64,
+        old_tid: TupleId,
+        old_row: &'e [u8],
+        new_tid: TupleId,
+        new_row: &'e [u8],
+    },
+    Delete {
+        xid: u64,
+        tid: TupleId,
+        row: &'e [u8],
+    },
+}
+
+/// An index kept in step with a heap space's versions.
+pub trait IndexHook {
+    /// Applies `event`, just made to the heap.
+    fn apply<'e>(&'e self, event: &'e HeapEvent<'e>) -> LocalBoxFuture<'e, Result<(), StorageError>>;
+}
+
+type Hook<'a> = Rc<dyn IndexHook + 'a>;
+
+/// The indexes subscribed to each heap space.
+#[derive(Default)]
+pub struct IndexRegistry<'a> {
+    hooks: RefCell<HashMap<(u32, u32), Vec<Hook<'a>>>>,
+}
+
+impl<'a> IndexRegistry<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes `hook` to the changes of heap space `space_id` of database `db_id`.
+    pub fn subscribe(&self, db_id: u32, space_id: u32, hook: Hook<'a>) {
+        self.hooks.borrow_mut().entry((db_id, space_id)).or_default().push(hook);
+    }
+
+    /// Unsubscribes `hook` from heap space `space_id`. Returns whether it was subscribed.
+    pub fn unsubscribe(&self, db_id: u32, space_id: u32, hook: &Hook<'a>) -> bool {
+        let mut hooks = self.hooks.borrow_mut();
+        let Some(subscribed) = hooks.get_mut(&(db_id, space_id)) else {
+            return false;
+        };
+        let before = subscribed.len();
+        subscribed.retain(|other| !Rc::ptr_eq(other, hook));
+        before != subscribed.len()
+    }
+
+    /// Hands `event` of heap space `space_id` to its indexes in turn, stopping at the
+    /// first that fails.
+    pub async fn notify(&self, db_id: u32, space_id: u32, event: &HeapEvent<'_>) -> Result<(), StorageError> {
+        // Cloned, so a hook may subscribe others meanwhile.
+        let hooks = self.hooks.borrow().get(&(db_id, space_id)).cloned().unwrap_or_default();
+        for hook in hooks {
+            hook.apply(event).await?;
+        }
+        Ok(())
+    }
+}
+
+type KeyFn<'a> = Box<dyn Fn(&[u8]) -> Option<u64> + 'a>;
+
+/// A unique B-tree index of the keys `key` takes from rows, to their newest version.
+/// Rows it gives no key for are left out.
+pub struct BTreeIndex<'a, S: PageStore + WalStore> {
+    tree: BTree<'a, S>,
+    key: KeyFn<'a>,
+}
+
+impl<'a, S: PageStore + WalStore> BTreeIndex<'a, S> {
+    pub fn new(tree: BTree<'a, S>, key: impl Fn(&[u8]) -> Option<u64> + 'a) -> Self {
+        Self { tree, key: Box::new(key) }
+    }
+
+    pub fn tree(&self) -> &BTree<'a, S> {
+        &self.tree
+    }
+
+    /// The tuple id `key` is indexed to, if any.
+    pub async fn lookup(&self, key: u64) -> Result<Option<TupleId>, StorageError> {
+        Ok(self.tree.search(key).await?.map(TupleId::from_u64))
+    }
+
+    // Indexes `key` to `tid`, unless another row has it
+    async fn add(&self, key: u64, tid: TupleId, replacing: Option<TupleId>) -> Result<(), StorageError> {
+        if let Some(existing) = self.tree.search(key).await? {
+            if Some(TupleId::from_u64(existing)) != replacing && existing != tid.to_u64() {
+                return Err(StorageError::DuplicateKey(key));
+            }
+        }
+        self.tree.insert(key, tid.to_u64()).await?;
+        Ok(())
+    }
+
+    // Drops `key`, if it is still indexed to `tid`
+    async fn remove(&self, key: u64, tid: TupleId) -> Result<(), StorageError> {
+        if self.tree.search(key).await? == Some(tid.to_u64()) {
+            self.tree.delete(key).await?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: PageStore + WalStore> IndexHook for BTreeIndex<'_, S> {
+    fn apply<'e>(&'e self, event: &'e HeapEvent<'e>) -> LocalBoxFuture<'e, Result<(), StorageError>> {
+        Box::pin(async move {
+            match *event {
+                HeapEvent::Insert { tid, row, .. } => match (self.key)(row) {
+                    Some(key) => self.add(key, tid, None).await,
+                    None => Ok(()),
+                },
+                HeapEvent::Update { old_tid, old_row, new_tid, new_row, .. } => {
+                    let old_key = (self.key)(old_row);
+                    let new_key = (self.key)(new_row);
+                    if let Some(key) = new_key {
+                        let replacing = (old_key == new_key).then_some(old_tid);
+                        self.add(key, new_tid, replacing).await?;
+                    }
+                    match old_key {
+                        Some(key) if old_key != new_key => self.remove(key, old_tid).await,
+                        _ => Ok(()),
+                    }
+                }
+                HeapEvent::Delete { tid, row, .. } => match (self.key)(row) {
+                    Some(key) => self.remove(key, tid).await,
+                    None => Ok(()),
+                },
+            }
+        })
+    }
+}
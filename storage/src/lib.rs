@@ -0,0 +1,74 @@
+// Thread-per-core: the storage traits are used on one thread and their futures are
+// never sent, so the `Send` bounds the lint asks about are deliberately left off.
+#![allow(async_fn_in_trait)]
+
+pub mod backup;
+pub mod bench;
+pub mod blob;
+pub mod bloom;
+pub mod btree;
+pub mod buffer_pool;
+pub mod catalog;
+pub mod checkpointer;
+pub mod checksum;
+pub mod cli;
+pub mod commit_ts;
+pub mod compression;
+pub mod core_storage;
+pub mod crash_test;
+pub mod discard;
+pub mod encryption;
+pub mod eviction;
+pub mod failpoint;
+pub mod fault;
+pub mod fsm;
+pub mod full_page;
+pub mod hash_index;
+pub mod heap;
+pub mod index;
+pub mod load;
+pub mod lock;
+pub mod lsm;
+pub mod mem_store;
+pub mod metrics;
+pub mod mount;
+pub mod multi_read;
+pub mod mvcc;
+pub mod numa;
+pub mod page;
+pub mod page_table;
+pub mod page_verify;
+pub mod partition;
+pub mod pinned;
+pub mod quarantine;
+pub mod record;
+pub mod restore;
+pub mod ring;
+pub mod runtime;
+pub mod segment;
+pub mod sequence;
+pub mod sim;
+pub mod stats;
+pub mod stream;
+pub mod trace;
+pub mod traits;
+pub mod txn;
+pub mod undo;
+pub mod vm;
+pub mod wal;
+pub mod wal_archive;
+pub mod wal_checkpoint;
+pub mod wal_compress;
+pub mod wal_crypt;
+pub mod wal_merge;
+pub mod wal_prealloc;
+pub mod wal_reader;
+pub mod wal_record;
+pub mod wal_recovery;
+pub mod wal_redo;
+pub mod wal_registry;
+pub mod wal_retention;
+pub mod wal_sender;
+pub mod wal_verify;
+pub mod warmup;
+pub mod watchdog;
@@ -0,0 +1,357 @@
+use std::io::BufRead;
+
+use crate::heap::HeapFile;
+use crate::record::{ColumnType, Value};
+use crate::traits::{PageStore, StorageError, WalStore};
+
+// -----------------------------------------------------------------------------
+// Bulk Loading
+//
+// `cascade-cli import` turns CSV into rows of a heap space (see `heap.rs` and
+// `record.rs`). The column list says how each field is read:
+//
+//   bool                   true/false, t/f, 1/0
+//   int16 int32 int64      decimal
+//   float64                as Rust parses it
+//   text                   as is
+//   bytes                  hex digits, `\x`-prefixed or not
+//
+// Fields are separated by commas and records by newlines, CRLF too. A field in double
+// quotes may hold commas, newlines and doubled quotes. An empty unquoted field is
+// NULL; a quoted one never is, so `""` is the empty string.
+//
+// A record is bad if it has another number of fields than there are columns, a field
+// doesn't read as its column's type, or it has NULL in a column declared not null.
+// Rows are plain heap tuples, inserted without a transaction, so a load that fails
+// halfway keeps the rows before the failure: `check_csv` goes through the whole input
+// first, and `load_csv` is only to be run on what passed it. A bad record is reported
+// with the line it starts on.
+// -----------------------------------------------------------------------------
+
+/// Rows `load_csv` inserts between calls to its progress callback.
+pub const PROGRESS_ROWS: u64 = 100_000;
+
+#[derive(Debug)]
+pub enum LoadError {
+    /// The record starting on `line` (1-based) can't be a row of the columns.
+    BadRecord { line: u64, reason: String },
+    Storage(StorageError),
+}
+
+impl From<StorageError> for LoadError {
+    fn from(e: StorageError) -> Self {
+        LoadError::Storage(e)
+    }
+}
+
+/// The columns of a table, with which of them may not be NULL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Columns {
+    pub types: Vec<ColumnType>,
+    pub not_null: Vec<bool>,
+}
+
+impl Columns {
+    /// Columns from a spec like `int64,text,bool`, all nullable.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let types = spec.split(',').map(parse_type).collect::<Result<Vec<_>, _>>()?;
+        let not_null = vec![false; types.len()];
+        Ok(Self { types, not_null })
+    }
+
+    /// Declares column `column` (0-based) not null.
+    pub fn set_not_null(&mut self, column: usize) -> Result<(), String> {
+        let count = self.types.len();
+        let flag = self.not_null.get_mut(column).ok_or_else(|| format!("no column {}, there are {}", column, count))?;
+        *flag = true;
+        Ok(())
+    }
+}
+
+fn parse_type(name: &str) -> Result<ColumnType, String> {
+    match name.trim() {
+        "bool" => Ok(ColumnType::Bool),
+        "int16" => Ok(ColumnType::Int16),
+        "int32" => Ok(ColumnType::Int32),
+        "int64" => Ok(ColumnType::Int64),
+        "float64" => Ok(ColumnType::Float64),
+        "bytes" => Ok(ColumnType::Bytes),
+        "text" => Ok(ColumnType::Text),
+        other => Err(format!("unknown column type {}", other)),
+    }
+}
+
+/// A CSV record's fields; `None` for NULL.
+pub type Fields = Vec<Option<String>>;
+
+/// Splits `input` into records, each with the line it starts on. `header` skips the
+/// first record.
+pub struct CsvRecords<R> {
+    input: R,
+    // Line the next record starts on
+    line: u64,
+    skip: bool,
+}
+
+impl<R: BufRead> CsvRecords<R> {
+    pub fn new(input: R, header: bool) -> Self {
+        Self { input, line: 1, skip: header }
+    }
+
+    // The next record, whether skipped or not. `None` at the end of the input.
+    fn read_record(&mut self) -> Option<Result<(u64, Fields), LoadError>> {
+        let start = self.line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut in_quotes = false;
+        let mut text = String::new();
+        loop {
+            text.clear();
+            match self.input.read_line(&mut text) {
+                Ok(0) if start == self.line && fields.is_empty() && field.is_empty() && !quoted => return None,
+                Ok(0) => {
+                    if in_quotes {
+                        return Some(Err(bad(start, "quoted field runs to the end of the input")));
+                    }
+                    fields.push(finish_field(&mut field, quoted));
+                    return Some(Ok((start, fields)));
+                }
+                Ok(_) => self.line += 1,
+                Err(e) => return Some(Err(LoadError::Storage(StorageError::Io(e)))),
+            }
+
+            let mut chars = text.chars().peekable();
+            while let Some(c) = chars.next() {
+                match (in_quotes, c) {
+                    (true, '"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    (true, '"') => in_quotes = false,
+                    (true, c) => field.push(c),
+                    (false, '"') if field.is_empty() && !quoted => {
+                        quoted = true;
+                        in_quotes = true;
+                    }
+                    (false, '"') => return Some(Err(bad(start, "stray quote in a field"))),
+                    (false, ',') => {
+                        fields.push(finish_field(&mut field, quoted));
+                        quoted = false;
+                    }
+                    (false, '\r') if chars.peek() == Some(&'\n') => {}
+                    (false, '\n') => {
+                        fields.push(finish_field(&mut field, quoted));
+                        return Some(Ok((start, fields)));
+                    }
+                    (false, _) if quoted => return Some(Err(bad(start, "text after a closing quote"))),
+                    (false, c) => field.push(c),
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for CsvRecords<R> {
+    type Item = Result<(u64, Fields), LoadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if std::mem::take(&mut self.skip) {
+            if let Err(e) = self.read_record()? {
+                return Some(Err(e));
+            }
+        }
+        self.read_record()
+    }
+}
+
+fn finish_field(field: &mut String, quoted: bool) -> Option<String> {
+    let field = std::mem::take(field);
+    (quoted || !field.is_empty()).then_some(field)
+}
+
+fn bad(line: u64, reason: impl Into<String>) -> LoadError {
+    LoadError::BadRecord { line, reason: reason.into() }
+}
+
+/// A row's values read from the fields of the record on `line`. Byte values are kept
+/// in `bytes`, which the returned values borrow.
+fn parse_row<'f>(columns: &Columns, line: u64, fields: &'f Fields, bytes: &'f mut Vec<Vec<u8>>) -> Result<Vec<Value<'f>>, LoadError> {
+    if fields.len() != columns.types.len() {
+        return Err(bad(line, format!("{} field(s), expected {}", fields.len(), columns.types.len())));
+    }
+    // Hex is decoded up front, so the values can borrow the decoded bytes.
+    bytes.clear();
+    for (column, field) in fields.iter().enumerate() {
+        if let (ColumnType::Bytes, Some(field)) = (columns.types[column], field) {
+            bytes.push(parse_hex(field).ok_or_else(|| bad(line, format!("column {}: bad hex {:?}", column, field)))?);
+        }
+    }
+
+    let bytes: &'f Vec<Vec<u8>> = bytes;
+    let mut decoded = bytes.iter();
+    let mut values = Vec::with_capacity(fields.len());
+    for (column, field) in fields.iter().enumerate() {
+        let Some(field) = field else {
+            if columns.not_null[column] {
+                return Err(bad(line, format!("column {}: NULL in a not null column", column)));
+            }
+            values.push(Value::Null);
+            continue;
+        };
+        let column_type = columns.types[column];
+        let wrong = || bad(line, format!("column {}: {:?} is not {:?}", column, field, column_type));
+        values.push(match column_type {
+            ColumnType::Bool => match field.as_str() {
+                "true" | "t" | "1" => Value::Bool(true),
+                "false" | "f" | "0" => Value::Bool(false),
+                _ => return Err(wrong()),
+            },
+            ColumnType::Int16 => Value::Int16(field.parse().map_err(|_| wrong())?),
+            ColumnType::Int32 => Value::Int32(field.parse().map_err(|_| wrong())?),
+            ColumnType::Int64 => Value::Int64(field.parse().map_err(|_| wrong())?),
+            ColumnType::Float64 => Value::Float64(field.parse().map_err(|_| wrong())?),
+            ColumnType::Text => Value::Text(field),
+            ColumnType::Bytes => Value::Bytes(decoded.next().unwrap()),
+        });
+    }
+    Ok(values)
+}
+
+fn parse_hex(field: &str) -> Option<Vec<u8>> {
+    let hex = field.strip_prefix("\\x").unwrap_or(field);
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|at| u8::from_str_radix(&hex[at..at + 2], 16).ok()).collect()
+}
+
+/// Reads every record of `input` as a row of `columns`, without storing any. Returns
+/// how many there are, or the first bad one.
+pub fn check_csv(input: impl BufRead, header: bool, columns: &Columns) -> Result<u64, LoadError> {
+    let mut rows = 0u64;
+    let mut bytes = Vec::new();
+    for record in CsvRecords::new(input, header) {
+        let (line, fields) = record?;
+        parse_row(columns, line, &fields, &mut bytes)?;
+        rows += 1;
+    }
+    Ok(rows)
+}
+
+/// Inserts every record of `input` into `heap` as a row of `columns`, calling
+/// `progress` with the rows inserted so far every `PROGRESS_ROWS`. Returns how many
+/// it inserted. Stops at the first bad record, keeping the rows before it.
+pub async fn load_csv<S: PageStore + WalStore>(
+    heap: &HeapFile<'_, S>,
+    input: impl BufRead,
+    header: bool,
+    columns: &Columns,
+    mut progress: impl FnMut(u64),
+) -> Result<u64, LoadError> {
+    let mut rows = 0u64;
+    let mut bytes = Vec::new();
+    for record in CsvRecords::new(input, header) {
+        let (line, fields) = record?;
+        let values = parse_row(columns, line, &fields, &mut bytes)?;
+        heap.insert_row(&columns.types, &values).await?;
+        rows += 1;
+        if rows.is_multiple_of(PROGRESS_ROWS) {
+            progress(rows);
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::buffer_pool::BufferPool;
+    use crate::heap::TupleId;
+    use crate::checksum::ChecksumAlgorithm;
+    use crate::eviction::EvictionKind;
+    use crate::mem_store::MemStore;
+    use crate::record;
+
+    fn records(input: &str) -> Vec<(u64, Fields)> {
+        CsvRecords::new(input.as_bytes(), false).collect::<Result<_, _>>().unwrap()
+    }
+
+    fn field(text: &str) -> Option<String> {
+        Some(text.to_string())
+    }
+
+    #[test]
+    fn quoted_fields_hold_separators_and_quotes() {
+        let input = "1,plain,\r\n2,\"a, \"\"quoted\"\"\nfield\",\"\"\n3";
+        assert_eq!(
+            records(input),
+            vec![
+                (1, vec![field("1"), field("plain"), None]),
+                (2, vec![field("2"), field("a, \"quoted\"\nfield"), field("")]),
+                (4, vec![field("3")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_records_are_reported_with_their_line() {
+        let mut columns = Columns::parse("int64,text,bool").unwrap();
+        columns.set_not_null(1).unwrap();
+        let check = |input: &str| match check_csv(input.as_bytes(), true, &columns) {
+            Err(LoadError::BadRecord { line, .. }) => Some(line),
+            Ok(_) => None,
+            Err(e) => panic!("{:?}", e),
+        };
+
+        assert_eq!(check("id,name,flag\n1,one,t\n2,two,\n"), None);
+        assert_eq!(check("id,name,flag\n1,one,t\n2,two\n"), Some(3));
+        assert_eq!(check("id,name,flag\n1,one,t\nx,two,f\n"), Some(3));
+        assert_eq!(check("id,name,flag\n1,one,maybe\n"), Some(2));
+        assert_eq!(check("id,name,flag\n1,,t\n"), Some(2), "NULL in a not null column");
+        assert_eq!(check("id,name,flag\n1,\"open,t\n"), Some(2));
+        assert!(Columns::parse("int64,decimal").is_err());
+    }
+
+    #[tokio::test]
+    async fn rows_load_into_the_heap_as_typed() {
+        let store = Rc::new(MemStore::new(ChecksumAlgorithm::Crc32c));
+        let pool = BufferPool::new(store, 16, 16, ChecksumAlgorithm::Crc32c, EvictionKind::ClockSweep);
+        let heap = HeapFile::new(&pool, 1, 1);
+        let columns = Columns::parse("int64,text,float64,bytes,bool").unwrap();
+
+        // Rows go after the probe, filling the heap's pages in order.
+        let probe = heap.insert_tuple(b"probe").await.unwrap();
+
+        let mut input = String::from("id,name,score,blob,flag\n");
+        for i in 0..1000 {
+            input.push_str(&format!("{},name {},{}.5,\\x{:04x},{}\n", i, i, i, i, i % 2));
+        }
+        input.push_str("1000,,,,\n");
+        assert_eq!(check_csv(input.as_bytes(), true, &columns).unwrap(), 1001);
+        let loaded = load_csv(&heap, input.as_bytes(), true, &columns, |_| {}).await.unwrap();
+        assert_eq!(loaded, 1001);
+
+        let mut found = Vec::new();
+        let mut tid = TupleId { slot: probe.slot + 1, ..probe };
+        while found.len() < 1001 && tid.page_no < probe.page_no + 100 {
+            match heap.get_tuple(tid).await.unwrap() {
+                Some(tuple) => {
+                    found.push(tuple);
+                    tid.slot += 1;
+                }
+                None => tid = TupleId { page_no: tid.page_no + 1, slot: 0 },
+            }
+        }
+        assert_eq!(found.len(), 1001);
+        let row = record::decode_row(&columns.types, &found[7]).unwrap();
+        assert_eq!(
+            row,
+            vec![Value::Int64(7), Value::Text("name 7"), Value::Float64(7.5), Value::Bytes(&[0, 7]), Value::Bool(true)]
+        );
+        let last = record::decode_row(&columns.types, &found[1000]).unwrap();
+        assert_eq!(last, vec![Value::Int64(1000), Value::Null, Value::Null, Value::Null, Value::Null]);
+    }
+}
@@ -0,0 +1,397 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use futures::channel::oneshot;
+
+use crate::traits::StorageError;
+
+// -----------------------------------------------------------------------------
+// Lock Manager
+//
+// Transactions lock the rows and pages they are about to write, shared or exclusive,
+// so conflicting writes take turns; readers go by their snapshot (`mvcc.rs`) and take
+// no locks. A row is a slot of a page (`LockTarget::row`), and locking one first
+// takes an intention lock on its page, so a page lock conflicts with the row locks
+// under it:
+//
+//            IS   IX   S    X
+//      IS    ok   ok   ok   -
+//      IX    ok   ok   -    -
+//      S     ok   -    ok   -
+//      X     -    -    -    -
+//
+// A transaction asking for a mode on top of the one it holds gets one covering both
+// (S and IX make X). Requests are granted in arrival order: one waits while another
+// holds a conflicting mode, or while anyone is queued before it, so a stream of
+// shared locks can't starve an exclusive one. Only a transaction upgrading a lock it
+// holds goes ahead of the queue. A request that would wait for a transaction waiting,
+// directly or not, for the requester fails with `StorageError::Deadlock` instead, and
+// the requester is expected to abort. A waiter that gives up (its future dropped)
+// leaves the queue.
+//
+// Locks are held until the transaction ends: `TransactionManager::commit` and `abort`
+// release them all. The table is per core (`PagePartition::locks`), for transactions
+// running on it; ones on different cores don't see each other's locks yet.
+// -----------------------------------------------------------------------------
+
+/// What a transaction locks: a page, or a row of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LockTarget {
+    pub db_id: u32,
+    pub space_id: u32,
+    pub page_no: u32,
+    /// The row's slot; `None` for the whole page.
+    pub slot: Option<u16>,
+}
+
+impl LockTarget {
+    pub fn page(db_id: u32, space_id: u32, page_no: u32) -> Self {
+        Self { db_id, space_id, page_no, slot: None }
+    }
+
+    pub fn row(db_id: u32, space_id: u32, page_no: u32, slot: u16) -> Self {
+        Self { db_id, space_id, page_no, slot: Some(slot) }
+    }
+}
+
+/// How a lock is held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+// Lock modes, the intention ones only ever taken on pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    IntentShared,
+    IntentExclusive,
+    Shared,
+    Exclusive,
+}
+
+impl Mode {
+    fn compatible(self, other: Mode) -> bool {
+        use Mode::*;
+        matches!(
+            (self, other),
+            (IntentShared, IntentShared | IntentExclusive | Shared)
+                | (IntentExclusive, IntentShared | IntentExclusive)
+                | (Shared, IntentShared | Shared)
+        )
+    }
+
+    fn covers(self, other: Mode) -> bool {
+        use Mode::*;
+        self == other || matches!((self, other), (Exclusive, _) | (Shared | IntentExclusive, IntentShared))
+    }
+
+    // A mode covering both
+    fn combine(self, other: Mode) -> Mode {
+        if self.covers(other) {
+            self
+        } else if other.covers(self) {
+            other
+        } else {
+            Mode::Exclusive
+        }
+    }
+}
+
+impl From<LockMode> for Mode {
+    fn from(mode: LockMode) -> Self {
+        match mode {
+            LockMode::Shared => Mode::Shared,
+            LockMode::Exclusive => Mode::Exclusive,
+        }
+    }
+}
+
+struct Waiter {
+    xid: u64,
+    mode: Mode,
+    grant: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct Entry {
+    // One per transaction, the mode covering all it asked for
+    granted: Vec<(u64, Mode)>,
+    waiting: VecDeque<Waiter>,
+}
+
+impl Entry {
+    fn held(&self, xid: u64) -> Option<Mode> {
+        self.granted.iter().find(|(holder, _)| *holder == xid).map(|(_, mode)| *mode)
+    }
+
+    // Whether `xid` can have `mode` as far as the other holders go
+    fn fits(&self, xid: u64, mode: Mode) -> bool {
+        self.granted.iter().all(|&(holder, held)| holder == xid || held.compatible(mode))
+    }
+
+    fn grant(&mut self, xid: u64, mode: Mode) {
+        match self.granted.iter_mut().find(|(holder, _)| *holder == xid) {
+            Some((_, held)) => *held = mode,
+            None => self.granted.push((xid, mode)),
+        }
+    }
+
+    // Grants queued requests in order, up to the first that has to keep waiting, and
+    // takes the granted out of `waits`
+    fn wake(&mut self, waits: &mut HashMap<u64, (LockTarget, Mode)>) {
+        while let Some(waiter) = self.waiting.front() {
+            if !self.fits(waiter.xid, waiter.mode) {
+                break;
+            }
+            let waiter = self.waiting.pop_front().unwrap();
+            waits.remove(&waiter.xid);
+            let before = self.held(waiter.xid);
+            self.grant(waiter.xid, waiter.mode);
+            if waiter.grant.send(()).is_err() {
+                // Gave up meanwhile
+                match before {
+                    Some(mode) => self.grant(waiter.xid, mode),
+                    None => self.granted.retain(|(holder, _)| *holder != waiter.xid),
+                }
+            }
+        }
+    }
+
+    // Transactions the request of `xid` for `mode` waits for: conflicting holders, and
+    // everyone queued before it. An upgrade goes to the front of the queue, so it waits
+    // for no one queued unless another upgrade got there first.
+    fn blockers(&self, xid: u64, mode: Mode) -> Vec<u64> {
+        let mut blockers: Vec<u64> = self
+            .granted
+            .iter()
+            .filter(|&&(holder, held)| holder != xid && !held.compatible(mode))
+            .map(|&(holder, _)| holder)
+            .collect();
+        let ahead = match self.waiting.iter().position(|waiter| waiter.xid == xid) {
+            Some(at) => at,
+            None if self.held(xid).is_some() => 0,
+            None => self.waiting.len(),
+        };
+        blockers.extend(self.waiting.iter().take(ahead).map(|waiter| waiter.xid).filter(|&waiter| waiter != xid));
+        blockers
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    entries: HashMap<LockTarget, Entry>,
+    // What each transaction holds or is queued for
+    held: HashMap<u64, HashSet<LockTarget>>,
+    // What each waiting transaction waits for
+    waits: HashMap<u64, (LockTarget, Mode)>,
+}
+
+impl Table {
+    // Whether `xid` waiting on `target` for `mode` would wait for itself
+    fn deadlocks(&self, xid: u64, target: LockTarget, mode: Mode) -> bool {
+        let mut stack = self.entries.get(&target).map(|entry| entry.blockers(xid, mode)).unwrap_or_default();
+        let mut seen = HashSet::new();
+        while let Some(blocker) = stack.pop() {
+            if blocker == xid {
+                return true;
+            }
+            if !seen.insert(blocker) {
+                continue;
+            }
+            if let Some(&(target, mode)) = self.waits.get(&blocker) {
+                stack.extend(self.entries[&target].blockers(blocker, mode));
+            }
+        }
+        false
+    }
+}
+
+/// The locks of the transactions running on one core.
+#[derive(Default)]
+pub struct LockManager {
+    table: RefCell<Table>,
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Locks `target` in `mode` for transaction `xid`, waiting for the transactions
+    /// holding it in a conflicting mode to end. Locking a row takes the intention lock
+    /// on its page first.
+    pub async fn lock(&self, xid: u64, target: LockTarget, mode: LockMode) -> Result<(), StorageError> {
+        if target.slot.is_some() {
+            let page = LockTarget { slot: None, ..target };
+            let intent = match mode {
+                LockMode::Shared => Mode::IntentShared,
+                LockMode::Exclusive => Mode::IntentExclusive,
+            };
+            self.acquire(xid, page, intent).await?;
+        }
+        self.acquire(xid, target, mode.into()).await
+    }
+
+    /// The mode `xid` holds `target` in, if it does. Pages locked only for rows of
+    /// theirs don't count.
+    pub fn held(&self, xid: u64, target: LockTarget) -> Option<LockMode> {
+        match self.table.borrow().entries.get(&target)?.held(xid)? {
+            Mode::Shared => Some(LockMode::Shared),
+            Mode::Exclusive => Some(LockMode::Exclusive),
+            Mode::IntentShared | Mode::IntentExclusive => None,
+        }
+    }
+
+    /// Releases every lock of `xid`, and grants the requests that were waiting for them.
+    /// `xid` must have no request of its own waiting.
+    pub fn release_all(&self, xid: u64) {
+        let mut table = self.table.borrow_mut();
+        let Table { entries, held, waits } = &mut *table;
+        let Some(targets) = held.remove(&xid) else {
+            return;
+        };
+        for target in targets {
+            let entry = entries.get_mut(&target).unwrap();
+            entry.granted.retain(|(holder, _)| *holder != xid);
+            entry.wake(waits);
+            if entry.granted.is_empty() && entry.waiting.is_empty() {
+                entries.remove(&target);
+            }
+        }
+    }
+
+    async fn acquire(&self, xid: u64, target: LockTarget, mode: Mode) -> Result<(), StorageError> {
+        let granted = {
+            let mut table = self.table.borrow_mut();
+            let entry = table.entries.entry(target).or_default();
+            let held = entry.held(xid);
+            if held.is_some_and(|held| held.covers(mode)) {
+                return Ok(());
+            }
+            let mode = held.map_or(mode, |held| held.combine(mode));
+            if entry.fits(xid, mode) && (held.is_some() || entry.waiting.is_empty()) {
+                entry.grant(xid, mode);
+                table.held.entry(xid).or_default().insert(target);
+                return Ok(());
+            }
+            if table.deadlocks(xid, target, mode) {
+                return Err(StorageError::Deadlock(xid));
+            }
+            let (grant, granted) = oneshot::channel();
+            let waiter = Waiter { xid, mode, grant };
+            let entry = table.entries.get_mut(&target).unwrap();
+            // An upgrade waits for the other holders only.
+            if held.is_some() {
+                entry.waiting.push_front(waiter);
+            } else {
+                entry.waiting.push_back(waiter);
+            }
+            table.held.entry(xid).or_default().insert(target);
+            table.waits.insert(xid, (target, mode));
+            granted
+        };
+
+        let mut cancel = CancelOnDrop { locks: self, xid, target, armed: true };
+        // The sender is only dropped with the manager.
+        let _ = granted.await;
+        cancel.armed = false;
+        Ok(())
+    }
+}
+
+// Takes a waiter out of the queue if its future is dropped before the lock is granted
+struct CancelOnDrop<'a> {
+    locks: &'a LockManager,
+    xid: u64,
+    target: LockTarget,
+    armed: bool,
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let mut table = self.locks.table.borrow_mut();
+        let Table { entries, held, waits } = &mut *table;
+        waits.remove(&self.xid);
+        let Some(entry) = entries.get_mut(&self.target) else {
+            return;
+        };
+        entry.waiting.retain(|waiter| waiter.xid != self.xid);
+        let holds = entry.held(self.xid).is_some();
+        entry.wake(waits);
+        if entry.granted.is_empty() && entry.waiting.is_empty() {
+            entries.remove(&self.target);
+        }
+        if !holds {
+            if let Some(targets) = held.get_mut(&self.xid) {
+                targets.remove(&self.target);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    const PAGE: LockTarget = LockTarget { db_id: 1, space_id: 1, page_no: 7, slot: None };
+
+    #[test]
+    fn upgrade_waits_only_for_other_holders() {
+        let locks = LockManager::new();
+        assert!(matches!(locks.lock(1, PAGE, LockMode::Shared).now_or_never(), Some(Ok(()))));
+        assert!(matches!(locks.lock(3, PAGE, LockMode::Shared).now_or_never(), Some(Ok(()))));
+        let mut exclusive = Box::pin(locks.lock(2, PAGE, LockMode::Exclusive));
+        assert!((&mut exclusive).now_or_never().is_none());
+
+        // T2 waits for T1, but T1's upgrade goes ahead of it and waits for T3 alone.
+        let mut upgrade = Box::pin(locks.lock(1, PAGE, LockMode::Exclusive));
+        assert!((&mut upgrade).now_or_never().is_none());
+
+        locks.release_all(3);
+        assert!(matches!((&mut upgrade).now_or_never(), Some(Ok(()))));
+        assert_eq!(locks.held(1, PAGE), Some(LockMode::Exclusive));
+        assert!((&mut exclusive).now_or_never().is_none());
+
+        locks.release_all(1);
+        assert!(matches!((&mut exclusive).now_or_never(), Some(Ok(()))));
+        assert_eq!(locks.held(2, PAGE), Some(LockMode::Exclusive));
+    }
+
+    #[test]
+    fn upgrade_deadlocks_on_another_upgrade() {
+        let locks = LockManager::new();
+        assert!(matches!(locks.lock(1, PAGE, LockMode::Shared).now_or_never(), Some(Ok(()))));
+        assert!(matches!(locks.lock(2, PAGE, LockMode::Shared).now_or_never(), Some(Ok(()))));
+        let mut upgrade = Box::pin(locks.lock(1, PAGE, LockMode::Exclusive));
+        assert!((&mut upgrade).now_or_never().is_none());
+        assert!(matches!(locks.lock(2, PAGE, LockMode::Exclusive).now_or_never(), Some(Err(StorageError::Deadlock(2)))));
+    }
+
+    #[test]
+    fn granting_clears_the_wait() {
+        let locks = LockManager::new();
+        let other = LockTarget { page_no: 8, ..PAGE };
+        assert!(matches!(locks.lock(1, PAGE, LockMode::Exclusive).now_or_never(), Some(Ok(()))));
+        assert!(matches!(locks.lock(2, other, LockMode::Exclusive).now_or_never(), Some(Ok(()))));
+        let mut waiting = Box::pin(locks.lock(2, PAGE, LockMode::Exclusive));
+        assert!((&mut waiting).now_or_never().is_none());
+        assert!(locks.table.borrow().waits.contains_key(&2));
+
+        // Granted on release, before T2 runs again to see it.
+        locks.release_all(1);
+        assert!(!locks.table.borrow().waits.contains_key(&2));
+
+        // T3 waits for T2, which waits for no one: no deadlock.
+        let mut blocked = Box::pin(locks.lock(3, other, LockMode::Shared));
+        assert!((&mut blocked).now_or_never().is_none());
+        assert!(matches!((&mut waiting).now_or_never(), Some(Ok(()))));
+        locks.release_all(2);
+        assert!(matches!((&mut blocked).now_or_never(), Some(Ok(()))));
+    }
+}
@@ -0,0 +1,1144 @@
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::lock::Mutex;
+
+use crate::bloom::{self, BloomFilter, BloomMetrics, BloomStats};
+use crate::buffer_pool::BufferPool;
+use crate::checksum;
+use crate::page::{self, PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::segment::EXTENT_PAGES;
+use crate::trace;
+use crate::traits::{AlignedBuf, Lsn, PageId, PageState, PageStore, StorageError, WalStore};
+use crate::wal_checkpoint::DirtyPage;
+use crate::wal_record::WalRecordType;
+use crate::wal_registry::{PageRecord, WalRecord, WalRegistry};
+
+// -----------------------------------------------------------------------------
+// LSM Trees
+//
+// A key-value store for write-heavy work, next to the B-tree and the heap: writes
+// go to memory and reach disk in big sequential runs, never updating a page in place.
+// A tree has a space of its own. Writes go into the memtable, sorted in memory, and
+// are logged as LSM_WRITE records; once it holds `memtable_bytes` it is frozen, a new
+// one takes the writes, and the frozen one is written out as SSTs (sorted string
+// tables) of level 0. An SST is a run of pages written with one `write_pages`,
+// bypassing the buffer pool, and never changed:
+//
+//   data pages     count u16 @24, then from 32 its entries, sorted:
+//                  key_len u16 | value_len u32 (TOMBSTONE: deleted) | key | value
+//   index pages    count u16 @24, then from 32 key_len u16 | key: the first key of
+//                  each data page, then the SST's last key
+//   bloom pages    a Bloom filter over the SST's keys, tombstones too, of
+//                  `bloom_bits_per_key` bits each (see `bloom.rs`); none at 0
+//
+// A tree's SSTs are listed in its manifest, page 0 of its space:
+//
+//   [24..32)  flushed_lsn   LSN of the oldest write not in an SST
+//   [32..40)  next_id       id of the next SST
+//   [40..42)  count         SSTs listed, at most MAX_SSTS
+//   [48..)    SSTs          id u64 | level u8 | reserved 1 | bloom_pages u16 |
+//                           first_page u32 | data_pages u32 | index_pages u32
+//
+// Each change rewrites it with an LSM_MANIFEST page record, logged and flushed before
+// anything goes on relying on it (page = db_id u32 | space_id u32 | page_no u32):
+//
+//   LSM_WRITE        db_id u32 | space_id u32 | key_len u16 | value_len u32 | key | value
+//   LSM_MANIFEST     page | flushed_lsn u64 | next_id u64 | count u16 | SSTs
+//
+// Level 0 holds memtables as they were written, so its SSTs overlap, newer ones
+// winning. Every level below holds SSTs of disjoint key ranges, each level up to
+// `level_fanout` times more pages than the one above. Compaction merges all of level
+// 0 into level 1 once it has `l0_trigger` SSTs, and one SST of a level over its size
+// into the SSTs it overlaps in the next, taking the SSTs of a level in turn. Deletes
+// are tombstones, dropped once merged into the lowest level holding anything. A lookup
+// reads the memtables, then level 0 newest first, then one SST per level below, and
+// takes the first hit, skipping the SSTs whose filter rules the key out; a scan merges
+// them all. Filters are read when an SST is opened and kept in memory, the other SST
+// pages aren't cached: every lookup in one reads its page. How often filters let a
+// lookup through for nothing is counted (`Lsm::bloom_metrics`).
+//
+// Recovery replays the manifest like any page. LSM_WRITE records are collected by the
+// `LsmRecovery` of each core's registry (`register`), and `Lsm::open` rebuilds the
+// memtable from those from `flushed_lsn` on. For redo to start early enough and the
+// log to be kept, checkpoints count a tree's memtables as its manifest page dirty since
+// their oldest write (`Lsm::unflushed`, `Checkpointer::with_unflushed`). Replaced SSTs
+// are freed once no lookup or scan still reads them; SSTs written but not in the
+// manifest when a flush or compaction fails, or the process crashes, are leaked.
+//
+// Like a B-tree, a tree is used through the pool it is opened on only (see
+// `btree.rs`), the pool of the core owning its manifest page, which logs its writes.
+// -----------------------------------------------------------------------------
+
+/// Page types of LSM pages.
+pub const PAGE_TYPE_LSM_MANIFEST: u16 = 10;
+pub const PAGE_TYPE_LSM_DATA: u16 = 11;
+pub const PAGE_TYPE_LSM_INDEX: u16 = 12;
+
+/// Page of a tree's space holding its manifest.
+pub const MANIFEST_PAGE: u32 = 0;
+
+/// Value length of a deleted key.
+pub const TOMBSTONE: u32 = u32::MAX;
+
+/// Levels of a tree; the last one grows without bound.
+pub const MAX_LEVELS: usize = 7;
+
+const COUNT_OFFSET: usize = 24;
+const ENTRIES_OFFSET: usize = 32;
+const ENTRY_HEADER_SIZE: usize = 6;
+
+/// Bytes of an entry's key and value together, with its header.
+pub const MAX_ENTRY_SIZE: usize = PAGE_SIZE - ENTRIES_OFFSET;
+
+const FLUSHED_LSN_OFFSET: usize = 24;
+const NEXT_ID_OFFSET: usize = 32;
+const SST_COUNT_OFFSET: usize = 40;
+const SSTS_OFFSET: usize = 48;
+const SST_META_SIZE: usize = 24;
+
+/// SSTs a tree can have.
+pub const MAX_SSTS: usize = (PAGE_SIZE - SSTS_OFFSET) / SST_META_SIZE;
+
+/// Sizes of a tree's memtable, SSTs and levels, and how often it is compacted.
+#[derive(Debug, Clone)]
+pub struct LsmConfig {
+    /// Bytes of entries the memtable takes before it is frozen and written out.
+    pub memtable_bytes: usize,
+    /// Most data pages of an SST; a bigger flush or compaction writes several.
+    pub sst_pages: u32,
+    /// SSTs in level 0 that trigger a compaction into level 1.
+    pub l0_trigger: usize,
+    /// Pages of level 1 before it is compacted into level 2.
+    pub level_base_pages: u64,
+    /// How many times more pages each level below 1 holds than the one above.
+    pub level_fanout: u64,
+    /// Pause between rounds of `Lsm::run_compaction`.
+    pub compaction_interval: Duration,
+    /// Bits a key of the Bloom filters of new SSTs; 0: SSTs without filters.
+    pub bloom_bits_per_key: u32,
+}
+
+impl Default for LsmConfig {
+    fn default() -> Self {
+        Self {
+            memtable_bytes: 4 << 20,
+            sst_pages: 512,
+            l0_trigger: 4,
+            level_base_pages: 8192,
+            level_fanout: 10,
+            compaction_interval: Duration::from_millis(100),
+            bloom_bits_per_key: bloom::DEFAULT_BITS_PER_KEY,
+        }
+    }
+}
+
+// A key, and its value or `None` for a tombstone
+type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+#[derive(Default)]
+struct Memtable {
+    entries: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    bytes: usize,
+    // LSNs of its oldest and newest writes
+    first_lsn: Option<Lsn>,
+    last_lsn: Option<Lsn>,
+}
+
+impl Memtable {
+    fn insert(&mut self, lsn: Lsn, key: Vec<u8>, value: Option<Vec<u8>>) {
+        let key_len = key.len();
+        self.bytes += ENTRY_HEADER_SIZE + key_len + value.as_ref().map_or(0, Vec::len);
+        if let Some(old) = self.entries.insert(key, value) {
+            self.bytes -= ENTRY_HEADER_SIZE + key_len + old.map_or(0, |old| old.len());
+        }
+        self.first_lsn.get_or_insert(lsn);
+        self.last_lsn = Some(lsn);
+    }
+
+    fn range(&self, start: &[u8], end: Option<&[u8]>) -> Vec<Entry> {
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        self.entries
+            .range::<[u8], _>((Bound::Included(start), end))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+}
+
+/// An SST as the manifest lists it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SstMeta {
+    pub id: u64,
+    pub level: u8,
+    pub first_page: u32,
+    pub data_pages: u32,
+    pub index_pages: u32,
+    pub bloom_pages: u32,
+}
+
+impl SstMeta {
+    /// Pages the SST takes, whole extents.
+    pub fn allocated_pages(&self) -> u32 {
+        (self.data_pages + self.index_pages + self.bloom_pages).next_multiple_of(EXTENT_PAGES)
+    }
+
+    fn encode(&self, out: &mut [u8]) {
+        out[0..8].copy_from_slice(&self.id.to_le_bytes());
+        out[8] = self.level;
+        out[9] = 0;
+        out[10..12].copy_from_slice(&(self.bloom_pages as u16).to_le_bytes());
+        out[12..16].copy_from_slice(&self.first_page.to_le_bytes());
+        out[16..20].copy_from_slice(&self.data_pages.to_le_bytes());
+        out[20..24].copy_from_slice(&self.index_pages.to_le_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        Self {
+            id: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            level: bytes[8],
+            first_page: get_u32(bytes, 12),
+            data_pages: get_u32(bytes, 16),
+            index_pages: get_u32(bytes, 20),
+            bloom_pages: get_u16(bytes, 10) as u32,
+        }
+    }
+}
+
+struct Sst {
+    meta: SstMeta,
+    // First key of each data page, then the last key
+    keys: Vec<Vec<u8>>,
+    bloom: Option<BloomFilter>,
+}
+
+impl Sst {
+    fn min(&self) -> &[u8] {
+        &self.keys[0]
+    }
+
+    fn max(&self) -> &[u8] {
+        self.keys.last().unwrap()
+    }
+
+    fn covers(&self, key: &[u8]) -> bool {
+        self.min() <= key && key <= self.max()
+    }
+
+    fn overlaps(&self, low: &[u8], high: &[u8]) -> bool {
+        self.min() <= high && low <= self.max()
+    }
+
+    // Data page that holds `key` if the SST does
+    fn page_for(&self, key: &[u8]) -> u32 {
+        let firsts = &self.keys[..self.meta.data_pages as usize];
+        firsts.partition_point(|first| first.as_slice() <= key).saturating_sub(1) as u32
+    }
+}
+
+// Level 0 newest first, the others by key
+fn arrange(ssts: Vec<Rc<Sst>>) -> Vec<Vec<Rc<Sst>>> {
+    let mut levels = vec![Vec::new(); MAX_LEVELS];
+    for sst in ssts {
+        levels[sst.meta.level as usize].push(sst);
+    }
+    levels[0].sort_by_key(|sst| Reverse(sst.meta.id));
+    for level in &mut levels[1..] {
+        level.sort_by(|a, b| a.min().cmp(b.min()));
+    }
+    levels
+}
+
+/// The LSM tree in space `space_id` of database `db_id`, over `pool`.
+pub struct Lsm<'a, S: PageStore + WalStore> {
+    pool: &'a BufferPool<S>,
+    db_id: u32,
+    space_id: u32,
+    config: LsmConfig,
+    active: RefCell<Memtable>,
+    // Being written out as level 0
+    frozen: RefCell<Option<Rc<Memtable>>>,
+    levels: RefCell<Vec<Vec<Rc<Sst>>>>,
+    flushed_lsn: Cell<Lsn>,
+    next_id: Cell<u64>,
+    bloom: BloomStats,
+    // Replaced SSTs, freed once nothing reads them
+    retired: RefCell<Vec<Rc<Sst>>>,
+    // Last key compacted out of each level, where the next compaction picks up
+    compact_from: RefCell<Vec<Option<Vec<u8>>>>,
+    // Held across a write's log append and memtable insert, and by a freeze
+    writing: Mutex<()>,
+    flushing: Mutex<()>,
+    compacting: Mutex<()>,
+}
+
+impl<'a, S: PageStore + WalStore> Lsm<'a, S> {
+    /// Opens the tree in space `space_id` of database `db_id`, creating it empty if
+    /// the space is new. After a crash, pass the `LsmRecovery` of this core's redo to
+    /// get back the writes that were only in the memtable.
+    pub async fn open(
+        pool: &'a BufferPool<S>,
+        db_id: u32,
+        space_id: u32,
+        config: LsmConfig,
+        recovered: Option<&LsmRecovery>,
+    ) -> Result<Self, StorageError> {
+        let page_id = PageId { db_id, space_id, page_no: MANIFEST_PAGE };
+        let mut manifest = pool.get_page_mut(page_id).await?;
+        if page::page_type(&manifest) != PAGE_TYPE_LSM_MANIFEST {
+            if !page::is_fresh(&manifest) {
+                return Err(StorageError::NotAnLsmTree(page_id));
+            }
+            // A create cut short may have allocated the page already, and this one is
+            // then leaked.
+            pool.store().allocate_extent(db_id, space_id, 1).await?;
+            manifest.apply(&LsmManifest { page_id, flushed_lsn: Lsn(0), next_id: 0, ssts: Vec::new() }).await?;
+        }
+        let flushed_lsn = Lsn(u64::from_le_bytes(manifest[FLUSHED_LSN_OFFSET..FLUSHED_LSN_OFFSET + 8].try_into().unwrap()));
+        let next_id = u64::from_le_bytes(manifest[NEXT_ID_OFFSET..NEXT_ID_OFFSET + 8].try_into().unwrap());
+        let metas = manifest_ssts(&manifest);
+        drop(manifest);
+
+        let lsm = Self {
+            pool,
+            db_id,
+            space_id,
+            config,
+            active: RefCell::new(Memtable::default()),
+            frozen: RefCell::new(None),
+            levels: RefCell::new(arrange(Vec::new())),
+            flushed_lsn: Cell::new(flushed_lsn),
+            next_id: Cell::new(next_id),
+            bloom: BloomStats::new(),
+            retired: RefCell::new(Vec::new()),
+            compact_from: RefCell::new(vec![None; MAX_LEVELS]),
+            writing: Mutex::new(()),
+            flushing: Mutex::new(()),
+            compacting: Mutex::new(()),
+        };
+        let mut ssts = Vec::with_capacity(metas.len());
+        for meta in metas {
+            let keys = lsm.read_index(&meta).await?;
+            let bloom = lsm.read_bloom(&meta).await?;
+            ssts.push(Rc::new(Sst { meta, keys, bloom }));
+        }
+        *lsm.levels.borrow_mut() = arrange(ssts);
+        if let Some(recovered) = recovered {
+            let mut active = lsm.active.borrow_mut();
+            for (lsn, write) in recovered.take(db_id, space_id) {
+                if lsn >= flushed_lsn {
+                    active.insert(lsn, write.key, write.value);
+                }
+            }
+        }
+        Ok(lsm)
+    }
+
+    pub fn db_id(&self) -> u32 {
+        self.db_id
+    }
+
+    pub fn space_id(&self) -> u32 {
+        self.space_id
+    }
+
+    /// Sets `key` to `value`. The write is logged but only durable once the WAL is
+    /// flushed (`sync`).
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.write(key, Some(value)).await
+    }
+
+    /// Deletes `key`, if set. Durable like `put`.
+    pub async fn delete(&self, key: &[u8]) -> Result<(), StorageError> {
+        self.write(key, None).await
+    }
+
+    /// Makes every write so far durable.
+    pub async fn sync(&self) -> Result<(), StorageError> {
+        self.pool.store().flush_wal(self.db_id).await
+    }
+
+    /// The value of `key`, if set.
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        if let Some(value) = self.active.borrow().entries.get(key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.frozen.borrow().as_ref().and_then(|frozen| frozen.entries.get(key)) {
+            return Ok(value.clone());
+        }
+        let hash = bloom::key_hash(key);
+        let levels = self.levels.borrow().clone();
+        for (level, ssts) in levels.iter().enumerate() {
+            let candidates: Vec<&Rc<Sst>> = match level {
+                0 => ssts.iter().filter(|sst| sst.covers(key)).collect(),
+                _ => {
+                    let at = ssts.partition_point(|sst| sst.max() < key);
+                    ssts.get(at).filter(|sst| sst.covers(key)).into_iter().collect()
+                }
+            };
+            for sst in candidates {
+                if sst.bloom.as_ref().is_some_and(|filter| !self.bloom.check(filter, hash)) {
+                    continue;
+                }
+                let mut entries = self.read_entries(sst, sst.page_for(key)).await?;
+                if let Ok(at) = entries.binary_search_by(|(entry_key, _)| entry_key.as_slice().cmp(key)) {
+                    return Ok(entries.swap_remove(at).1);
+                }
+                if sst.bloom.is_some() {
+                    self.bloom.false_positive();
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The keys set from `start` up to `end` (exclusive; `None`: to the last), in order,
+    /// with their values: at most `limit` of them.
+    pub async fn scan(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StorageError> {
+        // Newest first
+        let mut cursors = vec![Cursor::memory(self.active.borrow().range(start, end))];
+        if let Some(frozen) = self.frozen.borrow().as_ref() {
+            cursors.push(Cursor::memory(frozen.range(start, end)));
+        }
+        let levels = self.levels.borrow().clone();
+        for sst in &levels[0] {
+            cursors.push(self.seek(vec![sst.clone()], start).await?);
+        }
+        for ssts in &levels[1..] {
+            cursors.push(self.seek(ssts.clone(), start).await?);
+        }
+
+        let mut found = Vec::new();
+        while found.len() < limit {
+            let Some((key, value)) = self.merge_next(&mut cursors).await? else {
+                break;
+            };
+            if end.is_some_and(|end| key.as_slice() >= end) {
+                break;
+            }
+            if let Some(value) = value {
+                found.push((key, value));
+            }
+        }
+        Ok(found)
+    }
+
+    /// Writes everything in memory out as level-0 SSTs.
+    pub async fn flush(&self) -> Result<(), StorageError> {
+        self.write_frozen().await?;
+        if self.freeze().await {
+            self.write_frozen().await?;
+        }
+        Ok(())
+    }
+
+    /// Where the tree's writes held in memory start: its manifest page, dirty since
+    /// the oldest of them, for checkpoints to keep the log from there.
+    pub fn unflushed(&self) -> Option<DirtyPage> {
+        let frozen = self.frozen.borrow().as_ref().and_then(|frozen| frozen.first_lsn);
+        let rec_lsn = frozen.or(self.active.borrow().first_lsn)?;
+        Some(DirtyPage { page_id: self.page(MANIFEST_PAGE), rec_lsn })
+    }
+
+    /// How the SSTs' Bloom filters did for the lookups since the tree was opened.
+    pub fn bloom_metrics(&self) -> BloomMetrics {
+        self.bloom.metrics()
+    }
+
+    /// The SSTs of each level, level 0 first.
+    pub fn ssts(&self) -> Vec<Vec<SstMeta>> {
+        self.levels.borrow().iter().map(|level| level.iter().map(|sst| sst.meta).collect()).collect()
+    }
+
+    /// Runs the tree's background work: every `compaction_interval`, a `maintain`
+    /// round. Never returns; spawn it on the pool's core next to the foreground work.
+    pub async fn run_compaction(&self) {
+        loop {
+            if let Err(e) = self.maintain().await {
+                trace::warn_event!("lsm: db {} space {}: compaction failed: {:?}", self.db_id, self.space_id, e);
+            }
+            tokio::time::sleep(self.config.compaction_interval).await;
+        }
+    }
+
+    /// Writes out a frozen memtable, then compacts until no level is over its size, and
+    /// frees the SSTs replaced that nothing reads anymore.
+    pub async fn maintain(&self) -> Result<(), StorageError> {
+        self.write_frozen().await?;
+        while self.compact().await? {}
+        self.free_retired().await
+    }
+
+    /// One compaction, if a level calls for one. Returns whether it did one.
+    pub async fn compact(&self) -> Result<bool, StorageError> {
+        let _compacting = self.compacting.lock().await;
+        let levels = self.levels.borrow().clone();
+        let Some((level, inputs)) = self.pick(&levels) else {
+            return Ok(false);
+        };
+        let output = level + 1;
+        let low = inputs.iter().map(|sst| sst.min()).min().unwrap().to_vec();
+        let high = inputs.iter().map(|sst| sst.max()).max().unwrap().to_vec();
+        let below: Vec<Rc<Sst>> = levels[output].iter().filter(|sst| sst.overlaps(&low, &high)).cloned().collect();
+        // Nothing older left for a tombstone to hide
+        let bottom = levels[output + 1..].iter().all(Vec::is_empty);
+
+        // Newest first: level 0 SSTs one by one, a lower level's as one run
+        let mut cursors = Vec::new();
+        match level {
+            0 => {
+                for sst in &inputs {
+                    cursors.push(self.seek(vec![sst.clone()], &[]).await?);
+                }
+            }
+            _ => cursors.push(self.seek(inputs.clone(), &[]).await?),
+        }
+        cursors.push(self.seek(below.clone(), &[]).await?);
+        let mut builder = SstBuilder::new(output as u8);
+        while let Some((key, value)) = self.merge_next(&mut cursors).await? {
+            if value.is_none() && bottom {
+                continue;
+            }
+            builder.add(self, &key, value.as_deref()).await?;
+        }
+        let added = builder.finish(self).await?;
+
+        let replaced: Vec<Rc<Sst>> = inputs.into_iter().chain(below).collect();
+        let removed: Vec<u64> = replaced.iter().map(|sst| sst.meta.id).collect();
+        self.install(added, &removed, None).await?;
+        if level > 0 {
+            self.compact_from.borrow_mut()[level] = Some(high);
+        }
+        self.retired.borrow_mut().extend(replaced);
+        Ok(true)
+    }
+
+    // The level due for compaction and the SSTs of it to merge down: all of level 0,
+    // or the next SST in turn of the first level over its size
+    fn pick(&self, levels: &[Vec<Rc<Sst>>]) -> Option<(usize, Vec<Rc<Sst>>)> {
+        if levels[0].len() >= self.config.l0_trigger.max(1) {
+            return Some((0, levels[0].clone()));
+        }
+        let mut limit = self.config.level_base_pages;
+        for (level, ssts) in levels.iter().enumerate().take(MAX_LEVELS - 1).skip(1) {
+            let pages: u64 = ssts.iter().map(|sst| sst.meta.allocated_pages() as u64).sum();
+            if pages > limit {
+                let from = self.compact_from.borrow()[level].clone();
+                let next = from.and_then(|from| ssts.iter().find(|sst| sst.min() > from.as_slice()));
+                return Some((level, vec![next.unwrap_or(&ssts[0]).clone()]));
+            }
+            limit = limit.saturating_mul(self.config.level_fanout);
+        }
+        None
+    }
+
+    async fn write(&self, key: &[u8], value: Option<&[u8]>) -> Result<(), StorageError> {
+        let size = ENTRY_HEADER_SIZE + key.len() + value.map_or(0, <[u8]>::len);
+        if size > MAX_ENTRY_SIZE {
+            return Err(StorageError::TupleTooLarge(size));
+        }
+        let record = LsmWrite {
+            db_id: self.db_id,
+            space_id: self.space_id,
+            key: key.to_vec(),
+            value: value.map(<[u8]>::to_vec),
+        };
+        {
+            let _writing = self.writing.lock().await;
+            let lsn = self.pool.store().append_wal(self.db_id, LsmWrite::TYPE, &record.to_payload()).await?;
+            self.active.borrow_mut().insert(lsn, record.key, record.value);
+        }
+        let full = self.active.borrow().bytes >= self.config.memtable_bytes;
+        if full && !self.freeze().await {
+            // The last one is still being written out: writers wait for it.
+            self.write_frozen().await?;
+            self.freeze().await;
+        }
+        Ok(())
+    }
+
+    // Freezes the memtable, unless one is frozen already or it is empty. Returns
+    // whether it did.
+    async fn freeze(&self) -> bool {
+        let _writing = self.writing.lock().await;
+        if self.frozen.borrow().is_some() || self.active.borrow().entries.is_empty() {
+            return false;
+        }
+        let table = std::mem::take(&mut *self.active.borrow_mut());
+        *self.frozen.borrow_mut() = Some(Rc::new(table));
+        true
+    }
+
+    // Writes the frozen memtable, if any, out as level 0
+    async fn write_frozen(&self) -> Result<(), StorageError> {
+        let _flushing = self.flushing.lock().await;
+        let Some(frozen) = self.frozen.borrow().clone() else {
+            return Ok(());
+        };
+        let mut builder = SstBuilder::new(0);
+        for (key, value) in &frozen.entries {
+            builder.add(self, key, value.as_deref()).await?;
+        }
+        let added = builder.finish(self).await?;
+        let flushed_lsn = {
+            let _writing = self.writing.lock().await;
+            let next = Lsn(frozen.last_lsn.unwrap().0 + 1);
+            self.active.borrow().first_lsn.unwrap_or(next)
+        };
+        self.install(added, &[], Some(flushed_lsn)).await?;
+        *self.frozen.borrow_mut() = None;
+        Ok(())
+    }
+
+    // Lists `added` and unlists `removed` in the manifest, durably
+    async fn install(&self, added: Vec<Rc<Sst>>, removed: &[u64], flushed_lsn: Option<Lsn>) -> Result<(), StorageError> {
+        let page_id = self.page(MANIFEST_PAGE);
+        let mut manifest = self.pool.get_page_mut(page_id).await?;
+        let mut ssts: Vec<Rc<Sst>> = self
+            .levels
+            .borrow()
+            .iter()
+            .flatten()
+            .filter(|sst| !removed.contains(&sst.meta.id))
+            .cloned()
+            .collect();
+        ssts.extend(added);
+        if ssts.len() > MAX_SSTS {
+            return Err(StorageError::LsmManifestFull(page_id));
+        }
+        let flushed_lsn = flushed_lsn.unwrap_or(self.flushed_lsn.get());
+        let record = LsmManifest {
+            page_id,
+            flushed_lsn,
+            next_id: self.next_id.get(),
+            ssts: ssts.iter().map(|sst| sst.meta).collect(),
+        };
+        manifest.apply(&record).await?;
+        self.pool.store().flush_wal(self.db_id).await?;
+        self.flushed_lsn.set(flushed_lsn);
+        *self.levels.borrow_mut() = arrange(ssts);
+        Ok(())
+    }
+
+    async fn free_retired(&self) -> Result<(), StorageError> {
+        let unread: Vec<Rc<Sst>> = {
+            let mut retired = self.retired.borrow_mut();
+            let (unread, read) = retired.drain(..).partition(|sst| Rc::strong_count(sst) == 1);
+            *retired = read;
+            unread
+        };
+        for (at, sst) in unread.iter().enumerate() {
+            let meta = sst.meta;
+            if let Err(e) = self.pool.store().free_extent(self.db_id, self.space_id, meta.first_page, meta.allocated_pages()).await {
+                self.retired.borrow_mut().extend(unread[at..].iter().cloned());
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // A cursor over `ssts`, disjoint and in key order, from the first key at or after
+    // `start`
+    async fn seek(&self, ssts: Vec<Rc<Sst>>, start: &[u8]) -> Result<Cursor, StorageError> {
+        let first = ssts.iter().position(|sst| sst.max() >= start).unwrap_or(ssts.len());
+        let mut cursor = Cursor { ssts, sst: first, page: 0, entries: Vec::new(), pos: 0 };
+        if let Some(sst) = cursor.ssts.get(first).cloned() {
+            cursor.page = sst.page_for(start);
+            cursor.entries = self.read_entries(&sst, cursor.page).await?;
+            cursor.pos = cursor.entries.partition_point(|(key, _)| key.as_slice() < start);
+            self.fill(&mut cursor).await?;
+        }
+        Ok(cursor)
+    }
+
+    // Moves `cursor` on to the next page with entries left, if it is past its page's
+    async fn fill(&self, cursor: &mut Cursor) -> Result<(), StorageError> {
+        while cursor.pos >= cursor.entries.len() {
+            let Some(sst) = cursor.ssts.get(cursor.sst) else {
+                return Ok(());
+            };
+            if cursor.page + 1 < sst.meta.data_pages {
+                cursor.page += 1;
+            } else {
+                cursor.sst += 1;
+                cursor.page = 0;
+            }
+            let Some(sst) = cursor.ssts.get(cursor.sst).cloned() else {
+                return Ok(());
+            };
+            cursor.entries = self.read_entries(&sst, cursor.page).await?;
+            cursor.pos = 0;
+        }
+        Ok(())
+    }
+
+    // The smallest key of `cursors` with the value of the first, newest, holding it;
+    // every cursor holding it moves past it
+    async fn merge_next(&self, cursors: &mut [Cursor]) -> Result<Option<Entry>, StorageError> {
+        let Some(key) = cursors.iter().filter_map(Cursor::peek).min().map(<[u8]>::to_vec) else {
+            return Ok(None);
+        };
+        let mut found = None;
+        for cursor in cursors.iter_mut() {
+            if cursor.peek() == Some(key.as_slice()) {
+                let entry = std::mem::take(&mut cursor.entries[cursor.pos]);
+                found.get_or_insert(entry);
+                cursor.pos += 1;
+                self.fill(cursor).await?;
+            }
+        }
+        Ok(found)
+    }
+
+    async fn read_entries(&self, sst: &Sst, page_no: u32) -> Result<Vec<Entry>, StorageError> {
+        let page = self.read_page(sst.meta.first_page + page_no, PAGE_TYPE_LSM_DATA).await?;
+        let mut entries = Vec::with_capacity(get_u16(&page, COUNT_OFFSET) as usize);
+        let mut at = ENTRIES_OFFSET;
+        for _ in 0..get_u16(&page, COUNT_OFFSET) {
+            let key_len = get_u16(&page, at) as usize;
+            let value_len = get_u32(&page, at + 2);
+            at += ENTRY_HEADER_SIZE;
+            let key = page[at..at + key_len].to_vec();
+            at += key_len;
+            let value = (value_len != TOMBSTONE).then(|| {
+                let value = page[at..at + value_len as usize].to_vec();
+                at += value_len as usize;
+                value
+            });
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+
+    async fn read_index(&self, meta: &SstMeta) -> Result<Vec<Vec<u8>>, StorageError> {
+        let mut keys = Vec::with_capacity(meta.data_pages as usize + 1);
+        for page_no in 0..meta.index_pages {
+            let page = self.read_page(meta.first_page + meta.data_pages + page_no, PAGE_TYPE_LSM_INDEX).await?;
+            let mut at = ENTRIES_OFFSET;
+            for _ in 0..get_u16(&page, COUNT_OFFSET) {
+                let key_len = get_u16(&page, at) as usize;
+                keys.push(page[at + 2..at + 2 + key_len].to_vec());
+                at += 2 + key_len;
+            }
+        }
+        if keys.len() != meta.data_pages as usize + 1 {
+            return Err(StorageError::Corruption(self.page(meta.first_page + meta.data_pages)));
+        }
+        Ok(keys)
+    }
+
+    async fn read_bloom(&self, meta: &SstMeta) -> Result<Option<BloomFilter>, StorageError> {
+        if meta.bloom_pages == 0 {
+            return Ok(None);
+        }
+        let page_id = self.page(meta.first_page + meta.data_pages + meta.index_pages);
+        let filter = bloom::read_filter(&**self.pool.store(), page_id).await?;
+        if filter.pages() != meta.bloom_pages {
+            return Err(StorageError::Corruption(page_id));
+        }
+        Ok(Some(filter))
+    }
+
+    async fn read_page(&self, page_no: u32, page_type: u16) -> Result<AlignedBuf, StorageError> {
+        let page_id = self.page(page_no);
+        let (page, res) = self.pool.store().read_page(page_id, AlignedBuf::page()).await;
+        match res? {
+            PageState::Written if page::page_type(&page) == page_type => Ok(page),
+            _ => Err(StorageError::Corruption(page_id)),
+        }
+    }
+
+    fn page(&self, page_no: u32) -> PageId {
+        PageId {
+            db_id: self.db_id,
+            space_id: self.space_id,
+            page_no,
+        }
+    }
+}
+
+// Where a scan or compaction is in one source: a memtable's entries, or a run of SSTs
+struct Cursor {
+    ssts: Vec<Rc<Sst>>,
+    sst: usize,
+    page: u32,
+    entries: Vec<Entry>,
+    pos: usize,
+}
+
+impl Cursor {
+    fn memory(entries: Vec<Entry>) -> Self {
+        Self { ssts: Vec::new(), sst: 0, page: 0, entries, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<&[u8]> {
+        self.entries.get(self.pos).map(|(key, _)| key.as_slice())
+    }
+}
+
+// Writes sorted entries out as SSTs of one level, up to `sst_pages` data pages each
+struct SstBuilder {
+    level: u8,
+    pages: Vec<AlignedBuf>,
+    keys: Vec<Vec<u8>>,
+    last_key: Vec<u8>,
+    // Of the keys of the SST being built, for its filter
+    hashes: Vec<u64>,
+    page: AlignedBuf,
+    count: u16,
+    at: usize,
+    written: Vec<Rc<Sst>>,
+}
+
+impl SstBuilder {
+    fn new(level: u8) -> Self {
+        Self {
+            level,
+            pages: Vec::new(),
+            keys: Vec::new(),
+            last_key: Vec::new(),
+            hashes: Vec::new(),
+            page: AlignedBuf::page(),
+            count: 0,
+            at: ENTRIES_OFFSET,
+            written: Vec::new(),
+        }
+    }
+
+    async fn add<S: PageStore + WalStore>(&mut self, lsm: &Lsm<'_, S>, key: &[u8], value: Option<&[u8]>) -> Result<(), StorageError> {
+        let size = ENTRY_HEADER_SIZE + key.len() + value.map_or(0, <[u8]>::len);
+        if self.count > 0 && self.at + size > PAGE_SIZE {
+            self.seal();
+            if self.pages.len() >= lsm.config.sst_pages.max(1) as usize {
+                self.write(lsm).await?;
+            }
+        }
+        if self.count == 0 {
+            self.keys.push(key.to_vec());
+        }
+        let page = &mut self.page;
+        page[self.at..self.at + 2].copy_from_slice(&(key.len() as u16).to_le_bytes());
+        let value_len = value.map_or(TOMBSTONE, |value| value.len() as u32);
+        page[self.at + 2..self.at + 6].copy_from_slice(&value_len.to_le_bytes());
+        self.at += ENTRY_HEADER_SIZE;
+        page[self.at..self.at + key.len()].copy_from_slice(key);
+        self.at += key.len();
+        if let Some(value) = value {
+            page[self.at..self.at + value.len()].copy_from_slice(value);
+            self.at += value.len();
+        }
+        self.count += 1;
+        self.last_key = key.to_vec();
+        self.hashes.push(bloom::key_hash(key));
+        Ok(())
+    }
+
+    async fn finish<S: PageStore + WalStore>(mut self, lsm: &Lsm<'_, S>) -> Result<Vec<Rc<Sst>>, StorageError> {
+        if self.count > 0 {
+            self.seal();
+        }
+        if !self.pages.is_empty() {
+            self.write(lsm).await?;
+        }
+        Ok(self.written)
+    }
+
+    fn seal(&mut self) {
+        let mut page = std::mem::replace(&mut self.page, AlignedBuf::page());
+        page[COUNT_OFFSET..COUNT_OFFSET + 2].copy_from_slice(&self.count.to_le_bytes());
+        page::set_page_type(&mut page, PAGE_TYPE_LSM_DATA);
+        self.pages.push(page);
+        self.count = 0;
+        self.at = ENTRIES_OFFSET;
+    }
+
+    // Writes the sealed pages out as one SST, with its index and filter
+    async fn write<S: PageStore + WalStore>(&mut self, lsm: &Lsm<'_, S>) -> Result<(), StorageError> {
+        let mut pages = std::mem::take(&mut self.pages);
+        let data_pages = pages.len() as u32;
+        let mut keys = std::mem::take(&mut self.keys);
+        keys.push(self.last_key.clone());
+
+        let mut index = AlignedBuf::page();
+        let mut count = 0u16;
+        let mut at = ENTRIES_OFFSET;
+        for key in &keys {
+            if at + 2 + key.len() > PAGE_SIZE {
+                index[COUNT_OFFSET..COUNT_OFFSET + 2].copy_from_slice(&count.to_le_bytes());
+                page::set_page_type(&mut index, PAGE_TYPE_LSM_INDEX);
+                pages.push(std::mem::replace(&mut index, AlignedBuf::page()));
+                count = 0;
+                at = ENTRIES_OFFSET;
+            }
+            index[at..at + 2].copy_from_slice(&(key.len() as u16).to_le_bytes());
+            index[at + 2..at + 2 + key.len()].copy_from_slice(key);
+            at += 2 + key.len();
+            count += 1;
+        }
+        index[COUNT_OFFSET..COUNT_OFFSET + 2].copy_from_slice(&count.to_le_bytes());
+        page::set_page_type(&mut index, PAGE_TYPE_LSM_INDEX);
+        pages.push(index);
+        let index_pages = pages.len() as u32 - data_pages;
+
+        let hashes = std::mem::take(&mut self.hashes);
+        let bloom = (lsm.config.bloom_bits_per_key > 0).then(|| BloomFilter::build(&hashes, lsm.config.bloom_bits_per_key));
+        if let Some(filter) = &bloom {
+            pages.extend(filter.to_pages());
+        }
+        let mut meta = SstMeta {
+            id: lsm.next_id.get(),
+            level: self.level,
+            first_page: 0,
+            data_pages,
+            index_pages,
+            bloom_pages: bloom.as_ref().map_or(0, BloomFilter::pages),
+        };
+        lsm.next_id.set(meta.id + 1);
+        meta.first_page = lsm.pool.store().allocate_extent(lsm.db_id, lsm.space_id, meta.allocated_pages()).await?;
+        for page in &mut pages {
+            checksum::stamp_page(lsm.pool.checksum(), page);
+        }
+        lsm.pool.store().write_pages(lsm.page(meta.first_page), pages).await.1?;
+        self.written.push(Rc::new(Sst { meta, keys, bloom }));
+        Ok(())
+    }
+}
+
+fn get_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn get_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn manifest_ssts(page: &[u8]) -> Vec<SstMeta> {
+    let count = get_u16(page, SST_COUNT_OFFSET) as usize;
+    (0..count)
+        .map(|index| {
+            let at = SSTS_OFFSET + index * SST_META_SIZE;
+            SstMeta::decode(&page[at..at + SST_META_SIZE])
+        })
+        .collect()
+}
+
+// Writes of each tree, by database and space, in log order
+type TreeWrites = HashMap<(u32, u32), Vec<(Lsn, LsmWrite)>>;
+
+/// The LSM writes a core's redo replayed, for `Lsm::open` to rebuild memtables from.
+/// Every core replays every tree's, and keeps them until its trees are opened.
+#[derive(Default)]
+pub struct LsmRecovery {
+    writes: RefCell<TreeWrites>,
+}
+
+impl LsmRecovery {
+    // The writes of one tree, in log order
+    fn take(&self, db_id: u32, space_id: u32) -> Vec<(Lsn, LsmWrite)> {
+        self.writes.borrow_mut().remove(&(db_id, space_id)).unwrap_or_default()
+    }
+}
+
+/// Registers the LSM record types, so recovery can replay them. The writes replayed
+/// are collected in the `LsmRecovery` returned.
+pub fn register(registry: &mut WalRegistry) -> Rc<LsmRecovery> {
+    let recovery = Rc::new(LsmRecovery::default());
+    let writes = recovery.clone();
+    registry.register::<LsmWrite>("lsm", move |lsn, write| {
+        let key = (write.db_id, write.space_id);
+        writes.writes.borrow_mut().entry(key).or_default().push((lsn, write));
+        Ok(())
+    });
+    registry.register_page::<LsmManifest>("lsm");
+    recovery
+}
+
+// -----------------------------------------------------------------------------
+// WAL records
+// -----------------------------------------------------------------------------
+
+const PAGE_ID_SIZE: usize = 12;
+
+fn encode_page_id(out: &mut Vec<u8>, page_id: PageId) {
+    for part in [page_id.db_id, page_id.space_id, page_id.page_no] {
+        out.extend_from_slice(&part.to_le_bytes());
+    }
+}
+
+fn decode_page_id(payload: &[u8]) -> Option<PageId> {
+    let field = |at: usize| Some(get_u32(payload.get(at..at + 4)?, 0));
+    Some(PageId {
+        db_id: field(0)?,
+        space_id: field(4)?,
+        page_no: field(8)?,
+    })
+}
+
+/// A key set, or deleted (`value` of `None`), in a tree's memtable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsmWrite {
+    pub db_id: u32,
+    pub space_id: u32,
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+}
+
+impl WalRecord for LsmWrite {
+    const TYPE: WalRecordType = WalRecordType::LSM_WRITE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.db_id.to_le_bytes());
+        out.extend_from_slice(&self.space_id.to_le_bytes());
+        out.extend_from_slice(&(self.key.len() as u16).to_le_bytes());
+        let value_len = self.value.as_ref().map_or(TOMBSTONE, |value| value.len() as u32);
+        out.extend_from_slice(&value_len.to_le_bytes());
+        out.extend_from_slice(&self.key);
+        if let Some(value) = &self.value {
+            out.extend_from_slice(value);
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let header = payload.get(..8 + ENTRY_HEADER_SIZE)?;
+        let key_len = get_u16(header, 8) as usize;
+        let value_len = get_u32(header, 10);
+        let key = payload.get(header.len()..header.len() + key_len)?;
+        let rest = &payload[header.len() + key_len..];
+        let value = match value_len {
+            TOMBSTONE if rest.is_empty() => None,
+            len if len != TOMBSTONE && rest.len() == len as usize => Some(rest.to_vec()),
+            _ => return None,
+        };
+        Some(Self {
+            db_id: get_u32(header, 0),
+            space_id: get_u32(header, 4),
+            key: key.to_vec(),
+            value,
+        })
+    }
+}
+
+/// A tree's manifest rewritten.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsmManifest {
+    pub page_id: PageId,
+    pub flushed_lsn: Lsn,
+    pub next_id: u64,
+    pub ssts: Vec<SstMeta>,
+}
+
+impl WalRecord for LsmManifest {
+    const TYPE: WalRecordType = WalRecordType::LSM_MANIFEST;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.flushed_lsn.0.to_le_bytes());
+        out.extend_from_slice(&self.next_id.to_le_bytes());
+        out.extend_from_slice(&(self.ssts.len() as u16).to_le_bytes());
+        for sst in &self.ssts {
+            let mut meta = [0u8; SST_META_SIZE];
+            sst.encode(&mut meta);
+            out.extend_from_slice(&meta);
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let header = PAGE_ID_SIZE + 18;
+        let count = get_u16(payload.get(..header)?, header - 2) as usize;
+        if count > MAX_SSTS || payload.len() != header + count * SST_META_SIZE {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            flushed_lsn: Lsn(u64::from_le_bytes(payload[PAGE_ID_SIZE..PAGE_ID_SIZE + 8].try_into().unwrap())),
+            next_id: u64::from_le_bytes(payload[PAGE_ID_SIZE + 8..PAGE_ID_SIZE + 16].try_into().unwrap()),
+            ssts: payload[header..].chunks_exact(SST_META_SIZE).map(SstMeta::decode).collect(),
+        })
+    }
+}
+
+impl PageRecord for LsmManifest {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[PAGE_HEADER_SIZE..].fill(0);
+        page::set_page_type(page, PAGE_TYPE_LSM_MANIFEST);
+        page[FLUSHED_LSN_OFFSET..FLUSHED_LSN_OFFSET + 8].copy_from_slice(&self.flushed_lsn.0.to_le_bytes());
+        page[NEXT_ID_OFFSET..NEXT_ID_OFFSET + 8].copy_from_slice(&self.next_id.to_le_bytes());
+        page[SST_COUNT_OFFSET..SST_COUNT_OFFSET + 2].copy_from_slice(&(self.ssts.len() as u16).to_le_bytes());
+        for (index, sst) in self.ssts.iter().enumerate() {
+            let at = SSTS_OFFSET + index * SST_META_SIZE;
+            sst.encode(&mut page[at..at + SST_META_SIZE]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checksum::ChecksumAlgorithm;
+    use crate::eviction::EvictionKind;
+    use crate::mem_store::MemStore;
+
+    use super::*;
+
+    const KEYS: u32 = 2000;
+
+    fn pool() -> BufferPool<MemStore> {
+        let store = Rc::new(MemStore::new(ChecksumAlgorithm::Crc32c).with_end_to_end_checksums(true));
+        BufferPool::new(store, 64, 64, ChecksumAlgorithm::Crc32c, EvictionKind::default())
+    }
+
+    fn config() -> LsmConfig {
+        LsmConfig {
+            memtable_bytes: 32 << 10,
+            sst_pages: 4,
+            l0_trigger: 2,
+            level_base_pages: 16,
+            level_fanout: 4,
+            ..LsmConfig::default()
+        }
+    }
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key-{:08}", i).into_bytes()
+    }
+
+    fn value(i: u32) -> Vec<u8> {
+        vec![i as u8; 64]
+    }
+
+    #[tokio::test]
+    async fn flushed_and_compacted_ssts_read_back_from_the_store() {
+        let pool = pool();
+        let lsm = Lsm::open(&pool, 1, 1, config(), None).await.unwrap();
+        // Spread over the key range, so the SSTs of each flush overlap
+        for i in 0..KEYS {
+            lsm.put(&key(i * 7 % KEYS), &value(i * 7 % KEYS)).await.unwrap();
+        }
+        lsm.flush().await.unwrap();
+        lsm.maintain().await.unwrap();
+        let ssts: Vec<SstMeta> = lsm.ssts().into_iter().flatten().collect();
+        assert!(ssts.iter().any(|sst| sst.level > 0), "{:?}", ssts);
+
+        for sst in &ssts {
+            for page_no in sst.first_page..sst.first_page + sst.data_pages + sst.index_pages + sst.bloom_pages {
+                let stored = pool.store().page(lsm.page(page_no)).unwrap();
+                assert!(checksum::verify_page(ChecksumAlgorithm::Crc32c, &stored), "sst {} page {}", sst.id, page_no);
+            }
+        }
+        for i in 0..KEYS {
+            assert_eq!(lsm.get(&key(i)).await.unwrap(), Some(value(i)), "key {}", i);
+        }
+        drop(lsm);
+
+        let lsm = Lsm::open(&pool, 1, 1, config(), None).await.unwrap();
+        assert_eq!(lsm.ssts().into_iter().flatten().count(), ssts.len());
+        assert_eq!(lsm.scan(b"", None, usize::MAX).await.unwrap().len(), KEYS as usize);
+        assert_eq!(lsm.get(b"missing").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,330 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::compression;
+use crate::page;
+use crate::segment::{SegmentHeader, SpaceExtents, EXTENT_PAGES, PAGES_PER_SEGMENT};
+use crate::traits::{AlignedBuf, Lsn, PageId, PageState, PageStore, StorageError, WalStore};
+use crate::wal_record::{self, WalRecord, WalRecordType};
+
+// -----------------------------------------------------------------------------
+// In-Memory Store
+//
+// `MemStore` keeps pages in a map and each database's log in a list of records, for
+// tests of the buffer pool, indexes and recovery that should run fast and without
+// io_uring. It answers the way `CoreStorage` does where callers can tell:
+//
+//   reads       a page never written, or freed since, is Fresh and all zeros; any
+//               other is checked against its checksum (its frame's, if compressed)
+//               and a bad one fails with `Corruption`, with no quarantine
+//   writes      are refused with `WalNotFlushed` while the page's LSN is not below
+//               the flushed LSN, and with `InMemoryCorruption` if end-to-end
+//               checksums are on and the page fails its own
+//   extents     are placed as in the segment headers, so freed ones are handed out
+//               again lowest first; freeing drops their pages, like a punched hole
+//   WAL         LSNs start at 0 and advance by each record's framed length; appends
+//               are durable once flushed, and `crash` drops what wasn't
+//
+// A page write is durable at once, as with O_DIRECT. Pages are stored as given:
+// nothing is compressed or encrypted on the way.
+// -----------------------------------------------------------------------------
+
+// One database's log
+struct MemWal {
+    records: Vec<WalRecord>,
+    // Just past the last record
+    tail: Lsn,
+    flushed: Lsn,
+    full_page_horizon: Lsn,
+}
+
+impl Default for MemWal {
+    fn default() -> Self {
+        Self { records: Vec::new(), tail: Lsn(0), flushed: Lsn(0), full_page_horizon: Lsn(0) }
+    }
+}
+
+/// Pages and logs held in memory, on one thread.
+pub struct MemStore {
+    checksum: ChecksumAlgorithm,
+    end_to_end_checksums: bool,
+    full_page_writes: bool,
+    pages: RefCell<HashMap<PageId, Box<[u8]>>>,
+    extents: RefCell<HashMap<(u32, u32), SpaceExtents>>,
+    wals: RefCell<HashMap<u32, MemWal>>,
+}
+
+impl MemStore {
+    pub fn new(checksum: ChecksumAlgorithm) -> Self {
+        Self {
+            checksum,
+            end_to_end_checksums: false,
+            full_page_writes: false,
+            pages: RefCell::new(HashMap::new()),
+            extents: RefCell::new(HashMap::new()),
+            wals: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Checks each page's checksum before writing it (see `StorageConfig`).
+    pub fn with_end_to_end_checksums(mut self, enabled: bool) -> Self {
+        self.end_to_end_checksums = enabled;
+        self
+    }
+
+    /// Reports a full-page horizon, starting at the log's tail when it was opened.
+    pub fn with_full_page_writes(mut self, enabled: bool) -> Self {
+        self.full_page_writes = enabled;
+        self
+    }
+
+    /// The page as stored, if it was written and not freed since.
+    pub fn page(&self, page_id: PageId) -> Option<Vec<u8>> {
+        self.pages.borrow().get(&page_id).map(|page| page.to_vec())
+    }
+
+    /// Pages written and not freed.
+    pub fn page_count(&self) -> usize {
+        self.pages.borrow().len()
+    }
+
+    /// Stores `page` as is, with none of a write's checks: a torn or corrupt image.
+    pub fn put_page(&self, page_id: PageId, page: &[u8]) {
+        self.pages.borrow_mut().insert(page_id, Box::from(page));
+    }
+
+    /// Databases with a log, in id order.
+    pub fn databases(&self) -> Vec<u32> {
+        let mut db_ids: Vec<u32> = self.wals.borrow().keys().copied().collect();
+        db_ids.sort_unstable();
+        db_ids
+    }
+
+    /// LSN just past the database's last record.
+    pub fn wal_tail(&self, db_id: u32) -> Lsn {
+        self.wals.borrow().get(&db_id).map_or(Lsn(0), |wal| wal.tail)
+    }
+
+    /// Makes the database's log durable up to `lsn`, clamped to its tail: what a flush
+    /// does, without the call.
+    pub fn mark_flushed(&self, db_id: u32, lsn: Lsn) {
+        if let Some(wal) = self.wals.borrow_mut().get_mut(&db_id) {
+            wal.flushed = wal.flushed.max(lsn.min(wal.tail));
+        }
+    }
+
+    /// The database's records from `from_lsn` on, flushed or not.
+    pub fn wal_records(&self, db_id: u32, from_lsn: Lsn) -> Vec<WalRecord> {
+        let wals = self.wals.borrow();
+        let Some(wal) = wals.get(&db_id) else {
+            return Vec::new();
+        };
+        wal.records.iter().filter(|record| record.lsn >= from_lsn).cloned().collect()
+    }
+
+    /// The database's log from `from_lsn` on, framed as on disk.
+    pub fn wal_bytes(&self, db_id: u32, from_lsn: Lsn) -> Vec<u8> {
+        self.wal_records(db_id, from_lsn).iter().flat_map(WalRecord::encode).collect()
+    }
+
+    /// Moves the full-page horizon up to `lsn`, as a checkpoint's start does.
+    pub fn advance_full_page_horizon(&self, db_id: u32, lsn: Lsn) {
+        let mut wals = self.wals.borrow_mut();
+        let wal = wals.entry(db_id).or_default();
+        wal.full_page_horizon = wal.full_page_horizon.max(lsn.min(wal.tail));
+    }
+
+    /// Loses what a crash would: every record past each log's flushed LSN. The logs
+    /// resume at the flushed LSN, whose full-page horizon they start from again.
+    pub fn crash(&self) {
+        for wal in self.wals.borrow_mut().values_mut() {
+            wal.records.retain(|record| record.end_lsn() <= wal.flushed);
+            wal.tail = wal.flushed;
+            wal.full_page_horizon = wal.flushed;
+        }
+    }
+
+    // Checks a stored page as a read does
+    fn verify(&self, page_id: PageId, page: &[u8]) -> Result<PageState, StorageError> {
+        if page::is_fresh(page) {
+            return Ok(PageState::Fresh);
+        }
+        let intact = match compression::is_compressed(page) {
+            true => compression::verify_frame(self.checksum, page),
+            false => checksum::verify_page(self.checksum, page),
+        };
+        match intact {
+            true => Ok(PageState::Written),
+            false => Err(StorageError::Corruption(page_id)),
+        }
+    }
+
+    fn read_into(&self, page_id: PageId, buf: &mut [u8]) -> Result<PageState, StorageError> {
+        match self.pages.borrow().get(&page_id) {
+            Some(page) => {
+                buf.copy_from_slice(page);
+                self.verify(page_id, buf)
+            }
+            None => {
+                buf.fill(0);
+                Ok(PageState::Fresh)
+            }
+        }
+    }
+
+    // The checks `CoreStorage` makes before a page reaches its file
+    fn check_write(&self, page_id: PageId, page: &[u8]) -> Result<(), StorageError> {
+        if self.end_to_end_checksums && !checksum::verify_page(self.checksum, page) {
+            return Err(StorageError::InMemoryCorruption(page_id));
+        }
+        let page_lsn = page::page_lsn(page);
+        if page_lsn == Lsn(0) {
+            return Ok(());
+        }
+        let flushed = self.flushed_lsn(page_id.db_id)?;
+        if page_lsn >= flushed {
+            return Err(StorageError::WalNotFlushed { page_id, page_lsn, flushed });
+        }
+        Ok(())
+    }
+}
+
+fn nth_page(first: PageId, index: usize) -> PageId {
+    PageId { page_no: first.page_no + index as u32, ..first }
+}
+
+impl PageStore for MemStore {
+    async fn read_page(&self, page_id: PageId, mut buf: AlignedBuf) -> (AlignedBuf, Result<PageState, StorageError>) {
+        let res = self.read_into(page_id, &mut buf);
+        (buf, res)
+    }
+
+    async fn read_pages(&self, start_page_id: PageId, mut bufs: Vec<AlignedBuf>) -> (Vec<AlignedBuf>, Result<(), StorageError>) {
+        for (index, buf) in bufs.iter_mut().enumerate() {
+            if let Err(e) = self.read_into(nth_page(start_page_id, index), buf) {
+                return (bufs, Err(e));
+            }
+        }
+        (bufs, Ok(()))
+    }
+
+    async fn write_page(&self, page_id: PageId, buf: AlignedBuf) -> (AlignedBuf, Result<(), StorageError>) {
+        if let Err(e) = self.check_write(page_id, &buf) {
+            return (buf, Err(e));
+        }
+        self.pages.borrow_mut().insert(page_id, Box::from(&buf[..]));
+        (buf, Ok(()))
+    }
+
+    async fn write_pages(&self, start_page_id: PageId, bufs: Vec<AlignedBuf>) -> (Vec<AlignedBuf>, Result<(), StorageError>) {
+        // All or nothing, so a refused page leaves none of the range written.
+        for (index, buf) in bufs.iter().enumerate() {
+            if let Err(e) = self.check_write(nth_page(start_page_id, index), buf) {
+                return (bufs, Err(e));
+            }
+        }
+        let mut pages = self.pages.borrow_mut();
+        for (index, buf) in bufs.iter().enumerate() {
+            pages.insert(nth_page(start_page_id, index), Box::from(&buf[..]));
+        }
+        drop(pages);
+        (bufs, Ok(()))
+    }
+
+    async fn allocate_extent(&self, db_id: u32, space_id: u32, num_pages: u32) -> Result<u32, StorageError> {
+        if num_pages == 0 || num_pages > PAGES_PER_SEGMENT {
+            return Err(StorageError::OutOfSpace);
+        }
+        let mut extents = self.extents.borrow_mut();
+        let space = extents.entry((db_id, space_id)).or_insert_with(|| SpaceExtents::new(Vec::new()));
+        let start = space.place(num_pages).ok_or(StorageError::OutOfSpace)?;
+        start.checked_add(num_pages).ok_or(StorageError::OutOfSpace)?;
+
+        // Extents never straddle segments; a new segment starts with an empty header.
+        let seg_no = start / PAGES_PER_SEGMENT;
+        if seg_no as usize == space.headers.len() {
+            space.headers.push(SegmentHeader::new(db_id, space_id, seg_no, self.checksum));
+        }
+        let first = start % PAGES_PER_SEGMENT;
+        space.headers[seg_no as usize].claim(first, first + num_pages);
+        space.tail = start + num_pages;
+        Ok(start)
+    }
+
+    async fn free_extent(&self, db_id: u32, space_id: u32, start_page: u32, num_pages: u32) -> Result<(), StorageError> {
+        if num_pages == 0 || start_page % PAGES_PER_SEGMENT + num_pages > PAGES_PER_SEGMENT {
+            return Err(StorageError::OutOfSpace);
+        }
+        let mut extents = self.extents.borrow_mut();
+        let Some(header) = extents.get_mut(&(db_id, space_id)).and_then(|space| space.headers.get_mut((start_page / PAGES_PER_SEGMENT) as usize)) else {
+            return Ok(());
+        };
+        let first = start_page % PAGES_PER_SEGMENT;
+        header.release(first, first + num_pages);
+
+        // Only whole extents are released, and only their pages read back as zeros.
+        let base = start_page - first;
+        let released = first.div_ceil(EXTENT_PAGES) * EXTENT_PAGES..(first + num_pages) / EXTENT_PAGES * EXTENT_PAGES;
+        self.pages.borrow_mut().retain(|page_id, _| {
+            page_id.db_id != db_id || page_id.space_id != space_id || !released.contains(&page_id.page_no.wrapping_sub(base))
+        });
+        Ok(())
+    }
+}
+
+impl WalStore for MemStore {
+    async fn append_wal(&self, db_id: u32, record_type: WalRecordType, payload: &[u8]) -> Result<Lsn, StorageError> {
+        if payload.len() > wal_record::MAX_RECORD_PAYLOAD {
+            return Err(StorageError::RecordTooLarge(payload.len()));
+        }
+        let mut wals = self.wals.borrow_mut();
+        let wal = wals.entry(db_id).or_default();
+        let lsn = wal.tail;
+        let record = WalRecord { lsn, record_type, gsn: None, payload: payload.to_vec() };
+        wal.tail = record.end_lsn();
+        wal.records.push(record);
+        Ok(lsn)
+    }
+
+    async fn flush_wal(&self, db_id: u32) -> Result<(), StorageError> {
+        if let Some(wal) = self.wals.borrow_mut().get_mut(&db_id) {
+            wal.flushed = wal.tail;
+        }
+        Ok(())
+    }
+
+    async fn flush_wal_until(&self, db_id: u32, lsn: Lsn) -> Result<(), StorageError> {
+        self.mark_flushed(db_id, lsn);
+        Ok(())
+    }
+
+    fn flushed_lsn(&self, db_id: u32) -> Result<Lsn, StorageError> {
+        Ok(self.wals.borrow().get(&db_id).map_or(Lsn(0), |wal| wal.flushed))
+    }
+
+    fn full_page_horizon(&self, db_id: u32) -> Result<Option<Lsn>, StorageError> {
+        if !self.full_page_writes {
+            return Ok(None);
+        }
+        Ok(Some(self.wals.borrow().get(&db_id).map_or(Lsn(0), |wal| wal.full_page_horizon)))
+    }
+
+    async fn truncate_wal(&self, db_id: u32, up_to_lsn: Lsn) -> Result<u64, StorageError> {
+        let mut wals = self.wals.borrow_mut();
+        let Some(wal) = wals.get_mut(&db_id) else {
+            return Ok(0);
+        };
+        // Only durable records go, and only whole ones.
+        let up_to_lsn = up_to_lsn.min(wal.flushed);
+        let mut reclaimed = 0;
+        wal.records.retain(|record| {
+            let drop = record.end_lsn() <= up_to_lsn;
+            if drop {
+                reclaimed += record.framed_len() as u64;
+            }
+            !drop
+        });
+        Ok(reclaimed)
+    }
+}
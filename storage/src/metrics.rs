@@ -0,0 +1,496 @@
+use std::fmt::Write as _;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::stats::{LatencyStats, OpLatency};
+use crate::trace;
+use crate::traits::StorageError;
+
+// -----------------------------------------------------------------------------
+// Metrics
+//
+// Counters and latency histograms of each core's I/O, for Prometheus to scrape. A core
+// counts into its `CoreMetrics`, shared with whatever thread exports them, hence
+// atomics: `CoreStorage` its page reads and writes, fsyncs, checksum failures and WAL
+// traffic, and a `BufferPool` given them (`BufferPool::with_metrics`) its hits and
+// misses. `Metrics` holds every core's, and `gather` renders them in the text
+// exposition format, each sample labelled with its core:
+//
+//   cascade_page_reads_total{core="0"} 1234
+//
+// Histograms have power-of-two buckets, from 16us up to about 16s. `MetricsServer`
+// serves `gather` over HTTP from a thread of its own, for engines with no endpoint of
+// their own to put it on.
+//
+// Each histogram also keeps a window of finer, HDR-style buckets for percentiles: 16
+// linear sub-buckets per power of two, so a quantile is off by at most 1/16 of its
+// value, from 1us up to about 19h. The window is what `CoreMetrics::latency_stats`,
+// and the `cascade_latency_window_seconds` quantiles, report; `reset_latencies` starts
+// a new one, by hand or every `MetricsServer::with_latency_window`. The Prometheus
+// buckets, sums and counts are never reset, as Prometheus expects.
+//
+//   read_page    page reads, one per request however many pages
+//   write_page   page writes
+//   fsync        fsyncs of WAL and segment files
+//   wal_append   `append_wal`, including any write of full staging buffers
+// -----------------------------------------------------------------------------
+
+/// Upper bounds of the histogram buckets, in microseconds: 16us, 32us, ... 2^24us.
+const BUCKET_BOUNDS: [u64; 21] = {
+    let mut bounds = [0; 21];
+    let mut i = 0;
+    while i < bounds.len() {
+        bounds[i] = 16 << i;
+        i += 1;
+    }
+    bounds
+};
+
+// Window buckets: values up to 2^SUB_BITS us each have their own, then every power of
+// two is split into 2^SUB_BITS, up to 2^MAX_EXP us. Bucket bounds are inclusive above,
+// so the power-of-two bounds of `BUCKET_BOUNDS` fall on window bucket bounds.
+const SUB_BITS: u32 = 4;
+const MAX_EXP: u32 = 36;
+const WINDOW_BUCKETS: usize = ((MAX_EXP - SUB_BITS + 1) << SUB_BITS) as usize;
+
+// Window bucket of a latency of `micros`
+fn window_bucket(micros: u64) -> usize {
+    let below = micros.saturating_sub(1);
+    if below < 1 << SUB_BITS {
+        return below as usize;
+    }
+    let exp = 63 - below.leading_zeros();
+    let sub = (below >> (exp - SUB_BITS)) - (1 << SUB_BITS);
+    let bucket = (((exp - SUB_BITS + 1) as u64) << SUB_BITS) + sub;
+    (bucket as usize).min(WINDOW_BUCKETS - 1)
+}
+
+// Largest latency, in microseconds, window bucket `bucket` holds
+fn window_bound(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < 1 << SUB_BITS {
+        return bucket + 1;
+    }
+    let exp = (bucket >> SUB_BITS) as u32 + SUB_BITS - 1;
+    let sub = bucket & ((1 << SUB_BITS) - 1);
+    ((1 << SUB_BITS) + sub + 1) << (exp - SUB_BITS)
+}
+
+/// A count that only goes up.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Latencies, counted into `BUCKET_BOUNDS` and into a window of finer buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    // Observations in each bucket alone, the last one past every bound
+    buckets: [AtomicU64; BUCKET_BOUNDS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+    // Observations since the last reset, by window bucket, and the largest of them
+    window: Box<[AtomicU64]>,
+    window_max_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: Default::default(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            window: (0..WINDOW_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            window_max_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        let bucket = BUCKET_BOUNDS.partition_point(|&bound| bound < micros);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.window[window_bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.window_max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Starts a new window. Observations racing the reset may land in either.
+    pub fn reset(&self) {
+        for bucket in self.window.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.window_max_micros.store(0, Ordering::Relaxed);
+    }
+
+    /// The latency quantile `q` (0.0 to 1.0) of the window stays within: the bound of
+    /// its bucket, or the largest latency seen if lower. Zero for an empty window.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let counts: Vec<u64> = self.window.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((total as f64 * q.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let max = self.window_max_micros.load(Ordering::Relaxed);
+        let mut seen = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(window_bound(bucket).min(max.max(1)));
+            }
+        }
+        Duration::from_micros(max)
+    }
+
+    pub fn p50(&self) -> Duration {
+        self.quantile(0.50)
+    }
+
+    pub fn p99(&self) -> Duration {
+        self.quantile(0.99)
+    }
+
+    pub fn p999(&self) -> Duration {
+        self.quantile(0.999)
+    }
+
+    /// The window's observations and percentiles.
+    pub fn window(&self) -> OpLatency {
+        OpLatency {
+            count: self.window.iter().map(|bucket| bucket.load(Ordering::Relaxed)).sum(),
+            p50: self.p50(),
+            p99: self.p99(),
+            p999: self.p999(),
+            max: Duration::from_micros(self.window_max_micros.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    /// Observations up to each bound, then all of them.
+    pub fn cumulative(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .scan(0, |seen, bucket| {
+                *seen += bucket.load(Ordering::Relaxed);
+                Some(*seen)
+            })
+            .collect()
+    }
+}
+
+/// One core's I/O, counted on the core and read from anywhere.
+#[derive(Debug, Default)]
+pub struct CoreMetrics {
+    core_id: usize,
+    pub page_reads: Counter,
+    pub page_read_bytes: Counter,
+    pub page_writes: Counter,
+    pub page_write_bytes: Counter,
+    pub fsyncs: Counter,
+    /// Pages failing verification, read from disk or about to be written.
+    pub checksum_failures: Counter,
+    pub buffer_pool_hits: Counter,
+    pub buffer_pool_misses: Counter,
+    pub wal_records: Counter,
+    /// Bytes written to WAL files, framing included.
+    pub wal_bytes: Counter,
+    /// I/Os completing past `StorageConfig::slow_io_threshold`.
+    pub slow_ios: Counter,
+    pub read_latency: Histogram,
+    pub write_latency: Histogram,
+    pub fsync_latency: Histogram,
+    pub wal_append_latency: Histogram,
+}
+
+impl CoreMetrics {
+    pub fn new(core_id: usize) -> Arc<Self> {
+        Arc::new(Self { core_id, ..Default::default() })
+    }
+
+    pub fn core_id(&self) -> usize {
+        self.core_id
+    }
+
+    /// `pages` pages of `bytes` bytes on disk read in one request, taking `latency`.
+    pub fn read(&self, pages: usize, bytes: usize, latency: Duration) {
+        self.page_reads.add(pages as u64);
+        self.page_read_bytes.add(bytes as u64);
+        self.read_latency.observe(latency);
+    }
+
+    /// `pages` pages written as `bytes` bytes in one request, taking `latency`.
+    pub fn write(&self, pages: usize, bytes: usize, latency: Duration) {
+        self.page_writes.add(pages as u64);
+        self.page_write_bytes.add(bytes as u64);
+        self.write_latency.observe(latency);
+    }
+
+    pub fn fsync(&self, latency: Duration) {
+        self.fsyncs.inc();
+        self.fsync_latency.observe(latency);
+    }
+
+    /// A WAL record appended, taking `latency`.
+    pub fn wal_append(&self, latency: Duration) {
+        self.wal_records.inc();
+        self.wal_append_latency.observe(latency);
+    }
+
+    /// Percentiles of each operation since the last `reset_latencies`.
+    pub fn latency_stats(&self) -> LatencyStats {
+        LatencyStats {
+            read_page: self.read_latency.window(),
+            write_page: self.write_latency.window(),
+            fsync: self.fsync_latency.window(),
+            wal_append: self.wal_append_latency.window(),
+        }
+    }
+
+    /// Starts a new percentile window for every operation.
+    pub fn reset_latencies(&self) {
+        for (_, _, histogram) in HISTOGRAMS {
+            histogram(self).reset();
+        }
+    }
+}
+
+type CounterOf = fn(&CoreMetrics) -> &Counter;
+type HistogramOf = fn(&CoreMetrics) -> &Histogram;
+
+const COUNTERS: &[(&str, &str, CounterOf)] = &[
+    ("cascade_page_reads_total", "Pages read from disk.", |m| &m.page_reads),
+    ("cascade_page_read_bytes_total", "Bytes of pages read from disk.", |m| &m.page_read_bytes),
+    ("cascade_page_writes_total", "Pages written to disk.", |m| &m.page_writes),
+    ("cascade_page_write_bytes_total", "Bytes of pages written to disk, as stored.", |m| &m.page_write_bytes),
+    ("cascade_fsyncs_total", "Fsyncs of WAL and segment files.", |m| &m.fsyncs),
+    ("cascade_checksum_failures_total", "Pages failing checksum verification.", |m| &m.checksum_failures),
+    ("cascade_buffer_pool_hits_total", "Buffer pool requests served from memory.", |m| &m.buffer_pool_hits),
+    ("cascade_buffer_pool_misses_total", "Buffer pool requests that loaded the page.", |m| &m.buffer_pool_misses),
+    ("cascade_wal_records_total", "WAL records appended.", |m| &m.wal_records),
+    ("cascade_wal_bytes_total", "Bytes written to WAL files.", |m| &m.wal_bytes),
+    ("cascade_slow_ios_total", "I/Os slower than the slow-I/O threshold.", |m| &m.slow_ios),
+];
+
+const HISTOGRAMS: &[(&str, &str, HistogramOf)] = &[
+    ("cascade_page_read_seconds", "Latency of page reads.", |m| &m.read_latency),
+    ("cascade_page_write_seconds", "Latency of page writes.", |m| &m.write_latency),
+    ("cascade_fsync_seconds", "Latency of fsyncs.", |m| &m.fsync_latency),
+    ("cascade_wal_append_seconds", "Latency of WAL appends.", |m| &m.wal_append_latency),
+];
+
+// Operations of the percentile window, as labelled, and the quantiles exported
+const WINDOW_OPS: &[(&str, HistogramOf)] = &[
+    ("read_page", |m| &m.read_latency),
+    ("write_page", |m| &m.write_latency),
+    ("fsync", |m| &m.fsync_latency),
+    ("wal_append", |m| &m.wal_append_latency),
+];
+const WINDOW_QUANTILES: [f64; 3] = [0.5, 0.99, 0.999];
+
+/// Every core's metrics, for exporting.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    cores: Mutex<Vec<Arc<CoreMetrics>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// The metrics of core `core_id`, created on first use.
+    pub fn core(&self, core_id: usize) -> Arc<CoreMetrics> {
+        let mut cores = self.cores.lock().unwrap();
+        if let Some(core) = cores.iter().find(|core| core.core_id == core_id) {
+            return Arc::clone(core);
+        }
+        let core = CoreMetrics::new(core_id);
+        cores.push(Arc::clone(&core));
+        cores.sort_by_key(|core| core.core_id);
+        core
+    }
+
+    /// Every core's metrics in the Prometheus text exposition format.
+    pub fn gather(&self) -> String {
+        let cores = self.cores.lock().unwrap().clone();
+        let mut out = String::new();
+        for (name, help, counter) in COUNTERS {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+            for core in &cores {
+                let _ = writeln!(out, "{}{{core=\"{}\"}} {}", name, core.core_id, counter(core).get());
+            }
+        }
+        for (name, help, histogram) in HISTOGRAMS {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+            for core in &cores {
+                let histogram = histogram(core);
+                let cumulative = histogram.cumulative();
+                for (bound, seen) in BUCKET_BOUNDS.iter().zip(&cumulative) {
+                    let le = *bound as f64 / 1e6;
+                    let _ = writeln!(out, "{}_bucket{{core=\"{}\",le=\"{}\"}} {}", name, core.core_id, le, seen);
+                }
+                let _ = writeln!(out, "{}_bucket{{core=\"{}\",le=\"+Inf\"}} {}", name, core.core_id, cumulative.last().unwrap());
+                let _ = writeln!(out, "{}_sum{{core=\"{}\"}} {}", name, core.core_id, histogram.sum().as_secs_f64());
+                let _ = writeln!(out, "{}_count{{core=\"{}\"}} {}", name, core.core_id, histogram.count());
+            }
+        }
+        let name = "cascade_latency_window_seconds";
+        let _ = writeln!(out, "# HELP {} Latency quantiles since the last window reset.\n# TYPE {} gauge", name, name);
+        for core in &cores {
+            for (op, histogram) in WINDOW_OPS {
+                for q in WINDOW_QUANTILES {
+                    let latency = histogram(core).quantile(q).as_secs_f64();
+                    let _ = writeln!(out, "{}{{core=\"{}\",op=\"{}\",quantile=\"{}\"}} {}", name, core.core_id, op, q, latency);
+                }
+            }
+        }
+        out
+    }
+
+    /// Starts a new percentile window on every core.
+    pub fn reset_latencies(&self) {
+        for core in self.cores.lock().unwrap().iter() {
+            core.reset_latencies();
+        }
+    }
+}
+
+// How often the server thread looks for a connection or a stop
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: usize = 8192;
+
+/// A tiny HTTP endpoint serving `Metrics::gather` at `/metrics`, on a thread of its
+/// own. Connections are served one at a time; stopped when dropped.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    // Microseconds between resets of the percentile windows, 0 for never
+    latency_window: Arc<AtomicU64>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Starts serving `metrics` at `addr`.
+    pub fn start(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<Self, StorageError> {
+        let listener = TcpListener::bind(addr).map_err(StorageError::Io)?;
+        listener.set_nonblocking(true).map_err(StorageError::Io)?;
+        let addr = listener.local_addr().map_err(StorageError::Io)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = Arc::clone(&stop);
+        let latency_window = Arc::new(AtomicU64::new(0));
+        let window = Arc::clone(&latency_window);
+
+        let thread = thread::Builder::new()
+            .name("storage-metrics".to_string())
+            .spawn(move || {
+                let mut window_started = Instant::now();
+                while !stop_flag.load(Ordering::Relaxed) {
+                    let every = window.load(Ordering::Relaxed);
+                    if every > 0 && window_started.elapsed() >= Duration::from_micros(every) {
+                        metrics.reset_latencies();
+                        window_started = Instant::now();
+                    }
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            if let Err(e) = serve(stream, &metrics) {
+                                trace::warn_event!("metrics: request failed: {:?}", e);
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                        Err(e) => {
+                            trace::warn_event!("metrics: accept failed: {:?}", e);
+                            thread::sleep(ACCEPT_INTERVAL);
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn metrics thread");
+
+        Ok(Self {
+            addr,
+            stop,
+            latency_window,
+            thread: Some(thread),
+        })
+    }
+
+    /// Resets every core's percentile windows each `window`, so the quantiles scraped
+    /// cover recent latencies only. Accurate to the accept interval, 50ms.
+    pub fn with_latency_window(self, window: Duration) -> Self {
+        self.latency_window.store((window.as_micros() as u64).max(1), Ordering::Relaxed);
+        self
+    }
+
+    /// The address served, with the port the system picked if `start` was given 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Answers one request: the metrics for a GET of /metrics, 404 for anything else
+fn serve(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let line = request.split(|&byte| byte == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|&byte| byte == b' ');
+    let (method, path) = (parts.next(), parts.next());
+    let (status, content_type, body) = match (method, path) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", "text/plain; version=0.0.4", metrics.gather()),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::catalog::{self, SpaceEntry};
+use crate::page::PAGE_SIZE;
+use crate::segment;
+use crate::trace;
+use crate::traits::{StorageConfig, StorageError};
+use crate::wal_recovery;
+
+// -----------------------------------------------------------------------------
+// Mounting
+//
+// Two files in the data directory tell `StorageManager::mount` about the last process
+// that had it:
+//
+//   data_dir/LOCK     empty; an exclusive flock on it is held while mounted
+//   data_dir/CLEAN    empty; exists only between a clean shutdown and the next mount
+//
+// The lock keeps a second process from mounting the same directories and writing
+// over the first one's files. It is advisory and released by the kernel when the
+// process dies, so a crash never leaves it behind.
+//
+// `StorageManager::mark_clean_shutdown` creates CLEAN once every core has flushed its
+// buffer pool and WAL. Mount removes it, durably, before anything else writes, so the
+// marker only ever describes the last run: found, nothing needs recovering; missing,
+// the WAL is checked for a torn tail and replayed. A directory that was never mounted
+// has no marker either, and an empty WAL to recover from.
+//
+// Spaces are discovered from the segment files (see `segment.rs`). A segment whose
+// header names another database, space or segment than its path was copied or renamed
+// by hand, and is refused rather than served as the wrong space's pages. Then each
+// database's catalog is read (see `catalog.rs`), and a space it lists with another
+// page size, or as encrypted with no key configured, is refused too. A catalog page
+// failing its checksum after a crash may just be torn, for redo to rewrite, so that
+// database's catalog is not loaded rather than failing the mount.
+// -----------------------------------------------------------------------------
+
+const LOCK_FILE: &str = "LOCK";
+const CLEAN_FILE: &str = "CLEAN";
+
+/// The exclusive lock on a data directory, released on drop.
+#[derive(Debug)]
+pub struct DataDirLock {
+    _file: fs::File,
+}
+
+impl DataDirLock {
+    /// Locks `data_dir`, creating it if needed. Fails with `AlreadyMounted` if another
+    /// process holds it.
+    pub fn acquire(data_dir: &Path) -> Result<Self, StorageError> {
+        fs::create_dir_all(data_dir).map_err(StorageError::Io)?;
+        let path = data_dir.join(LOCK_FILE);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(StorageError::Io)?;
+        let rc = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if rc != 0 {
+            let e = io::Error::last_os_error();
+            return Err(match e.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => StorageError::AlreadyMounted(data_dir.to_path_buf()),
+                _ => StorageError::Io(e),
+            });
+        }
+        Ok(Self { _file: file })
+    }
+}
+
+/// Consumes the clean shutdown marker: whether the last process to mount `data_dir`
+/// shut down cleanly. Call with the lock held.
+pub fn take_clean_shutdown(data_dir: &Path) -> Result<bool, StorageError> {
+    match fs::remove_file(data_dir.join(CLEAN_FILE)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(StorageError::Io(e)),
+    }
+    fs::File::open(data_dir).and_then(|d| d.sync_all()).map_err(StorageError::Io)?;
+    Ok(true)
+}
+
+/// Durably marks `data_dir` as shut down cleanly.
+pub fn mark_clean_shutdown(data_dir: &Path) -> Result<(), StorageError> {
+    wal_recovery::write_durably(&data_dir.join(CLEAN_FILE), &[])?;
+    fs::File::open(data_dir).and_then(|d| d.sync_all()).map_err(StorageError::Io)
+}
+
+/// Every space under `data_dir`, by `(db_id, space_id)`, with its segment files in
+/// segment order. Refuses segments whose header disagrees with their path.
+pub fn discover_spaces(data_dir: &Path) -> Result<HashMap<(u32, u32), Vec<PathBuf>>, StorageError> {
+    let mut spaces: HashMap<(u32, u32), Vec<(u32, PathBuf)>> = HashMap::new();
+    for path in segment::segment_files(data_dir)? {
+        let (db_id, space_id, seg_no) = segment_ids(&path).ok_or_else(|| StorageError::NotASegment(path.clone()))?;
+        let header = segment::read_header(&path)?;
+        if (header.db_id, header.space_id, header.seg_no) != (db_id, space_id, seg_no) {
+            return Err(StorageError::IncompatibleFormat {
+                path,
+                reason: format!(
+                    "header is db {} space {} segment {}, path is db {} space {} segment {}",
+                    header.db_id, header.space_id, header.seg_no, db_id, space_id, seg_no
+                ),
+            });
+        }
+        spaces.entry((db_id, space_id)).or_default().push((seg_no, path));
+    }
+    Ok(spaces
+        .into_iter()
+        .map(|(space, mut segments)| {
+            segments.sort_unstable();
+            (space, segments.into_iter().map(|(_, path)| path).collect())
+        })
+        .collect())
+}
+
+// `db_<id>/space_<id>.<seg_no>.dat`, as `(db_id, space_id, seg_no)`
+fn segment_ids(path: &Path) -> Option<(u32, u32, u32)> {
+    let (space_id, seg_no) = segment::parse_segment_file_name(path.file_name()?.to_str()?)?;
+    let db_dir = path.parent()?.file_name()?.to_str()?;
+    let db_id = db_dir.strip_prefix("db_")?.parse().ok()?;
+    Some((db_id, space_id, seg_no))
+}
+
+/// The catalog of every database under `config.data_dir` that has one, by `db_id`.
+/// Refuses spaces listed with another page size or as encrypted with no key.
+pub fn load_catalogs(
+    config: &StorageConfig,
+    spaces: &HashMap<(u32, u32), Vec<PathBuf>>,
+    clean_shutdown: bool,
+) -> Result<HashMap<u32, Vec<SpaceEntry>>, StorageError> {
+    let mut catalogs = HashMap::new();
+    for (&(db_id, space_id), segments) in spaces {
+        if space_id != catalog::CATALOG_SPACE {
+            continue;
+        }
+        let entries = match catalog::read(&config.data_dir, db_id, config.checksum) {
+            Ok(entries) => entries,
+            Err(StorageError::Corruption(page_id)) if !clean_shutdown => {
+                trace::warn_event!("catalog: db {} page {} fails its checksum before redo, not loaded", db_id, page_id.page_no);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        for entry in &entries {
+            if entry.page_size != PAGE_SIZE as u32 {
+                return Err(StorageError::IncompatibleFormat {
+                    path: segments[0].clone(),
+                    reason: format!("space {} has {} byte pages, this build {}", entry.space_id, entry.page_size, PAGE_SIZE),
+                });
+            }
+            let keyed = config.spaces.get(&(db_id, entry.space_id)).is_some_and(|options| options.encryption.is_some());
+            if entry.flags & catalog::SPACE_ENCRYPTED != 0 && !keyed {
+                return Err(StorageError::MissingKey { db_id, space_id: entry.space_id });
+            }
+        }
+        catalogs.insert(db_id, entries);
+    }
+    Ok(catalogs)
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use futures::future::join_all;
+
+use crate::traits::{AlignedBuf, PageId, PageStore, StorageError};
+
+/// Longest run of adjacent pages merged into one `read_pages` call (256KB).
+pub const MAX_COALESCED_PAGES: usize = 32;
+
+/// Reads a batch of arbitrary pages, returning one result per input id, in input order.
+///
+/// Instead of N independent 8KB reads, the ids are deduplicated and sorted, adjacent
+/// page numbers in the same space are merged into vectored `read_pages` runs, and all
+/// runs are submitted to the ring together. This is the I/O half of a point-lookup
+/// `multi_get`: a caller that has resolved its keys to leaf/heap pages hands the whole
+/// set over at once. Never-written pages come back zero-filled (see `page::is_fresh`).
+pub async fn multi_read<S: PageStore>(store: &S, page_ids: &[PageId]) -> Vec<Result<AlignedBuf, StorageError>> {
+    let mut sorted: Vec<PageId> = page_ids.to_vec();
+    sorted.sort_by_key(|id| (id.db_id, id.space_id, id.page_no));
+    sorted.dedup();
+
+    let runs = coalesce(&sorted);
+    let outcomes = join_all(runs.iter().map(|run| read_run(store, run))).await;
+
+    let mut pages: HashMap<PageId, Result<AlignedBuf, StorageError>> = HashMap::with_capacity(sorted.len());
+    for (run, outcome) in runs.iter().zip(outcomes) {
+        pages.extend(run.iter().copied().zip(outcome));
+    }
+
+    // The last occurrence of an id takes the buffer; earlier duplicates get a copy.
+    let mut remaining: HashMap<PageId, usize> = HashMap::new();
+    for id in page_ids {
+        *remaining.entry(*id).or_default() += 1;
+    }
+
+    let mut results = Vec::with_capacity(page_ids.len());
+    for id in page_ids {
+        let left = remaining.get_mut(id).unwrap();
+        *left -= 1;
+        if *left == 0 {
+            results.push(pages.remove(id).unwrap());
+            continue;
+        }
+        match pages.get(id).unwrap() {
+            Ok(buf) => {
+                let mut copy = AlignedBuf::new(buf.len());
+                copy.copy_from_slice(buf);
+                results.push(Ok(copy));
+            }
+            // Errors can't be cloned; a duplicate of a failed page simply retries on its own.
+            Err(_) => results.push(read_one(store, *id).await),
+        }
+    }
+    results
+}
+
+/// Splits sorted, distinct ids into runs of physically adjacent pages.
+fn coalesce(sorted: &[PageId]) -> Vec<Vec<PageId>> {
+    let mut runs: Vec<Vec<PageId>> = Vec::new();
+    for &id in sorted {
+        match runs.last_mut() {
+            Some(run)
+                if run.len() < MAX_COALESCED_PAGES
+                    && run[0].db_id == id.db_id
+                    && run[0].space_id == id.space_id
+                    && run.last().unwrap().page_no + 1 == id.page_no =>
+            {
+                run.push(id)
+            }
+            _ => runs.push(vec![id]),
+        }
+    }
+    runs
+}
+
+/// Reads one run with a single vectored call. If the run fails as a whole, falls back
+/// to page-by-page reads so one bad page doesn't poison its neighbours' results.
+async fn read_run<S: PageStore>(store: &S, run: &[PageId]) -> Vec<Result<AlignedBuf, StorageError>> {
+    let bufs = run.iter().map(|_| AlignedBuf::page()).collect();
+    let (bufs, res) = store.read_pages(run[0], bufs).await;
+    if res.is_ok() {
+        return bufs.into_iter().map(Ok).collect();
+    }
+
+    let mut results = Vec::with_capacity(run.len());
+    for (&id, buf) in run.iter().zip(bufs) {
+        let (buf, res) = store.read_page(id, buf).await;
+        results.push(res.map(|_| buf));
+    }
+    results
+}
+
+async fn read_one<S: PageStore>(store: &S, id: PageId) -> Result<AlignedBuf, StorageError> {
+    let (buf, res) = store.read_page(id, AlignedBuf::page()).await;
+    res.map(|_| buf)
+}
@@ -0,0 +1,232 @@
+// -----------------------------------------------------------------------------
+// Tuple Versions
+//
+// Under MVCC an update or delete never overwrites a row in place: each version of it
+// is a heap tuple of its own (see `heap.rs`), stamped with the transactions that
+// created and deleted it, and a reader picks the versions its snapshot sees. Readers
+// take no locks, only page latches, so they never wait for writers, nor writers for
+// them. A version's tuple starts with its header:
+//
+//   [0..8)    xmin          transaction that inserted it
+//   [8..16)   xmax          transaction that deleted it (INVALID_XID: none)
+//   [16..20)  cmin          command of xmin that inserted it
+//   [20..24)  cmax          command of xmax that deleted it
+//   [24..)    the row
+//
+// A snapshot is the set of transactions whose changes its holder sees: every one
+// below `xmin`, and those from `xmin` up to `xmax` not in `active`, provided they
+// committed (`TransactionStatus`). A transaction sees its own changes made by earlier
+// commands than its current one (`cid`), so a statement never sees its own writes.
+// A version is visible if its insert is seen and its delete, if any, is not.
+//
+// Transaction ids, snapshots and commit status are the transaction manager's
+// (`txn.rs`); this only answers visibility for them. Nothing undoes an aborted
+// transaction's versions: no snapshot sees them, and they are reclaimed like the
+// deleted ones. A version is dead once no snapshot can see it again: its insert
+// aborted, or its delete committed below `horizon`, the oldest `xmin` of the snapshots
+// still held. Vacuum removes dead versions page by page (`HeapFile::prune_page`).
+// -----------------------------------------------------------------------------
+
+/// No transaction: the `xmax` of a version not deleted.
+pub const INVALID_XID: u64 = 0;
+
+/// Inserted by a transaction old enough for every snapshot to see: the `xmin` of a
+/// frozen version.
+pub const FROZEN_XID: u64 = 1;
+
+/// Bytes of the header before a version's row.
+pub const HEADER_SIZE: usize = 24;
+
+/// The outcome of a transaction, as far as is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XidStatus {
+    InProgress,
+    Committed,
+    Aborted,
+}
+
+/// Where visibility checks learn whether a transaction committed.
+pub trait TransactionStatus {
+    fn status(&self, xid: u64) -> XidStatus;
+}
+
+/// The transactions that created and deleted a version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TupleHeader {
+    pub xmin: u64,
+    pub xmax: u64,
+    pub cmin: u32,
+    pub cmax: u32,
+}
+
+impl TupleHeader {
+    /// A version inserted by `xid` in its command `cid`.
+    pub fn inserted(xid: u64, cid: u32) -> Self {
+        Self {
+            xmin: xid,
+            xmax: INVALID_XID,
+            cmin: cid,
+            cmax: 0,
+        }
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.xmin.to_le_bytes());
+        out.extend_from_slice(&self.xmax.to_le_bytes());
+        out.extend_from_slice(&self.cmin.to_le_bytes());
+        out.extend_from_slice(&self.cmax.to_le_bytes());
+    }
+
+    /// The header at the start of `tuple`; `None` if it is too short for one.
+    pub fn decode(tuple: &[u8]) -> Option<Self> {
+        let header = tuple.get(..HEADER_SIZE)?;
+        Some(Self {
+            xmin: u64::from_le_bytes(header[0..8].try_into().unwrap()),
+            xmax: u64::from_le_bytes(header[8..16].try_into().unwrap()),
+            cmin: u32::from_le_bytes(header[16..20].try_into().unwrap()),
+            cmax: u32::from_le_bytes(header[20..24].try_into().unwrap()),
+        })
+    }
+
+    /// Whether no snapshot taken with a `xmin` of at least `horizon` can see the
+    /// version.
+    pub fn is_dead(&self, horizon: u64, status: &impl TransactionStatus) -> bool {
+        if self.xmin != FROZEN_XID && status.status(self.xmin) == XidStatus::Aborted {
+            return true;
+        }
+        self.xmax != INVALID_XID && self.xmax < horizon && status.status(self.xmax) == XidStatus::Committed
+    }
+}
+
+/// The transactions whose changes a reader sees.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The reader's own transaction; `INVALID_XID` for one that writes nothing.
+    pub xid: u64,
+    /// The reader's current command: its own changes from earlier ones are seen.
+    pub cid: u32,
+    /// Every transaction below this had finished when the snapshot was taken.
+    pub xmin: u64,
+    /// Every transaction from this on started after the snapshot was taken.
+    pub xmax: u64,
+    // In progress when the snapshot was taken, between xmin and xmax; sorted
+    active: Vec<u64>,
+}
+
+impl Snapshot {
+    pub fn new(xid: u64, cid: u32, xmin: u64, xmax: u64, mut active: Vec<u64>) -> Self {
+        active.sort_unstable();
+        Self { xid, cid, xmin, xmax, active }
+    }
+
+    /// Transactions in progress when the snapshot was taken.
+    pub fn active(&self) -> &[u64] {
+        &self.active
+    }
+
+    /// Whether `xid` was still running, or not yet started, as the snapshot has it.
+    /// Its changes are not seen even once it commits.
+    pub fn in_progress(&self, xid: u64) -> bool {
+        xid >= self.xmax || (xid >= self.xmin && self.active.binary_search(&xid).is_ok())
+    }
+
+    /// Whether the snapshot sees the changes of `xid`, another transaction.
+    pub fn sees(&self, xid: u64, status: &impl TransactionStatus) -> bool {
+        xid == FROZEN_XID || (!self.in_progress(xid) && status.status(xid) == XidStatus::Committed)
+    }
+
+    /// Whether the snapshot sees the version with `header`.
+    pub fn is_visible(&self, header: &TupleHeader, status: &impl TransactionStatus) -> bool {
+        let inserted = match header.xmin {
+            xmin if xmin == self.xid => header.cmin < self.cid,
+            xmin => self.sees(xmin, status),
+        };
+        if !inserted {
+            return false;
+        }
+        match header.xmax {
+            INVALID_XID => true,
+            xmax if xmax == self.xid => header.cmax >= self.cid,
+            xmax => !self.sees(xmax, status),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Commit status from a table; transactions not in it are in progress.
+    #[derive(Default)]
+    pub(crate) struct Statuses(pub HashMap<u64, XidStatus>);
+
+    impl TransactionStatus for Statuses {
+        fn status(&self, xid: u64) -> XidStatus {
+            self.0.get(&xid).copied().unwrap_or(XidStatus::InProgress)
+        }
+    }
+
+    fn statuses(entries: &[(u64, XidStatus)]) -> Statuses {
+        Statuses(entries.iter().copied().collect())
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let header = TupleHeader { xmin: 7, xmax: 9, cmin: 1, cmax: 3 };
+        let mut tuple = Vec::new();
+        header.encode(&mut tuple);
+        assert_eq!(tuple.len(), HEADER_SIZE);
+        tuple.extend_from_slice(b"row");
+        assert_eq!(TupleHeader::decode(&tuple), Some(header));
+        assert_eq!(TupleHeader::decode(&tuple[..HEADER_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn snapshots_see_only_transactions_committed_before_them() {
+        use XidStatus::*;
+        let status = statuses(&[(3, Committed), (5, Committed), (6, Aborted), (8, Committed)]);
+        // Taken while 5 and 7 ran, before 8 started
+        let snapshot = Snapshot::new(INVALID_XID, 0, 4, 8, vec![7, 5]);
+        assert_eq!(snapshot.active(), &[5, 7]);
+        assert!(snapshot.sees(3, &status));
+        assert!(snapshot.sees(FROZEN_XID, &status));
+        assert!(!snapshot.sees(5, &status), "committed after the snapshot was taken");
+        assert!(!snapshot.sees(6, &status), "aborted");
+        assert!(!snapshot.sees(7, &status), "still running");
+        assert!(!snapshot.sees(8, &status), "started after the snapshot was taken");
+
+        assert!(snapshot.is_visible(&TupleHeader::inserted(3, 0), &status));
+        assert!(!snapshot.is_visible(&TupleHeader::inserted(5, 0), &status));
+        let deleted = |xmax| TupleHeader { xmax, ..TupleHeader::inserted(3, 0) };
+        assert!(!snapshot.is_visible(&deleted(3), &status), "deleted before the snapshot");
+        assert!(snapshot.is_visible(&deleted(5), &status), "deleted after the snapshot");
+        assert!(snapshot.is_visible(&deleted(6), &status), "delete aborted");
+    }
+
+    #[test]
+    fn a_transaction_sees_its_own_earlier_commands_only() {
+        let status = Statuses::default();
+        let snapshot = Snapshot::new(10, 2, 10, 11, Vec::new());
+        assert!(snapshot.is_visible(&TupleHeader::inserted(10, 1), &status));
+        assert!(!snapshot.is_visible(&TupleHeader::inserted(10, 2), &status), "inserted by the current command");
+        let deleted = |cmax| TupleHeader { xmax: 10, cmax, ..TupleHeader::inserted(FROZEN_XID, 0) };
+        assert!(!snapshot.is_visible(&deleted(1), &status));
+        assert!(snapshot.is_visible(&deleted(2), &status), "deleted by the current command");
+    }
+
+    #[test]
+    fn versions_die_once_their_delete_committed_below_the_horizon() {
+        use XidStatus::*;
+        let status = statuses(&[(3, Committed), (4, Aborted), (5, Committed)]);
+        assert!(TupleHeader::inserted(4, 0).is_dead(10, &status), "insert aborted");
+        assert!(!TupleHeader::inserted(3, 0).is_dead(10, &status));
+        assert!(!TupleHeader::inserted(FROZEN_XID, 0).is_dead(10, &status));
+        let deleted = |xmax| TupleHeader { xmax, ..TupleHeader::inserted(3, 0) };
+        assert!(deleted(5).is_dead(6, &status));
+        assert!(!deleted(5).is_dead(5, &status), "a snapshot at the horizon still sees it");
+        assert!(!deleted(4).is_dead(10, &status), "delete aborted");
+        assert!(!deleted(9).is_dead(10, &status), "delete still running");
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::io;
+
+use crate::traits::{AlignedBuf, StorageError};
+
+// -----------------------------------------------------------------------------
+// NUMA Placement
+//
+// On a multi-socket machine each socket has its own memory (a NUMA node), and reaching
+// another node's memory costs latency and interconnect bandwidth on every access. With
+// `StorageConfig::numa_aware`, each core's buffer pool frames are bound to the core's
+// node with mbind(2), and the core's worker thread is kept on that node's CPUs with
+// sched_setaffinity(2), so page accesses stay on the socket. Core ids are taken to be
+// CPU numbers. The topology comes from sysfs:
+//
+//   /sys/devices/system/node/node<N>/cpulist   CPUs of node N, e.g. "0-15,32-47"
+//
+// On a single-node machine (or without sysfs) there is nothing to place, and none of
+// this does anything.
+// -----------------------------------------------------------------------------
+
+const NODE_DIR: &str = "/sys/devices/system/node";
+
+// From <linux/mempolicy.h>
+const MPOL_BIND: libc::c_int = 2;
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+/// A NUMA node and the CPUs attached to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumaNode {
+    pub id: usize,
+    pub cpus: Vec<usize>,
+}
+
+/// The machine's NUMA nodes, by id. Empty if the kernel exposes none.
+pub fn nodes() -> Result<Vec<NumaNode>, StorageError> {
+    let entries = match fs::read_dir(NODE_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+    let mut nodes = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(StorageError::Io)?;
+        let Some(id) = entry.file_name().to_str().and_then(|name| name.strip_prefix("node")?.parse().ok()) else {
+            continue;
+        };
+        let list = fs::read_to_string(entry.path().join("cpulist")).map_err(StorageError::Io)?;
+        nodes.push(NumaNode { id, cpus: parse_cpu_list(&list)? });
+    }
+    nodes.sort_by_key(|node| node.id);
+    Ok(nodes)
+}
+
+/// The node `cpu` belongs to, on a machine with more than one; `None` where placement
+/// makes no difference.
+pub fn cpu_node(cpu: usize) -> Result<Option<NumaNode>, StorageError> {
+    let nodes = nodes()?;
+    if nodes.len() < 2 {
+        return Ok(None);
+    }
+    Ok(nodes.into_iter().find(|node| node.cpus.contains(&cpu)))
+}
+
+/// Restricts the calling thread to `node`'s CPUs.
+pub fn pin_thread(node: &NumaNode) -> Result<(), StorageError> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in &node.cpus {
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    let rc = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if rc != 0 {
+        return Err(StorageError::Io(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Binds `buf`'s memory to node `node`, moving pages already placed elsewhere.
+pub fn bind(buf: &AlignedBuf, node: usize) -> Result<(), StorageError> {
+    const BITS: usize = libc::c_ulong::BITS as usize;
+    let mut mask = vec![0 as libc::c_ulong; node / BITS + 1];
+    mask[node / BITS] |= 1 << (node % BITS);
+    // The kernel reads `maxnode - 1` bits of the mask.
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            buf.as_ptr(),
+            buf.len(),
+            MPOL_BIND,
+            mask.as_ptr(),
+            mask.len() * BITS + 1,
+            MPOL_MF_MOVE,
+        )
+    };
+    if rc != 0 {
+        return Err(StorageError::Io(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// Parses a sysfs CPU list: comma-separated CPUs and inclusive ranges.
+fn parse_cpu_list(list: &str) -> Result<Vec<usize>, StorageError> {
+    let invalid = || StorageError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("invalid CPU list {:?}", list)));
+    let mut cpus = Vec::new();
+    for part in list.trim().split(',').filter(|part| !part.is_empty()) {
+        let (first, last): (usize, usize) = match part.split_once('-') {
+            Some((first, last)) => (first.parse().map_err(|_| invalid())?, last.parse().map_err(|_| invalid())?),
+            None => {
+                let cpu = part.parse().map_err(|_| invalid())?;
+                (cpu, cpu)
+            }
+        };
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
@@ -0,0 +1,95 @@
+use crate::traits::Lsn;
+
+/// Size of every data page on disk and in the buffer pool.
+pub const PAGE_SIZE: usize = 8192;
+
+// -----------------------------------------------------------------------------
+// Page Header Layout (first 24 bytes of every 8KB page)
+//
+//   [0..4)   checksum   CRC over bytes [4..PAGE_SIZE), see `checksum.rs`
+//   [4..12)  page_lsn   LSN of the last WAL record applied to this page
+//   [12..14) page_type
+//   [14..16) flags
+//   [16..20) dict_version  compression dictionary generation (0 = none)
+//   [20..24) origin_checksum  caller's page CRC, carried through compressed/encrypted
+//            images in end-to-end checksum mode (0 otherwise)
+// -----------------------------------------------------------------------------
+pub const CHECKSUM_OFFSET: usize = 0;
+pub const LSN_OFFSET: usize = 4;
+pub const PAGE_TYPE_OFFSET: usize = 12;
+pub const FLAGS_OFFSET: usize = 14;
+pub const DICT_VERSION_OFFSET: usize = 16;
+pub const ORIGIN_CHECKSUM_OFFSET: usize = 20;
+pub const PAGE_HEADER_SIZE: usize = 24;
+
+// Page flags
+pub const FLAG_COMPRESSED_LZ4: u16 = 1 << 0;  // On-disk frame is LZ4 compressed (see `compression.rs`)
+pub const FLAG_COMPRESSED_ZSTD: u16 = 1 << 1; // On-disk frame is zstd compressed
+pub const FLAG_ENCRYPTED: u16 = 1 << 2;       // On-disk payload is AES-XTS encrypted (see `encryption.rs`)
+
+/// Everything after the checksum field is covered by the checksum.
+pub const CHECKSUM_COVERAGE_START: usize = CHECKSUM_OFFSET + 4;
+
+pub fn checksum(page: &[u8]) -> u32 {
+    u32::from_le_bytes(page[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].try_into().unwrap())
+}
+
+pub fn set_checksum(page: &mut [u8], crc: u32) {
+    page[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+}
+
+pub fn page_lsn(page: &[u8]) -> Lsn {
+    Lsn(u64::from_le_bytes(page[LSN_OFFSET..LSN_OFFSET + 8].try_into().unwrap()))
+}
+
+pub fn set_page_lsn(page: &mut [u8], lsn: Lsn) {
+    page[LSN_OFFSET..LSN_OFFSET + 8].copy_from_slice(&lsn.0.to_le_bytes());
+}
+
+pub fn page_type(page: &[u8]) -> u16 {
+    u16::from_le_bytes(page[PAGE_TYPE_OFFSET..PAGE_TYPE_OFFSET + 2].try_into().unwrap())
+}
+
+pub fn set_page_type(page: &mut [u8], page_type: u16) {
+    page[PAGE_TYPE_OFFSET..PAGE_TYPE_OFFSET + 2].copy_from_slice(&page_type.to_le_bytes());
+}
+
+pub fn flags(page: &[u8]) -> u16 {
+    u16::from_le_bytes(page[FLAGS_OFFSET..FLAGS_OFFSET + 2].try_into().unwrap())
+}
+
+pub fn set_flags(page: &mut [u8], flags: u16) {
+    page[FLAGS_OFFSET..FLAGS_OFFSET + 2].copy_from_slice(&flags.to_le_bytes());
+}
+
+pub fn add_flags(page: &mut [u8], flags: u16) {
+    let current = self::flags(page);
+    set_flags(page, current | flags);
+}
+
+pub fn clear_flags(page: &mut [u8], flags: u16) {
+    let current = self::flags(page);
+    set_flags(page, current & !flags);
+}
+
+pub fn dict_version(page: &[u8]) -> u32 {
+    u32::from_le_bytes(page[DICT_VERSION_OFFSET..DICT_VERSION_OFFSET + 4].try_into().unwrap())
+}
+
+pub fn set_dict_version(page: &mut [u8], version: u32) {
+    page[DICT_VERSION_OFFSET..DICT_VERSION_OFFSET + 4].copy_from_slice(&version.to_le_bytes());
+}
+
+pub fn origin_checksum(page: &[u8]) -> u32 {
+    u32::from_le_bytes(page[ORIGIN_CHECKSUM_OFFSET..ORIGIN_CHECKSUM_OFFSET + 4].try_into().unwrap())
+}
+
+pub fn set_origin_checksum(page: &mut [u8], crc: u32) {
+    page[ORIGIN_CHECKSUM_OFFSET..ORIGIN_CHECKSUM_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// A page that was never written reads back as all zeros, checksum field included.
+/// No stamped page can look like this, since the CRC of an all-zero body is nonzero.
+pub fn is_fresh(page: &[u8]) -> bool {
+    page.iter().all(|&b| b == 0)
+}
@@ -0,0 +1,206 @@
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::traits::PageId;
+
+// -----------------------------------------------------------------------------
+// Buffer Pool Page Table
+//
+// Maps each cached `PageId` to its frame. Only the owning core inserts and removes,
+// but any core may look pages up (see `PageRouter::is_cached`), so the table is a
+// fixed array of slots with open addressing and linear probing, read without locks:
+//
+//   slot   version  u32      even when stable, odd while being written (a seqlock)
+//          key      3 x u32  db_id, space_id, page_no
+//          frame    u32      frame index, or EMPTY
+//
+// The array holds twice the pool's frames, so it is at most half full and probes stay
+// short. A reader copies each slot between two loads of its version and retries the
+// slot if they differ or are odd, so it never sees half a write. An insert only fills
+// an empty slot, which can't hide other keys from a probe. A removal shifts the entries
+// after the freed slot back into it (no tombstones), which can move an entry behind a
+// probe in progress; removals therefore bump the table-wide `epoch`, another seqlock,
+// and a lookup that overlapped one starts over. Lookups on the owning core never do.
+//
+// Writers serialize on a mutex, uncontended since they all run on the owning core.
+// -----------------------------------------------------------------------------
+
+const EMPTY: u32 = u32::MAX;
+
+struct Slot {
+    version: AtomicU32,
+    key: [AtomicU32; 3],
+    frame: AtomicU32,
+}
+
+/// A page table readable from any thread, written by one core.
+pub struct PageTable {
+    slots: Box<[Slot]>,
+    mask: usize,
+    epoch: AtomicU64,
+    len: AtomicU32,
+    writer: Mutex<()>,
+}
+
+impl PageTable {
+    /// A table for up to `frames` pages.
+    pub fn new(frames: usize) -> Self {
+        assert!(frames < EMPTY as usize, "page table of {} frames is too large", frames);
+        let slots = (frames * 2).max(16).next_power_of_two();
+        Self {
+            slots: (0..slots)
+                .map(|_| Slot {
+                    version: AtomicU32::new(0),
+                    key: Default::default(),
+                    frame: AtomicU32::new(EMPTY),
+                })
+                .collect(),
+            mask: slots - 1,
+            epoch: AtomicU64::new(0),
+            len: AtomicU32::new(0),
+            writer: Mutex::new(()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The frame holding `page_id`, if cached.
+    pub fn get(&self, page_id: PageId) -> Option<usize> {
+        let key = key(page_id);
+        loop {
+            let epoch = self.epoch.load(Ordering::Acquire);
+            if epoch.is_multiple_of(2) {
+                let found = self.find(key).map(|(_, frame)| frame as usize);
+                fence(Ordering::Acquire);
+                if self.epoch.load(Ordering::Relaxed) == epoch {
+                    return found;
+                }
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    pub fn contains(&self, page_id: PageId) -> bool {
+        self.get(page_id).is_some()
+    }
+
+    /// Maps `page_id` to `frame`, replacing any previous mapping. Owning core only.
+    pub fn insert(&self, page_id: PageId, frame: usize) {
+        let _writer = self.writer.lock().unwrap();
+        let key = key(page_id);
+        let mut pos = self.home(key);
+        loop {
+            let (slot_key, slot_frame) = self.read_slot(pos);
+            if slot_frame == EMPTY {
+                break;
+            }
+            if slot_key == key {
+                self.write_slot(pos, key, frame as u32);
+                return;
+            }
+            pos = (pos + 1) & self.mask;
+        }
+        assert!(self.len() < self.slots.len() / 2, "page table is full");
+        self.write_slot(pos, key, frame as u32);
+        self.len.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Unmaps `page_id`, returning its frame. Owning core only.
+    pub fn remove(&self, page_id: PageId) -> Option<usize> {
+        let _writer = self.writer.lock().unwrap();
+        let (mut hole, frame) = self.find(key(page_id))?;
+
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        self.epoch.store(epoch + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        // Pull back each following entry whose probe would otherwise cross the hole.
+        let mut pos = hole;
+        loop {
+            pos = (pos + 1) & self.mask;
+            let (key, frame) = self.read_slot(pos);
+            if frame == EMPTY {
+                break;
+            }
+            // Whether the entry's home lies cyclically in (hole, pos]: then it stays put.
+            let home = self.home(key);
+            if (pos.wrapping_sub(home) & self.mask) < (pos.wrapping_sub(hole) & self.mask) {
+                continue;
+            }
+            self.write_slot(hole, key, frame);
+            hole = pos;
+        }
+        self.write_slot(hole, [0; 3], EMPTY);
+        self.epoch.store(epoch + 2, Ordering::Release);
+
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        Some(frame as usize)
+    }
+
+    fn home(&self, key: [u32; 3]) -> usize {
+        hash(key) & self.mask
+    }
+
+    // Position of the slot holding `key`, and its frame as of the same read
+    fn find(&self, key: [u32; 3]) -> Option<(usize, u32)> {
+        let mut pos = self.home(key);
+        // At most half the slots are taken, so the probe ends.
+        loop {
+            let (slot_key, frame) = self.read_slot(pos);
+            if frame == EMPTY {
+                return None;
+            }
+            if slot_key == key {
+                return Some((pos, frame));
+            }
+            pos = (pos + 1) & self.mask;
+        }
+    }
+
+    // A consistent copy of the slot's key and frame
+    fn read_slot(&self, pos: usize) -> ([u32; 3], u32) {
+        let slot = &self.slots[pos];
+        loop {
+            let version = slot.version.load(Ordering::Acquire);
+            if version.is_multiple_of(2) {
+                let key = [0, 1, 2].map(|i| slot.key[i].load(Ordering::Relaxed));
+                let frame = slot.frame.load(Ordering::Relaxed);
+                fence(Ordering::Acquire);
+                if slot.version.load(Ordering::Relaxed) == version {
+                    return (key, frame);
+                }
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    fn write_slot(&self, pos: usize, key: [u32; 3], frame: u32) {
+        let slot = &self.slots[pos];
+        let version = slot.version.load(Ordering::Relaxed);
+        slot.version.store(version + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (field, value) in slot.key.iter().zip(key) {
+            field.store(value, Ordering::Relaxed);
+        }
+        slot.frame.store(frame, Ordering::Relaxed);
+        slot.version.store(version + 2, Ordering::Release);
+    }
+}
+
+fn key(page_id: PageId) -> [u32; 3] {
+    [page_id.db_id, page_id.space_id, page_id.page_no]
+}
+
+// Multiply-shift over the key, keeping the well mixed high bits.
+fn hash(key: [u32; 3]) -> usize {
+    let mut h = 0u64;
+    for part in key {
+        h = (h ^ part as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+    (h >> 32) as usize
+}
@@ -0,0 +1,235 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::compression;
+use crate::page::{self, PAGE_SIZE};
+use crate::segment::{self, SegmentHeader, EXTENT_PAGES, PAGES_PER_SEGMENT};
+use crate::traits::{PageId, StorageError};
+
+// -----------------------------------------------------------------------------
+// Page Verification
+//
+// `verify_pages` checks the checksum of every page in use in the segments of a stopped
+// engine: those of one space, one database, or the whole data directory. A segment's
+// header says which extents are allocated and with which algorithm its pages are
+// stamped; only the pages of allocated extents below its high-water mark are read,
+// since freed ones may hold anything. Pages still all zeros were allocated and never written, and
+// pass. A compressed page is checked by its frame's checksum, as on a read.
+//
+// Runs of up to RUN_EXTENTS allocated extents are read at once, the way `read_pages`
+// reads a range: one read for the run, split into pages after. Worker threads take
+// runs off a shared list, so a large space is checked by all of them, not one each.
+// A page past the end of a file cut short counts as bad, and a segment whose header
+// can't be read is reported as such, with none of its pages checked.
+// -----------------------------------------------------------------------------
+
+/// Extents read at once: 1 MiB.
+const RUN_EXTENTS: u32 = 16;
+
+/// What `verify_pages` found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PageVerifyReport {
+    /// Segment files whose pages were checked.
+    pub segments: u64,
+    /// Pages of allocated extents read, and of those, the ones never written.
+    pub pages: u64,
+    pub unwritten: u64,
+    /// Pages failing their checksum or missing from their file, in page order.
+    pub bad_pages: Vec<PageId>,
+    /// Segment files that couldn't be checked, and why.
+    pub bad_segments: Vec<(PathBuf, String)>,
+}
+
+impl PageVerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.bad_pages.is_empty() && self.bad_segments.is_empty()
+    }
+}
+
+// A stretch of allocated extents of one segment
+struct Run {
+    segment: usize,
+    first_extent: u32,
+    extents: u32,
+}
+
+// What a worker found in the runs it took
+#[derive(Default)]
+struct Tally {
+    pages: u64,
+    unwritten: u64,
+    bad_pages: Vec<PageId>,
+}
+
+/// Checks the pages in use in the segments under `data_dir`, of database `db_id` and
+/// space `space_id` if given, on `threads` threads. The engine must not be running.
+pub fn verify_pages(data_dir: &Path, db_id: Option<u32>, space_id: Option<u32>, threads: usize) -> Result<PageVerifyReport, StorageError> {
+    let mut report = PageVerifyReport::default();
+    let mut segments: Vec<(File, SegmentHeader)> = Vec::new();
+    for path in segment::segment_files(data_dir)? {
+        let Some((seg_db_id, seg_space_id)) = segment_ids(&path) else {
+            continue;
+        };
+        if db_id.is_some_and(|id| id != seg_db_id) || space_id.is_some_and(|id| id != seg_space_id) {
+            continue;
+        }
+        let opened = segment::read_header(&path).and_then(|header| Ok((File::open(&path).map_err(StorageError::Io)?, header)));
+        match opened {
+            Ok(segment) => segments.push(segment),
+            Err(e) => report.bad_segments.push((path, format!("{:?}", e))),
+        }
+    }
+    report.segments = segments.len() as u64;
+
+    let runs: Vec<Run> = segments.iter().enumerate().flat_map(|(segment, (_, header))| runs(segment, header)).collect();
+    let next = AtomicUsize::new(0);
+    let tallies = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.max(1))
+            .map(|_| {
+                thread::Builder::new()
+                    .name("storage-verify".to_string())
+                    .spawn_scoped(scope, || {
+                        let mut tally = Tally::default();
+                        let mut buf = vec![0u8; (RUN_EXTENTS * EXTENT_PAGES) as usize * PAGE_SIZE];
+                        while let Some(run) = runs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            let (file, header) = &segments[run.segment];
+                            check_run(file, header, run, &mut buf, &mut tally)?;
+                        }
+                        Ok(tally)
+                    })
+                    .expect("failed to spawn verify thread")
+            })
+            .collect();
+        workers.into_iter().map(|worker| worker.join().unwrap()).collect::<Result<Vec<Tally>, StorageError>>()
+    })?;
+
+    for tally in tallies {
+        report.pages += tally.pages;
+        report.unwritten += tally.unwritten;
+        report.bad_pages.extend(tally.bad_pages);
+    }
+    report.bad_pages.sort_by_key(|page_id| (page_id.db_id, page_id.space_id, page_id.page_no));
+    Ok(report)
+}
+
+// (db_id, space_id) of a segment file, from its directory and name
+fn segment_ids(path: &Path) -> Option<(u32, u32)> {
+    let db_dir = path.parent()?.file_name()?.to_str()?;
+    let db_id = db_dir.strip_prefix("db_")?.parse().ok()?;
+    let (space_id, _) = segment::parse_segment_file_name(path.file_name()?.to_str()?)?;
+    Some((db_id, space_id))
+}
+
+// The runs of allocated extents below the segment's high-water mark
+fn runs(segment: usize, header: &SegmentHeader) -> Vec<Run> {
+    let mut runs: Vec<Run> = Vec::new();
+    for extent in 0..header.allocated_pages.div_ceil(EXTENT_PAGES) {
+        if !header.extents.is_allocated(extent) {
+            continue;
+        }
+        match runs.last_mut() {
+            Some(run) if run.segment == segment && run.first_extent + run.extents == extent && run.extents < RUN_EXTENTS => run.extents += 1,
+            _ => runs.push(Run { segment, first_extent: extent, extents: 1 }),
+        }
+    }
+    runs
+}
+
+fn check_run(file: &File, header: &SegmentHeader, run: &Run, buf: &mut [u8], tally: &mut Tally) -> Result<(), StorageError> {
+    let first_page = run.first_extent * EXTENT_PAGES;
+    // The last extent may be handed out only in part, and a sparse file ends there.
+    let pages = (run.extents * EXTENT_PAGES).min(header.allocated_pages - first_page) as usize;
+    let buf = &mut buf[..pages * PAGE_SIZE];
+    // Data pages start after the header page
+    let len = read_at(file, (first_page as u64 + 1) * PAGE_SIZE as u64, buf)?;
+
+    for (index, page) in buf.chunks(PAGE_SIZE).enumerate() {
+        tally.pages += 1;
+        let whole = (index + 1) * PAGE_SIZE <= len;
+        if whole && page::is_fresh(page) {
+            tally.unwritten += 1;
+        } else if !whole || !intact(header.checksum, page) {
+            tally.bad_pages.push(PageId {
+                db_id: header.db_id,
+                space_id: header.space_id,
+                page_no: header.seg_no * PAGES_PER_SEGMENT + first_page + index as u32,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Whether a written page passes its checksum, or its frame's if compressed
+fn intact(algorithm: ChecksumAlgorithm, page: &[u8]) -> bool {
+    match compression::is_compressed(page) {
+        true => compression::verify_frame(algorithm, page),
+        false => checksum::verify_page(algorithm, page),
+    }
+}
+
+// Reads up to `buf.len()` bytes at `offset`; short only at the end of the file
+fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> Result<usize, StorageError> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read_at(&mut buf[len..], offset + len as u64) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(StorageError::Io(e)),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileExt;
+
+    use super::*;
+    use crate::core_storage::CoreStorage;
+    use crate::page::PAGE_HEADER_SIZE;
+    use crate::traits::{AlignedBuf, PageStore, StorageConfig};
+
+    #[test]
+    fn pages_above_the_high_water_mark_are_not_checked() {
+        let dir = std::env::temp_dir().join(format!("aquifer-page_verify-{}-sparse", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::create_dir_all(dir.join("wal")).unwrap();
+        let config = StorageConfig::scratch(dir.join("data"), dir.join("wal"));
+
+        // Three pages of the first extent handed out one at a time, the last never
+        // written; a sparse segment ends right after them.
+        tokio_uring::start(async {
+            let storage = CoreStorage::new(&config, 0);
+            for i in 0..3u8 {
+                let page_no = storage.allocate_extent(1, 1, 1).await.unwrap();
+                if i == 2 {
+                    continue;
+                }
+                let mut page = AlignedBuf::page();
+                page[PAGE_HEADER_SIZE..].fill(i + 1);
+                checksum::stamp_page(config.checksum, &mut page);
+                let (_, res) = storage.write_page(PageId { db_id: 1, space_id: 1, page_no }, page).await;
+                res.unwrap();
+            }
+        });
+
+        let report = verify_pages(&config.data_dir, None, None, 2).unwrap();
+        assert_eq!((report.segments, report.pages, report.unwritten), (1, 3, 1));
+        assert!(report.is_clean(), "{:?}", report);
+
+        // A flipped byte in the second page is found.
+        let path = segment::segment_path(&config.data_dir, 1, 1, 0);
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_at(&[0xFF], 2 * PAGE_SIZE as u64 + 100).unwrap();
+        let report = verify_pages(&config.data_dir, Some(1), Some(1), 1).unwrap();
+        assert_eq!(report.bad_pages, vec![PageId { db_id: 1, space_id: 1, page_no: 1 }]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,280 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, OnceLock};
+
+use futures::channel::{mpsc, oneshot};
+use futures::future::LocalBoxFuture;
+use futures::StreamExt;
+
+use crate::buffer_pool::BufferPool;
+use crate::checksum;
+use crate::core_storage::CoreStorage;
+use crate::lock::LockManager;
+use crate::page;
+use crate::page_table::PageTable;
+use crate::traits::{Lsn, PageId, StorageError, WalStore};
+use crate::undo::{self, AbortRecord, PageUpdate, UndoEntry, UndoLog};
+use crate::wal_record::WalRecordType;
+use crate::warmup;
+
+// -----------------------------------------------------------------------------
+// Buffer Pool Partitions
+//
+// Every page belongs to exactly one core, picked by hashing its database, space and
+// run of OWNERSHIP_RUN pages: only that core's buffer pool caches, reads and writes
+// it. Whole runs keep a sequential scan's pages on one core, where read-ahead still
+// sees them in order.
+//
+// Another core gets at a page by shipping the work to the owner: a closure, sent over
+// the owner's inbox, that runs against the owner's pool and sends its result back.
+// Nothing is locked across cores; a remote access costs two channel hops, and changes
+// are logged in the owner's WAL. The one thing shared is each pool's page table, which
+// any core can read without locks (see `page_table.rs`): whether a page is cached on
+// its owner is known without asking it.
+// -----------------------------------------------------------------------------
+
+/// Consecutive pages owned by the same core.
+pub const OWNERSHIP_RUN: u32 = 64;
+
+/// The core, of `cores`, owning `page_id`.
+pub fn owning_core(page_id: PageId, cores: usize) -> usize {
+    let mut key = [0u8; 12];
+    key[..4].copy_from_slice(&page_id.db_id.to_le_bytes());
+    key[4..8].copy_from_slice(&page_id.space_id.to_le_bytes());
+    key[8..].copy_from_slice(&(page_id.page_no / OWNERSHIP_RUN).to_le_bytes());
+    checksum::crc32c(&key) as usize % cores
+}
+
+// Work shipped to a page's owner, run against its pool
+type RemoteOp = Box<dyn for<'a> FnOnce(&'a BufferPool<CoreStorage>) -> LocalBoxFuture<'a, ()> + Send>;
+
+/// Cross-core requests waiting for one core's `PagePartition::serve`.
+pub struct PageInbox(mpsc::UnboundedReceiver<RemoteOp>);
+
+/// Sends work to the core owning a page. Cheap to clone, and `Send`: every core holds
+/// one.
+#[derive(Clone)]
+pub struct PageRouter {
+    cores: Arc<Vec<mpsc::UnboundedSender<RemoteOp>>>,
+    // Each core's page table, once its partition is built
+    page_tables: Arc<Vec<OnceLock<Arc<PageTable>>>>,
+}
+
+impl PageRouter {
+    /// A router over `cores` cores, and each core's inbox, indexed by core id.
+    pub fn new(cores: usize) -> (Self, Vec<PageInbox>) {
+        let (senders, inboxes) = (0..cores)
+            .map(|_| {
+                let (tx, rx) = mpsc::unbounded();
+                (tx, PageInbox(rx))
+            })
+            .unzip();
+        let page_tables = (0..cores).map(|_| OnceLock::new()).collect();
+        (
+            Self {
+                cores: Arc::new(senders),
+                page_tables: Arc::new(page_tables),
+            },
+            inboxes,
+        )
+    }
+
+    pub fn cores(&self) -> usize {
+        self.cores.len()
+    }
+
+    pub fn owner(&self, page_id: PageId) -> usize {
+        owning_core(page_id, self.cores.len())
+    }
+
+    /// Whether `page_id` is cached on its owning core. Only a hint: the page may be
+    /// loaded or evicted right after.
+    pub fn is_cached(&self, page_id: PageId) -> bool {
+        self.page_tables[self.owner(page_id)].get().is_some_and(|table| table.contains(page_id))
+    }
+
+    fn submit(&self, core_id: usize, op: RemoteOp) -> Result<(), StorageError> {
+        self.cores[core_id].unbounded_send(op).map_err(|_| StorageError::CoreUnavailable(core_id))
+    }
+}
+
+/// One core's share of the buffer pool, and its way to the others'.
+pub struct PagePartition {
+    core_id: usize,
+    pool: Rc<BufferPool<CoreStorage>>,
+    router: PageRouter,
+    inbox: RefCell<Option<PageInbox>>,
+    locks: LockManager,
+}
+
+impl PagePartition {
+    pub fn new(core_id: usize, pool: Rc<BufferPool<CoreStorage>>, router: PageRouter, inbox: PageInbox) -> Self {
+        if router.page_tables[core_id].set(pool.page_table().clone()).is_err() {
+            panic!("buffer pool partition of core {} already built", core_id);
+        }
+        Self {
+            core_id,
+            pool,
+            router,
+            inbox: RefCell::new(Some(inbox)),
+            locks: LockManager::new(),
+        }
+    }
+
+    /// This core's pool. Only pages `is_local` may be requested from it directly.
+    pub fn pool(&self) -> &Rc<BufferPool<CoreStorage>> {
+        &self.pool
+    }
+
+    /// The row and page locks of the transactions running on this core.
+    pub fn locks(&self) -> &LockManager {
+        &self.locks
+    }
+
+    pub fn router(&self) -> &PageRouter {
+        &self.router
+    }
+
+    pub fn is_local(&self, page_id: PageId) -> bool {
+        self.router.owner(page_id) == self.core_id
+    }
+
+    /// Runs `f` over the page, read-latched, on the core owning it.
+    pub async fn read_page<R: Send + 'static>(
+        &self,
+        page_id: PageId,
+        f: impl FnOnce(&[u8]) -> R + Send + 'static,
+    ) -> Result<R, StorageError> {
+        self.on_owner(page_id, move |pool| {
+            Box::pin(async move {
+                let page = pool.get_page(page_id).await?;
+                Ok(f(&page))
+            })
+        })
+        .await
+    }
+
+    /// Runs `f` over the page, write-latched, on the core owning it. `f` returns the
+    /// WAL record describing its change, which is appended to the owner's log before
+    /// the latch is released; its LSN is stamped on the page and returned.
+    pub async fn modify_page(
+        &self,
+        page_id: PageId,
+        f: impl FnOnce(&mut [u8]) -> Option<(WalRecordType, Vec<u8>)> + Send + 'static,
+    ) -> Result<Option<Lsn>, StorageError> {
+        self.on_owner(page_id, move |pool| {
+            Box::pin(async move {
+                let mut page = pool.get_page_mut(page_id).await?;
+                let Some((record_type, payload)) = f(&mut page) else {
+                    return Ok(None);
+                };
+                let lsn = pool.store().append_wal(page_id.db_id, record_type, &payload).await?;
+                page.set_lsn(lsn);
+                Ok(Some(lsn))
+            })
+        })
+        .await
+    }
+
+    /// Overwrites bytes `offset..offset + bytes.len()` of the page for transaction `txn`,
+    /// on the core owning it: logs a PAGE_UPDATE holding the bytes replaced, and adds
+    /// the change to `txn`'s undo chain. Returns its LSN.
+    pub async fn update_page(&self, txn: &mut UndoLog, page_id: PageId, offset: usize, bytes: Vec<u8>) -> Result<Lsn, StorageError> {
+        assert!(
+            offset >= page::PAGE_HEADER_SIZE && offset + bytes.len() <= page::PAGE_SIZE,
+            "update of bytes {}..{} is outside the page body",
+            offset,
+            offset + bytes.len()
+        );
+        assert_eq!(page_id.db_id, txn.db_id(), "transaction updates a page of another database");
+        let xid = txn.xid();
+        let (lsn, before) = self
+            .on_owner(page_id, move |pool| {
+                Box::pin(async move {
+                    let mut page = pool.get_page_mut(page_id).await?;
+                    let range = offset..offset + bytes.len();
+                    let before = page[range.clone()].to_vec();
+                    page[range].copy_from_slice(&bytes);
+                    let update = PageUpdate {
+                        xid,
+                        page_id,
+                        offset: offset as u16,
+                        before,
+                        after: bytes,
+                    };
+                    let lsn = page.log(&update).await?;
+                    Ok((lsn, update.before))
+                })
+            })
+            .await?;
+        txn.push(UndoEntry {
+            lsn,
+            page_id,
+            offset: offset as u16,
+            before,
+        });
+        Ok(lsn)
+    }
+
+    /// Rolls `txn` back: undoes its changes newest first, each on the core owning the
+    /// page with a CLR in that core's WAL, then logs its ABORT here. After an error,
+    /// `txn` holds the changes still to undo; abort it again.
+    pub async fn abort(&self, txn: &mut UndoLog) -> Result<(), StorageError> {
+        let xid = txn.xid();
+        while let Some(entry) = txn.entries().last().cloned() {
+            self.on_owner(entry.page_id, move |pool| Box::pin(undo::undo_locally(pool, xid, entry))).await?;
+            txn.pop();
+        }
+        self.pool.store().append_record(txn.db_id(), &AbortRecord { xid }).await?;
+        Ok(())
+    }
+
+    async fn on_owner<R, F>(&self, page_id: PageId, work: F) -> Result<R, StorageError>
+    where
+        R: Send + 'static,
+        F: for<'a> FnOnce(&'a BufferPool<CoreStorage>) -> LocalBoxFuture<'a, Result<R, StorageError>> + Send + 'static,
+    {
+        let owner = self.router.owner(page_id);
+        if owner == self.core_id {
+            return work(&self.pool).await;
+        }
+        let (tx, rx) = oneshot::channel();
+        self.router.submit(
+            owner,
+            Box::new(move |pool| {
+                Box::pin(async move {
+                    // The requester may have given up waiting.
+                    let _ = tx.send(work(pool).await);
+                })
+            }),
+        )?;
+        rx.await.map_err(|_| StorageError::CoreUnavailable(owner))?
+    }
+
+    /// Records the pages this core's pool holds, for the next mount's `warm_up`. Call on
+    /// a clean shutdown.
+    pub fn save_resident_pages(&self) -> Result<(), StorageError> {
+        warmup::save(self.pool.store().data_dir(), self.core_id, &self.pool.resident_pages())
+    }
+
+    /// If configured, reads back the pages of this core among those every core held at
+    /// the last clean shutdown. Returns the pages loaded.
+    pub async fn warm_up(&self) -> Result<usize, StorageError> {
+        let storage = self.pool.store();
+        if !storage.buffer_pool_warm_up() {
+            return Ok(0);
+        }
+        let mut pages = warmup::load(storage.data_dir())?;
+        pages.retain(|&page_id| self.is_local(page_id));
+        Ok(self.pool.warm_up(&pages).await)
+    }
+
+    /// Serves the other cores' requests for this core's pages, all concurrently.
+    /// Returns once every other core's router is gone; spawn it on this core.
+    pub async fn serve(&self) {
+        let Some(PageInbox(inbox)) = self.inbox.borrow_mut().take() else {
+            panic!("core {} is already serving its pages", self.core_id);
+        };
+        inbox.for_each_concurrent(None, |op| op(&self.pool)).await;
+    }
+}
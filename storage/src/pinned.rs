@@ -0,0 +1,66 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use crate::page::PAGE_SIZE;
+use crate::traits::{AlignedBuf, PageId, StorageError};
+
+/// Upper bound on permanently pinned pages per core (8MB).
+pub const MAX_PINNED_PAGES: usize = 1024;
+
+/// Always-resident copies of hot metadata pages (catalog root, FSM roots, space maps).
+///
+/// Pages are pinned once at mount and never evicted. Reads are served from memory and
+/// writes go through to disk first, then refresh the copy, so the cache never holds
+/// anything that isn't durable-on-write.
+#[derive(Default)]
+pub struct PinnedPages {
+    pages: RefCell<HashMap<PageId, AlignedBuf>>,
+    hits: Cell<u64>,
+}
+
+impl PinnedPages {
+    pub fn contains(&self, page_id: PageId) -> bool {
+        self.pages.borrow().contains_key(&page_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.borrow().is_empty()
+    }
+
+    /// Reads served from the pinned tier since mount.
+    pub fn hits(&self) -> u64 {
+        self.hits.get()
+    }
+
+    pub fn insert(&self, page_id: PageId, page: AlignedBuf) -> Result<(), StorageError> {
+        let mut pages = self.pages.borrow_mut();
+        if pages.len() >= MAX_PINNED_PAGES && !pages.contains_key(&page_id) {
+            return Err(StorageError::OutOfSpace);
+        }
+        pages.insert(page_id, page);
+        Ok(())
+    }
+
+    /// Copies a pinned page into `dst`. Returns `false` if the page isn't pinned.
+    pub fn read_into(&self, page_id: PageId, dst: &mut [u8]) -> bool {
+        match self.pages.borrow().get(&page_id) {
+            Some(page) => {
+                dst[..PAGE_SIZE].copy_from_slice(&page[..PAGE_SIZE]);
+                self.hits.set(self.hits.get() + 1);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Refreshes the copy of a pinned page after it was written; no-op for other pages.
+    pub fn update(&self, page_id: PageId, src: &[u8]) {
+        if let Some(page) = self.pages.borrow_mut().get_mut(&page_id) {
+            page[..PAGE_SIZE].copy_from_slice(&src[..PAGE_SIZE]);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::traits::{PageId, StorageError};
+use crate::wal_recovery;
+use crate::warmup;
+
+// -----------------------------------------------------------------------------
+// Corrupt-Page Quarantine
+//
+// Every page a core finds corrupt on read is recorded in that core's quarantine list,
+// which survives restarts, so higher layers can find and rebuild the pages (from a
+// replica, a backup, or their own redundancy) and then release them:
+//
+//   data_dir/quarantine/core_<id>.pages   the format of a warm-up dump (`warmup.rs`)
+//
+// What the read then returns is `StorageConfig::corrupt_pages`: the corruption error
+// by default, or, for disaster recovery, a zeroed page (`PageState::Fresh`) that the
+// next write-back of the page puts over the damage. Redo likewise fails on a page torn
+// with no image to restore it from, unless `StorageConfig::redo_past_corruption` lets
+// it skip the page's records and leave it quarantined. A page restored from its image
+// during redo is released again.
+//
+// The list is rewritten whole, with blocking I/O, on every change. Corruption is rare
+// enough that this never matters, and a lost entry would be a lost page. A corrupt list
+// fails the next quarantine instead of being dropped.
+// -----------------------------------------------------------------------------
+
+/// What a read returns for a page failing its checksum, once quarantined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorruptPagePolicy {
+    /// `StorageError::Corruption`.
+    #[default]
+    Fail,
+    /// A zeroed page, as if never written. Its contents are lost until rebuilt.
+    Zero,
+}
+
+fn quarantine_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("quarantine")
+}
+
+pub fn quarantine_path(data_dir: &Path, core_id: usize) -> PathBuf {
+    quarantine_dir(data_dir).join(format!("core_{}.pages", core_id))
+}
+
+/// Replaces `core_id`'s quarantine list with `pages`.
+pub fn save(data_dir: &Path, core_id: usize, pages: &[PageId]) -> Result<(), StorageError> {
+    let dir = quarantine_dir(data_dir);
+    fs::create_dir_all(&dir).map_err(StorageError::Io)?;
+    wal_recovery::write_durably(&quarantine_path(data_dir, core_id), &warmup::encode(pages))?;
+    fs::File::open(&dir).and_then(|d| d.sync_all()).map_err(StorageError::Io)
+}
+
+/// `core_id`'s quarantine list, sorted. Empty if it has none.
+pub fn load_core(data_dir: &Path, core_id: usize) -> Result<Vec<PageId>, StorageError> {
+    let path = quarantine_path(data_dir, core_id);
+    match fs::read(&path) {
+        Ok(bytes) => read_list(&path, &bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(StorageError::Io(e)),
+    }
+}
+
+/// Every core's quarantined pages, sorted, without duplicates.
+pub fn load(data_dir: &Path) -> Result<Vec<PageId>, StorageError> {
+    let entries = match fs::read_dir(quarantine_dir(data_dir)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+    let mut pages = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(StorageError::Io)?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("pages") {
+            continue;
+        }
+        let bytes = fs::read(&path).map_err(StorageError::Io)?;
+        pages.extend(read_list(&path, &bytes)?);
+    }
+    pages.sort_by_key(|page_id| (page_id.db_id, page_id.space_id, page_id.page_no));
+    pages.dedup();
+    Ok(pages)
+}
+
+fn read_list(path: &Path, bytes: &[u8]) -> Result<Vec<PageId>, StorageError> {
+    warmup::decode(bytes).ok_or_else(|| {
+        StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("corrupt quarantine list: {}", path.display()),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileExt;
+
+    use super::*;
+    use crate::checksum::{self, ChecksumAlgorithm};
+    use crate::core_storage::CoreStorage;
+    use crate::page;
+    use crate::segment;
+    use crate::traits::{AlignedBuf, PageState, PageStore, StorageConfig};
+
+    fn page_id(page_no: u32) -> PageId {
+        PageId { db_id: 1, space_id: 1, page_no }
+    }
+
+    fn scratch_config(name: &str) -> StorageConfig {
+        let dir = std::env::temp_dir().join(format!("aquifer-quarantine-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::create_dir_all(dir.join("wal")).unwrap();
+        StorageConfig::scratch(dir.join("data"), dir.join("wal"))
+    }
+
+    #[test]
+    fn lists_round_trip_and_merge_across_cores() {
+        let config = scratch_config("lists");
+        let data_dir = &config.data_dir;
+        assert_eq!(load(data_dir).unwrap(), []);
+        save(data_dir, 0, &[page_id(9), page_id(2)]).unwrap();
+        save(data_dir, 1, &[page_id(2), page_id(5)]).unwrap();
+        assert_eq!(load_core(data_dir, 0).unwrap(), [page_id(2), page_id(9)]);
+        assert_eq!(load(data_dir).unwrap(), [page_id(2), page_id(5), page_id(9)]);
+
+        fs::write(quarantine_path(data_dir, 1), b"garbage").unwrap();
+        assert!(load(data_dir).is_err());
+    }
+
+    #[test]
+    fn a_corrupt_page_is_quarantined_until_released() {
+        let mut config = scratch_config("read");
+        tokio_uring::start(async {
+            let storage = CoreStorage::new(&config, 0);
+            storage.allocate_extent(1, 1, 2).await.unwrap();
+            let mut buf = AlignedBuf::page();
+            buf[page::PAGE_HEADER_SIZE..].fill(7);
+            checksum::stamp_page(ChecksumAlgorithm::Crc32c, &mut buf);
+            let (_, res) = storage.write_page(page_id(1), buf).await;
+            res.unwrap();
+            drop(storage);
+
+            // Flip a byte of the page on disk.
+            let (seg_no, offset) = segment::locate(1);
+            let file = fs::OpenOptions::new().write(true).open(segment::segment_path(&config.data_dir, 1, 1, seg_no)).unwrap();
+            file.write_all_at(&[0xFF], offset + page::PAGE_HEADER_SIZE as u64).unwrap();
+
+            let storage = CoreStorage::new(&config, 0);
+            let (_, res) = storage.read_page(page_id(1), AlignedBuf::page()).await;
+            assert!(matches!(res, Err(StorageError::Corruption(corrupt)) if corrupt == page_id(1)));
+            assert_eq!(storage.quarantined_pages().unwrap(), [page_id(1)]);
+            drop(storage);
+
+            // The list survives a restart; under the zero policy the page reads as new.
+            config.corrupt_pages = CorruptPagePolicy::Zero;
+            let storage = CoreStorage::new(&config, 0);
+            assert_eq!(storage.quarantined_pages().unwrap(), [page_id(1)]);
+            let (buf, res) = storage.read_page(page_id(1), AlignedBuf::page()).await;
+            assert_eq!(res.unwrap(), PageState::Fresh);
+            assert!(buf.iter().all(|&b| b == 0));
+
+            assert!(storage.release_quarantined(page_id(1)).unwrap());
+            assert!(!storage.release_quarantined(page_id(1)).unwrap());
+            assert_eq!(load(&config.data_dir).unwrap(), []);
+        });
+    }
+}
@@ -0,0 +1,279 @@
+// -----------------------------------------------------------------------------
+// Row Format
+//
+// How a row's column values are laid out as the bytes of a heap tuple (`heap.rs`),
+// so whoever writes rows and whoever reads them agree without the heap knowing about
+// columns. The row does not describe its columns' types: the reader brings the same
+// column list the writer used, in order.
+//
+//   [0..2)    column_count  columns written
+//   [2..)     null bitmap   one bit per column, lowest first in each byte; 1 = NULL
+//   ...       fields        one per non-NULL column, in column order, unaligned
+//
+// A fixed-width field is its value in little-endian, `ColumnType::width` bytes. A
+// varlena field is a u32 byte length, then the bytes; text is UTF-8. NULL columns
+// take no field at all, only their bit. A varlena value too big for the row is kept
+// in overflow pages instead (see `heap.rs`), and its field points there, a length of
+// OVERFLOW_MARK followed by `space_id u32 | first_page u32 | len u32`.
+//
+// A row written with fewer columns than the reader's list reads as NULL in the rest,
+// so columns can be added to the end of a table without rewriting its rows.
+// -----------------------------------------------------------------------------
+
+const COUNT_SIZE: usize = 2;
+const LENGTH_SIZE: usize = 4;
+const POINTER_SIZE: usize = 12;
+
+// Length of a varlena field that points to overflow pages
+const OVERFLOW_MARK: u32 = u32::MAX;
+
+/// Columns a row can have.
+pub const MAX_COLUMNS: usize = u16::MAX as usize;
+
+/// The type of a column, which decides its field's layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColumnType {
+    Bool,
+    Int16,
+    Int32,
+    Int64,
+    Float64,
+    Bytes,
+    Text,
+}
+
+impl ColumnType {
+    /// Bytes of a fixed-width field; `None` for a varlena one.
+    pub fn width(self) -> Option<usize> {
+        match self {
+            ColumnType::Bool => Some(1),
+            ColumnType::Int16 => Some(2),
+            ColumnType::Int32 => Some(4),
+            ColumnType::Int64 | ColumnType::Float64 => Some(8),
+            ColumnType::Bytes | ColumnType::Text => None,
+        }
+    }
+}
+
+/// Where a varlena value kept out of its row is: a chain of overflow pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OverflowPointer {
+    pub space_id: u32,
+    pub first_page: u32,
+    /// Bytes of the value.
+    pub len: u32,
+}
+
+/// One column's value, borrowing varlena bytes from the row it was read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Null,
+    Bool(bool),
+    Int16(i16),
+    Int32(i32),
+    Int64(i64),
+    Float64(f64),
+    Bytes(&'a [u8]),
+    Text(&'a str),
+    /// A bytes or text value kept in overflow pages.
+    Overflow(OverflowPointer),
+}
+
+impl Value<'_> {
+    /// Whether the value can go in a column of `column_type`. NULL fits any.
+    pub fn fits(&self, column_type: ColumnType) -> bool {
+        match self {
+            Value::Null => true,
+            Value::Bool(_) => column_type == ColumnType::Bool,
+            Value::Int16(_) => column_type == ColumnType::Int16,
+            Value::Int32(_) => column_type == ColumnType::Int32,
+            Value::Int64(_) => column_type == ColumnType::Int64,
+            Value::Float64(_) => column_type == ColumnType::Float64,
+            Value::Bytes(_) => column_type == ColumnType::Bytes,
+            Value::Text(_) => column_type == ColumnType::Text,
+            Value::Overflow(_) => column_type.width().is_none(),
+        }
+    }
+}
+
+fn bitmap_size(columns: usize) -> usize {
+    columns.div_ceil(8)
+}
+
+/// Writes one row, a value per column in order.
+pub struct RowEncoder<'c> {
+    columns: &'c [ColumnType],
+    out: Vec<u8>,
+    next: usize,
+}
+
+impl<'c> RowEncoder<'c> {
+    /// A row of `columns`. Panics if there are more than `MAX_COLUMNS`.
+    pub fn new(columns: &'c [ColumnType]) -> Self {
+        assert!(columns.len() <= MAX_COLUMNS, "{} columns, at most {}", columns.len(), MAX_COLUMNS);
+        let mut out = Vec::with_capacity(COUNT_SIZE + bitmap_size(columns.len()));
+        out.extend_from_slice(&(columns.len() as u16).to_le_bytes());
+        out.resize(COUNT_SIZE + bitmap_size(columns.len()), 0);
+        Self { columns, out, next: 0 }
+    }
+
+    /// Writes the next column's value. Panics if there is no next column, or the value
+    /// is of another type than it.
+    pub fn push(&mut self, value: Value<'_>) -> &mut Self {
+        let column = self.next;
+        let column_type = *self.columns.get(column).expect("value past the last column");
+        assert!(value.fits(column_type), "column {} is {:?}, not {:?}", column, column_type, value);
+        match value {
+            Value::Null => self.out[COUNT_SIZE + column / 8] |= 1 << (column % 8),
+            Value::Bool(v) => self.out.push(v as u8),
+            Value::Int16(v) => self.out.extend_from_slice(&v.to_le_bytes()),
+            Value::Int32(v) => self.out.extend_from_slice(&v.to_le_bytes()),
+            Value::Int64(v) => self.out.extend_from_slice(&v.to_le_bytes()),
+            Value::Float64(v) => self.out.extend_from_slice(&v.to_le_bytes()),
+            Value::Bytes(v) => self.push_varlena(v),
+            Value::Text(v) => self.push_varlena(v.as_bytes()),
+            Value::Overflow(pointer) => {
+                self.out.extend_from_slice(&OVERFLOW_MARK.to_le_bytes());
+                for part in [pointer.space_id, pointer.first_page, pointer.len] {
+                    self.out.extend_from_slice(&part.to_le_bytes());
+                }
+            }
+        }
+        self.next += 1;
+        self
+    }
+
+    fn push_varlena(&mut self, bytes: &[u8]) {
+        let len = u32::try_from(bytes.len()).ok().filter(|&len| len != OVERFLOW_MARK).expect("varlena field of 4 GiB or more");
+        self.out.extend_from_slice(&len.to_le_bytes());
+        self.out.extend_from_slice(bytes);
+    }
+
+    /// The row's bytes. Panics if a column has no value yet.
+    pub fn finish(self) -> Vec<u8> {
+        assert_eq!(self.next, self.columns.len(), "row is missing columns");
+        self.out
+    }
+}
+
+/// The row `values` of `columns` encodes to; see `RowEncoder`.
+pub fn encode_row(columns: &[ColumnType], values: &[Value<'_>]) -> Vec<u8> {
+    let mut encoder = RowEncoder::new(columns);
+    for value in values {
+        encoder.push(*value);
+    }
+    encoder.finish()
+}
+
+// Where a column's field is, if it has one
+const NO_FIELD: usize = usize::MAX;
+
+/// Reads the values of one row, checked whole up front.
+pub struct RowDecoder<'a, 'c> {
+    columns: &'c [ColumnType],
+    row: &'a [u8],
+    // Offset of each column's field; NO_FIELD if NULL or not written
+    fields: Vec<usize>,
+}
+
+impl<'a, 'c> RowDecoder<'a, 'c> {
+    /// Reads `row` as a row of `columns`. `None` if it is malformed: more columns than
+    /// `columns`, a field cut short or left over, or text that isn't UTF-8.
+    pub fn new(columns: &'c [ColumnType], row: &'a [u8]) -> Option<Self> {
+        let count = u16::from_le_bytes(row.get(..COUNT_SIZE)?.try_into().unwrap()) as usize;
+        if count > columns.len() {
+            return None;
+        }
+        let bitmap = row.get(COUNT_SIZE..COUNT_SIZE + bitmap_size(count))?;
+        let mut fields = vec![NO_FIELD; columns.len()];
+        let mut at = COUNT_SIZE + bitmap.len();
+        for (column, column_type) in columns[..count].iter().enumerate() {
+            if bitmap[column / 8] & (1 << (column % 8)) != 0 {
+                continue;
+            }
+            fields[column] = at;
+            let len = match column_type.width() {
+                Some(width) => width,
+                None => {
+                    let len = u32::from_le_bytes(row.get(at..at + LENGTH_SIZE)?.try_into().unwrap());
+                    at += LENGTH_SIZE;
+                    if len == OVERFLOW_MARK {
+                        row.get(at..at + POINTER_SIZE)?;
+                        at += POINTER_SIZE;
+                        continue;
+                    }
+                    len as usize
+                }
+            };
+            let field = row.get(at..at.checked_add(len)?)?;
+            match column_type {
+                ColumnType::Bool if field[0] > 1 => return None,
+                ColumnType::Text if std::str::from_utf8(field).is_err() => return None,
+                _ => {}
+            }
+            at += len;
+        }
+        if at != row.len() {
+            return None;
+        }
+        Some(Self { columns, row, fields })
+    }
+
+    /// Columns of the row, as the reader has them.
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// Whether column `column` is NULL. Panics if there is no such column.
+    pub fn is_null(&self, column: usize) -> bool {
+        self.fields[column] == NO_FIELD
+    }
+
+    /// The value of column `column`. Panics if there is no such column.
+    pub fn get(&self, column: usize) -> Value<'a> {
+        let at = self.fields[column];
+        if at == NO_FIELD {
+            return Value::Null;
+        }
+        let row = self.row;
+        let fixed = |width: usize| &row[at..at + width];
+        match self.columns[column] {
+            ColumnType::Bool => Value::Bool(row[at] != 0),
+            ColumnType::Int16 => Value::Int16(i16::from_le_bytes(fixed(2).try_into().unwrap())),
+            ColumnType::Int32 => Value::Int32(i32::from_le_bytes(fixed(4).try_into().unwrap())),
+            ColumnType::Int64 => Value::Int64(i64::from_le_bytes(fixed(8).try_into().unwrap())),
+            ColumnType::Float64 => Value::Float64(f64::from_le_bytes(fixed(8).try_into().unwrap())),
+            ColumnType::Bytes | ColumnType::Text if get_u32(row, at) == OVERFLOW_MARK => Value::Overflow(OverflowPointer {
+                space_id: get_u32(row, at + LENGTH_SIZE),
+                first_page: get_u32(row, at + LENGTH_SIZE + 4),
+                len: get_u32(row, at + LENGTH_SIZE + 8),
+            }),
+            ColumnType::Bytes => Value::Bytes(self.varlena(at)),
+            // Checked by `new`
+            ColumnType::Text => Value::Text(std::str::from_utf8(self.varlena(at)).unwrap()),
+        }
+    }
+
+    /// Every column's value, in order.
+    pub fn values(&self) -> Vec<Value<'a>> {
+        (0..self.len()).map(|column| self.get(column)).collect()
+    }
+
+    fn varlena(&self, at: usize) -> &'a [u8] {
+        let len = get_u32(self.row, at) as usize;
+        &self.row[at + LENGTH_SIZE..at + LENGTH_SIZE + len]
+    }
+}
+
+fn get_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// The values of `row` as a row of `columns`; see `RowDecoder::new`.
+pub fn decode_row<'a>(columns: &[ColumnType], row: &'a [u8]) -> Option<Vec<Value<'a>>> {
+    Some(RowDecoder::new(columns, row)?.values())
+}
@@ -0,0 +1,258 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use crate::backup::{self, BackupManifest};
+use crate::checksum::ChecksumAlgorithm;
+use crate::mount::{self, DataDirLock};
+use crate::page::{self, PAGE_SIZE};
+use crate::segment::{self, SegmentHeader};
+use crate::traits::{Lsn, StorageError};
+use crate::wal;
+use crate::wal_crypt::{WalCipher, WalKey};
+use crate::wal_recovery;
+use crate::wal_registry::WalRegistry;
+
+// -----------------------------------------------------------------------------
+// Restore
+//
+// `restore` rebuilds one database in the data and WAL directories of a stopped engine
+// from a full backup, the incrementals taken after it, and optionally the WAL archive
+// (`DirectoryArchiver`), leaving it for the next mount to recover:
+//
+//   1. The backups are verified, and the full one is copied next to the data, to
+//      `data_dir/.restore_db_<id>`, where the incrementals are merged onto the copy
+//      (`backup::combine`). The backups themselves are left as they were.
+//   2. Archived segments from the one holding the end of the backup's WAL on replace
+//      and extend it, as long as they follow on: mount replays up to the end of the
+//      archive instead. The archive keeps one stream per database, so this is for the
+//      per-database WAL layout only.
+//   3. Segment headers are made to own every page in use. A header was copied before
+//      the pages after it, which may have been allocated meanwhile, and the WAL may
+//      reach further still. Each header claims the extents of every written page of
+//      its segment and every page the WAL's page records change, and missing segments
+//      are created, so no page holding data is allocated again. Extents freed without
+//      their pages punched are claimed again too, which only leaks them.
+//   4. The copy's data and WAL move into place, and the clean shutdown marker is
+//      removed, so the next mount runs recovery (see `mount.rs`).
+//
+// A restore cut short leaves the staging directory behind, which the next attempt
+// starts over from. The database must not exist in the directories already; other
+// databases there are left alone. Like backups, this is blocking std::fs I/O.
+// -----------------------------------------------------------------------------
+
+/// What `restore` did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreSummary {
+    /// The manifest of the backup restored, incrementals merged.
+    pub manifest: BackupManifest,
+    /// Segments taken from the WAL archive.
+    pub archived_segments: usize,
+    /// Segment headers raised over pages in use, and segments created.
+    pub headers_fixed: usize,
+}
+
+/// Restores the database backed up in `full`, with the incrementals `chain` (oldest
+/// first) and archived WAL from `archive`, into `data_dir` and `wal_dir`, whose engine
+/// must not be running. `keys` decrypt the WAL and `registry` knows the page records
+/// it may hold.
+#[allow(clippy::too_many_arguments)]
+pub fn restore(
+    data_dir: &Path,
+    wal_dir: &Path,
+    checksum: ChecksumAlgorithm,
+    keys: &HashMap<u32, WalKey>,
+    full: &Path,
+    chain: &[PathBuf],
+    archive: Option<&Path>,
+    registry: &WalRegistry,
+) -> Result<RestoreSummary, StorageError> {
+    let _lock = DataDirLock::acquire(data_dir)?;
+    let manifest = backup::verify(full)?;
+    if manifest.parent.is_some() {
+        return Err(invalid(full, "not a full backup"));
+    }
+    let db_id = manifest.db_id;
+    let data_db_dir = data_dir.join(format!("db_{}", db_id));
+    let wal_db_dirs: Vec<PathBuf> = manifest
+        .streams
+        .iter()
+        .map(|stream| wal_dir.join(&stream.root).join(format!("db_{}", db_id)))
+        .collect();
+    if let Some(existing) = std::iter::once(&data_db_dir).chain(&wal_db_dirs).find(|dir| dir.exists()) {
+        return Err(invalid(existing, "database already exists"));
+    }
+
+    // 1. Staging copy, incrementals merged
+    let staging = data_dir.join(format!(".restore_db_{}", db_id));
+    if staging.exists() {
+        fs::remove_dir_all(&staging).map_err(StorageError::Io)?;
+    }
+    copy_tree(full, &staging)?;
+    let manifest = match chain.is_empty() {
+        true => manifest,
+        false => backup::combine(&staging, chain)?,
+    };
+
+    // 2. Archived WAL
+    let mut archived_segments = 0;
+    if let Some(archive) = archive {
+        for stream in &manifest.streams {
+            if stream.origin != wal::stream_origin(0) || !stream.root.as_os_str().is_empty() {
+                return Err(invalid(archive, "archived WAL only extends the per-database layout"));
+            }
+            archived_segments += extend_from_archive(&staging.join("wal"), db_id, stream.end, archive)?;
+        }
+    }
+
+    // 3. Segment headers
+    let mut in_use: HashMap<(u32, u32), BTreeSet<u32>> = HashMap::new();
+    for stream in &manifest.streams {
+        let cipher = keys.get(&db_id).map(|key| WalCipher::new(key, db_id, wal::lsn_core(stream.origin)));
+        let root = staging.join("wal").join(&stream.root);
+        wal_recovery::scan_records(&root, db_id, stream.origin, cipher.as_ref(), |record| {
+            if let Some(change) = registry.page_change(record)? {
+                let (seg_no, _) = segment::locate(change.page_id.page_no);
+                let page = change.page_id.page_no % segment::PAGES_PER_SEGMENT;
+                in_use.entry((change.page_id.space_id, seg_no)).or_default().insert(page);
+            }
+            Ok(())
+        })?;
+    }
+    let headers_fixed = fix_headers(&staging.join("data"), db_id, checksum, in_use)?;
+
+    // 4. Into place
+    move_dir(&staging.join("data").join(format!("db_{}", db_id)), &data_db_dir)?;
+    for (stream, dir) in manifest.streams.iter().zip(&wal_db_dirs) {
+        move_dir(&staging.join("wal").join(&stream.root).join(format!("db_{}", db_id)), dir)?;
+    }
+    fs::remove_dir_all(&staging).map_err(StorageError::Io)?;
+    mount::take_clean_shutdown(data_dir)?;
+    Ok(RestoreSummary { manifest, archived_segments, headers_fixed })
+}
+
+// Replaces the segments of the WAL under `wal_root` from the one holding `end` on with
+// the archive's, as long as the archive has them in sequence. Returns how many.
+fn extend_from_archive(wal_root: &Path, db_id: u32, end: Lsn, archive: &Path) -> Result<usize, StorageError> {
+    let (end_seg, _) = wal::locate(end);
+    let dir = wal_root.join(format!("db_{}", db_id));
+    let mut copied = 0;
+    let segments = wal::wal_segments(archive, db_id)?.into_iter().filter(|(seg_no, _)| *seg_no >= end_seg);
+    for (i, (seg_no, path)) in segments.enumerate() {
+        if seg_no != end_seg + i as u64 {
+            break;
+        }
+        // The backup's copy of the end segment stops at its end; the archived one is whole.
+        for name in [format!("{:016X}.wal", seg_no), format!("{:016X}.walz", seg_no)] {
+            match fs::remove_file(dir.join(name)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(StorageError::Io(e)),
+                _ => {}
+            }
+        }
+        backup::copy_file(&path, &dir.join(path.file_name().unwrap()))?;
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+// Has the headers of database `db_id`'s segments under `data_dir` claim their written
+// pages and the pages `in_use` (by space and segment: pages from the start of the
+// segment), creating missing segments. Returns the headers written.
+fn fix_headers(data_dir: &Path, db_id: u32, checksum: ChecksumAlgorithm, mut in_use: HashMap<(u32, u32), BTreeSet<u32>>) -> Result<usize, StorageError> {
+    let db_dir = data_dir.join(format!("db_{}", db_id));
+    fs::create_dir_all(&db_dir).map_err(StorageError::Io)?;
+    // Spaces have no holes in their run of segments.
+    let mut last_segment: HashMap<u32, u32> = HashMap::new();
+    for &(space_id, seg_no) in in_use.keys() {
+        let last = last_segment.entry(space_id).or_default();
+        *last = (*last).max(seg_no);
+    }
+    for (space_id, last) in last_segment {
+        for seg_no in 0..last {
+            in_use.entry((space_id, seg_no)).or_default();
+        }
+    }
+    for path in segment::segment_files(data_dir)? {
+        let (space_id, seg_no) = segment::parse_segment_file_name(path.file_name().unwrap().to_str().unwrap()).unwrap();
+        in_use.entry((space_id, seg_no)).or_default().extend(written_pages(&path)?);
+    }
+
+    let mut fixed = 0;
+    for ((space_id, seg_no), pages) in in_use {
+        let path = segment::segment_path(data_dir, db_id, space_id, seg_no);
+        let copied = match path.exists() {
+            true => Some(segment::read_header(&path)?),
+            false => None,
+        };
+        let mut header = copied.unwrap_or_else(|| SegmentHeader::new(db_id, space_id, seg_no, checksum));
+        for page in pages {
+            header.claim(page, page + 1);
+        }
+        if copied == Some(header) {
+            continue;
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(StorageError::Io)?;
+        let mut header_page = vec![0u8; PAGE_SIZE];
+        header.encode(&mut header_page);
+        file.write_all_at(&header_page, 0).map_err(StorageError::Io)?;
+        // Pages the header now claims must be there; never-written ones read as zeros.
+        let required = (1 + header.allocated_pages as u64) * PAGE_SIZE as u64;
+        if file.metadata().map_err(StorageError::Io)?.len() < required {
+            file.set_len(required).map_err(StorageError::Io)?;
+        }
+        file.sync_all().map_err(StorageError::Io)?;
+        fixed += 1;
+    }
+    fs::File::open(&db_dir).and_then(|dir| dir.sync_all()).map_err(StorageError::Io)?;
+    Ok(fixed)
+}
+
+// Pages of the segment not all zeros
+fn written_pages(path: &Path) -> Result<Vec<u32>, StorageError> {
+    let file = fs::File::open(path).map_err(StorageError::Io)?;
+    let pages = (file.metadata().map_err(StorageError::Io)?.len() / PAGE_SIZE as u64).saturating_sub(1);
+    let mut page = vec![0u8; PAGE_SIZE];
+    let mut written = Vec::new();
+    for index in 0..pages {
+        file.read_exact_at(&mut page, (index + 1) * PAGE_SIZE as u64).map_err(StorageError::Io)?;
+        if !page::is_fresh(&page) {
+            written.push(index as u32);
+        }
+    }
+    Ok(written)
+}
+
+fn copy_tree(src: &Path, dst: &Path) -> Result<(), StorageError> {
+    for path in backup::walk(src)? {
+        let to = dst.join(path.strip_prefix(src).unwrap());
+        fs::create_dir_all(to.parent().unwrap()).map_err(StorageError::Io)?;
+        backup::copy_file(&path, &to)?;
+    }
+    Ok(())
+}
+
+// Moves directory `src` to `dst`, copying across file systems, and syncs the parent.
+fn move_dir(src: &Path, dst: &Path) -> Result<(), StorageError> {
+    let parent = dst.parent().unwrap();
+    fs::create_dir_all(parent).map_err(StorageError::Io)?;
+    match fs::rename(src, dst) {
+        Ok(()) => {}
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            copy_tree(src, dst)?;
+            fs::remove_dir_all(src).map_err(StorageError::Io)?;
+        }
+        Err(e) => return Err(StorageError::Io(e)),
+    }
+    fs::File::open(parent).and_then(|dir| dir.sync_all()).map_err(StorageError::Io)
+}
+
+fn invalid(path: &Path, reason: &str) -> StorageError {
+    StorageError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", reason, path.display())))
+}
@@ -0,0 +1,91 @@
+use std::io;
+use std::time::Duration;
+
+use crate::traits::StorageError;
+
+// -----------------------------------------------------------------------------
+// Completion Handling
+//
+// How each core's io_uring posts completions and takes submissions, for trading
+// latency against throughput. tokio-uring drains the whole completion queue whenever
+// the ring's fd turns readable, and never blocks in `submit_and_wait`, so neither
+// completions reaped per tick nor a wait count can be set. What can be set is when the
+// kernel posts completions, and thereby how many each drain finds:
+//
+//   eager    the default: the kernel interrupts the thread to post each completion
+//            as it happens, so every one is handled as early as possible
+//   batched  IORING_SETUP_COOP_TASKRUN: completions are posted only when the thread
+//            next enters the kernel, to submit or to wait, so they arrive and are
+//            reaped in batches; fewer interrupts and wakeups, at up to a loop
+//            iteration of added latency
+//   polled   IORING_SETUP_SQPOLL: a kernel thread polls the submission queue, so
+//            submitting takes no syscall; the lowest latency, for a CPU kept busy
+//            while the core is, until it idles for the given time
+//
+// A larger completion queue (`cq_entries`) lets more completions wait for a drain
+// without overflowing into the kernel's backlog. It is never smaller than the default
+// of twice the submission entries: a smaller one is refused at mount. `cascade-bench --completions all`
+// runs a workload under each mode, to measure the tradeoff on a given device.
+// -----------------------------------------------------------------------------
+
+/// When the ring posts completions; see above.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompletionMode {
+    #[default]
+    Eager,
+    Batched,
+    /// Submission polling, the kernel thread sleeping after `idle` without work.
+    Polled { idle: Duration },
+}
+
+impl CompletionMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompletionMode::Eager => "eager",
+            CompletionMode::Batched => "batched",
+            CompletionMode::Polled { .. } => "polled",
+        }
+    }
+}
+
+/// Ring settings of every core (see `StorageManager::runtime`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompletionConfig {
+    pub mode: CompletionMode,
+    /// Completion queue entries; `None` leaves it at twice the submission entries, the
+    /// least it can be set to.
+    pub cq_entries: Option<u32>,
+}
+
+impl CompletionConfig {
+    /// Refuses a completion queue smaller than twice `sq_entries` submission entries.
+    pub fn validate(&self, sq_entries: u32) -> Result<(), StorageError> {
+        match self.cq_entries {
+            Some(cq_entries) if cq_entries < sq_entries.saturating_mul(2) => Err(StorageError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} completion queue entries, fewer than twice the {} submission entries", cq_entries, sq_entries),
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// A runtime builder for a core's thread, with `sq_entries` submission entries.
+    pub fn runtime(&self, sq_entries: u32) -> tokio_uring::Builder {
+        let mut ring = tokio_uring::uring_builder();
+        match self.mode {
+            CompletionMode::Eager => {}
+            CompletionMode::Batched => {
+                ring.setup_coop_taskrun();
+            }
+            CompletionMode::Polled { idle } => {
+                ring.setup_sqpoll(idle.as_millis().clamp(1, u32::MAX as u128) as u32);
+            }
+        }
+        if let Some(cq_entries) = self.cq_entries {
+            ring.setup_cqsize(cq_entries);
+        }
+        let mut builder = tokio_uring::builder();
+        builder.entries(sq_entries).uring_builder(&ring);
+        builder
+    }
+}
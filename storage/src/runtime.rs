@@ -0,0 +1,37 @@
+use std::time::{Duration, Instant};
+
+use futures::future::LocalBoxFuture;
+
+// -----------------------------------------------------------------------------
+// Runtime
+//
+// What the engine asks of the runtime besides I/O: the time, and waiting for some.
+// I/O goes through `PageStore` and `WalStore`, so a store decides when each request
+// completes. Together they are everything a simulation (see `sim.rs`) has to take
+// over to run the layers above the store on a seeded schedule: `TokioRuntime` is the
+// real clock, and is what a layer uses unless given another.
+//
+// Only layers generic over their store take a runtime (`BufferPool::with_runtime`);
+// those built on `CoreStorage` run on the real clock alone.
+// -----------------------------------------------------------------------------
+
+pub trait Runtime {
+    fn now(&self) -> Instant;
+
+    /// Completes once `duration` has passed on this runtime's clock.
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()>;
+}
+
+/// The wall clock, with tokio's timers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRuntime;
+
+impl Runtime for TokioRuntime {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
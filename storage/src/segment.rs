@@ -0,0 +1,807 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::page::{self, PAGE_HEADER_SIZE, PAGE_SIZE};
+use crate::traits::StorageError;
+
+// -----------------------------------------------------------------------------
+// Segment Files
+//
+// A space is stored as a series of segment files (`space_<id>.<seg_no>.dat`),
+// each holding a header page followed by up to `PAGES_PER_SEGMENT` data pages:
+//
+//   [0 .. 8KB)            segment header page
+//   [8KB .. 8KB + 1GiB)   data pages
+//
+// Pages are allocated in extents of `EXTENT_PAGES`. The header page tracks them in an
+// extent map, one bit per extent of the segment, set while it is allocated, next to
+// `allocated_pages`, the high-water mark of pages ever handed out. A freed extent is
+// cleared in the map and handed out again, lowest first, before the space grows (see
+// `SpaceExtents`). Headers written before the map existed have no
+// HEADER_FLAG_EXTENT_MAP, and count every extent below the high-water mark as
+// allocated; builds predating the map read the high-water mark alone and never reuse
+// an extent, so either can open the other's segments.
+// -----------------------------------------------------------------------------
+
+/// "CASCSEG\0" read as a little-endian u64.
+pub const SEGMENT_MAGIC: u64 = u64::from_le_bytes(*b"CASCSEG\0");
+
+/// Version 1: one headerless `space_<id>.dat` file per space, page N at N * 8KB, no checksums.
+/// Version 2: segment files with a header page; the checksum algorithm is recorded in it.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// 1 GiB of 8KB data pages per segment file.
+pub const PAGES_PER_SEGMENT: u32 = 131_072;
+
+/// Full size of a preallocated segment file, header page included.
+pub const SEGMENT_FILE_BYTES: u64 = (PAGES_PER_SEGMENT as u64 + 1) * PAGE_SIZE as u64;
+
+pub const PAGE_TYPE_SEGMENT_HEADER: u16 = 1;
+
+/// How segment files get their disk blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SegmentAllocation {
+    /// Each segment is preallocated to its full 1 GiB when created. Blocks are contiguous
+    /// and page writes never extend the file, but every space costs at least 1 GiB.
+    #[default]
+    Preallocate,
+    /// Segments start as a bare header page and grow extent-by-extent as `allocate_extent`
+    /// hands pages out. Suits many small spaces, at the cost of fragmentation: extents of
+    /// interleaved spaces end up scattered on disk, which hurts sequential scans. Watch
+    /// `physical_extent_count` for segments that need rewriting.
+    Sparse,
+}
+
+// Header field offsets, placed after the common page header.
+const MAGIC_OFFSET: usize = PAGE_HEADER_SIZE;
+const VERSION_OFFSET: usize = MAGIC_OFFSET + 8;
+const PAGE_SIZE_OFFSET: usize = VERSION_OFFSET + 4;
+const CHECKSUM_ALGO_OFFSET: usize = PAGE_SIZE_OFFSET + 4;
+const DB_ID_OFFSET: usize = CHECKSUM_ALGO_OFFSET + 4;
+const SPACE_ID_OFFSET: usize = DB_ID_OFFSET + 4;
+const SEG_NO_OFFSET: usize = SPACE_ID_OFFSET + 4;
+const ALLOCATED_PAGES_OFFSET: usize = SEG_NO_OFFSET + 4;
+const HEADER_FLAGS_OFFSET: usize = ALLOCATED_PAGES_OFFSET + 4;
+const EXTENT_MAP_OFFSET: usize = HEADER_FLAGS_OFFSET + 4;
+
+// The header carries an extent map.
+const HEADER_FLAG_EXTENT_MAP: u32 = 1;
+
+/// Pages per extent, the unit of allocation.
+pub const EXTENT_PAGES: u32 = 8;
+
+/// Extents per segment, one bit each in the header's extent map.
+pub const EXTENTS_PER_SEGMENT: u32 = PAGES_PER_SEGMENT / EXTENT_PAGES;
+
+const EXTENT_MAP_WORDS: usize = EXTENTS_PER_SEGMENT as usize / 64;
+
+/// Maps a logical page number to its segment and the byte offset inside that segment file.
+pub fn locate(page_no: u32) -> (u32, u64) {
+    let seg_no = page_no / PAGES_PER_SEGMENT;
+    let page_in_seg = (page_no % PAGES_PER_SEGMENT) as u64;
+    (seg_no, (page_in_seg + 1) * PAGE_SIZE as u64)
+}
+
+/// e.g., /data_dir/db_10/space_25.0003.dat
+pub fn segment_path(data_dir: &Path, db_id: u32, space_id: u32, seg_no: u32) -> PathBuf {
+    data_dir
+        .join(format!("db_{}", db_id))
+        .join(format!("space_{}.{:04}.dat", space_id, seg_no))
+}
+
+/// e.g., /data_dir/db_10/space_25.dat, the v1 layout `migrate` upgrades from.
+pub fn legacy_path(data_dir: &Path, db_id: u32, space_id: u32) -> PathBuf {
+    data_dir
+        .join(format!("db_{}", db_id))
+        .join(format!("space_{}.dat", space_id))
+}
+
+/// Parses `space_<id>.<seg_no>.dat` back into `(space_id, seg_no)`.
+pub fn parse_segment_file_name(name: &str) -> Option<(u32, u32)> {
+    let rest = name.strip_prefix("space_")?.strip_suffix(".dat")?;
+    let (space, seg) = rest.split_once('.')?;
+    Some((space.parse().ok()?, seg.parse().ok()?))
+}
+
+/// Parses a v1 `space_<id>.dat` file name back into its `space_id`.
+pub fn parse_legacy_file_name(name: &str) -> Option<u32> {
+    name.strip_prefix("space_")?.strip_suffix(".dat")?.parse().ok()
+}
+
+/// Parses a `db_<id>` directory name back into its `db_id`.
+fn parse_db_dir_name(name: &str) -> Option<u32> {
+    name.strip_prefix("db_")?.parse().ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentHeader {
+    pub format_version: u32,
+    pub page_size: u32,
+    pub checksum: ChecksumAlgorithm,
+    pub db_id: u32,
+    pub space_id: u32,
+    pub seg_no: u32,
+    /// High-water mark of extents handed out by `allocate_extent` within this segment.
+    /// Pages at or beyond it have never been allocated, so their contents don't matter.
+    pub allocated_pages: u32,
+    /// Extents allocated now.
+    pub extents: ExtentMap,
+}
+
+impl SegmentHeader {
+    pub fn new(db_id: u32, space_id: u32, seg_no: u32, checksum: ChecksumAlgorithm) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            page_size: PAGE_SIZE as u32,
+            checksum,
+            db_id,
+            space_id,
+            seg_no,
+            allocated_pages: 0,
+            extents: ExtentMap::default(),
+        }
+    }
+
+    /// Marks the extents holding pages `first..end` of the segment allocated, and
+    /// raises the high-water mark over them.
+    pub fn claim(&mut self, first: u32, end: u32) {
+        self.extents.set(first / EXTENT_PAGES..end.div_ceil(EXTENT_PAGES), true);
+        self.allocated_pages = self.allocated_pages.max(end);
+    }
+
+    /// Marks the extents wholly inside pages `first..end` of the segment free. Returns
+    /// whether any was allocated.
+    pub fn release(&mut self, first: u32, end: u32) -> bool {
+        let extents = first.div_ceil(EXTENT_PAGES)..end / EXTENT_PAGES;
+        let allocated = extents.clone().any(|extent| self.extents.is_allocated(extent));
+        self.extents.set(extents, false);
+        allocated
+    }
+
+    /// Pages of the segment in allocated extents, up to the high-water mark.
+    pub fn pages_in_use(&self) -> u32 {
+        (self.extents.allocated() * EXTENT_PAGES).min(self.allocated_pages)
+    }
+
+    /// Serializes the header into a full page and stamps the page checksum.
+    pub fn encode(&self, page: &mut [u8]) {
+        page[..PAGE_SIZE].fill(0);
+        page::set_page_type(page, PAGE_TYPE_SEGMENT_HEADER);
+        page[MAGIC_OFFSET..MAGIC_OFFSET + 8].copy_from_slice(&SEGMENT_MAGIC.to_le_bytes());
+        put_u32(page, VERSION_OFFSET, self.format_version);
+        put_u32(page, PAGE_SIZE_OFFSET, self.page_size);
+        put_u32(page, CHECKSUM_ALGO_OFFSET, checksum_to_tag(self.checksum));
+        put_u32(page, DB_ID_OFFSET, self.db_id);
+        put_u32(page, SPACE_ID_OFFSET, self.space_id);
+        put_u32(page, SEG_NO_OFFSET, self.seg_no);
+        put_u32(page, ALLOCATED_PAGES_OFFSET, self.allocated_pages);
+        put_u32(page, HEADER_FLAGS_OFFSET, HEADER_FLAG_EXTENT_MAP);
+        for (i, word) in self.extents.0.iter().enumerate() {
+            let at = EXTENT_MAP_OFFSET + i * 8;
+            page[at..at + 8].copy_from_slice(&word.to_le_bytes());
+        }
+        checksum::stamp_page(self.checksum, page);
+    }
+
+    /// Parses a header page. The header is checksummed with the algorithm it names,
+    /// so it can be verified before anything else about the segment is known.
+    pub fn decode(page: &[u8], path: &Path) -> Result<Self, StorageError> {
+        let magic = u64::from_le_bytes(page[MAGIC_OFFSET..MAGIC_OFFSET + 8].try_into().unwrap());
+        if magic != SEGMENT_MAGIC {
+            return Err(StorageError::NotASegment(path.to_path_buf()));
+        }
+
+        let format_version = get_u32(page, VERSION_OFFSET);
+        let checksum = checksum_from_tag(get_u32(page, CHECKSUM_ALGO_OFFSET))
+            .ok_or_else(|| incompatible(path, "unknown checksum algorithm tag"))?;
+        if !checksum::verify_page(checksum, page) {
+            return Err(incompatible(path, "segment header checksum mismatch"));
+        }
+
+        Ok(Self {
+            format_version,
+            page_size: get_u32(page, PAGE_SIZE_OFFSET),
+            checksum,
+            db_id: get_u32(page, DB_ID_OFFSET),
+            space_id: get_u32(page, SPACE_ID_OFFSET),
+            seg_no: get_u32(page, SEG_NO_OFFSET),
+            allocated_pages: get_u32(page, ALLOCATED_PAGES_OFFSET),
+            extents: match get_u32(page, HEADER_FLAGS_OFFSET) & HEADER_FLAG_EXTENT_MAP {
+                0 => ExtentMap::below(get_u32(page, ALLOCATED_PAGES_OFFSET)),
+                _ => ExtentMap::decode(&page[EXTENT_MAP_OFFSET..]),
+            },
+        })
+    }
+
+    /// Refuses segments this build cannot read.
+    pub fn validate(&self, path: &Path, expected_checksum: ChecksumAlgorithm) -> Result<(), StorageError> {
+        if self.format_version != FORMAT_VERSION {
+            return Err(incompatible(
+                path,
+                &format!("format version {} (expected {})", self.format_version, FORMAT_VERSION),
+            ));
+        }
+        if self.page_size as usize != PAGE_SIZE {
+            return Err(incompatible(
+                path,
+                &format!("page size {} (expected {})", self.page_size, PAGE_SIZE),
+            ));
+        }
+        if self.checksum != expected_checksum {
+            return Err(incompatible(
+                path,
+                &format!("checksum {:?} (configured {:?})", self.checksum, expected_checksum),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// One bit per extent of a segment, set while the extent is allocated.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ExtentMap([u64; EXTENT_MAP_WORDS]);
+
+impl Default for ExtentMap {
+    fn default() -> Self {
+        Self([0; EXTENT_MAP_WORDS])
+    }
+}
+
+impl std::fmt::Debug for ExtentMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ExtentMap({} allocated)", self.allocated())
+    }
+}
+
+impl ExtentMap {
+    /// Every extent holding a page below `allocated_pages` allocated.
+    pub fn below(allocated_pages: u32) -> Self {
+        let mut map = Self::default();
+        map.set(0..allocated_pages.div_ceil(EXTENT_PAGES), true);
+        map
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        let mut map = Self::default();
+        for (i, word) in map.0.iter_mut().enumerate() {
+            *word = u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        }
+        map
+    }
+
+    pub fn is_allocated(&self, extent: u32) -> bool {
+        self.0[extent as usize / 64] & 1 << (extent % 64) != 0
+    }
+
+    pub fn set(&mut self, extents: std::ops::Range<u32>, allocated: bool) {
+        for extent in extents.start..extents.end.min(EXTENTS_PER_SEGMENT) {
+            let word = &mut self.0[extent as usize / 64];
+            match allocated {
+                true => *word |= 1 << (extent % 64),
+                false => *word &= !(1 << (extent % 64)),
+            }
+        }
+    }
+
+    /// Number of allocated extents.
+    pub fn allocated(&self) -> u32 {
+        self.0.iter().map(|word| word.count_ones()).sum()
+    }
+
+    /// First of the lowest run of `count` free extents.
+    pub fn find_free(&self, count: u32) -> Option<u32> {
+        let mut run = 0;
+        let mut extent = 0;
+        while extent < EXTENTS_PER_SEGMENT {
+            // Whole words allocated are skipped at once.
+            if extent % 64 == 0 && self.0[extent as usize / 64] == u64::MAX {
+                run = 0;
+                extent += 64;
+                continue;
+            }
+            run = if self.is_allocated(extent) { 0 } else { run + 1 };
+            extent += 1;
+            if run == count {
+                return Some(extent - count);
+            }
+        }
+        None
+    }
+}
+
+/// A space's allocation state, rebuilt from its segment headers alone: the headers, in
+/// segment order, and the page after the last allocation.
+#[derive(Debug, Clone)]
+pub struct SpaceExtents {
+    pub headers: Vec<SegmentHeader>,
+    pub tail: u32,
+}
+
+impl SpaceExtents {
+    /// The state of a space with segment `headers`. The last allocation is taken to
+    /// end at the high-water mark.
+    pub fn new(headers: Vec<SegmentHeader>) -> Self {
+        let tail = headers
+            .iter()
+            .rev()
+            .find(|header| header.allocated_pages > 0)
+            .map_or(0, |header| header.seg_no * PAGES_PER_SEGMENT + header.allocated_pages);
+        Self { headers, tail }
+    }
+
+    /// Where `num_pages` (at most a segment's worth) go: the rest of the extent the
+    /// last allocation ended in, if they fit, else the lowest run of free extents in
+    /// a segment, else a new segment. `None` once page numbers run out.
+    pub fn place(&self, num_pages: u32) -> Option<u32> {
+        let left = (EXTENT_PAGES - self.tail % EXTENT_PAGES) % EXTENT_PAGES;
+        if num_pages <= left {
+            let header = self.headers.get((self.tail / PAGES_PER_SEGMENT) as usize);
+            if header.is_some_and(|header| header.extents.is_allocated(self.tail % PAGES_PER_SEGMENT / EXTENT_PAGES)) {
+                return Some(self.tail);
+            }
+        }
+        let extents = num_pages.div_ceil(EXTENT_PAGES);
+        for (seg_no, header) in self.headers.iter().enumerate() {
+            if let Some(extent) = header.extents.find_free(extents) {
+                return Some(seg_no as u32 * PAGES_PER_SEGMENT + extent * EXTENT_PAGES);
+            }
+        }
+        (self.headers.len() as u32).checked_mul(PAGES_PER_SEGMENT)
+    }
+}
+
+fn checksum_to_tag(algorithm: ChecksumAlgorithm) -> u32 {
+    match algorithm {
+        ChecksumAlgorithm::Crc32 => 1,
+        ChecksumAlgorithm::Crc32c => 2,
+    }
+}
+
+fn checksum_from_tag(tag: u32) -> Option<ChecksumAlgorithm> {
+    match tag {
+        1 => Some(ChecksumAlgorithm::Crc32),
+        2 => Some(ChecksumAlgorithm::Crc32c),
+        _ => None,
+    }
+}
+
+fn put_u32(page: &mut [u8], offset: usize, value: u32) {
+    page[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn get_u32(page: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap())
+}
+
+fn incompatible(path: &Path, reason: &str) -> StorageError {
+    StorageError::IncompatibleFormat {
+        path: path.to_path_buf(),
+        reason: reason.to_string(),
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Offline Format Checks & Migration
+//
+// These run before any core's ring exists (mount, maintenance tooling), so they
+// use plain blocking std::fs I/O.
+// -----------------------------------------------------------------------------
+
+/// Reads and decodes the header page of a segment file.
+pub fn read_header(path: &Path) -> Result<SegmentHeader, StorageError> {
+    let mut file = fs::File::open(path).map_err(StorageError::Io)?;
+    let mut page = vec![0u8; PAGE_SIZE];
+    file.read_exact(&mut page).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => StorageError::NotASegment(path.to_path_buf()),
+        _ => StorageError::Io(e),
+    })?;
+    SegmentHeader::decode(&page, path)
+}
+
+/// Walks every `db_*/space_*.dat` segment under `data_dir`, validates its header and
+/// file size, and returns the size repairs that were applied.
+/// A v1 file still sitting next to them fails the mount instead of being silently ignored.
+pub fn check_data_dir(
+    data_dir: &Path,
+    expected_checksum: ChecksumAlgorithm,
+    allocation: SegmentAllocation,
+) -> Result<Vec<(PathBuf, SizeRepair)>, StorageError> {
+    if let Some(path) = legacy_files(data_dir)?.into_iter().next() {
+        return Err(incompatible(&path, "headerless v1 space file, run segment::migrate_data_dir"));
+    }
+    let mut repairs = Vec::new();
+    for path in segment_files(data_dir)? {
+        let header = read_header(&path)?;
+        header.validate(&path, expected_checksum)?;
+        match check_segment_size(&path, &header, allocation)? {
+            SizeRepair::None => {}
+            repair => repairs.push((path, repair)),
+        }
+    }
+    Ok(repairs)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeRepair {
+    None,
+    /// The file ended inside the never-allocated region; it was extended back to full size.
+    Extended { from_bytes: u64, to_bytes: u64 },
+    /// The file ran past the last addressable page with only zeros; the excess was trimmed.
+    Trimmed { from_bytes: u64, to_bytes: u64 },
+}
+
+/// Compares a segment file's size with what its header says must exist.
+///
+/// Every allocated page must be fully present: anything less is real truncation and is
+/// reported precisely rather than surfacing later as a random `ShortRead`. A file that
+/// is merely short of its preallocated size (e.g. a preallocation interrupted by a
+/// crash), or that carries a zero-filled overhang, is repaired in place. Sparse segments
+/// are expected to be short and are only checked for truncation.
+pub fn check_segment_size(
+    path: &Path,
+    header: &SegmentHeader,
+    allocation: SegmentAllocation,
+) -> Result<SizeRepair, StorageError> {
+    let len = fs::metadata(path).map_err(StorageError::Io)?.len();
+    let required = (1 + header.allocated_pages as u64) * PAGE_SIZE as u64;
+
+    if len < required {
+        let complete_pages = (len / PAGE_SIZE as u64).saturating_sub(1) as u32;
+        return Err(StorageError::SegmentTruncated {
+            path: path.to_path_buf(),
+            expected_bytes: required,
+            actual_bytes: len,
+            first_missing_page: header.seg_no * PAGES_PER_SEGMENT + complete_pages,
+        });
+    }
+
+    if len < SEGMENT_FILE_BYTES && allocation == SegmentAllocation::Preallocate {
+        let file = OpenOptions::new().write(true).open(path).map_err(StorageError::Io)?;
+        preallocate(&file, len, SEGMENT_FILE_BYTES - len)?;
+        file.sync_all().map_err(StorageError::Io)?;
+        return Ok(SizeRepair::Extended { from_bytes: len, to_bytes: SEGMENT_FILE_BYTES });
+    }
+
+    if len > SEGMENT_FILE_BYTES {
+        let mut file = OpenOptions::new().read(true).write(true).open(path).map_err(StorageError::Io)?;
+        file.seek(SeekFrom::Start(SEGMENT_FILE_BYTES)).map_err(StorageError::Io)?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).map_err(StorageError::Io)?;
+        if tail.iter().any(|&b| b != 0) {
+            return Err(incompatible(
+                path,
+                &format!("{} unexpected non-zero bytes past the last page", len - SEGMENT_FILE_BYTES),
+            ));
+        }
+        file.set_len(SEGMENT_FILE_BYTES).map_err(StorageError::Io)?;
+        file.sync_all().map_err(StorageError::Io)?;
+        return Ok(SizeRepair::Trimmed { from_bytes: len, to_bytes: SEGMENT_FILE_BYTES });
+    }
+
+    Ok(SizeRepair::None)
+}
+
+// struct fiemap from <linux/fiemap.h>, without the trailing extent array.
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+// _IOWR('f', 11, struct fiemap)
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+
+/// Number of physical extents backing a segment file, as reported by FIEMAP.
+/// A preallocated segment is typically a handful of extents; a sparse segment grown
+/// alongside other spaces can reach one per `allocate_extent` call.
+pub fn physical_extent_count(path: &Path) -> Result<u32, StorageError> {
+    use std::os::fd::AsRawFd;
+    let file = fs::File::open(path).map_err(StorageError::Io)?;
+    // With fm_extent_count == 0 the kernel only counts the extents.
+    let mut map = Fiemap {
+        fm_length: u64::MAX,
+        ..Fiemap::default()
+    };
+    let rc = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut map as *mut Fiemap) };
+    if rc != 0 {
+        return Err(StorageError::Io(std::io::Error::last_os_error()));
+    }
+    Ok(map.fm_mapped_extents)
+}
+
+/// Blocking fallocate; only for offline paths where no ring is running yet.
+pub fn preallocate(file: &fs::File, offset: u64, len: u64) -> Result<(), StorageError> {
+    use std::os::fd::AsRawFd;
+    let rc = unsafe { libc::fallocate(file.as_raw_fd(), 0, offset as libc::off_t, len as libc::off_t) };
+    if rc != 0 {
+        return Err(StorageError::Io(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Lists all segment files under `data_dir`. A missing directory means an empty cluster.
+pub fn segment_files(data_dir: &Path) -> Result<Vec<PathBuf>, StorageError> {
+    space_files(data_dir, |name| parse_segment_file_name(name).is_some())
+}
+
+/// Lists all v1 `space_<id>.dat` files under `data_dir`.
+pub fn legacy_files(data_dir: &Path) -> Result<Vec<PathBuf>, StorageError> {
+    space_files(data_dir, |name| parse_legacy_file_name(name).is_some())
+}
+
+fn space_files(data_dir: &Path, matches: impl Fn(&str) -> bool) -> Result<Vec<PathBuf>, StorageError> {
+    let mut files = Vec::new();
+    let db_dirs = match fs::read_dir(data_dir) {
+        Ok(dirs) => dirs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+
+    for db_dir in db_dirs {
+        let db_dir = db_dir.map_err(StorageError::Io)?.path();
+        let is_db_dir = db_dir
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(parse_db_dir_name)
+            .is_some();
+        if !is_db_dir || !db_dir.is_dir() {
+            continue;
+        }
+
+        for entry in fs::read_dir(&db_dir).map_err(StorageError::Io)? {
+            let path = entry.map_err(StorageError::Io)?.path();
+            if path.file_name().and_then(|n| n.to_str()).is_some_and(&matches) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MigrationReport {
+    pub segments_written: u64,
+    pub pages_rewritten: u64,
+    pub pages_empty: u64,
+}
+
+/// Upgrades every v1 space file under `data_dir` to v2 segments stamped with `target`.
+pub fn migrate_data_dir(
+    data_dir: &Path,
+    target: ChecksumAlgorithm,
+    allocation: SegmentAllocation,
+) -> Result<MigrationReport, StorageError> {
+    let mut report = MigrationReport::default();
+    for path in legacy_files(data_dir)? {
+        let one = migrate(&path, target, allocation)?;
+        report.segments_written += one.segments_written;
+        report.pages_rewritten += one.pages_rewritten;
+        report.pages_empty += one.pages_empty;
+    }
+    Ok(report)
+}
+
+/// Upgrades one v1 `db_<id>/space_<id>.dat` file to v2 segment files.
+///
+/// v1 page N sits at N * 8KB; in v2 it moves to segment N / `PAGES_PER_SEGMENT`, one page
+/// further in to make room for the header page. v1 never filled the checksum field, so every
+/// non-zero page is stamped with `target` on the way. All-zero pages were never written and
+/// stay zero, just like freshly allocated v2 pages.
+///
+/// Each segment is built under a `.migrating` name, synced and renamed into place; the v1 file
+/// is removed last. It stays the source of truth until then, so a crash mid-migration is
+/// repaired by running `migrate` again.
+pub fn migrate(
+    path: &Path,
+    target: ChecksumAlgorithm,
+    allocation: SegmentAllocation,
+) -> Result<MigrationReport, StorageError> {
+    let space_id = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(parse_legacy_file_name)
+        .ok_or_else(|| incompatible(path, "not a v1 space_<id>.dat file"))?;
+    let db_dir = path.parent().ok_or_else(|| incompatible(path, "not inside a db_<id> directory"))?;
+    let db_id = db_dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(parse_db_dir_name)
+        .ok_or_else(|| incompatible(path, "not inside a db_<id> directory"))?;
+
+    let mut legacy = fs::File::open(path).map_err(StorageError::Io)?;
+    let len = legacy.metadata().map_err(StorageError::Io)?.len();
+    if len % PAGE_SIZE as u64 != 0 {
+        return Err(incompatible(path, &format!("size {} is not a multiple of the page size", len)));
+    }
+    let total_pages = len / PAGE_SIZE as u64;
+
+    let mut report = MigrationReport::default();
+    let mut page = vec![0u8; PAGE_SIZE];
+    let mut page_no = 0u64;
+    let mut seg_no = 0u32;
+
+    // An empty v1 file still becomes segment 0, so the space keeps existing.
+    while page_no < total_pages || seg_no == 0 {
+        let final_path = db_dir.join(format!("space_{}.{:04}.dat", space_id, seg_no));
+        let tmp_path = db_dir.join(format!("space_{}.{:04}.dat.migrating", space_id, seg_no));
+        let mut segment = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .map_err(StorageError::Io)?;
+
+        // Sized the way `allocate_extent` would have left it.
+        let seg_end = total_pages.min(page_no + PAGES_PER_SEGMENT as u64);
+        match allocation {
+            SegmentAllocation::Preallocate => preallocate(&segment, 0, SEGMENT_FILE_BYTES)?,
+            SegmentAllocation::Sparse => segment
+                .set_len((1 + seg_end - page_no) * PAGE_SIZE as u64)
+                .map_err(StorageError::Io)?,
+        }
+        let header = SegmentHeader {
+            allocated_pages: (seg_end - page_no) as u32,
+            ..SegmentHeader::new(db_id, space_id, seg_no, target)
+        };
+        header.encode(&mut page);
+        segment.write_all(&page).map_err(StorageError::Io)?;
+
+        while page_no < seg_end {
+            legacy.read_exact(&mut page).map_err(StorageError::Io)?;
+            if page.iter().all(|&b| b == 0) {
+                // Already zero from the preallocation or `set_len`.
+                segment.seek(SeekFrom::Current(PAGE_SIZE as i64)).map_err(StorageError::Io)?;
+                report.pages_empty += 1;
+            } else {
+                checksum::stamp_page(target, &mut page);
+                segment.write_all(&page).map_err(StorageError::Io)?;
+                report.pages_rewritten += 1;
+            }
+            page_no += 1;
+        }
+
+        segment.sync_all().map_err(StorageError::Io)?;
+        fs::rename(&tmp_path, &final_path).map_err(StorageError::Io)?;
+        report.segments_written += 1;
+        seg_no += 1;
+    }
+
+    fs::remove_file(path).map_err(StorageError::Io)?;
+    fs::File::open(db_dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(StorageError::Io)?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh, empty data dir under the system temp dir, unique per test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aquifer-segment-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn legacy_page(fill: u8) -> Vec<u8> {
+        let mut page = vec![fill; PAGE_SIZE];
+        page[..4].fill(0); // v1 never wrote the checksum field
+        page
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = SegmentHeader::new(10, 25, 3, ChecksumAlgorithm::Crc32c);
+        let mut page = vec![0u8; PAGE_SIZE];
+        header.encode(&mut page);
+
+        let path = Path::new("space_25.0003.dat");
+        let decoded = SegmentHeader::decode(&page, path).unwrap();
+        assert_eq!(decoded, header);
+        decoded.validate(path, ChecksumAlgorithm::Crc32c).unwrap();
+        assert!(matches!(
+            decoded.validate(path, ChecksumAlgorithm::Crc32),
+            Err(StorageError::IncompatibleFormat { .. })
+        ));
+    }
+
+    #[test]
+    fn decode_rejects_foreign_and_torn_headers() {
+        let path = Path::new("space_1.0000.dat");
+        assert!(matches!(
+            SegmentHeader::decode(&vec![0u8; PAGE_SIZE], path),
+            Err(StorageError::NotASegment(_))
+        ));
+
+        let mut page = vec![0u8; PAGE_SIZE];
+        SegmentHeader::new(1, 1, 0, ChecksumAlgorithm::Crc32c).encode(&mut page);
+        page[SEG_NO_OFFSET] ^= 0xFF;
+        assert!(matches!(
+            SegmentHeader::decode(&page, path),
+            Err(StorageError::IncompatibleFormat { .. })
+        ));
+    }
+
+    #[test]
+    fn file_names_parse_back() {
+        let dir = Path::new("/data");
+        assert_eq!(segment_path(dir, 10, 25, 3), Path::new("/data/db_10/space_25.0003.dat"));
+        assert_eq!(parse_segment_file_name("space_25.0003.dat"), Some((25, 3)));
+        assert_eq!(parse_segment_file_name("space_25.dat"), None);
+        assert_eq!(parse_legacy_file_name("space_25.dat"), Some(25));
+        assert_eq!(parse_legacy_file_name("space_25.0003.dat"), None);
+        assert_eq!(locate(0), (0, PAGE_SIZE as u64));
+        assert_eq!(locate(PAGES_PER_SEGMENT + 1), (1, 2 * PAGE_SIZE as u64));
+    }
+
+    #[test]
+    fn mount_check_refuses_legacy_files_until_migrated() {
+        let dir = scratch_dir("legacy");
+        let legacy = legacy_path(&dir, 3, 7);
+        fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+        let pages = [legacy_page(0xA1), vec![0u8; PAGE_SIZE], legacy_page(0xC3)];
+        fs::write(&legacy, pages.concat()).unwrap();
+
+        assert!(matches!(
+            check_data_dir(&dir, ChecksumAlgorithm::Crc32c, SegmentAllocation::Preallocate),
+            Err(StorageError::IncompatibleFormat { .. })
+        ));
+
+        let report = migrate_data_dir(&dir, ChecksumAlgorithm::Crc32c, SegmentAllocation::Preallocate).unwrap();
+        assert_eq!(report.segments_written, 1);
+        assert_eq!(report.pages_rewritten, 2);
+        assert_eq!(report.pages_empty, 1);
+        assert!(!legacy.exists());
+        check_data_dir(&dir, ChecksumAlgorithm::Crc32c, SegmentAllocation::Preallocate).unwrap();
+
+        // Page N moved one page further in, behind the header, and now carries a checksum.
+        let seg_path = segment_path(&dir, 3, 7, 0);
+        let header = read_header(&seg_path).unwrap();
+        assert_eq!((header.db_id, header.space_id, header.seg_no), (3, 7, 0));
+        assert_eq!(header.allocated_pages, 3);
+        let bytes = fs::read(&seg_path).unwrap();
+        assert_eq!(bytes.len() as u64, SEGMENT_FILE_BYTES);
+        for (page_no, old) in pages.iter().enumerate() {
+            let (seg_no, offset) = locate(page_no as u32);
+            assert_eq!(seg_no, 0);
+            let new = &bytes[offset as usize..offset as usize + PAGE_SIZE];
+            assert_eq!(&new[4..], &old[4..]);
+            if old.iter().any(|&b| b != 0) {
+                assert!(checksum::verify_page(ChecksumAlgorithm::Crc32c, new));
+            }
+        }
+
+        // Nothing left to do on a second run.
+        let again = migrate_data_dir(&dir, ChecksumAlgorithm::Crc32c, SegmentAllocation::Preallocate).unwrap();
+        assert_eq!(again.segments_written, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn migrate_reruns_cleanly_after_a_crash() {
+        let dir = scratch_dir("rerun");
+        let legacy = legacy_path(&dir, 1, 2);
+        fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+        fs::write(&legacy, legacy_page(0x5A)).unwrap();
+
+        // A crash after the segment was renamed in but before the v1 file was removed.
+        fs::write(segment_path(&dir, 1, 2, 0), vec![0xEE; PAGE_SIZE]).unwrap();
+
+        migrate(&legacy, ChecksumAlgorithm::Crc32c, SegmentAllocation::Sparse).unwrap();
+        check_data_dir(&dir, ChecksumAlgorithm::Crc32c, SegmentAllocation::Sparse).unwrap();
+        let bytes = fs::read(segment_path(&dir, 1, 2, 0)).unwrap();
+        assert!(checksum::verify_page(ChecksumAlgorithm::Crc32c, &bytes[PAGE_SIZE..]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
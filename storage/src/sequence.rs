@@ -0,0 +1,181 @@
+use std::cell::Cell;
+
+use crate::buffer_pool::BufferPool;
+use crate::page::{self, PAGE_HEADER_SIZE};
+use crate::traits::{PageId, PageStore, StorageError, WalStore};
+use crate::wal_record::WalRecordType;
+use crate::wal_registry::{PageRecord, WalRecord};
+
+// -----------------------------------------------------------------------------
+// Sequences
+//
+// A sequence hands out increasing u64 values, never the same one twice, crash or not:
+// surrogate keys, ids of objects. Each has a page of its own, which keeps the end of
+// the values reserved so far:
+//
+//   [24..32)  reserved_end  every value below it may have been handed out
+//
+// Values are reserved a chunk at a time: `next` hands them out from memory, and once
+// the chunk is used up logs a SEQUENCE_SET record moving `reserved_end` a chunk on
+// (page = db_id u32 | space_id u32 | page_no u32):
+//
+//   SEQUENCE_SET     page | reserved_end u64   page formatted or chunk reserved
+//
+// and flushes the WAL before handing any of the new chunk out, holding the page's
+// write latch so the others wanting a value wait for it. Redo replays the last
+// reservation, and a sequence opened after a restart starts a new chunk from there,
+// skipping what the last run left of its own. A bigger chunk means fewer flushes and
+// bigger gaps.
+//
+// Like a B-tree, a sequence is used through the pool it is opened on only (see
+// `btree.rs`), the pool of the core owning its page.
+// -----------------------------------------------------------------------------
+
+/// Page type of sequence pages.
+pub const PAGE_TYPE_SEQUENCE: u16 = 9;
+
+/// Values reserved at a time, unless opened with another chunk.
+pub const DEFAULT_CHUNK: u64 = 1000;
+
+const RESERVED_END_OFFSET: usize = 24;
+
+/// A sequence of values, on its page over `pool`.
+pub struct Sequence<'a, S: PageStore + WalStore> {
+    pool: &'a BufferPool<S>,
+    page_id: PageId,
+    chunk: u64,
+    // Values from `next` up to `end` are reserved and not handed out yet
+    next: Cell<u64>,
+    end: Cell<u64>,
+}
+
+impl<'a, S: PageStore + WalStore> Sequence<'a, S> {
+    /// Creates a sequence on a new page of space `space_id`, starting at `start`.
+    /// Returns its page, to `open` it by.
+    pub async fn create(pool: &BufferPool<S>, db_id: u32, space_id: u32, start: u64) -> Result<PageId, StorageError> {
+        let page_no = pool.store().allocate_extent(db_id, space_id, 1).await?;
+        let page_id = PageId { db_id, space_id, page_no };
+        let mut page = pool.get_page_overwrite(page_id).await?;
+        page.apply(&SequenceSet { page_id, reserved_end: start }).await?;
+        Ok(page_id)
+    }
+
+    /// Opens the sequence on page `page_id`, reserving `chunk` values at a time.
+    pub async fn open(pool: &'a BufferPool<S>, page_id: PageId, chunk: u64) -> Result<Self, StorageError> {
+        assert!(chunk > 0, "sequence chunk must not be empty");
+        let page = pool.get_page(page_id).await?;
+        if page::page_type(&page) != PAGE_TYPE_SEQUENCE {
+            return Err(StorageError::NotASequence(page_id));
+        }
+        Ok(Self {
+            pool,
+            page_id,
+            chunk,
+            next: Cell::new(0),
+            end: Cell::new(0),
+        })
+    }
+
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    /// The next value, reserving a new chunk durably first if this one is used up.
+    pub async fn next(&self) -> Result<u64, StorageError> {
+        if let Some(value) = self.take() {
+            return Ok(value);
+        }
+        let mut page = self.pool.get_page_mut(self.page_id).await?;
+        // Reserved by another while this waited for the latch
+        if let Some(value) = self.take() {
+            return Ok(value);
+        }
+        let start = reserved_end(&page);
+        let end = start.checked_add(self.chunk).ok_or(StorageError::SequenceExhausted(self.page_id))?;
+        page.apply(&SequenceSet { page_id: self.page_id, reserved_end: end }).await?;
+        self.pool.store().flush_wal(self.page_id.db_id).await?;
+        drop(page);
+        self.next.set(start + 1);
+        self.end.set(end);
+        Ok(start)
+    }
+
+    /// The end of the values reserved, durable or not: every value handed out so far
+    /// is below it.
+    pub async fn reserved_end(&self) -> Result<u64, StorageError> {
+        Ok(reserved_end(&self.pool.get_page(self.page_id).await?))
+    }
+
+    fn take(&self) -> Option<u64> {
+        let value = self.next.get();
+        (value < self.end.get()).then(|| {
+            self.next.set(value + 1);
+            value
+        })
+    }
+}
+
+fn reserved_end(page: &[u8]) -> u64 {
+    u64::from_le_bytes(page[RESERVED_END_OFFSET..RESERVED_END_OFFSET + 8].try_into().unwrap())
+}
+
+// -----------------------------------------------------------------------------
+// WAL records
+// -----------------------------------------------------------------------------
+
+const PAGE_ID_SIZE: usize = 12;
+
+fn encode_page_id(out: &mut Vec<u8>, page_id: PageId) {
+    for part in [page_id.db_id, page_id.space_id, page_id.page_no] {
+        out.extend_from_slice(&part.to_le_bytes());
+    }
+}
+
+fn decode_page_id(payload: &[u8]) -> Option<PageId> {
+    let field = |at: usize| Some(u32::from_le_bytes(payload.get(at..at + 4)?.try_into().unwrap()));
+    Some(PageId {
+        db_id: field(0)?,
+        space_id: field(4)?,
+        page_no: field(8)?,
+    })
+}
+
+/// A sequence page with `reserved_end`: formatted, or a chunk reserved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceSet {
+    pub page_id: PageId,
+    pub reserved_end: u64,
+}
+
+impl WalRecord for SequenceSet {
+    const TYPE: WalRecordType = WalRecordType::SEQUENCE_SET;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.reserved_end.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 8 {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            reserved_end: u64::from_le_bytes(payload[PAGE_ID_SIZE..].try_into().unwrap()),
+        })
+    }
+}
+
+impl PageRecord for SequenceSet {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        if page::page_type(page) != PAGE_TYPE_SEQUENCE {
+            page[PAGE_HEADER_SIZE..].fill(0);
+            page::set_page_type(page, PAGE_TYPE_SEQUENCE);
+        }
+        page[RESERVED_END_OFFSET..RESERVED_END_OFFSET + 8].copy_from_slice(&self.reserved_end.to_le_bytes());
+    }
+}
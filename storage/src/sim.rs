@@ -0,0 +1,456 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+use futures::future::LocalBoxFuture;
+
+use crate::mem_store::MemStore;
+use crate::page::PAGE_SIZE;
+use crate::runtime::Runtime;
+use crate::traits::{AlignedBuf, Lsn, PageId, PageState, PageStore, StorageError, WalStore};
+use crate::wal_record::WalRecordType;
+
+// -----------------------------------------------------------------------------
+// Deterministic Simulation
+//
+// A `Simulation` runs tasks on one thread with everything that could vary between
+// runs drawn from its seed, FoundationDB-style, so a concurrency or recovery bug found
+// on some seed comes back on every run with it:
+//
+//   scheduling   each step polls one task woken since the last, picked at random
+//   time         a virtual clock, which only moves when no task can run: to the
+//                earliest timer, which then fires. Nothing waits for real.
+//   I/O          `SimStore` requests complete after a random latency, so requests in
+//                flight together complete in any order
+//   crashes      `SimStore::crash` decides what a power cut left: some of the WAL
+//                written past the last flush, and for each page write in flight,
+//                nothing, all of it, or (with torn writes on) its first half
+//
+// Layers above the store run on it unchanged: the buffer pool takes the simulation's
+// clock with `with_runtime` (see `runtime.rs`), and a `SimStore` in place of
+// `CoreStorage`. Waits on anything but the simulation's timers and its store's I/O,
+// a tokio timer or a real thread, would stall it, and a layer iterating a `HashMap`
+// to decide what to do next can still vary between runs.
+//
+// A crash test runs a workload for a while (`run_for`), abandons its tasks mid-flight,
+// crashes the store, and recovers in a new simulation over the store's `MemStore`.
+// -----------------------------------------------------------------------------
+
+/// Handle to a simulation; clones share it.
+#[derive(Clone)]
+pub struct Simulation {
+    state: Rc<SimState>,
+}
+
+struct SimState {
+    seed: u64,
+    rng: Cell<u64>,
+    // Real time the virtual clock started at, and how far it has moved since
+    epoch: Instant,
+    elapsed: Cell<Duration>,
+    // Wakers of the pending timers, by deadline and a sequence number to break ties
+    timers: RefCell<BTreeMap<(Duration, u64), Waker>>,
+    next_timer: Cell<u64>,
+    // Live tasks by id; a task being polled is taken out of its slot
+    tasks: RefCell<Vec<Option<LocalBoxFuture<'static, ()>>>>,
+    woken: Arc<Mutex<Vec<usize>>>,
+    steps: Cell<u64>,
+}
+
+// Queues its task to be polled when woken
+struct TaskWaker {
+    task: usize,
+    woken: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let mut woken = self.woken.lock().unwrap();
+        if !woken.contains(&self.task) {
+            woken.push(self.task);
+        }
+    }
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Rc::new(SimState {
+                seed,
+                // Never zero, which xorshift can't leave
+                rng: Cell::new(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1),
+                epoch: Instant::now(),
+                elapsed: Cell::new(Duration::ZERO),
+                timers: RefCell::new(BTreeMap::new()),
+                next_timer: Cell::new(0),
+                tasks: RefCell::new(Vec::new()),
+                woken: Arc::new(Mutex::new(Vec::new())),
+                steps: Cell::new(0),
+            }),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.state.seed
+    }
+
+    /// Virtual time since the simulation started.
+    pub fn elapsed(&self) -> Duration {
+        self.state.elapsed.get()
+    }
+
+    /// Tasks polled so far.
+    pub fn steps(&self) -> u64 {
+        self.state.steps.get()
+    }
+
+    /// The next number from the seed's sequence (xorshift64*).
+    pub fn random(&self) -> u64 {
+        let mut x = self.state.rng.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state.rng.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A random number below `n`, which must not be zero.
+    pub fn below(&self, n: u64) -> u64 {
+        ((self.random() >> 32) * n) >> 32
+    }
+
+    /// A random duration from `min` up to `max`.
+    pub fn between(&self, min: Duration, max: Duration) -> Duration {
+        let span = max.saturating_sub(min).as_nanos().min(u32::MAX as u128) as u64;
+        min + Duration::from_nanos(self.below(span + 1))
+    }
+
+    /// The simulation's clock, for layers that take a runtime.
+    pub fn runtime(&self) -> Rc<dyn Runtime> {
+        Rc::new(self.clone())
+    }
+
+    /// Completes once `duration` has passed on the virtual clock.
+    pub fn sleep(&self, duration: Duration) -> Sleep {
+        Sleep { state: Rc::clone(&self.state), deadline: self.elapsed() + duration, timer: None }
+    }
+
+    /// Adds a task, first polled on a later step.
+    pub fn spawn(&self, task: impl Future<Output = ()> + 'static) {
+        let mut tasks = self.state.tasks.borrow_mut();
+        let id = tasks.len();
+        tasks.push(Some(Box::pin(task)));
+        self.state.woken.lock().unwrap().push(id);
+    }
+
+    /// Runs `main` and the tasks it spawns until `main` completes, and returns its
+    /// output. Panics if every task is waiting on something that never comes.
+    pub fn run<T: 'static>(&self, main: impl Future<Output = T> + 'static) -> T {
+        let output = Rc::new(RefCell::new(None));
+        let slot = Rc::clone(&output);
+        self.spawn(async move {
+            *slot.borrow_mut() = Some(main.await);
+        });
+        while output.borrow().is_none() {
+            assert!(self.step(), "simulation {} stalled: no task can run and no timer is set", self.seed());
+        }
+        output.take().unwrap()
+    }
+
+    /// Runs the tasks until `duration` has passed on the virtual clock, or none is
+    /// left that can run. Returns whether every task completed.
+    pub fn run_for(&self, duration: Duration) -> bool {
+        let deadline = self.elapsed() + duration;
+        loop {
+            let next_timer = self.state.timers.borrow().keys().next().map(|&(at, _)| at);
+            if self.state.woken.lock().unwrap().is_empty() && next_timer.is_none_or(|at| at > deadline) {
+                break;
+            }
+            self.step();
+        }
+        self.state.elapsed.set(self.elapsed().max(deadline));
+        self.state.tasks.borrow().iter().all(Option::is_none)
+    }
+
+    /// Drops every task where it stands, as a crash would stop them.
+    pub fn abandon(&self) {
+        let tasks = std::mem::take(&mut *self.state.tasks.borrow_mut());
+        self.state.woken.lock().unwrap().clear();
+        drop(tasks);
+        self.state.timers.borrow_mut().clear();
+    }
+
+    /// Polls one woken task, or with none, fires the earliest timer. Returns whether
+    /// there was anything to do.
+    pub fn step(&self) -> bool {
+        let task = {
+            let mut woken = self.state.woken.lock().unwrap();
+            match woken.len() {
+                0 => None,
+                len => Some(woken.swap_remove(self.below(len as u64) as usize)),
+            }
+        };
+        let Some(task) = task else {
+            let Some(((at, _), waker)) = self.state.timers.borrow_mut().pop_first() else {
+                return false;
+            };
+            self.state.elapsed.set(self.elapsed().max(at));
+            waker.wake();
+            return true;
+        };
+
+        let Some(mut future) = self.state.tasks.borrow_mut().get_mut(task).and_then(Option::take) else {
+            return true;
+        };
+        self.state.steps.set(self.steps() + 1);
+        let waker = Waker::from(Arc::new(TaskWaker { task, woken: Arc::clone(&self.state.woken) }));
+        if future.as_mut().poll(&mut Context::from_waker(&waker)).is_pending() {
+            if let Some(slot) = self.state.tasks.borrow_mut().get_mut(task) {
+                *slot = Some(future);
+            }
+        }
+        true
+    }
+}
+
+impl Runtime for Simulation {
+    fn now(&self) -> Instant {
+        self.state.epoch + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) -> LocalBoxFuture<'static, ()> {
+        Box::pin(Simulation::sleep(self, duration))
+    }
+}
+
+/// A timer on a simulation's virtual clock.
+pub struct Sleep {
+    state: Rc<SimState>,
+    deadline: Duration,
+    timer: Option<(Duration, u64)>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.state.elapsed.get() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let timer = match self.timer {
+            Some(timer) => timer,
+            None => {
+                let seq = self.state.next_timer.get();
+                self.state.next_timer.set(seq + 1);
+                (self.deadline, seq)
+            }
+        };
+        self.timer = Some(timer);
+        self.state.timers.borrow_mut().insert(timer, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer {
+            self.state.timers.borrow_mut().remove(&timer);
+        }
+    }
+}
+
+/// A `MemStore` whose requests take a simulation's time, complete in its order, and
+/// can be cut short by a crash.
+pub struct SimStore {
+    sim: Simulation,
+    inner: MemStore,
+    min_latency: Duration,
+    max_latency: Duration,
+    torn_writes: bool,
+    // Page writes issued and not yet complete, by request
+    in_flight: RefCell<BTreeMap<u64, InFlight>>,
+    next_request: Cell<u64>,
+}
+
+// A page write a crash can catch
+struct InFlight {
+    page_id: PageId,
+    image: Box<[u8]>,
+}
+
+impl SimStore {
+    /// Requests take 10 to 200 microseconds.
+    pub fn new(sim: Simulation, inner: MemStore) -> Self {
+        Self {
+            sim,
+            inner,
+            min_latency: Duration::from_micros(10),
+            max_latency: Duration::from_micros(200),
+            torn_writes: false,
+            in_flight: RefCell::new(BTreeMap::new()),
+            next_request: Cell::new(0),
+        }
+    }
+
+    pub fn with_latency(mut self, min: Duration, max: Duration) -> Self {
+        self.min_latency = min;
+        self.max_latency = max.max(min);
+        self
+    }
+
+    /// Lets a crash leave a page write in flight half done.
+    pub fn with_torn_writes(mut self, enabled: bool) -> Self {
+        self.torn_writes = enabled;
+        self
+    }
+
+    pub fn inner(&self) -> &MemStore {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> MemStore {
+        self.inner
+    }
+
+    /// Page writes issued and not yet complete.
+    pub fn writes_in_flight(&self) -> usize {
+        self.in_flight.borrow().len()
+    }
+
+    /// Leaves the store as a power cut would: each log keeps a random number of the
+    /// records past its flushed LSN, and each page write in flight is lost, lands, or
+    /// with torn writes on, lands its first half. Abandon the tasks first; requests
+    /// still pending never complete.
+    pub fn crash(&self) {
+        for db_id in self.inner.databases() {
+            let unflushed = self.inner.wal_records(db_id, self.inner.flushed_lsn(db_id).unwrap_or(Lsn(0)));
+            let kept = self.sim.below(unflushed.len() as u64 + 1) as usize;
+            if let Some(last) = unflushed[..kept].last() {
+                self.inner.mark_flushed(db_id, last.end_lsn());
+            }
+        }
+        let in_flight = std::mem::take(&mut *self.in_flight.borrow_mut());
+        for InFlight { page_id, image } in in_flight.into_values() {
+            let outcomes = if self.torn_writes { 3 } else { 2 };
+            match self.sim.below(outcomes) {
+                0 => {}
+                1 => self.inner.put_page(page_id, &image),
+                _ => {
+                    let mut torn = self.inner.page(page_id).unwrap_or_else(|| vec![0; PAGE_SIZE]);
+                    torn[..PAGE_SIZE / 2].copy_from_slice(&image[..PAGE_SIZE / 2]);
+                    self.inner.put_page(page_id, &torn);
+                }
+            }
+        }
+        self.inner.crash();
+    }
+
+    // A request's time in the device
+    fn io(&self) -> Sleep {
+        self.sim.sleep(self.sim.between(self.min_latency, self.max_latency))
+    }
+
+    // Tracks the page writes of one request until it completes
+    fn issue(&self, first: PageId, bufs: &[&[u8]]) -> Vec<u64> {
+        let mut in_flight = self.in_flight.borrow_mut();
+        bufs.iter()
+            .enumerate()
+            .map(|(index, buf)| {
+                let request = self.next_request.get();
+                self.next_request.set(request + 1);
+                let page_id = PageId { page_no: first.page_no + index as u32, ..first };
+                in_flight.insert(request, InFlight { page_id, image: Box::from(*buf) });
+                request
+            })
+            .collect()
+    }
+
+    fn complete(&self, requests: &[u64]) {
+        let mut in_flight = self.in_flight.borrow_mut();
+        for request in requests {
+            in_flight.remove(request);
+        }
+    }
+}
+
+impl PageStore for SimStore {
+    async fn read_page(&self, page_id: PageId, buf: AlignedBuf) -> (AlignedBuf, Result<PageState, StorageError>) {
+        self.io().await;
+        self.inner.read_page(page_id, buf).await
+    }
+
+    async fn read_pages(&self, start_page_id: PageId, bufs: Vec<AlignedBuf>) -> (Vec<AlignedBuf>, Result<(), StorageError>) {
+        self.io().await;
+        self.inner.read_pages(start_page_id, bufs).await
+    }
+
+    async fn write_page(&self, page_id: PageId, buf: AlignedBuf) -> (AlignedBuf, Result<(), StorageError>) {
+        let requests = self.issue(page_id, &[&buf]);
+        self.io().await;
+        self.complete(&requests);
+        self.inner.write_page(page_id, buf).await
+    }
+
+    async fn write_pages(&self, start_page_id: PageId, bufs: Vec<AlignedBuf>) -> (Vec<AlignedBuf>, Result<(), StorageError>) {
+        let pages: Vec<&[u8]> = bufs.iter().map(|buf| &buf[..]).collect();
+        let requests = self.issue(start_page_id, &pages);
+        self.io().await;
+        self.complete(&requests);
+        self.inner.write_pages(start_page_id, bufs).await
+    }
+
+    async fn allocate_extent(&self, db_id: u32, space_id: u32, num_pages: u32) -> Result<u32, StorageError> {
+        self.io().await;
+        self.inner.allocate_extent(db_id, space_id, num_pages).await
+    }
+
+    async fn free_extent(&self, db_id: u32, space_id: u32, start_page: u32, num_pages: u32) -> Result<(), StorageError> {
+        self.io().await;
+        self.inner.free_extent(db_id, space_id, start_page, num_pages).await
+    }
+}
+
+impl WalStore for SimStore {
+    async fn append_wal(&self, db_id: u32, record_type: WalRecordType, payload: &[u8]) -> Result<Lsn, StorageError> {
+        // Staged in memory, as `CoreStorage` does; only a flush waits for the device.
+        self.inner.append_wal(db_id, record_type, payload).await
+    }
+
+    async fn flush_wal(&self, db_id: u32) -> Result<(), StorageError> {
+        let tail = self.inner.wal_tail(db_id);
+        self.flush_wal_until(db_id, tail).await
+    }
+
+    async fn flush_wal_until(&self, db_id: u32, lsn: Lsn) -> Result<(), StorageError> {
+        // Covers what was appended when it was issued, not what comes in meanwhile.
+        let target = lsn.min(self.inner.wal_tail(db_id));
+        if self.inner.flushed_lsn(db_id)? >= target {
+            return Ok(());
+        }
+        self.io().await;
+        self.inner.mark_flushed(db_id, target);
+        Ok(())
+    }
+
+    fn flushed_lsn(&self, db_id: u32) -> Result<Lsn, StorageError> {
+        self.inner.flushed_lsn(db_id)
+    }
+
+    fn full_page_horizon(&self, db_id: u32) -> Result<Option<Lsn>, StorageError> {
+        self.inner.full_page_horizon(db_id)
+    }
+
+    async fn truncate_wal(&self, db_id: u32, up_to_lsn: Lsn) -> Result<u64, StorageError> {
+        self.io().await;
+        self.inner.truncate_wal(db_id, up_to_lsn).await
+    }
+}
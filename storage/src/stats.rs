@@ -0,0 +1,455 @@
+use std::cell::{Cell, RefCell};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::page::PAGE_SIZE;
+use crate::segment;
+use crate::traits::{Lsn, StorageError};
+use crate::wal;
+
+/// Per-core byte counters behind the write amplification ratio.
+///
+/// "Logical" is what callers asked to persist: page images handed to `write_page` and
+/// WAL payloads handed to `append_wal`. "Physical" is what this core actually submitted
+/// to the device: compressed frames, WAL framing and rewrites, segment header updates.
+#[derive(Debug, Default)]
+pub struct WriteCounters {
+    logical_page_bytes: Cell<u64>,
+    physical_page_bytes: Cell<u64>,
+    logical_wal_bytes: Cell<u64>,
+    physical_wal_bytes: Cell<u64>,
+    physical_meta_bytes: Cell<u64>,
+}
+
+impl WriteCounters {
+    pub fn page_write(&self, logical: usize, physical: usize) {
+        add(&self.logical_page_bytes, logical);
+        add(&self.physical_page_bytes, physical);
+    }
+
+    pub fn wal_append(&self, logical: usize) {
+        add(&self.logical_wal_bytes, logical);
+    }
+
+    pub fn wal_write(&self, physical: usize) {
+        add(&self.physical_wal_bytes, physical);
+    }
+
+    /// Segment headers and other bookkeeping writes with no logical counterpart.
+    pub fn meta_write(&self, physical: usize) {
+        add(&self.physical_meta_bytes, physical);
+    }
+
+    pub fn snapshot(&self) -> WriteStats {
+        WriteStats {
+            logical_page_bytes: self.logical_page_bytes.get(),
+            physical_page_bytes: self.physical_page_bytes.get(),
+            logical_wal_bytes: self.logical_wal_bytes.get(),
+            physical_wal_bytes: self.physical_wal_bytes.get(),
+            physical_meta_bytes: self.physical_meta_bytes.get(),
+        }
+    }
+}
+
+fn add(counter: &Cell<u64>, bytes: usize) {
+    counter.set(counter.get() + bytes as u64);
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    pub logical_page_bytes: u64,
+    pub physical_page_bytes: u64,
+    pub logical_wal_bytes: u64,
+    pub physical_wal_bytes: u64,
+    pub physical_meta_bytes: u64,
+}
+
+impl WriteStats {
+    /// Device bytes written per byte of data pages the caller asked to persist.
+    /// WAL bytes count against the page data they protect, as in the usual definition.
+    /// `None` until something has been written.
+    pub fn write_amplification(&self) -> Option<f64> {
+        let physical = self.physical_page_bytes + self.physical_wal_bytes + self.physical_meta_bytes;
+        ratio(physical, self.logical_page_bytes)
+    }
+
+    /// Physical page bytes per logical page byte; below 1.0 when compression pays off.
+    pub fn page_amplification(&self) -> Option<f64> {
+        ratio(self.physical_page_bytes, self.logical_page_bytes)
+    }
+
+    /// WAL bytes written per payload byte: framing overhead plus tail rewrites.
+    pub fn wal_amplification(&self) -> Option<f64> {
+        ratio(self.physical_wal_bytes, self.logical_wal_bytes)
+    }
+
+    /// Sums several cores' counters.
+    pub fn merge(&self, other: &WriteStats) -> WriteStats {
+        WriteStats {
+            logical_page_bytes: self.logical_page_bytes + other.logical_page_bytes,
+            physical_page_bytes: self.physical_page_bytes + other.physical_page_bytes,
+            logical_wal_bytes: self.logical_wal_bytes + other.logical_wal_bytes,
+            physical_wal_bytes: self.physical_wal_bytes + other.physical_wal_bytes,
+            physical_meta_bytes: self.physical_meta_bytes + other.physical_meta_bytes,
+        }
+    }
+}
+
+fn ratio(numerator: u64, denominator: u64) -> Option<f64> {
+    (denominator > 0).then(|| numerator as f64 / denominator as f64)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpaceStats {
+    /// Pages handed out by `allocate_extent` and not freed since, in bytes.
+    pub logical_bytes: u64,
+    /// Blocks the filesystem has actually allocated to segment files.
+    pub data_file_bytes: u64,
+    /// Blocks allocated to WAL segment files.
+    pub wal_file_bytes: u64,
+}
+
+impl SpaceStats {
+    /// Bytes on disk per byte of allocated data. Preallocated-but-unallocated segment
+    /// space and retained WAL both show up here; compression and hole punching pull it down.
+    pub fn space_amplification(&self) -> Option<f64> {
+        ratio(self.data_file_bytes + self.wal_file_bytes, self.logical_bytes)
+    }
+}
+
+/// Walks the data and WAL directories and totals logical vs on-disk sizes. Uses
+/// allocated blocks (`st_blocks`), not file length, so holes and sparse files count as free.
+pub fn space_stats(data_dir: &Path, wal_dir: &Path) -> Result<SpaceStats, StorageError> {
+    let mut stats = SpaceStats::default();
+    for path in segment::segment_files(data_dir)? {
+        let header = segment::read_header(&path)?;
+        stats.logical_bytes += header.pages_in_use() as u64 * PAGE_SIZE as u64;
+        stats.data_file_bytes += allocated_bytes(&path)?;
+    }
+
+    for (root, _) in wal::wal_roots(wal_dir)? {
+        for db_id in wal::wal_databases(&root)? {
+            for (_, path) in wal::wal_segments(&root, db_id)? {
+                stats.wal_file_bytes += allocated_bytes(&path)?;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+fn allocated_bytes(path: &Path) -> Result<u64, StorageError> {
+    Ok(std::fs::metadata(path).map_err(StorageError::Io)?.blocks() * 512)
+}
+
+// Rates are averaged over this many complete seconds.
+const RATE_WINDOW_SECS: u64 = 10;
+// Power-of-two microsecond buckets: up to ~2^39us (6 days), far past any sane fsync.
+const LATENCY_BUCKETS: usize = 40;
+
+/// One database's WAL activity on one core: appends, and the fsyncs making them durable.
+#[derive(Debug)]
+pub struct WalCounters {
+    records: Cell<u64>,
+    bytes: Cell<u64>,
+    fsyncs: Cell<u64>,
+    // Fsync latency histogram; bucket i counts latencies below 2^i microseconds
+    fsync_latency: RefCell<[u64; LATENCY_BUCKETS]>,
+    started: Instant,
+    // Per-second (second, bytes, records), indexed by second modulo the window
+    recent: RefCell<[(u64, u64, u64); RATE_WINDOW_SECS as usize]>,
+}
+
+impl Default for WalCounters {
+    fn default() -> Self {
+        Self {
+            records: Cell::new(0),
+            bytes: Cell::new(0),
+            fsyncs: Cell::new(0),
+            fsync_latency: RefCell::new([0; LATENCY_BUCKETS]),
+            started: Instant::now(),
+            recent: RefCell::new([(0, 0, 0); RATE_WINDOW_SECS as usize]),
+        }
+    }
+}
+
+impl WalCounters {
+    /// A record of `framed_len` bytes was appended.
+    pub fn record_appended(&self, framed_len: usize) {
+        add(&self.bytes, framed_len);
+        self.records.set(self.records.get() + 1);
+
+        let second = self.started.elapsed().as_secs();
+        let mut recent = self.recent.borrow_mut();
+        let bucket = &mut recent[(second % RATE_WINDOW_SECS) as usize];
+        if bucket.0 != second {
+            *bucket = (second, 0, 0);
+        }
+        bucket.1 += framed_len as u64;
+        bucket.2 += 1;
+    }
+
+    pub fn fsync(&self, latency: Duration) {
+        self.fsyncs.set(self.fsyncs.get() + 1);
+        let micros = latency.as_micros().max(1) as u64;
+        let bucket = (64 - micros.leading_zeros() as usize).min(LATENCY_BUCKETS - 1);
+        self.fsync_latency.borrow_mut()[bucket] += 1;
+    }
+
+    /// Counters plus rates; the caller fills in the stream's LSNs.
+    pub fn snapshot(&self, tail: Lsn, written: Lsn, flushed: Lsn) -> WalStats {
+        let now = self.started.elapsed().as_secs();
+        let window = now.min(RATE_WINDOW_SECS);
+        let (bytes, records) = self
+            .recent
+            .borrow()
+            .iter()
+            .filter(|(second, _, _)| *second < now && *second + window >= now)
+            .fold((0, 0), |(bytes, records), (_, b, r)| (bytes + b, records + r));
+        let per_sec = |n: u64| if window == 0 { 0.0 } else { n as f64 / window as f64 };
+
+        let histogram = self.fsync_latency.borrow();
+        WalStats {
+            records: self.records.get(),
+            bytes_appended: self.bytes.get(),
+            fsyncs: self.fsyncs.get(),
+            bytes_per_sec: per_sec(bytes),
+            records_per_sec: per_sec(records),
+            fsync_p50: percentile(&histogram, 0.50),
+            fsync_p99: percentile(&histogram, 0.99),
+            fsync_p999: percentile(&histogram, 0.999),
+            tail_lsn: tail,
+            written_lsn: written,
+            flushed_lsn: flushed,
+        }
+    }
+}
+
+// Upper bound of the histogram bucket holding quantile `q`; zero with no samples.
+fn percentile(histogram: &[u64; LATENCY_BUCKETS], q: f64) -> Duration {
+    let total: u64 = histogram.iter().sum();
+    if total == 0 {
+        return Duration::ZERO;
+    }
+    let rank = ((total as f64 * q).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (i, count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return Duration::from_micros(1 << i);
+        }
+    }
+    Duration::from_micros(1 << (LATENCY_BUCKETS - 1))
+}
+
+/// WAL throughput and durability for one database on one core (see `CoreStorage::wal_stats`).
+///
+/// Counts are totals since the stream was opened; rates average the last few complete
+/// seconds. Fsync percentiles are power-of-two bucket bounds, so accurate within 2x.
+/// A commit workload is fsync-bound when `fsyncs` tracks commits one to one and the
+/// p99 approaches the commit latency, or when `unflushed_bytes` keeps growing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WalStats {
+    pub records: u64,
+    /// Framed bytes appended, headers and CRCs included.
+    pub bytes_appended: u64,
+    pub fsyncs: u64,
+    pub bytes_per_sec: f64,
+    pub records_per_sec: f64,
+    pub fsync_p50: Duration,
+    pub fsync_p99: Duration,
+    pub fsync_p999: Duration,
+    /// Where the next record goes.
+    pub tail_lsn: Lsn,
+    /// Everything below this has been handed to the kernel.
+    pub written_lsn: Lsn,
+    /// Everything below this is durable.
+    pub flushed_lsn: Lsn,
+}
+
+impl WalStats {
+    /// Log bytes appended but not yet durable: what a crash right now would lose.
+    pub fn unflushed_bytes(&self) -> u64 {
+        self.tail_lsn.0 - self.flushed_lsn.0
+    }
+
+    /// Written to the kernel but not yet fsynced.
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.written_lsn.0 - self.flushed_lsn.0
+    }
+}
+
+/// Latency of one kind of operation over the current window (see `metrics.rs`).
+/// Percentiles are within 1/16 of the true value; all zero for an empty window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpLatency {
+    pub count: u64,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+/// One core's I/O latencies since its last window reset (see `CoreStorage::latency_stats`).
+///
+/// A p999 far above the p99 with a low count is usually one stall, not a trend; reset
+/// the window and watch it again. `wal_append` close to `fsync` means appends keep
+/// waiting on full staging buffers being written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub read_page: OpLatency,
+    pub write_page: OpLatency,
+    pub fsync: OpLatency,
+    pub wal_append: OpLatency,
+}
+
+/// Events per second, averaged over the last few complete seconds.
+#[derive(Debug)]
+pub struct RecentRate {
+    started: Instant,
+    // Per-second (second, events), indexed by second modulo the window
+    recent: RefCell<[(u64, u64); RATE_WINDOW_SECS as usize]>,
+}
+
+impl Default for RecentRate {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            recent: RefCell::new([(0, 0); RATE_WINDOW_SECS as usize]),
+        }
+    }
+}
+
+impl RecentRate {
+    pub fn record(&self, events: u64) {
+        let second = self.started.elapsed().as_secs();
+        let mut recent = self.recent.borrow_mut();
+        let bucket = &mut recent[(second % RATE_WINDOW_SECS) as usize];
+        if bucket.0 != second {
+            *bucket = (second, 0);
+        }
+        bucket.1 += events;
+    }
+
+    pub fn per_sec(&self) -> f64 {
+        let now = self.started.elapsed().as_secs();
+        let window = now.min(RATE_WINDOW_SECS);
+        if window == 0 {
+            return 0.0;
+        }
+        let events: u64 = self
+            .recent
+            .borrow()
+            .iter()
+            .filter(|(second, _)| *second < now && *second + window >= now)
+            .map(|(_, events)| events)
+            .sum();
+        events as f64 / window as f64
+    }
+}
+
+/// One buffer pool's state and activity (see `BufferPool::stats`).
+///
+/// A pool is too small when the hit ratio stays low and evictions keep up with misses;
+/// `victim_writes` climbing means loads wait on write-backs, so the background writer
+/// lags. A high dirty ratio means checkpoints will be long, and a low prefetch accuracy
+/// that read-ahead reads pages nobody uses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferPoolStats {
+    /// Frames in use.
+    pub capacity: usize,
+    /// Frames holding a page, holding a modified one, and pinned right now.
+    pub cached: usize,
+    pub dirty: usize,
+    pub pinned: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub evictions_per_sec: f64,
+    /// Evictions that had to write the victim back first.
+    pub victim_writes: u64,
+    pub background_writes: u64,
+    /// Mean time a frame stays pinned, from its first pin to its last unpin.
+    pub avg_pin_duration: Duration,
+    /// Pages read ahead, then used, or evicted before anyone used them.
+    pub prefetched: u64,
+    pub prefetch_hits: u64,
+    pub prefetch_wasted: u64,
+    /// Frames bulk scans reused from their scan ring.
+    pub ring_recycles: u64,
+    /// Frames holding pages bulk operations loaded, and their requests served around the
+    /// pool once over budget.
+    pub bulk_cached: usize,
+    pub bulk_spills: u64,
+}
+
+impl BufferPoolStats {
+    /// Requests served without a read. `None` before the first request.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        ratio(self.hits, self.hits + self.misses)
+    }
+
+    /// Share of the frames in use holding unwritten changes.
+    pub fn dirty_ratio(&self) -> Option<f64> {
+        ratio(self.dirty as u64, self.capacity as u64)
+    }
+
+    /// Share of read-ahead pages that were used before being evicted, among those
+    /// settled either way.
+    pub fn prefetch_accuracy(&self) -> Option<f64> {
+        ratio(self.prefetch_hits, self.prefetch_hits + self.prefetch_wasted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum;
+    use crate::core_storage::CoreStorage;
+    use crate::page::PAGE_HEADER_SIZE;
+    use crate::traits::{AlignedBuf, PageId, PageStore, StorageConfig, WalStore};
+    use crate::wal_record::WalRecordType;
+
+    fn scratch(name: &str) -> StorageConfig {
+        let dir = std::env::temp_dir().join(format!("aquifer-stats-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::create_dir_all(dir.join("wal")).unwrap();
+        StorageConfig::scratch(dir.join("data"), dir.join("wal"))
+    }
+
+    #[test]
+    fn amplification_counts_wal_and_headers_against_page_data() {
+        let config = scratch("amplification");
+        tokio_uring::start(async {
+            let storage = CoreStorage::new(&config, 0);
+            assert_eq!(storage.write_stats().write_amplification(), None);
+
+            let first = storage.allocate_extent(1, 1, 8).await.unwrap();
+            for i in 0..8 {
+                let mut page = AlignedBuf::page();
+                page[PAGE_HEADER_SIZE..].fill(i as u8 + 1);
+                checksum::stamp_page(config.checksum, &mut page);
+                let page_id = PageId { db_id: 1, space_id: 1, page_no: first + i };
+                let (_, res) = storage.write_page(page_id, page).await;
+                res.unwrap();
+            }
+            storage.append_wal(1, WalRecordType::OPAQUE, &[7; 100]).await.unwrap();
+            storage.flush_wal(1).await.unwrap();
+
+            let writes = storage.write_stats();
+            assert_eq!(writes.logical_page_bytes, 8 * PAGE_SIZE as u64);
+            assert_eq!(writes.page_amplification(), Some(1.0));
+            assert_eq!(writes.logical_wal_bytes, 100);
+            // Framing at least, and the flush writes whole blocks
+            assert!(writes.wal_amplification().unwrap() > 1.0);
+            assert!(writes.write_amplification().unwrap() > 1.0);
+        });
+
+        let space = space_stats(&config.data_dir, &config.wal_dir).unwrap();
+        assert_eq!(space.logical_bytes, 8 * PAGE_SIZE as u64);
+        assert!(space.data_file_bytes >= space.logical_bytes);
+        assert!(space.wal_file_bytes > 0);
+        assert!(space.space_amplification().unwrap() > 1.0);
+    }
+}
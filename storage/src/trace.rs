@@ -0,0 +1,61 @@
+// -----------------------------------------------------------------------------
+// Tracing
+//
+// Built with the `tracing` feature, the I/O paths run in `tracing` spans, for a
+// subscriber (`tracing-subscriber`, an OpenTelemetry layer, ...) to time them and see
+// what they wait on. Spans nest as the calls do: a buffer pool miss's `evict` and
+// `load` hold the store's `write_page` and `read_page`, a WAL flush its `sync_wal`, a
+// checkpoint its `write_back`. Spans of a page or a record are at debug level, the
+// rest at info:
+//
+//   CoreStorage   read_page, write_page     db_id, space_id, page_no (and page lsn)
+//                 read_pages                db_id, space_id, page_no, pages
+//                 append_wal                db_id, record_type, len, lsn once assigned
+//                 flush_wal_until           db_id, lsn (the target)
+//                 sync_wal                  db_id, flushed: the LSN made durable
+//   BufferPool    load, evict               db_id, space_id, page_no (and the frame, dirty)
+//   Checkpointer  checkpoint                db_id, begin_lsn, end_lsn
+//                 write_back                pages, lsn
+//
+// The spans are `tracing::instrument` attributes behind `cfg_attr`, and fields known
+// only partway through are filled in with `record!`; without the feature, both are
+// compiled out.
+//
+// What the engine has to tell an operator outside any call, a stalled core say, goes
+// out as a `tracing` event with `warn_event!`. Without the feature it is compiled out
+// too, and the library prints nothing.
+// -----------------------------------------------------------------------------
+
+/// Records `field = value`s on the current span, declared with
+/// `tracing::field::Empty`. Nothing without the `tracing` feature.
+#[cfg(feature = "tracing")]
+macro_rules! record {
+    ($($field:ident = $value:expr),+ $(,)?) => {{
+        let span = tracing::Span::current();
+        $(span.record(stringify!($field), $value);)+
+    }};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! record {
+    ($($field:ident = $value:expr),+ $(,)?) => {};
+}
+
+pub(crate) use record;
+
+/// Emits a `tracing` warning event. Nothing without the `tracing` feature.
+#[cfg(feature = "tracing")]
+macro_rules! warn_event {
+    ($($arg:tt)+) => {
+        tracing::warn!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! warn_event {
+    ($($arg:tt)+) => {{
+        let _ = || format!($($arg)+);
+    }};
+}
+
+pub(crate) use warn_event;
@@ -1,10 +1,115 @@
-use std::io::Result;
-use std::path::PathBuf;
+use std::alloc::{self, Layout};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::backup::{self, BackupManifest};
+use crate::buffer_pool::{BackgroundWriterConfig, BufferPool, PrefetchConfig, ScanRingConfig};
+use crate::catalog::SpaceEntry;
+use crate::checkpointer::CheckpointerConfig;
+use crate::checksum::ChecksumAlgorithm;
+use crate::compression::PageCompression;
+use crate::core_storage;
+use crate::discard::DiscardConfig;
+use crate::encryption::XtsKey;
+use crate::eviction::EvictionKind;
+use crate::metrics::Metrics;
+use crate::mount::{self, DataDirLock};
+use crate::numa::{self, NumaNode};
+use crate::partition::{PageInbox, PageRouter, PagePartition};
+use crate::quarantine::{self, CorruptPagePolicy};
+use crate::ring::CompletionConfig;
+use crate::segment;
+use crate::trace;
+use crate::wal_archive::WalArchiver;
+use crate::wal_compress::WalCompression;
+use crate::wal;
+use crate::wal_crypt::{WalCipher, WalKey};
+use crate::wal_recovery::{self, TornTail};
+use crate::wal_record::WalRecordType;
+use crate::wal_redo::{self, RedoPlan, RedoSummary};
+use crate::wal_registry::WalRegistry;
 
 /// Represents a 4096-byte aligned memory buffer required for O_DIRECT.
 /// Backed by the pre-allocated Buffer Pool RAM.
 pub struct AlignedBuf {
-    // Internal pointer to aligned memory
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl AlignedBuf {
+    /// O_DIRECT requires both the memory address and the length to be multiples of this.
+    pub const ALIGNMENT: usize = 4096;
+
+    /// Allocates a zeroed buffer. `len` is rounded up to the next `ALIGNMENT` boundary.
+    pub fn new(len: usize) -> Self {
+        let len = len.max(1).next_multiple_of(Self::ALIGNMENT);
+        let layout = Layout::from_size_align(len, Self::ALIGNMENT).expect("invalid AlignedBuf layout");
+        // Zeroing up front means every byte is always initialized from tokio-uring's point of view.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, len }
+    }
+
+    /// Allocates a single zeroed 8KB page.
+    pub fn page() -> Self {
+        Self::new(crate::page::PAGE_SIZE)
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.len, Self::ALIGNMENT).unwrap();
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), layout) };
+    }
+}
+
+// The buffer exclusively owns its allocation, so handing it to another thread
+// (e.g. the checksum offload worker) is as safe as moving a `Vec<u8>`.
+unsafe impl Send for AlignedBuf {}
+
+// The allocation never moves while the kernel holds the buffer, which is what
+// tokio-uring requires of submitted buffers.
+unsafe impl tokio_uring::buf::IoBuf for AlignedBuf {
+    fn stable_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.len
+    }
+}
+
+unsafe impl tokio_uring::buf::IoBufMut for AlignedBuf {
+    fn stable_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    unsafe fn set_init(&mut self, _pos: usize) {
+        // Always fully initialized (zeroed at allocation).
+    }
 }
 
 /// Uniquely identifies an 8KB physical page across the system.
@@ -15,6 +120,16 @@ pub struct PageId {
     pub page_no: u32,  // 8KB logical offset
 }
 
+/// What `read_page` found on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageState {
+    /// A verified page, decoded into the buffer.
+    Written,
+    /// The page was never written (an all-zero image or beyond the end of the segment).
+    /// The buffer is zero-filled; the buffer pool should format it as a new page.
+    Fresh,
+}
+
 /// A physical byte offset in the Write-Ahead Log.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Lsn(pub u64);
@@ -26,6 +141,33 @@ pub enum StorageError {
     UnalignedBuffer,    // Buffer didn't meet O_DIRECT requirements
     OutOfSpace,
     ShortRead,          // Hit EOF before filling all requested buffers
+    NotASegment(PathBuf), // File has no valid segment header magic
+    IncompatibleFormat { path: PathBuf, reason: String }, // Version, page size, or checksum mismatch
+    MissingDictionary { db_id: u32, space_id: u32, version: u32 }, // Page references an unknown zstd dictionary
+    MissingKey { db_id: u32, space_id: u32 }, // Encrypted page in a space configured without a key
+    RecordTooLarge(usize),      // WAL record payload over `wal_record::MAX_RECORD_PAYLOAD`
+    UnknownWalRecord { lsn: Lsn, record_type: WalRecordType }, // No subsystem registered the type of a replayed record
+    MalformedWalRecord { lsn: Lsn, record_type: WalRecordType }, // Payload its owner's `WalRecord::decode` rejects
+    BufferPoolExhausted, // Every buffer pool frame is in use
+    CoreUnavailable(usize), // The core owning a page stopped serving other cores' requests
+    InMemoryCorruption(PageId), // Page CRC stopped matching between layers (end-to-end checksum mode)
+    SegmentTruncated { path: PathBuf, expected_bytes: u64, actual_bytes: u64, first_missing_page: u32 }, // Allocated pages missing from disk
+    AlreadyMounted(PathBuf), // Another process holds the data directory's lock
+    WalNotFlushed { page_id: PageId, page_lsn: Lsn, flushed: Lsn }, // Page write would put a change on disk before its WAL record
+    TupleTooLarge(usize), // Heap tuple over `heap::MAX_TUPLE_SIZE`, overflow value of 4 GiB or more, or LSM entry over `lsm::MAX_ENTRY_SIZE`
+    BrokenOverflow(PageId), // Overflow chain ends early, or runs into a page of another kind
+    NotACatalog(u32), // Space 0 of the database holds something other than its catalog
+    Deadlock(u64), // Transaction would wait for a lock held by one waiting for it; abort it
+    NotASequence(PageId), // Page opened as a sequence holds something else
+    SequenceExhausted(PageId), // Sequence's next chunk would go past u64::MAX
+    NotAnLsmTree(PageId), // Manifest page of an LSM tree's space holds something else
+    LsmManifestFull(PageId), // LSM tree would have more than `lsm::MAX_SSTS` SSTs
+    NotABlobStore(PageId), // Root page of a blob store's space holds something else
+    BlobNotFound(PageId), // Header page holds no live blob with the id's serial
+    BlobTooLarge(u64), // Blob over `blob::MAX_BLOB_SIZE` bytes
+    BlobGarbageFull(PageId), // Blob store's garbage list holds `blob::MAX_GARBAGE` blobs already
+    DuplicateKey(u64), // Unique index already has the key, for another row
+    NotAHashIndex(PageId), // Meta page of a hash index's space holds something else
 }
 
 // -----------------------------------------------------------------------------
@@ -34,15 +176,17 @@ pub enum StorageError {
 pub trait PageStore {
     /// Reads a single 8KB page from the NVMe drive.
     /// Takes ownership of the AlignedBuf and returns it to avoid copying.
+    /// A never-written page is not corruption: it comes back zero-filled as `PageState::Fresh`.
     async fn read_page(
         &self, 
         page_id: PageId, 
         buf: AlignedBuf
-    ) -> (AlignedBuf, Result<(), StorageError>);
+    ) -> (AlignedBuf, Result<PageState, StorageError>);
 
     /// Reads a contiguous range of 8KB pages from disk into multiple buffers.
     /// Highly optimized for Sequential Scans and Prefetching via io_uring vectored I/O.
     /// The `bufs` length determines how many sequential pages are read starting at `start_page_id`.
+    /// Never-written pages are zero-filled; use `page::is_fresh` to tell them apart.
     async fn read_pages(
         &self, 
         start_page_id: PageId, 
@@ -67,7 +211,8 @@ pub trait PageStore {
     ) -> (Vec<AlignedBuf>, Result<(), StorageError>);
 
     /// Pre-allocates a chunk of disk space to prevent file fragmentation.
-    /// Returns the starting `page_no` of the newly allocated extent.
+    /// Returns the starting `page_no` of the newly allocated extent, which may be one
+    /// freed earlier (see `segment.rs`).
     async fn allocate_extent(
         &self, 
         db_id: u32, 
@@ -75,7 +220,9 @@ pub trait PageStore {
         num_pages: u32
     ) -> Result<u32, StorageError>;
     
-    /// Reclaims space to the OS (punching a hole or truncating).
+    /// Reclaims space to the OS (punching a hole or truncating). Extents wholly inside
+    /// the range become free for `allocate_extent` to hand out again; the caller must be
+    /// done with their pages, in its buffer pool too.
     async fn free_extent(
         &self, 
         db_id: u32, 
@@ -89,11 +236,14 @@ pub trait PageStore {
 // 2. The Sequential I/O Interface (Used by the Transaction Manager)
 // -----------------------------------------------------------------------------
 pub trait WalStore {
-    /// Appends a binary WAL record to the end of the log.
+    /// Appends a binary WAL record to the end of the log, framed with its type and a CRC
+    /// (see `wal_record.rs`).
     /// Returns the exact byte offset (LSN) where this record was written.
+    /// The record is staged in memory and is only durable after `flush_wal`.
     async fn append_wal(
         &self, 
         db_id: u32, 
+        record_type: WalRecordType,
         payload: &[u8]
     ) -> Result<Lsn, StorageError>;
 
@@ -101,9 +251,28 @@ pub trait WalStore {
     /// Call this when the user types `COMMIT`.
     async fn flush_wal(&self, db_id: u32) -> Result<(), StorageError>;
 
+    /// Makes the log durable at least up to `lsn` (clamped to the current tail), e.g. a
+    /// commit record's `end_lsn`. Returns at once if it already is; if a flush already
+    /// in flight covers `lsn`, waits for that one instead of issuing another.
+    async fn flush_wal_until(&self, db_id: u32, lsn: Lsn) -> Result<(), StorageError>;
+
+    /// Everything of the database's log below this LSN is durable. No I/O once the log
+    /// is open, so the buffer pool can ask before every page write whether the WAL
+    /// still needs a flush.
+    fn flushed_lsn(&self, db_id: u32) -> Result<Lsn, StorageError>;
+
+    /// With full-page writes on, the LSN a page must have been changed at or after for
+    /// its next change not to log the whole page first (see `full_page.rs`); `None` with
+    /// them off. No I/O once the log is open.
+    fn full_page_horizon(&self, db_id: u32) -> Result<Option<Lsn>, StorageError>;
+
     /// Deletes or recycles physical WAL segment files older than the given LSN.
     /// Called by the Checkpointer after 8KB data pages are safely on disk.
-    async fn truncate_wal(&self, db_id: u32, up_to_lsn: Lsn) -> Result<(), StorageError>;
+    /// With archiving configured, stops at the first segment not yet archived. The last
+    /// checkpoint's end record is always kept; pass `CheckpointEnd::oldest_needed_lsn`.
+    /// Segments a replication slot or a backup in progress still needs are kept too.
+    /// Returns the bytes of segment files removed or recycled.
+    async fn truncate_wal(&self, db_id: u32, up_to_lsn: Lsn) -> Result<u64, StorageError>;
 }
 
 // -----------------------------------------------------------------------------
@@ -115,45 +284,373 @@ pub struct StorageConfig {
     pub data_dir: PathBuf,
     pub wal_dir: PathBuf,
     pub io_uring_entries: u32, // e.g., 1024 or 2048
+    /// When each core's ring posts completions: eagerly for latency, batched for
+    /// throughput, or with submission polling. See `ring.rs`.
+    pub completions: CompletionConfig,
+    pub checksum: ChecksumAlgorithm,
+    /// Batches larger than this many bytes are hashed on the checksum helper thread
+    /// instead of the io_uring submit loop. `None` keeps all hashing inline.
+    pub checksum_offload_threshold: Option<usize>,
+    /// Per-space overrides keyed by `(db_id, space_id)`. Spaces not listed use `SpaceOptions::default()`.
+    pub spaces: HashMap<(u32, u32), SpaceOptions>,
+    /// Whether freed extents and dropped spaces are punched out / trimmed, per device.
+    pub discard: DiscardConfig,
+    /// Preallocate whole segments, or grow them per extent. See `SegmentAllocation`
+    /// for the fragmentation tradeoff.
+    pub segment_allocation: segment::SegmentAllocation,
+    /// Verify the buffer pool's page CRC on every hop instead of only on disk reads:
+    /// before a page is written, and against the reconstructed page after a compressed
+    /// or encrypted image is decoded. Catches corruption in memory between layers.
+    pub end_to_end_checksums: bool,
+    /// Log a page's whole image before its first change after each checkpoint, so redo
+    /// can repair a page torn by a crash mid-write from the WAL alone. Costs up to a
+    /// page of WAL per page changed per checkpoint. See `full_page.rs`.
+    pub full_page_writes: bool,
+    /// What a read returns for a page failing its checksum, which is quarantined either
+    /// way: the error, or a zeroed page for disaster recovery. See `quarantine.rs`.
+    pub corrupt_pages: CorruptPagePolicy,
+    /// Let crash recovery skip a torn page it has no full-page image for, leaving it
+    /// quarantined, instead of failing. For disaster scenarios only: the page stays
+    /// corrupt until a higher layer rebuilds it.
+    pub redo_past_corruption: bool,
+    /// Hot metadata pages (catalog root, FSM roots, space maps) loaded at mount and kept
+    /// resident for the life of the process. At most `pinned::MAX_PINNED_PAGES`.
+    pub pinned_pages: Vec<PageId>,
+    /// Group commit window (like Postgres' `commit_delay`): a flush leader that sees other
+    /// commits queued behind it waits this long before its fsync so more of them share it.
+    /// A lone commit never waits. `None` flushes immediately.
+    pub commit_delay: Option<Duration>,
+    /// Upper bound on how long a `Durability::Async` commit stays non-durable, e.g. 10ms.
+    /// Longer windows batch more commits per fsync and lose more on a crash.
+    pub async_commit_window: Duration,
+    /// Receives every completed WAL segment; `truncate_wal` keeps segments until their
+    /// archiving succeeded. `None` disables archiving.
+    pub wal_archiver: Option<Arc<dyn WalArchiver>>,
+    /// Compress WAL segments once they are complete. Readers (recovery, replication)
+    /// expand them transparently.
+    pub wal_compression: WalCompression,
+    /// Per-database AES-256-GCM keys for WAL encryption, keyed by `db_id`. Databases not
+    /// listed write a plaintext WAL. Pair this with page encryption: encrypted pages next
+    /// to a plaintext WAL leak every change through the log. A key must be in place
+    /// before the database's first WAL record; existing plaintext segments are not converted.
+    pub wal_keys: HashMap<u32, WalKey>,
+    /// WAL segments kept created, fallocated and zero-filled ahead of each log's tail by
+    /// a helper thread, so appends crossing into a new segment never wait on block
+    /// allocation. Truncated segments are recycled into these slots. 0 disables it.
+    pub wal_future_segments: usize,
+    /// One WAL per database, or one per database per core so commits on different cores
+    /// never share an fsync. See `wal::WalLayout` for the ordering guarantees.
+    pub wal_layout: wal::WalLayout,
+    /// Write the WAL with O_DIRECT instead of through the page cache. Writes are whole
+    /// 4K blocks: a partial last block goes out zero-padded and is written again as it
+    /// fills. Saves a copy per byte logged on fsync-heavy commit workloads, at the cost
+    /// of rewriting the tail block on every flush. Encrypted WALs (whose stored blocks
+    /// aren't 4K) always use buffered I/O.
+    pub wal_direct_io: bool,
+    /// Cores running a `CoreStorage`, numbered from 0. Pages are partitioned across
+    /// their buffer pools (see `partition::owning_core`).
+    pub cores: usize,
+    /// 8KB frames in each core's buffer pool at startup.
+    pub buffer_pool_frames: usize,
+    /// Frames `BufferPool::resize` may grow each core's pool to.
+    pub buffer_pool_max_frames: usize,
+    /// Reload the pages cached at the last clean shutdown on mount (see `warmup.rs`).
+    pub buffer_pool_warm_up: bool,
+    /// Place each core's buffer pool in the memory of the core's NUMA node, and keep its
+    /// worker thread on that node's CPUs. No effect on single-node machines.
+    pub numa_aware: bool,
+    /// Replacement policy of the buffer pools over each core. Clock sweep is the
+    /// cheapest; LRU-2 resists scans, TinyLFU skewed access. See `eviction.rs`.
+    pub buffer_pool_eviction: EvictionKind,
+    /// How hard each buffer pool's background writer works to keep the next eviction
+    /// victims clean.
+    pub background_writer: BackgroundWriterConfig,
+    /// When each buffer pool's prefetcher treats accesses to a space as a sequential
+    /// scan, and how far it reads ahead.
+    pub prefetch: PrefetchConfig,
+    /// When a sequential scan counts as a bulk scan, and the frames its pages recycle
+    /// among in each buffer pool instead of evicting the working set.
+    pub scan_ring: ScanRingConfig,
+    /// Percent of each buffer pool's frames bulk loads and index builds may fill; past
+    /// it their pages bypass the pool. 100 puts no limit on them.
+    pub buffer_pool_bulk_budget: u32,
+    /// When each core's `Checkpointer` checkpoints a database: by time or WAL volume.
+    pub checkpointer: CheckpointerConfig,
+    /// Reads, writes, fsyncs and hole punches taking at least this long are counted
+    /// (`cascade_slow_ios_total`) and logged as a `watchdog::SlowIo` warning, e.g. 50ms:
+    /// a device getting slower often does so well before it starts failing. `None`
+    /// disables it.
+    pub slow_io_threshold: Option<Duration>,
+}
+
+#[cfg(test)]
+impl StorageConfig {
+    /// A config for a `CoreStorage` over scratch directories: sparse segments and nothing
+    /// optional turned on, so tests neither allocate gigabytes nor start helper threads.
+    pub(crate) fn scratch(data_dir: PathBuf, wal_dir: PathBuf) -> Self {
+        Self {
+            data_dir,
+            wal_dir,
+            io_uring_entries: 256,
+            checksum: ChecksumAlgorithm::Crc32c,
+            checksum_offload_threshold: None,
+            spaces: HashMap::new(),
+            discard: Default::default(),
+            segment_allocation: segment::SegmentAllocation::Sparse,
+            end_to_end_checksums: true,
+            full_page_writes: false,
+            corrupt_pages: Default::default(),
+            redo_past_corruption: false,
+            pinned_pages: Vec::new(),
+            commit_delay: None,
+            async_commit_window: Duration::from_millis(10),
+            wal_archiver: None,
+            wal_compression: Default::default(),
+            wal_keys: HashMap::new(),
+            wal_future_segments: 0,
+            wal_layout: Default::default(),
+            wal_direct_io: false,
+            cores: 1,
+            buffer_pool_frames: 64,
+            buffer_pool_max_frames: 64,
+            buffer_pool_eviction: Default::default(),
+            buffer_pool_warm_up: false,
+            numa_aware: false,
+            background_writer: Default::default(),
+            prefetch: Default::default(),
+            scan_ring: Default::default(),
+            buffer_pool_bulk_budget: 100,
+            checkpointer: Default::default(),
+            slow_io_threshold: None,
+            completions: Default::default(),
+        }
+    }
+}
+
+/// Storage options that can differ between spaces.
+#[derive(Debug, Clone, Default)]
+pub struct SpaceOptions {
+    /// Opt-in transparent page compression. Trades CPU on every read/write for
+    /// device space, so it suits cold analytical spaces rather than hot OLTP ones.
+    pub compression: Option<PageCompression>,
+    /// AES-256-XTS at-rest encryption of page contents. The page header (checksum, LSN,
+    /// type, flags) stays in the clear so recovery and checksum scans work without the key.
+    pub encryption: Option<XtsKey>,
 }
 
 /// The global manager that boots the database, discovers files, and runs crash recovery.
 pub struct StorageManager {
     config: StorageConfig,
+    // Held until the manager is dropped
+    _lock: DataDirLock,
+    clean_shutdown: bool,
+    // Segment files of every space, by (db_id, space_id)
+    spaces: HashMap<(u32, u32), Vec<PathBuf>>,
+    // Catalog of every database that has one, as read during mount
+    catalogs: HashMap<u32, Vec<SpaceEntry>>,
+    size_repairs: Vec<(PathBuf, segment::SizeRepair)>,
+    torn_tails: Vec<TornTail>,
+    redo_plans: Vec<RedoPlan>,
+    // Routes page requests to their owning core; each core's inbox until it claims it
+    page_router: PageRouter,
+    page_inboxes: Mutex<Vec<Option<PageInbox>>>,
+    // Every core's I/O metrics, for the exporter
+    metrics: Arc<Metrics>,
 }
 
 impl StorageManager {
+    /// Locks and validates the data directory, discovers its spaces and, unless the
+    /// last run shut down cleanly, prepares WAL recovery. See `mount.rs`.
     pub fn mount(config: StorageConfig) -> Result<Self, StorageError> {
-        // ... scans directories, maps db_id to physical paths ...
-        todo!()
+        config.completions.validate(config.io_uring_entries)?;
+        // Nothing is read or written before the lock is ours.
+        let lock = DataDirLock::acquire(&config.data_dir)?;
+        let clean_shutdown = mount::take_clean_shutdown(&config.data_dir)?;
+
+        // Refuse to touch any segment written in a format this build does not understand.
+        // Older versions must be upgraded offline with `segment::migrate` first.
+        // Benign size mismatches are repaired here instead of surfacing later as short reads.
+        let size_repairs = segment::check_data_dir(&config.data_dir, config.checksum, config.segment_allocation)?;
+        let spaces = mount::discover_spaces(&config.data_dir)?;
+        let catalogs = mount::load_catalogs(&config, &spaces, clean_shutdown)?;
+
+        // A crash mid-append can leave a partial record at the end of a WAL. Cut it off
+        // now, before anything reads the log or appends after the garbage. Then find
+        // where each stream's redo starts, from its last checkpoint (see `wal_redo.rs`).
+        // After a clean shutdown every change is on disk, and there is nothing to do.
+        let mut torn_tails = Vec::new();
+        let mut redo_plans = Vec::new();
+        let wal_roots = if clean_shutdown { Vec::new() } else { wal::wal_roots(&config.wal_dir)? };
+        for (root, origin) in wal_roots {
+            let core_id = wal::lsn_core(origin);
+            for db_id in wal::wal_databases(&root)? {
+                let cipher = config.wal_keys.get(&db_id).map(|key| WalCipher::new(key, db_id, core_id));
+                if let Some(torn) = wal_recovery::truncate_torn_tail(&root, db_id, origin, cipher.as_ref())? {
+                    trace::warn_event!(
+                        "wal: db {} core {} truncated at {:?} ({}), discarded {} bytes",
+                        torn.db_id, core_id, torn.truncated_at, torn.reason, torn.discarded_bytes
+                    );
+                    torn_tails.push(torn);
+                }
+                redo_plans.push(wal_redo::plan(&root, db_id, origin, cipher.as_ref())?);
+            }
+        }
+
+        let (page_router, inboxes) = PageRouter::new(config.cores);
+        let page_inboxes = Mutex::new(inboxes.into_iter().map(Some).collect());
+
+        Ok(Self {
+            config,
+            _lock: lock,
+            clean_shutdown,
+            spaces,
+            catalogs,
+            size_repairs,
+            torn_tails,
+            redo_plans,
+            page_router,
+            page_inboxes,
+            metrics: Metrics::new(),
+        })
+    }
+
+    /// Whether the last run shut down cleanly, so `mount` skipped WAL recovery.
+    pub fn clean_shutdown(&self) -> bool {
+        self.clean_shutdown
+    }
+
+    /// Every space found during `mount`, by `(db_id, space_id)`, with its segment files
+    /// in segment order.
+    pub fn spaces(&self) -> &HashMap<(u32, u32), Vec<PathBuf>> {
+        &self.spaces
+    }
+
+    /// The spaces database `db_id`'s catalog lists, as read during `mount`; `None` if
+    /// it has no catalog, or it was left to recovery. After a crash this is the catalog
+    /// before redo: `catalog::Catalog::list` gives the recovered one.
+    pub fn catalog(&self, db_id: u32) -> Option<&[SpaceEntry]> {
+        self.catalogs.get(&db_id).map(Vec::as_slice)
+    }
+
+    /// Copies database `db_id` into `dest` while the engine runs, for a restore to
+    /// recover to a consistent state (see `backup.rs`). Blocking; call it off the cores.
+    pub fn basebackup(&self, db_id: u32, dest: &Path) -> Result<BackupManifest, StorageError> {
+        backup::basebackup(&self.config.data_dir, &self.config.wal_dir, db_id, self.config.checksum, &self.config.wal_keys, dest)
+    }
+
+    /// Copies the pages of database `db_id` changed since backup `parent` into `dest`;
+    /// see `backup::incremental_backup`.
+    pub fn incremental_backup(&self, db_id: u32, parent: &BackupManifest, dest: &Path) -> Result<BackupManifest, StorageError> {
+        let config = &self.config;
+        backup::incremental_backup(&config.data_dir, &config.wal_dir, db_id, config.checksum, &config.wal_keys, parent, dest)
+    }
+
+    /// Records a clean shutdown, so the next `mount` skips WAL recovery. Call last, once
+    /// every core has stopped taking work and flushed its buffer pool and WAL.
+    pub fn mark_clean_shutdown(&self) -> Result<(), StorageError> {
+        mount::mark_clean_shutdown(&self.config.data_dir)
+    }
+
+    /// Segment files whose size was repaired during `mount`, for operator diagnostics.
+    pub fn size_repairs(&self) -> &[(PathBuf, segment::SizeRepair)] {
+        &self.size_repairs
+    }
+
+    /// WALs whose torn tail was cut off during `mount`.
+    pub fn torn_tails(&self) -> &[TornTail] {
+        &self.torn_tails
+    }
+
+    /// Where crash recovery replays each WAL stream from, as found during `mount`.
+    pub fn redo_plans(&self) -> &[RedoPlan] {
+        &self.redo_plans
+    }
+
+    /// Crash recovery of one core's pages: replays the WAL into `partition`'s pool
+    /// wherever a page is missing a logged change, then loads the core's share of
+    /// `StorageConfig::pinned_pages`. Run on every core, on the core, before spawning
+    /// its `serve` or taking any other work.
+    pub async fn recover(&self, partition: &PagePartition, registry: &WalRegistry) -> Result<RedoSummary, StorageError> {
+        let config = &self.config;
+        let summary = wal_redo::redo(partition, &self.redo_plans, &config.wal_keys, registry, config.redo_past_corruption).await?;
+        // Redone pages are still dirty in the pool, which serves them ahead of the pinned
+        // copy; writing them back refreshes it.
+        let pinned: Vec<PageId> = config.pinned_pages.iter().copied().filter(|&page_id| partition.is_local(page_id)).collect();
+        partition.pool().store().pin_pages(&pinned).await?;
+        Ok(summary)
+    }
+
+    /// Every core's I/O metrics, for `Metrics::gather` or a `MetricsServer`. Each core's
+    /// are counted by its `CoreStorage` and buffer pool partition.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Every page quarantined as corrupt on any core and not yet released, for higher
+    /// layers to rebuild. Blocking; see `CoreStorage::release_quarantined`.
+    pub fn quarantined_pages(&self) -> Result<Vec<PageId>, StorageError> {
+        quarantine::load(&self.config.data_dir)
+    }
+
+    /// The runtime to start each core's thread with, its ring set up as configured:
+    /// `manager.runtime().start(async { ... manager.local_worker(core_id) ... })`.
+    pub fn runtime(&self) -> tokio_uring::Builder {
+        self.config.completions.runtime(self.config.io_uring_entries)
     }
 
     /// Spawns a dedicated, lock-free io_uring storage instance for a specific CPU core.
     /// Note: The returned `CoreStorage` is strictly `!Send` and `!Sync`.
-    pub fn local_worker(&self, core_id: usize) -> CoreStorage {
-        todo!()
+    /// Only a mounted manager hands out workers, so no core starts before the data
+    /// directory is locked and validated; run `recover` on each before it serves.
+    pub fn local_worker(&self, core_id: usize) -> core_storage::CoreStorage {
+        if let Some(node) = self.numa_node(core_id) {
+            if let Err(e) = numa::pin_thread(&node) {
+                trace::warn_event!("core {}: worker not pinned to NUMA node {}: {:?}", core_id, node.id, e);
+            }
+        }
+        core_storage::CoreStorage::new(&self.config, core_id).with_metrics(self.metrics.core(core_id))
     }
-}
-
-/// The actual engine running on a single thread. It holds the `tokio-uring` ring
-/// and an array of open File Descriptors.
-pub struct CoreStorage {
-    core_id: usize,
-    // active_files: HashMap<(u32, u32), std::os::fd::RawFd>,
-}
 
-impl PageStore for CoreStorage {
-    async fn read_page(&self, page_id: PageId, buf: AlignedBuf) -> (AlignedBuf, Result<(), StorageError>) { todo!() }
-    async fn read_pages(&self, start_page_id: PageId, bufs: Vec<AlignedBuf>) -> (Vec<AlignedBuf>, Result<(), StorageError>) { todo!() }
-    async fn write_page(&self, page_id: PageId, buf: AlignedBuf) -> (AlignedBuf, Result<(), StorageError>) { todo!() }
-    async fn write_pages(&self, start_page_id: PageId, bufs: Vec<AlignedBuf>) -> (Vec<AlignedBuf>, Result<(), StorageError>) { todo!() }
-    async fn allocate_extent(&self, db_id: u32, space_id: u32, num_pages: u32) -> Result<u32, StorageError> { todo!() }
-    async fn free_extent(&self, db_id: u32, space_id: u32, start_page: u32, num_pages: u32) -> Result<(), StorageError> { todo!() }
-}
+    // The NUMA node to place `core_id`'s thread and memory on, if that is configured and
+    // makes a difference
+    fn numa_node(&self, core_id: usize) -> Option<NumaNode> {
+        if !self.config.numa_aware {
+            return None;
+        }
+        numa::cpu_node(core_id).unwrap_or_else(|e| {
+            trace::warn_event!("core {}: NUMA topology unavailable: {:?}", core_id, e);
+            None
+        })
+    }
 
-impl WalStore for CoreStorage {
-    async fn append_wal(&self, db_id: u32, payload: &[u8]) -> Result<Lsn, StorageError> { todo!() }
-    async fn flush_wal(&self, db_id: u32) -> Result<(), StorageError> { todo!() }
-    async fn truncate_wal(&self, db_id: u32, up_to_lsn: Lsn) -> Result<(), StorageError> { todo!() }
+    /// Builds the buffer pool partition of `core_id`, over that core's storage. Call once
+    /// per core, on the core, then spawn its `serve`. Panics if `core_id` is not below
+    /// `StorageConfig::cores` or its partition was already built.
+    pub fn local_partition(&self, core_id: usize, storage: Rc<core_storage::CoreStorage>) -> PagePartition {
+        assert!(
+            core_id < self.config.cores,
+            "buffer pool partition of core {} requested, but the storage was mounted with {} cores",
+            core_id,
+            self.config.cores
+        );
+        let inbox = self.page_inboxes.lock().unwrap()[core_id]
+            .take()
+            .unwrap_or_else(|| panic!("buffer pool partition of core {} already built", core_id));
+        let pool = BufferPool::new(
+            storage,
+            self.config.buffer_pool_frames,
+            self.config.buffer_pool_max_frames,
+            self.config.checksum,
+            self.config.buffer_pool_eviction,
+        )
+        .with_scan_ring(self.config.scan_ring.clone())
+        .with_bulk_budget(self.config.buffer_pool_bulk_budget)
+        .with_metrics(self.metrics.core(core_id));
+        if let Some(node) = self.numa_node(core_id) {
+            if let Err(e) = pool.bind_to_numa_node(node.id) {
+                trace::warn_event!("core {}: buffer pool left unbound, NUMA node {}: {:?}", core_id, node.id, e);
+            }
+        }
+        PagePartition::new(core_id, Rc::new(pool), self.page_router.clone(), inbox)
+    }
 }
-
@@ -0,0 +1,277 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::checksum;
+use crate::commit_ts::{Commit, Durability};
+use crate::mvcc::{Snapshot, TransactionStatus, XidStatus};
+use crate::partition::PagePartition;
+use crate::traits::StorageError;
+use crate::undo::UndoLog;
+use crate::wal_recovery;
+
+// -----------------------------------------------------------------------------
+// Transaction Manager
+//
+// Hands out transaction ids, keeps the table of transactions running, and takes each
+// to its end. One per database, shared by all its cores, with two files in the
+// database's directory:
+//
+//   data_dir/db_<id>/XID       end of the ids reserved u64 | CRC32C u32
+//   data_dir/db_<id>/ABORTED   xid u64 of each transaction aborted, appended
+//
+// Ids increase for the life of the database, from FIRST_XID (below it are
+// `mvcc::INVALID_XID` and `mvcc::FROZEN_XID`). They are reserved `XID_BATCH` at a time,
+// durably, before any of a batch is handed out, so a restart carries on after the last
+// reservation and never hands an id out twice; what a run left of its batch is skipped.
+//
+// `commit` logs the commit record on the committing core, waiting for it to be flushed
+// with `Durability::Sync` (`CoreStorage::commit`), and only then takes the transaction
+// out of the table, so a snapshot sees its changes once they are durable, or staged
+// with `Durability::Async`. `abort` appends the id to ABORTED first, so the
+// transaction's versions are invisible from then on, restart or not, then rolls its
+// undo chain back (`PagePartition::abort`). The versions stay for vacuum to remove.
+// Either way the transaction's locks on the core (`lock.rs`) are released last.
+//
+// The manager is the status source of visibility checks (`mvcc.rs`): a transaction in
+// ABORTED is aborted, one in the table in progress, and any other id handed out
+// committed, including those of earlier runs. Recovery reports the losers of a crash,
+// the transactions it rolls back (`UndoScan::losers`), with `mark_aborted`. A
+// transaction in flight at a crash that had logged nothing through its undo chain is
+// not among them, and its versions would read as committed: one writing versions must
+// log a change through its chain first.
+//
+// Both files are written with blocking I/O, once per batch and once per abort.
+// -----------------------------------------------------------------------------
+
+/// First transaction id handed out.
+pub const FIRST_XID: u64 = 2;
+
+/// Ids reserved by each write of XID.
+pub const XID_BATCH: u64 = 4096;
+
+const XID_FILE_SIZE: usize = 12;
+
+/// A running transaction.
+#[derive(Debug)]
+pub struct Transaction {
+    xid: u64,
+    cid: u32,
+    snapshot: Snapshot,
+    undo: UndoLog,
+}
+
+impl Transaction {
+    pub fn xid(&self) -> u64 {
+        self.xid
+    }
+
+    /// The current command.
+    pub fn cid(&self) -> u32 {
+        self.cid
+    }
+
+    /// Starts the next command, which sees the changes of those before it.
+    pub fn next_command(&mut self) {
+        self.cid += 1;
+        self.snapshot.cid = self.cid;
+    }
+
+    /// The snapshot taken at `begin`, at the current command: for reads that see the
+    /// same data throughout the transaction. `TransactionManager::snapshot` takes a
+    /// newer one.
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    /// The undo chain, for changes that `abort` rolls back
+    /// (`PagePartition::update_page`).
+    pub fn undo_log(&mut self) -> &mut UndoLog {
+        &mut self.undo
+    }
+}
+
+/// The transactions of database `db_id`.
+pub struct TransactionManager {
+    db_id: u32,
+    dir: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    next_xid: u64,
+    reserved_end: u64,
+    // Running transactions, with the oldest `xmin` of the snapshots they took
+    active: BTreeMap<u64, u64>,
+    aborted: HashSet<u64>,
+    aborted_file: fs::File,
+}
+
+impl TransactionManager {
+    /// Loads the ids reserved and the transactions aborted of database `db_id`, under
+    /// `data_dir`. Blocking.
+    pub fn open(data_dir: &Path, db_id: u32) -> Result<Self, StorageError> {
+        let dir = data_dir.join(format!("db_{}", db_id));
+        fs::create_dir_all(&dir).map_err(StorageError::Io)?;
+
+        let xid_path = dir.join("XID");
+        let reserved_end = match fs::read(&xid_path) {
+            Ok(bytes) => decode_reserved(&xid_path, &bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => FIRST_XID,
+            Err(e) => return Err(StorageError::Io(e)),
+        };
+
+        // An abort cut short by a crash can leave part of an id at the end.
+        let aborted_path = dir.join("ABORTED");
+        let bytes = match fs::read(&aborted_path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(StorageError::Io(e)),
+        };
+        let whole = bytes.len() / 8 * 8;
+        let aborted = bytes[..whole].chunks_exact(8).map(|xid| u64::from_le_bytes(xid.try_into().unwrap())).collect();
+        let aborted_file = OpenOptions::new().create(true).append(true).open(&aborted_path).map_err(StorageError::Io)?;
+        if whole < bytes.len() {
+            aborted_file.set_len(whole as u64).map_err(StorageError::Io)?;
+        }
+
+        Ok(Self {
+            db_id,
+            dir,
+            state: Mutex::new(State {
+                next_xid: reserved_end,
+                reserved_end,
+                active: BTreeMap::new(),
+                aborted,
+                aborted_file,
+            }),
+        })
+    }
+
+    pub fn db_id(&self) -> u32 {
+        self.db_id
+    }
+
+    /// Starts a transaction, with a new id and a snapshot of those running.
+    pub fn begin(&self) -> Result<Transaction, StorageError> {
+        let mut state = self.state();
+        if state.next_xid == state.reserved_end {
+            let reserved_end = state.reserved_end + XID_BATCH;
+            self.save_reserved(reserved_end)?;
+            state.reserved_end = reserved_end;
+        }
+        let xid = state.next_xid;
+        state.next_xid += 1;
+        let xmin = state.active.keys().next().copied().unwrap_or(xid);
+        state.active.insert(xid, xmin);
+        let snapshot = take_snapshot(&state, xid, 0);
+        Ok(Transaction {
+            xid,
+            cid: 0,
+            snapshot,
+            undo: UndoLog::new(self.db_id, xid),
+        })
+    }
+
+    /// A new snapshot for `txn`'s current command: for reads that see every change
+    /// committed so far.
+    pub fn snapshot(&self, txn: &Transaction) -> Snapshot {
+        take_snapshot(&self.state(), txn.xid, txn.cid)
+    }
+
+    /// Commits `txn` on this core: see `CoreStorage::commit`. After an error `txn` is
+    /// still running; abort it.
+    pub async fn commit(&self, txn: &Transaction, partition: &PagePartition, durability: Durability) -> Result<Commit, StorageError> {
+        let commit = partition.pool().store().commit(self.db_id, txn.xid, durability).await?;
+        self.state().active.remove(&txn.xid);
+        partition.locks().release_all(txn.xid);
+        Ok(commit)
+    }
+
+    /// Aborts `txn`, and rolls back its undo chain. After an error `txn` holds the
+    /// changes still to undo; abort it again.
+    pub async fn abort(&self, txn: &mut Transaction, partition: &PagePartition) -> Result<(), StorageError> {
+        self.mark_aborted([txn.xid])?;
+        self.state().active.remove(&txn.xid);
+        partition.abort(&mut txn.undo).await?;
+        partition.locks().release_all(txn.xid);
+        Ok(())
+    }
+
+    /// Records transactions `xids` as aborted, such as the losers recovery rolled back.
+    pub fn mark_aborted(&self, xids: impl IntoIterator<Item = u64>) -> Result<(), StorageError> {
+        let mut state = self.state();
+        let new: Vec<u64> = xids.into_iter().filter(|xid| !state.aborted.contains(xid)).collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+        let bytes: Vec<u8> = new.iter().flat_map(|xid| xid.to_le_bytes()).collect();
+        state.aborted_file.write_all(&bytes).map_err(StorageError::Io)?;
+        state.aborted_file.sync_data().map_err(StorageError::Io)?;
+        state.aborted.extend(new);
+        Ok(())
+    }
+
+    /// The transactions running, oldest first.
+    pub fn active(&self) -> Vec<u64> {
+        self.state().active.keys().copied().collect()
+    }
+
+    /// The oldest `xmin` of the snapshots the running transactions took: a version
+    /// deleted by a commit below it is dead (`mvcc::TupleHeader::is_dead`).
+    pub fn horizon(&self) -> u64 {
+        let state = self.state();
+        state.active.values().copied().min().unwrap_or(state.next_xid)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn save_reserved(&self, reserved_end: u64) -> Result<(), StorageError> {
+        let mut bytes = reserved_end.to_le_bytes().to_vec();
+        let crc = checksum::crc32c(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        wal_recovery::write_durably(&self.dir.join("XID"), &bytes)?;
+        fs::File::open(&self.dir).and_then(|dir| dir.sync_all()).map_err(StorageError::Io)
+    }
+}
+
+impl TransactionStatus for TransactionManager {
+    fn status(&self, xid: u64) -> XidStatus {
+        let state = self.state();
+        if state.aborted.contains(&xid) {
+            XidStatus::Aborted
+        } else if state.active.contains_key(&xid) {
+            XidStatus::InProgress
+        } else if xid < state.next_xid {
+            XidStatus::Committed
+        } else {
+            XidStatus::InProgress
+        }
+    }
+}
+
+// A snapshot for command `cid` of `xid`, which is running
+fn take_snapshot(state: &State, xid: u64, cid: u32) -> Snapshot {
+    let active: Vec<u64> = state.active.keys().copied().collect();
+    let xmin = active.first().copied().unwrap_or(state.next_xid);
+    Snapshot::new(xid, cid, xmin, state.next_xid, active)
+}
+
+fn decode_reserved(path: &Path, bytes: &[u8]) -> Result<u64, StorageError> {
+    let invalid = |reason: &str| StorageError::IncompatibleFormat {
+        path: path.to_path_buf(),
+        reason: reason.to_string(),
+    };
+    if bytes.len() != XID_FILE_SIZE {
+        return Err(invalid("transaction id file has the wrong size"));
+    }
+    let crc = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if checksum::crc32c(&bytes[..8]) != crc {
+        return Err(invalid("transaction id file fails its checksum"));
+    }
+    Ok(u64::from_le_bytes(bytes[..8].try_into().unwrap()).max(FIRST_XID))
+}
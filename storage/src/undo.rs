@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+
+use crate::buffer_pool::BufferPool;
+use crate::commit_ts::CommitRecord;
+use crate::core_storage::CoreStorage;
+use crate::page;
+use crate::partition::PagePartition;
+use crate::traits::{Lsn, PageId, StorageError};
+use crate::wal_record::{self, WalRecordType};
+use crate::wal_registry::{PageRecord, WalRecord};
+
+// -----------------------------------------------------------------------------
+// Undo Logging
+//
+// A change a transaction may have to take back is logged as a PAGE_UPDATE: the bytes
+// of one page range before and after it. The after image is for redo, the before
+// image for undo. Rolling back writes each before image back, newest change first,
+// and logs every one as a COMPENSATION record (a CLR). A CLR is redo-only: it names
+// the update it undid, so a rollback cut short by a crash is never undone twice, and
+// its own redo reapplies the before image. ABORT marks a rollback complete.
+//
+//   PAGE_UPDATE    xid u64 | db_id u32 | space_id u32 | page_no u32 | offset u16
+//                  | len u16 | before [len] | after [len]
+//   COMPENSATION   xid u64 | undone LSN u64 | db_id u32 | space_id u32 | page_no u32
+//                  | offset u16 | len u16 | image [len]
+//   ABORT          xid u64
+//
+// A running transaction keeps its undo chain in memory (`UndoLog`), so `abort` never
+// reads the log. Each undo runs on the core owning the page, and its CLR goes to that
+// core's WAL, like any other change. Undo is physical, so the transaction must hold
+// its locks on what it changed until the rollback completes.
+//
+// At recovery, the redo pass hands every record to an `UndoScan`. A transaction with
+// changes but no COMMIT or ABORT was in flight at the crash (a loser), and each core
+// rolls back its losers' changes to the pages it owns, newest first, after redo has
+// brought those pages up to date. Nothing marks a loser done: its next recovery would
+// find every change compensated. The scan starts early enough to see the first record
+// of every transaction active at the last checkpoint (`CheckpointEnd::active_txns`).
+// -----------------------------------------------------------------------------
+
+const UPDATE_HEADER_SIZE: usize = 24;
+const CLR_HEADER_SIZE: usize = 32;
+
+/// A transaction's change to bytes `offset..offset + before.len()` of a page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageUpdate {
+    pub xid: u64,
+    pub page_id: PageId,
+    pub offset: u16,
+    pub before: Vec<u8>,
+    pub after: Vec<u8>,
+}
+
+impl WalRecord for PageUpdate {
+    const TYPE: WalRecordType = WalRecordType::PAGE_UPDATE;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.reserve(UPDATE_HEADER_SIZE + 2 * self.before.len());
+        out.extend_from_slice(&self.xid.to_le_bytes());
+        encode_range(out, self.page_id, self.offset, self.before.len());
+        out.extend_from_slice(&self.before);
+        out.extend_from_slice(&self.after);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let xid = u64::from_le_bytes(payload.get(..8)?.try_into().unwrap());
+        let (page_id, offset, len) = decode_range(&payload[8..])?;
+        let images = &payload[UPDATE_HEADER_SIZE..];
+        if images.len() != 2 * len {
+            return None;
+        }
+        Some(Self {
+            xid,
+            page_id,
+            offset,
+            before: images[..len].to_vec(),
+            after: images[len..].to_vec(),
+        })
+    }
+}
+
+impl PageRecord for PageUpdate {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let start = self.offset as usize;
+        page[start..start + self.after.len()].copy_from_slice(&self.after);
+    }
+}
+
+/// The undo of one PAGE_UPDATE (at LSN `undone`): its before image, written back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compensation {
+    pub xid: u64,
+    pub undone: Lsn,
+    pub page_id: PageId,
+    pub offset: u16,
+    pub image: Vec<u8>,
+}
+
+impl WalRecord for Compensation {
+    const TYPE: WalRecordType = WalRecordType::COMPENSATION;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.reserve(CLR_HEADER_SIZE + self.image.len());
+        out.extend_from_slice(&self.xid.to_le_bytes());
+        out.extend_from_slice(&self.undone.0.to_le_bytes());
+        encode_range(out, self.page_id, self.offset, self.image.len());
+        out.extend_from_slice(&self.image);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let xid = u64::from_le_bytes(payload.get(..8)?.try_into().unwrap());
+        let undone = Lsn(u64::from_le_bytes(payload.get(8..16)?.try_into().unwrap()));
+        let (page_id, offset, len) = decode_range(&payload[16..])?;
+        let image = &payload[CLR_HEADER_SIZE..];
+        if image.len() != len {
+            return None;
+        }
+        Some(Self { xid, undone, page_id, offset, image: image.to_vec() })
+    }
+}
+
+impl PageRecord for Compensation {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let start = self.offset as usize;
+        page[start..start + self.image.len()].copy_from_slice(&self.image);
+    }
+}
+
+/// A completed rollback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbortRecord {
+    pub xid: u64,
+}
+
+impl WalRecord for AbortRecord {
+    const TYPE: WalRecordType = WalRecordType::ABORT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.xid.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        Some(Self {
+            xid: u64::from_le_bytes(payload.try_into().ok()?),
+        })
+    }
+}
+
+// page id, offset and length, as both record types lay them out
+fn encode_range(out: &mut Vec<u8>, page_id: PageId, offset: u16, len: usize) {
+    for part in [page_id.db_id, page_id.space_id, page_id.page_no] {
+        out.extend_from_slice(&part.to_le_bytes());
+    }
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&(len as u16).to_le_bytes());
+}
+
+fn decode_range(bytes: &[u8]) -> Option<(PageId, u16, usize)> {
+    let field = |at: usize| Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().unwrap()));
+    let page_id = PageId {
+        db_id: field(0)?,
+        space_id: field(4)?,
+        page_no: field(8)?,
+    };
+    let offset = u16::from_le_bytes(bytes.get(12..14)?.try_into().unwrap());
+    let len = u16::from_le_bytes(bytes.get(14..16)?.try_into().unwrap()) as usize;
+    if offset as usize + len > page::PAGE_SIZE || (offset as usize) < page::PAGE_HEADER_SIZE {
+        return None;
+    }
+    Some((page_id, offset, len))
+}
+
+/// One change to take back: where, and the bytes it replaced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoEntry {
+    /// LSN of the PAGE_UPDATE.
+    pub lsn: Lsn,
+    pub page_id: PageId,
+    pub offset: u16,
+    pub before: Vec<u8>,
+}
+
+/// A running transaction's undo chain: its changes, oldest first.
+#[derive(Debug)]
+pub struct UndoLog {
+    db_id: u32,
+    xid: u64,
+    entries: Vec<UndoEntry>,
+}
+
+impl UndoLog {
+    pub fn new(db_id: u32, xid: u64) -> Self {
+        Self { db_id, xid, entries: Vec::new() }
+    }
+
+    pub fn db_id(&self) -> u32 {
+        self.db_id
+    }
+
+    pub fn xid(&self) -> u64 {
+        self.xid
+    }
+
+    /// LSN of the transaction's first change, for `CheckpointEnd::active_txns`.
+    pub fn first_lsn(&self) -> Option<Lsn> {
+        self.entries.first().map(|entry| entry.lsn)
+    }
+
+    pub fn entries(&self) -> &[UndoEntry] {
+        &self.entries
+    }
+
+    pub fn push(&mut self, entry: UndoEntry) {
+        self.entries.push(entry);
+    }
+
+    /// Drops the newest change, once undone.
+    pub fn pop(&mut self) -> Option<UndoEntry> {
+        self.entries.pop()
+    }
+}
+
+/// Transactions in flight as the log is replayed, with their changes to this core's
+/// pages not yet compensated.
+#[derive(Debug, Default)]
+pub struct UndoScan {
+    // By database and xid; a transaction's entries in log order
+    txns: HashMap<(u32, u64), Vec<UndoEntry>>,
+}
+
+impl UndoScan {
+    /// Takes note of one record of database `db_id`'s log. `is_local` says which pages
+    /// this core rolls back.
+    pub fn observe(&mut self, db_id: u32, record: &wal_record::WalRecord, is_local: impl Fn(PageId) -> bool) -> Result<(), StorageError> {
+        let malformed = || StorageError::MalformedWalRecord { lsn: record.lsn, record_type: record.record_type };
+        match record.record_type {
+            PageUpdate::TYPE => {
+                let update = PageUpdate::decode(&record.payload).ok_or_else(malformed)?;
+                let entries = self.txns.entry((db_id, update.xid)).or_default();
+                if is_local(update.page_id) {
+                    entries.push(UndoEntry {
+                        lsn: record.lsn,
+                        page_id: update.page_id,
+                        offset: update.offset,
+                        before: update.before,
+                    });
+                }
+            }
+            Compensation::TYPE => {
+                let clr = Compensation::decode(&record.payload).ok_or_else(malformed)?;
+                if let Some(entries) = self.txns.get_mut(&(db_id, clr.xid)) {
+                    entries.retain(|entry| entry.lsn != clr.undone);
+                }
+            }
+            CommitRecord::TYPE => {
+                let commit = CommitRecord::decode(&record.payload).ok_or_else(malformed)?;
+                self.txns.remove(&(db_id, commit.xid));
+            }
+            AbortRecord::TYPE => {
+                let abort = AbortRecord::decode(&record.payload).ok_or_else(malformed)?;
+                self.txns.remove(&(db_id, abort.xid));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Transactions in flight at the end of the log, as (db_id, xid).
+    pub fn losers(&self) -> Vec<(u32, u64)> {
+        let mut losers: Vec<(u32, u64)> = self.txns.keys().copied().collect();
+        losers.sort_unstable();
+        losers
+    }
+
+    /// Rolls back the losers' changes to this core's pages, newest first, logging a CLR
+    /// for each in this core's WAL. Returns the changes undone.
+    pub async fn roll_back(self, partition: &PagePartition) -> Result<u64, StorageError> {
+        let mut undo: Vec<(u64, UndoEntry)> = self
+            .txns
+            .into_iter()
+            .flat_map(|((_, xid), entries)| entries.into_iter().map(move |entry| (xid, entry)))
+            .collect();
+        undo.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.lsn));
+        let undone = undo.len() as u64;
+        for (xid, entry) in undo {
+            undo_locally(partition.pool(), xid, entry).await?;
+        }
+        Ok(undone)
+    }
+}
+
+/// Writes `entry`'s before image back and logs the CLR, in `pool`, the one of the
+/// core owning the page.
+pub async fn undo_locally(pool: &BufferPool<CoreStorage>, xid: u64, entry: UndoEntry) -> Result<Lsn, StorageError> {
+    let mut page = pool.get_page_mut(entry.page_id).await?;
+    let start = entry.offset as usize;
+    page[start..start + entry.before.len()].copy_from_slice(&entry.before);
+    let clr = Compensation {
+        xid,
+        undone: entry.lsn,
+        page_id: entry.page_id,
+        offset: entry.offset,
+        image: entry.before,
+    };
+    page.log(&clr).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::checksum::ChecksumAlgorithm;
+    use crate::commit_ts::CommitTimestamp;
+    use crate::partition::PageRouter;
+    use crate::traits::{PageStore, StorageConfig, WalStore};
+    use crate::wal_redo;
+    use crate::wal_registry::WalRegistry;
+
+    const BODY: usize = page::PAGE_HEADER_SIZE;
+
+    fn page_id(page_no: u32) -> PageId {
+        PageId { db_id: 1, space_id: 1, page_no }
+    }
+
+    fn scratch_config(name: &str) -> StorageConfig {
+        let dir = std::env::temp_dir().join(format!("aquifer-undo-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::create_dir_all(dir.join("wal")).unwrap();
+        StorageConfig::scratch(dir.join("data"), dir.join("wal"))
+    }
+
+    fn partition(config: &StorageConfig) -> PagePartition {
+        let storage = Rc::new(CoreStorage::new(config, 0));
+        let pool = BufferPool::new(storage, 16, 16, ChecksumAlgorithm::Crc32c, config.buffer_pool_eviction);
+        let (router, mut inboxes) = PageRouter::new(1);
+        PagePartition::new(0, Rc::new(pool), router, inboxes.remove(0))
+    }
+
+    async fn body(partition: &PagePartition, page_no: u32, len: usize) -> Vec<u8> {
+        let page = partition.pool().get_page(page_id(page_no)).await.unwrap();
+        page[BODY..BODY + len].to_vec()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let update = PageUpdate { xid: 7, page_id: page_id(3), offset: BODY as u16, before: vec![1, 2, 3], after: vec![4, 5, 6] };
+        assert_eq!(PageUpdate::decode(&update.to_payload()), Some(update.clone()));
+        let clr = Compensation { xid: 7, undone: Lsn(96), page_id: page_id(3), offset: BODY as u16, image: vec![1, 2, 3] };
+        assert_eq!(Compensation::decode(&clr.to_payload()), Some(clr));
+        assert_eq!(AbortRecord::decode(&AbortRecord { xid: 7 }.to_payload()), Some(AbortRecord { xid: 7 }));
+
+        // Cut short, or reaching into the page header
+        let payload = update.to_payload();
+        assert_eq!(PageUpdate::decode(&payload[..payload.len() - 1]), None);
+        let header = PageUpdate { offset: 0, ..update };
+        assert_eq!(PageUpdate::decode(&header.to_payload()), None);
+    }
+
+    #[test]
+    fn abort_takes_back_every_change_newest_first() {
+        let config = scratch_config("abort");
+        tokio_uring::start(async {
+            let partition = partition(&config);
+            partition.pool().store().allocate_extent(1, 1, 2).await.unwrap();
+            let mut txn = UndoLog::new(1, 9);
+            partition.update_page(&mut txn, page_id(0), BODY, vec![1; 4]).await.unwrap();
+            partition.update_page(&mut txn, page_id(0), BODY + 2, vec![2; 4]).await.unwrap();
+            partition.update_page(&mut txn, page_id(1), BODY, vec![3; 4]).await.unwrap();
+            assert_eq!(body(&partition, 0, 6).await, [1, 1, 2, 2, 2, 2]);
+            assert_eq!(txn.entries().len(), 3);
+
+            partition.abort(&mut txn).await.unwrap();
+            assert!(txn.entries().is_empty());
+            assert_eq!(body(&partition, 0, 6).await, [0; 6]);
+            assert_eq!(body(&partition, 1, 4).await, [0; 4]);
+        });
+    }
+
+    #[test]
+    fn recovery_rolls_back_transactions_in_flight() {
+        let config = scratch_config("losers");
+        tokio_uring::start(async {
+            let partition = partition(&config);
+            let storage = Rc::clone(partition.pool().store());
+            storage.allocate_extent(1, 1, 3).await.unwrap();
+            let mut committed = UndoLog::new(1, 1);
+            partition.update_page(&mut committed, page_id(0), BODY, vec![1; 4]).await.unwrap();
+            storage.append_record(1, &CommitRecord { xid: 1, commit_ts: CommitTimestamp::now() }).await.unwrap();
+            let mut aborted = UndoLog::new(1, 2);
+            partition.update_page(&mut aborted, page_id(1), BODY, vec![2; 4]).await.unwrap();
+            partition.abort(&mut aborted).await.unwrap();
+            let mut in_flight = UndoLog::new(1, 3);
+            partition.update_page(&mut in_flight, page_id(2), BODY, vec![3; 4]).await.unwrap();
+            partition.update_page(&mut in_flight, page_id(0), BODY + 4, vec![3; 4]).await.unwrap();
+            storage.flush_wal(1).await.unwrap();
+            // Crash: no page was written back.
+            drop((partition, storage));
+
+            let plans = vec![wal_redo::plan(&config.wal_dir, 1, Lsn(0), None).unwrap()];
+            let partition = self::partition(&config);
+            let summary = wal_redo::redo(&partition, &plans, &HashMap::new(), &WalRegistry::new(), false).await.unwrap();
+            assert_eq!((summary.losers, summary.undone), (1, 2));
+            assert_eq!(body(&partition, 0, 8).await, [1, 1, 1, 1, 0, 0, 0, 0]);
+            assert_eq!(body(&partition, 1, 4).await, [0; 4]);
+            assert_eq!(body(&partition, 2, 4).await, [0; 4]);
+            partition.pool().store().flush_wal(1).await.unwrap();
+
+            // A crash during recovery: the CLRs show every change already compensated.
+            let partition = self::partition(&config);
+            let summary = wal_redo::redo(&partition, &plans, &HashMap::new(), &WalRegistry::new(), false).await.unwrap();
+            assert_eq!((summary.losers, summary.undone), (1, 0));
+            assert_eq!(body(&partition, 0, 8).await, [1, 1, 1, 1, 0, 0, 0, 0]);
+            assert_eq!(body(&partition, 2, 4).await, [0; 4]);
+        });
+    }
+}
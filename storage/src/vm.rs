@@ -0,0 +1,220 @@
+use crate::buffer_pool::BufferPool;
+use crate::fsm;
+use crate::page::{self, PAGE_HEADER_SIZE};
+use crate::traits::{PageId, PageStore, StorageError, WalStore};
+use crate::wal_record::WalRecordType;
+use crate::wal_registry::{PageRecord, WalRecord, WalRegistry};
+
+// -----------------------------------------------------------------------------
+// Visibility Map
+//
+// Two bits per data page of a heap space: `ALL_VISIBLE`, every tuple on the page is
+// visible to every transaction, and `ALL_FROZEN`, every tuple is frozen too. An
+// index-only scan trusts an all-visible page without reading it, and vacuum skips
+// the pages the bits say need nothing. The map's pages sit second in each group of
+// the space, after the free space map's (see `fsm.rs`), covering the same data pages:
+//
+//   [32..)    bits          two per data page covered, lowest first in each byte
+//
+// A map page never formatted reads as all zeros, which is every page of its group
+// not all-visible, so it is formatted only when a bit is first set.
+//
+// Bits are set by whoever can vouch for a page, such as vacuum, through the heap
+// (`HeapFile::set_all_visible`), and cleared by the heap before it changes the page.
+// A bit set wrongly would have scans skip tuples, so both go through the log, and a
+// clear is logged before the change it is for: a crash between the two leaves the
+// bit clear, never set over a changed page.
+//
+//   VM_INIT   page                         map page formatted, every bit clear
+//   VM_SET    page | index u16 | bits u8   bits of one data page
+//
+// (page = db_id u32 | space_id u32 | page_no u32.)
+// -----------------------------------------------------------------------------
+
+/// Page type of visibility map pages.
+pub const PAGE_TYPE_VM: u16 = 6;
+
+/// Every tuple on the page is visible to every transaction.
+pub const ALL_VISIBLE: u8 = 1;
+/// Every tuple on the page is frozen. Only set along with `ALL_VISIBLE`.
+pub const ALL_FROZEN: u8 = 2;
+
+const BITS_OFFSET: usize = 32;
+
+/// Registers the map's record types, so recovery can replay them.
+pub fn register(registry: &mut WalRegistry) {
+    registry.register_page::<VmInit>("vm");
+    registry.register_page::<VmSet>("vm");
+}
+
+/// The visibility maps of database `db_id`'s heap spaces, over `pool`.
+pub struct VisibilityMap<'a, S: PageStore + WalStore> {
+    pool: &'a BufferPool<S>,
+    db_id: u32,
+}
+
+impl<'a, S: PageStore + WalStore> VisibilityMap<'a, S> {
+    pub fn new(pool: &'a BufferPool<S>, db_id: u32) -> Self {
+        Self { pool, db_id }
+    }
+
+    /// The bits of data page `page_no` of `space_id`.
+    pub async fn get(&self, space_id: u32, page_no: u32) -> Result<u8, StorageError> {
+        let (map_no, index) = locate(page_no);
+        let map = self.pool.get_page(self.page(space_id, map_no)).await?;
+        if page::page_type(&map) != PAGE_TYPE_VM {
+            return Ok(0);
+        }
+        Ok(bits(&map, index))
+    }
+
+    /// Whether data page `page_no` of `space_id` is all-visible.
+    pub async fn is_all_visible(&self, space_id: u32, page_no: u32) -> Result<bool, StorageError> {
+        Ok(self.get(space_id, page_no).await? & ALL_VISIBLE != 0)
+    }
+
+    /// Sets `bits` of data page `page_no` of `space_id`, on top of those it has.
+    pub async fn set(&self, space_id: u32, page_no: u32, bits: u8) -> Result<(), StorageError> {
+        let (map_no, index) = locate(page_no);
+        let mut map = self.pool.get_page_mut(self.page(space_id, map_no)).await?;
+        let page_id = map.page_id();
+        if page::page_type(&map) != PAGE_TYPE_VM {
+            map.apply(&VmInit { page_id }).await?;
+        }
+        let old = self::bits(&map, index);
+        if old | bits != old {
+            map.apply(&VmSet { page_id, index: index as u16, bits: old | bits }).await?;
+        }
+        Ok(())
+    }
+
+    /// Clears every bit of data page `page_no` of `space_id`.
+    pub async fn clear(&self, space_id: u32, page_no: u32) -> Result<(), StorageError> {
+        let (map_no, index) = locate(page_no);
+        let mut map = self.pool.get_page_mut(self.page(space_id, map_no)).await?;
+        if page::page_type(&map) != PAGE_TYPE_VM || bits(&map, index) == 0 {
+            return Ok(());
+        }
+        let page_id = map.page_id();
+        map.apply(&VmSet { page_id, index: index as u16, bits: 0 }).await?;
+        Ok(())
+    }
+
+    fn page(&self, space_id: u32, page_no: u32) -> PageId {
+        PageId {
+            db_id: self.db_id,
+            space_id,
+            page_no,
+        }
+    }
+}
+
+// Map page covering data page `page_no`, and its entry there
+fn locate(page_no: u32) -> (u32, usize) {
+    let map_no = fsm::group_start(page_no) + 1;
+    (map_no, (page_no - map_no - 1) as usize)
+}
+
+fn bits(map: &[u8], index: usize) -> u8 {
+    map[BITS_OFFSET + index / 4] >> (index % 4 * 2) & 0b11
+}
+
+// -----------------------------------------------------------------------------
+// WAL records
+// -----------------------------------------------------------------------------
+
+const PAGE_ID_SIZE: usize = 12;
+
+fn encode_page_id(out: &mut Vec<u8>, page_id: PageId) {
+    for part in [page_id.db_id, page_id.space_id, page_id.page_no] {
+        out.extend_from_slice(&part.to_le_bytes());
+    }
+}
+
+fn decode_page_id(payload: &[u8]) -> Option<PageId> {
+    let field = |at: usize| Some(u32::from_le_bytes(payload.get(at..at + 4)?.try_into().unwrap()));
+    Some(PageId {
+        db_id: field(0)?,
+        space_id: field(4)?,
+        page_no: field(8)?,
+    })
+}
+
+/// A map page formatted, every bit clear.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmInit {
+    pub page_id: PageId,
+}
+
+impl WalRecord for VmInit {
+    const TYPE: WalRecordType = WalRecordType::VM_INIT;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE {
+            return None;
+        }
+        Some(Self { page_id: decode_page_id(payload)? })
+    }
+}
+
+impl PageRecord for VmInit {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        page[PAGE_HEADER_SIZE..].fill(0);
+        page::set_page_type(page, PAGE_TYPE_VM);
+    }
+}
+
+/// The bits of the data page at `index` of a map page, all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSet {
+    pub page_id: PageId,
+    pub index: u16,
+    pub bits: u8,
+}
+
+impl WalRecord for VmSet {
+    const TYPE: WalRecordType = WalRecordType::VM_SET;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_page_id(out, self.page_id);
+        out.extend_from_slice(&self.index.to_le_bytes());
+        out.push(self.bits);
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != PAGE_ID_SIZE + 3 {
+            return None;
+        }
+        let index = u16::from_le_bytes(payload[PAGE_ID_SIZE..PAGE_ID_SIZE + 2].try_into().unwrap());
+        let bits = payload[PAGE_ID_SIZE + 2];
+        if index as u32 >= fsm::PAGES_PER_MAP_PAGE || bits > ALL_VISIBLE | ALL_FROZEN {
+            return None;
+        }
+        Some(Self {
+            page_id: decode_page_id(payload)?,
+            index,
+            bits,
+        })
+    }
+}
+
+impl PageRecord for VmSet {
+    fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    fn redo(&self, page: &mut [u8]) {
+        let index = self.index as usize;
+        let byte = &mut page[BITS_OFFSET + index / 4];
+        let shift = index % 4 * 2;
+        *byte = *byte & !(0b11 << shift) | self.bits << shift;
+    }
+}
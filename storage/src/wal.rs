@@ -0,0 +1,431 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::channel::oneshot;
+use futures::lock::Mutex;
+
+use crate::stats::WalCounters;
+use crate::traits::{AlignedBuf, Lsn, StorageError};
+use crate::wal_compress;
+use crate::wal_record::{self, WalRecordType};
+
+// -----------------------------------------------------------------------------
+// WAL Segments
+//
+// Each database's log is one logical byte stream; an LSN is a byte offset into it.
+// The stream is cut into fixed-size segment files named by segment number:
+//
+//   wal_dir/db_<id>/<seg_no as 16 hex digits>.wal
+//
+// so LSN -> (segment, offset) is pure arithmetic and the tail LSN can be rebuilt
+// from the directory listing after a restart.
+//
+// With `WalLayout::PerCore` every core appends to its own stream, rooted at
+//
+//   wal_dir/core_<id>/db_<id>/...
+//
+// and the top 8 bits of the LSNs of that stream hold the core id, so an LSN names
+// both the stream and the byte in it. The per-database layout is core 0's encoding.
+// -----------------------------------------------------------------------------
+
+/// Bits of an LSN above the stream offset, holding the core id of per-core streams.
+pub const LSN_CORE_SHIFT: u32 = 56;
+pub const LSN_OFFSET_MASK: u64 = (1 << LSN_CORE_SHIFT) - 1;
+
+/// Whether each database has one WAL, or one per core.
+///
+/// A single stream funnels every commit's fsync on a database through one file. Per-core
+/// streams let each core commit independently; recovery merges them by each record's
+/// global sequence number (see `wal_merge.rs`). Records that touch the same page must
+/// come from the same core (true when pages are owned by cores); a transaction spanning
+/// cores must flush each core's stream before it is acknowledged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalLayout {
+    #[default]
+    PerDatabase,
+    PerCore,
+}
+
+/// The first LSN of a core's stream.
+pub fn stream_origin(core_id: u16) -> Lsn {
+    Lsn((core_id as u64) << LSN_CORE_SHIFT)
+}
+
+/// The core whose stream an LSN belongs to (0 in the per-database layout).
+pub fn lsn_core(lsn: Lsn) -> u16 {
+    (lsn.0 >> LSN_CORE_SHIFT) as u16
+}
+
+/// e.g., /wal_dir/core_3
+pub fn core_wal_dir(wal_dir: &Path, core_id: u16) -> PathBuf {
+    wal_dir.join(format!("core_{}", core_id))
+}
+
+/// Every stream root under `wal_dir` with the origin of its LSNs: `wal_dir` itself for
+/// per-database logs, then each per-core root.
+pub fn wal_roots(wal_dir: &Path) -> Result<Vec<(PathBuf, Lsn)>, StorageError> {
+    let mut roots = vec![(wal_dir.to_path_buf(), Lsn(0))];
+    let entries = match fs::read_dir(wal_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(roots),
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+    for entry in entries {
+        let entry = entry.map_err(StorageError::Io)?;
+        if let Some(core_id) = entry.file_name().to_str().and_then(|n| n.strip_prefix("core_")?.parse().ok()) {
+            roots.push((entry.path(), stream_origin(core_id)));
+        }
+    }
+    roots[1..].sort_unstable_by_key(|(_, origin)| *origin);
+    Ok(roots)
+}
+
+/// 16MiB per WAL segment file.
+pub const WAL_SEGMENT_SIZE: u64 = 16 << 20;
+
+/// Unit in which appends are staged and written.
+pub const WAL_BLOCK_SIZE: usize = 4096;
+
+/// Size of the per-database staging buffer; a full buffer is written with one ring op.
+pub const WAL_STAGING_SIZE: usize = 16 * WAL_BLOCK_SIZE;
+
+/// Maps an LSN to its segment number and the byte offset inside that segment, within
+/// the LSN's stream.
+pub fn locate(lsn: Lsn) -> (u64, u64) {
+    let offset = lsn.0 & LSN_OFFSET_MASK;
+    (offset / WAL_SEGMENT_SIZE, offset % WAL_SEGMENT_SIZE)
+}
+
+/// First LSN of segment `seg_no` of the stream starting at `origin`.
+pub fn segment_start(origin: Lsn, seg_no: u64) -> Lsn {
+    Lsn(origin.0 + seg_no * WAL_SEGMENT_SIZE)
+}
+
+/// e.g., /wal_dir/db_10/00000000000000A3.wal
+pub fn wal_segment_path(wal_dir: &Path, db_id: u32, seg_no: u64) -> PathBuf {
+    wal_dir.join(format!("db_{}", db_id)).join(format!("{:016X}.wal", seg_no))
+}
+
+/// Accepts both plain (`.wal`) and compressed (`.walz`) segment files.
+pub fn parse_wal_segment_file_name(name: &str) -> Option<u64> {
+    let hex = name.strip_suffix(".wal").or_else(|| name.strip_suffix(".walz"))?;
+    (hex.len() == 16).then(|| u64::from_str_radix(hex, 16).ok()).flatten()
+}
+
+/// Lists a database's WAL segments, ordered by segment number. A segment caught mid-
+/// compression has both files; the plain one is listed, since it is still complete.
+pub fn wal_segments(wal_dir: &Path, db_id: u32) -> Result<Vec<(u64, PathBuf)>, StorageError> {
+    let dir = wal_dir.join(format!("db_{}", db_id));
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+
+    let mut segments = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(StorageError::Io)?;
+        if let Some(seg_no) = entry.file_name().to_str().and_then(parse_wal_segment_file_name) {
+            segments.push((seg_no, entry.path()));
+        }
+    }
+    segments.sort_unstable_by_key(|(seg_no, path)| (*seg_no, wal_compress::is_compressed_path(path)));
+    segments.dedup_by_key(|(seg_no, _)| *seg_no);
+    Ok(segments)
+}
+
+/// Databases with a WAL directory under `wal_dir`.
+pub fn wal_databases(wal_dir: &Path) -> Result<Vec<u32>, StorageError> {
+    let entries = match fs::read_dir(wal_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+    let mut dbs = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(StorageError::Io)?;
+        if let Some(db_id) = entry.file_name().to_str().and_then(|n| n.strip_prefix("db_")?.parse().ok()) {
+            dbs.push(db_id);
+        }
+    }
+    dbs.sort_unstable();
+    Ok(dbs)
+}
+
+/// A staged run of log bytes that is ready to be written at `start`. In block-aligned
+/// mode it may begin with bytes of a partial block that were already written once.
+pub struct SealedBlock {
+    pub start: Lsn,
+    pub buf: AlignedBuf,
+    pub len: usize,
+}
+
+/// Per-database WAL state on one core.
+///
+/// Appends copy into `staging` synchronously, so LSN assignment and byte order never
+/// depend on how tasks interleave across awaits. A full (or flushed) staging buffer is
+/// sealed onto a FIFO queue; whoever holds `write_lock` drains the queue in order, so
+/// a flush that drains also writes every block sealed before it.
+pub struct WalStream {
+    staging: RefCell<AlignedBuf>,
+    staged_start: Cell<u64>,
+    staged_len: Cell<usize>,
+    // Block-aligned mode: bytes at the front of `staging` carried over from the last
+    // sealed buffer's partial block, already queued for writing once
+    block_aligned: bool,
+    carried: Cell<usize>,
+    // Per-core streams: the last global sequence number stamped on a record
+    gsn_clock: Option<Cell<u64>>,
+    sealed: RefCell<VecDeque<SealedBlock>>,
+    pub write_lock: Mutex<()>,
+    // Group commit: one flusher at a time; queued flushers usually find themselves covered
+    pub sync_lock: Mutex<()>,
+    flushers: Cell<usize>,
+    // What the flush in flight will make durable, and flushers waiting on it
+    in_flight: Cell<Option<u64>>,
+    waiters: RefCell<Vec<(u64, oneshot::Sender<()>)>>,
+    written: Cell<u64>,
+    flushed: Cell<u64>,
+    // Segments written since the last fdatasync
+    unsynced: RefCell<BTreeSet<u64>>,
+    counters: WalCounters,
+}
+
+impl WalStream {
+    pub fn new(tail: Lsn) -> Self {
+        Self::with_staging(tail, tail, 0, false)
+    }
+
+    /// A stream whose sealed blocks always start on a `WAL_BLOCK_SIZE` boundary: a
+    /// partially filled last block is carried into the next staging buffer and written
+    /// again, whole, as it fills. Needed when blocks can't be patched in place (encrypted
+    /// blocks) or can only be written whole (O_DIRECT). `tail_block` is the part of the block ending at `tail` already on disk.
+    pub fn block_aligned(tail: Lsn, tail_block: &[u8]) -> Self {
+        assert_eq!(tail.0 % WAL_BLOCK_SIZE as u64, tail_block.len() as u64, "tail block does not end at the tail");
+        let stream = Self::with_staging(tail, Lsn(tail.0 - tail_block.len() as u64), tail_block.len(), true);
+        stream.staging.borrow_mut()[..tail_block.len()].copy_from_slice(tail_block);
+        stream
+    }
+
+    /// Stamps every record with a global sequence number, for a per-core stream. GSNs are
+    /// a hybrid logical clock: the wall clock in microseconds, but always above both the
+    /// stream's previous GSN (`last_gsn`, recovered from the log) and any GSN observed
+    /// from another core, so records merge in an order consistent with causality.
+    pub fn sequenced(mut self, last_gsn: u64) -> Self {
+        self.gsn_clock = Some(Cell::new(last_gsn));
+        self
+    }
+
+    fn with_staging(tail: Lsn, staged_start: Lsn, carried: usize, block_aligned: bool) -> Self {
+        Self {
+            staging: RefCell::new(AlignedBuf::new(WAL_STAGING_SIZE)),
+            staged_start: Cell::new(staged_start.0),
+            staged_len: Cell::new(carried),
+            block_aligned,
+            carried: Cell::new(carried),
+            gsn_clock: None,
+            sealed: RefCell::new(VecDeque::new()),
+            write_lock: Mutex::new(()),
+            sync_lock: Mutex::new(()),
+            flushers: Cell::new(0),
+            in_flight: Cell::new(None),
+            waiters: RefCell::new(Vec::new()),
+            written: Cell::new(tail.0),
+            flushed: Cell::new(tail.0),
+            unsynced: RefCell::new(BTreeSet::new()),
+            counters: WalCounters::default(),
+        }
+    }
+
+    /// LSN the next append will get.
+    pub fn tail(&self) -> Lsn {
+        Lsn(self.staged_start.get() + self.staged_len.get() as u64)
+    }
+
+    /// Everything below this LSN has been handed to the kernel.
+    pub fn written(&self) -> Lsn {
+        Lsn(self.written.get())
+    }
+
+    /// Everything below this LSN is durable.
+    pub fn flushed(&self) -> Lsn {
+        Lsn(self.flushed.get())
+    }
+
+    /// Frames a record and stages it; returns the LSN of its header.
+    pub fn append_record(&self, record_type: WalRecordType, payload: &[u8]) -> Lsn {
+        let lsn = self.tail();
+        match &self.gsn_clock {
+            Some(clock) => {
+                let gsn = (clock.get() + 1).max(now_micros());
+                clock.set(gsn);
+                let (header, trailer) = wal_record::encode_sequenced_frame(record_type, gsn, payload);
+                for part in [&header[..], payload, &trailer[..]] {
+                    self.append(part);
+                }
+            }
+            None => {
+                let (header, trailer) = wal_record::encode_frame(record_type, payload);
+                for part in [&header[..], payload, &trailer[..]] {
+                    self.append(part);
+                }
+            }
+        }
+        self.counters.record_appended((self.tail().0 - lsn.0) as usize);
+        lsn
+    }
+
+    /// Append and fsync activity, for `CoreStorage::wal_stats`.
+    pub fn counters(&self) -> &WalCounters {
+        &self.counters
+    }
+
+    /// GSN of the last record appended, for a sequenced stream.
+    pub fn last_gsn(&self) -> Option<u64> {
+        self.gsn_clock.as_ref().map(Cell::get)
+    }
+
+    /// Moves the GSN clock past `gsn`, so records appended from now on merge after the
+    /// record (typically on another core) that carried it.
+    pub fn observe_gsn(&self, gsn: u64) {
+        if let Some(clock) = &self.gsn_clock {
+            clock.set(clock.get().max(gsn));
+        }
+    }
+
+    /// Copies raw bytes into the staging buffer and returns their start LSN. Buffers are
+    /// sealed as they fill; a sealed buffer never crosses a segment boundary.
+    fn append(&self, bytes: &[u8]) -> Lsn {
+        let lsn = self.tail();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let start = self.staged_start.get();
+            let len = self.staged_len.get();
+            let room_in_segment = WAL_SEGMENT_SIZE - start % WAL_SEGMENT_SIZE;
+            let capacity = (WAL_STAGING_SIZE as u64).min(room_in_segment) as usize;
+
+            let n = rest.len().min(capacity - len);
+            self.staging.borrow_mut()[len..len + n].copy_from_slice(&rest[..n]);
+            self.staged_len.set(len + n);
+            rest = &rest[n..];
+
+            if len + n == capacity {
+                self.seal();
+            }
+        }
+        lsn
+    }
+
+    /// Seals whatever is staged, even a partial buffer. Used by flush.
+    pub fn seal(&self) {
+        let len = self.staged_len.get();
+        if len == self.carried.get() {
+            return;
+        }
+        let start = self.staged_start.get();
+        let mut next = AlignedBuf::new(WAL_STAGING_SIZE);
+        let carry = if self.block_aligned { ((start + len as u64) % WAL_BLOCK_SIZE as u64) as usize } else { 0 };
+        next[..carry].copy_from_slice(&self.staging.borrow()[len - carry..len]);
+
+        let buf = self.staging.replace(next);
+        self.sealed.borrow_mut().push_back(SealedBlock {
+            start: Lsn(start),
+            buf,
+            len,
+        });
+        self.staged_start.set(start + (len - carry) as u64);
+        self.staged_len.set(carry);
+        self.carried.set(carry);
+    }
+
+    pub fn has_sealed(&self) -> bool {
+        !self.sealed.borrow().is_empty()
+    }
+
+    pub fn sealed_blocks(&self) -> usize {
+        self.sealed.borrow().len()
+    }
+
+    /// Next block to write. The caller must hold `write_lock`.
+    pub fn pop_sealed(&self) -> Option<SealedBlock> {
+        self.sealed.borrow_mut().pop_front()
+    }
+
+    /// Puts back a block whose write failed, so the next drain retries it first.
+    pub fn unpop_sealed(&self, block: SealedBlock) {
+        self.sealed.borrow_mut().push_front(block);
+    }
+
+    pub fn mark_written(&self, block_end: Lsn) {
+        let (seg_no, _) = locate(Lsn(block_end.0 - 1));
+        self.unsynced.borrow_mut().insert(seg_no);
+        self.written.set(block_end.0);
+    }
+
+    /// Whether anything written since the last fdatasync still needs one.
+    pub fn has_unsynced(&self) -> bool {
+        !self.unsynced.borrow().is_empty()
+    }
+
+    /// Segments that need an fdatasync before `written` is durable.
+    pub fn take_unsynced(&self) -> BTreeSet<u64> {
+        std::mem::take(&mut *self.unsynced.borrow_mut())
+    }
+
+    /// Returns segments to the unsynced set after a failed fdatasync.
+    pub fn restore_unsynced(&self, segments: BTreeSet<u64>) {
+        self.unsynced.borrow_mut().extend(segments);
+    }
+
+    /// Flushers currently waiting on or holding `sync_lock`.
+    pub fn flushers(&self) -> usize {
+        self.flushers.get()
+    }
+
+    pub fn enter_flush(&self) {
+        self.flushers.set(self.flushers.get() + 1);
+    }
+
+    pub fn exit_flush(&self) {
+        self.flushers.set(self.flushers.get() - 1);
+    }
+
+    pub fn mark_flushed(&self, lsn: Lsn) {
+        self.flushed.set(self.flushed.get().max(lsn.0));
+    }
+
+    /// Announces that the flush leader will make everything below `covers` durable.
+    pub fn begin_flush(&self, covers: Lsn) {
+        self.in_flight.set(Some(covers.0));
+    }
+
+    /// If the flush in flight covers `target`, a receiver that fires when it finishes.
+    pub fn wait_for_flush(&self, target: Lsn) -> Option<oneshot::Receiver<()>> {
+        let covers = self.in_flight.get()?;
+        if target.0 > covers {
+            return None;
+        }
+        let (tx, rx) = oneshot::channel();
+        self.waiters.borrow_mut().push((target.0, tx));
+        Some(rx)
+    }
+
+    /// Ends the flush in flight, successful or not. Waiters whose target is now durable
+    /// are signalled; the rest see their sender dropped and go flush for themselves.
+    pub fn finish_flush(&self) {
+        self.in_flight.set(None);
+        let flushed = self.flushed.get();
+        for (target, tx) in self.waiters.borrow_mut().drain(..) {
+            if target <= flushed {
+                let _ = tx.send(());
+            }
+        }
+    }
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+}
@@ -0,0 +1,142 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+use crate::traits::StorageError;
+
+// -----------------------------------------------------------------------------
+// WAL Archiving
+//
+// Archive state is kept as marker files next to the segments, so it survives restarts:
+//
+//   wal_dir/db_<id>/archive_status/<seg>.ready   segment complete, not yet archived
+//   wal_dir/db_<id>/archive_status/<seg>.done    archiver succeeded
+//
+// A segment without a `.done` marker is never recycled while archiving is configured.
+// -----------------------------------------------------------------------------
+
+/// Receives every completed WAL segment before it becomes eligible for recycling.
+///
+/// Called on the archiver thread, never on a core. It may be called again for a segment
+/// it already archived (after a crash between archiving and recording `.done`), so it
+/// must be idempotent. Returning an error leaves the segment pending; it is retried the
+/// next time a segment completes.
+pub trait WalArchiver: Send + Sync {
+    fn archive(&self, db_id: u32, seg_no: u64, segment: &Path) -> io::Result<()>;
+}
+
+/// Copies segments into `dest/db_<id>/`, fsyncing the copy before reporting success.
+pub struct DirectoryArchiver {
+    pub dest: PathBuf,
+}
+
+impl WalArchiver for DirectoryArchiver {
+    fn archive(&self, db_id: u32, _seg_no: u64, segment: &Path) -> io::Result<()> {
+        let dir = self.dest.join(format!("db_{}", db_id));
+        fs::create_dir_all(&dir)?;
+        let name = segment.file_name().expect("segment path has a file name");
+        let target = dir.join(name);
+        let tmp = target.with_extension("wal.tmp");
+
+        fs::copy(segment, &tmp)?;
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &target)?;
+        fs::File::open(&dir)?.sync_all()
+    }
+}
+
+fn status_dir(wal_dir: &Path, db_id: u32) -> PathBuf {
+    wal_dir.join(format!("db_{}", db_id)).join("archive_status")
+}
+
+fn marker(wal_dir: &Path, db_id: u32, seg_no: u64, state: &str) -> PathBuf {
+    status_dir(wal_dir, db_id).join(format!("{:016X}.{}", seg_no, state))
+}
+
+/// Records that a segment is complete and waiting for the archiver.
+pub fn mark_ready(wal_dir: &Path, db_id: u32, seg_no: u64) -> Result<(), StorageError> {
+    if is_archived(wal_dir, db_id, seg_no) {
+        return Ok(());
+    }
+    fs::create_dir_all(status_dir(wal_dir, db_id)).map_err(StorageError::Io)?;
+    fs::File::create(marker(wal_dir, db_id, seg_no, "ready")).map_err(StorageError::Io)?;
+    Ok(())
+}
+
+pub fn is_archived(wal_dir: &Path, db_id: u32, seg_no: u64) -> bool {
+    marker(wal_dir, db_id, seg_no, "done").exists()
+}
+
+/// Segments marked ready but not yet archived, oldest first.
+pub fn pending(wal_dir: &Path, db_id: u32) -> Result<Vec<u64>, StorageError> {
+    let entries = match fs::read_dir(status_dir(wal_dir, db_id)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+    let mut segments = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(StorageError::Io)?;
+        let name = entry.file_name();
+        let Some(hex) = name.to_str().and_then(|n| n.strip_suffix(".ready")) else {
+            continue;
+        };
+        if let Ok(seg_no) = u64::from_str_radix(hex, 16) {
+            segments.push(seg_no);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Forgets the markers of a segment that has been recycled.
+pub fn clear(wal_dir: &Path, db_id: u32, seg_no: u64) {
+    let _ = fs::remove_file(marker(wal_dir, db_id, seg_no, "ready"));
+    let _ = fs::remove_file(marker(wal_dir, db_id, seg_no, "done"));
+}
+
+/// Background thread running the configured `WalArchiver`. `notify(db_id)` asks it to
+/// archive everything pending for that database; requests are cheap and coalesce.
+pub struct ArchiveWorker {
+    tx: mpsc::Sender<u32>,
+}
+
+impl ArchiveWorker {
+    pub fn start(wal_dir: PathBuf, archiver: Arc<dyn WalArchiver>, core_id: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<u32>();
+        thread::Builder::new()
+            .name(format!("wal-archiver-{}", core_id))
+            .spawn(move || {
+                // Exits once the owning CoreStorage drops the sender.
+                for db_id in rx {
+                    archive_pending(&wal_dir, db_id, archiver.as_ref());
+                }
+            })
+            .expect("failed to spawn WAL archiver thread");
+        Self { tx }
+    }
+
+    pub fn notify(&self, db_id: u32) {
+        let _ = self.tx.send(db_id);
+    }
+}
+
+fn archive_pending(wal_dir: &Path, db_id: u32, archiver: &dyn WalArchiver) {
+    let Ok(segments) = pending(wal_dir, db_id) else {
+        return;
+    };
+    // In order: stop at the first failure so the archive never has gaps.
+    for seg_no in segments {
+        let path = crate::wal::wal_segment_path(wal_dir, db_id, seg_no);
+        if archiver.archive(db_id, seg_no, &path).is_err() {
+            return;
+        }
+        let ready = marker(wal_dir, db_id, seg_no, "ready");
+        if fs::rename(&ready, marker(wal_dir, db_id, seg_no, "done")).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::checksum;
+use crate::commit_ts::CommitTimestamp;
+use crate::traits::{Lsn, PageId, StorageError};
+use crate::wal_record::WalRecordType;
+use crate::wal_recovery;
+use crate::wal_registry::WalRecord;
+
+// -----------------------------------------------------------------------------
+// Checkpoints
+//
+// A fuzzy checkpoint writes two records. CHECKPOINT_BEGIN marks the point from which
+// the snapshot was taken; CHECKPOINT_END, written after it, carries the snapshot:
+//
+//   [0..8)     LSN of the begin record
+//   [8..12)    dirty page count (u32 LE), then per page:
+//                db_id u32 | space_id u32 | page_no u32 | rec_lsn u64
+//   [..+4)     active transaction count (u32 LE), then per transaction:
+//                xid u64 | first_lsn u64
+//
+// where a page's rec_lsn is the oldest change not yet on disk, and a transaction's
+// first_lsn is its first record. Once the end record is durable, its LSN goes in
+//
+//   wal_dir/db_<id>/checkpoint        LSN (u64 LE) | CRC32C (u32 LE)
+//
+// and recovery reads that record first: redo starts at the oldest rec_lsn (or at the
+// begin record, if nothing was dirty), instead of at the start of the log.
+// -----------------------------------------------------------------------------
+
+const CHECKPOINT_FILE: &str = "checkpoint";
+
+const DIRTY_PAGE_SIZE: usize = 20;
+const ACTIVE_TXN_SIZE: usize = 16;
+
+/// Starts a checkpoint; its payload is the wall-clock time, for diagnostics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckpointBegin {
+    pub started_at: CommitTimestamp,
+}
+
+impl WalRecord for CheckpointBegin {
+    const TYPE: WalRecordType = WalRecordType::CHECKPOINT_BEGIN;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.started_at.0.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        Some(Self {
+            started_at: CommitTimestamp(u64::from_le_bytes(payload.try_into().ok()?)),
+        })
+    }
+}
+
+/// A page with changes not yet written back, and the LSN of the oldest one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyPage {
+    pub page_id: PageId,
+    pub rec_lsn: Lsn,
+}
+
+/// A transaction in progress at the checkpoint, and the LSN of its first record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveTxn {
+    pub xid: u64,
+    pub first_lsn: Lsn,
+}
+
+/// Completes a checkpoint with the dirty page table and active transactions, both
+/// snapshotted after the begin record was appended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointEnd {
+    pub begin_lsn: Lsn,
+    pub dirty_pages: Vec<DirtyPage>,
+    pub active_txns: Vec<ActiveTxn>,
+}
+
+impl CheckpointEnd {
+    /// Where redo starts: the oldest change possibly missing from disk. Changes older
+    /// than the begin record not in the dirty page table were written back before it.
+    pub fn redo_lsn(&self) -> Lsn {
+        self.dirty_pages.iter().map(|page| page.rec_lsn).fold(self.begin_lsn, Lsn::min)
+    }
+
+    /// The oldest record recovery can still need: the redo start, or the first record of
+    /// a transaction that may have to be rolled back. The log before it can be truncated.
+    pub fn oldest_needed_lsn(&self) -> Lsn {
+        self.active_txns.iter().map(|txn| txn.first_lsn).fold(self.redo_lsn(), Lsn::min)
+    }
+}
+
+impl WalRecord for CheckpointEnd {
+    const TYPE: WalRecordType = WalRecordType::CHECKPOINT_END;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.reserve(16 + self.dirty_pages.len() * DIRTY_PAGE_SIZE + self.active_txns.len() * ACTIVE_TXN_SIZE);
+        out.extend_from_slice(&self.begin_lsn.0.to_le_bytes());
+        out.extend_from_slice(&(self.dirty_pages.len() as u32).to_le_bytes());
+        for page in &self.dirty_pages {
+            out.extend_from_slice(&page.page_id.db_id.to_le_bytes());
+            out.extend_from_slice(&page.page_id.space_id.to_le_bytes());
+            out.extend_from_slice(&page.page_id.page_no.to_le_bytes());
+            out.extend_from_slice(&page.rec_lsn.0.to_le_bytes());
+        }
+        out.extend_from_slice(&(self.active_txns.len() as u32).to_le_bytes());
+        for txn in &self.active_txns {
+            out.extend_from_slice(&txn.xid.to_le_bytes());
+            out.extend_from_slice(&txn.first_lsn.0.to_le_bytes());
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let mut rest = payload;
+        let begin_lsn = Lsn(take_u64(&mut rest)?);
+
+        let pages = take_u32(&mut rest)? as usize;
+        if rest.len() < pages.checked_mul(DIRTY_PAGE_SIZE)? {
+            return None;
+        }
+        let mut dirty_pages = Vec::with_capacity(pages);
+        for _ in 0..pages {
+            let page_id = PageId {
+                db_id: take_u32(&mut rest)?,
+                space_id: take_u32(&mut rest)?,
+                page_no: take_u32(&mut rest)?,
+            };
+            dirty_pages.push(DirtyPage { page_id, rec_lsn: Lsn(take_u64(&mut rest)?) });
+        }
+
+        let txns = take_u32(&mut rest)? as usize;
+        if rest.len() != txns.checked_mul(ACTIVE_TXN_SIZE)? {
+            return None;
+        }
+        let mut active_txns = Vec::with_capacity(txns);
+        for _ in 0..txns {
+            active_txns.push(ActiveTxn {
+                xid: take_u64(&mut rest)?,
+                first_lsn: Lsn(take_u64(&mut rest)?),
+            });
+        }
+        Some(Self { begin_lsn, dirty_pages, active_txns })
+    }
+}
+
+fn take_u32(rest: &mut &[u8]) -> Option<u32> {
+    let (head, tail) = rest.split_first_chunk::<4>()?;
+    *rest = tail;
+    Some(u32::from_le_bytes(*head))
+}
+
+fn take_u64(rest: &mut &[u8]) -> Option<u64> {
+    let (head, tail) = rest.split_first_chunk::<8>()?;
+    *rest = tail;
+    Some(u64::from_le_bytes(*head))
+}
+
+fn checkpoint_path(wal_dir: &Path, db_id: u32) -> PathBuf {
+    wal_dir.join(format!("db_{}", db_id)).join(CHECKPOINT_FILE)
+}
+
+/// LSN of the database's last completed checkpoint's end record, if it has one.
+pub fn last_checkpoint(wal_dir: &Path, db_id: u32) -> Result<Option<Lsn>, StorageError> {
+    let path = checkpoint_path(wal_dir, db_id);
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+    if bytes.len() != 12 || checksum::crc32c(&bytes[..8]) != u32::from_le_bytes(bytes[8..12].try_into().unwrap()) {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("corrupt WAL checkpoint file {}", path.display()),
+        )));
+    }
+    Ok(Some(Lsn(u64::from_le_bytes(bytes[..8].try_into().unwrap()))))
+}
+
+/// Durably records `end_lsn`, the LSN of a durable CHECKPOINT_END record, as the
+/// database's last checkpoint.
+pub fn set_last_checkpoint(wal_dir: &Path, db_id: u32, end_lsn: Lsn) -> Result<(), StorageError> {
+    let path = checkpoint_path(wal_dir, db_id);
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir).map_err(StorageError::Io)?;
+
+    let mut bytes = [0u8; 12];
+    bytes[..8].copy_from_slice(&end_lsn.0.to_le_bytes());
+    let crc = checksum::crc32c(&bytes[..8]);
+    bytes[8..].copy_from_slice(&crc.to_le_bytes());
+    wal_recovery::write_durably(&path, &bytes)?;
+    fs::File::open(dir).and_then(|d| d.sync_all()).map_err(StorageError::Io)
+}
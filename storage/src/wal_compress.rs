@@ -0,0 +1,177 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::checksum;
+use crate::traits::StorageError;
+use crate::wal;
+use crate::wal_archive;
+use crate::wal_crypt;
+
+// -----------------------------------------------------------------------------
+// Compressed WAL Segments
+//
+// A segment the durable tail has moved past is rewritten as a sibling file
+//
+//   wal_dir/db_<id>/<seg_no>.walz
+//
+//   [0..4)     magic "CWZ1"
+//   [4]        codec (1 = LZ4, 2 = zstd)
+//   [5..8)     reserved
+//   [8..16)    uncompressed length (u64 LE)
+//   [16..20)   CRC32C of the compressed body
+//   [20..)     compressed body
+//
+// and the `.wal` file is removed. LSNs are untouched: a compressed segment still
+// covers the same LSN range, the reader just expands it in memory first.
+// -----------------------------------------------------------------------------
+
+const MAGIC: [u8; 4] = *b"CWZ1";
+const HEADER_SIZE: usize = 20;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+
+/// Whether completed WAL segments are compressed.
+///
+/// Compression happens once per segment, on a helper thread after the segment is durable
+/// (and archived, when archiving is configured), so it never sits on the commit path. The
+/// codec choice trades that thread's CPU, and how long a segment stays full size, against
+/// the space saved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalCompression {
+    #[default]
+    Off,
+    /// Fast enough to keep up with a saturated log; typically 2-4x on WAL.
+    Lz4,
+    /// Better ratios for long retention (PITR, slow standbys) at more CPU per segment.
+    Zstd { level: i32 },
+}
+
+/// e.g., /wal_dir/db_10/00000000000000A3.walz
+pub fn compressed_segment_path(wal_dir: &Path, db_id: u32, seg_no: u64) -> PathBuf {
+    wal::wal_segment_path(wal_dir, db_id, seg_no).with_extension("walz")
+}
+
+pub fn is_compressed_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "walz")
+}
+
+/// Writes `segment`'s compressed form next to it and removes the original.
+/// Crash-safe: the compressed file is synced and renamed into place before the
+/// original goes away, and readers prefer the original while both exist.
+pub fn compress_segment(segment: &Path, codec: WalCompression) -> Result<(), StorageError> {
+    let raw = fs::read(segment).map_err(StorageError::Io)?;
+    let (codec_id, body) = match codec {
+        WalCompression::Off => return Ok(()),
+        WalCompression::Lz4 => (CODEC_LZ4, lz4_flex::block::compress(&raw)),
+        WalCompression::Zstd { level } => (CODEC_ZSTD, zstd::bulk::compress(&raw, level).map_err(StorageError::Io)?),
+    };
+
+    let mut header = [0u8; HEADER_SIZE];
+    header[0..4].copy_from_slice(&MAGIC);
+    header[4] = codec_id;
+    header[8..16].copy_from_slice(&(raw.len() as u64).to_le_bytes());
+    header[16..20].copy_from_slice(&checksum::crc32c(&body).to_le_bytes());
+
+    let target = segment.with_extension("walz");
+    let tmp = segment.with_extension("walz.tmp");
+    let mut file = fs::File::create(&tmp).map_err(StorageError::Io)?;
+    file.write_all(&header).map_err(StorageError::Io)?;
+    file.write_all(&body).map_err(StorageError::Io)?;
+    file.sync_all().map_err(StorageError::Io)?;
+    fs::rename(&tmp, &target).map_err(StorageError::Io)?;
+    if let Some(dir) = segment.parent() {
+        fs::File::open(dir).and_then(|d| d.sync_all()).map_err(StorageError::Io)?;
+    }
+    fs::remove_file(segment).map_err(StorageError::Io)
+}
+
+/// Uncompressed length recorded in a compressed segment's header.
+pub fn uncompressed_len(path: &Path) -> Result<u64, StorageError> {
+    let mut header = [0u8; HEADER_SIZE];
+    io::Read::read_exact(&mut fs::File::open(path).map_err(StorageError::Io)?, &mut header).map_err(StorageError::Io)?;
+    parse_header(&header, path).map(|(_, len)| len)
+}
+
+/// Expands a compressed segment file read into memory.
+pub fn decompress_segment(bytes: &[u8], path: &Path) -> Result<Vec<u8>, StorageError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(corrupt(path, "truncated header"));
+    }
+    let (codec_id, len) = parse_header(&bytes[..HEADER_SIZE], path)?;
+    let body = &bytes[HEADER_SIZE..];
+    let stored = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
+    if checksum::crc32c(body) != stored {
+        return Err(corrupt(path, "CRC mismatch"));
+    }
+
+    let raw = match codec_id {
+        CODEC_LZ4 => lz4_flex::block::decompress(body, len as usize).map_err(|_| corrupt(path, "bad LZ4 data"))?,
+        _ => zstd::bulk::decompress(body, len as usize).map_err(|_| corrupt(path, "bad zstd data"))?,
+    };
+    if raw.len() as u64 != len {
+        return Err(corrupt(path, "length mismatch"));
+    }
+    Ok(raw)
+}
+
+fn parse_header(header: &[u8], path: &Path) -> Result<(u8, u64), StorageError> {
+    if header[0..4] != MAGIC || !matches!(header[4], CODEC_LZ4 | CODEC_ZSTD) {
+        return Err(corrupt(path, "bad header"));
+    }
+    let len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    // Encrypted segments carry per-block trailers and are the largest kind.
+    if len > wal_crypt::ENCRYPTED_SEGMENT_SIZE {
+        return Err(corrupt(path, "implausible length"));
+    }
+    Ok((header[4], len))
+}
+
+fn corrupt(path: &Path, reason: &str) -> StorageError {
+    StorageError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("compressed WAL segment {}: {}", path.display(), reason),
+    ))
+}
+
+/// Background thread compressing completed segments. `notify(db_id, below)` asks it to
+/// compress every plain segment numbered below `below`; requests are cheap and coalesce.
+pub struct CompressWorker {
+    tx: mpsc::Sender<(u32, u64)>,
+}
+
+impl CompressWorker {
+    /// With `archiving` set, segments are left alone until their `.done` marker exists,
+    /// so the archiver always copies the plain file.
+    pub fn start(wal_dir: PathBuf, codec: WalCompression, archiving: bool, core_id: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<(u32, u64)>();
+        thread::Builder::new()
+            .name(format!("wal-compress-{}", core_id))
+            .spawn(move || {
+                // Exits once the owning CoreStorage drops the sender.
+                for (db_id, below) in rx {
+                    let Ok(segments) = wal::wal_segments(&wal_dir, db_id) else {
+                        continue;
+                    };
+                    for (seg_no, path) in segments {
+                        if seg_no >= below {
+                            break;
+                        }
+                        if is_compressed_path(&path) || (archiving && !wal_archive::is_archived(&wal_dir, db_id, seg_no)) {
+                            continue;
+                        }
+                        // A failure leaves the plain segment in place; it is retried next time.
+                        let _ = compress_segment(&path, codec);
+                    }
+                }
+            })
+            .expect("failed to spawn WAL compression thread");
+        Self { tx }
+    }
+
+    pub fn notify(&self, db_id: u32, below: u64) {
+        let _ = self.tx.send((db_id, below));
+    }
+}
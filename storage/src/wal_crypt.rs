@@ -0,0 +1,174 @@
+use std::fmt;
+
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce, Tag};
+
+use crate::wal::{WAL_BLOCK_SIZE, WAL_SEGMENT_SIZE};
+
+// -----------------------------------------------------------------------------
+// Encrypted WAL Blocks
+//
+// With encryption on, every WAL_BLOCK_SIZE block of a segment is stored as
+//
+//   [0..4096)      ciphertext of the block's first `fill` bytes, zero padded
+//   [4096..4098)   fill (u16 LE), 1..=4096; 0 means the block was never written
+//   [4098..4100)   salt (u16 LE), random per WalCipher
+//   [4100..4112)   reserved, zero
+//   [4112..4128)   AES-GCM tag
+//
+// LSNs keep counting log bytes, not file bytes: block `b` of a segment simply lives
+// at file offset `b * ENCRYPTED_BLOCK_SIZE`. The nonce is
+//
+//   global block number (u64 LE) | fill (u16 LE) | salt (u16 LE)
+//
+// where the global block number has the stream's core id in its top 16 bits, so the
+// per-core streams of a database never share a nonce under its one key.
+//
+// The tail block is rewritten as it fills, always with a larger `fill`, so a rewrite
+// never reuses a nonce; the salt separates rewrites after a restart that lost writes
+// which never became durable. The database id is authenticated as associated data, so
+// a block copied into another database's log fails to open.
+// -----------------------------------------------------------------------------
+
+pub const BLOCK_TRAILER_SIZE: usize = 32;
+pub const ENCRYPTED_BLOCK_SIZE: usize = WAL_BLOCK_SIZE + BLOCK_TRAILER_SIZE;
+
+pub const BLOCKS_PER_SEGMENT: u64 = WAL_SEGMENT_SIZE / WAL_BLOCK_SIZE as u64;
+
+/// On-disk size of a full encrypted segment.
+pub const ENCRYPTED_SEGMENT_SIZE: u64 = BLOCKS_PER_SEGMENT * ENCRYPTED_BLOCK_SIZE as u64;
+
+const FILL_OFFSET: usize = WAL_BLOCK_SIZE;
+const SALT_OFFSET: usize = FILL_OFFSET + 2;
+const TAG_OFFSET: usize = ENCRYPTED_BLOCK_SIZE - 16;
+
+/// A 256-bit AES-GCM key for one database's WAL.
+#[derive(Clone)]
+pub struct WalKey([u8; 32]);
+
+impl WalKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+// Never print key material.
+impl fmt::Debug for WalKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WalKey(..)")
+    }
+}
+
+impl Drop for WalKey {
+    fn drop(&mut self) {
+        for byte in self.0.iter_mut() {
+            // Volatile so the wipe isn't optimized away as a dead store.
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+/// File offset of the encrypted block holding segment offset `offset`.
+pub fn physical_block_offset(offset: u64) -> u64 {
+    offset / WAL_BLOCK_SIZE as u64 * ENCRYPTED_BLOCK_SIZE as u64
+}
+
+/// Why an encrypted block could not be opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// All zero: past the end of what was ever written.
+    Unwritten,
+    /// Implausible trailer or failed tag check: torn, corrupt, or tampered with.
+    Unauthenticated,
+}
+
+/// Seals and opens WAL blocks of one database.
+pub struct WalCipher {
+    aead: Aes256Gcm,
+    db_id: u32,
+    // Core id of the stream, mixed into every nonce (0 in the per-database layout)
+    stream: u16,
+    salt: u16,
+}
+
+impl WalCipher {
+    /// A cipher for the stream of core `stream` (0 for a per-database log).
+    pub fn new(key: &WalKey, db_id: u32, stream: u16) -> Self {
+        let mut salt = [0u8; 2];
+        // SAFETY: writes at most `salt.len()` bytes into `salt`.
+        let n = unsafe { libc::getrandom(salt.as_mut_ptr().cast(), salt.len(), 0) };
+        assert_eq!(n, salt.len() as isize, "getrandom failed");
+        Self {
+            aead: Aes256Gcm::new_from_slice(&key.0).expect("32-byte key"),
+            db_id,
+            stream,
+            salt: u16::from_le_bytes(salt),
+        }
+    }
+
+    /// Encrypts the first `plain.len()` bytes of block `block` of segment `seg_no` into
+    /// `out`, which must be `ENCRYPTED_BLOCK_SIZE` bytes.
+    pub fn seal_block(&self, seg_no: u64, block: u64, plain: &[u8], out: &mut [u8]) {
+        let fill = plain.len();
+        assert!(fill > 0 && fill <= WAL_BLOCK_SIZE, "bad WAL block fill");
+        out[..fill].copy_from_slice(plain);
+        out[fill..].fill(0);
+        out[FILL_OFFSET..SALT_OFFSET].copy_from_slice(&(fill as u16).to_le_bytes());
+        out[SALT_OFFSET..SALT_OFFSET + 2].copy_from_slice(&self.salt.to_le_bytes());
+
+        let nonce = self.nonce(seg_no, block, fill as u16, self.salt);
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &self.db_id.to_le_bytes(), &mut out[..fill])
+            .expect("block fits in one GCM message");
+        out[TAG_OFFSET..].copy_from_slice(&tag);
+    }
+
+    /// Authenticates and decrypts one stored block in place, returning its plaintext.
+    pub fn open_block<'a>(&self, seg_no: u64, block: u64, stored: &'a mut [u8]) -> Result<&'a [u8], BlockError> {
+        let fill = u16::from_le_bytes(stored[FILL_OFFSET..SALT_OFFSET].try_into().unwrap());
+        if fill == 0 {
+            return Err(if stored.iter().all(|&b| b == 0) { BlockError::Unwritten } else { BlockError::Unauthenticated });
+        }
+        if fill as usize > WAL_BLOCK_SIZE {
+            return Err(BlockError::Unauthenticated);
+        }
+        let salt = u16::from_le_bytes(stored[SALT_OFFSET..SALT_OFFSET + 2].try_into().unwrap());
+        let tag = *Tag::from_slice(&stored[TAG_OFFSET..]);
+
+        let nonce = self.nonce(seg_no, block, fill, salt);
+        let plain = &mut stored[..fill as usize];
+        self.aead
+            .decrypt_in_place_detached(Nonce::from_slice(&nonce), &self.db_id.to_le_bytes(), plain, &tag)
+            .map_err(|_| BlockError::Unauthenticated)?;
+        Ok(plain)
+    }
+
+    /// Opens consecutive stored blocks starting at block `first` and returns their
+    /// concatenated plaintext. Stops after a partially filled block (the tail), before an
+    /// unwritten one, or before one that fails authentication, which is also returned.
+    pub fn open_blocks(&self, seg_no: u64, first: u64, stored: &mut [u8]) -> (Vec<u8>, Option<BlockError>) {
+        let mut plain = Vec::with_capacity(stored.len() / ENCRYPTED_BLOCK_SIZE * WAL_BLOCK_SIZE);
+        for (i, block) in stored.chunks_exact_mut(ENCRYPTED_BLOCK_SIZE).enumerate() {
+            match self.open_block(seg_no, first + i as u64, block) {
+                Ok(bytes) => {
+                    plain.extend_from_slice(bytes);
+                    if bytes.len() < WAL_BLOCK_SIZE {
+                        break;
+                    }
+                }
+                Err(e) => return (plain, Some(e)),
+            }
+        }
+        (plain, None)
+    }
+
+    fn nonce(&self, seg_no: u64, block: u64, fill: u16, salt: u16) -> [u8; 12] {
+        let global_block = (self.stream as u64) << 48 | (seg_no * BLOCKS_PER_SEGMENT + block);
+        let mut nonce = [0u8; 12];
+        nonce[0..8].copy_from_slice(&global_block.to_le_bytes());
+        nonce[8..10].copy_from_slice(&fill.to_le_bytes());
+        nonce[10..12].copy_from_slice(&salt.to_le_bytes());
+        nonce
+    }
+}
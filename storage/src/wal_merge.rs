@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::traits::{Lsn, StorageError};
+use crate::wal;
+use crate::wal_crypt::{WalCipher, WalKey};
+use crate::wal_reader::WalReader;
+use crate::wal_record::WalRecord;
+use crate::wal_recovery;
+
+// -----------------------------------------------------------------------------
+// Merging Per-Core Streams
+//
+// With `WalLayout::PerCore` a database has one stream per core that wrote to it, each
+// in LSN order but with no order between them. Every record carries a global sequence
+// number (GSN) from its core's hybrid logical clock, and replay takes records in
+//
+//   (gsn, core id, lsn)
+//
+// order: the core id only breaks ties between cores whose clocks produced the same GSN,
+// and the LSN keeps a stream's own records in log order, so every replay of the same
+// log is identical. Records without a GSN (a per-database log) sort first.
+//
+// GSNs order records that depend on each other, since a core observes the GSN of
+// anything it builds on (`CoreStorage::observe_wal_gsn`). Unrelated records on
+// different cores may come out in either order, which is harmless because they touch
+// different pages.
+// -----------------------------------------------------------------------------
+
+struct Source {
+    core_id: u16,
+    reader: WalReader,
+    // Next record of this stream, read ahead to compare against the other streams
+    head: Option<WalRecord>,
+    done: bool,
+}
+
+/// Reader over all of a database's WAL streams in replay order.
+pub struct MergedWalReader {
+    sources: Vec<Source>,
+    primed: bool,
+}
+
+impl MergedWalReader {
+    /// Merges readers over the streams of one database, each positioned at a record
+    /// boundary of its stream.
+    pub fn new(readers: Vec<WalReader>) -> Self {
+        let sources = readers
+            .into_iter()
+            .map(|reader| Source {
+                core_id: wal::lsn_core(reader.position()),
+                reader,
+                head: None,
+                done: false,
+            })
+            .collect();
+        Self { sources, primed: false }
+    }
+
+    /// Opens every stream of the database found under `wal_dir`, per-database and
+    /// per-core alike, at its recovery start. `key` decrypts an encrypted WAL.
+    pub async fn open(wal_dir: &Path, db_id: u32, key: Option<&WalKey>) -> Result<Self, StorageError> {
+        let mut readers = Vec::new();
+        for (root, origin) in wal::wal_roots(wal_dir)? {
+            if wal::wal_segments(&root, db_id)?.is_empty() {
+                continue;
+            }
+            let start = wal_recovery::recovery_start(&root, db_id, origin)?;
+            let reader = WalReader::open(&root, db_id, start).await?;
+            readers.push(match key {
+                Some(key) => reader.decrypt_with(Rc::new(WalCipher::new(key, db_id, wal::lsn_core(origin)))),
+                None => reader,
+            });
+        }
+        Ok(Self::new(readers))
+    }
+
+    /// The next record in replay order, or `None` once every stream has ended.
+    pub async fn next(&mut self) -> Option<Result<WalRecord, StorageError>> {
+        // Every stream needs a head before the smallest can be picked; afterwards only
+        // the stream a record was taken from does.
+        if !self.primed {
+            for i in 0..self.sources.len() {
+                if let Err(e) = self.advance(i).await {
+                    return Some(Err(e));
+                }
+            }
+            self.primed = true;
+        }
+
+        let next = self
+            .sources
+            .iter()
+            .enumerate()
+            .filter_map(|(i, source)| source.head.as_ref().map(|head| (merge_key(source.core_id, head), i)))
+            .min()?
+            .1;
+        let record = self.sources[next].head.take().unwrap();
+        if let Err(e) = self.advance(next).await {
+            return Some(Err(e));
+        }
+        Some(Ok(record))
+    }
+
+    /// Where each stream resumes, by core: one past its last record returned.
+    pub fn positions(&self) -> HashMap<u16, Lsn> {
+        self.sources
+            .iter()
+            .map(|source| {
+                let position = source.head.as_ref().map_or(source.reader.position(), |head| head.lsn);
+                (source.core_id, position)
+            })
+            .collect()
+    }
+
+    /// Streams that stopped at an invalid record, with where and why.
+    pub fn stopped_at(&self) -> Vec<(u16, Lsn, &'static str)> {
+        self.sources
+            .iter()
+            .filter_map(|source| source.reader.stopped_at().map(|(lsn, reason)| (source.core_id, lsn, reason)))
+            .collect()
+    }
+
+    async fn advance(&mut self, i: usize) -> Result<(), StorageError> {
+        let source = &mut self.sources[i];
+        if source.done {
+            return Ok(());
+        }
+        match source.reader.next().await {
+            Some(record) => source.head = Some(record?),
+            None => source.done = true,
+        }
+        Ok(())
+    }
+}
+
+fn merge_key(core_id: u16, record: &WalRecord) -> (u64, u16, Lsn) {
+    (record.gsn.unwrap_or(0), core_id, record.lsn)
+}
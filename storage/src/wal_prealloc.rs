@@ -0,0 +1,151 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::segment;
+use crate::traits::StorageError;
+use crate::wal;
+use crate::wal_compress;
+
+// -----------------------------------------------------------------------------
+// Future WAL Segments
+//
+// A helper thread keeps the next N segments of each active log created, fallocated
+// and zero-filled under their final names, so the append that crosses into a new
+// segment finds its blocks already allocated and written. Zeros decode as the end of
+// the log, so a future segment is indistinguishable from unwritten space.
+//
+// Segments dropped by `truncate_wal` are recycled rather than deleted:
+//
+//   wal_dir/db_<id>/recycle/<old seg_no>.spare
+//
+// and zero-filled again before they are renamed into a future slot. Stale records
+// must never appear past the tail, where a scan could mistake them for the log.
+// -----------------------------------------------------------------------------
+
+const ZERO_CHUNK: usize = 1 << 20;
+
+fn recycle_dir(wal_dir: &Path, db_id: u32) -> PathBuf {
+    wal_dir.join(format!("db_{}", db_id)).join("recycle")
+}
+
+/// Removes a segment `truncate_wal` no longer needs, or parks it for reuse if fewer
+/// than `keep` are parked already. Compressed segments are the wrong size to reuse.
+pub fn retire_segment(wal_dir: &Path, db_id: u32, seg_no: u64, path: &Path, keep: usize) -> Result<(), StorageError> {
+    let dir = recycle_dir(wal_dir, db_id);
+    let parked = match fs::read_dir(&dir) {
+        Ok(entries) => entries.count(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+    if wal_compress::is_compressed_path(path) || parked >= keep {
+        return fs::remove_file(path).map_err(StorageError::Io);
+    }
+    fs::create_dir_all(&dir).map_err(StorageError::Io)?;
+    fs::rename(path, dir.join(format!("{:016X}.spare", seg_no))).map_err(StorageError::Io)
+}
+
+/// Zero-fills `file` to exactly `len` bytes, with every block allocated and written.
+fn zero_fill(file: &fs::File, len: u64) -> Result<(), StorageError> {
+    file.set_len(len).map_err(StorageError::Io)?;
+    segment::preallocate(file, 0, len)?;
+    let zeros = vec![0u8; ZERO_CHUNK];
+    let mut offset = 0;
+    while offset < len {
+        let n = (len - offset).min(ZERO_CHUNK as u64) as usize;
+        file.write_all_at(&zeros[..n], offset).map_err(StorageError::Io)?;
+        offset += n as u64;
+    }
+    file.sync_data().map_err(StorageError::Io)
+}
+
+/// Renames `from` to `to` unless `to` exists: the core may have created the segment
+/// itself in the meantime, and replacing it would orphan what it wrote.
+fn rename_noreplace(from: &Path, to: &Path) -> io::Result<bool> {
+    let from = CString::new(from.as_os_str().as_bytes())?;
+    let to = CString::new(to.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid NUL-terminated strings for the duration of the call.
+    let rc = unsafe {
+        libc::renameat2(libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), libc::RENAME_NOREPLACE)
+    };
+    if rc == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::AlreadyExists {
+        return Ok(false);
+    }
+    Err(err)
+}
+
+/// Makes sure segments `current + 1 ..= current + future` exist, zero-filled to
+/// `segment_len` bytes, reusing parked segments first.
+pub fn ensure_future_segments(wal_dir: &Path, db_id: u32, current: u64, future: usize, segment_len: u64) -> Result<(), StorageError> {
+    let dir = recycle_dir(wal_dir, db_id);
+    fs::create_dir_all(&dir).map_err(StorageError::Io)?;
+    let mut created = false;
+
+    for seg_no in current + 1..=current + future as u64 {
+        let target = wal::wal_segment_path(wal_dir, db_id, seg_no);
+        if target.exists() || wal_compress::compressed_segment_path(wal_dir, db_id, seg_no).exists() {
+            continue;
+        }
+
+        let spare = fs::read_dir(&dir)
+            .map_err(StorageError::Io)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .find(|path| path.extension().is_some_and(|ext| ext == "spare"));
+        let staging = dir.join(format!("{:016X}.filling", seg_no));
+        match spare {
+            Some(spare) => fs::rename(&spare, &staging).map_err(StorageError::Io)?,
+            None => drop(fs::File::create(&staging).map_err(StorageError::Io)?),
+        }
+
+        let file = fs::OpenOptions::new().write(true).open(&staging).map_err(StorageError::Io)?;
+        zero_fill(&file, segment_len)?;
+        if rename_noreplace(&staging, &target).map_err(StorageError::Io)? {
+            created = true;
+        } else {
+            fs::rename(&staging, dir.join(format!("{:016X}.spare", seg_no))).map_err(StorageError::Io)?;
+        }
+    }
+
+    if created {
+        let db_dir = dir.parent().unwrap();
+        fs::File::open(db_dir).and_then(|d| d.sync_all()).map_err(StorageError::Io)?;
+    }
+    Ok(())
+}
+
+/// Background thread creating future segments. `notify(db_id, current, len)` says a
+/// log has reached segment `current`; requests are cheap and coalesce.
+pub struct PreallocWorker {
+    tx: mpsc::Sender<(u32, u64, u64)>,
+}
+
+impl PreallocWorker {
+    pub fn start(wal_dir: PathBuf, future: usize, core_id: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<(u32, u64, u64)>();
+        thread::Builder::new()
+            .name(format!("wal-prealloc-{}", core_id))
+            .spawn(move || {
+                // Exits once the owning CoreStorage drops the sender.
+                for (db_id, current, segment_len) in rx {
+                    // A failure (e.g. ENOSPC) only means the core creates the segment
+                    // itself when it gets there.
+                    let _ = ensure_future_segments(&wal_dir, db_id, current, future, segment_len);
+                }
+            })
+            .expect("failed to spawn WAL preallocation thread");
+        Self { tx }
+    }
+
+    pub fn notify(&self, db_id: u32, current: u64, segment_len: u64) {
+        let _ = self.tx.send((db_id, current, segment_len));
+    }
+}
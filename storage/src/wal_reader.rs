@@ -0,0 +1,296 @@
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use futures::stream::{self, Stream};
+use tokio_uring::fs::File;
+
+use crate::traits::{Lsn, StorageError};
+use crate::wal::{self, WAL_BLOCK_SIZE, WAL_SEGMENT_SIZE};
+use crate::wal_compress;
+use crate::wal_crypt::{self, BlockError, WalCipher};
+use crate::wal_record::{Decoded, WalDecoder, WalRecord};
+
+/// Bytes read from a segment file per ring op (256KB).
+pub const WAL_READ_CHUNK: usize = 256 << 10;
+
+enum Segment {
+    Plain(File),
+    // A compressed segment, expanded in memory when first reached
+    Expanded(Vec<u8>),
+}
+
+/// Sequential reader over a database's WAL, shared by recovery and replication.
+///
+/// Reads segment after segment, feeds the bytes to a `WalDecoder` (so records spanning
+/// segment files come out whole), and yields records with their LSNs. It stops cleanly
+/// at the end of the log: a missing or short segment, a torn record at the tail, or the
+/// first record that fails validation. Compressed segments are read transparently. Afterwards `stopped_at` and `trailing_bytes` say
+/// which of those it was.
+pub struct WalReader {
+    wal_dir: PathBuf,
+    db_id: u32,
+    decoder: WalDecoder,
+    // LSN of the next byte to read from disk
+    read_lsn: u64,
+    segment: Option<(u64, Segment)>,
+    cipher: Option<Rc<WalCipher>>,
+    finished: bool,
+    stopped_at: Option<(Lsn, &'static str)>,
+}
+
+impl WalReader {
+    /// Opens a reader positioned at `start_lsn`, which must be a record boundary
+    /// (an LSN returned by `append_wal`, or a record's `end_lsn`).
+    pub async fn open(wal_dir: &Path, db_id: u32, start_lsn: Lsn) -> Result<Self, StorageError> {
+        Ok(Self {
+            wal_dir: wal_dir.to_path_buf(),
+            db_id,
+            decoder: WalDecoder::new(start_lsn),
+            read_lsn: start_lsn.0,
+            segment: None,
+            cipher: None,
+            finished: false,
+            stopped_at: None,
+        })
+    }
+
+    /// Authenticates and decrypts blocks with `cipher`, for a database with WAL
+    /// encryption. A block that fails authentication ends the log like a torn record.
+    pub fn decrypt_with(mut self, cipher: Rc<WalCipher>) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// The next record, or `None` at the end of the log.
+    pub async fn next(&mut self) -> Option<Result<WalRecord, StorageError>> {
+        loop {
+            if self.finished {
+                return None;
+            }
+            match self.decoder.next_record() {
+                Decoded::Record(record) => return Some(Ok(record)),
+                Decoded::Invalid { lsn, reason } => {
+                    self.stopped_at = Some((lsn, reason));
+                    self.finished = true;
+                }
+                Decoded::NeedMore => match self.fill().await {
+                    Ok(true) => {}
+                    Ok(false) => self.finished = true,
+                    Err(e) => {
+                        self.finished = true;
+                        return Some(Err(e));
+                    }
+                },
+            }
+        }
+    }
+
+    /// LSN just past the last record returned: where the next append should go if this
+    /// reader ran to the end of the log.
+    pub fn position(&self) -> Lsn {
+        self.decoder.position()
+    }
+
+    /// Where and why reading stopped, if it stopped at an invalid record rather than at
+    /// the end of the available bytes.
+    pub fn stopped_at(&self) -> Option<(Lsn, &'static str)> {
+        self.stopped_at
+    }
+
+    /// Bytes read past `position` that didn't form a complete record: a torn tail.
+    pub fn trailing_bytes(&self) -> u64 {
+        self.read_lsn - self.decoder.position().0
+    }
+
+    /// Adapts the reader into a `Stream` of records.
+    pub fn into_stream(self) -> impl Stream<Item = Result<WalRecord, StorageError>> {
+        stream::unfold(self, |mut reader| async move {
+            let item = reader.next().await?;
+            Some((item, reader))
+        })
+    }
+
+    /// Reads the next chunk into the decoder. `Ok(false)` at the end of the log.
+    async fn fill(&mut self) -> Result<bool, StorageError> {
+        let (seg_no, offset) = wal::locate(Lsn(self.read_lsn));
+        if self.segment.as_ref().map(|(open, _)| *open) != Some(seg_no) {
+            match self.open_segment(seg_no).await? {
+                Some(segment) => self.segment = Some((seg_no, segment)),
+                None => return Ok(false),
+            }
+        }
+        let (_, segment) = self.segment.as_ref().unwrap();
+
+        let len = (WAL_READ_CHUNK as u64).min(WAL_SEGMENT_SIZE - offset) as usize;
+        let buf = match &self.cipher {
+            None => read_physical(segment, offset, len).await?,
+            Some(cipher) => {
+                // Whole blocks are read and authenticated, then trimmed to the read position.
+                let block = offset / WAL_BLOCK_SIZE as u64;
+                let within = (offset % WAL_BLOCK_SIZE as u64) as usize;
+                let blocks = len.div_ceil(WAL_BLOCK_SIZE);
+                let physical = wal_crypt::physical_block_offset(offset);
+                let mut stored = read_physical(segment, physical, blocks * wal_crypt::ENCRYPTED_BLOCK_SIZE).await?;
+                let (plain, failed) = cipher.open_blocks(seg_no, block, &mut stored);
+                if plain.len() <= within {
+                    if failed == Some(BlockError::Unauthenticated) {
+                        self.stopped_at = Some((Lsn(self.read_lsn), "WAL block failed authentication"));
+                    }
+                    return Ok(false);
+                }
+                plain[within..].to_vec()
+            }
+        };
+        let n = buf.len();
+        // A short segment is the end of the log; the next segment, if any, is not
+        // contiguous with it and must not be stitched on.
+        if n == 0 {
+            return Ok(false);
+        }
+        self.decoder.feed(&buf[..n]);
+        self.read_lsn += n as u64;
+        Ok(true)
+    }
+
+    /// Opens segment `seg_no`, preferring the plain file. `None` if neither form exists.
+    async fn open_segment(&self, seg_no: u64) -> Result<Option<Segment>, StorageError> {
+        let path = wal::wal_segment_path(&self.wal_dir, self.db_id, seg_no);
+        match File::open(&path).await {
+            Ok(file) => return Ok(Some(Segment::Plain(file))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(StorageError::Io(e)),
+        }
+
+        let path = wal_compress::compressed_segment_path(&self.wal_dir, self.db_id, seg_no);
+        let file = match File::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StorageError::Io(e)),
+        };
+        let len = std::fs::metadata(&path).map_err(StorageError::Io)?.len() as usize;
+        let (res, mut bytes) = file.read_at(vec![0u8; len], 0).await;
+        bytes.truncate(res.map_err(StorageError::Io)?);
+        let _ = file.close().await;
+        Ok(Some(Segment::Expanded(wal_compress::decompress_segment(&bytes, &path)?)))
+    }
+}
+
+/// Up to `len` stored bytes of a segment from `offset`; fewer at the end of the file.
+async fn read_physical(segment: &Segment, offset: u64, len: usize) -> Result<Vec<u8>, StorageError> {
+    match segment {
+        Segment::Plain(file) => {
+            let (res, mut buf) = file.read_at(vec![0u8; len], offset).await;
+            buf.truncate(res.map_err(StorageError::Io)?);
+            Ok(buf)
+        }
+        Segment::Expanded(bytes) => {
+            let start = (offset as usize).min(bytes.len());
+            Ok(bytes[start..(start + len).min(bytes.len())].to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal_record::{encode_frame, WalRecordType, RECORD_HEADER_SIZE};
+
+    /// A fresh, empty WAL dir under the system temp dir, unique per test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aquifer-wal-reader-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("db_1")).unwrap();
+        dir
+    }
+
+    fn frame(log: &mut Vec<u8>, payload: &[u8]) -> Lsn {
+        let lsn = Lsn(log.len() as u64);
+        let (header, trailer) = encode_frame(WalRecordType::OPAQUE, payload);
+        log.extend_from_slice(&header);
+        log.extend_from_slice(payload);
+        log.extend_from_slice(&trailer);
+        lsn
+    }
+
+    /// Lays the byte stream out over segment files the way `append_wal` does.
+    fn write_log(wal_dir: &Path, log: &[u8]) {
+        for (seg_no, chunk) in log.chunks(WAL_SEGMENT_SIZE as usize).enumerate() {
+            std::fs::write(wal::wal_segment_path(wal_dir, 1, seg_no as u64), chunk).unwrap();
+        }
+    }
+
+    async fn read_all(wal_dir: &Path, start: Lsn) -> (Vec<WalRecord>, WalReader) {
+        let mut reader = WalReader::open(wal_dir, 1, start).await.unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.next().await {
+            records.push(record.unwrap());
+        }
+        (records, reader)
+    }
+
+    #[test]
+    fn records_straddling_segment_files_come_out_whole() {
+        let dir = scratch_dir("straddle");
+        let mut log = Vec::new();
+        // Leaves 20 bytes of segment 0, so the next record starts in it and ends in segment 1.
+        let filler = vec![0xAB; WAL_SEGMENT_SIZE as usize - 20 - 12];
+        let lsns = [frame(&mut log, &filler), frame(&mut log, b"straddles the boundary"), frame(&mut log, b"tail")];
+        write_log(&dir, &log);
+
+        tokio_uring::start(async {
+            let (records, reader) = read_all(&dir, Lsn(0)).await;
+            assert_eq!(records.iter().map(|r| r.lsn).collect::<Vec<_>>(), lsns);
+            assert_eq!(records[1].payload, b"straddles the boundary");
+            assert_eq!(reader.position(), Lsn(log.len() as u64));
+            assert_eq!(reader.stopped_at(), None);
+            assert_eq!(reader.trailing_bytes(), 0);
+
+            // Starting from a later record boundary skips what came before.
+            let (records, _) = read_all(&dir, lsns[2]).await;
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].payload, b"tail");
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_torn_tail_ends_the_log() {
+        let dir = scratch_dir("torn");
+        let mut log = Vec::new();
+        frame(&mut log, b"first");
+        let second = frame(&mut log, b"second");
+        let end = log.len();
+        frame(&mut log, b"torn by a crash");
+        log.truncate(end + 10);
+        write_log(&dir, &log);
+
+        tokio_uring::start(async {
+            let (records, reader) = read_all(&dir, Lsn(0)).await;
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[1].lsn, second);
+            assert_eq!(reader.position(), Lsn(end as u64));
+            assert_eq!(reader.stopped_at(), None);
+            assert_eq!(reader.trailing_bytes(), 10);
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_corrupt_record_stops_the_scan() {
+        let dir = scratch_dir("corrupt");
+        let mut log = Vec::new();
+        frame(&mut log, b"good");
+        let bad = frame(&mut log, b"flipped");
+        frame(&mut log, b"never reached");
+        log[bad.0 as usize + RECORD_HEADER_SIZE] ^= 0xFF;
+        write_log(&dir, &log);
+
+        tokio_uring::start(async {
+            let (records, reader) = read_all(&dir, Lsn(0)).await;
+            assert_eq!(records.len(), 1);
+            assert_eq!(reader.stopped_at(), Some((bad, "record CRC mismatch")));
+            assert_eq!(reader.position(), bad);
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
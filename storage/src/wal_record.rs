@@ -0,0 +1,269 @@
+use crate::checksum;
+use crate::traits::Lsn;
+
+// -----------------------------------------------------------------------------
+// WAL Record Framing
+//
+//   [0..4)         payload length (u32 LE)
+//   [4..6)         record type (u16 LE), 0 is never valid
+//   [6..8)         flags (u16 LE)
+//   [8..16)        global sequence number (u64 LE), only with FLAG_SEQUENCED
+//   [..+len)       payload
+//   [..+4)         CRC32C over everything before it
+//
+// Per-core streams (`WalLayout::PerCore`) set FLAG_SEQUENCED on every record, so that
+// recovery can merge the streams back into one order (see `wal_merge.rs`).
+//
+// Frames are laid end to end in the log's byte stream with no alignment, so a record
+// may straddle staging blocks and segment files. The decoder is fed the stream in
+// whatever chunks the reader has, and reassembles across those boundaries.
+// -----------------------------------------------------------------------------
+
+pub const RECORD_HEADER_SIZE: usize = 8;
+pub const RECORD_TRAILER_SIZE: usize = 4;
+pub const GSN_SIZE: usize = 8;
+
+/// The record carries a global sequence number after its header.
+pub const FLAG_SEQUENCED: u16 = 1;
+const KNOWN_FLAGS: u16 = FLAG_SEQUENCED;
+
+/// Payloads larger than this are rejected on encode and treated as garbage on decode.
+pub const MAX_RECORD_PAYLOAD: usize = 64 << 20;
+
+/// Identifies which subsystem a record belongs to and how to interpret its payload.
+/// Each subsystem tags its records from its own range (see `wal_registry.rs`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct WalRecordType(pub u16);
+
+impl WalRecordType {
+    /// Uninterpreted bytes from callers that don't define a record type yet.
+    pub const OPAQUE: Self = Self(1);
+    /// Transaction commit with its commit timestamp (see `commit_ts.rs`).
+    pub const COMMIT: Self = Self(2);
+    /// Start and end of a fuzzy checkpoint (see `wal_checkpoint.rs`).
+    pub const CHECKPOINT_BEGIN: Self = Self(3);
+    pub const CHECKPOINT_END: Self = Self(4);
+    /// Page change with its before image, its undo (redo-only), and the end of a
+    /// rollback (see `undo.rs`).
+    pub const PAGE_UPDATE: Self = Self(5);
+    pub const COMPENSATION: Self = Self(6);
+    pub const ABORT: Self = Self(7);
+    /// A page's image before its first change since a checkpoint (see `full_page.rs`).
+    pub const FULL_PAGE_IMAGE: Self = Self(8);
+    /// Space catalog changes: a page formatted, an entry written or removed, and the
+    /// next page of the chain (see `catalog.rs`).
+    pub const CATALOG_INIT: Self = Self(9);
+    pub const CATALOG_PUT: Self = Self(10);
+    pub const CATALOG_REMOVE: Self = Self(11);
+    pub const CATALOG_LINK: Self = Self(12);
+    /// A sequence page formatted or a chunk of its values reserved (see `sequence.rs`).
+    pub const SEQUENCE_SET: Self = Self(13);
+    /// Heap page changes: a page formatted, a tuple added or removed, a page compacted,
+    /// a page marked all-visible, an overflow page written, and a version's deleter set
+    /// (see `heap.rs`).
+    pub const HEAP_INIT: Self = Self(0x0200);
+    pub const HEAP_INSERT: Self = Self(0x0201);
+    pub const HEAP_DELETE: Self = Self(0x0202);
+    pub const HEAP_COMPACT: Self = Self(0x0203);
+    pub const HEAP_VISIBLE: Self = Self(0x0204);
+    pub const HEAP_OVERFLOW: Self = Self(0x0205);
+    pub const HEAP_SET_XMAX: Self = Self(0x0206);
+    /// B-tree node changes: a whole node, an entry added, removed or given a new value,
+    /// and the left half of a split (see `btree.rs`).
+    pub const BTREE_NODE: Self = Self(0x0300);
+    pub const BTREE_INSERT: Self = Self(0x0301);
+    pub const BTREE_DELETE: Self = Self(0x0302);
+    pub const BTREE_UPDATE: Self = Self(0x0303);
+    pub const BTREE_SPLIT: Self = Self(0x0304);
+    /// Free space map changes: a map page formatted, an entry set, and the count of
+    /// map pages (see `fsm.rs`).
+    pub const FSM_INIT: Self = Self(0x0400);
+    pub const FSM_SET: Self = Self(0x0401);
+    pub const FSM_PAGES: Self = Self(0x0402);
+    /// Visibility map changes: a map page formatted, and a data page's bits (see `vm.rs`).
+    pub const VM_INIT: Self = Self(0x0410);
+    pub const VM_SET: Self = Self(0x0411);
+    /// LSM tree changes: a key set or deleted in a memtable, and a manifest rewritten
+    /// (see `lsm.rs`).
+    pub const LSM_WRITE: Self = Self(0x0500);
+    pub const LSM_MANIFEST: Self = Self(0x0501);
+    /// Blob store changes: the root formatted, its garbage list, and blob headers
+    /// (see `blob.rs`).
+    pub const BLOB_ROOT_INIT: Self = Self(0x0600);
+    pub const BLOB_GARBAGE_ADD: Self = Self(0x0601);
+    pub const BLOB_GARBAGE_REMOVE: Self = Self(0x0602);
+    pub const BLOB_INIT: Self = Self(0x0603);
+    pub const BLOB_SET_REFS: Self = Self(0x0604);
+    /// Hash index changes: the meta page and bucket pages written anew, and their
+    /// entries (see `hash_index.rs`).
+    pub const HASH_META: Self = Self(0x0700);
+    pub const HASH_PAGE: Self = Self(0x0701);
+    pub const HASH_INSERT: Self = Self(0x0702);
+    pub const HASH_DELETE: Self = Self(0x0703);
+    pub const HASH_UPDATE: Self = Self(0x0704);
+    pub const HASH_LINK: Self = Self(0x0705);
+}
+
+/// Total on-disk size of a record with `payload_len` bytes of payload.
+pub fn framed_len(payload_len: usize) -> usize {
+    RECORD_HEADER_SIZE + payload_len + RECORD_TRAILER_SIZE
+}
+
+/// Total on-disk size of a sequenced record with `payload_len` bytes of payload.
+pub fn sequenced_framed_len(payload_len: usize) -> usize {
+    framed_len(payload_len) + GSN_SIZE
+}
+
+/// Builds the header and CRC trailer for `payload`. They are returned separately so the
+/// caller can stage header, payload and trailer without copying the payload twice.
+pub fn encode_frame(record_type: WalRecordType, payload: &[u8]) -> ([u8; RECORD_HEADER_SIZE], [u8; RECORD_TRAILER_SIZE]) {
+    let header = frame_header(record_type, 0, payload.len());
+    let crc = checksum::crc32c_append(checksum::crc32c(&header), payload);
+    (header, crc.to_le_bytes())
+}
+
+/// Like `encode_frame`, for a record stamped with global sequence number `gsn`; the
+/// returned header includes it.
+pub fn encode_sequenced_frame(record_type: WalRecordType, gsn: u64, payload: &[u8]) -> ([u8; RECORD_HEADER_SIZE + GSN_SIZE], [u8; RECORD_TRAILER_SIZE]) {
+    let mut header = [0u8; RECORD_HEADER_SIZE + GSN_SIZE];
+    header[..RECORD_HEADER_SIZE].copy_from_slice(&frame_header(record_type, FLAG_SEQUENCED, payload.len()));
+    header[RECORD_HEADER_SIZE..].copy_from_slice(&gsn.to_le_bytes());
+    let crc = checksum::crc32c_append(checksum::crc32c(&header), payload);
+    (header, crc.to_le_bytes())
+}
+
+fn frame_header(record_type: WalRecordType, flags: u16, payload_len: usize) -> [u8; RECORD_HEADER_SIZE] {
+    assert!(record_type.0 != 0, "record type 0 is reserved");
+    assert!(payload_len <= MAX_RECORD_PAYLOAD, "WAL record payload too large");
+
+    let mut header = [0u8; RECORD_HEADER_SIZE];
+    header[0..4].copy_from_slice(&(payload_len as u32).to_le_bytes());
+    header[4..6].copy_from_slice(&record_type.0.to_le_bytes());
+    header[6..8].copy_from_slice(&flags.to_le_bytes());
+    header
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalRecord {
+    pub lsn: Lsn,
+    pub record_type: WalRecordType,
+    /// Global sequence number, for records from a per-core stream.
+    pub gsn: Option<u64>,
+    pub payload: Vec<u8>,
+}
+
+impl WalRecord {
+    /// LSN just past this record: where the next one starts.
+    pub fn end_lsn(&self) -> Lsn {
+        Lsn(self.lsn.0 + self.framed_len() as u64)
+    }
+
+    /// On-disk size of the record.
+    pub fn framed_len(&self) -> usize {
+        match self.gsn {
+            Some(_) => sequenced_framed_len(self.payload.len()),
+            None => framed_len(self.payload.len()),
+        }
+    }
+
+    /// The record framed exactly as it is in the log, CRC included.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.framed_len());
+        let trailer = match self.gsn {
+            Some(gsn) => {
+                let (header, trailer) = encode_sequenced_frame(self.record_type, gsn, &self.payload);
+                out.extend_from_slice(&header);
+                trailer
+            }
+            None => {
+                let (header, trailer) = encode_frame(self.record_type, &self.payload);
+                out.extend_from_slice(&header);
+                trailer
+            }
+        };
+        out.extend_from_slice(&self.payload);
+        out.extend_from_slice(&trailer);
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+    Record(WalRecord),
+    /// The buffered bytes end mid-record; feed more.
+    NeedMore,
+    /// The bytes at `lsn` are not a valid record: a torn write, zero-filled space past the
+    /// tail, or corruption. Recovery treats this as the end of the log.
+    Invalid { lsn: Lsn, reason: &'static str },
+}
+
+/// Incremental record decoder over the log's byte stream.
+pub struct WalDecoder {
+    buf: Vec<u8>,
+    // Start of the undecoded bytes in `buf`; consumed bytes are compacted away on `feed`
+    pos: usize,
+    // LSN of buf[pos]
+    lsn: u64,
+}
+
+impl WalDecoder {
+    /// `start` must be the LSN of a record boundary.
+    pub fn new(start: Lsn) -> Self {
+        Self { buf: Vec::new(), pos: 0, lsn: start.0 }
+    }
+
+    /// LSN of the next undecoded byte.
+    pub fn position(&self) -> Lsn {
+        Lsn(self.lsn)
+    }
+
+    /// Appends the next chunk of the stream.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn next_record(&mut self) -> Decoded {
+        let buf = &self.buf[self.pos..];
+        if buf.len() < RECORD_HEADER_SIZE {
+            return Decoded::NeedMore;
+        }
+        let lsn = Lsn(self.lsn);
+        let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+        let record_type = u16::from_le_bytes(buf[4..6].try_into().unwrap());
+        let flags = u16::from_le_bytes(buf[6..8].try_into().unwrap());
+        if record_type == 0 {
+            return Decoded::Invalid { lsn, reason: "record type 0" };
+        }
+        if flags & !KNOWN_FLAGS != 0 {
+            return Decoded::Invalid { lsn, reason: "unknown record flags" };
+        }
+        if len > MAX_RECORD_PAYLOAD {
+            return Decoded::Invalid { lsn, reason: "implausible record length" };
+        }
+
+        let sequenced = flags & FLAG_SEQUENCED != 0;
+        let header_len = if sequenced { RECORD_HEADER_SIZE + GSN_SIZE } else { RECORD_HEADER_SIZE };
+        let total = header_len + len + RECORD_TRAILER_SIZE;
+        if buf.len() < total {
+            return Decoded::NeedMore;
+        }
+        let body = &buf[..header_len + len];
+        let stored = u32::from_le_bytes(buf[header_len + len..total].try_into().unwrap());
+        if checksum::crc32c(body) != stored {
+            return Decoded::Invalid { lsn, reason: "record CRC mismatch" };
+        }
+
+        let gsn = sequenced.then(|| u64::from_le_bytes(buf[RECORD_HEADER_SIZE..header_len].try_into().unwrap()));
+        let payload = buf[header_len..header_len + len].to_vec();
+        self.pos += total;
+        self.lsn += total as u64;
+        Decoded::Record(WalRecord {
+            lsn,
+            record_type: WalRecordType(record_type),
+            gsn,
+            payload,
+        })
+    }
+}
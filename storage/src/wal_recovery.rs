@@ -0,0 +1,494 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::checksum;
+use crate::traits::{Lsn, StorageError};
+use crate::wal::{self, WAL_BLOCK_SIZE, WAL_SEGMENT_SIZE};
+use crate::wal_archive;
+use crate::wal_compress;
+use crate::wal_crypt::{self, BlockError, WalCipher, ENCRYPTED_BLOCK_SIZE};
+use crate::wal_record::{Decoded, WalDecoder, WalRecord};
+
+// -----------------------------------------------------------------------------
+// Recovery Start
+//
+// Segments are cut at fixed byte offsets, so the first retained segment usually starts
+// mid-record and can't be decoded from its first byte. The oldest retained record
+// boundary is kept in
+//
+//   wal_dir/db_<id>/recovery_start     LSN (u64 LE) | CRC32C (u32 LE)
+//
+// written before `truncate_wal` deletes anything. Without it the log starts at its
+// origin: LSN 0, or the first LSN of a per-core stream (`wal::stream_origin`).
+// -----------------------------------------------------------------------------
+
+const RECOVERY_START_FILE: &str = "recovery_start";
+
+fn recovery_start_path(wal_dir: &Path, db_id: u32) -> PathBuf {
+    wal_dir.join(format!("db_{}", db_id)).join(RECOVERY_START_FILE)
+}
+
+/// The LSN a scan of the database's WAL must start from; `origin` is the first LSN of
+/// the stream rooted at `wal_dir`.
+pub fn recovery_start(wal_dir: &Path, db_id: u32, origin: Lsn) -> Result<Lsn, StorageError> {
+    let path = recovery_start_path(wal_dir, db_id);
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(origin),
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+    if bytes.len() != 12 || checksum::crc32c(&bytes[..8]) != u32::from_le_bytes(bytes[8..12].try_into().unwrap()) {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("corrupt WAL recovery start file {}", path.display()),
+        )));
+    }
+    Ok(Lsn(u64::from_le_bytes(bytes[..8].try_into().unwrap())))
+}
+
+/// Durably records `lsn`, which must be a record boundary, as where scans start.
+pub fn set_recovery_start(wal_dir: &Path, db_id: u32, lsn: Lsn) -> Result<(), StorageError> {
+    let path = recovery_start_path(wal_dir, db_id);
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir).map_err(StorageError::Io)?;
+
+    let mut bytes = [0u8; 12];
+    bytes[..8].copy_from_slice(&lsn.0.to_le_bytes());
+    let crc = checksum::crc32c(&bytes[..8]);
+    bytes[8..].copy_from_slice(&crc.to_le_bytes());
+    write_durably(&path, &bytes)?;
+    fs::File::open(dir).and_then(|d| d.sync_all()).map_err(StorageError::Io)
+}
+
+/// What mount cut off the end of a database's WAL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TornTail {
+    pub db_id: u32,
+    /// The new end of the log: one past the last valid record.
+    pub truncated_at: Lsn,
+    /// Why the bytes at `truncated_at` were rejected.
+    pub reason: &'static str,
+    /// Bytes cut from the end of the log: log bytes past the cut, plus whatever
+    /// unreadable bytes followed them on disk (whole removed segments included).
+    pub discarded_bytes: u64,
+}
+
+// Where a scan of the log from its recovery start ended.
+struct ScanEnd {
+    end: Lsn,
+    reason: &'static str,
+    // Plaintext of the segment holding `end`, up to `end`, if the scan reached it
+    tail_plain: Vec<u8>,
+    // Highest GSN among the records scanned (0 if none were sequenced)
+    last_gsn: u64,
+}
+
+/// Where a database's WAL stream resumes after a restart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveredTail {
+    /// One past the last valid record.
+    pub tail: Lsn,
+    /// The log bytes of the partially filled block ending at `tail` (empty at a block
+    /// boundary). A block-aligned stream (encrypted or O_DIRECT) rewrites them as it
+    /// appends.
+    pub tail_block: Vec<u8>,
+    /// Highest GSN in the retained log, where a per-core stream's clock resumes.
+    pub last_gsn: u64,
+}
+
+type Visit<'a> = &'a mut dyn FnMut(&WalRecord) -> Result<(), StorageError>;
+
+/// Decodes the log from its recovery start up to the first record that doesn't
+/// validate: torn, corrupt, or the zeros of preallocated space past the tail. Each
+/// valid record goes to `visit`. `None` if the segment holding the recovery start is
+/// missing.
+fn scan(
+    wal_dir: &Path,
+    db_id: u32,
+    origin: Lsn,
+    cipher: Option<&WalCipher>,
+    segments: &[(u64, PathBuf)],
+    visit: Visit,
+) -> Result<Option<ScanEnd>, StorageError> {
+    let start = recovery_start(wal_dir, db_id, origin)?;
+    let (start_seg, start_offset) = wal::locate(start);
+    if segments.first().is_some_and(|(seg_no, _)| *seg_no != start_seg) {
+        return Ok(None);
+    }
+
+    let mut decoder = WalDecoder::new(start);
+    let mut reason = "incomplete record";
+    let mut last_gsn = 0;
+    // Plaintext of the last two segments fed: a record straddling into the last one can
+    // leave the end in the one before
+    let mut fed: Vec<(u64, Vec<u8>)> = Vec::new();
+    'scan: for ((seg_no, path), expected) in segments.iter().zip(start_seg..) {
+        // A gap, or a segment after a short one, is not contiguous with the log.
+        if *seg_no != expected {
+            break;
+        }
+        let (plain, failed) = segment_plaintext(path, *seg_no, cipher)?;
+        let from = if *seg_no == start_seg { start_offset as usize } else { 0 };
+        decoder.feed(plain.get(from..).unwrap_or_default());
+        if fed.len() == 2 {
+            fed.remove(0);
+        }
+        fed.push((*seg_no, plain));
+        loop {
+            match decoder.next_record() {
+                Decoded::Record(record) => {
+                    last_gsn = last_gsn.max(record.gsn.unwrap_or(0));
+                    visit(&record)?;
+                }
+                Decoded::NeedMore => break,
+                Decoded::Invalid { reason: why, .. } => {
+                    reason = why;
+                    break 'scan;
+                }
+            }
+        }
+        if failed {
+            reason = "WAL block failed authentication";
+            break;
+        }
+        if fed.last().unwrap().1.len() < WAL_SEGMENT_SIZE as usize {
+            break;
+        }
+    }
+
+    let end = decoder.position();
+    let (end_seg, end_offset) = wal::locate(end);
+    let tail_plain = match fed.into_iter().find(|(seg_no, _)| *seg_no == end_seg) {
+        Some((_, mut plain)) if end_offset as usize <= plain.len() => {
+            plain.truncate(end_offset as usize);
+            plain
+        }
+        _ => Vec::new(),
+    };
+    Ok(Some(ScanEnd { end, reason, tail_plain, last_gsn }))
+}
+
+/// Finds where the stream resumes by decoding from the recovery start; preallocated
+/// segments make file sizes meaningless.
+pub fn recover_tail(wal_dir: &Path, db_id: u32, origin: Lsn, cipher: Option<&WalCipher>) -> Result<RecoveredTail, StorageError> {
+    let segments = scanned_segments(wal_dir, db_id, origin)?;
+    if segments.is_empty() {
+        return Ok(RecoveredTail {
+            tail: recovery_start(wal_dir, db_id, origin)?,
+            tail_block: Vec::new(),
+            last_gsn: 0,
+        });
+    }
+    let Some(scan) = scan(wal_dir, db_id, origin, cipher, &segments, &mut |_| Ok(()))? else {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("WAL segment holding the recovery start of db {} is missing", db_id),
+        )));
+    };
+    let within = (scan.end.0 % WAL_BLOCK_SIZE as u64) as usize;
+    if within > scan.tail_plain.len() {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("WAL tail block of db {} could not be read back", db_id),
+        )));
+    }
+    let tail_block = scan.tail_plain[scan.tail_plain.len() - within..].to_vec();
+    Ok(RecoveredTail {
+        tail: scan.end,
+        tail_block,
+        last_gsn: scan.last_gsn,
+    })
+}
+
+/// Hands every record of the database's WAL, from its recovery start to where the
+/// stream would resume, to `visit` in log order, read without a ring (for offline
+/// tools).
+pub fn scan_records(
+    wal_dir: &Path,
+    db_id: u32,
+    origin: Lsn,
+    cipher: Option<&WalCipher>,
+    mut visit: impl FnMut(&WalRecord) -> Result<(), StorageError>,
+) -> Result<(), StorageError> {
+    let segments = scanned_segments(wal_dir, db_id, origin)?;
+    if segments.is_empty() {
+        return Ok(());
+    }
+    match scan(wal_dir, db_id, origin, cipher, &segments, &mut visit)? {
+        Some(_) => Ok(()),
+        None => Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("WAL segment holding the recovery start of db {} is missing", db_id),
+        ))),
+    }
+}
+
+/// The record at `lsn`, a record boundary, read without a ring (for mount). `None` if
+/// no valid record starts there.
+pub fn read_record_at(wal_dir: &Path, db_id: u32, lsn: Lsn, cipher: Option<&WalCipher>) -> Result<Option<WalRecord>, StorageError> {
+    let (first_seg, offset) = wal::locate(lsn);
+    let mut decoder = WalDecoder::new(lsn);
+    let segments = wal::wal_segments(wal_dir, db_id)?.into_iter().filter(|(seg_no, _)| *seg_no >= first_seg);
+    for ((seg_no, path), expected) in segments.zip(first_seg..) {
+        if seg_no != expected {
+            break;
+        }
+        let (plain, failed) = segment_plaintext(&path, seg_no, cipher)?;
+        let from = if seg_no == first_seg { offset as usize } else { 0 };
+        decoder.feed(plain.get(from..).unwrap_or_default());
+        match decoder.next_record() {
+            Decoded::Record(record) => return Ok(Some(record)),
+            Decoded::Invalid { .. } => return Ok(None),
+            Decoded::NeedMore => {}
+        }
+        if failed || plain.len() < WAL_SEGMENT_SIZE as usize {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// Segments from the one holding the recovery start onwards.
+fn scanned_segments(wal_dir: &Path, db_id: u32, origin: Lsn) -> Result<Vec<(u64, PathBuf)>, StorageError> {
+    let (start_seg, _) = wal::locate(recovery_start(wal_dir, db_id, origin)?);
+    Ok(wal::wal_segments(wal_dir, db_id)?
+        .into_iter()
+        .filter(|(seg_no, _)| *seg_no >= start_seg)
+        .collect())
+}
+
+/// Scans the database's WAL from its recovery start, validating every record, and cuts
+/// the log at the first one that is torn or invalid so recovery never replays garbage.
+/// Bytes past the cut are zeroed in preallocated segments and truncated away in others;
+/// later segments holding anything but zeros are removed. Returns `None` if the log was
+/// already clean.
+///
+/// Runs at mount, before any `CoreStorage` has the log open.
+pub fn truncate_torn_tail(wal_dir: &Path, db_id: u32, origin: Lsn, cipher: Option<&WalCipher>) -> Result<Option<TornTail>, StorageError> {
+    let segments = scanned_segments(wal_dir, db_id, origin)?;
+    // Without the segment holding the start there is nothing to scan from, and cutting
+    // on that basis would throw away the whole log.
+    let Some(scan) = scan(wal_dir, db_id, origin, cipher, &segments, &mut |_| Ok(()))? else {
+        return Ok(None);
+    };
+
+    let (end_seg, end_offset) = wal::locate(scan.end);
+    let mut discarded = 0;
+    for (seg_no, path) in &segments {
+        if *seg_no < end_seg {
+            continue;
+        }
+        if *seg_no > end_seg || end_offset == 0 {
+            let bytes = segment_physical(path)?;
+            let used = nonzero_len(&bytes);
+            // Preallocated future segments are all zeros and stay.
+            if used == 0 {
+                continue;
+            }
+            fs::remove_file(path).map_err(StorageError::Io)?;
+            let _ = fs::remove_file(wal_compress::compressed_segment_path(wal_dir, db_id, *seg_no));
+            wal_archive::clear(wal_dir, db_id, *seg_no);
+            discarded += used as u64;
+            continue;
+        }
+        if let Some((kept, cut)) = truncated_segment(path, *seg_no, end_offset, cipher)? {
+            write_durably(&wal::wal_segment_path(wal_dir, db_id, *seg_no), &kept)?;
+            if wal_compress::is_compressed_path(path) {
+                fs::remove_file(path).map_err(StorageError::Io)?;
+            }
+            discarded += cut;
+        }
+    }
+    if let Some(dir) = segments.first().and_then(|(_, path)| path.parent()) {
+        fs::File::open(dir).and_then(|d| d.sync_all()).map_err(StorageError::Io)?;
+    }
+
+    Ok((discarded > 0).then_some(TornTail {
+        db_id,
+        truncated_at: scan.end,
+        reason: scan.reason,
+        discarded_bytes: discarded,
+    }))
+}
+
+// Length of `bytes` without its trailing zeros.
+fn nonzero_len(bytes: &[u8]) -> usize {
+    bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1)
+}
+
+/// A segment's stored bytes, expanded if compressed.
+fn segment_physical(path: &Path) -> Result<Vec<u8>, StorageError> {
+    let bytes = fs::read(path).map_err(StorageError::Io)?;
+    if wal_compress::is_compressed_path(path) {
+        wal_compress::decompress_segment(&bytes, path)
+    } else {
+        Ok(bytes)
+    }
+}
+
+/// A segment's log bytes, and whether decryption stopped at a block that failed
+/// authentication (rather than at the end of the written blocks).
+fn segment_plaintext(path: &Path, seg_no: u64, cipher: Option<&WalCipher>) -> Result<(Vec<u8>, bool), StorageError> {
+    let mut physical = segment_physical(path)?;
+    match cipher {
+        None => Ok((physical, false)),
+        Some(cipher) => {
+            let (plain, failed) = cipher.open_blocks(seg_no, 0, &mut physical);
+            Ok((plain, failed == Some(BlockError::Unauthenticated)))
+        }
+    }
+}
+
+/// The stored bytes of a segment cut at log offset `offset`, and how many bytes that
+/// discards; `None` if nothing but zeros follows `offset`. An encrypted block that the
+/// cut splits is re-sealed with only its surviving bytes. A preallocated (full size)
+/// segment keeps its size, zero-filled past the cut.
+fn truncated_segment(path: &Path, seg_no: u64, offset: u64, cipher: Option<&WalCipher>) -> Result<Option<(Vec<u8>, u64)>, StorageError> {
+    let mut physical = segment_physical(path)?;
+    let full_len = physical.len();
+    let preallocated = full_len as u64 >= if cipher.is_some() { wal_crypt::ENCRYPTED_SEGMENT_SIZE } else { WAL_SEGMENT_SIZE };
+
+    let kept_len = match cipher {
+        None => {
+            let used = nonzero_len(&physical);
+            if used as u64 <= offset {
+                return Ok(None);
+            }
+            physical.truncate(offset as usize);
+            let cut = used as u64 - offset;
+            if preallocated {
+                physical.resize(full_len, 0);
+            }
+            return Ok(Some((physical, cut)));
+        }
+        Some(_) if offset.is_multiple_of(WAL_BLOCK_SIZE as u64) => wal_crypt::physical_block_offset(offset) as usize,
+        Some(_) => wal_crypt::physical_block_offset(offset) as usize + ENCRYPTED_BLOCK_SIZE,
+    };
+    let cipher = cipher.unwrap();
+
+    let extent = cipher.open_blocks(seg_no, 0, &mut physical.clone()).0.len();
+    let extent_len = wal_crypt::physical_block_offset(extent.next_multiple_of(WAL_BLOCK_SIZE) as u64) as usize;
+    let used = nonzero_len(&physical);
+    if extent as u64 == offset && used <= kept_len {
+        return Ok(None);
+    }
+    let cut = (extent as u64).saturating_sub(offset) + used.saturating_sub(extent_len) as u64;
+
+    let within = (offset % WAL_BLOCK_SIZE as u64) as usize;
+    if within > 0 {
+        let block = offset / WAL_BLOCK_SIZE as u64;
+        let start = kept_len - ENCRYPTED_BLOCK_SIZE;
+        let mut stored = physical[start..kept_len].to_vec();
+        let plain = cipher
+            .open_block(seg_no, block, &mut stored)
+            .map_err(|_| StorageError::Io(io::Error::new(io::ErrorKind::InvalidData, "WAL block failed authentication")))?
+            .to_vec();
+        cipher.seal_block(seg_no, block, &plain[..within], &mut physical[start..kept_len]);
+    }
+    physical.truncate(kept_len);
+    if preallocated {
+        physical.resize(full_len, 0);
+    }
+    Ok(Some((physical, cut)))
+}
+
+/// Replaces `path` with `bytes` via a synced temporary file.
+pub fn write_durably(path: &Path, bytes: &[u8]) -> Result<(), StorageError> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp).map_err(StorageError::Io)?;
+    file.write_all(bytes).map_err(StorageError::Io)?;
+    file.sync_all().map_err(StorageError::Io)?;
+    fs::rename(&tmp, path).map_err(StorageError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal_record::{encode_frame, WalRecordType};
+
+    /// A fresh, empty WAL dir under the system temp dir, unique per test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aquifer-wal-recovery-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("db_1")).unwrap();
+        dir
+    }
+
+    fn frame(log: &mut Vec<u8>, payload: &[u8]) -> Lsn {
+        let lsn = Lsn(log.len() as u64);
+        let (header, trailer) = encode_frame(WalRecordType::OPAQUE, payload);
+        log.extend_from_slice(&header);
+        log.extend_from_slice(payload);
+        log.extend_from_slice(&trailer);
+        lsn
+    }
+
+    #[test]
+    fn recovery_start_round_trips_and_defaults_to_zero() {
+        let dir = scratch_dir("start");
+        assert_eq!(recovery_start(&dir, 1, Lsn(0)).unwrap(), Lsn(0));
+        set_recovery_start(&dir, 1, Lsn(12345)).unwrap();
+        assert_eq!(recovery_start(&dir, 1, Lsn(0)).unwrap(), Lsn(12345));
+
+        // A damaged file is an error, not a silent restart from LSN 0.
+        fs::write(recovery_start_path(&dir, 1), [0xFFu8; 12]).unwrap();
+        assert!(recovery_start(&dir, 1, Lsn(0)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_clean_log_is_left_alone() {
+        let dir = scratch_dir("clean");
+        let mut log = Vec::new();
+        frame(&mut log, b"one");
+        frame(&mut log, b"two");
+        fs::write(wal::wal_segment_path(&dir, 1, 0), &log).unwrap();
+
+        assert_eq!(truncate_torn_tail(&dir, 1, Lsn(0), None).unwrap(), None);
+        assert_eq!(fs::read(wal::wal_segment_path(&dir, 1, 0)).unwrap(), log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_torn_tail_is_cut_and_later_segments_removed() {
+        let dir = scratch_dir("torn");
+        let mut log = Vec::new();
+        frame(&mut log, b"committed");
+        let end = log.len();
+        frame(&mut log, b"torn by a crash");
+        log.truncate(end + 5);
+        fs::write(wal::wal_segment_path(&dir, 1, 0), &log).unwrap();
+        // Not contiguous with the short segment 0, so it can't be part of the log.
+        fs::write(wal::wal_segment_path(&dir, 1, 1), b"stray").unwrap();
+
+        let torn = truncate_torn_tail(&dir, 1, Lsn(0), None).unwrap().unwrap();
+        assert_eq!(torn.truncated_at, Lsn(end as u64));
+        assert_eq!(torn.reason, "incomplete record");
+        assert_eq!(torn.discarded_bytes, 5 + 5);
+        assert_eq!(fs::read(wal::wal_segment_path(&dir, 1, 0)).unwrap(), &log[..end]);
+        assert!(!wal::wal_segment_path(&dir, 1, 1).exists());
+
+        // Running it again finds nothing more to do.
+        assert_eq!(truncate_torn_tail(&dir, 1, Lsn(0), None).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_scan_starts_at_the_recorded_boundary() {
+        let dir = scratch_dir("boundary");
+        let mut log = Vec::new();
+        frame(&mut log, b"before the boundary");
+        let start = frame(&mut log, b"after");
+        let end = log.len();
+        frame(&mut log, b"corrupt");
+        log[end + 9] ^= 0xFF;
+        fs::write(wal::wal_segment_path(&dir, 1, 0), &log).unwrap();
+        set_recovery_start(&dir, 1, start).unwrap();
+
+        let torn = truncate_torn_tail(&dir, 1, Lsn(0), None).unwrap().unwrap();
+        assert_eq!(torn.truncated_at, Lsn(end as u64));
+        assert_eq!(torn.reason, "record CRC mismatch");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
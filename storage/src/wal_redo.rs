@@ -0,0 +1,340 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::full_page::FullPageImage;
+use crate::page;
+use crate::partition::PagePartition;
+use crate::trace;
+use crate::traits::{Lsn, StorageError};
+use crate::undo::UndoScan;
+use crate::wal;
+use crate::wal_checkpoint::{self, CheckpointEnd};
+use crate::wal_crypt::{WalCipher, WalKey};
+use crate::wal_merge::MergedWalReader;
+use crate::wal_reader::WalReader;
+use crate::wal_recovery;
+use crate::wal_registry::{WalRecord, WalRegistry};
+
+// -----------------------------------------------------------------------------
+// Crash Recovery: Redo
+//
+// ARIES-style redo, in two halves.
+//
+// Analysis runs in `StorageManager::mount`, before any core is up, with blocking I/O.
+// For each database and each of its WAL streams, the checkpoint file names the last
+// CHECKPOINT_END record, and that record's redo LSN is where replay of the stream
+// starts: every change older than it is on disk. A stream that never checkpointed is
+// replayed from the start of its retained log.
+//
+// Redo runs on every core before it takes any work, through the core's buffer pool,
+// so pages of compressed or encrypted spaces are read and written as usual. Each core
+// reads the whole log from those starts, streams merged (see `wal_merge.rs`), and
+// replays the page records of the pages it owns: every page is redone on exactly one
+// core, and the cores work in parallel. A record is reapplied only if the page's LSN
+// is older than the record's, which means the page on disk is missing it; a page that
+// was written back after the change is left alone. Redo is therefore idempotent, and
+// a crash during recovery just starts it over. Page LSNs only order records of one
+// stream, which holds as long as pages keep their owning core (the number of cores
+// stays the same). Records other than page records go to `WalRegistry::dispatch`, on
+// every core.
+//
+// A page that fails its checksum was torn by a crash mid-write. Its records are
+// skipped until a FULL_PAGE_IMAGE of it (see `full_page.rs`), which is written over
+// it unread, and the records after that are replayed as usual. The image holds every
+// change skipped, and the page comes off the quarantine list (see `quarantine.rs`). A
+// torn page with no image later in the log fails recovery, or with `past_corruption`
+// stays quarantined, its records skipped.
+//
+// The same pass collects the transactions in flight at the crash, and once the log is
+// replayed each core rolls back their changes to its pages (see `undo.rs`). For that
+// the scan starts earlier than redo does, at the first record of the oldest
+// transaction active at the checkpoint; records before the redo start are only read.
+//
+// Redone pages stay dirty in the pool. The WAL covers them until the next checkpoint
+// writes them back.
+// -----------------------------------------------------------------------------
+
+/// Where redo of one of a database's WAL streams starts, as found at mount.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedoPlan {
+    pub db_id: u32,
+    /// Root of the stream, and its first LSN.
+    pub wal_dir: PathBuf,
+    pub origin: Lsn,
+    /// LSN of the last checkpoint's end record, if the stream has one.
+    pub checkpoint: Option<Lsn>,
+    /// The first record to replay.
+    pub start: Lsn,
+    /// The first record to read: `start`, or the first record of an older transaction
+    /// that may have to be rolled back.
+    pub scan_from: Lsn,
+}
+
+/// What one core's redo pass did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RedoSummary {
+    /// Records read from the log, other cores' pages included.
+    pub records: u64,
+    /// Page records of this core's pages reapplied, and those their page already had.
+    pub redone: u64,
+    pub skipped: u64,
+    /// Torn pages restored from a full-page image, and those left quarantined with no
+    /// image to restore them from.
+    pub restored: u64,
+    pub quarantined: usize,
+    /// Transactions in flight at the crash, and their changes to this core's pages
+    /// rolled back.
+    pub losers: usize,
+    pub undone: u64,
+}
+
+/// Finds where redo of the database's stream rooted at `wal_dir` starts. Runs at mount,
+/// after the torn tail has been cut off.
+pub fn plan(wal_dir: &Path, db_id: u32, origin: Lsn, cipher: Option<&WalCipher>) -> Result<RedoPlan, StorageError> {
+    let retained = wal_recovery::recovery_start(wal_dir, db_id, origin)?;
+    let Some(end_lsn) = wal_checkpoint::last_checkpoint(wal_dir, db_id)? else {
+        return Ok(RedoPlan {
+            db_id,
+            wal_dir: wal_dir.to_path_buf(),
+            origin,
+            checkpoint: None,
+            start: retained,
+            scan_from: retained,
+        });
+    };
+    let Some(record) = wal_recovery::read_record_at(wal_dir, db_id, end_lsn, cipher)? else {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("checkpoint record at {:?} of db {} is missing from the WAL", end_lsn, db_id),
+        )));
+    };
+    if record.record_type != CheckpointEnd::TYPE {
+        return Err(StorageError::UnknownWalRecord { lsn: end_lsn, record_type: record.record_type });
+    }
+    let end = CheckpointEnd::decode(&record.payload).ok_or(StorageError::MalformedWalRecord {
+        lsn: end_lsn,
+        record_type: record.record_type,
+    })?;
+    Ok(RedoPlan {
+        db_id,
+        wal_dir: wal_dir.to_path_buf(),
+        origin,
+        checkpoint: Some(end_lsn),
+        // Truncation keeps everything from the oldest needed LSN on.
+        start: end.redo_lsn().max(retained),
+        scan_from: end.oldest_needed_lsn().max(retained),
+    })
+}
+
+/// Replays the log from `plans` into the pages this core owns, then rolls back the
+/// changes of transactions left in flight to them. `keys` decrypt encrypted WALs;
+/// `past_corruption` skips torn pages with no image instead of failing. Call on every
+/// core before it serves any request.
+pub async fn redo(
+    partition: &PagePartition,
+    plans: &[RedoPlan],
+    keys: &HashMap<u32, WalKey>,
+    registry: &WalRegistry,
+    past_corruption: bool,
+) -> Result<RedoSummary, StorageError> {
+    let mut summary = RedoSummary::default();
+    let mut undo = UndoScan::default();
+    // Torn pages waiting for their image
+    let mut torn = HashSet::new();
+    let mut db_ids: Vec<u32> = plans.iter().map(|plan| plan.db_id).collect();
+    db_ids.sort_unstable();
+    db_ids.dedup();
+    for db_id in db_ids {
+        let mut readers = Vec::new();
+        // Where redo starts in each stream, by core
+        let mut redo_from = HashMap::new();
+        for plan in plans.iter().filter(|plan| plan.db_id == db_id) {
+            redo_from.insert(wal::lsn_core(plan.origin), plan.start);
+            let reader = WalReader::open(&plan.wal_dir, db_id, plan.scan_from).await?;
+            readers.push(match keys.get(&db_id) {
+                Some(key) => reader.decrypt_with(Rc::new(WalCipher::new(key, db_id, wal::lsn_core(plan.origin)))),
+                None => reader,
+            });
+        }
+        let mut log = MergedWalReader::new(readers);
+        while let Some(record) = log.next().await {
+            let record = record?;
+            summary.records += 1;
+            undo.observe(db_id, &record, |page_id| partition.is_local(page_id))?;
+            if redo_from.get(&wal::lsn_core(record.lsn)).is_some_and(|&start| record.lsn < start) {
+                continue;
+            }
+            let Some(change) = registry.page_change(&record)? else {
+                registry.dispatch(&record)?;
+                continue;
+            };
+            let page_id = change.page_id;
+            if !partition.is_local(page_id) {
+                continue;
+            }
+            let image = record.record_type == FullPageImage::TYPE;
+            if torn.contains(&page_id) && !image {
+                continue;
+            }
+            let mut page = match partition.pool().get_page_mut(page_id).await {
+                Ok(page) => page,
+                Err(StorageError::Corruption(corrupt)) if corrupt == page_id => {
+                    if !image {
+                        torn.insert(page_id);
+                        continue;
+                    }
+                    torn.remove(&page_id);
+                    summary.restored += 1;
+                    partition.pool().get_page_overwrite(page_id).await?
+                }
+                Err(e) => return Err(e),
+            };
+            // A never-written page has LSN 0 but none of the log's changes.
+            if !page::is_fresh(&page) && page::page_lsn(&page) >= record.lsn {
+                summary.skipped += 1;
+                continue;
+            }
+            change.apply(&mut page);
+            page.set_lsn(record.lsn);
+            summary.redone += 1;
+            // Read as zeros under `CorruptPagePolicy::Zero`, or torn: either way whole again.
+            if image {
+                partition.pool().store().release_quarantined(page_id)?;
+            }
+        }
+    }
+    match torn.iter().next() {
+        Some(&page_id) if !past_corruption => return Err(StorageError::Corruption(page_id)),
+        Some(_) => {
+            trace::warn_event!("redo: {} torn page(s) skipped and left quarantined: {:?}", torn.len(), torn);
+            summary.quarantined = torn.len();
+        }
+        None => {}
+    }
+    summary.losers = undo.losers().len();
+    summary.undone = undo.roll_back(partition).await?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_pool::BufferPool;
+    use crate::checksum::ChecksumAlgorithm;
+    use crate::core_storage::CoreStorage;
+    use crate::partition::PageRouter;
+    use crate::traits::{PageId, PageStore, StorageConfig, WalStore};
+    use crate::wal_record::WalRecordType;
+    use crate::wal_registry::PageRecord;
+
+    const PAGES: u32 = 6;
+
+    // Fills a page's body with one byte
+    struct Fill {
+        page_id: PageId,
+        value: u8,
+    }
+
+    impl WalRecord for Fill {
+        const TYPE: WalRecordType = WalRecordType(0x00FF);
+
+        fn encode(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&self.page_id.page_no.to_le_bytes());
+            out.push(self.value);
+        }
+
+        fn decode(payload: &[u8]) -> Option<Self> {
+            let page_no = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?);
+            Some(Self { page_id: page_id(page_no), value: *payload.get(4)? })
+        }
+    }
+
+    impl PageRecord for Fill {
+        fn page_id(&self) -> PageId {
+            self.page_id
+        }
+
+        fn redo(&self, page: &mut [u8]) {
+            page[page::PAGE_HEADER_SIZE..].fill(self.value);
+        }
+    }
+
+    fn page_id(page_no: u32) -> PageId {
+        PageId { db_id: 1, space_id: 1, page_no }
+    }
+
+    fn scratch_config(name: &str) -> StorageConfig {
+        let dir = std::env::temp_dir().join(format!("aquifer-wal-redo-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::create_dir_all(dir.join("wal")).unwrap();
+        StorageConfig::scratch(dir.join("data"), dir.join("wal"))
+    }
+
+    fn partition(config: &StorageConfig) -> PagePartition {
+        let storage = Rc::new(CoreStorage::new(config, 0));
+        let pool = BufferPool::new(storage, 16, 16, ChecksumAlgorithm::Crc32c, config.buffer_pool_eviction);
+        let (router, mut inboxes) = PageRouter::new(1);
+        PagePartition::new(0, Rc::new(pool), router, inboxes.remove(0))
+    }
+
+    fn registry() -> WalRegistry {
+        let mut registry = WalRegistry::new();
+        registry.register_page::<Fill>("test");
+        registry
+    }
+
+    #[test]
+    fn the_plan_starts_at_the_last_checkpoint() {
+        let config = scratch_config("plan");
+        tokio_uring::start(async {
+            let storage = CoreStorage::new(&config, 0);
+            storage.append_record(1, &Fill { page_id: page_id(0), value: 1 }).await.unwrap();
+            storage.flush_wal(1).await.unwrap();
+            let plan_before = plan(&config.wal_dir, 1, Lsn(0), None).unwrap();
+            assert_eq!(plan_before.checkpoint, None);
+            assert_eq!(plan_before.start, Lsn(0));
+
+            let begin_lsn = storage.begin_checkpoint(1).await.unwrap();
+            let end = CheckpointEnd { begin_lsn, dirty_pages: Vec::new(), active_txns: Vec::new() };
+            let end_lsn = storage.end_checkpoint(1, &end).await.unwrap();
+            let plan_after = plan(&config.wal_dir, 1, Lsn(0), None).unwrap();
+            assert_eq!(plan_after.checkpoint, Some(end_lsn));
+            assert_eq!(plan_after.start, end.redo_lsn());
+            assert!(plan_after.start > Lsn(0));
+        });
+    }
+
+    #[test]
+    fn redo_reapplies_only_changes_the_pages_are_missing() {
+        let config = scratch_config("replay");
+        tokio_uring::start(async {
+            // Log changes that never reach the pages, as if the process died right after.
+            let storage = CoreStorage::new(&config, 0);
+            storage.allocate_extent(1, 1, PAGES).await.unwrap();
+            for page_no in 0..PAGES {
+                storage.append_record(1, &Fill { page_id: page_id(page_no), value: page_no as u8 + 1 }).await.unwrap();
+            }
+            storage.flush_wal(1).await.unwrap();
+            drop(storage);
+
+            let plans = vec![plan(&config.wal_dir, 1, Lsn(0), None).unwrap()];
+            let partition = partition(&config);
+            let summary = redo(&partition, &plans, &HashMap::new(), &registry(), false).await.unwrap();
+            assert_eq!(summary, RedoSummary { records: PAGES as u64, redone: PAGES as u64, ..Default::default() });
+            for page_no in 0..PAGES {
+                let page = partition.pool().get_page(page_id(page_no)).await.unwrap();
+                assert!(page[page::PAGE_HEADER_SIZE..].iter().all(|&b| b == page_no as u8 + 1), "page {}", page_no);
+            }
+            partition.pool().flush_all().await.unwrap();
+
+            // A second crash before any checkpoint replays the same log over pages that
+            // already have every change.
+            let partition = self::partition(&config);
+            let summary = redo(&partition, &plans, &HashMap::new(), &registry(), false).await.unwrap();
+            assert_eq!(summary, RedoSummary { records: PAGES as u64, skipped: PAGES as u64, ..Default::default() });
+        });
+    }
+}
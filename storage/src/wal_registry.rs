@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use crate::catalog::{CatalogInit, CatalogLink, CatalogPut, CatalogRemove};
+use crate::full_page::FullPageImage;
+use crate::sequence::SequenceSet;
+use crate::traits::{Lsn, PageId, StorageError};
+use crate::undo::{Compensation, PageUpdate};
+use crate::wal_record::{self, WalRecordType};
+
+// -----------------------------------------------------------------------------
+// WAL Record Types
+//
+// Every subsystem that logs redo defines its records as types implementing
+// `WalRecord`: a type tag from its own range, and the payload's byte format. The
+// framing (length, type, CRC) stays in `wal_record.rs`; only the payload is theirs.
+//
+//   0x0001 - 0x00FF    storage engine (opaque, commit, checkpoint, catalog, ...)
+//   0x0100 - 0x01FF    transactions
+//   0x0200 - 0x02FF    heap
+//   0x0300 - 0x03FF    B-tree
+//   0x0400 - 0x04FF    free space and visibility maps
+//   0x0500 - 0x05FF    LSM trees
+//   0x0600 - 0x06FF    blob store
+//   0x0700 - 0x07FF    hash indexes
+//
+// At recovery every record is handed to the subsystem that registered its type with a
+// `WalRegistry`. A record nobody registered stops recovery: skipping it would silently
+// lose a change. Records changing a single page implement `PageRecord` as well, and
+// are replayed by the redo pass against the page itself (see `wal_redo.rs`), only if
+// the page doesn't have the change yet.
+// -----------------------------------------------------------------------------
+
+/// A record type with its own payload format.
+pub trait WalRecord: Sized {
+    /// Tag written in the frame header. Must be unique across subsystems.
+    const TYPE: WalRecordType;
+
+    /// Appends the payload bytes to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Parses a payload written by `encode`; `None` if it is malformed.
+    fn decode(payload: &[u8]) -> Option<Self>;
+
+    /// The encoded payload on its own.
+    fn to_payload(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+/// A record type that changes one page: what redo reapplies to it.
+pub trait PageRecord: WalRecord {
+    /// The page the record changes.
+    fn page_id(&self) -> PageId;
+
+    /// Applies the change to the page as it was before the record was logged. Redo
+    /// stamps the record's LSN on the page afterwards.
+    fn redo(&self, page: &mut [u8]);
+}
+
+type ChangeFn = Box<dyn FnOnce(&mut [u8])>;
+
+/// A replayed page record, decoded and ready to reapply.
+pub struct PageChange {
+    pub page_id: PageId,
+    redo: ChangeFn,
+}
+
+impl PageChange {
+    pub fn apply(self, page: &mut [u8]) {
+        (self.redo)(page)
+    }
+}
+
+type RedoFn = Box<dyn Fn(Lsn, &[u8]) -> Result<(), StorageError>>;
+type PageRedoFn = Box<dyn Fn(Lsn, &[u8]) -> Result<PageChange, StorageError>>;
+
+enum Redo {
+    // Commit markers and the like
+    Nothing,
+    Logical(RedoFn),
+    Page(PageRedoFn),
+}
+
+struct Registration {
+    owner: &'static str,
+    redo: Redo,
+}
+
+/// Routes replayed records to the subsystem that owns their type.
+///
+/// Built once per core before recovery starts. Redo handlers run synchronously in log
+/// order; one that needs I/O should queue it and let the caller drive it between records.
+pub struct WalRegistry {
+    types: HashMap<WalRecordType, Registration>,
+}
+
+impl WalRegistry {
+    /// A registry holding the storage engine's own record types.
+    pub fn new() -> Self {
+        let mut registry = Self { types: HashMap::new() };
+        registry.register_no_redo(WalRecordType::OPAQUE, "storage");
+        // Commit timestamps are rebuilt from the log separately (`rebuild_commit_timestamps`).
+        registry.register_no_redo(WalRecordType::COMMIT, "storage");
+        // Read before the scan to pick its start, not replayed.
+        registry.register_no_redo(WalRecordType::CHECKPOINT_BEGIN, "storage");
+        registry.register_no_redo(WalRecordType::CHECKPOINT_END, "storage");
+        registry.register_page::<PageUpdate>("storage");
+        registry.register_page::<Compensation>("storage");
+        // Rollbacks are finished by the losers pass of recovery, not by redo.
+        registry.register_no_redo(WalRecordType::ABORT, "storage");
+        registry.register_page::<FullPageImage>("storage");
+        registry.register_page::<CatalogInit>("storage");
+        registry.register_page::<CatalogPut>("storage");
+        registry.register_page::<CatalogRemove>("storage");
+        registry.register_page::<CatalogLink>("storage");
+        registry.register_page::<SequenceSet>("storage");
+        registry
+    }
+
+    /// Registers the redo handler for records of type `R`, owned by subsystem `owner`.
+    /// Panics if another subsystem already claimed the type.
+    pub fn register<R: WalRecord + 'static>(&mut self, owner: &'static str, redo: impl Fn(Lsn, R) -> Result<(), StorageError> + 'static) {
+        let decode_and_redo = move |lsn: Lsn, payload: &[u8]| {
+            let record = R::decode(payload).ok_or(StorageError::MalformedWalRecord { lsn, record_type: R::TYPE })?;
+            redo(lsn, record)
+        };
+        self.insert(R::TYPE, owner, Redo::Logical(Box::new(decode_and_redo)));
+    }
+
+    /// Registers page record type `R`, owned by subsystem `owner`, for the redo pass.
+    /// Panics if another subsystem already claimed the type.
+    pub fn register_page<R: PageRecord + 'static>(&mut self, owner: &'static str) {
+        let decode = |lsn: Lsn, payload: &[u8]| {
+            let record = R::decode(payload).ok_or(StorageError::MalformedWalRecord { lsn, record_type: R::TYPE })?;
+            Ok(PageChange {
+                page_id: record.page_id(),
+                redo: Box::new(move |page: &mut [u8]| record.redo(page)),
+            })
+        };
+        self.insert(R::TYPE, owner, Redo::Page(Box::new(decode)));
+    }
+
+    /// Claims a record type that recovery skips: records with nothing to redo, such as
+    /// commit markers or opaque test payloads.
+    pub fn register_no_redo(&mut self, record_type: WalRecordType, owner: &'static str) {
+        self.insert(record_type, owner, Redo::Nothing);
+    }
+
+    fn insert(&mut self, record_type: WalRecordType, owner: &'static str, redo: Redo) {
+        if let Some(existing) = self.types.get(&record_type) {
+            panic!("WAL record type {:#06x} registered by both {} and {}", record_type.0, existing.owner, owner);
+        }
+        self.types.insert(record_type, Registration { owner, redo });
+    }
+
+    /// The subsystem that registered `record_type`, for diagnostics and log dumps.
+    pub fn owner(&self, record_type: WalRecordType) -> Option<&'static str> {
+        self.types.get(&record_type).map(|registration| registration.owner)
+    }
+
+    /// Hands one replayed record to its owner's redo handler. Page records are left to
+    /// `page_change`.
+    pub fn dispatch(&self, record: &wal_record::WalRecord) -> Result<(), StorageError> {
+        match &self.registration(record)?.redo {
+            Redo::Logical(redo) => redo(record.lsn, &record.payload),
+            Redo::Nothing | Redo::Page(_) => Ok(()),
+        }
+    }
+
+    /// The change a replayed page record makes; `None` for any other record.
+    pub fn page_change(&self, record: &wal_record::WalRecord) -> Result<Option<PageChange>, StorageError> {
+        match &self.registration(record)?.redo {
+            Redo::Page(decode) => decode(record.lsn, &record.payload).map(Some),
+            Redo::Nothing | Redo::Logical(_) => Ok(None),
+        }
+    }
+
+    fn registration(&self, record: &wal_record::WalRecord) -> Result<&Registration, StorageError> {
+        self.types.get(&record.record_type).ok_or(StorageError::UnknownWalRecord {
+            lsn: record.lsn,
+            record_type: record.record_type,
+        })
+    }
+}
+
+impl Default for WalRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
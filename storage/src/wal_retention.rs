@@ -0,0 +1,109 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::checksum;
+use crate::traits::{Lsn, StorageError};
+use crate::wal_recovery;
+
+// -----------------------------------------------------------------------------
+// Replication Slots
+//
+// A slot pins the WAL a consumer (a standby, a logical decoder) still has to read:
+//
+//   wal_dir/db_<id>/slots/<name>      restart LSN (u64 LE) | CRC32C (u32 LE)
+//
+// `truncate_wal` never removes a segment at or past the oldest restart LSN, so a
+// consumer that disconnects can resume where it left off. Slots survive restarts; an
+// abandoned one holds WAL forever and must be dropped.
+// -----------------------------------------------------------------------------
+
+fn slots_dir(wal_dir: &Path, db_id: u32) -> PathBuf {
+    wal_dir.join(format!("db_{}", db_id)).join("slots")
+}
+
+fn slot_path(wal_dir: &Path, db_id: u32, name: &str) -> Result<PathBuf, StorageError> {
+    let valid = !name.is_empty() && name.len() <= 64 && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+    if !valid {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid replication slot name {:?}", name),
+        )));
+    }
+    Ok(slots_dir(wal_dir, db_id).join(name))
+}
+
+/// Creates slot `name` holding the WAL from `restart_lsn` on. Fails if it exists.
+pub fn create_slot(wal_dir: &Path, db_id: u32, name: &str, restart_lsn: Lsn) -> Result<(), StorageError> {
+    let path = slot_path(wal_dir, db_id, name)?;
+    if path.exists() {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("replication slot {} of db {} already exists", name, db_id),
+        )));
+    }
+    write_slot(&path, restart_lsn)
+}
+
+/// Moves slot `name` forward to `restart_lsn`: its consumer no longer needs the WAL
+/// before it. A slot never moves backwards.
+pub fn advance_slot(wal_dir: &Path, db_id: u32, name: &str, restart_lsn: Lsn) -> Result<(), StorageError> {
+    let path = slot_path(wal_dir, db_id, name)?;
+    if restart_lsn > read_slot(&path)? {
+        write_slot(&path, restart_lsn)?;
+    }
+    Ok(())
+}
+
+/// Removes slot `name`, releasing the WAL it held.
+pub fn drop_slot(wal_dir: &Path, db_id: u32, name: &str) -> Result<(), StorageError> {
+    let path = slot_path(wal_dir, db_id, name)?;
+    fs::remove_file(&path).map_err(StorageError::Io)?;
+    fs::File::open(path.parent().unwrap()).and_then(|d| d.sync_all()).map_err(StorageError::Io)
+}
+
+/// The database's slots with their restart LSNs, ordered by name.
+pub fn slots(wal_dir: &Path, db_id: u32) -> Result<Vec<(String, Lsn)>, StorageError> {
+    let entries = match fs::read_dir(slots_dir(wal_dir, db_id)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+    let mut slots = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(StorageError::Io)?;
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        // Leftover of an interrupted update.
+        if name.ends_with(".tmp") {
+            continue;
+        }
+        slots.push((name, read_slot(&entry.path())?));
+    }
+    slots.sort_unstable();
+    Ok(slots)
+}
+
+fn read_slot(path: &Path) -> Result<Lsn, StorageError> {
+    let bytes = fs::read(path).map_err(StorageError::Io)?;
+    if bytes.len() != 12 || checksum::crc32c(&bytes[..8]) != u32::from_le_bytes(bytes[8..12].try_into().unwrap()) {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("corrupt replication slot file {}", path.display()),
+        )));
+    }
+    Ok(Lsn(u64::from_le_bytes(bytes[..8].try_into().unwrap())))
+}
+
+fn write_slot(path: &Path, restart_lsn: Lsn) -> Result<(), StorageError> {
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir).map_err(StorageError::Io)?;
+
+    let mut bytes = [0u8; 12];
+    bytes[..8].copy_from_slice(&restart_lsn.0.to_le_bytes());
+    let crc = checksum::crc32c(&bytes[..8]);
+    bytes[8..].copy_from_slice(&crc.to_le_bytes());
+    wal_recovery::write_durably(path, &bytes)?;
+    fs::File::open(dir).and_then(|d| d.sync_all()).map_err(StorageError::Io)
+}
@@ -0,0 +1,237 @@
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use tokio_uring::net::{TcpStream, UnixStream};
+
+use crate::commit_ts::CommitTimestamp;
+use crate::core_storage::CoreStorage;
+use crate::traits::{Lsn, StorageError};
+use crate::wal;
+use crate::wal_record::WalRecord;
+
+// -----------------------------------------------------------------------------
+// Replication Protocol
+//
+// Standby -> primary, once:   "CWAL" | db_id u32 | start_lsn u64
+// Primary -> standby:         'R' | lsn u64 | framed record (as in the WAL, CRC included)
+//                             'K' | flushed_lsn u64 | now_micros u64      (keepalive)
+// Standby -> primary:         'F' | received_lsn u64                      (feedback)
+//
+// All integers little-endian. Only durable (flushed) records are sent, so a standby
+// never gets ahead of what the primary could recover after a crash.
+// -----------------------------------------------------------------------------
+
+pub const START_MAGIC: [u8; 4] = *b"CWAL";
+pub const MSG_RECORD: u8 = b'R';
+pub const MSG_KEEPALIVE: u8 = b'K';
+pub const MSG_FEEDBACK: u8 = b'F';
+
+const START_LEN: usize = 16;
+const FEEDBACK_LEN: usize = 9;
+
+#[derive(Debug, Clone)]
+pub struct WalSenderConfig {
+    /// Send a keepalive after this long without sending anything.
+    pub keepalive_interval: Duration,
+    /// Drop the standby after this long without feedback.
+    pub standby_timeout: Duration,
+    /// Flow control: stop sending while this many bytes are unacknowledged.
+    pub max_unacked_bytes: u64,
+    /// How often to look for newly flushed WAL once caught up.
+    pub poll_interval: Duration,
+    /// Replication slot advanced as the standby acknowledges WAL, so the primary keeps
+    /// whatever it hasn't received while it is disconnected.
+    pub slot: Option<String>,
+}
+
+impl Default for WalSenderConfig {
+    fn default() -> Self {
+        Self {
+            keepalive_interval: Duration::from_secs(10),
+            standby_timeout: Duration::from_secs(60),
+            max_unacked_bytes: 16 << 20,
+            poll_interval: Duration::from_millis(5),
+            slot: None,
+        }
+    }
+}
+
+/// The byte-stream sockets a standby can connect over.
+pub trait ReplicationConn {
+    async fn read(&self, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>);
+    async fn write_all(&self, buf: Vec<u8>) -> (io::Result<()>, Vec<u8>);
+    fn shutdown(&self);
+}
+
+impl ReplicationConn for TcpStream {
+    async fn read(&self, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        TcpStream::read(self, buf).await
+    }
+    async fn write_all(&self, buf: Vec<u8>) -> (io::Result<()>, Vec<u8>) {
+        TcpStream::write_all(self, buf).await
+    }
+    fn shutdown(&self) {
+        let _ = TcpStream::shutdown(self, std::net::Shutdown::Both);
+    }
+}
+
+impl ReplicationConn for UnixStream {
+    async fn read(&self, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        UnixStream::read(self, buf).await
+    }
+    async fn write_all(&self, buf: Vec<u8>) -> (io::Result<()>, Vec<u8>) {
+        UnixStream::write_all(self, buf).await
+    }
+    fn shutdown(&self) {
+        let _ = UnixStream::shutdown(self, std::net::Shutdown::Both);
+    }
+}
+
+// Updated by the feedback task, read by the sender loop.
+struct Feedback {
+    acked: Cell<u64>,
+    last_heard: Cell<Instant>,
+    closed: Cell<bool>,
+}
+
+/// Primary-side half of physical replication: tails one database's WAL from the LSN a
+/// standby asks for and streams flushed records to it.
+pub struct WalSender<'a> {
+    storage: &'a CoreStorage,
+    config: WalSenderConfig,
+}
+
+impl<'a> WalSender<'a> {
+    pub fn new(storage: &'a CoreStorage, config: WalSenderConfig) -> Self {
+        Self { storage, config }
+    }
+
+    /// Serves one standby until it disconnects or times out. Must run on the core that
+    /// owns the database's WAL.
+    pub async fn serve<C: ReplicationConn + 'static>(&self, conn: C) -> Result<(), StorageError> {
+        let conn = Rc::new(conn);
+        let start = read_exact(conn.as_ref(), START_LEN).await.map_err(StorageError::Io)?;
+        if start[0..4] != START_MAGIC {
+            conn.shutdown();
+            return Err(StorageError::Io(io::Error::new(io::ErrorKind::InvalidData, "bad replication handshake")));
+        }
+        let db_id = u32::from_le_bytes(start[4..8].try_into().unwrap());
+        let start_lsn = Lsn(u64::from_le_bytes(start[8..16].try_into().unwrap()));
+
+        let feedback = Rc::new(Feedback {
+            acked: Cell::new(start_lsn.0),
+            last_heard: Cell::new(Instant::now()),
+            closed: Cell::new(false),
+        });
+        tokio_uring::spawn(read_feedback(Rc::clone(&conn), Rc::clone(&feedback)));
+
+        let res = self.stream(conn.as_ref(), &feedback, db_id, start_lsn).await;
+        conn.shutdown();
+        res
+    }
+
+    async fn stream<C: ReplicationConn>(&self, conn: &C, feedback: &Feedback, db_id: u32, start_lsn: Lsn) -> Result<(), StorageError> {
+        let mut reader = self.storage.wal_reader(db_id, start_lsn).await?;
+        let mut pending: Option<WalRecord> = None;
+        let mut sent_lsn = start_lsn.0;
+        let mut last_sent = Instant::now();
+        let mut slot_lsn = start_lsn;
+
+        loop {
+            if feedback.closed.get() {
+                return Ok(());
+            }
+            if feedback.last_heard.get().elapsed() > self.config.standby_timeout {
+                return Err(StorageError::Io(io::ErrorKind::TimedOut.into()));
+            }
+
+            // Segments are retained whole, so the slot only needs to move per segment.
+            if let Some(slot) = &self.config.slot {
+                let acked = Lsn(feedback.acked.get());
+                if wal::locate(acked).0 > wal::locate(slot_lsn).0 {
+                    self.storage.advance_replication_slot(db_id, slot, acked)?;
+                    slot_lsn = acked;
+                }
+            }
+
+            let window_open = sent_lsn - feedback.acked.get().min(sent_lsn) < self.config.max_unacked_bytes;
+            if window_open {
+                if pending.is_none() {
+                    pending = match reader.next().await {
+                        Some(record) => Some(record?),
+                        None => {
+                            // Caught up (or hit a block still being written): resume from
+                            // the last good record on the next poll.
+                            reader = self.storage.wal_reader(db_id, reader.position()).await?;
+                            None
+                        }
+                    };
+                }
+                let flushed = self.storage.wal_flushed_lsn(db_id)?;
+                if let Some(record) = pending.take_if(|record| record.end_lsn() <= flushed) {
+                    sent_lsn = record.end_lsn().0;
+                    send(conn, encode_record(&record)).await?;
+                    last_sent = Instant::now();
+                    continue;
+                }
+            }
+
+            if last_sent.elapsed() >= self.config.keepalive_interval {
+                send(conn, encode_keepalive(self.storage.wal_flushed_lsn(db_id)?)).await?;
+                last_sent = Instant::now();
+            }
+            tokio::time::sleep(self.config.poll_interval).await;
+        }
+    }
+}
+
+async fn read_feedback<C: ReplicationConn>(conn: Rc<C>, feedback: Rc<Feedback>) {
+    loop {
+        match read_exact(conn.as_ref(), FEEDBACK_LEN).await {
+            Ok(msg) if msg[0] == MSG_FEEDBACK => {
+                let lsn = u64::from_le_bytes(msg[1..9].try_into().unwrap());
+                feedback.acked.set(feedback.acked.get().max(lsn));
+                feedback.last_heard.set(Instant::now());
+            }
+            _ => {
+                feedback.closed.set(true);
+                return;
+            }
+        }
+    }
+}
+
+async fn read_exact<C: ReplicationConn>(conn: &C, len: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        let (res, buf) = conn.read(vec![0u8; len - out.len()]).await;
+        match res? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => out.extend_from_slice(&buf[..n]),
+        }
+    }
+    Ok(out)
+}
+
+async fn send<C: ReplicationConn>(conn: &C, msg: Vec<u8>) -> Result<(), StorageError> {
+    let (res, _) = conn.write_all(msg).await;
+    res.map_err(StorageError::Io)
+}
+
+fn encode_record(record: &WalRecord) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(9 + record.framed_len());
+    msg.push(MSG_RECORD);
+    msg.extend_from_slice(&record.lsn.0.to_le_bytes());
+    msg.extend_from_slice(&record.encode());
+    msg
+}
+
+fn encode_keepalive(flushed: Lsn) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(17);
+    msg.push(MSG_KEEPALIVE);
+    msg.extend_from_slice(&flushed.0.to_le_bytes());
+    msg.extend_from_slice(&CommitTimestamp::now().0.to_le_bytes());
+    msg
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::checksum;
+use crate::trace;
+use crate::traits::{PageId, StorageError};
+use crate::wal_recovery;
+
+// -----------------------------------------------------------------------------
+// Buffer Pool Warm-up
+//
+// On a clean shutdown each core records which pages its buffer pool holds (ids only,
+// not contents), so the next mount can read them back in bulk instead of taking hours
+// of misses to rebuild the working set:
+//
+//   data_dir/buffer_pool/core_<id>.pages   count (u32 LE)
+//                                          count x (db_id, space_id, page_no) (u32 LE)
+//                                          CRC32C of the above (u32 LE)
+//
+// Pages are sorted, so neighbours coalesce into `read_pages` runs on reload. Every core
+// reads every file and keeps the pages it owns, so a dump survives a change in the
+// number of cores. The list is only a hint: a stale one (after a crash, or pages since
+// freed) costs some wasted reads, and a corrupt file is skipped.
+// -----------------------------------------------------------------------------
+
+fn dump_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("buffer_pool")
+}
+
+pub fn dump_path(data_dir: &Path, core_id: usize) -> PathBuf {
+    dump_dir(data_dir).join(format!("core_{}.pages", core_id))
+}
+
+/// Records `pages` as the resident set of `core_id`, replacing its previous dump.
+pub fn save(data_dir: &Path, core_id: usize, pages: &[PageId]) -> Result<(), StorageError> {
+    let dir = dump_dir(data_dir);
+    fs::create_dir_all(&dir).map_err(StorageError::Io)?;
+
+    wal_recovery::write_durably(&dump_path(data_dir, core_id), &encode(pages))?;
+    fs::File::open(&dir).and_then(|d| d.sync_all()).map_err(StorageError::Io)
+}
+
+/// Every core's dumped pages, sorted, without duplicates. Empty without any dump.
+pub fn load(data_dir: &Path) -> Result<Vec<PageId>, StorageError> {
+    let entries = match fs::read_dir(dump_dir(data_dir)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StorageError::Io(e)),
+    };
+    let mut pages = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(StorageError::Io)?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("pages") {
+            continue;
+        }
+        let bytes = fs::read(&path).map_err(StorageError::Io)?;
+        match decode(&bytes) {
+            Some(dumped) => pages.extend(dumped),
+            None => trace::warn_event!("buffer pool: skipping corrupt warm-up file {}", path.display()),
+        }
+    }
+    pages.sort_by_key(|page_id| (page_id.db_id, page_id.space_id, page_id.page_no));
+    pages.dedup();
+    Ok(pages)
+}
+
+/// `pages` sorted, in the format of a dump file.
+pub fn encode(pages: &[PageId]) -> Vec<u8> {
+    let mut sorted = pages.to_vec();
+    sorted.sort_by_key(|page_id| (page_id.db_id, page_id.space_id, page_id.page_no));
+    let mut bytes = Vec::with_capacity(8 + sorted.len() * 12);
+    bytes.extend_from_slice(&(sorted.len() as u32).to_le_bytes());
+    for page_id in &sorted {
+        for part in [page_id.db_id, page_id.space_id, page_id.page_no] {
+            bytes.extend_from_slice(&part.to_le_bytes());
+        }
+    }
+    let crc = checksum::crc32c(&bytes);
+    bytes.extend_from_slice(&crc.to_le_bytes());
+    bytes
+}
+
+/// The pages of a dump file, or `None` if it is corrupt.
+pub fn decode(bytes: &[u8]) -> Option<Vec<PageId>> {
+    let count = u32::from_le_bytes(bytes.get(..4)?.try_into().unwrap()) as usize;
+    let body_len = 4 + count.checked_mul(12)?;
+    if bytes.len() != body_len + 4 {
+        return None;
+    }
+    let crc = u32::from_le_bytes(bytes[body_len..].try_into().unwrap());
+    if checksum::crc32c(&bytes[..body_len]) != crc {
+        return None;
+    }
+    let field = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+    Some(
+        (0..count)
+            .map(|i| {
+                let at = 4 + i * 12;
+                PageId {
+                    db_id: field(at),
+                    space_id: field(at + 4),
+                    page_no: field(at + 8),
+                }
+            })
+            .collect(),
+    )
+}
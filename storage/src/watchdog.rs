@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::trace;
+use crate::traits::PageId;
+
+#[derive(Debug, Clone)]
+pub struct WatchdogConfig {
+    /// How often each core's event loop records a heartbeat, and how often the
+    /// watchdog thread inspects the cores.
+    pub interval: Duration,
+    /// An operation in flight, or a heartbeat missing, for longer than this is a stall.
+    pub stall_threshold: Duration,
+    /// Mark a stalled core unhealthy so `CoreHealth::is_healthy` fails health checks
+    /// until it recovers. When false, stalls are only reported.
+    pub fail_health_checks: bool,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            stall_threshold: Duration::from_secs(10),
+            fail_health_checks: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpKind {
+    Read,
+    Write,
+    ReadVectored,
+    WriteVectored,
+    Fallocate,
+    Sync,
+}
+
+impl OpKind {
+    /// Name used in warnings and log fields.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OpKind::Read => "read",
+            OpKind::Write => "write",
+            OpKind::ReadVectored => "read_vectored",
+            OpKind::WriteVectored => "write_vectored",
+            OpKind::Fallocate => "fallocate",
+            OpKind::Sync => "sync",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InFlightOp {
+    pub kind: OpKind,
+    pub page_id: Option<PageId>,
+    /// Byte offset in the file, where the operation has one.
+    pub offset: Option<u64>,
+    pub started: Instant,
+}
+
+/// Liveness state of one core, shared between its (thread-local) event loop and the
+/// watchdog thread. Everything here is touched from both sides, hence atomics and a
+/// mutex the watchdog only holds for a snapshot.
+pub struct CoreHealth {
+    core_id: usize,
+    epoch: Instant,
+    // Milliseconds since `epoch` of the last heartbeat; 0 until the loop first ticks.
+    last_tick_ms: AtomicU64,
+    next_op: AtomicU64,
+    in_flight: Mutex<HashMap<u64, InFlightOp>>,
+    healthy: AtomicBool,
+}
+
+impl CoreHealth {
+    pub fn new(core_id: usize) -> Arc<Self> {
+        Arc::new(Self {
+            core_id,
+            epoch: Instant::now(),
+            last_tick_ms: AtomicU64::new(0),
+            next_op: AtomicU64::new(0),
+            in_flight: Mutex::new(HashMap::new()),
+            healthy: AtomicBool::new(true),
+        })
+    }
+
+    pub fn core_id(&self) -> usize {
+        self.core_id
+    }
+
+    /// Records that the event loop is making progress.
+    pub fn tick(&self) {
+        let ms = self.epoch.elapsed().as_millis() as u64;
+        self.last_tick_ms.store(ms.max(1), Ordering::Relaxed);
+    }
+
+    /// Registers an I/O submitted to the ring; it stays registered until the guard drops.
+    pub fn begin(self: &Arc<Self>, kind: OpKind, page_id: Option<PageId>) -> OpGuard {
+        self.begin_at(kind, page_id, None)
+    }
+
+    /// `begin` for an I/O at a known file offset, reported along with it.
+    pub fn begin_at(self: &Arc<Self>, kind: OpKind, page_id: Option<PageId>, offset: Option<u64>) -> OpGuard {
+        let id = self.next_op.fetch_add(1, Ordering::Relaxed);
+        let op = InFlightOp {
+            kind,
+            page_id,
+            offset,
+            started: Instant::now(),
+        };
+        self.in_flight.lock().unwrap().insert(id, op);
+        OpGuard {
+            health: Arc::clone(self),
+            id,
+        }
+    }
+
+    /// I/Os registered and not yet completed.
+    pub fn queue_depth(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+
+    /// False while the watchdog considers this core stalled (and is configured to fail
+    /// health checks).
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Inspects the core, returning a report if it is stalled.
+    pub fn check(&self, stall_threshold: Duration) -> Option<StallReport> {
+        let now = Instant::now();
+        let last_tick = self.last_tick_ms.load(Ordering::Relaxed);
+        // Before the first heartbeat the loop may simply not be running yet.
+        let loop_stalled_for = (last_tick != 0)
+            .then(|| now.duration_since(self.epoch) - Duration::from_millis(last_tick))
+            .filter(|since| *since > stall_threshold);
+
+        let mut hung_ops: Vec<InFlightOp> = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|op| now.duration_since(op.started) > stall_threshold)
+            .copied()
+            .collect();
+        hung_ops.sort_by_key(|op| op.started);
+
+        if loop_stalled_for.is_none() && hung_ops.is_empty() {
+            return None;
+        }
+        Some(StallReport {
+            core_id: self.core_id,
+            at: now,
+            loop_stalled_for,
+            hung_ops,
+        })
+    }
+}
+
+/// Deregisters an in-flight operation when the awaiting future completes or is dropped.
+pub struct OpGuard {
+    health: Arc<CoreHealth>,
+    id: u64,
+}
+
+impl OpGuard {
+    /// Deregisters the operation now it has completed, returning it.
+    pub fn finish(self) -> InFlightOp {
+        self.health.in_flight.lock().unwrap().remove(&self.id).expect("operation registered until its guard drops")
+    }
+}
+
+impl Drop for OpGuard {
+    fn drop(&mut self) {
+        self.health.in_flight.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// An I/O that completed, but took longer than the slow-I/O threshold (see
+/// `StorageConfig::slow_io_threshold`). Displays as `key=value` pairs, for log search.
+#[derive(Debug, Clone, Copy)]
+pub struct SlowIo {
+    pub core_id: usize,
+    pub kind: OpKind,
+    pub page_id: Option<PageId>,
+    pub offset: Option<u64>,
+    pub duration: Duration,
+    /// Other I/Os of the core in flight when this one completed.
+    pub queue_depth: usize,
+}
+
+impl SlowIo {
+    /// Logs the warning as a `tracing` event, with the `tracing` feature; a no-op without it.
+    pub fn warn(&self) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            core = self.core_id,
+            op = self.kind.as_str(),
+            db_id = self.page_id.map(|page_id| page_id.db_id),
+            space_id = self.page_id.map(|page_id| page_id.space_id),
+            page_no = self.page_id.map(|page_id| page_id.page_no),
+            offset = self.offset,
+            duration_us = self.duration.as_micros() as u64,
+            queue_depth = self.queue_depth,
+            "slow I/O"
+        );
+    }
+}
+
+impl fmt::Display for SlowIo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "slow I/O core={} op={}", self.core_id, self.kind.as_str())?;
+        if let Some(page_id) = self.page_id {
+            write!(f, " db={} space={} page={}", page_id.db_id, page_id.space_id, page_id.page_no)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " offset={}", offset)?;
+        }
+        write!(f, " duration_ms={:.3} queue_depth={}", self.duration.as_secs_f64() * 1e3, self.queue_depth)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StallReport {
+    pub core_id: usize,
+    pub at: Instant,
+    /// Time since the event loop last ticked, if that exceeds the threshold.
+    pub loop_stalled_for: Option<Duration>,
+    /// Operations outstanding beyond the threshold, oldest first.
+    pub hung_ops: Vec<InFlightOp>,
+}
+
+impl fmt::Display for StallReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "core {} stalled", self.core_id)?;
+        if let Some(since) = self.loop_stalled_for {
+            write!(f, "; event loop has not ticked for {:?}", since)?;
+        }
+        write!(f, "; {} hung operation(s)", self.hung_ops.len())?;
+        for op in &self.hung_ops {
+            write!(f, "\n  {:?} outstanding for {:?}", op.kind, self.at.duration_since(op.started))?;
+            if let Some(page_id) = op.page_id {
+                write!(f, " (db {} space {} page {})", page_id.db_id, page_id.space_id, page_id.page_no)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Spawns the heartbeat task on the calling core's runtime. It only ticks when the event
+/// loop actually polls it, so a loop blocked in a syscall or spinning in a task stops ticking.
+pub fn spawn_heartbeat(health: Arc<CoreHealth>, interval: Duration) {
+    tokio_uring::spawn(async move {
+        loop {
+            health.tick();
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+/// Receives the report of a stalled core, on the watchdog's thread.
+pub type StallHandler = Box<dyn Fn(&StallReport) + Send>;
+
+/// Background thread that periodically checks every core. It has to live outside the
+/// cores it watches: a stalled event loop can't report on itself.
+pub struct Watchdog {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Starts watching `cores`. `on_stall` receives a report on every check that finds a
+    /// core stalled; `None` logs them as `tracing` warnings (see `trace.rs`).
+    pub fn start(
+        cores: Vec<Arc<CoreHealth>>,
+        config: WatchdogConfig,
+        on_stall: Option<StallHandler>,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = Arc::clone(&stop);
+        let on_stall = on_stall.unwrap_or_else(|| Box::new(|report| trace::warn_event!("watchdog: {}", report)));
+
+        let thread = thread::Builder::new()
+            .name("storage-watchdog".to_string())
+            .spawn(move || {
+                while !stop_flag.load(Ordering::Relaxed) {
+                    thread::sleep(config.interval);
+                    for core in &cores {
+                        let report = core.check(config.stall_threshold);
+                        if let Some(report) = &report {
+                            on_stall(report);
+                        }
+                        if config.fail_health_checks {
+                            core.healthy.store(report.is_none(), Ordering::Relaxed);
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn watchdog thread");
+
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}